@@ -0,0 +1,77 @@
+// Decoding of responses with many names, into owned strings with ciborium and borrowed from the
+// frame with cbor4ii.
+//
+// cargo bench -p mcumgr-smp --features payload-cbor-borrowed --bench borrowed
+
+use std::collections::BTreeMap;
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use mcumgr_smp::os_management::{TaskStats, TaskStatsResult, TaskStatsResultRef};
+use mcumgr_smp::stat_management::{GroupDataResult, GroupDataResultRef};
+use mcumgr_smp::{Group, OpCode, SmpFrame};
+
+fn response<T: serde::Serialize>(group: Group, command: u8, data: T) -> Vec<u8> {
+    SmpFrame::new(OpCode::ReadResponse, 1, group, command, data).encode_with_cbor()
+}
+
+fn taskstat(c: &mut Criterion) {
+    let mut group = c.benchmark_group("taskstat");
+
+    for count in [4, 32] {
+        let tasks = (0..count)
+            .map(|i| {
+                let stats = TaskStats {
+                    prio: Some(i),
+                    tid: Some(i as u64),
+                    state: Some(1),
+                    stkuse: Some(100),
+                    stksiz: Some(256),
+                    cswcnt: Some(1000),
+                    runtime: Some(50_000),
+                    ..Default::default()
+                };
+                (format!("worker_thread_{i}"), stats)
+            })
+            .collect();
+        let frame = response(Group::Default, 2, TaskStatsResult::Ok { tasks });
+
+        group.throughput(Throughput::Bytes(frame.len() as u64));
+        group.bench_with_input(BenchmarkId::new("owned", count), &frame, |b, frame| {
+            b.iter(|| SmpFrame::<TaskStatsResult>::decode_with_cbor(black_box(frame)).unwrap())
+        });
+        group.bench_with_input(BenchmarkId::new("borrowed", count), &frame, |b, frame| {
+            b.iter(|| {
+                SmpFrame::<TaskStatsResultRef>::decode_with_cbor_borrowed(black_box(frame)).unwrap()
+            })
+        });
+    }
+    group.finish();
+}
+
+fn stats_group(c: &mut Criterion) {
+    let mut group = c.benchmark_group("stats_group");
+
+    for count in [4, 64] {
+        let fields: BTreeMap<String, u64> =
+            (0..count).map(|i| (format!("counter_{i}"), i)).collect();
+        let data = GroupDataResult::Ok {
+            name: "ble_ll_stats".to_string(),
+            fields,
+        };
+        let frame = response(Group::Statistics, 0, data);
+
+        group.throughput(Throughput::Bytes(frame.len() as u64));
+        group.bench_with_input(BenchmarkId::new("owned", count), &frame, |b, frame| {
+            b.iter(|| SmpFrame::<GroupDataResult>::decode_with_cbor(black_box(frame)).unwrap())
+        });
+        group.bench_with_input(BenchmarkId::new("borrowed", count), &frame, |b, frame| {
+            b.iter(|| {
+                SmpFrame::<GroupDataResultRef>::decode_with_cbor_borrowed(black_box(frame)).unwrap()
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, taskstat, stats_group);
+criterion_main!(benches);
@@ -0,0 +1,124 @@
+// Encoding and decoding of frames and the serial console framing, without any hardware.
+//
+// cargo bench -p mcumgr-smp
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use mcumgr_smp::application_management::{
+    GetImageStatePayload, GetImageStateResult, ImageState, ImageWriter, SplitStatus,
+};
+use mcumgr_smp::os_management::{self, EchoResult};
+use mcumgr_smp::transport::smp_framing::{self, SmpTransportDecoder};
+use mcumgr_smp::{Group, OpCode, SmpFrame};
+
+fn echo(c: &mut Criterion) {
+    let request = os_management::echo(1, "hello, this is a benchmark".to_string());
+    let response = SmpFrame::new(
+        OpCode::WriteResponse,
+        1,
+        Group::Default,
+        0,
+        EchoResult::Ok {
+            r: "hello, this is a benchmark".to_string(),
+        },
+    )
+    .encode_with_cbor();
+
+    let mut group = c.benchmark_group("echo");
+    group.throughput(Throughput::Bytes(response.len() as u64));
+    group.bench_function("encode", |b| {
+        b.iter(|| black_box(&request).encode_with_cbor())
+    });
+    group.bench_function("decode", |b| {
+        b.iter(|| SmpFrame::<EchoResult>::decode_with_cbor(black_box(&response)).unwrap())
+    });
+    group.finish();
+}
+
+fn upload_chunk(c: &mut Criterion) {
+    let mut group = c.benchmark_group("upload_chunk");
+
+    for size in [256, 4096] {
+        let data: Vec<u8> = (0..size).map(|i| i as u8).collect();
+        // a chunk in the middle of the image, without the length and hash of the first one
+        let mut writer = ImageWriter::new(None, 64 * 1024, None, false);
+        writer.offset = 1024;
+        let frame = writer.write_chunk(&data);
+        let encoded = frame.encode_with_cbor();
+
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_with_input(BenchmarkId::new("encode", size), &frame, |b, frame| {
+            b.iter(|| frame.encode_with_cbor())
+        });
+        group.bench_with_input(BenchmarkId::new("encode_into", size), &frame, |b, frame| {
+            let mut buf = Vec::new();
+            b.iter(|| frame.encode_into(&mut buf))
+        });
+        // the chunk borrows its data, which the CBOR decoder can't provide
+        group.bench_with_input(BenchmarkId::new("decode", size), &encoded, |b, encoded| {
+            b.iter(|| SmpFrame::<ciborium::Value>::decode_with_cbor(encoded).unwrap())
+        });
+    }
+    group.finish();
+}
+
+fn image_state(c: &mut Criterion) {
+    // two images with a primary and secondary slot each, as a multi-image device reports them
+    let images = (0..4)
+        .map(|i| ImageState {
+            image: Some(i / 2),
+            slot: i % 2,
+            version: format!("1.{}.0+{}", i, i * 100),
+            hash: vec![i as u8; 32],
+            bootable: true,
+            pending: i == 1,
+            confirmed: i % 2 == 0,
+            active: i % 2 == 0,
+            permanent: false,
+            extra: Default::default(),
+        })
+        .collect();
+    let response = SmpFrame::new(
+        OpCode::ReadResponse,
+        1,
+        Group::ApplicationManagement,
+        0,
+        GetImageStateResult::Ok(GetImageStatePayload {
+            images,
+            split_status: Some(SplitStatus::Invalid),
+            extra: Default::default(),
+        }),
+    )
+    .encode_with_cbor();
+
+    let mut group = c.benchmark_group("image_state");
+    group.throughput(Throughput::Bytes(response.len() as u64));
+    group.bench_function("decode", |b| {
+        b.iter(|| SmpFrame::<GetImageStateResult>::decode_with_cbor(black_box(&response)).unwrap())
+    });
+    group.finish();
+}
+
+fn serial_framing(c: &mut Criterion) {
+    let frame: Vec<u8> = (0..512).map(|i| i as u8).collect();
+    let lines = smp_framing::encode_lines(&frame);
+
+    let mut group = c.benchmark_group("serial_framing");
+    group.throughput(Throughput::Bytes(frame.len() as u64));
+    group.bench_function("encode", |b| {
+        let mut out = Vec::new();
+        b.iter(|| smp_framing::encode_lines_into(black_box(&frame), &mut out))
+    });
+    group.bench_function("decode", |b| {
+        b.iter(|| {
+            let mut decoder = SmpTransportDecoder::new();
+            for line in black_box(&lines).split_inclusive(|b| *b == b'\n') {
+                decoder.input_line(line).unwrap();
+            }
+            decoder.into_frame_payload().unwrap()
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, echo, upload_chunk, image_state, serial_framing);
+criterion_main!(benches);
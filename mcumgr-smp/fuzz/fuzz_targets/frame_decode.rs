@@ -0,0 +1,27 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use mcumgr_smp::application_management::{GetImageStateResult, WriteImageChunkResult};
+use mcumgr_smp::fs_management::FileDownloadResult;
+use mcumgr_smp::os_management::{EchoResult, TaskStatsResult};
+use mcumgr_smp::setting_management::ReadSettingResult;
+use mcumgr_smp::shell_management::ShellResult;
+use mcumgr_smp::{SmpError, SmpFrame};
+
+/// Errors are rendered too, they include a dump of the payload
+fn check<T>(result: Result<SmpFrame<T>, SmpError>) {
+    if let Err(e) = result {
+        let _ = e.to_string();
+    }
+}
+
+fuzz_target!(|data: &[u8]| {
+    check(SmpFrame::<ciborium::Value>::decode_with_cbor(data));
+    check(SmpFrame::<EchoResult>::decode_with_cbor(data));
+    check(SmpFrame::<ShellResult>::decode_with_cbor(data));
+    check(SmpFrame::<TaskStatsResult>::decode_with_cbor(data));
+    check(SmpFrame::<GetImageStateResult>::decode_with_cbor(data));
+    check(SmpFrame::<WriteImageChunkResult>::decode_with_cbor(data));
+    check(SmpFrame::<ReadSettingResult>::decode_with_cbor(data));
+    check(SmpFrame::<FileDownloadResult>::decode_with_cbor(data));
+});
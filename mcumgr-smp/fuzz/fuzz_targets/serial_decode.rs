@@ -0,0 +1,22 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use mcumgr_smp::transport::smp_framing::SmpTransportDecoder;
+use mcumgr_smp::SmpFrame;
+
+// console data as the serial transport receives it, line by line
+fuzz_target!(|data: &[u8]| {
+    let mut decoder = SmpTransportDecoder::new();
+    for line in data.split_inclusive(|b| *b == b'\n') {
+        match decoder.input_line(line) {
+            Ok(true) => {
+                let complete = std::mem::take(&mut decoder);
+                if let Ok(frame) = complete.into_frame_payload() {
+                    let _ = SmpFrame::<ciborium::Value>::decode_with_cbor(&frame);
+                }
+            }
+            Ok(false) => {}
+            Err(_) => decoder = SmpTransportDecoder::new(),
+        }
+    }
+});
@@ -1,9 +1,11 @@
 // Author: Sascha Zenglein <zenglein@gessler.de>
 // Copyright (c) 2023 Gessler GmbH.
 
-use crate::{Group, OpCode, SmpFrame};
+use crate::{ExtraFields, Group, HexBytes, OpCode, SmpFrame};
 
 use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fmt::{Debug, Display, Formatter};
 
 pub enum ApplicationManagementCommand {
     State,
@@ -23,28 +25,78 @@ impl From<ApplicationManagementCommand> for u8 {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 #[serde(untagged)]
 pub enum GetImageStateResult {
     Ok(GetImageStatePayload),
     Err(GetImageStateError),
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct GetImageStatePayload {
     pub images: Vec<ImageState>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub split_status: Option<i32>,
+    #[serde(rename = "splitStatus")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub split_status: Option<SplitStatus>,
+    /// fields this crate doesn't know, e.g. vendor extensions
+    #[serde(flatten)]
+    pub extra: ExtraFields,
+}
+
+/// Whether a split image matches its loader. Devices without split images report
+/// [SplitStatus::Invalid]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(from = "i32", into = "i32")]
+pub enum SplitStatus {
+    Invalid,
+    NotMatching,
+    Matching,
+    /// a value this crate doesn't know
+    Other(i32),
+}
+
+impl From<i32> for SplitStatus {
+    fn from(status: i32) -> Self {
+        match status {
+            0 => SplitStatus::Invalid,
+            1 => SplitStatus::NotMatching,
+            2 => SplitStatus::Matching,
+            other => SplitStatus::Other(other),
+        }
+    }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+impl From<SplitStatus> for i32 {
+    fn from(status: SplitStatus) -> Self {
+        match status {
+            SplitStatus::Invalid => 0,
+            SplitStatus::NotMatching => 1,
+            SplitStatus::Matching => 2,
+            SplitStatus::Other(other) => other,
+        }
+    }
+}
+
+impl Display for SplitStatus {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SplitStatus::Invalid => f.write_str("invalid"),
+            SplitStatus::NotMatching => f.write_str("not matching"),
+            SplitStatus::Matching => f.write_str("matching"),
+            SplitStatus::Other(other) => write!(f, "{}", other),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
 pub struct GetImageStateError {
+    #[serde(alias = "err", deserialize_with = "crate::smp::error_rc")]
     pub rc: i32,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub rsn: Option<String>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
 pub struct ImageState {
     pub image: Option<i32>,
     pub slot: i32,
@@ -61,14 +113,36 @@ pub struct ImageState {
     pub active: bool,
     #[serde(default)]
     pub permanent: bool,
+    /// fields this crate doesn't know, e.g. vendor extensions
+    #[serde(flatten)]
+    pub extra: ExtraFields,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+impl Debug for ImageState {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ImageState")
+            .field("image", &self.image)
+            .field("slot", &self.slot)
+            .field("version", &self.version)
+            .field("hash", &HexBytes(&self.hash))
+            .field("bootable", &self.bootable)
+            .field("pending", &self.pending)
+            .field("confirmed", &self.confirmed)
+            .field("active", &self.active)
+            .field("permanent", &self.permanent)
+            .field("extra", &self.extra)
+            .finish()
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
 pub struct GetStatePayload {}
 
 pub fn get_state(sequence: u8) -> SmpFrame<GetStatePayload> {
     SmpFrame {
         operation: OpCode::ReadRequest,
+        version: 0,
+        reserved: 0,
         flags: 0,
         group: Group::ApplicationManagement,
         sequence,
@@ -77,18 +151,34 @@ pub fn get_state(sequence: u8) -> SmpFrame<GetStatePayload> {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Hash)]
 pub struct SetStatePayload {
-    #[serde(with = "serde_bytes")]
-    pub hash: Vec<u8>,
+    /// Image to mark. If omitted when confirming, the running image is confirmed.
+    #[serde(default, with = "serde_bytes")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hash: Option<Vec<u8>>,
     pub confirm: bool,
 }
 
+impl Debug for SetStatePayload {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SetStatePayload")
+            .field("hash", &self.hash.as_deref().map(HexBytes))
+            .field("confirm", &self.confirm)
+            .finish()
+    }
+}
+
 pub fn set_state(hash: Vec<u8>, confirm: bool, sequence: u8) -> SmpFrame<SetStatePayload> {
-    let data = SetStatePayload { hash, confirm };
+    let data = SetStatePayload {
+        hash: Some(hash),
+        confirm,
+    };
 
     SmpFrame {
         operation: OpCode::WriteRequest,
+        version: 0,
+        reserved: 0,
         flags: 0,
         group: Group::ApplicationManagement,
         sequence,
@@ -97,7 +187,61 @@ pub fn set_state(hash: Vec<u8>, confirm: bool, sequence: u8) -> SmpFrame<SetStat
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+/// Mark the image with the given hash for test on the next boot.  
+/// The response is a [GetImageStateResult].
+pub fn set_pending(hash: Vec<u8>, sequence: u8) -> SmpFrame<SetStatePayload> {
+    set_state(hash, false, sequence)
+}
+
+/// Make the image with the given hash permanent, or the running image if no hash is given.  
+/// The response is a [GetImageStateResult].
+pub fn confirm(hash: Option<Vec<u8>>, sequence: u8) -> SmpFrame<SetStatePayload> {
+    let data = SetStatePayload {
+        hash,
+        confirm: true,
+    };
+
+    SmpFrame::new(
+        OpCode::WriteRequest,
+        sequence,
+        Group::ApplicationManagement,
+        ApplicationManagementCommand::State.into(),
+        data,
+    )
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct EraseImagePayload {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub slot: Option<u32>,
+}
+
+/// Erase the given slot, or the secondary slot if none is given.
+pub fn erase_image(slot: Option<u32>, sequence: u8) -> SmpFrame<EraseImagePayload> {
+    let data = EraseImagePayload { slot };
+
+    SmpFrame::new(
+        OpCode::WriteRequest,
+        sequence,
+        Group::ApplicationManagement,
+        ApplicationManagementCommand::Erase.into(),
+        data,
+    )
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+#[serde(untagged)]
+pub enum EraseImageResult {
+    Err {
+        #[serde(alias = "err", deserialize_with = "crate::smp::error_rc")]
+        rc: i32,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        rsn: Option<String>,
+    },
+    Ok {},
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Hash)]
 pub struct ImageChunk<'d, 's> {
     #[serde(with = "serde_bytes")]
     pub data: &'d [u8],
@@ -113,29 +257,61 @@ pub struct ImageChunk<'d, 's> {
     pub upgrade: Option<bool>,
 }
 
+impl Debug for ImageChunk<'_, '_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ImageChunk")
+            .field("data", &HexBytes(self.data))
+            .field("off", &self.off)
+            .field("image", &self.image)
+            .field("len", &self.len)
+            .field("sha", &self.sha.map(HexBytes))
+            .field("upgrade", &self.upgrade)
+            .finish()
+    }
+}
+
+/// Splits an image upload into chunk requests.
+///
+/// `image`, `len`, `sha` and `upgrade` are only sent with the chunk at offset 0, strict
+/// servers reject them in later chunks. `upgrade` is left out unless it is true, as some
+/// firmware treats its presence as true whatever its value.
 pub struct ImageWriter<'s> {
     pub image: Option<u8>,
     pub hash: Option<&'s [u8]>,
+    /// Send only the first bytes of `hash`, for devices that expect a truncated `sha`
+    pub sha_length: Option<usize>,
     pub offset: usize,
     pub len: usize,
     pub sequence: u8,
     pub upgrade: bool,
+    /// offset of the chunk last written
+    chunk_start: usize,
 }
 
 impl ImageWriter<'_> {
-    pub fn new(image: Option<u8>, len: usize, hash: Option<&[u8]>, upgrade: bool) -> ImageWriter {
+    pub fn new(
+        image: Option<u8>,
+        len: usize,
+        hash: Option<&[u8]>,
+        upgrade: bool,
+    ) -> ImageWriter<'_> {
         ImageWriter {
             image,
             hash,
+            sha_length: None,
             offset: 0,
             len,
             sequence: 0,
             upgrade,
+            chunk_start: 0,
         }
     }
 
+    /// The frame for the next chunk. It borrows the data, so together with a buffer reused by
+    /// `send_cbor_with` of the CBOR transports an upload doesn't allocate per chunk for sending.
     pub fn write_chunk<'d>(&mut self, data: &'d [u8]) -> SmpFrame<ImageChunk<'d, '_>> {
         let data_len = data.len();
+        self.chunk_start = self.offset;
 
         let mut chunk_data = ImageChunk {
             data,
@@ -154,7 +330,8 @@ impl ImageWriter<'_> {
             }
 
             if let Some(hash) = self.hash {
-                chunk_data.sha = Some(hash);
+                let len = self.sha_length.unwrap_or(hash.len()).min(hash.len());
+                chunk_data.sha = Some(&hash[..len]);
             }
 
             if self.upgrade {
@@ -174,16 +351,178 @@ impl ImageWriter<'_> {
             chunk_data,
         )
     }
+
+    /// Decide how to continue after the response to a chunk.
+    ///
+    /// The device reports the offset it expects next, which is where the following chunk has
+    /// to start, even if it differs from the end of the chunk just sent. Devices that reject a
+    /// chunk, e.g. with [ReturnCode::OutOfMemory](crate::ReturnCode::OutOfMemory), may still
+    /// report that offset, then the upload can be retried from there without reading the image
+    /// state. The offset of the next [write_chunk](Self::write_chunk) is set accordingly.
+    ///
+    /// An offset before the chunk just sent means the device lost data, e.g. because it
+    /// rebooted, see [UploadAction::Restarted] and [UploadAction::Rewind]. An offset beyond
+    /// the data sent is an [UploadAction::InvalidOffset], except in the response to the first
+    /// chunk, where a device that already has the start of the image skips ahead.
+    pub fn handle_response(&mut self, response: &WriteImageChunkResult) -> UploadAction {
+        let off = match response {
+            WriteImageChunkResult::Ok(payload) => payload.off as usize,
+            WriteImageChunkResult::Err(WriteImageChunkError { off: Some(off), .. }) => {
+                *off as usize
+            }
+            WriteImageChunkResult::Err(err) => return UploadAction::Fatal { rc: err.rc },
+        };
+
+        let sent = self.offset;
+        if off > self.len || (off > sent && self.chunk_start > 0) {
+            return UploadAction::InvalidOffset { off, sent };
+        }
+        self.offset = off;
+
+        match response {
+            WriteImageChunkResult::Ok(_) if off == 0 && self.chunk_start > 0 => {
+                UploadAction::Restarted
+            }
+            WriteImageChunkResult::Ok(_) if off < self.chunk_start => UploadAction::Rewind { off },
+            WriteImageChunkResult::Ok(_) => UploadAction::Continue { next_off: off },
+            WriteImageChunkResult::Err(_) => UploadAction::RetryFrom { off },
+        }
+    }
+
+    /// Like [handle_response](Self::handle_response), for the response to one of several
+    /// chunks in flight, which was written from `chunk.off` to `chunk.end`.
+    ///
+    /// The offset of the writer stays after the last chunk sent if the device expects the end
+    /// of this chunk next, otherwise it is moved to the offset the device reported and the
+    /// chunks still in flight are stale.
+    pub fn handle_pipelined_response(
+        &mut self,
+        response: &WriteImageChunkResult,
+        chunk: InFlight,
+    ) -> UploadAction {
+        let sent = self.offset;
+        self.chunk_start = chunk.off;
+        let action = self.handle_response(response);
+        if matches!(action, UploadAction::Continue { next_off } if next_off == chunk.end) {
+            self.offset = sent;
+        }
+        action
+    }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+/// Packet buffers of the device left free by a pipelined upload, e.g. for a response
+pub const UPLOAD_BUFFER_MARGIN: u32 = 1;
+
+/// A chunk of a pipelined upload whose response hasn't arrived yet
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct InFlight {
+    pub sequence: u8,
+    pub off: usize,
+    pub end: usize,
+}
+
+/// Credits of a pipelined upload, one per chunk in flight.
+///
+/// A device drops frames it has no packet buffer for, so the chunks in flight never exceed the
+/// `buf_count` of its MCUmgr parameters minus [UPLOAD_BUFFER_MARGIN]. Devices that don't report
+/// their buffers get a window of one chunk, i.e. no pipelining.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UploadCredits {
+    window: usize,
+    /// oldest first, responses arrive in the order the chunks were sent
+    in_flight: VecDeque<InFlight>,
+}
+
+impl UploadCredits {
+    pub fn new(buf_count: Option<u32>) -> Self {
+        let window = buf_count.map_or(1, |count| {
+            count.saturating_sub(UPLOAD_BUFFER_MARGIN).max(1) as usize
+        });
+        Self {
+            window,
+            in_flight: VecDeque::with_capacity(window),
+        }
+    }
+
+    /// The most chunks in flight at the same time
+    pub fn window(&self) -> usize {
+        self.window
+    }
+
+    /// Whether another chunk may be sent before a response arrives
+    pub fn available(&self) -> bool {
+        self.in_flight.len() < self.window
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.len()
+    }
+
+    /// Take a credit for a chunk sent with `sequence`
+    pub fn sent(&mut self, sequence: u8, off: usize, end: usize) {
+        self.in_flight.push_back(InFlight { sequence, off, end });
+    }
+
+    /// Give back the credit of the chunk answered by a response with `sequence`. The chunks
+    /// sent before it didn't get their response in order, it was lost, so their credits are
+    /// given back as well.
+    ///
+    /// `None` if no chunk in flight was sent with `sequence`, e.g. for a late response.
+    pub fn received(&mut self, sequence: u8) -> Option<InFlight> {
+        let index = self
+            .in_flight
+            .iter()
+            .position(|chunk| chunk.sequence == sequence)?;
+        self.in_flight.drain(..index);
+        self.in_flight.pop_front()
+    }
+
+    /// Give back all credits, e.g. when the responses still in flight are given up on
+    pub fn clear(&mut self) {
+        self.in_flight.clear();
+    }
+}
+
+/// How to continue an upload, see [ImageWriter::handle_response]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum UploadAction {
+    /// The chunk was written, the upload continues at `next_off`
+    Continue { next_off: usize },
+    /// The chunk was rejected, but the device reported where the upload can be resumed
+    RetryFrom { off: usize },
+    /// The chunk was rejected without an offset to resume at
+    Fatal { rc: i32 },
+    /// The device reported offset 0 after the first chunk, it started the upload over, e.g.
+    /// after a reboot. The upload continues from the start, with the fields of the first chunk
+    Restarted,
+    /// The device reported an offset before the chunk just sent, the data from there on is
+    /// sent again
+    Rewind { off: usize },
+    /// The device reported an offset beyond the `sent` bytes, e.g. in a stale response. The
+    /// upload can't continue, the offset of the writer is left unchanged
+    InvalidOffset { off: usize, sent: usize },
+}
+
+/// The response to a chunk of an upload. Decoded by the error code, see
+/// [deserialize_result](crate::smp::deserialize_result), so an error that also reports the
+/// offset is an `Err` with its `off`
+#[derive(Serialize, Debug, Clone, PartialEq, Eq, Hash)]
 #[serde(untagged)]
 pub enum WriteImageChunkResult {
     Ok(WriteImageChunkPayload),
     Err(WriteImageChunkError),
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+impl<'de> Deserialize<'de> for WriteImageChunkResult {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Ok(match crate::smp::deserialize_result(deserializer)? {
+            Ok(payload) => WriteImageChunkResult::Ok(payload),
+            Err(error) => WriteImageChunkResult::Err(error),
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
 pub struct WriteImageChunkPayload {
     pub off: u32,
     #[serde(rename = "match")]
@@ -191,9 +530,356 @@ pub struct WriteImageChunkPayload {
     pub match_: Option<bool>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
 pub struct WriteImageChunkError {
+    #[serde(alias = "err", deserialize_with = "crate::smp::error_rc")]
     pub rc: i32,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub rsn: Option<String>,
+    /// offset the device expects next, some devices report it along with the error
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub off: Option<u32>,
+    #[serde(rename = "match")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub match_: Option<bool>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cbor::decode_value;
+    use ciborium::{cbor, Value};
+
+    #[test]
+    fn chunk_written() {
+        let result: WriteImageChunkResult = decode_value(cbor!({ "off" => 512 }).unwrap());
+        assert_eq!(
+            result,
+            WriteImageChunkResult::Ok(WriteImageChunkPayload {
+                off: 512,
+                match_: None
+            })
+        );
+    }
+
+    #[test]
+    fn chunk_written_with_rc_0() {
+        let result: WriteImageChunkResult =
+            decode_value(cbor!({ "rc" => 0, "off" => 4096, "match" => true }).unwrap());
+        assert_eq!(
+            result,
+            WriteImageChunkResult::Ok(WriteImageChunkPayload {
+                off: 4096,
+                match_: Some(true)
+            })
+        );
+    }
+
+    #[test]
+    fn chunk_rc() {
+        let result: WriteImageChunkResult = decode_value(cbor!({ "rc" => 3 }).unwrap());
+        assert_eq!(
+            result,
+            WriteImageChunkResult::Err(WriteImageChunkError {
+                rc: 3,
+                rsn: None,
+                off: None,
+                match_: None
+            })
+        );
+    }
+
+    #[test]
+    fn chunk_v2_err() {
+        let result: WriteImageChunkResult =
+            decode_value(cbor!({ "err" => { "group" => 1, "rc" => 6 } }).unwrap());
+        assert_eq!(
+            result,
+            WriteImageChunkResult::Err(WriteImageChunkError {
+                rc: 6,
+                rsn: None,
+                off: None,
+                match_: None
+            })
+        );
+    }
+
+    /// Zephyr's answer to a chunk it has no memory for, ENOMEM with the offset to resume at:
+    /// `{"rc": 2, "off": 512}` in a write response of the image group, sequence 42
+    const ENOMEM_WITH_OFF: [u8; 20] = [
+        0x03, 0x00, 0x00, 0x0c, 0x00, 0x01, 0x2a, 0x01, // header
+        0xa2, 0x62, b'r', b'c', 0x02, 0x63, b'o', b'f', b'f', 0x19, 0x02, 0x00,
+    ];
+
+    /// The same in an SMP version 2 response, `{"err": {"group": 1, "rc": 2}, "off": 512}`
+    const ENOMEM_V2_WITH_OFF: [u8; 32] = [
+        0x0b, 0x00, 0x00, 0x18, 0x00, 0x01, 0x2a, 0x01, // header
+        0xa2, 0x63, b'e', b'r', b'r', 0xa2, 0x65, b'g', b'r', b'o', b'u', b'p', 0x01, 0x62, b'r',
+        b'c', 0x02, 0x63, b'o', b'f', b'f', 0x19, 0x02, 0x00,
+    ];
+
+    fn enomem_at(off: u32) -> WriteImageChunkResult {
+        WriteImageChunkResult::Err(WriteImageChunkError {
+            rc: 2,
+            rsn: None,
+            off: Some(off),
+            match_: None,
+        })
+    }
+
+    #[test]
+    fn enomem_with_off_decodes_as_err() {
+        let frame = SmpFrame::<WriteImageChunkResult>::decode_with_cbor(&ENOMEM_WITH_OFF).unwrap();
+        assert_eq!(frame.sequence, 42);
+        assert_eq!(frame.data, enomem_at(512));
+
+        let frame =
+            SmpFrame::<WriteImageChunkResult>::decode_with_cbor(&ENOMEM_V2_WITH_OFF).unwrap();
+        assert_eq!(frame.data, enomem_at(512));
+    }
+
+    #[test]
+    fn enomem_with_off_retries_from_there() {
+        let data = [0u8; 2048];
+        let mut writer = ImageWriter::new(None, data.len(), None, false);
+
+        writer.write_chunk(&data[..512]);
+        let written = WriteImageChunkResult::Ok(WriteImageChunkPayload {
+            off: 512,
+            match_: None,
+        });
+        assert_eq!(
+            writer.handle_response(&written),
+            UploadAction::Continue { next_off: 512 }
+        );
+
+        writer.write_chunk(&data[512..1024]);
+        let frame = SmpFrame::<WriteImageChunkResult>::decode_with_cbor(&ENOMEM_WITH_OFF).unwrap();
+        assert_eq!(
+            writer.handle_response(&frame.data),
+            UploadAction::RetryFrom { off: 512 }
+        );
+        assert_eq!(writer.offset, 512);
+    }
+
+    #[test]
+    fn error_without_off_is_fatal() {
+        let data = [0u8; 1024];
+        let mut writer = ImageWriter::new(None, data.len(), None, false);
+        writer.write_chunk(&data[..512]);
+
+        let rejected: WriteImageChunkResult = decode_value(cbor!({ "rc" => 2 }).unwrap());
+        assert_eq!(
+            writer.handle_response(&rejected),
+            UploadAction::Fatal { rc: 2 }
+        );
+    }
+
+    #[test]
+    fn error_with_off_beyond_the_data_sent_is_invalid() {
+        let data = [0u8; 2048];
+        let mut writer = ImageWriter::new(None, data.len(), None, false);
+        writer.write_chunk(&data[..512]);
+        writer.handle_response(&WriteImageChunkResult::Ok(WriteImageChunkPayload {
+            off: 512,
+            match_: None,
+        }));
+        writer.write_chunk(&data[512..1024]);
+
+        assert_eq!(
+            writer.handle_response(&enomem_at(1536)),
+            UploadAction::InvalidOffset {
+                off: 1536,
+                sent: 1024
+            }
+        );
+        assert_eq!(writer.offset, 1024);
+    }
+
+    fn written(off: u32) -> WriteImageChunkResult {
+        WriteImageChunkResult::Ok(WriteImageChunkPayload { off, match_: None })
+    }
+
+    #[test]
+    fn offset_0_after_the_first_chunk_restarts() {
+        let data = [0u8; 2048];
+        let hash = [0xaa; 32];
+        let mut writer = ImageWriter::new(Some(1), data.len(), Some(&hash), false);
+        writer.write_chunk(&data[..512]);
+        writer.handle_response(&written(512));
+        writer.write_chunk(&data[512..1024]);
+
+        assert_eq!(writer.handle_response(&written(0)), UploadAction::Restarted);
+        assert_eq!(writer.offset, 0);
+
+        // the upload starts over with the fields of the first chunk
+        let chunk = writer.write_chunk(&data[..512]);
+        assert_eq!(chunk.data.off, 0);
+        assert_eq!(chunk.data.len, Some(data.len()));
+        assert_eq!(chunk.data.sha, Some(&hash[..]));
+        assert_eq!(chunk.data.image, Some(1));
+    }
+
+    #[test]
+    fn offset_0_for_the_first_chunk_continues() {
+        let data = [0u8; 1024];
+        let mut writer = ImageWriter::new(None, data.len(), None, false);
+        writer.write_chunk(&data[..512]);
+
+        assert_eq!(
+            writer.handle_response(&written(0)),
+            UploadAction::Continue { next_off: 0 }
+        );
+    }
+
+    #[test]
+    fn offset_before_the_chunk_rewinds() {
+        let data = [0u8; 2048];
+        let mut writer = ImageWriter::new(None, data.len(), None, false);
+        writer.write_chunk(&data[..512]);
+        writer.handle_response(&written(512));
+        writer.write_chunk(&data[512..1024]);
+        writer.handle_response(&written(1024));
+        writer.write_chunk(&data[1024..1536]);
+
+        assert_eq!(
+            writer.handle_response(&written(600)),
+            UploadAction::Rewind { off: 600 }
+        );
+        assert_eq!(writer.offset, 600);
+
+        let chunk = writer.write_chunk(&data[600..1112]);
+        assert_eq!(chunk.data.off, 600);
+        assert_eq!(chunk.data.len, None);
+    }
+
+    #[test]
+    fn first_frame_bytes() {
+        let hash = [0xaa, 0xbb, 0xcc, 0xdd, 0xee, 0xff, 0x00, 0x11];
+        let mut writer = ImageWriter::new(Some(1), 6, Some(&hash), true);
+        writer.sha_length = Some(4);
+
+        let mut expected = vec![0x02, 0x00, 0x00, 0x2d, 0x00, 0x01, 0x01, 0x01, 0xa6];
+        expected.extend(b"\x64data\x43\x01\x02\x03");
+        expected.extend(b"\x63off\x00");
+        expected.extend(b"\x65image\x01");
+        expected.extend(b"\x63len\x06");
+        expected.extend(b"\x63sha\x44\xaa\xbb\xcc\xdd");
+        expected.extend(b"\x67upgrade\xf5");
+        assert_eq!(writer.write_chunk(&[1, 2, 3]).encode_with_cbor(), expected);
+    }
+
+    #[test]
+    fn later_frames_have_only_data_and_off() {
+        let hash = [0xaa; 32];
+        let mut writer = ImageWriter::new(Some(1), 6, Some(&hash), true);
+        writer.write_chunk(&[1, 2, 3]);
+
+        let mut expected = vec![0x02, 0x00, 0x00, 0x0f, 0x00, 0x01, 0x02, 0x01, 0xa2];
+        expected.extend(b"\x64data\x43\x04\x05\x06");
+        expected.extend(b"\x63off\x03");
+        assert_eq!(writer.write_chunk(&[4, 5, 6]).encode_with_cbor(), expected);
+    }
+
+    #[test]
+    fn upgrade_false_is_left_out() {
+        let mut writer = ImageWriter::new(None, 6, None, false);
+
+        let mut expected = vec![0x02, 0x00, 0x00, 0x14, 0x00, 0x01, 0x01, 0x01, 0xa3];
+        expected.extend(b"\x64data\x43\x01\x02\x03");
+        expected.extend(b"\x63off\x00");
+        expected.extend(b"\x63len\x06");
+        assert_eq!(writer.write_chunk(&[1, 2, 3]).encode_with_cbor(), expected);
+    }
+
+    #[test]
+    fn sha_length_beyond_the_hash_sends_all_of_it() {
+        let hash = [0xaa, 0xbb];
+        let mut writer = ImageWriter::new(None, 3, Some(&hash), false);
+        writer.sha_length = Some(32);
+
+        assert_eq!(writer.write_chunk(&[1, 2, 3]).data.sha, Some(&hash[..]));
+    }
+
+    #[test]
+    fn chunk_debug_format() {
+        let hash = [0xaa; 32];
+        let mut writer = ImageWriter::new(Some(0), 4, Some(&hash), false);
+        assert_eq!(
+            format!("{:?}", writer.write_chunk(&[1, 2, 3, 4]).data),
+            "ImageChunk { data: 01020304 (4 bytes), off: 0, image: Some(0), len: Some(4), \
+             sha: Some(aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa… (32 bytes)), upgrade: None }"
+        );
+    }
+
+    #[test]
+    fn image_state_debug_format() {
+        let state = ImageState {
+            image: None,
+            slot: 1,
+            version: "1.2.3".to_string(),
+            hash: vec![0x12, 0x34],
+            bootable: true,
+            pending: false,
+            confirmed: false,
+            active: false,
+            permanent: false,
+            extra: ExtraFields::new(),
+        };
+        assert_eq!(
+            format!("{:?}", state),
+            "ImageState { image: None, slot: 1, version: \"1.2.3\", hash: 1234 (2 bytes), \
+             bootable: true, pending: false, confirmed: false, active: false, permanent: false, \
+             extra: {} }"
+        );
+        assert_eq!(
+            format!("{:?}", confirm(None, 0).data),
+            "SetStatePayload { hash: None, confirm: true }"
+        );
+    }
+
+    #[test]
+    fn image_state_keeps_vendor_fields() {
+        let value = cbor!({
+            "images" => [{
+                "slot" => 0,
+                "version" => "2.0.0",
+                "hash" => Value::Bytes(vec![0xab; 32]),
+                "bootable" => true,
+                "confirmed" => true,
+                "active" => true,
+                "x-build-id" => "a1b2c3",
+                "x-signed-by" => ["release", 1],
+            }],
+            "splitStatus" => 2,
+            "x-vendor" => { "rev" => 3, "fused" => false },
+        })
+        .unwrap();
+        let GetImageStateResult::Ok(payload) = decode_value(value.clone()) else {
+            panic!("image state is an error");
+        };
+        assert_eq!(payload.split_status, Some(SplitStatus::Matching));
+        assert_eq!(
+            payload.extra["x-vendor"],
+            cbor!({ "rev" => 3, "fused" => false }).unwrap()
+        );
+        assert_eq!(payload.extra.len(), 1);
+        let image = &payload.images[0];
+        assert!(image.bootable && image.confirmed && image.active);
+        assert!(!image.pending && !image.permanent);
+        assert_eq!(image.extra["x-build-id"], cbor!("a1b2c3").unwrap());
+        assert_eq!(image.extra["x-signed-by"], cbor!(["release", 1]).unwrap());
+        assert_eq!(image.extra.len(), 2);
+
+        // the vendor fields are sent on
+        let reencoded: Value = Value::serialized(&GetImageStateResult::Ok(payload)).unwrap();
+        let Value::Map(entries) = reencoded else {
+            panic!("{:?}", reencoded);
+        };
+        assert!(entries.contains(&(
+            cbor!("x-vendor").unwrap(),
+            cbor!({ "rev" => 3, "fused" => false }).unwrap()
+        )));
+        assert!(entries.contains(&(cbor!("splitStatus").unwrap(), cbor!(2).unwrap())));
+    }
 }
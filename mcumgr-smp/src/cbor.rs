@@ -0,0 +1,226 @@
+//! The CBOR backend of the payloads, currently ciborium.
+//!
+//! Frames and transports only encode and decode through these functions, so the backend
+//! is replaced here alone. Whatever it is, it has to produce the same bytes for the payload
+//! types of this crate.
+//!
+//! ciborium hands strings to serde from its own buffer, so payloads borrowing from the frame
+//! are decoded with cbor4ii instead, with the `payload-cbor-borrowed` feature.
+
+use std::error::Error;
+
+use ciborium::Value;
+
+use crate::smp::MAX_CBOR_DEPTH;
+
+/// Append the encoding of `value` to `buf`, map keys in the order serde produces them
+pub(crate) fn encode_into<T: serde::Serialize>(
+    value: &T,
+    buf: &mut Vec<u8>,
+) -> Result<(), Box<dyn Error>> {
+    ciborium::ser::into_writer(value, buf)?;
+    Ok(())
+}
+
+/// Append the deterministic encoding of `value` to `buf`, as defined in RFC 8949 section
+/// 4.2: definite lengths, the shortest form of integers and map keys sorted by their encoding
+pub(crate) fn encode_canonical_into<T: serde::Serialize>(
+    value: &T,
+    buf: &mut Vec<u8>,
+) -> Result<(), Box<dyn Error>> {
+    let mut value = Value::serialized(value)?;
+    canonicalize(&mut value);
+    ciborium::ser::into_writer(&value, buf)?;
+    Ok(())
+}
+
+/// Length of the encoding of `value`
+pub(crate) fn encoded_len<T: serde::Serialize>(value: &T) -> Result<usize, Box<dyn Error>> {
+    let mut buf = Vec::new();
+    encode_into(value, &mut buf)?;
+    Ok(buf.len())
+}
+
+/// Decode a value, nested at most [MAX_CBOR_DEPTH] levels
+pub(crate) fn decode<T: serde::de::DeserializeOwned>(
+    buf: &[u8],
+) -> Result<T, Box<dyn Error + Send + Sync>> {
+    Ok(ciborium::de::from_reader_with_recursion_limit(
+        buf,
+        MAX_CBOR_DEPTH,
+    )?)
+}
+
+/// Decode a value that borrows strings and byte strings from `buf`, with about the depth
+/// limit of [decode], see [DepthLimit]
+#[cfg(feature = "payload-cbor-borrowed")]
+pub(crate) fn decode_borrowed<'de, T: serde::Deserialize<'de>>(
+    buf: &'de [u8],
+) -> Result<T, Box<dyn Error + Send + Sync>> {
+    let reader = DepthLimit {
+        reader: cbor4ii::core::utils::SliceReader::new(buf),
+        depth: 2 * MAX_CBOR_DEPTH + 1,
+    };
+    Ok(T::deserialize(&mut cbor4ii::serde::Deserializer::new(
+        reader,
+    ))?)
+}
+
+/// A reader of cbor4ii limited to `depth` steps into nested values, instead of its own 256.
+///
+/// cbor4ii steps twice into an array or map it decodes as any value, e.g. a [CborValue](crate::CborValue),
+/// and once into one of a known type, so `2 * MAX_CBOR_DEPTH + 1` steps accept what ciborium
+/// accepts of any value, and nothing deeper than twice that.
+#[cfg(feature = "payload-cbor-borrowed")]
+struct DepthLimit<R> {
+    reader: R,
+    depth: usize,
+}
+
+#[cfg(feature = "payload-cbor-borrowed")]
+impl<'de, R: cbor4ii::core::dec::Read<'de>> cbor4ii::core::dec::Read<'de> for DepthLimit<R> {
+    type Error = R::Error;
+
+    fn fill<'short>(
+        &'short mut self,
+        want: usize,
+    ) -> Result<cbor4ii::core::dec::Reference<'de, 'short>, Self::Error> {
+        self.reader.fill(want)
+    }
+
+    fn advance(&mut self, n: usize) {
+        self.reader.advance(n)
+    }
+
+    fn step_in(&mut self) -> bool {
+        match self.depth.checked_sub(1) {
+            Some(depth) => {
+                self.depth = depth;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn step_out(&mut self) {
+        self.depth += 1;
+    }
+}
+
+/// Sort the keys of all maps in the value by their encoding, as RFC 8949 section 4.2.1 requires
+fn canonicalize(value: &mut Value) {
+    match value {
+        Value::Array(items) => items.iter_mut().for_each(canonicalize),
+        Value::Map(entries) => {
+            for (key, entry) in entries.iter_mut() {
+                canonicalize(key);
+                canonicalize(entry);
+            }
+            entries.sort_by_cached_key(|(key, _)| cbor_bytes(key));
+        }
+        Value::Tag(_, inner) => canonicalize(inner),
+        _ => {}
+    }
+}
+
+/// A value written with definite lengths and the shortest form of integers, as ciborium does
+fn cbor_bytes(value: &Value) -> Vec<u8> {
+    let mut buf = Vec::new();
+    ciborium::ser::into_writer(value, &mut buf).expect("values can be written to a Vec");
+    buf
+}
+
+/// Encode a value written with [ciborium::cbor] and decode it as a payload, like a response
+/// from a device
+#[cfg(test)]
+pub(crate) fn decode_value<T: serde::de::DeserializeOwned>(value: Value) -> T {
+    let mut buf = Vec::new();
+    ciborium::ser::into_writer(&value, &mut buf).unwrap();
+    decode(&buf).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(s: &str) -> Value {
+        Value::Text(s.to_string())
+    }
+
+    fn int(i: i64) -> Value {
+        Value::Integer(i.into())
+    }
+
+    /// Keys in none of the orders RFC 8949 section 4.2 allows, nested maps too
+    fn unsorted() -> Value {
+        Value::Map(vec![
+            (
+                text("aa"),
+                Value::Array(vec![Value::Map(vec![
+                    (text("d"), int(1)),
+                    (text("c"), int(2)),
+                ])]),
+            ),
+            (
+                text("b"),
+                Value::Map(vec![(text("y"), int(1)), (text("x"), int(2))]),
+            ),
+            (int(-1), int(2)),
+            (int(10), int(1)),
+            (text("a"), int(300)),
+        ])
+    }
+
+    fn canonical<T: serde::Serialize>(value: &T) -> Vec<u8> {
+        let mut buf = Vec::new();
+        encode_canonical_into(value, &mut buf).unwrap();
+        buf
+    }
+
+    #[test]
+    fn keys_are_sorted_by_their_encoding() {
+        // shorter keys first, so "b" comes before "aa", integers before text
+        let expected = [
+            &[0xa5][..],
+            &[0x0a, 0x01],
+            &[0x20, 0x02],
+            &[0x61, b'a', 0x19, 0x01, 0x2c],
+            &[0x61, b'b', 0xa2, 0x61, b'x', 0x02, 0x61, b'y', 0x01],
+            &[
+                0x62, b'a', b'a', 0x81, 0xa2, 0x61, b'c', 0x02, 0x61, b'd', 0x01,
+            ],
+        ]
+        .concat();
+        assert_eq!(canonical(&unsorted()), expected);
+    }
+
+    #[test]
+    fn canonical_encoding_is_stable_and_decodes_the_same() {
+        let canonical_bytes = canonical(&unsorted());
+        let mut plain = Vec::new();
+        encode_into(&unsorted(), &mut plain).unwrap();
+        assert_ne!(plain, canonical_bytes);
+
+        // encoding again keeps the bytes, decoding doesn't care about the order
+        let decoded: Value = decode(&canonical_bytes).unwrap();
+        assert_eq!(canonical(&decoded), canonical_bytes);
+        assert_eq!(
+            canonical(&decode::<Value>(&plain).unwrap()),
+            canonical_bytes
+        );
+    }
+
+    #[test]
+    fn struct_fields_are_sorted_with_the_shortest_integers() {
+        #[derive(serde::Serialize)]
+        struct Payload {
+            zz: u64,
+            a: i64,
+        }
+
+        assert_eq!(
+            canonical(&Payload { zz: 1, a: -300 }),
+            [0xa2, 0x61, b'a', 0x39, 0x01, 0x2b, 0x62, b'z', b'z', 0x01]
+        );
+    }
+}
@@ -0,0 +1,504 @@
+use crate::{Group, HexBytes, SmpFrame};
+
+use crate::OpCode::{ReadRequest, WriteRequest};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt::{Debug, Formatter};
+
+pub enum FsManagementCommand {
+    File,
+    Status,
+    Hash,
+    SupportedHashes,
+    Close,
+}
+
+impl From<FsManagementCommand> for u8 {
+    fn from(cmd: FsManagementCommand) -> Self {
+        match cmd {
+            FsManagementCommand::File => 0,
+            FsManagementCommand::Status => 1,
+            FsManagementCommand::Hash => 2,
+            FsManagementCommand::SupportedHashes => 3,
+            FsManagementCommand::Close => 4,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct FileDownloadRequest {
+    pub off: u64,
+    pub name: String,
+}
+
+/// Read the part of a file starting at `off`. The device decides how many bytes it sends,
+/// the length of the file is only included in the response for offset 0. See [RangeReader]
+/// to read a given number of bytes.
+pub fn download(sequence: u8, name: String, off: u64) -> SmpFrame<FileDownloadRequest> {
+    let payload = FileDownloadRequest { off, name };
+
+    SmpFrame::new(
+        ReadRequest,
+        sequence,
+        Group::FileManagement,
+        FsManagementCommand::File.into(),
+        payload,
+    )
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Hash)]
+#[serde(untagged)]
+pub enum FileDownloadResult {
+    Ok {
+        off: u64,
+        #[serde(with = "serde_bytes")]
+        data: Vec<u8>,
+        /// total length of the file, only sent in the response for offset 0
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        len: Option<u64>,
+    },
+    Err {
+        #[serde(alias = "err", deserialize_with = "crate::smp::error_rc")]
+        rc: i32,
+    },
+}
+
+impl Debug for FileDownloadResult {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FileDownloadResult::Ok { off, data, len } => f
+                .debug_struct("Ok")
+                .field("off", off)
+                .field("data", &HexBytes(data))
+                .field("len", len)
+                .finish(),
+            FileDownloadResult::Err { rc } => f.debug_struct("Err").field("rc", rc).finish(),
+        }
+    }
+}
+
+/// Reads the bytes of a file from `offset` on, up to `len` of them or up to the end of the
+/// file. If the file ends first, the bytes up to its end are read like from a local file, e.g.
+/// when asking for the last 4 KiB of a log that is shorter.
+///
+/// The device decides how many bytes it sends per request, data beyond the range is cut off.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct RangeReader {
+    pub name: String,
+    /// offset of the next request
+    pub offset: u64,
+    /// bytes still to read, `None` reads up to the end of the file
+    pub remaining: Option<u64>,
+    /// length of the file, once known. The device only sends it in the response for offset 0,
+    /// for other offsets it can be filled in from a [status] request
+    pub file_len: Option<u64>,
+    pub sequence: u8,
+    /// the device sent no data, the file ended
+    eof: bool,
+}
+
+impl RangeReader {
+    /// Read `len` bytes from `offset` on, or up to the end of the file if `len` is `None`
+    pub fn new(name: impl Into<String>, offset: u64, len: Option<u64>) -> Self {
+        RangeReader {
+            name: name.into(),
+            offset,
+            remaining: len,
+            file_len: None,
+            sequence: 0,
+            eof: false,
+        }
+    }
+
+    /// Whether the range is complete, no further requests are needed
+    pub fn is_done(&self) -> bool {
+        self.eof
+            || self.remaining == Some(0)
+            || self
+                .file_len
+                .is_some_and(|file_len| self.offset >= file_len)
+    }
+
+    /// The end of the range as far as it is known, the end of the file if that comes first
+    pub fn end(&self) -> Option<u64> {
+        let end = self.remaining.map(|remaining| self.offset + remaining);
+        match (end, self.file_len) {
+            (Some(end), Some(file_len)) => Some(end.min(file_len)),
+            (end, file_len) => end.or(file_len),
+        }
+    }
+
+    /// The request for the next part of the range
+    pub fn read_chunk(&mut self) -> SmpFrame<FileDownloadRequest> {
+        (self.sequence, _) = self.sequence.overflowing_add(1);
+        download(self.sequence, self.name.clone(), self.offset)
+    }
+
+    /// Take the data of the response to [read_chunk](Self::read_chunk) that belongs to the
+    /// range and move on behind it.
+    ///
+    /// The range is complete once `len` bytes were read or the file ends, which is the case
+    /// when the device sends no data or the offset reaches the length of the file. Reading at
+    /// exactly the end of the file completes the range with no data.
+    pub fn handle_response<'r>(&mut self, response: &'r FileDownloadResult) -> ReadAction<'r> {
+        let (off, data, len) = match response {
+            FileDownloadResult::Ok { off, data, len } => (*off, data.as_slice(), *len),
+            FileDownloadResult::Err { rc } => return ReadAction::Fatal { rc: *rc },
+        };
+        if off != self.offset {
+            return ReadAction::InvalidOffset {
+                off,
+                expected: self.offset,
+            };
+        }
+        if len.is_some() {
+            self.file_len = len;
+        }
+
+        let take = match self.remaining {
+            Some(remaining) => remaining.min(data.len() as u64),
+            None => data.len() as u64,
+        };
+        let data = &data[..take as usize];
+        self.offset += take;
+        if let Some(remaining) = &mut self.remaining {
+            *remaining -= take;
+        }
+
+        self.eof = data.is_empty();
+        match self.is_done() {
+            true => ReadAction::Done { data },
+            false => ReadAction::Continue { data },
+        }
+    }
+}
+
+/// How to continue reading a range, see [RangeReader::handle_response]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ReadAction<'r> {
+    /// `data` is the next part of the range, more follows
+    Continue { data: &'r [u8] },
+    /// `data` is the last part of the range, it may be empty at the end of the file
+    Done { data: &'r [u8] },
+    /// The device rejected the request
+    Fatal { rc: i32 },
+    /// The device sent data of another offset than the requested one, e.g. in a stale
+    /// response. The offset of the reader is left unchanged
+    InvalidOffset { off: u64, expected: u64 },
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Hash)]
+pub struct FileUploadRequest<'d> {
+    pub off: u64,
+    #[serde(with = "serde_bytes")]
+    pub data: &'d [u8],
+    pub name: String,
+    /// total length of the file, only sent with the first chunk
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub len: Option<u64>,
+}
+
+impl Debug for FileUploadRequest<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("FileUploadRequest")
+            .field("off", &self.off)
+            .field("data", &HexBytes(self.data))
+            .field("name", &self.name)
+            .field("len", &self.len)
+            .finish()
+    }
+}
+
+/// Write a part of a file. The file is truncated by the chunk at offset 0, which also
+/// carries the total length.
+pub fn upload(
+    sequence: u8,
+    name: String,
+    off: u64,
+    data: &[u8],
+    len: Option<u64>,
+) -> SmpFrame<FileUploadRequest<'_>> {
+    let payload = FileUploadRequest {
+        off,
+        data,
+        name,
+        len,
+    };
+
+    SmpFrame::new(
+        WriteRequest,
+        sequence,
+        Group::FileManagement,
+        FsManagementCommand::File.into(),
+        payload,
+    )
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+#[serde(untagged)]
+pub enum FileUploadResult {
+    Ok {
+        off: u64,
+    },
+    Err {
+        #[serde(alias = "err", deserialize_with = "crate::smp::error_rc")]
+        rc: i32,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct FileStatusRequest {
+    pub name: String,
+}
+
+/// Query the length of a file
+pub fn status(sequence: u8, name: String) -> SmpFrame<FileStatusRequest> {
+    let payload = FileStatusRequest { name };
+
+    SmpFrame::new(
+        ReadRequest,
+        sequence,
+        Group::FileManagement,
+        FsManagementCommand::Status.into(),
+        payload,
+    )
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+#[serde(untagged)]
+pub enum FileStatusResult {
+    Ok {
+        len: u64,
+    },
+    Err {
+        #[serde(alias = "err", deserialize_with = "crate::smp::error_rc")]
+        rc: i32,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct FileHashRequest {
+    pub name: String,
+    /// hash or checksum type, e.g. `sha256` or `crc32`. The device picks one if omitted
+    #[serde(rename = "type", skip_serializing_if = "Option::is_none")]
+    pub type_: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub off: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub len: Option<u64>,
+}
+
+/// Let the device compute a hash or checksum of a complete file
+pub fn hash(sequence: u8, name: String, type_: Option<String>) -> SmpFrame<FileHashRequest> {
+    let payload = FileHashRequest {
+        name,
+        type_,
+        off: None,
+        len: None,
+    };
+
+    SmpFrame::new(
+        ReadRequest,
+        sequence,
+        Group::FileManagement,
+        FsManagementCommand::Hash.into(),
+        payload,
+    )
+}
+
+/// Hashes are sent as bytes, checksums as integers
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+#[serde(untagged)]
+pub enum HashOutput {
+    Hash(#[serde(with = "serde_bytes")] Vec<u8>),
+    Checksum(u64),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+#[serde(untagged)]
+pub enum FileHashResult {
+    Ok {
+        #[serde(rename = "type")]
+        type_: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        off: Option<u64>,
+        len: u64,
+        output: HashOutput,
+    },
+    Err {
+        #[serde(alias = "err", deserialize_with = "crate::smp::error_rc")]
+        rc: i32,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct SupportedHashesRequest {}
+
+/// Query the hash and checksum types the device supports for [hash]
+pub fn supported_hashes(sequence: u8) -> SmpFrame<SupportedHashesRequest> {
+    SmpFrame::new(
+        ReadRequest,
+        sequence,
+        Group::FileManagement,
+        FsManagementCommand::SupportedHashes.into(),
+        SupportedHashesRequest {},
+    )
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct HashType {
+    /// 0 if the output is an integer checksum, 1 if it is a byte string hash
+    pub format: u8,
+    /// size of the output in bytes
+    pub size: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+#[serde(untagged)]
+pub enum SupportedHashesResult {
+    Ok {
+        types: BTreeMap<String, HashType>,
+    },
+    Err {
+        #[serde(alias = "err", deserialize_with = "crate::smp::error_rc")]
+        rc: i32,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct FileCloseRequest {}
+
+/// Close the file the device keeps open after a transfer
+pub fn close(sequence: u8) -> SmpFrame<FileCloseRequest> {
+    SmpFrame::new(
+        WriteRequest,
+        sequence,
+        Group::FileManagement,
+        FsManagementCommand::Close.into(),
+        FileCloseRequest {},
+    )
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+#[serde(untagged)]
+pub enum FileCloseResult {
+    Err {
+        #[serde(alias = "err", deserialize_with = "crate::smp::error_rc")]
+        rc: i32,
+    },
+    Ok {},
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk(off: u64, data: &[u8], len: Option<u64>) -> FileDownloadResult {
+        FileDownloadResult::Ok {
+            off,
+            data: data.to_vec(),
+            len,
+        }
+    }
+
+    #[test]
+    fn read_ending_at_the_end_of_the_file() {
+        let mut reader = RangeReader::new("/lfs/log", 0, Some(8));
+
+        let first = chunk(0, &[0; 4], Some(8));
+        assert_eq!(
+            reader.handle_response(&first),
+            ReadAction::Continue { data: &[0; 4] }
+        );
+        assert_eq!(reader.end(), Some(8));
+
+        // the last byte of the range is the last byte of the file, no empty read follows
+        let last = chunk(4, &[1; 4], None);
+        assert_eq!(
+            reader.handle_response(&last),
+            ReadAction::Done { data: &[1; 4] }
+        );
+        assert_eq!(reader.offset, 8);
+        assert!(reader.is_done());
+    }
+
+    #[test]
+    fn file_shorter_than_the_range() {
+        let mut reader = RangeReader::new("/lfs/log", 0, Some(4096));
+
+        let response = chunk(0, &[7; 100], Some(100));
+        assert_eq!(
+            reader.handle_response(&response),
+            ReadAction::Done { data: &[7; 100] }
+        );
+        assert_eq!(reader.end(), Some(100));
+        assert_eq!(reader.remaining, Some(3996));
+    }
+
+    #[test]
+    fn file_shorter_than_the_range_without_len() {
+        let mut reader = RangeReader::new("/lfs/log", 50, Some(4096));
+
+        let response = chunk(50, &[7; 50], None);
+        assert_eq!(
+            reader.handle_response(&response),
+            ReadAction::Continue { data: &[7; 50] }
+        );
+        assert_eq!(
+            reader.handle_response(&chunk(100, &[], None)),
+            ReadAction::Done { data: &[] }
+        );
+        assert!(reader.is_done());
+    }
+
+    #[test]
+    fn read_starting_at_the_end_of_the_file() {
+        let mut reader = RangeReader::new("/lfs/log", 100, None);
+
+        assert_eq!(
+            reader.handle_response(&chunk(100, &[], None)),
+            ReadAction::Done { data: &[] }
+        );
+        assert_eq!(reader.offset, 100);
+    }
+
+    #[test]
+    fn oversized_chunk_is_cut_to_the_range() {
+        let mut reader = RangeReader::new("/lfs/log", 0, Some(3));
+
+        assert_eq!(
+            reader.handle_response(&chunk(0, &[1, 2, 3, 4, 5], Some(5))),
+            ReadAction::Done { data: &[1, 2, 3] }
+        );
+        assert_eq!(reader.offset, 3);
+        assert_eq!(reader.remaining, Some(0));
+    }
+
+    #[test]
+    fn stale_offset_is_invalid() {
+        let mut reader = RangeReader::new("/lfs/log", 0, None);
+        reader.handle_response(&chunk(0, &[0; 16], Some(64)));
+
+        assert_eq!(
+            reader.handle_response(&chunk(0, &[0; 16], Some(64))),
+            ReadAction::InvalidOffset {
+                off: 0,
+                expected: 16
+            }
+        );
+        assert_eq!(reader.offset, 16);
+        assert_eq!(
+            reader.handle_response(&FileDownloadResult::Err { rc: 5 }),
+            ReadAction::Fatal { rc: 5 }
+        );
+    }
+
+    #[test]
+    fn requests_count_the_sequence_up() {
+        let mut reader = RangeReader::new("/lfs/log", 32, None);
+        let first = reader.read_chunk();
+        let second = reader.read_chunk();
+        assert_eq!(first.data.off, 32);
+        assert_eq!(second.sequence, first.sequence.wrapping_add(1));
+    }
+}
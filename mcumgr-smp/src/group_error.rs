@@ -0,0 +1,276 @@
+use crate::Group;
+
+/// Defines an enum of the error codes of a group, with their names as a table
+macro_rules! group_error_codes {
+    (
+        $(#[$meta:meta])*
+        $name:ident {
+            $($variant:ident = $code:literal => $text:literal,)*
+        }
+    ) => {
+        $(#[$meta])*
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+        pub enum $name {
+            $($variant = $code,)*
+        }
+
+        impl $name {
+            /// Every code with its name as used by Zephyr
+            pub const TABLE: &'static [(i32, &'static str)] = &[$(($code, $text),)*];
+
+            /// Name of the error code as used by Zephyr
+            pub fn name(&self) -> &'static str {
+                match self {
+                    $($name::$variant => $text,)*
+                }
+            }
+        }
+
+        impl TryFrom<i32> for $name {
+            type Error = i32;
+
+            fn try_from(code: i32) -> Result<Self, Self::Error> {
+                Ok(match code {
+                    $($code => $name::$variant,)*
+                    code => return Err(code),
+                })
+            }
+        }
+
+        impl std::fmt::Display for $name {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                f.write_str(self.name())
+            }
+        }
+    };
+}
+
+group_error_codes! {
+    /// Error codes of the OS management group, from Zephyr's `os_mgmt.h`
+    OsMgmtError {
+        Ok = 0 => "OS_MGMT_ERR_OK",
+        Unknown = 1 => "OS_MGMT_ERR_UNKNOWN",
+        InvalidFormat = 2 => "OS_MGMT_ERR_INVALID_FORMAT",
+        QueryYieldsNoAnswer = 3 => "OS_MGMT_ERR_QUERY_YIELDS_NO_ANSWER",
+        RtcNotSet = 4 => "OS_MGMT_ERR_RTC_NOT_SET",
+        RtcCommandFailed = 5 => "OS_MGMT_ERR_RTC_COMMAND_FAILED",
+        QueryResponseValueNotValid = 6 => "OS_MGMT_ERR_QUERY_RESPONSE_VALUE_NOT_VALID",
+    }
+}
+
+group_error_codes! {
+    /// Error codes of the image management group, from Zephyr's `img_mgmt.h`
+    ImgMgmtError {
+        Ok = 0 => "IMG_MGMT_ERR_OK",
+        Unknown = 1 => "IMG_MGMT_ERR_UNKNOWN",
+        FlashConfigQueryFail = 2 => "IMG_MGMT_ERR_FLASH_CONFIG_QUERY_FAIL",
+        NoImage = 3 => "IMG_MGMT_ERR_NO_IMAGE",
+        NoTlvs = 4 => "IMG_MGMT_ERR_NO_TLVS",
+        InvalidTlv = 5 => "IMG_MGMT_ERR_INVALID_TLV",
+        TlvMultipleHashesFound = 6 => "IMG_MGMT_ERR_TLV_MULTIPLE_HASHES_FOUND",
+        TlvInvalidSize = 7 => "IMG_MGMT_ERR_TLV_INVALID_SIZE",
+        HashNotFound = 8 => "IMG_MGMT_ERR_HASH_NOT_FOUND",
+        NoFreeSlot = 9 => "IMG_MGMT_ERR_NO_FREE_SLOT",
+        FlashOpenFailed = 10 => "IMG_MGMT_ERR_FLASH_OPEN_FAILED",
+        FlashReadFailed = 11 => "IMG_MGMT_ERR_FLASH_READ_FAILED",
+        FlashWriteFailed = 12 => "IMG_MGMT_ERR_FLASH_WRITE_FAILED",
+        FlashEraseFailed = 13 => "IMG_MGMT_ERR_FLASH_ERASE_FAILED",
+        InvalidSlot = 14 => "IMG_MGMT_ERR_INVALID_SLOT",
+        NoFreeMemory = 15 => "IMG_MGMT_ERR_NO_FREE_MEMORY",
+        FlashContextAlreadySet = 16 => "IMG_MGMT_ERR_FLASH_CONTEXT_ALREADY_SET",
+        FlashContextNotSet = 17 => "IMG_MGMT_ERR_FLASH_CONTEXT_NOT_SET",
+        FlashAreaDeviceNull = 18 => "IMG_MGMT_ERR_FLASH_AREA_DEVICE_NULL",
+        InvalidPageOffset = 19 => "IMG_MGMT_ERR_INVALID_PAGE_OFFSET",
+        InvalidOffset = 20 => "IMG_MGMT_ERR_INVALID_OFFSET",
+        InvalidLength = 21 => "IMG_MGMT_ERR_INVALID_LENGTH",
+        InvalidImageHeader = 22 => "IMG_MGMT_ERR_INVALID_IMAGE_HEADER",
+        InvalidImageHeaderMagic = 23 => "IMG_MGMT_ERR_INVALID_IMAGE_HEADER_MAGIC",
+        InvalidHash = 24 => "IMG_MGMT_ERR_INVALID_HASH",
+        InvalidFlashAddress = 25 => "IMG_MGMT_ERR_INVALID_FLASH_ADDRESS",
+        VersionGetFailed = 26 => "IMG_MGMT_ERR_VERSION_GET_FAILED",
+        CurrentVersionIsNewer = 27 => "IMG_MGMT_ERR_CURRENT_VERSION_IS_NEWER",
+        ImageAlreadyPending = 28 => "IMG_MGMT_ERR_IMAGE_ALREADY_PENDING",
+        InvalidImageVectorTable = 29 => "IMG_MGMT_ERR_INVALID_IMAGE_VECTOR_TABLE",
+        InvalidImageTooLarge = 30 => "IMG_MGMT_ERR_INVALID_IMAGE_TOO_LARGE",
+        InvalidImageDataOverrun = 31 => "IMG_MGMT_ERR_INVALID_IMAGE_DATA_OVERRUN",
+        ImageConfirmationDenied = 32 => "IMG_MGMT_ERR_IMAGE_CONFIRMATION_DENIED",
+        ImageSettingTestToActiveDenied = 33 => "IMG_MGMT_ERR_IMAGE_SETTING_TEST_TO_ACTIVE_DENIED",
+        ActiveSlotNotKnown = 34 => "IMG_MGMT_ERR_ACTIVE_SLOT_NOT_KNOWN",
+    }
+}
+
+group_error_codes! {
+    /// Error codes of the settings management group, from Zephyr's `settings_mgmt.h`
+    SettingsMgmtError {
+        Ok = 0 => "SETTINGS_MGMT_ERR_OK",
+        Unknown = 1 => "SETTINGS_MGMT_ERR_UNKNOWN",
+        KeyTooLong = 2 => "SETTINGS_MGMT_ERR_KEY_TOO_LONG",
+        KeyNotFound = 3 => "SETTINGS_MGMT_ERR_KEY_NOT_FOUND",
+        ReadNotSupported = 4 => "SETTINGS_MGMT_ERR_READ_NOT_SUPPORTED",
+        RootKeyNotFound = 5 => "SETTINGS_MGMT_ERR_ROOT_KEY_NOT_FOUND",
+        WriteNotSupported = 6 => "SETTINGS_MGMT_ERR_WRITE_NOT_SUPPORTED",
+        DeleteNotSupported = 7 => "SETTINGS_MGMT_ERR_DELETE_NOT_SUPPORTED",
+        SaveFailed = 8 => "SETTINGS_MGMT_ERR_SAVE_FAILED",
+    }
+}
+
+group_error_codes! {
+    /// Error codes of the file management group, from Zephyr's `fs_mgmt.h`
+    FsMgmtError {
+        Ok = 0 => "FS_MGMT_ERR_OK",
+        Unknown = 1 => "FS_MGMT_ERR_UNKNOWN",
+        FileInvalidName = 2 => "FS_MGMT_ERR_FILE_INVALID_NAME",
+        FileNotFound = 3 => "FS_MGMT_ERR_FILE_NOT_FOUND",
+        FileIsDirectory = 4 => "FS_MGMT_ERR_FILE_IS_DIRECTORY",
+        FileOpenFailed = 5 => "FS_MGMT_ERR_FILE_OPEN_FAILED",
+        FileSeekFailed = 6 => "FS_MGMT_ERR_FILE_SEEK_FAILED",
+        FileReadFailed = 7 => "FS_MGMT_ERR_FILE_READ_FAILED",
+        FileTruncateFailed = 8 => "FS_MGMT_ERR_FILE_TRUNCATE_FAILED",
+        FileDeleteFailed = 9 => "FS_MGMT_ERR_FILE_DELETE_FAILED",
+        FileWriteFailed = 10 => "FS_MGMT_ERR_FILE_WRITE_FAILED",
+        FileOffsetNotValid = 11 => "FS_MGMT_ERR_FILE_OFFSET_NOT_VALID",
+        FileOffsetLargerThanFile = 12 => "FS_MGMT_ERR_FILE_OFFSET_LARGER_THAN_FILE",
+        ChecksumHashNotFound = 13 => "FS_MGMT_ERR_CHECKSUM_HASH_NOT_FOUND",
+        MountPointNotFound = 14 => "FS_MGMT_ERR_MOUNT_POINT_NOT_FOUND",
+        ReadOnlyFilesystem = 15 => "FS_MGMT_ERR_READ_ONLY_FILESYSTEM",
+        FileEmpty = 16 => "FS_MGMT_ERR_FILE_EMPTY",
+    }
+}
+
+/// Name of the group as used in Zephyr's error code prefixes, e.g. `img_mgmt`
+pub fn group_mgmt_name(group: Group) -> Option<&'static str> {
+    match group {
+        Group::Default => Some("os_mgmt"),
+        Group::ApplicationManagement => Some("img_mgmt"),
+        Group::SettingManagement => Some("settings_mgmt"),
+        Group::FileManagement => Some("fs_mgmt"),
+        _ => None,
+    }
+}
+
+/// Name of an error code that a group reports in SMP version 2 responses, e.g.
+/// `IMG_MGMT_ERR_INVALID_IMAGE_TOO_LARGE`. `None` for groups and codes that aren't known.
+pub fn decode_group_error(group: Group, code: i32) -> Option<&'static str> {
+    let table = match group {
+        Group::Default => OsMgmtError::TABLE,
+        Group::ApplicationManagement => ImgMgmtError::TABLE,
+        Group::SettingManagement => SettingsMgmtError::TABLE,
+        Group::FileManagement => FsMgmtError::TABLE,
+        _ => return None,
+    };
+    table
+        .iter()
+        .find(|(known, _)| *known == code)
+        .map(|(_, name)| *name)
+}
+
+/// A group error for display, e.g. `img_mgmt: IMG_MGMT_ERR_INVALID_IMAGE_TOO_LARGE (group 1,
+/// code 30)`, or just the numbers if the code isn't known
+pub(crate) fn format_group_error(group: Group, code: i32) -> String {
+    match (group_mgmt_name(group), decode_group_error(group, code)) {
+        (Some(group_name), Some(name)) => format!(
+            "{}: {} (group {}, code {})",
+            group_name,
+            name,
+            u16::from(group),
+            code
+        ),
+        _ => format!("group {}, code {}", u16::from(group), code),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::smp::SmpError;
+
+    #[test]
+    fn known_codes() {
+        for (group, code, name) in [
+            (Group::Default, 4, "OS_MGMT_ERR_RTC_NOT_SET"),
+            (
+                Group::ApplicationManagement,
+                10,
+                "IMG_MGMT_ERR_FLASH_OPEN_FAILED",
+            ),
+            (
+                Group::ApplicationManagement,
+                30,
+                "IMG_MGMT_ERR_INVALID_IMAGE_TOO_LARGE",
+            ),
+            (
+                Group::ApplicationManagement,
+                34,
+                "IMG_MGMT_ERR_ACTIVE_SLOT_NOT_KNOWN",
+            ),
+            (
+                Group::SettingManagement,
+                3,
+                "SETTINGS_MGMT_ERR_KEY_NOT_FOUND",
+            ),
+            (Group::FileManagement, 3, "FS_MGMT_ERR_FILE_NOT_FOUND"),
+            (Group::FileManagement, 16, "FS_MGMT_ERR_FILE_EMPTY"),
+        ] {
+            assert_eq!(
+                decode_group_error(group, code),
+                Some(name),
+                "{group:?} {code}"
+            );
+        }
+        assert_eq!(
+            ImgMgmtError::try_from(30),
+            Ok(ImgMgmtError::InvalidImageTooLarge)
+        );
+        assert_eq!(
+            ImgMgmtError::InvalidImageTooLarge.to_string(),
+            "IMG_MGMT_ERR_INVALID_IMAGE_TOO_LARGE"
+        );
+    }
+
+    #[test]
+    fn tables_are_numbered_without_gaps() {
+        for (prefix, table) in [
+            ("OS_MGMT_ERR_", OsMgmtError::TABLE),
+            ("IMG_MGMT_ERR_", ImgMgmtError::TABLE),
+            ("SETTINGS_MGMT_ERR_", SettingsMgmtError::TABLE),
+            ("FS_MGMT_ERR_", FsMgmtError::TABLE),
+        ] {
+            for (index, (code, name)) in table.iter().enumerate() {
+                assert_eq!(*code, index as i32, "{name}");
+                assert!(name.starts_with(prefix), "{name}");
+            }
+        }
+    }
+
+    #[test]
+    fn unknown_codes_and_groups() {
+        assert_eq!(decode_group_error(Group::ApplicationManagement, 99), None);
+        assert_eq!(decode_group_error(Group::Custom(64), 1), None);
+        assert_eq!(decode_group_error(Group::Statistics, 1), None);
+        assert_eq!(ImgMgmtError::try_from(99), Err(99));
+
+        assert_eq!(
+            format_group_error(Group::ApplicationManagement, 99),
+            "group 1, code 99"
+        );
+        assert_eq!(format_group_error(Group::Custom(64), 2), "group 64, code 2");
+    }
+
+    #[test]
+    fn smp_error_display() {
+        let error = SmpError::GroupError {
+            group: Group::ApplicationManagement,
+            code: 30,
+        };
+        assert_eq!(
+            error.to_string(),
+            "img_mgmt: IMG_MGMT_ERR_INVALID_IMAGE_TOO_LARGE (group 1, code 30)"
+        );
+
+        let error = SmpError::GroupError {
+            group: Group::Custom(64),
+            code: 7,
+        };
+        assert_eq!(error.to_string(), "group 64, code 7");
+    }
+}
@@ -16,17 +16,35 @@
 //! [SmpFrame] is implemented in such a way that it uses raw bytes (i.e. [Vec]) to encode or decode
 //! messages. You can handle this conversion yourself and send these bytes over any channel.
 
+#[cfg(all(
+    feature = "async",
+    not(any(feature = "runtime-tokio", feature = "runtime-smol"))
+))]
+compile_error!("the async transports need a runtime, enable `runtime-tokio` or `runtime-smol`");
+
 /// Implementation of a general [SmpFrame] that can have any payload.
 pub mod smp;
 
+/// Error codes of the groups in SMP version 2 responses
+pub mod group_error;
+
+#[cfg(feature = "payload-cbor")]
+mod cbor;
+
 #[cfg(feature = "payload-cbor")]
 pub mod application_management;
 #[cfg(feature = "payload-cbor")]
+pub mod fs_management;
+#[cfg(feature = "payload-cbor")]
+pub mod log_management;
+#[cfg(feature = "payload-cbor")]
 pub mod os_management;
 #[cfg(feature = "payload-cbor")]
 pub mod shell_management;
 #[cfg(feature = "payload-cbor")]
 pub mod setting_management;
+#[cfg(feature = "payload-cbor")]
+pub mod stat_management;
 
 /// Implementations over Serial, BLE and UDP transports
 pub mod transport;
@@ -0,0 +1,500 @@
+use crate::{Group, SmpFrame};
+
+use crate::OpCode::{ReadRequest, WriteRequest};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+pub enum LogManagementCommand {
+    Show,
+    Clear,
+    ModuleList,
+    LevelList,
+    LogList,
+}
+
+impl From<LogManagementCommand> for u8 {
+    fn from(cmd: LogManagementCommand) -> Self {
+        match cmd {
+            LogManagementCommand::Show => 0,
+            LogManagementCommand::Clear => 1,
+            LogManagementCommand::ModuleList => 3,
+            LogManagementCommand::LevelList => 4,
+            LogManagementCommand::LogList => 5,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ShowRequest {
+    /// only show this log, all logs if omitted
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub log_name: Option<String>,
+    /// only show entries with an index of at least this
+    pub index: u32,
+}
+
+/// Read log entries starting at `index`.
+///
+/// The response is limited by the buffer size of the device, so it may end before the
+/// newest entry. `next_index` in the response is the index the next new entry will get.
+pub fn show(sequence: u8, log_name: Option<String>, index: u32) -> SmpFrame<ShowRequest> {
+    let payload = ShowRequest { log_name, index };
+
+    SmpFrame::new(
+        ReadRequest,
+        sequence,
+        Group::LogManagement,
+        LogManagementCommand::Show.into(),
+        payload,
+    )
+}
+
+/// Log messages are text, or CBOR encoded bytes for binary logs
+#[derive(Serialize, Debug, Clone, PartialEq, Eq, Hash)]
+#[serde(untagged)]
+pub enum LogMessage {
+    Text(String),
+    Binary(#[serde(with = "serde_bytes")] Vec<u8>),
+}
+
+/// Told apart by the CBOR major type, an untagged enum would take bytes that are valid UTF-8
+/// for text
+impl<'de> Deserialize<'de> for LogMessage {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct MessageVisitor;
+
+        impl serde::de::Visitor<'_> for MessageVisitor {
+            type Value = LogMessage;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                f.write_str("a text or byte string")
+            }
+
+            fn visit_str<E: serde::de::Error>(self, v: &str) -> Result<LogMessage, E> {
+                Ok(LogMessage::Text(v.to_string()))
+            }
+
+            fn visit_string<E: serde::de::Error>(self, v: String) -> Result<LogMessage, E> {
+                Ok(LogMessage::Text(v))
+            }
+
+            fn visit_bytes<E: serde::de::Error>(self, v: &[u8]) -> Result<LogMessage, E> {
+                Ok(LogMessage::Binary(v.to_vec()))
+            }
+
+            fn visit_byte_buf<E: serde::de::Error>(self, v: Vec<u8>) -> Result<LogMessage, E> {
+                Ok(LogMessage::Binary(v))
+            }
+        }
+
+        deserializer.deserialize_any(MessageVisitor)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct LogEntry {
+    pub msg: LogMessage,
+    /// microseconds since the epoch, or since boot on devices without a clock
+    pub ts: i64,
+    pub level: u8,
+    pub index: u32,
+    pub module: u16,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Log {
+    pub name: String,
+    #[serde(rename = "type", default)]
+    pub type_: Option<i32>,
+    pub entries: Vec<LogEntry>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+#[serde(untagged)]
+pub enum ShowResult {
+    Ok {
+        next_index: u32,
+        logs: Vec<Log>,
+    },
+    Err {
+        #[serde(alias = "err", deserialize_with = "crate::smp::error_rc")]
+        rc: i32,
+    },
+}
+
+/// An item of a batch read by [LogReader]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum LogItem {
+    /// The next entry, of the log with the given name
+    Entry { log: String, entry: LogEntry },
+    /// `missed` entries were overwritten in the buffer of the device before they were read
+    Gap { missed: u32 },
+    /// The index went backwards, the device rebooted or the logs were cleared. Reading starts
+    /// over at index 0
+    Restarted,
+}
+
+/// Reads the entries of the logs in order, e.g. to follow them.
+///
+/// The reader keeps the index to continue at and the newest index read per log, so no entry
+/// is returned twice. Entries that the device overwrote before they could be read are
+/// reported as [LogItem::Gap], not skipped silently. The index is shared by all logs, so gaps
+/// are only visible when reading all of them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogReader {
+    /// only read this log, all logs if `None`
+    pub log_name: Option<String>,
+    /// index the next batch starts at
+    pub index: u32,
+    pub sequence: u8,
+    /// newest index read per log
+    cursors: BTreeMap<String, u32>,
+    /// the last response was limited by the buffer size of the device
+    more: bool,
+    /// no batch was read yet, entries before the first index aren't missed
+    first: bool,
+}
+
+impl LogReader {
+    /// Read the entries from `index` on
+    pub fn new(log_name: Option<String>, index: u32) -> Self {
+        LogReader {
+            log_name,
+            index,
+            sequence: 0,
+            cursors: BTreeMap::new(),
+            more: false,
+            first: true,
+        }
+    }
+
+    /// The request for the next batch of entries
+    pub fn next_batch(&mut self) -> SmpFrame<ShowRequest> {
+        (self.sequence, _) = self.sequence.overflowing_add(1);
+        show(self.sequence, self.log_name.clone(), self.index)
+    }
+
+    /// Whether the device has more entries than fit into the last response, so the next batch
+    /// can be read right away instead of waiting for new entries
+    pub fn has_more(&self) -> bool {
+        self.more
+    }
+
+    /// Sort the entries of the response to [next_batch](Self::next_batch) by index and move on
+    /// behind the newest one. Returns the error of the device if it rejected the request.
+    ///
+    /// A gap is reported when the oldest entry returned is newer than the index requested,
+    /// except for the first batch.
+    pub fn handle_response(&mut self, response: &ShowResult) -> Result<Vec<LogItem>, i32> {
+        let (next_index, logs) = match response {
+            ShowResult::Ok { next_index, logs } => (*next_index, logs),
+            ShowResult::Err { rc } => return Err(*rc),
+        };
+
+        if next_index < self.index {
+            self.index = 0;
+            self.cursors.clear();
+            self.more = true;
+            return Ok(vec![LogItem::Restarted]);
+        }
+
+        let mut entries: Vec<(&str, &LogEntry)> = logs
+            .iter()
+            .flat_map(|log| log.entries.iter().map(|entry| (log.name.as_str(), entry)))
+            .filter(|(name, entry)| {
+                self.cursors
+                    .get(*name)
+                    .is_none_or(|&seen| entry.index > seen)
+            })
+            .collect();
+        entries.sort_by_key(|(_, entry)| entry.index);
+
+        let mut items = Vec::with_capacity(entries.len() + 1);
+        let oldest = entries.first().map_or(next_index, |(_, entry)| entry.index);
+        if !self.first && self.log_name.is_none() && oldest > self.index {
+            items.push(LogItem::Gap {
+                missed: oldest - self.index,
+            });
+        }
+        self.first = false;
+
+        let mut newest = None;
+        for (name, entry) in entries {
+            newest = newest.max(Some(entry.index));
+            let seen = self.cursors.entry(name.to_string()).or_insert(entry.index);
+            *seen = (*seen).max(entry.index);
+            items.push(LogItem::Entry {
+                log: name.to_string(),
+                entry: entry.clone(),
+            });
+        }
+
+        self.index = match newest {
+            Some(newest) => newest + 1,
+            None => next_index,
+        };
+        self.more = newest.is_some() && self.index < next_index;
+
+        Ok(items)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ClearRequest {}
+
+/// Delete the entries of all logs
+pub fn clear(sequence: u8) -> SmpFrame<ClearRequest> {
+    SmpFrame::new(
+        WriteRequest,
+        sequence,
+        Group::LogManagement,
+        LogManagementCommand::Clear.into(),
+        ClearRequest {},
+    )
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+#[serde(untagged)]
+pub enum ClearResult {
+    Err {
+        #[serde(alias = "err", deserialize_with = "crate::smp::error_rc")]
+        rc: i32,
+    },
+    Ok {},
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ModuleListRequest {}
+
+/// Query the names of the log modules
+pub fn module_list(sequence: u8) -> SmpFrame<ModuleListRequest> {
+    SmpFrame::new(
+        ReadRequest,
+        sequence,
+        Group::LogManagement,
+        LogManagementCommand::ModuleList.into(),
+        ModuleListRequest {},
+    )
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+#[serde(untagged)]
+pub enum ModuleListResult {
+    Ok {
+        module_map: BTreeMap<String, u16>,
+    },
+    Err {
+        #[serde(alias = "err", deserialize_with = "crate::smp::error_rc")]
+        rc: i32,
+    },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(index: u32) -> LogEntry {
+        LogEntry {
+            msg: LogMessage::Text(format!("entry {}", index)),
+            ts: index as i64,
+            level: 1,
+            index,
+            module: 0,
+        }
+    }
+
+    fn response(next_index: u32, logs: &[(&str, &[u32])]) -> ShowResult {
+        ShowResult::Ok {
+            next_index,
+            logs: logs
+                .iter()
+                .map(|(name, indices)| Log {
+                    name: name.to_string(),
+                    type_: None,
+                    entries: indices.iter().copied().map(entry).collect(),
+                })
+                .collect(),
+        }
+    }
+
+    /// The indices of the entries and the sizes of the gaps, in the order they are returned
+    fn read(reader: &mut LogReader, response: &ShowResult) -> Vec<String> {
+        reader.next_batch();
+        reader
+            .handle_response(response)
+            .unwrap()
+            .into_iter()
+            .map(|item| match item {
+                LogItem::Entry { log, entry } => format!("{}:{}", log, entry.index),
+                LogItem::Gap { missed } => format!("gap {}", missed),
+                LogItem::Restarted => "restarted".to_string(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn consecutive_batches_have_no_gap() {
+        let mut reader = LogReader::new(None, 0);
+
+        assert_eq!(
+            read(&mut reader, &response(3, &[("log", &[0, 1, 2])])),
+            ["log:0", "log:1", "log:2"]
+        );
+        assert_eq!(reader.index, 3);
+        assert_eq!(
+            read(&mut reader, &response(5, &[("log", &[3, 4])])),
+            ["log:3", "log:4"]
+        );
+        assert_eq!(reader.index, 5);
+        assert!(!reader.has_more());
+    }
+
+    #[test]
+    fn first_batch_has_no_gap() {
+        let mut reader = LogReader::new(None, 0);
+
+        assert_eq!(
+            read(&mut reader, &response(12, &[("log", &[10, 11])])),
+            ["log:10", "log:11"]
+        );
+    }
+
+    #[test]
+    fn overwritten_entries_are_a_gap() {
+        let mut reader = LogReader::new(None, 0);
+        read(&mut reader, &response(3, &[("log", &[0, 1, 2])]));
+
+        // 3 to 6 were overwritten before they were read
+        assert_eq!(
+            read(&mut reader, &response(9, &[("log", &[7, 8])])),
+            ["gap 4", "log:7", "log:8"]
+        );
+        assert_eq!(reader.index, 9);
+    }
+
+    #[test]
+    fn gap_in_an_empty_batch() {
+        let mut reader = LogReader::new(None, 0);
+        read(&mut reader, &response(2, &[("log", &[0, 1])]));
+
+        assert_eq!(read(&mut reader, &response(6, &[("log", &[])])), ["gap 4"]);
+        assert_eq!(reader.index, 6);
+    }
+
+    #[test]
+    fn entries_of_all_logs_are_sorted_by_index() {
+        let mut reader = LogReader::new(None, 0);
+
+        assert_eq!(
+            read(
+                &mut reader,
+                &response(5, &[("a", &[0, 3]), ("b", &[1, 2, 4])])
+            ),
+            ["a:0", "b:1", "b:2", "a:3", "b:4"]
+        );
+    }
+
+    #[test]
+    fn entries_are_not_returned_twice() {
+        let mut reader = LogReader::new(None, 0);
+        read(&mut reader, &response(3, &[("log", &[0, 1, 2])]));
+
+        // a device that ignores the index returns the old entries again
+        assert_eq!(
+            read(&mut reader, &response(4, &[("log", &[1, 2, 3])])),
+            ["log:3"]
+        );
+    }
+
+    #[test]
+    fn limited_response_has_more() {
+        let mut reader = LogReader::new(None, 0);
+
+        assert_eq!(
+            read(&mut reader, &response(10, &[("log", &[0, 1])])),
+            ["log:0", "log:1"]
+        );
+        assert!(reader.has_more());
+        assert_eq!(reader.index, 2);
+
+        // the rest is no gap
+        assert_eq!(
+            read(&mut reader, &response(10, &[("log", &[2, 3])])),
+            ["log:2", "log:3"]
+        );
+    }
+
+    #[test]
+    fn single_log_has_no_gaps() {
+        let mut reader = LogReader::new(Some("log".to_string()), 0);
+        read(&mut reader, &response(2, &[("log", &[0, 1])]));
+
+        // the entries in between may belong to other logs
+        assert_eq!(read(&mut reader, &response(8, &[("log", &[7])])), ["log:7"]);
+    }
+
+    #[test]
+    fn index_going_back_restarts() {
+        let mut reader = LogReader::new(None, 0);
+        read(&mut reader, &response(5, &[("log", &[3, 4])]));
+
+        assert_eq!(
+            read(&mut reader, &response(2, &[("log", &[0, 1])])),
+            ["restarted"]
+        );
+        assert_eq!(reader.index, 0);
+        assert_eq!(
+            read(&mut reader, &response(2, &[("log", &[0, 1])])),
+            ["log:0", "log:1"]
+        );
+    }
+
+    #[test]
+    fn device_error_is_returned() {
+        let mut reader = LogReader::new(None, 0);
+        reader.next_batch();
+
+        assert_eq!(reader.handle_response(&ShowResult::Err { rc: 8 }), Err(8));
+    }
+
+    #[test]
+    fn binary_messages_stay_bytes() {
+        let entry = |msg| {
+            ciborium::Value::Map(vec![
+                ("msg".into(), msg),
+                ("ts".into(), 0.into()),
+                ("level".into(), 1.into()),
+                ("index".into(), 0.into()),
+                ("module".into(), 0.into()),
+            ])
+        };
+        let show: ShowResult = crate::cbor::decode_value(ciborium::Value::Map(vec![
+            ("next_index".into(), 2.into()),
+            (
+                "logs".into(),
+                ciborium::Value::Array(vec![ciborium::Value::Map(vec![
+                    ("name".into(), "log".into()),
+                    (
+                        "entries".into(),
+                        ciborium::Value::Array(vec![
+                            entry(ciborium::Value::Bytes(b"abc".to_vec())),
+                            entry(ciborium::Value::Text("abc".into())),
+                        ]),
+                    ),
+                ])]),
+            ),
+        ]));
+
+        let ShowResult::Ok { logs, .. } = show else {
+            panic!("{:?}", show);
+        };
+        let msgs: Vec<_> = logs[0].entries.iter().map(|e| &e.msg).collect();
+        assert_eq!(
+            msgs,
+            [
+                &LogMessage::Binary(b"abc".to_vec()),
+                &LogMessage::Text("abc".into())
+            ]
+        );
+    }
+}
@@ -1,11 +1,14 @@
 // Author: Sascha Zenglein <zenglein@gessler.de>
 // Copyright (c) 2023 Gessler GmbH.
-use crate::{Group, SmpFrame};
+use crate::smp::ErrorCode;
+use crate::{ExtraFields, Group, SmpFrame};
 
 use crate::OpCode::{ReadRequest, WriteRequest};
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::time::Duration;
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
 pub struct EchoRequest {
     pub d: String,
 }
@@ -15,32 +18,335 @@ pub fn echo(sequence: u8, msg: String) -> SmpFrame<EchoRequest> {
     SmpFrame::new(WriteRequest, sequence, Group::Default, 0, payload)
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+/// The echoed text, or the error of the device. Decoded by the error code, see
+/// [deserialize_result](crate::smp::deserialize_result)
+#[derive(Serialize, Debug, Clone, PartialEq, Eq, Hash)]
 #[serde(untagged)]
 pub enum EchoResult {
     Ok { r: String },
     Err { rc: i32 },
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+impl<'de> Deserialize<'de> for EchoResult {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        struct Echo {
+            r: String,
+        }
+
+        Ok(
+            match crate::smp::deserialize_result::<_, Echo, ErrorCode>(deserializer)? {
+                Ok(Echo { r }) => EchoResult::Ok { r },
+                Err(ErrorCode { rc }) => EchoResult::Err { rc },
+            },
+        )
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct TaskStatsRequest {}
+
+/// Query the statistics of all tasks
+pub fn task_stats(sequence: u8) -> SmpFrame<TaskStatsRequest> {
+    SmpFrame::new(
+        ReadRequest,
+        sequence,
+        Group::Default,
+        2,
+        TaskStatsRequest {},
+    )
+}
+
+/// Statistics of a single task. Devices leave out what they don't measure
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash, Default)]
+#[serde(default)]
+pub struct TaskStats {
+    pub prio: Option<i64>,
+    pub tid: Option<u64>,
+    pub state: Option<u64>,
+    /// used stack in 4 byte words
+    pub stkuse: Option<u64>,
+    /// stack size in 4 byte words
+    pub stksiz: Option<u64>,
+    pub cswcnt: Option<u64>,
+    pub runtime: Option<u64>,
+    pub last_checkin: Option<u64>,
+    pub next_checkin: Option<u64>,
+}
+
+/// The statistics by task name, or the error of the device. Decoded by the error code, see
+/// [deserialize_result](crate::smp::deserialize_result)
+#[derive(Serialize, Debug, Clone, PartialEq, Eq, Hash)]
+#[serde(untagged)]
+pub enum TaskStatsResult {
+    Ok { tasks: BTreeMap<String, TaskStats> },
+    Err { rc: i32 },
+}
+
+impl<'de> Deserialize<'de> for TaskStatsResult {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        struct Tasks {
+            tasks: BTreeMap<String, TaskStats>,
+        }
+
+        Ok(
+            match crate::smp::deserialize_result::<_, Tasks, ErrorCode>(deserializer)? {
+                Ok(Tasks { tasks }) => TaskStatsResult::Ok { tasks },
+                Err(ErrorCode { rc }) => TaskStatsResult::Err { rc },
+            },
+        )
+    }
+}
+
+/// Like [TaskStatsResult], with the task names borrowed from the response, see
+/// [SmpFrame::decode_with_cbor_borrowed]
+#[cfg(feature = "payload-cbor-borrowed")]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+#[serde(untagged)]
+pub enum TaskStatsResultRef<'a> {
+    Ok {
+        #[serde(borrow)]
+        tasks: BTreeMap<&'a str, TaskStats>,
+    },
+    Err {
+        #[serde(alias = "err", deserialize_with = "crate::smp::error_rc")]
+        rc: i32,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct GetDateTimeRequest {}
+
+/// Read the clock of the device
+pub fn get_datetime(sequence: u8) -> SmpFrame<GetDateTimeRequest> {
+    SmpFrame::new(
+        ReadRequest,
+        sequence,
+        Group::Default,
+        4,
+        GetDateTimeRequest {},
+    )
+}
+
+/// The clock of the device, or its error. Decoded by the error code, see
+/// [deserialize_result](crate::smp::deserialize_result)
+#[derive(Serialize, Debug, Clone, PartialEq, Eq, Hash)]
+#[serde(untagged)]
+pub enum GetDateTimeResult {
+    Ok { datetime: String },
+    Err { rc: i32 },
+}
+
+impl<'de> Deserialize<'de> for GetDateTimeResult {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        struct DateTime {
+            datetime: String,
+        }
+
+        Ok(
+            match crate::smp::deserialize_result::<_, DateTime, ErrorCode>(deserializer)? {
+                Ok(DateTime { datetime }) => GetDateTimeResult::Ok { datetime },
+                Err(ErrorCode { rc }) => GetDateTimeResult::Err { rc },
+            },
+        )
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct SetDateTimeRequest {
+    /// `yyyy-MM-ddTHH:mm:ss`, optionally with fractional seconds
+    pub datetime: String,
+}
+
+/// Set the clock of the device
+pub fn set_datetime(sequence: u8, datetime: String) -> SmpFrame<SetDateTimeRequest> {
+    let payload = SetDateTimeRequest { datetime };
+
+    SmpFrame::new(WriteRequest, sequence, Group::Default, 4, payload)
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+#[serde(untagged)]
+pub enum SetDateTimeResult {
+    Err {
+        #[serde(alias = "err", deserialize_with = "crate::smp::error_rc")]
+        rc: i32,
+    },
+    Ok {},
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
 pub struct GetInfoRequest {
     pub format: String,
 }
 
+/// Query OS and application information, like `uname`. `format` selects the fields, e.g. `s`
+/// for the kernel name or `a` for all of them
 pub fn get_info(sequence: u8, format: String) -> SmpFrame<GetInfoRequest> {
     let request = GetInfoRequest { format };
 
     SmpFrame::new(ReadRequest, sequence, Group::Default, 7, request)
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+/// The requested information, or the error of the device. Decoded by the error code, see
+/// [deserialize_result](crate::smp::deserialize_result)
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum GetInfoResult {
+    Ok {
+        output: String,
+        /// fields this crate doesn't know, e.g. vendor extensions
+        #[serde(flatten)]
+        extra: ExtraFields,
+    },
+    Err {
+        rc: i32,
+    },
+}
+
+impl<'de> Deserialize<'de> for GetInfoResult {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        struct Info {
+            output: String,
+            #[serde(flatten)]
+            extra: ExtraFields,
+        }
+
+        Ok(
+            match crate::smp::deserialize_result::<_, Info, ErrorCode>(deserializer)? {
+                Ok(Info { output, extra }) => GetInfoResult::Ok { output, extra },
+                Err(ErrorCode { rc }) => GetInfoResult::Err { rc },
+            },
+        )
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct BootloaderInfoRequest {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub query: Option<String>,
+}
+
+/// Query the name of the bootloader, or with a `query` like `mode` details about it
+pub fn bootloader_info(sequence: u8, query: Option<String>) -> SmpFrame<BootloaderInfoRequest> {
+    let payload = BootloaderInfoRequest { query };
+
+    SmpFrame::new(ReadRequest, sequence, Group::Default, 8, payload)
+}
+
+/// The fields depend on the query, all of them are optional. The answers to other queries
+/// than `mode` end up in `extra`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum BootloaderInfoResult {
+    Err {
+        #[serde(alias = "err", deserialize_with = "crate::smp::error_rc")]
+        rc: i32,
+    },
+    Ok {
+        bootloader: Option<String>,
+        /// MCUboot mode, see the mcumgr documentation for the values
+        mode: Option<i32>,
+        #[serde(rename = "no-downgrade")]
+        no_downgrade: Option<bool>,
+        /// fields this crate doesn't know, e.g. vendor extensions
+        #[serde(flatten)]
+        extra: ExtraFields,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct McumgrParamsRequest {}
+
+/// Query the size and number of the buffers the device uses for SMP frames
+pub fn mcumgr_params(sequence: u8) -> SmpFrame<McumgrParamsRequest> {
+    SmpFrame::new(
+        ReadRequest,
+        sequence,
+        Group::Default,
+        6,
+        McumgrParamsRequest {},
+    )
+}
+
+/// The SMP buffers of the device, or its error. Decoded by the error code, see
+/// [deserialize_result](crate::smp::deserialize_result)
+#[derive(Serialize, Debug, Clone, PartialEq, Eq, Hash)]
+#[serde(untagged)]
+pub enum McumgrParamsResult {
+    Ok { buf_size: u32, buf_count: u32 },
+    Err { rc: i32 },
+}
+
+impl<'de> Deserialize<'de> for McumgrParamsResult {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        struct Params {
+            buf_size: u32,
+            buf_count: u32,
+        }
+
+        Ok(
+            match crate::smp::deserialize_result::<_, Params, ErrorCode>(deserializer)? {
+                Ok(Params {
+                    buf_size,
+                    buf_count,
+                }) => McumgrParamsResult::Ok {
+                    buf_size,
+                    buf_count,
+                },
+                Err(ErrorCode { rc }) => McumgrParamsResult::Err { rc },
+            },
+        )
+    }
+}
+
+/// What a [mcumgr_params] request sent right after connecting tells about the device
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProbeInfo {
+    /// Round trip time of the request
+    pub rtt: Duration,
+    /// SMP version of the response header, 0 for version 1
+    pub version: u8,
+    /// `None` if the device doesn't support the parameters command
+    pub buf_size: Option<u32>,
+    pub buf_count: Option<u32>,
+}
+
+impl ProbeInfo {
+    /// Any response proves that SMP is served, an error as well, e.g. by a device without the
+    /// parameters command
+    pub fn from_response(response: &SmpFrame<McumgrParamsResult>, rtt: Duration) -> Self {
+        let (buf_size, buf_count) = match response.data {
+            McumgrParamsResult::Ok {
+                buf_size,
+                buf_count,
+            } => (Some(buf_size), Some(buf_count)),
+            McumgrParamsResult::Err { .. } => (None, None),
+        };
+        Self {
+            rtt,
+            version: response.version,
+            buf_size,
+            buf_count,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
 #[serde(untagged)]
 pub enum ResetResult {
+    Err {
+        #[serde(alias = "err", deserialize_with = "crate::smp::error_rc")]
+        rc: i32,
+    },
     Ok {},
-    Err { rc: i32 },
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
 pub struct ResetRequest {
     pub force: u8,
 }
@@ -50,3 +356,156 @@ pub fn reset(sequence: u8, force: bool) -> SmpFrame<ResetRequest> {
 
     SmpFrame::new(WriteRequest, sequence, Group::Default, 5, payload)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cbor::decode_value;
+    use ciborium::cbor;
+
+    #[test]
+    fn echo_ok() {
+        let echo: EchoResult = decode_value(cbor!({ "r" => "hello" }).unwrap());
+        assert_eq!(echo, EchoResult::Ok { r: "hello".into() });
+    }
+
+    #[test]
+    fn echo_ok_with_rc_0() {
+        let echo: EchoResult = decode_value(cbor!({ "rc" => 0, "r" => "hello" }).unwrap());
+        assert_eq!(echo, EchoResult::Ok { r: "hello".into() });
+
+        let echo: EchoResult = decode_value(cbor!({ "r" => "hello", "rc" => 0 }).unwrap());
+        assert_eq!(echo, EchoResult::Ok { r: "hello".into() });
+    }
+
+    #[test]
+    fn echo_rc() {
+        let echo: EchoResult = decode_value(cbor!({ "rc" => 8 }).unwrap());
+        assert_eq!(echo, EchoResult::Err { rc: 8 });
+    }
+
+    #[test]
+    fn echo_v2_err() {
+        let echo: EchoResult =
+            decode_value(cbor!({ "err" => { "group" => 0, "rc" => 2 } }).unwrap());
+        assert_eq!(echo, EchoResult::Err { rc: 2 });
+    }
+
+    #[cfg(feature = "payload-cbor-borrowed")]
+    #[test]
+    fn task_stats_borrowed() {
+        let value = cbor!({
+            "tasks" => { "main" => { "prio" => 0 }, "idle" => { "prio" => 15 } }
+        })
+        .unwrap();
+        let mut buf = Vec::new();
+        ciborium::ser::into_writer(&value, &mut buf).unwrap();
+
+        let TaskStatsResultRef::Ok { tasks } = crate::cbor::decode_borrowed(&buf).unwrap() else {
+            panic!("task statistics are an error");
+        };
+        let owned: TaskStatsResult = decode_value(value);
+        let TaskStatsResult::Ok { tasks: expected } = owned else {
+            panic!("task statistics are an error");
+        };
+        assert!(tasks
+            .iter()
+            .map(|(k, v)| (*k, v))
+            .eq(expected.iter().map(|(k, v)| (k.as_str(), v))));
+        // the names point into the frame instead of being copied
+        assert!(tasks
+            .keys()
+            .all(|name| buf.as_ptr_range().contains(&name.as_ptr())));
+    }
+
+    #[cfg(feature = "payload-cbor-borrowed")]
+    #[test]
+    fn task_stats_borrowed_v2_err() {
+        let value = cbor!({ "err" => { "group" => 0, "rc" => 2 } }).unwrap();
+        let mut buf = Vec::new();
+        ciborium::ser::into_writer(&value, &mut buf).unwrap();
+        let result: TaskStatsResultRef = crate::cbor::decode_borrowed(&buf).unwrap();
+        assert_eq!(result, TaskStatsResultRef::Err { rc: 2 });
+    }
+
+    #[test]
+    fn results_are_decided_by_the_rc() {
+        let tasks: TaskStatsResult =
+            decode_value(cbor!({ "rc" => 0, "tasks" => { "idle" => { "prio" => 15 } } }).unwrap());
+        assert!(matches!(tasks, TaskStatsResult::Ok { tasks } if tasks["idle"].prio == Some(15)));
+        let tasks: TaskStatsResult = decode_value(cbor!({ "tasks" => {}, "rc" => 3 }).unwrap());
+        assert_eq!(tasks, TaskStatsResult::Err { rc: 3 });
+
+        let datetime: GetDateTimeResult =
+            decode_value(cbor!({ "datetime" => "2024-01-02T03:04:05", "rc" => 0 }).unwrap());
+        assert_eq!(
+            datetime,
+            GetDateTimeResult::Ok {
+                datetime: "2024-01-02T03:04:05".into()
+            }
+        );
+        let datetime: GetDateTimeResult =
+            decode_value(cbor!({ "err" => { "group" => 0, "rc" => 8 } }).unwrap());
+        assert_eq!(datetime, GetDateTimeResult::Err { rc: 8 });
+
+        // an error next to the output used to end up among the extra fields of an `Ok`
+        let info: GetInfoResult = decode_value(cbor!({ "output" => "", "rc" => 8 }).unwrap());
+        assert_eq!(info, GetInfoResult::Err { rc: 8 });
+        let info: GetInfoResult =
+            decode_value(cbor!({ "output" => "Zephyr", "vendor" => 1 }).unwrap());
+        let GetInfoResult::Ok { output, extra } = info else {
+            panic!("{:?}", info);
+        };
+        assert_eq!(output, "Zephyr");
+        assert!(extra.contains_key("vendor"));
+
+        let params: McumgrParamsResult =
+            decode_value(cbor!({ "buf_size" => 2475, "buf_count" => 4, "rc" => 0 }).unwrap());
+        assert_eq!(
+            params,
+            McumgrParamsResult::Ok {
+                buf_size: 2475,
+                buf_count: 4
+            }
+        );
+        let params: McumgrParamsResult =
+            decode_value(cbor!({ "err" => { "group" => 0, "rc" => 8 } }).unwrap());
+        assert_eq!(params, McumgrParamsResult::Err { rc: 8 });
+    }
+
+    #[test]
+    fn bootloader_info_keeps_vendor_fields() {
+        let info: BootloaderInfoResult = decode_value(
+            cbor!({
+                "bootloader" => "MCUboot",
+                "mode" => 3,
+                "no-downgrade" => true,
+                "x-key-hash" => ciborium::Value::Bytes(vec![0xde, 0xad, 0xbe, 0xef]),
+                "x-slots" => 2,
+            })
+            .unwrap(),
+        );
+        let BootloaderInfoResult::Ok {
+            bootloader,
+            mode,
+            no_downgrade,
+            extra,
+        } = info
+        else {
+            panic!("{:?}", info);
+        };
+        assert_eq!(bootloader.as_deref(), Some("MCUboot"));
+        assert_eq!(mode, Some(3));
+        assert_eq!(no_downgrade, Some(true));
+        assert_eq!(
+            extra.into_iter().collect::<Vec<_>>(),
+            [
+                (
+                    "x-key-hash".to_string(),
+                    ciborium::Value::Bytes(vec![0xde, 0xad, 0xbe, 0xef])
+                ),
+                ("x-slots".to_string(), cbor!(2).unwrap()),
+            ]
+        );
+    }
+}
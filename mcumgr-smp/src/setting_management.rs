@@ -1,11 +1,13 @@
 // Author: Sascha Zenglein <zenglein@gessler.de>
 // Copyright (c) 2023 Gessler GmbH.
-use crate::{Group, SmpFrame};
+use crate::smp::ErrorCode;
+use crate::{Group, HexBytes, SmpFrame};
 
 use crate::OpCode::{ReadRequest, WriteRequest};
 use serde::{Deserialize, Serialize};
+use std::fmt::{Debug, Formatter};
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
 pub struct ReadSettingRequest {
     pub name: String,
 }
@@ -16,7 +18,9 @@ pub fn read_setting(sequence: u8, name: String) -> SmpFrame<ReadSettingRequest>
     SmpFrame::new(ReadRequest, sequence, Group::SettingManagement, 0, payload)
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+/// The value read, or the error of the device. Decoded by the error code, see
+/// [deserialize_result](crate::smp::deserialize_result)
+#[derive(Serialize, Clone, PartialEq, Eq, Hash)]
 #[serde(untagged)]
 pub enum ReadSettingResult {
     Ok {
@@ -28,6 +32,34 @@ pub enum ReadSettingResult {
     },
 }
 
+impl<'de> Deserialize<'de> for ReadSettingResult {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        struct Value {
+            #[serde(with = "serde_bytes")]
+            val: Vec<u8>,
+        }
+
+        Ok(
+            match crate::smp::deserialize_result::<_, Value, ErrorCode>(deserializer)? {
+                Ok(Value { val }) => ReadSettingResult::Ok { val },
+                Err(ErrorCode { rc }) => ReadSettingResult::Err { rc },
+            },
+        )
+    }
+}
+
+impl Debug for ReadSettingResult {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ReadSettingResult::Ok { val } => {
+                f.debug_struct("Ok").field("val", &HexBytes(val)).finish()
+            }
+            ReadSettingResult::Err { rc } => f.debug_struct("Err").field("rc", rc).finish(),
+        }
+    }
+}
+
 impl ReadSettingResult {
     pub fn into_result(self) -> Result<Vec<u8>, i32> {
         match self {
@@ -35,26 +67,51 @@ impl ReadSettingResult {
             ReadSettingResult::Err { rc } => Err(rc),
         }
     }
+
+    /// The value read, to be interpreted with the accessors of [SettingValue]
+    pub fn into_value(self) -> Result<SettingValue, i32> {
+        self.into_result().map(SettingValue::Bytes)
+    }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Hash)]
 pub struct WriteSettingRequest {
     pub name: String,
     #[serde(with = "serde_bytes")]
     pub val: Vec<u8>,
 }
 
-pub fn write_setting(sequence: u8, name: String, val: Vec<u8>) -> SmpFrame<WriteSettingRequest> {
-    let payload = WriteSettingRequest { name, val };
+impl Debug for WriteSettingRequest {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("WriteSettingRequest")
+            .field("name", &self.name)
+            .field("val", &HexBytes(&self.val))
+            .finish()
+    }
+}
+
+/// Write a setting, given as bytes or any [SettingValue]
+pub fn write_setting(
+    sequence: u8,
+    name: String,
+    val: impl Into<SettingValue>,
+) -> SmpFrame<WriteSettingRequest> {
+    let payload = WriteSettingRequest {
+        name,
+        val: val.into().into_bytes(),
+    };
 
     SmpFrame::new(WriteRequest, sequence, Group::SettingManagement, 0, payload)
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
 #[serde(untagged)]
 pub enum WriteSettingResult {
+    Err {
+        #[serde(alias = "err", deserialize_with = "crate::smp::error_rc")]
+        rc: i32,
+    },
     Ok {},
-    Err { rc: i32 },
 }
 
 impl WriteSettingResult {
@@ -66,7 +123,7 @@ impl WriteSettingResult {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
 pub struct SaveSettingRequest {}
 
 pub fn save_setting(sequence: u8) -> SmpFrame<SaveSettingRequest> {
@@ -75,11 +132,14 @@ pub fn save_setting(sequence: u8) -> SmpFrame<SaveSettingRequest> {
     SmpFrame::new(WriteRequest, sequence, Group::SettingManagement, 3, payload)
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
 #[serde(untagged)]
 pub enum SaveSettingResult {
+    Err {
+        #[serde(alias = "err", deserialize_with = "crate::smp::error_rc")]
+        rc: i32,
+    },
     Ok {},
-    Err { rc: i32 },
 }
 
 impl SaveSettingResult {
@@ -90,3 +150,767 @@ impl SaveSettingResult {
         }
     }
 }
+
+/// Separator of the levels of a setting name, e.g. `bt/name`
+pub const NAME_SEPARATOR: char = '/';
+
+/// Zephyr's `SETTINGS_MAX_NAME_LEN`, the default of `CONFIG_MCUMGR_GRP_SETTINGS_NAME_LEN`
+pub const DEFAULT_MAX_NAME_LEN: usize = 64;
+
+/// Zephyr's `SETTINGS_MAX_DIR_DEPTH`
+pub const DEFAULT_MAX_NAME_DEPTH: usize = 8;
+
+/// Why a setting name would be rejected by the device. Segments are counted from 1.
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum SettingNameError {
+    #[error("name is empty")]
+    Empty,
+    #[error("name has {len} bytes, at most {max} are allowed")]
+    TooLong { len: usize, max: usize },
+    #[error("name has {depth} segments, at most {max} are allowed")]
+    TooDeep { depth: usize, max: usize },
+    #[error("name ends with the separator '/'")]
+    TrailingSeparator,
+    #[error("segment {0} is empty")]
+    EmptySegment(usize),
+    #[error("invalid character {ch:?} at offset {offset}")]
+    InvalidChar { ch: char, offset: usize },
+}
+
+/// How strictly setting names are checked before a request is sent.
+///
+/// The defaults are those of Zephyr, devices built with other limits need their own rules.
+/// Empty segments, a trailing separator, `=` and control characters are always rejected,
+/// the settings subsystem can't store such names.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NameRules {
+    /// Longest name in bytes, `None` for no limit
+    pub max_len: Option<usize>,
+    /// Most segments separated by [NAME_SEPARATOR], `None` for no limit
+    pub max_depth: Option<usize>,
+}
+
+impl NameRules {
+    /// Only the structure of the name is checked, not its length or depth
+    pub fn lenient() -> Self {
+        Self {
+            max_len: None,
+            max_depth: None,
+        }
+    }
+
+    /// Check a name, see [validate_name]
+    pub fn validate(&self, name: &str) -> Result<(), SettingNameError> {
+        if name.is_empty() {
+            return Err(SettingNameError::Empty);
+        }
+        if let Some(max) = self.max_len.filter(|&max| name.len() > max) {
+            return Err(SettingNameError::TooLong {
+                len: name.len(),
+                max,
+            });
+        }
+        if let Some((offset, ch)) = name
+            .char_indices()
+            .find(|&(_, ch)| ch == '=' || ch.is_control())
+        {
+            return Err(SettingNameError::InvalidChar { ch, offset });
+        }
+        if name.ends_with(NAME_SEPARATOR) {
+            return Err(SettingNameError::TrailingSeparator);
+        }
+        if let Some(index) = name.split(NAME_SEPARATOR).position(str::is_empty) {
+            return Err(SettingNameError::EmptySegment(index + 1));
+        }
+
+        let depth = name.split(NAME_SEPARATOR).count();
+        if let Some(max) = self.max_depth.filter(|&max| depth > max) {
+            return Err(SettingNameError::TooDeep { depth, max });
+        }
+
+        Ok(())
+    }
+}
+
+impl Default for NameRules {
+    fn default() -> Self {
+        Self {
+            max_len: Some(DEFAULT_MAX_NAME_LEN),
+            max_depth: Some(DEFAULT_MAX_NAME_DEPTH),
+        }
+    }
+}
+
+/// Check a setting name with the default [NameRules], e.g. while the user is typing it.
+///
+/// The device answers an invalid name with a bare error code, this says what is wrong.
+pub fn validate_name(name: &str) -> Result<(), SettingNameError> {
+    NameRules::default().validate(name)
+}
+
+/// Like [read_setting], fails without a frame if the name breaks the `rules`
+pub fn try_read_setting(
+    sequence: u8,
+    name: String,
+    rules: &NameRules,
+) -> Result<SmpFrame<ReadSettingRequest>, SettingNameError> {
+    rules.validate(&name)?;
+    Ok(read_setting(sequence, name))
+}
+
+/// Like [write_setting], fails without a frame if the name breaks the `rules`
+pub fn try_write_setting(
+    sequence: u8,
+    name: String,
+    val: impl Into<SettingValue>,
+    rules: &NameRules,
+) -> Result<SmpFrame<WriteSettingRequest>, SettingNameError> {
+    rules.validate(&name)?;
+    Ok(write_setting(sequence, name, val))
+}
+
+/// Width of an integer setting value
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IntWidth {
+    W8,
+    W16,
+    W32,
+    W64,
+}
+
+impl IntWidth {
+    pub fn bits(self) -> u32 {
+        match self {
+            IntWidth::W8 => 8,
+            IntWidth::W16 => 16,
+            IntWidth::W32 => 32,
+            IntWidth::W64 => 64,
+        }
+    }
+}
+
+/// Byte order of an integer setting value
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Endian {
+    #[default]
+    Little,
+    Big,
+}
+
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+pub enum SettingValueError {
+    #[error("hex string has an odd length")]
+    OddLength,
+    #[error("invalid hex digit {0:?}")]
+    InvalidHex(char),
+    #[error("invalid base64: {0}")]
+    InvalidBase64(String),
+    #[error("{value} doesn't fit into a {bits} bit integer")]
+    OutOfRange { value: i128, bits: u32 },
+    #[error("unknown value prefix {0:?}, use hex: or base64:")]
+    UnknownPrefix(String),
+    #[error("value has {len} bytes, expected {expected}")]
+    Length { len: usize, expected: &'static str },
+    #[error("value is not UTF-8 text, invalid byte at offset {0}")]
+    NotUtf8(usize),
+    #[error("value is an integer, not text")]
+    NotText,
+}
+
+/// A setting value and its encoding into the bytes sent to the device.
+///
+/// Settings are stored as plain bytes, the device application decides how to
+/// interpret them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SettingValue {
+    Bytes(Vec<u8>),
+    String(String),
+    /// An integer, stored as its two's complement representation of the given width
+    Int {
+        value: i64,
+        width: IntWidth,
+        endian: Endian,
+    },
+}
+
+impl SettingValue {
+    /// Raw bytes, stored as they are
+    pub fn from_bytes(bytes: impl Into<Vec<u8>>) -> Self {
+        SettingValue::Bytes(bytes.into())
+    }
+
+    /// A 32 bit little-endian unsigned integer
+    pub fn from_u32_le(value: u32) -> Self {
+        SettingValue::Int {
+            value: value as i64,
+            width: IntWidth::W32,
+            endian: Endian::Little,
+        }
+    }
+
+    /// A 64 bit little-endian signed integer
+    pub fn from_i64_le(value: i64) -> Self {
+        SettingValue::Int {
+            value,
+            width: IntWidth::W64,
+            endian: Endian::Little,
+        }
+    }
+
+    /// A single byte, 1 for true and 0 for false
+    pub fn from_bool(value: bool) -> Self {
+        SettingValue::Bytes(vec![value as u8])
+    }
+
+    /// An integer value, checked to fit into the given width.
+    ///
+    /// Both signed and unsigned values are accepted, e.g. -1 and 255 for 8 bits.
+    pub fn int(value: i128, width: IntWidth, endian: Endian) -> Result<Self, SettingValueError> {
+        let bits = width.bits();
+        let min = -(1i128 << (bits - 1));
+        let max = (1i128 << bits) - 1;
+        if value < min || value > max {
+            return Err(SettingValueError::OutOfRange { value, bits });
+        }
+
+        Ok(SettingValue::Int {
+            // values above the signed range wrap to the same bit pattern
+            value: value as i64,
+            width,
+            endian,
+        })
+    }
+
+    /// Parse hex digits into a byte value
+    pub fn from_hex(hex: &str) -> Result<Self, SettingValueError> {
+        let chars: Vec<char> = hex.chars().collect();
+        if !chars.len().is_multiple_of(2) {
+            return Err(SettingValueError::OddLength);
+        }
+
+        let digit = |c: char| c.to_digit(16).ok_or(SettingValueError::InvalidHex(c));
+        let bytes = chars
+            .chunks(2)
+            .map(|pair| Ok((digit(pair[0])? << 4 | digit(pair[1])?) as u8))
+            .collect::<Result<_, _>>()?;
+
+        Ok(SettingValue::Bytes(bytes))
+    }
+
+    /// Decode standard base64 into a byte value
+    #[cfg(feature = "base64")]
+    pub fn from_base64(data: &str) -> Result<Self, SettingValueError> {
+        use base64::Engine;
+
+        base64::engine::general_purpose::STANDARD
+            .decode(data)
+            .map(SettingValue::Bytes)
+            .map_err(|e| SettingValueError::InvalidBase64(e.to_string()))
+    }
+
+    /// Parse a byte value written as `hex:DEADBEEF` or `base64:3q2+7w==`
+    pub fn parse_bytes(s: &str) -> Result<Self, SettingValueError> {
+        if let Some(hex) = s.strip_prefix("hex:") {
+            return Self::from_hex(hex);
+        }
+        #[cfg(feature = "base64")]
+        if let Some(data) = s.strip_prefix("base64:") {
+            return Self::from_base64(data);
+        }
+
+        let prefix = s.split_once(':').map_or(s, |(prefix, _)| prefix);
+        Err(SettingValueError::UnknownPrefix(prefix.to_string()))
+    }
+
+    /// The bytes that are written to the device
+    pub fn to_bytes(&self) -> Vec<u8> {
+        match self {
+            SettingValue::Bytes(bytes) => bytes.clone(),
+            SettingValue::String(s) => s.as_bytes().to_vec(),
+            SettingValue::Int {
+                value,
+                width,
+                endian,
+            } => {
+                let len = width.bits() as usize / 8;
+                match endian {
+                    Endian::Little => value.to_le_bytes()[..len].to_vec(),
+                    Endian::Big => value.to_be_bytes()[8 - len..].to_vec(),
+                }
+            }
+        }
+    }
+
+    /// Like [Self::to_bytes], without copying bytes and strings
+    pub fn into_bytes(self) -> Vec<u8> {
+        match self {
+            SettingValue::Bytes(bytes) => bytes,
+            SettingValue::String(s) => s.into_bytes(),
+            int => int.to_bytes(),
+        }
+    }
+
+    /// The value as UTF-8 text. Integers aren't text and always fail.
+    pub fn as_str(&self) -> Result<&str, SettingValueError> {
+        match self {
+            SettingValue::String(s) => Ok(s),
+            SettingValue::Bytes(bytes) => {
+                std::str::from_utf8(bytes).map_err(|e| SettingValueError::NotUtf8(e.valid_up_to()))
+            }
+            SettingValue::Int { .. } => Err(SettingValueError::NotText),
+        }
+    }
+
+    /// The value as a 32 bit little-endian unsigned integer, it must have exactly 4 bytes
+    pub fn as_u32_le(&self) -> Result<u32, SettingValueError> {
+        let bytes = self.to_bytes();
+        let bytes: [u8; 4] =
+            bytes
+                .as_slice()
+                .try_into()
+                .map_err(|_| SettingValueError::Length {
+                    len: bytes.len(),
+                    expected: "4",
+                })?;
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    /// The value as a little-endian signed integer of 1, 2, 4 or 8 bytes, sign-extended from
+    /// its width
+    pub fn as_i64_le(&self) -> Result<i64, SettingValueError> {
+        let (bytes, len) = self.int_bytes_le()?;
+        let shift = 64 - 8 * len as u32;
+        Ok((i64::from_le_bytes(bytes) << shift) >> shift)
+    }
+
+    /// The value as a little-endian unsigned integer of 1, 2, 4 or 8 bytes
+    pub fn as_u64_le(&self) -> Result<u64, SettingValueError> {
+        let (bytes, _) = self.int_bytes_le()?;
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    /// The value as a boolean, a single byte that is true unless it is 0
+    pub fn as_bool(&self) -> Result<bool, SettingValueError> {
+        match self.to_bytes()[..] {
+            [byte] => Ok(byte != 0),
+            ref bytes => Err(SettingValueError::Length {
+                len: bytes.len(),
+                expected: "1",
+            }),
+        }
+    }
+
+    /// The bytes of an integer zero-extended to 8 bytes, and its original length
+    fn int_bytes_le(&self) -> Result<([u8; 8], usize), SettingValueError> {
+        let val = self.to_bytes();
+        if !matches!(val.len(), 1 | 2 | 4 | 8) {
+            return Err(SettingValueError::Length {
+                len: val.len(),
+                expected: "1, 2, 4 or 8",
+            });
+        }
+
+        let mut bytes = [0; 8];
+        bytes[..val.len()].copy_from_slice(&val);
+        Ok((bytes, val.len()))
+    }
+}
+
+impl From<Vec<u8>> for SettingValue {
+    fn from(bytes: Vec<u8>) -> Self {
+        SettingValue::Bytes(bytes)
+    }
+}
+
+impl From<&[u8]> for SettingValue {
+    fn from(bytes: &[u8]) -> Self {
+        SettingValue::Bytes(bytes.to_vec())
+    }
+}
+
+impl From<String> for SettingValue {
+    fn from(s: String) -> Self {
+        SettingValue::String(s)
+    }
+}
+
+impl From<&str> for SettingValue {
+    fn from(s: &str) -> Self {
+        SettingValue::String(s.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cbor::decode_value;
+    use ciborium::{cbor, Value};
+
+    fn val() -> Value {
+        Value::Bytes(vec![0x01, 0x00, 0x00, 0x00])
+    }
+
+    #[test]
+    fn read_value() {
+        let result: ReadSettingResult = decode_value(cbor!({ "val" => val() }).unwrap());
+        assert_eq!(result.into_result(), Ok(vec![1, 0, 0, 0]));
+    }
+
+    #[test]
+    fn read_value_with_rc_0() {
+        let result: ReadSettingResult = decode_value(cbor!({ "rc" => 0, "val" => val() }).unwrap());
+        assert_eq!(result.into_result(), Ok(vec![1, 0, 0, 0]));
+
+        let result: ReadSettingResult = decode_value(cbor!({ "val" => val(), "rc" => 0 }).unwrap());
+        assert_eq!(result.into_result(), Ok(vec![1, 0, 0, 0]));
+    }
+
+    #[test]
+    fn read_rc() {
+        let result: ReadSettingResult = decode_value(cbor!({ "rc" => 5 }).unwrap());
+        assert_eq!(result, ReadSettingResult::Err { rc: 5 });
+    }
+
+    #[test]
+    fn read_v2_err() {
+        let result: ReadSettingResult =
+            decode_value(cbor!({ "err" => { "group" => 3, "rc" => 3 } }).unwrap());
+        assert_eq!(result, ReadSettingResult::Err { rc: 3 });
+    }
+
+    #[test]
+    fn write_ok_with_rc_0() {
+        let result: WriteSettingResult = decode_value(cbor!({ "rc" => 0 }).unwrap());
+        assert_eq!(result, WriteSettingResult::Ok {});
+    }
+
+    #[test]
+    fn debug_format() {
+        let request = WriteSettingRequest {
+            name: "foo/bar".to_string(),
+            val: vec![0xde, 0xad],
+        };
+        assert_eq!(
+            format!("{:?}", request),
+            "WriteSettingRequest { name: \"foo/bar\", val: dead (2 bytes) }"
+        );
+        assert_eq!(
+            format!("{:?}", ReadSettingResult::Ok { val: vec![] }),
+            "Ok { val: (0 bytes) }"
+        );
+        assert_eq!(
+            format!("{:?}", ReadSettingResult::Err { rc: 5 }),
+            "Err { rc: 5 }"
+        );
+    }
+
+    #[test]
+    fn str_round_trip() {
+        assert_eq!(SettingValue::from("zephyr").as_str(), Ok("zephyr"));
+        assert_eq!(SettingValue::from_bytes("zephyr").as_str(), Ok("zephyr"));
+        assert_eq!(
+            SettingValue::from_bytes(vec![b'o', b'k', 0xff]).as_str(),
+            Err(SettingValueError::NotUtf8(2))
+        );
+        assert_eq!(
+            SettingValue::from_u32_le(1).as_str(),
+            Err(SettingValueError::NotText)
+        );
+    }
+
+    #[test]
+    fn u32_le_round_trip() {
+        for value in [0, 1, 0x1234_5678, u32::MAX] {
+            let setting = SettingValue::from_u32_le(value);
+            assert_eq!(setting.to_bytes(), value.to_le_bytes());
+            assert_eq!(setting.as_u32_le(), Ok(value));
+            assert_eq!(
+                SettingValue::from(setting.into_bytes()).as_u32_le(),
+                Ok(value)
+            );
+        }
+    }
+
+    #[test]
+    fn i64_le_round_trip() {
+        for value in [0, 1, -1, i64::MIN, i64::MAX] {
+            let setting = SettingValue::from_i64_le(value);
+            assert_eq!(setting.to_bytes(), value.to_le_bytes());
+            assert_eq!(setting.as_i64_le(), Ok(value));
+            assert_eq!(
+                SettingValue::from(setting.into_bytes()).as_i64_le(),
+                Ok(value)
+            );
+        }
+    }
+
+    #[test]
+    fn u64_le_round_trip() {
+        assert_eq!(SettingValue::from_i64_le(-1).as_u64_le(), Ok(u64::MAX));
+        assert_eq!(
+            SettingValue::from_u32_le(u32::MAX).as_u64_le(),
+            Ok(0xffff_ffff)
+        );
+        assert_eq!(
+            SettingValue::from_bytes([0x34, 0x12]).as_u64_le(),
+            Ok(0x1234)
+        );
+    }
+
+    #[test]
+    fn narrow_ints_sign_extend() {
+        assert_eq!(SettingValue::from_bytes([0xff]).as_i64_le(), Ok(-1));
+        assert_eq!(SettingValue::from_bytes([0xfe, 0xff]).as_i64_le(), Ok(-2));
+        assert_eq!(SettingValue::from_bytes([0xff]).as_u64_le(), Ok(0xff));
+    }
+
+    #[test]
+    fn bool_round_trip() {
+        assert_eq!(SettingValue::from_bool(true).to_bytes(), [1]);
+        assert_eq!(SettingValue::from_bool(true).as_bool(), Ok(true));
+        assert_eq!(SettingValue::from_bool(false).as_bool(), Ok(false));
+        assert_eq!(SettingValue::from_bytes([2]).as_bool(), Ok(true));
+    }
+
+    #[test]
+    fn bytes_round_trip() {
+        let bytes = vec![0x00, 0xff, 0x10];
+        let setting = SettingValue::from_bytes(bytes.clone());
+        assert_eq!(setting.to_bytes(), bytes);
+        assert_eq!(setting.into_bytes(), bytes);
+    }
+
+    #[test]
+    fn size_mismatch_reports_the_length() {
+        let three = SettingValue::from_bytes([1, 2, 3]);
+        assert_eq!(
+            three.as_u32_le(),
+            Err(SettingValueError::Length {
+                len: 3,
+                expected: "4"
+            })
+        );
+        assert_eq!(
+            three.as_i64_le(),
+            Err(SettingValueError::Length {
+                len: 3,
+                expected: "1, 2, 4 or 8"
+            })
+        );
+        assert_eq!(
+            SettingValue::from_i64_le(1).as_u32_le(),
+            Err(SettingValueError::Length {
+                len: 8,
+                expected: "4"
+            })
+        );
+        assert_eq!(
+            SettingValue::from_bytes([]).as_bool(),
+            Err(SettingValueError::Length {
+                len: 0,
+                expected: "1"
+            })
+        );
+        assert_eq!(
+            SettingValue::from_u32_le(1)
+                .as_bool()
+                .unwrap_err()
+                .to_string(),
+            "value has 4 bytes, expected 1"
+        );
+    }
+
+    #[test]
+    fn name_with_empty_segment() {
+        let error = validate_name("a/b//c").unwrap_err();
+        assert_eq!(error, SettingNameError::EmptySegment(3));
+        assert_eq!(error.to_string(), "segment 3 is empty");
+        assert_eq!(validate_name("/a"), Err(SettingNameError::EmptySegment(1)));
+    }
+
+    #[test]
+    fn name_with_trailing_separator() {
+        let error = validate_name("bt/name/").unwrap_err();
+        assert_eq!(error, SettingNameError::TrailingSeparator);
+        assert_eq!(error.to_string(), "name ends with the separator '/'");
+    }
+
+    #[test]
+    fn name_too_long() {
+        let name = "a".repeat(DEFAULT_MAX_NAME_LEN + 1);
+        let error = validate_name(&name).unwrap_err();
+        assert_eq!(
+            error,
+            SettingNameError::TooLong {
+                len: DEFAULT_MAX_NAME_LEN + 1,
+                max: DEFAULT_MAX_NAME_LEN
+            }
+        );
+        assert_eq!(
+            error.to_string(),
+            "name has 65 bytes, at most 64 are allowed"
+        );
+        assert_eq!(validate_name(&name[1..]), Ok(()));
+    }
+
+    #[test]
+    fn name_too_deep_and_invalid() {
+        assert_eq!(
+            validate_name("a/b/c/d/e/f/g/h/i"),
+            Err(SettingNameError::TooDeep { depth: 9, max: 8 })
+        );
+        assert_eq!(
+            validate_name("a=b"),
+            Err(SettingNameError::InvalidChar { ch: '=', offset: 1 })
+        );
+        assert_eq!(validate_name(""), Err(SettingNameError::Empty));
+        assert_eq!(validate_name("bt/name"), Ok(()));
+    }
+
+    #[test]
+    fn lenient_names() {
+        let rules = NameRules::lenient();
+        let long = "x".repeat(200);
+        let deep = "a/b/c/d/e/f/g/h/i/j/k";
+        assert_eq!(rules.validate(&long), Ok(()));
+        assert_eq!(rules.validate(deep), Ok(()));
+        assert_eq!(rules.validate("vendor/ключ/v1.2"), Ok(()));
+
+        assert!(validate_name(deep).is_err());
+        assert_eq!(
+            rules.validate("a//b"),
+            Err(SettingNameError::EmptySegment(2))
+        );
+    }
+
+    #[test]
+    fn try_read_setting_validates() {
+        assert!(try_read_setting(0, "a/".to_string(), &NameRules::default()).is_err());
+        let frame = try_read_setting(0, "a/b".to_string(), &NameRules::default()).unwrap();
+        assert_eq!(frame.data.name, "a/b");
+    }
+
+    #[test]
+    fn int_widths_and_byte_orders() {
+        use Endian::{Big, Little};
+        use IntWidth::{W16, W32, W64, W8};
+
+        let table: &[(i128, IntWidth, Endian, &[u8])] = &[
+            (0x12, W8, Little, &[0x12]),
+            (0x12, W8, Big, &[0x12]),
+            (-1, W8, Little, &[0xff]),
+            (255, W8, Big, &[0xff]),
+            (0x1234, W16, Little, &[0x34, 0x12]),
+            (0x1234, W16, Big, &[0x12, 0x34]),
+            (-2, W16, Big, &[0xff, 0xfe]),
+            (0x1234_5678, W32, Little, &[0x78, 0x56, 0x34, 0x12]),
+            (0x1234_5678, W32, Big, &[0x12, 0x34, 0x56, 0x78]),
+            (u32::MAX as i128, W32, Little, &[0xff; 4]),
+            (
+                0x0102_0304_0506_0708,
+                W64,
+                Little,
+                &[8, 7, 6, 5, 4, 3, 2, 1],
+            ),
+            (0x0102_0304_0506_0708, W64, Big, &[1, 2, 3, 4, 5, 6, 7, 8]),
+            (u64::MAX as i128, W64, Big, &[0xff; 8]),
+            (i64::MIN as i128, W64, Big, &[0x80, 0, 0, 0, 0, 0, 0, 0]),
+        ];
+        for &(value, width, endian, bytes) in table {
+            let setting = SettingValue::int(value, width, endian).unwrap();
+            assert_eq!(setting.to_bytes(), bytes, "{value} as {width:?} {endian:?}");
+            assert_eq!(setting.into_bytes(), bytes);
+        }
+    }
+
+    #[test]
+    fn int_out_of_range() {
+        let table: &[(i128, IntWidth)] = &[
+            (256, IntWidth::W8),
+            (-129, IntWidth::W8),
+            (0x1_0000, IntWidth::W16),
+            (-0x8001, IntWidth::W16),
+            (0x1_0000_0000, IntWidth::W32),
+            (u64::MAX as i128 + 1, IntWidth::W64),
+            (i64::MIN as i128 - 1, IntWidth::W64),
+        ];
+        for &(value, width) in table {
+            assert_eq!(
+                SettingValue::int(value, width, Endian::Little),
+                Err(SettingValueError::OutOfRange {
+                    value,
+                    bits: width.bits()
+                })
+            );
+        }
+        assert_eq!(
+            SettingValue::int(256, IntWidth::W8, Endian::Big)
+                .unwrap_err()
+                .to_string(),
+            "256 doesn't fit into a 8 bit integer"
+        );
+    }
+
+    #[test]
+    fn hex_values() {
+        assert_eq!(
+            SettingValue::from_hex("DEadbe0f"),
+            Ok(SettingValue::Bytes(vec![0xde, 0xad, 0xbe, 0x0f]))
+        );
+        assert_eq!(SettingValue::from_hex(""), Ok(SettingValue::Bytes(vec![])));
+        assert_eq!(
+            SettingValue::from_hex("abc"),
+            Err(SettingValueError::OddLength)
+        );
+        assert_eq!(
+            SettingValue::from_hex("0g"),
+            Err(SettingValueError::InvalidHex('g'))
+        );
+        // an odd number of characters, not bytes
+        assert_eq!(
+            SettingValue::from_hex("éa"),
+            Err(SettingValueError::InvalidHex('é'))
+        );
+    }
+
+    #[cfg(feature = "base64")]
+    #[test]
+    fn base64_values() {
+        assert_eq!(
+            SettingValue::from_base64("3q2+7w=="),
+            Ok(SettingValue::Bytes(vec![0xde, 0xad, 0xbe, 0xef]))
+        );
+        assert!(matches!(
+            SettingValue::from_base64("3q2+7w="),
+            Err(SettingValueError::InvalidBase64(_))
+        ));
+        assert!(matches!(
+            SettingValue::from_base64("!!!!"),
+            Err(SettingValueError::InvalidBase64(_))
+        ));
+        assert_eq!(
+            SettingValue::parse_bytes("base64:AP8="),
+            Ok(SettingValue::Bytes(vec![0x00, 0xff]))
+        );
+    }
+
+    #[test]
+    fn parse_bytes_prefixes() {
+        assert_eq!(
+            SettingValue::parse_bytes("hex:00ff"),
+            Ok(SettingValue::Bytes(vec![0x00, 0xff]))
+        );
+        assert_eq!(
+            SettingValue::parse_bytes("hex:0"),
+            Err(SettingValueError::OddLength)
+        );
+        assert_eq!(
+            SettingValue::parse_bytes("b64:AP8="),
+            Err(SettingValueError::UnknownPrefix("b64".to_string()))
+        );
+        assert_eq!(
+            SettingValue::parse_bytes("00ff"),
+            Err(SettingValueError::UnknownPrefix("00ff".to_string()))
+        );
+    }
+}
@@ -1,25 +1,54 @@
 // Author: Sascha Zenglein <zenglein@gessler.de>
 // Copyright (c) 2023 Gessler GmbH.
+use crate::smp::ErrorCode;
 use crate::{Group, SmpFrame};
 
 use crate::OpCode::WriteRequest;
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
 pub struct ShellCommand {
     /// argv containing cmd + arg, arg, ...
     pub argv: Vec<String>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+/// The output of the command, or the error of the device. Decoded by the error code, see
+/// [deserialize_result](crate::smp::deserialize_result)
+#[derive(Serialize, Debug, Clone, PartialEq, Eq, Hash)]
 #[serde(untagged)]
 pub enum ShellResult {
-    Ok { o: String, ret: i32 },
-    Err { rc: i32 },
+    Ok {
+        o: String,
+        /// exit status of the command, older devices don't report it
+        #[serde(skip_serializing_if = "Option::is_none")]
+        ret: Option<i32>,
+    },
+    Err {
+        rc: i32,
+    },
+}
+
+impl<'de> Deserialize<'de> for ShellResult {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        struct Output {
+            #[serde(deserialize_with = "text_or_bytes")]
+            o: String,
+            #[serde(default)]
+            ret: Option<i32>,
+        }
+
+        Ok(
+            match crate::smp::deserialize_result::<_, Output, ErrorCode>(deserializer)? {
+                Ok(Output { o, ret }) => ShellResult::Ok { o, ret },
+                Err(ErrorCode { rc }) => ShellResult::Err { rc },
+            },
+        )
+    }
 }
 
 impl ShellResult {
-    pub fn into_result(self) -> Result<(String, i32), i32> {
+    pub fn into_result(self) -> Result<(String, Option<i32>), i32> {
         match self {
             ShellResult::Ok { o, ret } => Ok((o, ret)),
             ShellResult::Err { rc } => Err(rc),
@@ -27,8 +56,81 @@ impl ShellResult {
     }
 }
 
+/// The output is text, but some devices send it as a byte string which may not be valid UTF-8
+fn text_or_bytes<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Output {
+        Text(String),
+        Bytes(#[serde(with = "serde_bytes")] Vec<u8>),
+    }
+
+    Ok(match Output::deserialize(deserializer)? {
+        Output::Text(text) => text,
+        Output::Bytes(bytes) => String::from_utf8_lossy(&bytes).into_owned(),
+    })
+}
+
 pub fn shell_command(sequence: u8, command_args: Vec<String>) -> SmpFrame<ShellCommand> {
     let payload = ShellCommand { argv: command_args };
 
     SmpFrame::new(WriteRequest, sequence, Group::ShellManagement, 0, payload)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cbor::decode_value;
+    use ciborium::cbor;
+
+    #[test]
+    fn output() {
+        let result: ShellResult = decode_value(cbor!({ "o" => "uart:~$ ", "ret" => 0 }).unwrap());
+        assert_eq!(
+            result,
+            ShellResult::Ok {
+                o: "uart:~$ ".into(),
+                ret: Some(0)
+            }
+        );
+    }
+
+    #[test]
+    fn output_as_bytes_without_ret() {
+        let o = ciborium::Value::Bytes(b"line 1\nline 2\n".to_vec());
+        let result: ShellResult = decode_value(cbor!({ "o" => o }).unwrap());
+        assert_eq!(
+            result,
+            ShellResult::Ok {
+                o: "line 1\nline 2\n".into(),
+                ret: None
+            }
+        );
+    }
+
+    #[test]
+    fn output_with_rc_0() {
+        let result: ShellResult =
+            decode_value(cbor!({ "rc" => 0, "o" => "ok", "ret" => -8 }).unwrap());
+        assert_eq!(
+            result,
+            ShellResult::Ok {
+                o: "ok".into(),
+                ret: Some(-8)
+            }
+        );
+    }
+
+    #[test]
+    fn rc() {
+        let result: ShellResult = decode_value(cbor!({ "rc" => 8 }).unwrap());
+        assert_eq!(result, ShellResult::Err { rc: 8 });
+    }
+
+    #[test]
+    fn v2_err() {
+        let result: ShellResult =
+            decode_value(cbor!({ "err" => { "group" => 9, "rc" => 2 } }).unwrap());
+        assert_eq!(result, ShellResult::Err { rc: 2 });
+    }
+}
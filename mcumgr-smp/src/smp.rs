@@ -6,14 +6,24 @@ use thiserror::Error;
 #[derive(Error, Debug)]
 pub enum SmpError {
     #[error("payload decoding error: {0}")]
-    PayloadDecodingError(#[from] Box<dyn std::error::Error>),
+    PayloadDecodingError(Box<PayloadError>),
     #[error("smp frame decoding error")]
     InvalidFrame,
+    #[error("header announces {expected} payload bytes, but the frame has {actual}")]
+    LengthMismatch { expected: usize, actual: usize },
+    #[error("unknown opcode {0}")]
+    UnknownOpCode(u8),
     #[error("unexpected sequence number")]
     UnexpectedSeq,
+    #[error("incomplete frame, {missing} bytes missing")]
+    IncompleteFrame { missing: usize },
+    /// An error reported by a group in an SMP version 2 response, see
+    /// [decode_group_error](crate::group_error::decode_group_error)
+    #[error("{}", crate::group_error::format_group_error(*.group, *.code))]
+    GroupError { group: Group, code: i32 },
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum OpCode {
     ReadRequest = 0,
     ReadResponse = 1,
@@ -21,15 +31,17 @@ pub enum OpCode {
     WriteResponse = 3,
 }
 
-impl From<u8> for OpCode {
-    fn from(num: u8) -> Self {
-        match num {
+impl TryFrom<u8> for OpCode {
+    type Error = u8;
+
+    fn try_from(num: u8) -> Result<Self, Self::Error> {
+        Ok(match num {
             0 => OpCode::ReadRequest,
             1 => OpCode::ReadResponse,
             2 => OpCode::WriteRequest,
             3 => OpCode::WriteResponse,
-            _ => panic!("unknown opcode"),
-        }
+            num => return Err(num),
+        })
     }
 }
 
@@ -44,12 +56,51 @@ impl From<OpCode> for u8 {
     }
 }
 
-#[derive(Debug, Clone, Copy)]
+impl OpCode {
+    pub fn is_request(self) -> bool {
+        matches!(self, OpCode::ReadRequest | OpCode::WriteRequest)
+    }
+
+    pub fn is_response(self) -> bool {
+        !self.is_request()
+    }
+
+    /// The opcode of the response to a request, responses map to themselves
+    pub fn response_of(self) -> OpCode {
+        match self {
+            OpCode::ReadRequest | OpCode::ReadResponse => OpCode::ReadResponse,
+            OpCode::WriteRequest | OpCode::WriteResponse => OpCode::WriteResponse,
+        }
+    }
+
+    /// The opcode of the request a response answers, requests map to themselves
+    pub fn request_of(self) -> OpCode {
+        match self {
+            OpCode::ReadRequest | OpCode::ReadResponse => OpCode::ReadRequest,
+            OpCode::WriteRequest | OpCode::WriteResponse => OpCode::WriteRequest,
+        }
+    }
+}
+
+impl std::fmt::Display for OpCode {
+    /// The names mcumgr uses, `read`, `read rsp`, `write` and `write rsp`
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            OpCode::ReadRequest => "read",
+            OpCode::ReadResponse => "read rsp",
+            OpCode::WriteRequest => "write",
+            OpCode::WriteResponse => "write rsp",
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum Group {
     Default,
     ApplicationManagement,
     Statistics,
     SettingManagement,
+    LogManagement,
     FileManagement,
     ShellManagement,
     ZephyrCommand,
@@ -63,6 +114,7 @@ impl From<u16> for Group {
             1 => Self::ApplicationManagement,
             2 => Self::Statistics,
             3 => Self::SettingManagement,
+            4 => Self::LogManagement,
             8 => Self::FileManagement,
             9 => Self::ShellManagement,
             63 => Self::ZephyrCommand,
@@ -78,6 +130,7 @@ impl From<Group> for u16 {
             Group::ApplicationManagement => 1,
             Group::Statistics => 2,
             Group::SettingManagement => 3,
+            Group::LogManagement => 4,
             Group::FileManagement => 8,
             Group::ShellManagement => 9,
             Group::ZephyrCommand => 63,
@@ -86,19 +139,221 @@ impl From<Group> for u16 {
     }
 }
 
+impl Group {
+    /// The short name mcumgr uses, e.g. `os` or `img`, `None` for custom groups
+    pub fn name(&self) -> Option<&'static str> {
+        Some(match self {
+            Group::Default => "os",
+            Group::ApplicationManagement => "img",
+            Group::Statistics => "stat",
+            Group::SettingManagement => "config",
+            Group::LogManagement => "log",
+            Group::FileManagement => "fs",
+            Group::ShellManagement => "shell",
+            Group::ZephyrCommand => "zephyr",
+            Group::Custom(_) => return None,
+        })
+    }
+}
+
+impl std::fmt::Display for Group {
+    /// The short names mcumgr uses, e.g. `os` or `img`, and the number of other groups
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.name() {
+            Some(name) => f.write_str(name),
+            None => write!(f, "group {}", u16::from(*self)),
+        }
+    }
+}
+
+/// Generic SMP return codes, as found in the `rc` field of error responses
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum ReturnCode {
     Ok = 0,
     Unknown = 1,
     OutOfMemory = 2,
-    // ...
+    InvalidValue = 3,
+    Timeout = 4,
+    NoEntry = 5,
+    BadState = 6,
+    MessageSize = 7,
+    NotSupported = 8,
+    Corrupt = 9,
+    Busy = 10,
+    AccessDenied = 11,
+    UnsupportedTooOld = 12,
+    UnsupportedTooNew = 13,
     UserDefined = 256,
 }
 
+impl TryFrom<i32> for ReturnCode {
+    type Error = i32;
+
+    fn try_from(rc: i32) -> Result<Self, Self::Error> {
+        Ok(match rc {
+            0 => ReturnCode::Ok,
+            1 => ReturnCode::Unknown,
+            2 => ReturnCode::OutOfMemory,
+            3 => ReturnCode::InvalidValue,
+            4 => ReturnCode::Timeout,
+            5 => ReturnCode::NoEntry,
+            6 => ReturnCode::BadState,
+            7 => ReturnCode::MessageSize,
+            8 => ReturnCode::NotSupported,
+            9 => ReturnCode::Corrupt,
+            10 => ReturnCode::Busy,
+            11 => ReturnCode::AccessDenied,
+            12 => ReturnCode::UnsupportedTooOld,
+            13 => ReturnCode::UnsupportedTooNew,
+            256 => ReturnCode::UserDefined,
+            rc => return Err(rc),
+        })
+    }
+}
+
+impl ReturnCode {
+    /// Name of the return code as used by Zephyr and mcumgr
+    pub fn name(&self) -> &'static str {
+        match self {
+            ReturnCode::Ok => "MGMT_ERR_EOK",
+            ReturnCode::Unknown => "MGMT_ERR_EUNKNOWN",
+            ReturnCode::OutOfMemory => "MGMT_ERR_ENOMEM",
+            ReturnCode::InvalidValue => "MGMT_ERR_EINVAL",
+            ReturnCode::Timeout => "MGMT_ERR_ETIMEOUT",
+            ReturnCode::NoEntry => "MGMT_ERR_ENOENT",
+            ReturnCode::BadState => "MGMT_ERR_EBADSTATE",
+            ReturnCode::MessageSize => "MGMT_ERR_EMSGSIZE",
+            ReturnCode::NotSupported => "MGMT_ERR_ENOTSUP",
+            ReturnCode::Corrupt => "MGMT_ERR_ECORRUPT",
+            ReturnCode::Busy => "MGMT_ERR_EBUSY",
+            ReturnCode::AccessDenied => "MGMT_ERR_EACCESSDENIED",
+            ReturnCode::UnsupportedTooOld => "MGMT_ERR_UNSUPPORTED_TOO_OLD",
+            ReturnCode::UnsupportedTooNew => "MGMT_ERR_UNSUPPORTED_TOO_NEW",
+            ReturnCode::UserDefined => "MGMT_ERR_EPERUSER",
+        }
+    }
+
+    /// The name without the `MGMT_ERR_` prefix, e.g. `ENOTSUP`
+    pub fn short_name(&self) -> &'static str {
+        self.name().trim_start_matches("MGMT_ERR_")
+    }
+
+    /// What the return code means, e.g. "command not supported by this device"
+    pub fn description(&self) -> &'static str {
+        match self {
+            ReturnCode::Ok => "no error",
+            ReturnCode::Unknown => "unknown error",
+            ReturnCode::OutOfMemory => "the device is out of memory",
+            ReturnCode::InvalidValue => "invalid value in the request",
+            ReturnCode::Timeout => "operation timed out on the device",
+            ReturnCode::NoEntry => "no such entry",
+            ReturnCode::BadState => "the device is in a state that doesn't allow the request",
+            ReturnCode::MessageSize => "response too large",
+            ReturnCode::NotSupported => "command not supported by this device",
+            ReturnCode::Corrupt => "corrupt data",
+            ReturnCode::Busy => "the device is busy",
+            ReturnCode::AccessDenied => "access denied",
+            ReturnCode::UnsupportedTooOld => "the SMP version of the request is too old",
+            ReturnCode::UnsupportedTooNew => "the SMP version of the request is too new",
+            ReturnCode::UserDefined => "application specific error",
+        }
+    }
+}
+
+impl std::fmt::Display for ReturnCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Bytes shown as lowercase hex in debug output, truncated after [HexBytes::MAX_SHOWN] bytes,
+/// e.g. `a1b2c3d4… (32 bytes)`.
+///
+/// Payloads with byte fields like hashes, setting values and upload chunks use it in their
+/// [Debug](std::fmt::Debug) output, so logs don't fill up with long lists of decimal numbers.
+pub struct HexBytes<'a>(pub &'a [u8]);
+
+impl HexBytes<'_> {
+    pub const MAX_SHOWN: usize = 16;
+}
+
+impl std::fmt::Debug for HexBytes<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for byte in self.0.iter().take(Self::MAX_SHOWN) {
+            write!(f, "{:02x}", byte)?;
+        }
+        if self.0.len() > Self::MAX_SHOWN {
+            f.write_str("…")?;
+        }
+        if !self.0.is_empty() {
+            f.write_str(" ")?;
+        }
+        write!(f, "({} bytes)", self.0.len())
+    }
+}
+
+impl std::fmt::Display for HexBytes<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        std::fmt::Debug::fmt(self, f)
+    }
+}
+
+/// A payload that couldn't be decoded, with the header of its frame and the payload itself.
+#[derive(Debug)]
+pub struct PayloadError {
+    pub operation: OpCode,
+    pub group: Group,
+    pub command: u8,
+    pub sequence: u8,
+    /// the Rust type the payload was decoded as
+    pub type_name: &'static str,
+    /// the beginning of the payload, up to [DecodeOptions::payload_dump_limit] bytes
+    pub payload: Vec<u8>,
+    /// length of the whole payload
+    pub payload_len: usize,
+    pub source: Box<dyn std::error::Error + Send + Sync>,
+}
+
+impl std::fmt::Display for PayloadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} {} id {} seq {} as {}: {}, payload",
+            self.operation, self.group, self.command, self.sequence, self.type_name, self.source
+        )?;
+        if !self.payload.is_empty() {
+            f.write_str(" ")?;
+        }
+        for byte in &self.payload {
+            write!(f, "{:02x}", byte)?;
+        }
+        if self.payload.len() < self.payload_len {
+            f.write_str("…")?;
+        }
+        write!(f, " ({} bytes)", self.payload_len)
+    }
+}
+
+impl std::error::Error for PayloadError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(self.source.as_ref())
+    }
+}
+
 /// Definitition of a single SMP message.  
 /// SMP Requests and Responses always have this format.
-#[derive(Debug, Clone)]
+///
+/// Frames are built with [SmpFrame::new] or [SmpFrame::builder], so header fields added later
+/// don't break code outside this crate.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[non_exhaustive]
 pub struct SmpFrame<T> {
     pub operation: OpCode,
+    /// protocol version in bits 3 and 4 of the first header byte, 0 for SMP version 1
+    pub version: u8,
+    /// bits 5 to 7 of the first header byte, reserved by the protocol. Received frames keep
+    /// them as they are, frames of this crate leave them 0
+    pub reserved: u8,
     pub flags: u8,
     pub group: Group,
     pub sequence: u8,
@@ -107,10 +362,12 @@ pub struct SmpFrame<T> {
 }
 
 impl<T> SmpFrame<T> {
-    ///  Create new with default flags
+    ///  Create new with default flags and version, a shorthand for [SmpFrame::builder]
     pub fn new(operation: OpCode, sequence: u8, group: Group, command: u8, payload: T) -> Self {
         Self {
             operation,
+            version: 0,
+            reserved: 0,
             flags: 0,
             group,
             sequence,
@@ -118,6 +375,130 @@ impl<T> SmpFrame<T> {
             data: payload,
         }
     }
+
+    pub fn operation(&self) -> OpCode {
+        self.operation
+    }
+
+    pub fn version(&self) -> u8 {
+        self.version
+    }
+
+    pub fn reserved(&self) -> u8 {
+        self.reserved
+    }
+
+    pub fn flags(&self) -> u8 {
+        self.flags
+    }
+
+    pub fn group(&self) -> Group {
+        self.group
+    }
+
+    pub fn sequence(&self) -> u8 {
+        self.sequence
+    }
+
+    pub fn command(&self) -> u8 {
+        self.command
+    }
+
+    pub fn data(&self) -> &T {
+        &self.data
+    }
+
+    pub fn into_data(self) -> T {
+        self.data
+    }
+}
+
+impl SmpFrame<()> {
+    /// Build a frame with other header fields than [SmpFrame::new] sets, e.g. flags or the
+    /// protocol version: `SmpFrame::builder().op(..).group(..).version(1).payload(data)`
+    pub fn builder() -> SmpFrameBuilder {
+        SmpFrameBuilder::default()
+    }
+}
+
+/// Builder of an [SmpFrame], see [SmpFrame::builder].  
+/// Fields that are not set are 0, a read request to the default group.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SmpFrameBuilder {
+    operation: OpCode,
+    version: u8,
+    reserved: u8,
+    flags: u8,
+    group: Group,
+    sequence: u8,
+    command: u8,
+}
+
+impl Default for SmpFrameBuilder {
+    fn default() -> Self {
+        Self {
+            operation: OpCode::ReadRequest,
+            version: 0,
+            reserved: 0,
+            flags: 0,
+            group: Group::Default,
+            sequence: 0,
+            command: 0,
+        }
+    }
+}
+
+impl SmpFrameBuilder {
+    pub fn op(mut self, operation: OpCode) -> Self {
+        self.operation = operation;
+        self
+    }
+
+    /// The protocol version, only the lower two bits fit into the header
+    pub fn version(mut self, version: u8) -> Self {
+        self.version = version & 0x03;
+        self
+    }
+
+    /// The reserved bits of the first header byte, only the lower three bits fit into it
+    pub fn reserved(mut self, reserved: u8) -> Self {
+        self.reserved = reserved & 0x07;
+        self
+    }
+
+    pub fn flags(mut self, flags: u8) -> Self {
+        self.flags = flags;
+        self
+    }
+
+    pub fn group(mut self, group: Group) -> Self {
+        self.group = group;
+        self
+    }
+
+    pub fn sequence(mut self, sequence: u8) -> Self {
+        self.sequence = sequence;
+        self
+    }
+
+    pub fn command(mut self, command: u8) -> Self {
+        self.command = command;
+        self
+    }
+
+    /// Finish the frame with its payload
+    pub fn payload<T>(self, data: T) -> SmpFrame<T> {
+        SmpFrame {
+            operation: self.operation,
+            version: self.version,
+            reserved: self.reserved,
+            flags: self.flags,
+            group: self.group,
+            sequence: self.sequence,
+            command: self.command,
+            data,
+        }
+    }
 }
 
 impl<T> SmpFrame<T> {
@@ -132,72 +513,558 @@ impl<T> SmpFrame<T> {
         let encoded = encode_payload(&self.data)?;
         let data: &[u8] = encoded.as_ref();
 
-        buf.push(self.operation.into());
+        self.write_header(&mut buf, data.len());
+        buf.extend(data.iter());
+
+        Ok(buf)
+    }
+
+    /// Append the 8 header bytes for a payload of the given length
+    fn write_header(&self, buf: &mut Vec<u8>, data_len: usize) {
+        let operation: u8 = self.operation.into();
+        buf.push(((self.reserved & 0x07) << 5) | ((self.version & 0x03) << 3) | operation);
         buf.push(self.flags);
-        buf.extend_from_slice(&(data.len() as u16).to_be_bytes());
+        buf.extend_from_slice(&(data_len as u16).to_be_bytes());
         let group: u16 = self.group.into();
         buf.extend_from_slice(&group.to_be_bytes());
         buf.push(self.sequence);
         buf.push(self.command);
-
-        buf.extend(data.iter());
-
-        Ok(buf)
     }
 
     /// Decode the frame from bytes using the given decode_payload handler.  
     /// For the common CBOR serialisation, see [SmpFrame::decode_with_cbor]
-    pub fn decode(
-        buf: &[u8],
-        decode_payload: impl FnOnce(&[u8]) -> Result<T, Box<dyn std::error::Error>>,
+    ///
+    /// Undecodable payloads are kept in the error as [DecodeOptions::default] says, see
+    /// [SmpFrame::decode_with] to change that.
+    ///
+    /// The buffer has to hold exactly one frame: a payload shorter or longer than the length
+    /// in the header is rejected with [SmpError::LengthMismatch], so nothing is allocated based
+    /// on the length field, which can't exceed 64 KiB anyway. The flags are kept as they
+    /// are, including reserved bits, and so are the version and the reserved bits of the
+    /// first header byte. Payloads the handler fails on are reported with the header and the
+    /// payload bytes in a [PayloadError].
+    pub fn decode<'b>(
+        buf: &'b [u8],
+        decode_payload: impl FnOnce(&'b [u8]) -> Result<T, Box<dyn std::error::Error + Send + Sync>>,
+    ) -> Result<SmpFrame<T>, SmpError> {
+        Self::decode_with(buf, DecodeOptions::default(), decode_payload)
+    }
+
+    /// Like [SmpFrame::decode], with the given [DecodeOptions]
+    pub fn decode_with<'b>(
+        buf: &'b [u8],
+        options: DecodeOptions,
+        decode_payload: impl FnOnce(&'b [u8]) -> Result<T, Box<dyn std::error::Error + Send + Sync>>,
     ) -> Result<SmpFrame<T>, SmpError> {
-        if buf.len() < 8 {
+        let Some((header, data_buf)) = buf.split_first_chunk::<8>() else {
             return Err(SmpError::InvalidFrame);
-        }
+        };
 
-        let operation = OpCode::from(buf[0] & 0x07);
-        let group = Group::from(u16::from_be_bytes([buf[4], buf[5]]));
-        let data_len = u16::from_be_bytes([buf[2], buf[3]]);
-        let _flags = buf[1];
-        let sequence = buf[6];
-        let command = buf[7];
+        let operation = OpCode::try_from(header[0] & 0x07).map_err(SmpError::UnknownOpCode)?;
+        let version = (header[0] >> 3) & 0x03;
+        let reserved = header[0] >> 5;
+        let flags = header[1];
+        let data_len = u16::from_be_bytes([header[2], header[3]]) as usize;
+        let group = Group::from(u16::from_be_bytes([header[4], header[5]]));
+        let sequence = header[6];
+        let command = header[7];
 
-        if buf.len() < (8 + data_len) as usize {
-            return Err(SmpError::InvalidFrame);
+        if data_buf.len() != data_len {
+            return Err(SmpError::LengthMismatch {
+                expected: data_len,
+                actual: data_buf.len(),
+            });
         }
 
-        let data_buf = &buf[8..(8 + data_len as usize)];
-        let data = decode_payload(data_buf)?;
+        let data = decode_payload(data_buf).map_err(|source| {
+            let limit = options.payload_dump_limit;
+            SmpError::PayloadDecodingError(Box::new(PayloadError {
+                operation,
+                group,
+                command,
+                sequence,
+                type_name: std::any::type_name::<T>(),
+                payload: data_buf[..data_buf.len().min(limit)].to_vec(),
+                payload_len: data_buf.len(),
+                source,
+            }))
+        })?;
 
-        Ok(SmpFrame::new(operation, sequence, group, command, data))
+        Ok(SmpFrame::builder()
+            .op(operation)
+            .version(version)
+            .reserved(reserved)
+            .flags(flags)
+            .group(group)
+            .sequence(sequence)
+            .command(command)
+            .payload(data))
     }
 }
 
+/// How many bytes are missing for the frame starting at the beginning of `buf` to be
+/// complete, according to the length in its header, or `None` if it is complete.  
+/// Without a full header, the bytes missing for the header are returned.
+pub fn missing_bytes(buf: &[u8]) -> Option<usize> {
+    let Some((header, data)) = buf.split_first_chunk::<8>() else {
+        return Some(8 - buf.len());
+    };
+
+    let data_len = u16::from_be_bytes([header[2], header[3]]) as usize;
+    data_len
+        .checked_sub(data.len())
+        .filter(|missing| *missing > 0)
+}
+
+/// How frames are decoded, see [SmpFrame::decode_with]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub struct DecodeOptions {
+    /// bytes of an undecodable payload kept in its [PayloadError], 64 by default
+    pub payload_dump_limit: usize,
+}
+
+impl Default for DecodeOptions {
+    fn default() -> Self {
+        Self {
+            payload_dump_limit: 64,
+        }
+    }
+}
+
+/// How CBOR payloads are encoded, see [SmpFrame::encode_with_cbor_as]
+#[cfg(feature = "payload-cbor")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum CborEncoding {
+    /// map keys in the order serde produces them, usually the order of the struct fields
+    #[default]
+    Plain,
+    /// deterministic encoding as defined in RFC 8949 section 4.2: definite lengths, the
+    /// shortest form of integers and map keys sorted by their encoded bytes
+    Canonical,
+}
+
 #[cfg(feature = "payload-cbor")]
 impl<T: serde::Serialize> SmpFrame<T> {
     /// Encode the frame to bytes using CBOR serialization.  
     /// This method requires Serde
     pub fn encode_with_cbor(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        self.encode_into(&mut buf);
+        buf
+    }
+
+    /// Encode the frame into `buf`, replacing its contents.
+    ///
+    /// The payload is serialized right behind the header, so a buffer reused for every frame
+    /// of e.g. an upload only allocates when a frame is larger than all before.
+    pub fn encode_into(&self, buf: &mut Vec<u8>) {
+        self.encode_into_as(buf, CborEncoding::Plain)
+    }
+
+    /// Encode the frame to bytes using the given CBOR encoding.
+    ///
+    /// The same payload always gives the same bytes with [CborEncoding::Canonical], e.g. for
+    /// comparing or signing frames. Devices decode both encodings the same way.
+    pub fn encode_with_cbor_as(&self, encoding: CborEncoding) -> Vec<u8> {
+        let mut buf = Vec::new();
+        self.encode_into_as(&mut buf, encoding);
+        buf
+    }
+
+    /// Like [SmpFrame::encode_into], with the given CBOR encoding.  
+    /// [CborEncoding::Canonical] still builds the payload as a value first to sort it.
+    pub fn encode_into_as(&self, buf: &mut Vec<u8>, encoding: CborEncoding) {
+        buf.clear();
+        self.write_header(buf, 0);
+
         // buf cannot run out of space because it can allocate
-        self.encode(|data| {
-            let mut buf = Vec::new();
-            match ciborium::ser::into_writer(data, &mut buf) {
-                Ok(_) => Ok(buf),
-                Err(e) => Err(e),
-            }
-        })
-        .unwrap()
+        match encoding {
+            CborEncoding::Plain => crate::cbor::encode_into(&self.data, buf).unwrap(),
+            CborEncoding::Canonical => crate::cbor::encode_canonical_into(&self.data, buf).unwrap(),
+        }
+
+        let data_len = (buf.len() - 8) as u16;
+        buf[2..4].copy_from_slice(&data_len.to_be_bytes());
     }
 }
 
+#[cfg(feature = "payload-cbor")]
+impl<T: serde::Serialize> std::fmt::Display for SmpFrame<T> {
+    /// A one-line summary of the header and the length of the CBOR payload, e.g.
+    /// `write img id 1 seq 12 len 290`
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} {} id {} seq {}",
+            self.operation, self.group, self.command, self.sequence
+        )?;
+
+        match crate::cbor::encoded_len(&self.data) {
+            Ok(len) => write!(f, " len {}", len),
+            Err(_) => f.write_str(" len ?"),
+        }
+    }
+}
+
+/// A CBOR value of a field this crate doesn't know
+#[cfg(feature = "payload-cbor")]
+pub use ciborium::Value as CborValue;
+
+/// Fields of a response that this crate doesn't know, e.g. vendor extensions, by their name.
+/// They are kept when the response is encoded again.
+#[cfg(feature = "payload-cbor")]
+pub type ExtraFields = std::collections::BTreeMap<String, CborValue>;
+
+/// Nesting depth of CBOR payloads [SmpFrame::decode_with_cbor] accepts. SMP payloads nest a
+/// few levels at most, deeper payloads are rejected instead of exhausting the stack.
+#[cfg(feature = "payload-cbor")]
+pub const MAX_CBOR_DEPTH: usize = 64;
+
 #[cfg(feature = "payload-cbor")]
 impl<T: serde::de::DeserializeOwned> SmpFrame<T> {
     /// Decode the frame to bytes using CBOR deserialization.  
     /// This method requires Serde
     pub fn decode_with_cbor(buf: &[u8]) -> Result<SmpFrame<T>, SmpError> {
-        Self::decode(buf, |buf| {
-            let x: T = ciborium::de::from_reader(buf)?;
-            Ok(x)
-        })
+        Self::decode(buf, crate::cbor::decode)
+    }
+
+    /// Like [SmpFrame::decode_with_cbor], with the given [DecodeOptions]
+    pub fn decode_with_cbor_as(
+        buf: &[u8],
+        options: DecodeOptions,
+    ) -> Result<SmpFrame<T>, SmpError> {
+        Self::decode_with(buf, options, crate::cbor::decode)
+    }
+}
+
+#[cfg(feature = "payload-cbor-borrowed")]
+impl<T> SmpFrame<T> {
+    /// Like [SmpFrame::decode_with_cbor], for payloads borrowing strings and byte strings
+    /// from `buf` instead of copying them, e.g.
+    /// [TaskStatsResultRef](crate::os_management::TaskStatsResultRef).  
+    /// This method requires the `payload-cbor-borrowed` feature, which decodes with cbor4ii
+    pub fn decode_with_cbor_borrowed<'b>(buf: &'b [u8]) -> Result<SmpFrame<T>, SmpError>
+    where
+        T: serde::Deserialize<'b>,
+    {
+        Self::decode(buf, crate::cbor::decode_borrowed)
+    }
+}
+
+/// Deserialize the `rc` of the `Err` variant of a result.
+///
+/// Devices report errors as a nonzero `rc` (SMP version 1) or as an `err` map with the group
+/// and its own error code (version 2), which the `Err` variants accept under the alias `err`.
+/// Some firmware adds `rc: 0` to successful responses, which is rejected here so that untagged
+/// results fall through to their `Ok` variant.
+#[cfg(feature = "payload-cbor")]
+pub(crate) fn error_rc<'de, D>(deserializer: D) -> Result<i32, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    use serde::de::Error;
+    use serde::Deserialize;
+
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Rc {
+        Code(i32),
+        /// `{ group, rc }`, the group is implied by the request
+        Group {
+            rc: i32,
+        },
+    }
+
+    match Rc::deserialize(deserializer)? {
+        Rc::Code(0) | Rc::Group { rc: 0 } => Err(D::Error::custom("rc 0 is not an error")),
+        Rc::Code(rc) | Rc::Group { rc } => Ok(rc),
+    }
+}
+
+/// The error code of a response: a nonzero `rc` (SMP version 1) or the `rc` of an `err` map
+/// (version 2). `None` for a successful response, also with `rc: 0`.
+#[cfg(feature = "payload-cbor")]
+fn response_rc(value: &CborValue) -> Option<i32> {
+    fn field<'v>(map: &'v [(CborValue, CborValue)], name: &str) -> Option<&'v CborValue> {
+        map.iter()
+            .find(|(key, _)| key.as_text() == Some(name))
+            .map(|(_, value)| value)
+    }
+
+    let map = value.as_map()?;
+    let rc = match field(map, "err").or_else(|| field(map, "rc"))? {
+        CborValue::Map(err) => field(err, "rc")?,
+        rc => rc,
+    };
+    let rc = i32::try_from(i128::from(rc.as_integer()?)).ok()?;
+    (rc != 0).then_some(rc)
+}
+
+/// The `rc` of an error response without further fields, see [deserialize_result]
+#[cfg(feature = "payload-cbor")]
+#[derive(serde::Deserialize)]
+pub(crate) struct ErrorCode {
+    #[serde(alias = "err", deserialize_with = "error_rc")]
+    pub rc: i32,
+}
+
+/// Deserialize a response into its success payload `T` or its error `E`, decided by the error
+/// code alone: `E` if the response has a nonzero `rc` or an `err` map with one, `T` otherwise.
+///
+/// Untagged enums pick the first variant whose fields are present, so the outcome would depend
+/// on the order of the variants and on which fields they share, e.g. an error carrying `off`
+/// would decode as the successful upload of a chunk.
+#[cfg(feature = "payload-cbor")]
+pub(crate) fn deserialize_result<'de, D, T, E>(deserializer: D) -> Result<Result<T, E>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: serde::de::DeserializeOwned,
+    E: serde::de::DeserializeOwned,
+{
+    use serde::de::Error;
+    use serde::Deserialize;
+
+    let value = CborValue::deserialize(deserializer)?;
+    let result = match response_rc(&value) {
+        Some(_) => value.deserialized().map(Err),
+        None => value.deserialized().map(Ok),
+    };
+    result.map_err(D::Error::custom)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An echo request, `{"d": "hello"}`
+    const ECHO: [u8; 17] = [
+        0x02, 0x00, 0x00, 0x09, 0x00, 0x00, 0x2a, 0x00, // header
+        0xa1, 0x61, b'd', 0x65, b'h', b'e', b'l', b'l', b'o',
+    ];
+
+    fn raw(data: &[u8]) -> Result<Vec<u8>, Box<dyn std::error::Error + Send + Sync>> {
+        Ok(data.to_vec())
+    }
+
+    #[test]
+    fn frame_decodes() {
+        let frame = SmpFrame::decode(&ECHO, raw).unwrap();
+        assert_eq!(frame.operation, OpCode::WriteRequest);
+        assert_eq!(frame.group, Group::Default);
+        assert_eq!(frame.sequence, 42);
+        assert_eq!(frame.data, &ECHO[8..]);
+    }
+
+    #[test]
+    fn truncated_frames_are_rejected() {
+        for len in 0..ECHO.len() {
+            match SmpFrame::decode(&ECHO[..len], raw) {
+                Err(SmpError::InvalidFrame) if len < 8 => {}
+                Err(SmpError::LengthMismatch { expected, actual }) if len >= 8 => {
+                    assert_eq!((expected, actual), (9, len - 8));
+                }
+                result => panic!("{} bytes: {:?}", len, result),
+            }
+        }
+    }
+
+    #[test]
+    fn trailing_bytes_are_rejected() {
+        let mut frame = ECHO.to_vec();
+        frame.push(0);
+        assert!(matches!(
+            SmpFrame::decode(&frame, raw),
+            Err(SmpError::LengthMismatch {
+                expected: 9,
+                actual: 10
+            })
+        ));
+    }
+
+    #[cfg(feature = "payload-cbor")]
+    #[test]
+    fn truncated_payloads_are_rejected() {
+        // with the length in the header fixed up, the truncated payload reaches the CBOR decoder
+        for len in 8..ECHO.len() {
+            let mut frame = ECHO[..len].to_vec();
+            frame[2..4].copy_from_slice(&((len - 8) as u16).to_be_bytes());
+            let result = SmpFrame::<CborValue>::decode_with_cbor(&frame);
+            assert!(
+                matches!(result, Err(SmpError::PayloadDecodingError(_))),
+                "{} bytes: {:?}",
+                len,
+                result
+            );
+        }
+    }
+
+    #[cfg(feature = "payload-cbor")]
+    #[test]
+    fn undecodable_payloads_are_dumped_up_to_the_limit() {
+        let options = DecodeOptions {
+            payload_dump_limit: 4,
+        };
+        let Err(SmpError::PayloadDecodingError(error)) =
+            SmpFrame::<u8>::decode_with_cbor_as(&ECHO, options)
+        else {
+            panic!("an echo request decoded as a number");
+        };
+        assert_eq!(error.payload, &ECHO[8..12]);
+        assert_eq!(error.payload_len, 9);
+        let message = error.to_string();
+        assert!(
+            message.starts_with("write os id 0 seq 42 as u8: "),
+            "{}",
+            message
+        );
+        assert!(
+            message.ends_with(", payload a1616465… (9 bytes)"),
+            "{}",
+            message
+        );
+
+        let Err(SmpError::PayloadDecodingError(error)) = SmpFrame::<u8>::decode_with_cbor(&ECHO)
+        else {
+            panic!("an echo request decoded as a number");
+        };
+        assert_eq!(error.payload, &ECHO[8..]);
+    }
+
+    #[cfg(feature = "payload-cbor-borrowed")]
+    #[test]
+    fn borrowed_payloads_are_as_deep_as_owned_ones() {
+        let nested = |depth: usize| {
+            let mut payload = vec![0x81; depth];
+            payload.push(0x00);
+            SmpFrame::new(OpCode::ReadResponse, 0, Group::Default, 0, payload)
+                .encode(|data| Ok::<_, ()>(data.clone()))
+                .unwrap()
+        };
+
+        // the limit of ciborium for the same value
+        let frame = nested(MAX_CBOR_DEPTH);
+        assert!(SmpFrame::<CborValue>::decode_with_cbor(&frame).is_ok());
+        assert!(SmpFrame::<CborValue>::decode_with_cbor_borrowed(&frame).is_ok());
+        let frame = nested(MAX_CBOR_DEPTH + 1);
+        assert!(SmpFrame::<CborValue>::decode_with_cbor(&frame).is_err());
+        assert!(matches!(
+            SmpFrame::<CborValue>::decode_with_cbor_borrowed(&frame),
+            Err(SmpError::PayloadDecodingError(_))
+        ));
+    }
+
+    #[test]
+    fn unknown_opcodes_are_rejected() {
+        for op in 4..8 {
+            let mut frame = ECHO;
+            frame[0] = op;
+            assert!(matches!(
+                SmpFrame::decode(&frame, raw),
+                Err(SmpError::UnknownOpCode(code)) if code == op
+            ));
+        }
+    }
+
+    #[test]
+    fn reserved_flags_and_version_are_kept() {
+        let mut buf = ECHO;
+        buf[0] |= 0xa8;
+        buf[1] = 0xf0;
+        let frame = SmpFrame::decode(&buf, raw).unwrap();
+        assert_eq!(frame.operation, OpCode::WriteRequest);
+        assert_eq!((frame.version, frame.reserved, frame.flags), (1, 5, 0xf0));
+
+        // and written back as they were
+        let encoded = frame.encode(|data| Ok::<_, std::convert::Infallible>(data.clone()));
+        assert_eq!(encoded.unwrap(), buf);
+    }
+
+    const OPCODES: [(OpCode, u8, &str); 4] = [
+        (OpCode::ReadRequest, 0, "read"),
+        (OpCode::ReadResponse, 1, "read rsp"),
+        (OpCode::WriteRequest, 2, "write"),
+        (OpCode::WriteResponse, 3, "write rsp"),
+    ];
+
+    const GROUPS: [(Group, u16, &str); 8] = [
+        (Group::Default, 0, "os"),
+        (Group::ApplicationManagement, 1, "img"),
+        (Group::Statistics, 2, "stat"),
+        (Group::SettingManagement, 3, "config"),
+        (Group::LogManagement, 4, "log"),
+        (Group::FileManagement, 8, "fs"),
+        (Group::ShellManagement, 9, "shell"),
+        (Group::ZephyrCommand, 63, "zephyr"),
+    ];
+
+    #[test]
+    fn opcode_conversions() {
+        for (op, num, name) in OPCODES {
+            assert_eq!(OpCode::try_from(num), Ok(op));
+            assert_eq!(u8::from(op), num);
+            assert_eq!(op.to_string(), name);
+        }
+        for num in [4, 7, 8, 255] {
+            assert_eq!(OpCode::try_from(num), Err(num));
+        }
+    }
+
+    #[test]
+    fn requests_and_responses() {
+        use OpCode::*;
+
+        for (request, response) in [(ReadRequest, ReadResponse), (WriteRequest, WriteResponse)] {
+            assert!(request.is_request() && !request.is_response());
+            assert!(response.is_response() && !response.is_request());
+            assert_eq!(request.response_of(), response);
+            assert_eq!(response.response_of(), response);
+            assert_eq!(response.request_of(), request);
+            assert_eq!(request.request_of(), request);
+        }
+    }
+
+    #[test]
+    fn group_conversions() {
+        for (group, num, name) in GROUPS {
+            assert_eq!(Group::from(num), group);
+            assert_eq!(u16::from(group), num);
+            assert_eq!(group.name(), Some(name));
+            assert_eq!(group.to_string(), name);
+        }
+        for num in [5, 10, 64, u16::MAX] {
+            let group = Group::from(num);
+            assert_eq!(group, Group::Custom(num));
+            assert_eq!(u16::from(group), num);
+            assert_eq!(group.name(), None);
+            assert_eq!(group.to_string(), format!("group {}", num));
+        }
+    }
+
+    #[test]
+    fn hex_bytes_format() {
+        assert_eq!(format!("{:?}", HexBytes(&[])), "(0 bytes)");
+        assert_eq!(
+            format!("{:?}", HexBytes(&[0xa1, 0xb2, 0x03])),
+            "a1b203 (3 bytes)"
+        );
+        assert_eq!(
+            format!("{}", HexBytes(&[0xab; 32])),
+            "abababababababababababababababab… (32 bytes)"
+        );
+    }
+
+    #[cfg(feature = "payload-cbor")]
+    #[test]
+    fn frame_display() {
+        let echo = crate::os_management::echo(12, "hello".to_string());
+        assert_eq!(echo.to_string(), "write os id 0 seq 12 len 9");
+
+        let custom = SmpFrame::builder()
+            .op(OpCode::ReadResponse)
+            .group(Group::Custom(64))
+            .command(3)
+            .payload(CborValue::Map(vec![]));
+        assert_eq!(custom.to_string(), "read rsp group 64 id 3 seq 0 len 1");
     }
 }
@@ -0,0 +1,140 @@
+use crate::{Group, SmpFrame};
+
+use crate::OpCode::ReadRequest;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct GroupDataRequest {
+    pub name: String,
+}
+
+/// Read all counters of a statistics group
+pub fn group_data(sequence: u8, name: String) -> SmpFrame<GroupDataRequest> {
+    let payload = GroupDataRequest { name };
+
+    SmpFrame::new(ReadRequest, sequence, Group::Statistics, 0, payload)
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+#[serde(untagged)]
+pub enum GroupDataResult {
+    Ok {
+        name: String,
+        fields: BTreeMap<String, u64>,
+    },
+    Err {
+        #[serde(alias = "err", deserialize_with = "crate::smp::error_rc")]
+        rc: i32,
+    },
+}
+
+/// Like [GroupDataResult], with the names borrowed from the response, see
+/// [SmpFrame::decode_with_cbor_borrowed]
+#[cfg(feature = "payload-cbor-borrowed")]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+#[serde(untagged)]
+pub enum GroupDataResultRef<'a> {
+    Ok {
+        name: &'a str,
+        #[serde(borrow)]
+        fields: BTreeMap<&'a str, u64>,
+    },
+    Err {
+        #[serde(alias = "err", deserialize_with = "crate::smp::error_rc")]
+        rc: i32,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ListGroupsRequest {}
+
+/// List the names of all statistics groups
+pub fn list_groups(sequence: u8) -> SmpFrame<ListGroupsRequest> {
+    SmpFrame::new(
+        ReadRequest,
+        sequence,
+        Group::Statistics,
+        1,
+        ListGroupsRequest {},
+    )
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+#[serde(untagged)]
+pub enum ListGroupsResult {
+    Ok {
+        stat_list: Vec<String>,
+    },
+    Err {
+        #[serde(alias = "err", deserialize_with = "crate::smp::error_rc")]
+        rc: i32,
+    },
+}
+
+/// Like [ListGroupsResult], with the group names borrowed from the response, see
+/// [SmpFrame::decode_with_cbor_borrowed]
+#[cfg(feature = "payload-cbor-borrowed")]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+#[serde(untagged)]
+pub enum ListGroupsResultRef<'a> {
+    Ok {
+        #[serde(borrow)]
+        stat_list: Vec<&'a str>,
+    },
+    Err {
+        #[serde(alias = "err", deserialize_with = "crate::smp::error_rc")]
+        rc: i32,
+    },
+}
+
+#[cfg(all(test, feature = "payload-cbor-borrowed"))]
+mod tests {
+    use super::*;
+    use crate::cbor::{decode_borrowed, decode_value};
+    use ciborium::{cbor, Value};
+
+    fn encode(value: &Value) -> Vec<u8> {
+        let mut buf = Vec::new();
+        ciborium::ser::into_writer(value, &mut buf).unwrap();
+        buf
+    }
+
+    #[test]
+    fn group_data_borrowed() {
+        let value =
+            cbor!({ "name" => "ble_phy", "fields" => { "tx_good" => 12, "rx_crc" => 0 } }).unwrap();
+        let buf = encode(&value);
+
+        let GroupDataResultRef::Ok { name, fields } = decode_borrowed(&buf).unwrap() else {
+            panic!("group data is an error");
+        };
+        assert_eq!(name, "ble_phy");
+        assert_eq!(fields, BTreeMap::from([("rx_crc", 0), ("tx_good", 12)]));
+        assert!(buf.as_ptr_range().contains(&name.as_ptr()));
+
+        let GroupDataResult::Ok { fields: owned, .. } = decode_value(value) else {
+            panic!("group data is an error");
+        };
+        assert!(fields
+            .iter()
+            .map(|(k, v)| (*k, v))
+            .eq(owned.iter().map(|(k, v)| (k.as_str(), v))));
+    }
+
+    #[test]
+    fn list_groups_borrowed() {
+        let buf = encode(&cbor!({ "stat_list" => ["ble_phy", "ble_ll"] }).unwrap());
+        let result: ListGroupsResultRef = decode_borrowed(&buf).unwrap();
+        assert_eq!(
+            result,
+            ListGroupsResultRef::Ok {
+                stat_list: vec!["ble_phy", "ble_ll"]
+            }
+        );
+
+        let buf = encode(&cbor!({ "rc" => 5 }).unwrap());
+        let result: ListGroupsResultRef = decode_borrowed(&buf).unwrap();
+        assert_eq!(result, ListGroupsResultRef::Err { rc: 5 });
+    }
+}
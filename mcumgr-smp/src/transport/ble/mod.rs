@@ -6,17 +6,123 @@ use btleplug::{
     api::{Central, Characteristic, Manager as _, Peripheral as _, ScanFilter},
     platform::{Adapter, Manager, Peripheral},
 };
-use futures::{Stream, StreamExt};
+use futures::{Future, Stream, StreamExt};
+use std::io;
 use std::{pin::Pin, time::Duration};
-use tokio::time::sleep;
+use tokio::time::{sleep, Instant};
 use uuid::{uuid, Uuid};
 
 pub const SMP_CHAR: Uuid = uuid!("DA2E7828-FBCE-4E01-AE9E-261174997C48");
 
+/// Deadlines of the steps of a BLE connection and of each request.
+///
+/// Some platforms never complete a step, e.g. service discovery of a device that went out of
+/// range, so every step fails after its deadline with an error naming it, like
+/// `service discovery timed out after 10 s`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BleTimeouts {
+    /// How long to listen for advertisements if the adapter doesn't know the device yet
+    pub scan: Duration,
+    /// Each call to the adapter while looking for the device, e.g. listing peripherals
+    pub adapter: Duration,
+    pub connect: Duration,
+    pub discovery: Duration,
+    pub subscribe: Duration,
+    /// All steps of connecting together, each step gets at most what is left of it
+    pub total: Duration,
+    /// Writing a request
+    pub write: Duration,
+    /// Waiting for a notification of a response. `None` leaves the deadline to the caller,
+    /// who knows how long e.g. a flash erase takes
+    pub notification: Option<Duration>,
+}
+
+impl BleTimeouts {
+    /// `scan` for advertisements and `step` for each other step
+    pub fn new(scan: Duration, step: Duration) -> Self {
+        Self {
+            scan,
+            adapter: step,
+            connect: step,
+            discovery: step,
+            subscribe: step,
+            total: scan + step * 3,
+            write: step,
+            notification: None,
+        }
+    }
+}
+
+impl Default for BleTimeouts {
+    /// 5 s of scanning and 10 s for each other step
+    fn default() -> Self {
+        Self::new(Duration::from_secs(5), Duration::from_secs(10))
+    }
+}
+
+/// Run one step of the connection or of a request for at most `timeout`
+async fn step<T>(
+    name: &str,
+    timeout: Duration,
+    future: impl Future<Output = Result<T, btleplug::Error>>,
+) -> Result<T, Error> {
+    match tokio::time::timeout(timeout, future).await {
+        Ok(ret) => Ok(ret?),
+        Err(_) => Err(timed_out(format!(
+            "{} timed out after {:.1?}",
+            name, timeout
+        ))),
+    }
+}
+
+fn timed_out(msg: String) -> Error {
+    io::Error::new(io::ErrorKind::TimedOut, msg).into()
+}
+
+/// The overall deadline of connecting
+struct Deadline {
+    at: Instant,
+    total: Duration,
+}
+
+impl Deadline {
+    fn new(total: Duration) -> Self {
+        Self {
+            at: Instant::now() + total,
+            total,
+        }
+    }
+
+    fn left(&self) -> Duration {
+        self.at.saturating_duration_since(Instant::now())
+    }
+
+    /// Run a step of connecting for at most `timeout`, or what is left of the total if less
+    async fn step<T>(
+        &self,
+        name: &str,
+        timeout: Duration,
+        future: impl Future<Output = Result<T, btleplug::Error>>,
+    ) -> Result<T, Error> {
+        let left = self.left();
+        if left >= timeout {
+            return step(name, timeout, future).await;
+        }
+        match tokio::time::timeout(left, future).await {
+            Ok(ret) => Ok(ret?),
+            Err(_) => Err(timed_out(format!(
+                "connecting timed out after {:.1?}, during {}",
+                self.total, name
+            ))),
+        }
+    }
+}
+
 pub struct BleTransport {
     peripheral_device: Peripheral,
     smp_char: Characteristic,
     notifications: Pin<Box<dyn Stream<Item = btleplug::api::ValueNotification> + Send>>,
+    timeouts: BleTimeouts,
 }
 
 impl BleTransport {
@@ -34,57 +140,47 @@ impl BleTransport {
     /// After that allows to find peripheral device by advertized name.
     /// Unfortunatelly, MacOS and iOS doesn't allow access to BD-addresses
     /// of peripheral devices, so name filtering is the only way.
+    ///
+    /// The other steps get the deadlines of [BleTimeouts::default].
     pub async fn new(
         name: String,
         adapter: &Adapter,
         scan_timeout: Duration,
     ) -> Result<Self, Error> {
-        let mut peripheral_device = None;
+        let timeouts = BleTimeouts {
+            scan: scan_timeout,
+            ..BleTimeouts::default()
+        };
+        Self::with_timeouts(name, adapter, timeouts).await
+    }
 
-        for pd in adapter.peripherals().await? {
-            if let Some(props) = pd.properties().await? {
-                if props.local_name == Some(name.to_owned()) {
-                    peripheral_device = Some(pd);
-                    break;
-                }
-            }
-        }
+    /// Like [Self::new], with deadlines for every step
+    pub async fn with_timeouts(
+        name: String,
+        adapter: &Adapter,
+        timeouts: BleTimeouts,
+    ) -> Result<Self, Error> {
+        let deadline = Deadline::new(timeouts.total);
+
+        let mut peripheral_device = find(adapter, &name, &timeouts, &deadline).await?;
 
         if peripheral_device.is_none() {
-            adapter.start_scan(ScanFilter::default()).await?;
-            sleep(scan_timeout).await;
-            adapter.stop_scan().await?;
-
-            for pd in adapter.peripherals().await? {
-                if let Some(props) = pd.properties().await? {
-                    if props.local_name == Some(name.to_owned()) {
-                        peripheral_device = Some(pd);
-                        break;
-                    }
-                }
-            }
+            let scan = adapter.start_scan(ScanFilter::default());
+            deadline
+                .step("starting the scan", timeouts.adapter, scan)
+                .await?;
+            sleep(timeouts.scan.min(deadline.left())).await;
+            deadline
+                .step("stopping the scan", timeouts.adapter, adapter.stop_scan())
+                .await?;
+
+            peripheral_device = find(adapter, &name, &timeouts, &deadline).await?;
         }
 
         let peripheral_device =
             peripheral_device.ok_or(Error::BLE(btleplug::Error::DeviceNotFound))?;
 
-        peripheral_device.connect().await?;
-        peripheral_device.discover_services().await?;
-        let smp_char = peripheral_device
-            .characteristics()
-            .into_iter()
-            .find(|attr| attr.uuid == SMP_CHAR)
-            .ok_or(Error::BLE(btleplug::Error::NoSuchCharacteristic))?;
-
-        peripheral_device.subscribe(&smp_char).await?;
-
-        let notifications = peripheral_device.notifications().await?;
-
-        Ok(Self {
-            peripheral_device,
-            notifications,
-            smp_char,
-        })
+        Self::connect(peripheral_device, timeouts, &deadline).await
     }
 
     /// A bit more flexible than new()
@@ -92,50 +188,137 @@ impl BleTransport {
     /// implemented by himself. For example - Scan filtering by the list of
     /// advertized services.
     pub async fn from_peripheral(device: Peripheral) -> Result<Self, Error> {
-        device.connect().await?;
-        device.discover_services().await?;
+        Self::from_peripheral_with_timeouts(device, BleTimeouts::default()).await
+    }
+
+    /// Like [Self::from_peripheral], with deadlines for every step
+    pub async fn from_peripheral_with_timeouts(
+        device: Peripheral,
+        timeouts: BleTimeouts,
+    ) -> Result<Self, Error> {
+        Self::connect(device, timeouts, &Deadline::new(timeouts.total)).await
+    }
+
+    async fn connect(
+        device: Peripheral,
+        timeouts: BleTimeouts,
+        deadline: &Deadline,
+    ) -> Result<Self, Error> {
+        deadline
+            .step("connecting", timeouts.connect, device.connect())
+            .await?;
+        deadline
+            .step(
+                "service discovery",
+                timeouts.discovery,
+                device.discover_services(),
+            )
+            .await?;
         let smp_char = device
             .characteristics()
             .into_iter()
             .find(|attr| attr.uuid == SMP_CHAR)
             .ok_or(Error::BLE(btleplug::Error::NoSuchCharacteristic))?;
 
-        device.subscribe(&smp_char).await?;
-
-        let notifications = device.notifications().await?;
+        deadline
+            .step(
+                "subscribing",
+                timeouts.subscribe,
+                device.subscribe(&smp_char),
+            )
+            .await?;
+        let notifications = deadline
+            .step("subscribing", timeouts.subscribe, device.notifications())
+            .await?;
 
         Ok(Self {
             peripheral_device: device,
             notifications,
             smp_char,
+            timeouts,
         })
     }
+
+    pub fn timeouts(&self) -> BleTimeouts {
+        self.timeouts
+    }
+}
+
+/// The peripheral the adapter knows with the advertised `name`
+async fn find(
+    adapter: &Adapter,
+    name: &str,
+    timeouts: &BleTimeouts,
+    deadline: &Deadline,
+) -> Result<Option<Peripheral>, Error> {
+    let peripherals = deadline
+        .step(
+            "listing peripherals",
+            timeouts.adapter,
+            adapter.peripherals(),
+        )
+        .await?;
+    for pd in peripherals {
+        let props = deadline
+            .step(
+                "reading an advertisement",
+                timeouts.adapter,
+                pd.properties(),
+            )
+            .await?;
+        if props.is_some_and(|props| props.local_name.as_deref() == Some(name)) {
+            return Ok(Some(pd));
+        }
+    }
+    Ok(None)
 }
 
 #[async_trait]
 impl SmpTransportAsync for BleTransport {
     async fn send(&mut self, frame: Vec<u8>) -> Result<(), Error> {
-        self.peripheral_device
-            .write(
-                &self.smp_char,
-                &frame,
-                btleplug::api::WriteType::WithoutResponse,
-            )
-            .await?;
-        Ok(())
+        self.send_slice(&frame).await
+    }
+
+    async fn send_slice(&mut self, frame: &[u8]) -> Result<(), Error> {
+        let write = self.peripheral_device.write(
+            &self.smp_char,
+            frame,
+            btleplug::api::WriteType::WithoutResponse,
+        );
+        step("writing the request", self.timeouts.write, write).await
     }
 
     async fn receive(&mut self) -> Result<Vec<u8>, Error> {
-        loop {
-            match self.notifications.next().await {
-                Some(res) if res.uuid == SMP_CHAR => return Ok(res.value),
-                Some(_) => continue,
-                None => {
-                    return Err(Error::BLE(btleplug::Error::RuntimeError(String::from(
-                        "Notification stream error",
-                    ))));
+        let notifications = &mut self.notifications;
+        let next = async {
+            loop {
+                match notifications.next().await {
+                    Some(res) if res.uuid == SMP_CHAR => return Ok(res.value),
+                    Some(_) => continue,
+                    None => {
+                        return Err(btleplug::Error::RuntimeError(String::from(
+                            "Notification stream error",
+                        )));
+                    }
                 }
             }
+        };
+        match self.timeouts.notification {
+            Some(timeout) => step("waiting for a notification", timeout, next).await,
+            None => Ok(next.await?),
         }
     }
+
+    async fn close(&mut self) -> Result<(), Error> {
+        let timeout = self.timeouts.connect;
+        let unsubscribe = self.peripheral_device.unsubscribe(&self.smp_char);
+        step("unsubscribing", timeout, unsubscribe).await?;
+        step(
+            "disconnecting",
+            timeout,
+            self.peripheral_device.disconnect(),
+        )
+        .await?;
+        Ok(())
+    }
 }
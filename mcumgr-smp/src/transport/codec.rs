@@ -0,0 +1,154 @@
+use std::convert::Infallible;
+
+use bytes::BytesMut;
+use tokio_util::codec::{Decoder, Encoder};
+
+use super::smp_framing::{self, ConsoleDecoder};
+use crate::transport::error::Error;
+use crate::SmpFrame;
+
+/// The serial console framing as a tokio codec, e.g. for a
+/// [Framed](tokio_util::codec::Framed) over a TCP bridge to a serial port.
+///
+/// Frames carry their payload as raw bytes, to be decoded like
+/// `ciborium::from_reader(frame.data.as_slice())`. Decoding is incremental, console output
+/// around frames is skipped the same way [SerialTransport](super::serial::SerialTransport)
+/// does.
+#[derive(Default)]
+pub struct SmpSerialCodec {
+    console: ConsoleDecoder,
+}
+
+impl SmpSerialCodec {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Bytes of other console output skipped so far
+    pub fn skipped_bytes(&self) -> u64 {
+        self.console.skipped_bytes()
+    }
+}
+
+impl Decoder for SmpSerialCodec {
+    type Item = SmpFrame<Vec<u8>>;
+    type Error = Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        while let Some(len) = smp_framing::line_len(src) {
+            let line = src.split_to(len);
+            if let Some(frame) = self.console.input_line(&line)? {
+                return Ok(Some(SmpFrame::decode(&frame, |data| Ok(data.to_vec()))?));
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+impl Encoder<SmpFrame<Vec<u8>>> for SmpSerialCodec {
+    type Error = Error;
+
+    fn encode(
+        &mut self,
+        mut frame: SmpFrame<Vec<u8>>,
+        dst: &mut BytesMut,
+    ) -> Result<(), Self::Error> {
+        let payload = std::mem::take(&mut frame.data);
+        let bytes = frame
+            .encode(|_| Ok::<_, Infallible>(payload))
+            .unwrap_or_else(|e| match e {});
+        dst.extend_from_slice(&smp_framing::encode_lines(&bytes));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Group, OpCode};
+
+    fn frame(payload_len: usize) -> SmpFrame<Vec<u8>> {
+        let payload = (0..payload_len).map(|i| i as u8).collect();
+        SmpFrame::new(OpCode::WriteResponse, 7, Group::Default, 0, payload)
+    }
+
+    fn encoded(frame: &SmpFrame<Vec<u8>>) -> BytesMut {
+        let mut buf = BytesMut::new();
+        SmpSerialCodec::new()
+            .encode(frame.clone(), &mut buf)
+            .unwrap();
+        buf
+    }
+
+    /// Feed `input` in the given pieces, decoding after each, and collect the frames
+    fn decode_in_pieces<'a>(pieces: impl IntoIterator<Item = &'a [u8]>) -> Vec<SmpFrame<Vec<u8>>> {
+        let mut codec = SmpSerialCodec::new();
+        let mut buf = BytesMut::new();
+        let mut frames = Vec::new();
+        for piece in pieces {
+            buf.extend_from_slice(piece);
+            while let Some(frame) = codec.decode(&mut buf).unwrap() {
+                frames.push(frame);
+            }
+        }
+        frames
+    }
+
+    #[test]
+    fn every_split_point() {
+        // short enough for one line, and spread over several
+        for frame in [frame(10), frame(300)] {
+            let input = encoded(&frame);
+            for split in 0..=input.len() {
+                let (first, second) = input.split_at(split);
+                assert_eq!(
+                    decode_in_pieces([first, second]),
+                    std::slice::from_ref(&frame),
+                    "{}",
+                    split
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn byte_by_byte() {
+        let frame = frame(300);
+        let input = encoded(&frame);
+        assert_eq!(decode_in_pieces(input.chunks(1)), [frame]);
+    }
+
+    #[test]
+    fn console_output_is_skipped() {
+        let frame = frame(10);
+        let mut input = b"uart:~$ \r\n*** Booting Zephyr ***\n".to_vec();
+        input.extend_from_slice(&encoded(&frame));
+        input.extend_from_slice(b"uart:~$ ");
+        input.extend_from_slice(&encoded(&frame));
+
+        for split in 0..=input.len() {
+            let (first, second) = input.split_at(split);
+            assert_eq!(
+                decode_in_pieces([first, second]),
+                [frame.clone(), frame.clone()],
+                "{}",
+                split
+            );
+        }
+    }
+
+    #[test]
+    fn frames_in_one_buffer() {
+        let first = frame(10);
+        let second = frame(200);
+        let mut input = encoded(&first);
+        input.extend_from_slice(&encoded(&second));
+
+        let mut codec = SmpSerialCodec::new();
+        assert_eq!(codec.decode(&mut input).unwrap(), Some(first));
+        assert_eq!(codec.decode(&mut input).unwrap(), Some(second));
+        assert_eq!(codec.decode(&mut input).unwrap(), None);
+        assert!(input.is_empty());
+    }
+}
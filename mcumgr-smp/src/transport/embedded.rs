@@ -0,0 +1,556 @@
+//! Serial console framing over [embedded_io] for hosts without an operating system, e.g. a
+//! microcontroller talking to a Zephyr device over UART.
+//!
+//! This module only uses `core`: frames are sent from and received into buffers of the
+//! caller, and the line buffer has a size fixed at compile time. Frames are raw SMP frames,
+//! header included, as produced by [SmpFrame::encode](crate::SmpFrame::encode).
+
+use core::fmt;
+
+use base64::engine::general_purpose;
+use base64::Engine;
+
+use crate::transport::smp_framing::{frame_start, SmpTransportEncoder, CALC_CRC};
+
+/// A console line of an SMP frame is at most 127 bytes with its newline
+pub const DEFAULT_LINE_LEN: usize = 128;
+
+/// Bytes a line holds after base64 decoding
+const MAX_RAW_LINE_LEN: usize = 96;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FramingError {
+    /// A frame line isn't valid base64
+    Base64,
+    /// The frame is shorter or longer than announced in its first line
+    PacketLength { expected: usize, actual: usize },
+    /// The CRC of the frame doesn't match
+    Crc,
+    /// The receive buffer can't hold the frame, which needs `needed` bytes including its CRC
+    BufferTooSmall { needed: usize },
+}
+
+impl fmt::Display for FramingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FramingError::Base64 => f.write_str("base64 decoding error"),
+            FramingError::PacketLength { expected, actual } => write!(
+                f,
+                "packet length invalid: expected {} bytes, got {}",
+                expected, actual
+            ),
+            FramingError::Crc => f.write_str("wrong crc"),
+            FramingError::BufferTooSmall { needed } => {
+                write!(
+                    f,
+                    "receive buffer too small, the frame needs {} bytes",
+                    needed
+                )
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum EmbeddedError<E> {
+    /// Reading or writing failed
+    Io(E),
+    /// The reader reached its end before a frame was complete
+    Eof,
+    Framing(FramingError),
+}
+
+impl<E> From<FramingError> for EmbeddedError<E> {
+    fn from(err: FramingError) -> Self {
+        EmbeddedError::Framing(err)
+    }
+}
+
+impl<E: fmt::Debug> fmt::Display for EmbeddedError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EmbeddedError::Io(e) => write!(f, "io error: {:?}", e),
+            EmbeddedError::Eof => f.write_str("end of input"),
+            EmbeddedError::Framing(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+/// Reassembles a frame from console lines into a buffer of the caller.
+///
+/// Other console output is skipped, a frame cut off by the start of another one is dropped.
+struct FrameDecoder<'b> {
+    buf: &'b mut [u8],
+    len: usize,
+    /// length + 2 bytes CRC, 0 until the first line of a frame arrived
+    content_length: usize,
+}
+
+impl<'b> FrameDecoder<'b> {
+    fn new(buf: &'b mut [u8]) -> Self {
+        Self {
+            buf,
+            len: 0,
+            content_length: 0,
+        }
+    }
+
+    /// Feed a line including its newline, returns the frame length once its last line is in
+    fn input_line(&mut self, line: &[u8]) -> Result<Option<usize>, FramingError> {
+        let Some(start) = frame_start(line) else {
+            return Ok(None);
+        };
+        let line = &line[start..];
+        let Some((_newline, base64_line)) = line[2..].split_last() else {
+            return Ok(None);
+        };
+
+        let mut raw = [0u8; MAX_RAW_LINE_LEN];
+        let raw_len = general_purpose::STANDARD
+            .decode_slice(base64_line, &mut raw)
+            .map_err(|_| FramingError::Base64)?;
+        let raw = &raw[..raw_len];
+
+        let body = if line[0] == 0x06 {
+            // a new frame, possibly cutting off an incomplete one
+            let Some((length, body)) = raw.split_first_chunk::<2>() else {
+                return Err(FramingError::PacketLength {
+                    expected: 2,
+                    actual: raw.len(),
+                });
+            };
+            let content_length = u16::from_be_bytes(*length) as usize;
+            if content_length < 2 {
+                return Err(FramingError::PacketLength {
+                    expected: content_length,
+                    actual: body.len(),
+                });
+            }
+            if content_length > self.buf.len() {
+                return Err(FramingError::BufferTooSmall {
+                    needed: content_length,
+                });
+            }
+            self.content_length = content_length;
+            self.len = 0;
+            body
+        } else if self.content_length == 0 {
+            // a continuation without its start
+            return Ok(None);
+        } else {
+            raw
+        };
+
+        let total_len = self.len + body.len();
+        if total_len > self.content_length {
+            let expected = self.content_length;
+            self.content_length = 0;
+            return Err(FramingError::PacketLength {
+                expected,
+                actual: total_len,
+            });
+        }
+        self.buf[self.len..total_len].copy_from_slice(body);
+        self.len = total_len;
+
+        if self.len < self.content_length {
+            return Ok(None);
+        }
+
+        let frame_len = self.len - 2;
+        self.content_length = 0;
+        let crc = u16::from_be_bytes([self.buf[frame_len], self.buf[frame_len + 1]]);
+        let mut digest = CALC_CRC.digest();
+        digest.update(&self.buf[..frame_len]);
+        if digest.finalize() != crc {
+            return Err(FramingError::Crc);
+        }
+
+        Ok(Some(frame_len))
+    }
+}
+
+/// Received bytes split into lines, bytes after the current line are kept for the next one
+struct LineBuffer<const LINE: usize> {
+    buf: [u8; LINE],
+    filled: usize,
+}
+
+impl<const LINE: usize> LineBuffer<LINE> {
+    fn new() -> Self {
+        Self {
+            buf: [0; LINE],
+            filled: 0,
+        }
+    }
+
+    /// Length of the first complete line including its newline
+    fn line_len(&self) -> Option<usize> {
+        self.buf[..self.filled]
+            .iter()
+            .position(|b| *b == b'\n')
+            .map(|newline| newline + 1)
+    }
+
+    /// Space for the next read. A line longer than the buffer can't be a frame line, its
+    /// start is dropped so the rest is read up to the newline.
+    fn space(&mut self) -> &mut [u8] {
+        if self.filled == LINE {
+            self.filled = 0;
+        }
+        &mut self.buf[self.filled..]
+    }
+
+    fn add(&mut self, len: usize) {
+        self.filled += len;
+    }
+
+    fn line(&self, len: usize) -> &[u8] {
+        &self.buf[..len]
+    }
+
+    fn consume(&mut self, len: usize) {
+        self.buf.copy_within(len..self.filled, 0);
+        self.filled -= len;
+    }
+}
+
+/// Sends and receives frames over an [embedded_io] reader and writer, e.g. a UART.
+///
+/// `LINE` is the size of the line buffer. Console output longer than that is skipped, so
+/// it only needs to be larger if the device puts other output in front of a frame on the
+/// same line.
+pub struct EmbeddedTransport<T, const LINE: usize = DEFAULT_LINE_LEN> {
+    io: T,
+    lines: LineBuffer<LINE>,
+}
+
+impl<T, const LINE: usize> EmbeddedTransport<T, LINE> {
+    pub fn new(io: T) -> Self {
+        Self {
+            io,
+            lines: LineBuffer::new(),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.io
+    }
+}
+
+impl<T: embedded_io::Read + embedded_io::Write, const LINE: usize> EmbeddedTransport<T, LINE> {
+    /// Send a frame as console lines
+    pub fn send(&mut self, frame: &[u8]) -> Result<(), EmbeddedError<T::Error>> {
+        let mut encoder = SmpTransportEncoder::new(frame);
+        let mut line = [0; DEFAULT_LINE_LEN];
+        while !encoder.is_complete() {
+            let len = encoder
+                .write_line(&mut line)
+                .expect("buffer is large enough for a line");
+            self.io.write_all(&line[..len]).map_err(EmbeddedError::Io)?;
+        }
+        self.io.flush().map_err(EmbeddedError::Io)
+    }
+
+    /// Receive a frame into `buf`, which needs room for the frame and its 2 bytes CRC.
+    /// Returns the frame within `buf`.
+    pub fn receive<'b>(&mut self, buf: &'b mut [u8]) -> Result<&'b [u8], EmbeddedError<T::Error>> {
+        let mut decoder = FrameDecoder::new(buf);
+        let len = loop {
+            let line_len = loop {
+                if let Some(len) = self.lines.line_len() {
+                    break len;
+                }
+                let read = self
+                    .io
+                    .read(self.lines.space())
+                    .map_err(EmbeddedError::Io)?;
+                if read == 0 {
+                    return Err(EmbeddedError::Eof);
+                }
+                self.lines.add(read);
+            };
+            let frame = decoder.input_line(self.lines.line(line_len));
+            self.lines.consume(line_len);
+            if let Some(len) = frame? {
+                break len;
+            }
+        };
+        Ok(&buf[..len])
+    }
+
+    /// Send a frame and receive the response into `buf`
+    pub fn transceive<'b>(
+        &mut self,
+        frame: &[u8],
+        buf: &'b mut [u8],
+    ) -> Result<&'b [u8], EmbeddedError<T::Error>> {
+        self.send(frame)?;
+        self.receive(buf)
+    }
+}
+
+/// Like [EmbeddedTransport], but over an [embedded_io_async] reader and writer
+#[cfg(feature = "transport-embedded-async")]
+pub struct EmbeddedTransportAsync<T, const LINE: usize = DEFAULT_LINE_LEN> {
+    io: T,
+    lines: LineBuffer<LINE>,
+}
+
+#[cfg(feature = "transport-embedded-async")]
+impl<T, const LINE: usize> EmbeddedTransportAsync<T, LINE> {
+    pub fn new(io: T) -> Self {
+        Self {
+            io,
+            lines: LineBuffer::new(),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.io
+    }
+}
+
+#[cfg(feature = "transport-embedded-async")]
+impl<T: embedded_io_async::Read + embedded_io_async::Write, const LINE: usize>
+    EmbeddedTransportAsync<T, LINE>
+{
+    /// Send a frame as console lines
+    pub async fn send(&mut self, frame: &[u8]) -> Result<(), EmbeddedError<T::Error>> {
+        let mut encoder = SmpTransportEncoder::new(frame);
+        let mut line = [0; DEFAULT_LINE_LEN];
+        while !encoder.is_complete() {
+            let len = encoder
+                .write_line(&mut line)
+                .expect("buffer is large enough for a line");
+            self.io
+                .write_all(&line[..len])
+                .await
+                .map_err(EmbeddedError::Io)?;
+        }
+        self.io.flush().await.map_err(EmbeddedError::Io)
+    }
+
+    /// Receive a frame into `buf`, which needs room for the frame and its 2 bytes CRC.
+    /// Returns the frame within `buf`.
+    pub async fn receive<'b>(
+        &mut self,
+        buf: &'b mut [u8],
+    ) -> Result<&'b [u8], EmbeddedError<T::Error>> {
+        let mut decoder = FrameDecoder::new(buf);
+        let len = loop {
+            let line_len = loop {
+                if let Some(len) = self.lines.line_len() {
+                    break len;
+                }
+                let read = self
+                    .io
+                    .read(self.lines.space())
+                    .await
+                    .map_err(EmbeddedError::Io)?;
+                if read == 0 {
+                    return Err(EmbeddedError::Eof);
+                }
+                self.lines.add(read);
+            };
+            let frame = decoder.input_line(self.lines.line(line_len));
+            self.lines.consume(line_len);
+            if let Some(len) = frame? {
+                break len;
+            }
+        };
+        Ok(&buf[..len])
+    }
+
+    /// Send a frame and receive the response into `buf`
+    pub async fn transceive<'b>(
+        &mut self,
+        frame: &[u8],
+        buf: &'b mut [u8],
+    ) -> Result<&'b [u8], EmbeddedError<T::Error>> {
+        self.send(frame).await?;
+        self.receive(buf).await
+    }
+}
+
+#[cfg(all(test, feature = "payload-cbor"))]
+mod tests {
+    use super::*;
+    use crate::os_management::{self, EchoRequest, EchoResult};
+    use crate::{Group, OpCode, SmpFrame};
+    use core::convert::Infallible;
+    use std::cell::RefCell;
+    use std::collections::VecDeque;
+    use std::rc::Rc;
+
+    type Wire = Rc<RefCell<VecDeque<u8>>>;
+
+    /// One end of two in-memory pipes, reading what the other end wrote. Reads return at most
+    /// 7 bytes, so lines arrive in parts like from a UART.
+    struct End {
+        rx: Wire,
+        tx: Wire,
+    }
+
+    fn pipes() -> (End, End) {
+        let a = Wire::default();
+        let b = Wire::default();
+        let host = End {
+            rx: a.clone(),
+            tx: b.clone(),
+        };
+        (host, End { rx: b, tx: a })
+    }
+
+    impl End {
+        fn read_into(&mut self, buf: &mut [u8]) -> usize {
+            let mut rx = self.rx.borrow_mut();
+            let len = buf.len().min(rx.len()).min(7);
+            for (dst, src) in buf.iter_mut().zip(rx.drain(..len)) {
+                *dst = src;
+            }
+            len
+        }
+    }
+
+    impl embedded_io::ErrorType for End {
+        type Error = Infallible;
+    }
+
+    impl embedded_io::Read for End {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize, Infallible> {
+            Ok(self.read_into(buf))
+        }
+    }
+
+    impl embedded_io::Write for End {
+        fn write(&mut self, buf: &[u8]) -> Result<usize, Infallible> {
+            self.tx.borrow_mut().extend(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> Result<(), Infallible> {
+            Ok(())
+        }
+    }
+
+    fn echo_response(request: &[u8]) -> Vec<u8> {
+        let request = SmpFrame::<EchoRequest>::decode_with_cbor(request).unwrap();
+        SmpFrame::new(
+            OpCode::WriteResponse,
+            request.sequence,
+            Group::Default,
+            0,
+            EchoResult::Ok { r: request.data.d },
+        )
+        .encode_with_cbor()
+    }
+
+    /// Receive a request on the device end and answer it like `smp_svr`
+    fn answer_echo(device: &mut EmbeddedTransport<End>) {
+        let mut buf = [0; 1024];
+        let request = device.receive(&mut buf).unwrap();
+        device.send(&echo_response(request)).unwrap();
+    }
+
+    #[test]
+    fn echo_over_pipes() {
+        let (host, device) = pipes();
+        let mut host = EmbeddedTransport::<_>::new(host);
+        let mut device = EmbeddedTransport::<_>::new(device);
+
+        // one line, and a frame spread over several lines
+        for (sequence, msg) in [(1, "hello".to_string()), (2, "hello".repeat(60))] {
+            let request = os_management::echo(sequence, msg.clone()).encode_with_cbor();
+            host.send(&request).unwrap();
+            answer_echo(&mut device);
+
+            let mut buf = [0; 512];
+            let response = host.receive(&mut buf).unwrap();
+            let response = SmpFrame::<EchoResult>::decode_with_cbor(response).unwrap();
+            assert_eq!(response.sequence, sequence);
+            assert_eq!(response.data, EchoResult::Ok { r: msg });
+        }
+    }
+
+    #[test]
+    fn console_output_before_the_response_is_skipped() {
+        let (host, device) = pipes();
+        let wire = device.tx.clone();
+        let mut host = EmbeddedTransport::<_>::new(host);
+        let mut device = EmbeddedTransport::<_>::new(device);
+
+        host.send(&os_management::echo(3, "hi".to_string()).encode_with_cbor())
+            .unwrap();
+        wire.borrow_mut()
+            .extend(b"*** Booting Zephyr OS ***\r\nuart:~$ ");
+        answer_echo(&mut device);
+
+        let mut buf = [0; 64];
+        let response = host.receive(&mut buf).unwrap();
+        let response = SmpFrame::<EchoResult>::decode_with_cbor(response).unwrap();
+        assert_eq!(
+            response.data,
+            EchoResult::Ok {
+                r: "hi".to_string()
+            }
+        );
+    }
+
+    #[test]
+    fn small_buffer_and_end_of_input() {
+        let (host, device) = pipes();
+        let mut host = EmbeddedTransport::<_>::new(host);
+        let mut device = EmbeddedTransport::<_>::new(device);
+
+        let request = os_management::echo(4, "hello".repeat(20)).encode_with_cbor();
+        host.send(&request).unwrap();
+        let mut buf = [0; 16];
+        assert!(matches!(
+            device.receive(&mut buf),
+            Err(EmbeddedError::Framing(FramingError::BufferTooSmall { needed }))
+                if needed == request.len() + 2
+        ));
+
+        let mut buf = [0; 16];
+        assert!(matches!(host.receive(&mut buf), Err(EmbeddedError::Eof)));
+    }
+
+    #[cfg(feature = "transport-embedded-async")]
+    mod asynchronous {
+        use super::*;
+
+        impl embedded_io_async::Read for End {
+            async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Infallible> {
+                Ok(self.read_into(buf))
+            }
+        }
+
+        impl embedded_io_async::Write for End {
+            async fn write(&mut self, buf: &[u8]) -> Result<usize, Infallible> {
+                self.tx.borrow_mut().extend(buf);
+                Ok(buf.len())
+            }
+        }
+
+        #[tokio::test]
+        async fn echo_over_pipes() {
+            let (host, device) = pipes();
+            let mut host = EmbeddedTransportAsync::<_>::new(host);
+            let mut device = EmbeddedTransportAsync::<_>::new(device);
+
+            let msg = "hello".repeat(60);
+            let request = os_management::echo(5, msg.clone()).encode_with_cbor();
+            host.send(&request).await.unwrap();
+
+            let mut buf = [0; 1024];
+            let received = device.receive(&mut buf).await.unwrap();
+            device.send(&echo_response(received)).await.unwrap();
+
+            let mut buf = [0; 512];
+            let response = host.receive(&mut buf).await.unwrap();
+            let response = SmpFrame::<EchoResult>::decode_with_cbor(response).unwrap();
+            assert_eq!(response.data, EchoResult::Ok { r: msg });
+        }
+    }
+}
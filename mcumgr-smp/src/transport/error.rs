@@ -4,6 +4,8 @@ pub enum Error {
     Io(#[from] std::io::Error),
     #[error("SMP: {0}")]
     Smp(#[from] crate::smp::SmpError),
+    #[error("Replay: {0}")]
+    Replay(#[from] super::replay::ReplayError),
     #[cfg(feature = "transport-serial")]
     #[error("SmpTransport: {0}")]
     SmpTransport(#[from] super::smp_framing::SmpTransportError),
@@ -0,0 +1,160 @@
+use crate::smp::SmpFrame;
+
+/// Counters of what the receive path dropped instead of failing the request
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct TransportStats {
+    /// responses with a sequence number no request is waiting for, e.g. late responses
+    pub unexpected_frames: u64,
+    /// identical copies of a response that was already received, e.g. after UDP retransmissions
+    pub duplicate_frames: u64,
+    /// bytes of other console output skipped to find the start of a frame on a serial line
+    pub skipped_bytes: u64,
+}
+
+/// Drops responses that don't belong to the pending request, used by the CBOR transports.
+///
+/// Frames that can't be decoded are passed on, so their error reaches the caller.
+#[derive(Debug, Clone, Default)]
+pub struct ResponseFilter {
+    /// sequence of a request whose response was given up on
+    pub stale_sequence: Option<u8>,
+    /// the last response passed on, a copy of it is a duplicate
+    last_response: Option<(u8, Vec<u8>)>,
+    unexpected_frames: u64,
+    duplicate_frames: u64,
+}
+
+impl ResponseFilter {
+    /// Note that a request is sent, a response with its sequence number is expected again
+    pub fn sent(&mut self, sequence: u8) {
+        if self.stale_sequence == Some(sequence) {
+            self.stale_sequence = None;
+        }
+        if matches!(self.last_response, Some((last, _)) if last == sequence) {
+            self.last_response = None;
+        }
+    }
+
+    /// Whether a received frame is passed on to the request waiting for `expected_sequence`,
+    /// or to any request if it is `None`
+    pub fn accept(&mut self, frame: &[u8], expected_sequence: Option<u8>) -> bool {
+        let Ok(header) = SmpFrame::decode(frame, |_| Ok(())) else {
+            return true;
+        };
+
+        if matches!(&self.last_response, Some((_, last)) if last == frame) {
+            log::debug!("dropping duplicate response, seq {}", header.sequence);
+            self.duplicate_frames += 1;
+            return false;
+        }
+
+        if self.stale_sequence == Some(header.sequence) {
+            log::debug!("dropping late response, seq {}", header.sequence);
+            self.stale_sequence = None;
+            self.unexpected_frames += 1;
+            return false;
+        }
+
+        if let Some(expected) = expected_sequence.filter(|&expected| expected != header.sequence) {
+            log::debug!(
+                "dropping unexpected response, seq {} while waiting for {}",
+                header.sequence,
+                expected
+            );
+            self.unexpected_frames += 1;
+            return false;
+        }
+
+        self.last_response = Some((header.sequence, frame.to_vec()));
+        true
+    }
+
+    /// The counters of dropped frames, `skipped_bytes` is counted by the transports
+    pub fn stats(&self) -> TransportStats {
+        TransportStats {
+            unexpected_frames: self.unexpected_frames,
+            duplicate_frames: self.duplicate_frames,
+            skipped_bytes: 0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An echo response with the given sequence and CBOR payload
+    fn response(sequence: u8, payload: &[u8]) -> Vec<u8> {
+        let mut frame = vec![
+            0x03,
+            0x00,
+            0x00,
+            payload.len() as u8,
+            0x00,
+            0x00,
+            sequence,
+            0x00,
+        ];
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    #[test]
+    fn duplicates_are_dropped() {
+        let mut filter = ResponseFilter::default();
+        let frame = response(7, &[0xa0]);
+        filter.sent(7);
+        assert!(filter.accept(&frame, Some(7)));
+        assert!(!filter.accept(&frame, Some(7)));
+        assert!(!filter.accept(&frame, None));
+        assert_eq!(
+            filter.stats(),
+            TransportStats {
+                duplicate_frames: 2,
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn same_sequence_is_accepted_again_after_sending() {
+        let mut filter = ResponseFilter::default();
+        let frame = response(7, &[0xa0]);
+        filter.sent(7);
+        assert!(filter.accept(&frame, Some(7)));
+
+        // 256 requests later the sequence number comes around again
+        filter.sent(7);
+        assert!(filter.accept(&frame, Some(7)));
+        assert_eq!(filter.stats(), TransportStats::default());
+    }
+
+    #[test]
+    fn other_sequences_are_dropped() {
+        let mut filter = ResponseFilter::default();
+        filter.sent(2);
+        assert!(!filter.accept(&response(1, &[0xa0]), Some(2)));
+        assert!(!filter.accept(&response(200, &[0xa0]), Some(2)));
+        assert!(filter.accept(&response(2, &[0xa0]), Some(2)));
+        assert_eq!(filter.stats().unexpected_frames, 2);
+    }
+
+    #[test]
+    fn stale_response_is_dropped_once() {
+        let mut filter = ResponseFilter {
+            stale_sequence: Some(4),
+            ..Default::default()
+        };
+        assert!(!filter.accept(&response(4, &[0xa0]), None));
+        assert_eq!(filter.stale_sequence, None);
+        assert!(filter.accept(&response(4, &[0xa1]), None));
+        assert_eq!(filter.stats().unexpected_frames, 1);
+    }
+
+    #[test]
+    fn undecodable_frames_are_passed_on() {
+        let mut filter = ResponseFilter::default();
+        assert!(filter.accept(&[0x03, 0x00], Some(1)));
+        assert_eq!(filter.stats(), TransportStats::default());
+    }
+}
@@ -0,0 +1,739 @@
+//! A client that several tasks share over one connection.
+//!
+//! [SmpHandle] is cheap to clone, all clones send their requests over the same transport.
+//! The handle is `Send` and `Sync`, so clones can be moved to other tasks and threads.
+//!
+//! # Concurrency
+//! - Each request holds the connection from sending the request until its response
+//!   arrives, requests of different tasks are never interleaved on the wire.
+//! - An upload holds the connection only for one window of chunks at a time, so requests of
+//!   other tasks, e.g. a heartbeat echo, are sent between two windows instead of waiting for
+//!   the upload.
+//! - Waiting requests get the connection in roughly the order they asked for it, none waits
+//!   forever while others keep sending.
+//! - Every handle assigns the sequence numbers of its requests from a counter shared by all
+//!   clones, so a response is never taken for the response to another request.
+//! - The timeout of a call includes the time waiting for the connection. A request that
+//!   times out gives up the connection, its late response is dropped by the next request.
+//!
+//! # Probing
+//! Opening a UDP socket, or a serial port of firmware without SMP, succeeds without any
+//! device answering. [SmpHandle::connect] sends a parameters request with the fast deadline
+//! first and fails right away if it isn't answered. What the response told, e.g. the buffer
+//! size of the device, is kept for all clones, see [SmpHandle::probe_info].
+
+use std::io;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::{Arc, Mutex as StdMutex};
+use std::time::{Duration, Instant};
+
+use async_lock::Mutex;
+
+use crate::application_management::{
+    self, ApplicationManagementCommand, EraseImageResult, GetImageStateResult, ImageWriter,
+    UploadAction, UploadCredits, WriteImageChunkResult,
+};
+use crate::fs_management::{self, FileCloseResult, FileDownloadResult, RangeReader, ReadAction};
+use crate::os_management::{
+    self, EchoResult, McumgrParamsResult, ProbeInfo, ResetResult, TaskStatsResult,
+};
+use crate::transport::error::Error;
+use crate::transport::metrics::{self, MetricsSink, Outcome};
+use crate::transport::retry::is_lost;
+use crate::transport::runtime;
+use crate::transport::smp::CborSmpTransportAsync;
+use crate::transport::timeouts::{OperationClass, Timeouts};
+use crate::{Group, SmpFrame};
+
+struct Shared {
+    transport: Mutex<CborSmpTransportAsync>,
+    sequence: AtomicU8,
+    /// the sink of the transport, for the calls the handle gives up on
+    metrics: Option<Arc<dyn MetricsSink>>,
+    /// the result of the last probe
+    probe: StdMutex<Option<ProbeInfo>>,
+}
+
+/// How an [SmpHandle::upload_image] ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UploadOutcome {
+    /// [UploadAction::Continue] with `next_off` at the end of the data when the upload is
+    /// complete, otherwise the action that ended it
+    pub action: UploadAction,
+    /// the most chunks that were in flight at the same time, see [SmpHandle::upload_window]
+    pub window: usize,
+}
+
+/// A cloneable client over one connection, see the [module](self) for its guarantees
+#[derive(Clone)]
+pub struct SmpHandle {
+    shared: Arc<Shared>,
+    timeouts: Timeouts,
+}
+
+impl SmpHandle {
+    /// Share `transport`, each call waits for its response as long as `timeouts` allows for
+    /// its [OperationClass]
+    pub fn new(transport: CborSmpTransportAsync, timeouts: Timeouts) -> Self {
+        Self {
+            shared: Arc::new(Shared {
+                metrics: transport.metrics.clone(),
+                transport: Mutex::new(transport),
+                sequence: AtomicU8::new(0),
+                probe: StdMutex::new(None),
+            }),
+            timeouts,
+        }
+    }
+
+    /// Like [Self::new], but fail unless the device answers a probe, see [Self::probe]
+    pub async fn connect(
+        transport: CborSmpTransportAsync,
+        timeouts: Timeouts,
+    ) -> Result<Self, Error> {
+        let handle = Self::new(transport, timeouts);
+        handle.probe().await?;
+        Ok(handle)
+    }
+
+    /// A handle over the same connection with other deadlines, e.g. short ones for a
+    /// heartbeat next to long ones for uploads
+    pub fn with_timeouts(&self, timeouts: Timeouts) -> Self {
+        Self {
+            shared: self.shared.clone(),
+            timeouts,
+        }
+    }
+
+    pub fn timeouts(&self) -> Timeouts {
+        self.timeouts
+    }
+
+    fn next_sequence(&self) -> u8 {
+        self.shared.sequence.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// The error of a call that timed out, reported to the metrics sink, if any
+    fn timed_out<T>(&self, frame: &SmpFrame<T>, start: Instant) -> Error {
+        let sink = self.shared.metrics.as_deref();
+        metrics::report(sink, frame.group, frame.command, start, Outcome::Timeout);
+        io::Error::new(
+            io::ErrorKind::TimedOut,
+            "no response within the timeout of the handle",
+        )
+        .into()
+    }
+
+    /// Send a request and wait for its response as long as the deadline of its
+    /// [OperationClass]. The sequence number of `frame` is replaced by the next one of the
+    /// handle.
+    pub async fn transceive<Req, Resp>(&self, frame: SmpFrame<Req>) -> Result<SmpFrame<Resp>, Error>
+    where
+        Req: serde::Serialize + Send + Sync,
+        Resp: serde::de::DeserializeOwned,
+    {
+        let timeout = self.timeouts.of_frame(&frame);
+        self.transceive_within(frame, timeout).await
+    }
+
+    /// Like [Self::transceive], with the given deadline
+    pub async fn transceive_within<Req, Resp>(
+        &self,
+        mut frame: SmpFrame<Req>,
+        timeout: Duration,
+    ) -> Result<SmpFrame<Resp>, Error>
+    where
+        Req: serde::Serialize + Send + Sync,
+        Resp: serde::de::DeserializeOwned,
+    {
+        frame.sequence = self.next_sequence();
+        let start = Instant::now();
+        let request = async {
+            let mut transport = self.shared.transport.lock().await;
+            let mut ret = transport.transceive_cbor(&frame, true).await;
+            // the transport may give up before the deadline, e.g. while the device erases flash
+            while matches!(&ret, Err(e) if is_lost(e)) {
+                ret = transport.receive_cbor(Some(frame.sequence)).await;
+            }
+            ret
+        };
+        runtime::timeout(timeout, request)
+            .await
+            .unwrap_or_else(|_| Err(self.timed_out(&frame, start)))
+    }
+
+    /// Check that the device answers SMP requests with a parameters request, which gets the
+    /// fast deadline. The result is kept for [Self::probe_info].
+    ///
+    /// Fails with [io::ErrorKind::TimedOut] if the device doesn't answer.
+    pub async fn probe(&self) -> Result<ProbeInfo, Error> {
+        let start = Instant::now();
+        let ret: SmpFrame<McumgrParamsResult> = self
+            .transceive(os_management::mcumgr_params(0))
+            .await
+            .map_err(|e| match e {
+                e if is_lost(&e) => io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "the device did not respond to the SMP probe",
+                )
+                .into(),
+                e => e,
+            })?;
+        let info = ProbeInfo::from_response(&ret, start.elapsed());
+        *self.shared.probe.lock().expect("probe lock poisoned") = Some(info);
+        Ok(info)
+    }
+
+    /// The result of the last successful [Self::probe], `None` before
+    pub fn probe_info(&self) -> Option<ProbeInfo> {
+        *self.shared.probe.lock().expect("probe lock poisoned")
+    }
+
+    pub async fn echo(&self, msg: impl Into<String>) -> Result<EchoResult, Error> {
+        let ret = self.transceive(os_management::echo(0, msg.into())).await?;
+        Ok(ret.data)
+    }
+
+    pub async fn task_stats(&self) -> Result<TaskStatsResult, Error> {
+        let ret = self.transceive(os_management::task_stats(0)).await?;
+        Ok(ret.data)
+    }
+
+    /// Reset the device. `None` if it reset before sending its response within the reset grace
+    /// period
+    pub async fn reset(&self, force: bool) -> Result<Option<ResetResult>, Error> {
+        let frame = os_management::reset(self.next_sequence(), force);
+        let start = Instant::now();
+        let request = async {
+            let mut transport = self.shared.transport.lock().await;
+            transport
+                .transceive_cbor_optional::<_, ResetResult>(&frame, self.timeouts.reset_grace)
+                .await
+        };
+        // waiting for the connection counts against the fast deadline, not the grace period
+        let timeout = self.timeouts.fast.max(self.timeouts.reset_grace);
+        let ret = match runtime::timeout(timeout, request).await {
+            Ok(ret) => ret?,
+            Err(_) => {
+                let sink = self.shared.metrics.as_deref();
+                metrics::report(sink, frame.group, frame.command, start, Outcome::Timeout);
+                None
+            }
+        };
+        Ok(ret.map(|ret| ret.data))
+    }
+
+    pub async fn image_state(&self) -> Result<GetImageStateResult, Error> {
+        let ret = self
+            .transceive(application_management::get_state(0))
+            .await?;
+        Ok(ret.data)
+    }
+
+    /// Mark the image with the given hash for test on the next boot
+    pub async fn test_image(&self, hash: Vec<u8>) -> Result<GetImageStateResult, Error> {
+        let ret = self
+            .transceive(application_management::set_pending(hash, 0))
+            .await?;
+        Ok(ret.data)
+    }
+
+    /// Make the image with the given hash permanent, or the running image without a hash
+    pub async fn confirm_image(&self, hash: Option<Vec<u8>>) -> Result<GetImageStateResult, Error> {
+        let ret = self
+            .transceive(application_management::confirm(hash, 0))
+            .await?;
+        Ok(ret.data)
+    }
+
+    /// Erase the given slot, or the secondary slot if none is given
+    pub async fn erase_image(&self, slot: Option<u32>) -> Result<EraseImageResult, Error> {
+        let ret = self
+            .transceive(application_management::erase_image(slot, 0))
+            .await?;
+        Ok(ret.data)
+    }
+
+    /// The most upload chunks in flight at the same time, `buf_count` of the device minus a
+    /// margin, see [UploadCredits]. One chunk at a time until a [probe](Self::probe) told the
+    /// buffers of the device
+    pub fn upload_window(&self) -> usize {
+        UploadCredits::new(self.probe_info().and_then(|info| info.buf_count)).window()
+    }
+
+    /// Upload an image in chunks of `chunk_size` bytes, calling `progress` with the offset and
+    /// the length after every chunk or window of chunks. The first chunk is sent alone with the
+    /// slow deadline, as many devices erase the slot before answering it. The others are
+    /// pipelined, up to [Self::upload_window] chunks are sent before their responses are
+    /// collected, each with the fast deadline.
+    ///
+    /// Returns [UploadAction::Continue] with `next_off` at the end of `data` when the upload is
+    /// complete, otherwise the action that ended it, e.g. [UploadAction::Fatal] with the error
+    /// of the device, along with the window used. Offsets the device goes back to are sent
+    /// again.
+    pub async fn upload_image(
+        &self,
+        data: &[u8],
+        image: Option<u8>,
+        chunk_size: usize,
+        mut progress: impl FnMut(usize, usize) + Send,
+    ) -> Result<UploadOutcome, Error> {
+        let mut writer = ImageWriter::new(image, data.len(), None, false);
+        let mut credits = UploadCredits::new(self.probe_info().and_then(|info| info.buf_count));
+        let window = credits.window();
+        let outcome = |action| UploadOutcome { action, window };
+        loop {
+            let offset = writer.offset;
+            let action = if offset == 0 || credits.window() == 1 {
+                let end = data.len().min(offset + chunk_size);
+                let timeout = self.timeouts.of(OperationClass::of_upload_chunk(offset));
+                let ret: SmpFrame<WriteImageChunkResult> = self
+                    .transceive_within(writer.write_chunk(&data[offset..end]), timeout)
+                    .await?;
+                writer.handle_response(&ret.data)
+            } else {
+                self.upload_chunks(&mut writer, &mut credits, data, chunk_size)
+                    .await?
+            };
+
+            match action {
+                UploadAction::Continue { next_off } if next_off >= data.len() => {
+                    progress(next_off, data.len());
+                    return Ok(outcome(action));
+                }
+                UploadAction::Continue { next_off } if next_off <= offset => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("the device didn't accept the chunk at offset {}", offset),
+                    )
+                    .into());
+                }
+                UploadAction::Continue { .. }
+                | UploadAction::Restarted
+                | UploadAction::Rewind { .. } => progress(writer.offset, data.len()),
+                UploadAction::RetryFrom { .. }
+                | UploadAction::Fatal { .. }
+                | UploadAction::InvalidOffset { .. } => return Ok(outcome(action)),
+            }
+        }
+    }
+
+    /// Send as many chunks as `credits` allow, then collect their responses while holding the
+    /// connection.
+    ///
+    /// Returns the action of the first response that doesn't continue right after its chunk,
+    /// the responses after it are stale, or [UploadAction::Continue] after the last chunk sent.
+    async fn upload_chunks(
+        &self,
+        writer: &mut ImageWriter<'_>,
+        credits: &mut UploadCredits,
+        data: &[u8],
+        chunk_size: usize,
+    ) -> Result<UploadAction, Error> {
+        let sink = self.shared.metrics.as_deref();
+        let command = ApplicationManagementCommand::Upload.into();
+        let mut transport = self.shared.transport.lock().await;
+
+        let start = Instant::now();
+        while credits.available() && writer.offset < data.len() {
+            let offset = writer.offset;
+            let end = data.len().min(offset + chunk_size);
+            let mut frame = writer.write_chunk(&data[offset..end]);
+            frame.sequence = self.next_sequence();
+            transport.send_cbor(&frame).await?;
+            credits.sent(frame.sequence, offset, end);
+        }
+
+        let mut ended = None;
+        while credits.in_flight() > 0 {
+            let response = runtime::timeout(self.timeouts.fast, transport.receive_cbor(None))
+                .await
+                .unwrap_or_else(|_| {
+                    Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        "no response within the timeout of the handle",
+                    )
+                    .into())
+                });
+            let ret: SmpFrame<WriteImageChunkResult> = match response {
+                Ok(ret) => ret,
+                Err(e) => {
+                    // the responses still in flight are lost or late, a late one is dropped as
+                    // it has no credit anymore
+                    let outcome = Outcome::of_error(&e);
+                    for _ in 0..credits.in_flight() {
+                        metrics::report(
+                            sink,
+                            Group::ApplicationManagement,
+                            command,
+                            start,
+                            outcome,
+                        );
+                    }
+                    credits.clear();
+                    return match ended {
+                        Some(action) if is_lost(&e) => Ok(action),
+                        _ => Err(e),
+                    };
+                }
+            };
+            // a late response to a chunk of an earlier window
+            let Some(chunk) = credits.received(ret.sequence) else {
+                continue;
+            };
+
+            let outcome = match &ret.data {
+                WriteImageChunkResult::Ok(_) => Outcome::Ok,
+                WriteImageChunkResult::Err(err) => Outcome::DeviceError { rc: err.rc },
+            };
+            metrics::report(sink, Group::ApplicationManagement, command, start, outcome);
+
+            if ended.is_none() {
+                let action = writer.handle_pipelined_response(&ret.data, chunk);
+                if !matches!(action, UploadAction::Continue { next_off } if next_off == chunk.end) {
+                    ended = Some(action);
+                }
+            }
+        }
+
+        Ok(ended.unwrap_or(UploadAction::Continue {
+            next_off: writer.offset,
+        }))
+    }
+
+    /// Read `len` bytes of a file from `offset` on, fewer if the file ends earlier. Unlike a
+    /// download of the whole file, reading stops once the range is complete.
+    ///
+    /// Returns [FileDownloadResult::Ok] with the bytes of the range at `off`, along with the
+    /// length of the file if the device reported it, or the error of the device.
+    pub async fn read_range(
+        &self,
+        name: impl Into<String>,
+        offset: u64,
+        len: u64,
+    ) -> Result<FileDownloadResult, Error> {
+        let mut reader = RangeReader::new(name, offset, Some(len));
+        let mut data = Vec::new();
+        while !reader.is_done() {
+            let ret: SmpFrame<FileDownloadResult> = self.transceive(reader.read_chunk()).await?;
+            match reader.handle_response(&ret.data) {
+                ReadAction::Continue { data: chunk } | ReadAction::Done { data: chunk } => {
+                    data.extend_from_slice(chunk)
+                }
+                ReadAction::Fatal { rc } => return Ok(FileDownloadResult::Err { rc }),
+                ReadAction::InvalidOffset { off, expected } => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!(
+                            "the device sent offset {} instead of the requested {}",
+                            off, expected
+                        ),
+                    )
+                    .into());
+                }
+            }
+        }
+
+        // older devices don't support closing and close the file after a timeout
+        let _ = self
+            .transceive::<_, FileCloseResult>(fs_management::close(0))
+            .await;
+
+        Ok(FileDownloadResult::Ok {
+            off: offset,
+            data,
+            len: reader.file_len,
+        })
+    }
+
+    /// Close the connection, later calls of all clones fail
+    pub async fn close(&self) -> Result<(), Error> {
+        self.shared.transport.lock().await.close().await
+    }
+}
+
+#[cfg(all(test, feature = "runtime-tokio"))]
+mod tests {
+    use super::*;
+    use crate::application_management::WriteImageChunkPayload;
+    use crate::transport::filter::ResponseFilter;
+    use crate::transport::smp::SmpTransportAsync;
+    use crate::{CborEncoding, CborValue, DecodeOptions};
+    use async_trait::async_trait;
+    use serde::{Deserialize, Serialize};
+    use std::collections::VecDeque;
+
+    #[derive(Default)]
+    struct Device {
+        buf_count: u32,
+        /// responses the client didn't read yet and how long they take to arrive, each holds a
+        /// buffer of the device
+        responses: VecDeque<(Duration, Vec<u8>)>,
+        /// the most buffers held at the same time
+        peak: usize,
+        /// requests dropped as all buffers were held
+        dropped: usize,
+        image: Vec<u8>,
+        /// how long a response takes to arrive
+        latency: Duration,
+        /// how long the responses to the next requests take instead, `None` for a request that
+        /// is never answered
+        script: VecDeque<Option<Duration>>,
+        /// group and command of the requests answered, in the order they arrived
+        requests: Vec<(Group, u8)>,
+    }
+
+    #[derive(Deserialize)]
+    struct Echo {
+        d: String,
+    }
+
+    #[derive(Deserialize)]
+    struct Chunk {
+        #[serde(with = "serde_bytes")]
+        data: Vec<u8>,
+        off: usize,
+    }
+
+    /// Answers echo, its parameters and image uploads like Zephyr's smp_svr sample. A request
+    /// holds one of its `buf_count` buffers until the client reads the response, requests
+    /// arriving while all of them are held are dropped.
+    #[derive(Clone, Default)]
+    struct MockDevice(Arc<StdMutex<Device>>);
+
+    impl MockDevice {
+        fn new(buf_count: u32) -> Self {
+            let device = Self::default();
+            device.state().buf_count = buf_count;
+            device
+        }
+
+        fn state(&self) -> std::sync::MutexGuard<'_, Device> {
+            self.0.lock().unwrap()
+        }
+
+        fn transport(&self) -> CborSmpTransportAsync {
+            CborSmpTransportAsync {
+                transport: Box::new(self.clone()),
+                encoding: CborEncoding::Plain,
+                decoding: DecodeOptions::default(),
+                filter: ResponseFilter::default(),
+                metrics: None,
+            }
+        }
+    }
+
+    fn respond<T: Serialize>(request: &SmpFrame<CborValue>, data: T) -> Vec<u8> {
+        SmpFrame::new(
+            request.operation.response_of(),
+            request.sequence,
+            request.group,
+            request.command,
+            data,
+        )
+        .encode_with_cbor()
+    }
+
+    impl Device {
+        fn answer(&mut self, frame: &[u8]) -> Vec<u8> {
+            let request = SmpFrame::<CborValue>::decode_with_cbor(frame).unwrap();
+            self.requests.push((request.group, request.command));
+            match (request.group, request.command) {
+                (Group::Default, 0) => {
+                    let echo = SmpFrame::<Echo>::decode_with_cbor(frame).unwrap().data;
+                    respond(&request, EchoResult::Ok { r: echo.d })
+                }
+                (Group::Default, 6) => {
+                    let params = McumgrParamsResult::Ok {
+                        buf_size: 2048,
+                        buf_count: self.buf_count,
+                    };
+                    respond(&request, params)
+                }
+                (Group::ApplicationManagement, 1) => {
+                    let chunk = SmpFrame::<Chunk>::decode_with_cbor(frame).unwrap().data;
+                    if chunk.off == 0 {
+                        self.image.clear();
+                    }
+                    if chunk.off == self.image.len() {
+                        self.image.extend_from_slice(&chunk.data);
+                    }
+                    let written = WriteImageChunkPayload {
+                        off: self.image.len() as u32,
+                        match_: None,
+                    };
+                    respond(&request, WriteImageChunkResult::Ok(written))
+                }
+                other => panic!("unexpected request {:?}", other),
+            }
+        }
+    }
+
+    #[async_trait]
+    impl SmpTransportAsync for MockDevice {
+        async fn send(&mut self, frame: Vec<u8>) -> Result<(), Error> {
+            let mut device = self.state();
+            if device.responses.len() >= device.buf_count as usize {
+                device.dropped += 1;
+                return Ok(());
+            }
+            let response = device.answer(&frame);
+            let latency = device.latency;
+            match device.script.pop_front().unwrap_or(Some(latency)) {
+                Some(latency) => device.responses.push_back((latency, response)),
+                None => return Ok(()),
+            }
+            device.peak = device.peak.max(device.responses.len());
+            Ok(())
+        }
+
+        async fn receive(&mut self) -> Result<Vec<u8>, Error> {
+            let response = self.state().responses.pop_front();
+            match response {
+                Some((latency, response)) => {
+                    tokio::time::sleep(latency).await;
+                    Ok(response)
+                }
+                // a dropped request is never answered
+                None => std::future::pending().await,
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn the_mock_device_drops_requests_beyond_its_buffers() {
+        let device = MockDevice::new(3);
+        let mut transport = device.transport();
+        for sequence in 0..4 {
+            let request = os_management::echo(sequence, "hello".to_string());
+            transport.send_cbor(&request).await.unwrap();
+        }
+        assert_eq!(device.state().dropped, 1);
+    }
+
+    #[tokio::test]
+    async fn credits_keep_the_upload_within_the_buffers() {
+        let device = MockDevice::new(3);
+        let handle = SmpHandle::connect(device.transport(), Timeouts::default())
+            .await
+            .unwrap();
+        assert_eq!(handle.upload_window(), 2);
+
+        let data: Vec<u8> = (0..10_000).map(|i| i as u8).collect();
+        let outcome = handle
+            .upload_image(&data, None, 256, |_, _| {})
+            .await
+            .unwrap();
+
+        assert_eq!(
+            outcome,
+            UploadOutcome {
+                action: UploadAction::Continue {
+                    next_off: data.len()
+                },
+                window: 2,
+            }
+        );
+        let device = device.state();
+        assert_eq!(device.image, data);
+        assert_eq!(device.dropped, 0);
+        assert_eq!(device.peak, 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn echoes_are_answered_during_an_upload() {
+        let device = MockDevice::new(3);
+        device.state().latency = Duration::from_millis(5);
+        let handle = SmpHandle::connect(device.transport(), Timeouts::default())
+            .await
+            .unwrap();
+
+        let echoes = |name: &'static str| {
+            let handle = handle.clone();
+            tokio::spawn(async move {
+                for n in 0..20 {
+                    let msg = format!("{} {}", name, n);
+                    let ret = handle.echo(msg.clone()).await.unwrap();
+                    assert_eq!(ret, EchoResult::Ok { r: msg });
+                }
+            })
+        };
+        let first = echoes("first");
+        let second = echoes("second");
+
+        let data: Vec<u8> = (0..20_000).map(|i| i as u8).collect();
+        let upload = tokio::spawn({
+            let handle = handle.clone();
+            let data = data.clone();
+            async move {
+                let ret = handle.upload_image(&data, None, 256, |_, _| {}).await;
+                ret.unwrap()
+            }
+        });
+
+        first.await.unwrap();
+        second.await.unwrap();
+        let outcome = upload.await.unwrap();
+        assert_eq!(
+            outcome.action,
+            UploadAction::Continue {
+                next_off: data.len()
+            }
+        );
+
+        let device = device.state();
+        assert_eq!(device.image, data);
+        assert_eq!(device.dropped, 0);
+        // echoes were sent between the windows of the upload instead of after it
+        let chunks =
+            |&(group, command): &(Group, u8)| (group, command) == (Group::ApplicationManagement, 1);
+        let first_chunk = device.requests.iter().position(chunks).unwrap();
+        let last_chunk = device.requests.iter().rposition(chunks).unwrap();
+        let echoes_during_upload = device.requests[first_chunk..last_chunk]
+            .iter()
+            .filter(|request| **request == (Group::Default, 0))
+            .count();
+        assert!(echoes_during_upload > 0);
+    }
+
+    fn scripted(script: &[Option<Duration>]) -> (MockDevice, SmpHandle) {
+        let device = MockDevice::new(3);
+        device.state().script = script.iter().copied().collect();
+        let timeouts = Timeouts::new(Duration::from_secs(1), Duration::from_secs(10));
+        let handle = SmpHandle::new(device.transport(), timeouts);
+        (device, handle)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn hung_request_fails_after_the_fast_deadline() {
+        let (_device, handle) = scripted(&[None]);
+        let start = tokio::time::Instant::now();
+        let ret = handle.echo("hello").await;
+        assert!(matches!(ret, Err(e) if is_lost(&e)));
+        assert_eq!(start.elapsed(), Duration::from_secs(1));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn slow_first_chunk_is_awaited_until_the_slow_deadline() {
+        let (device, handle) = scripted(&[Some(Duration::from_secs(5))]);
+        let data = [0x5a; 1024];
+        let start = tokio::time::Instant::now();
+        let outcome = handle
+            .upload_image(&data, None, 256, |_, _| {})
+            .await
+            .unwrap();
+        assert_eq!(outcome.action, UploadAction::Continue { next_off: 1024 });
+        assert!(start.elapsed() >= Duration::from_secs(5));
+        assert_eq!(device.state().image, data);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn slow_later_chunk_fails_after_the_fast_deadline() {
+        let (_device, handle) = scripted(&[Some(Duration::ZERO), Some(Duration::from_secs(5))]);
+        let data = [0x5a; 1024];
+        let start = tokio::time::Instant::now();
+        let ret = handle.upload_image(&data, None, 256, |_, _| {}).await;
+        assert!(matches!(ret, Err(e) if is_lost(&e)));
+        assert_eq!(start.elapsed(), Duration::from_secs(1));
+    }
+}
@@ -0,0 +1,388 @@
+//! Hooks for request counts, error counts and latencies, e.g. to export them to Prometheus.
+//!
+//! Install a [MetricsSink] in the `metrics` field of
+//! [CborSmpTransport](crate::transport::smp::CborSmpTransport) or
+//! [CborSmpTransportAsync](crate::transport::smp::CborSmpTransportAsync). The transports call it
+//! with the group and command id of every request and the [Outcome] of every response, all
+//! labels are `&'static str`, so nothing is allocated for a call.
+
+use std::time::Duration;
+
+use crate::smp::{Group, SmpError};
+use crate::transport::error::Error;
+use crate::transport::retry::is_lost;
+
+/// How a request ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Outcome {
+    /// a response without an error code
+    Ok,
+    /// a response with a nonzero `rc`
+    DeviceError { rc: i32 },
+    /// a response with an SMP version 2 group error, `code` is defined by the group
+    GroupError { code: i32 },
+    /// no response arrived in time
+    Timeout,
+    /// the request couldn't be sent or the response couldn't be received
+    TransportError,
+    /// a response arrived, but it couldn't be decoded
+    DecodeError,
+}
+
+impl Outcome {
+    /// A label for the outcome, e.g. `ok` or `device_error`
+    pub fn label(&self) -> &'static str {
+        match self {
+            Outcome::Ok => "ok",
+            Outcome::DeviceError { .. } => "device_error",
+            Outcome::GroupError { .. } => "group_error",
+            Outcome::Timeout => "timeout",
+            Outcome::TransportError => "transport_error",
+            Outcome::DecodeError => "decode_error",
+        }
+    }
+
+    /// The outcome of a request that failed with `error`
+    pub fn of_error(error: &Error) -> Self {
+        match error {
+            e if is_lost(e) => Outcome::Timeout,
+            Error::Smp(SmpError::GroupError { code, .. }) => Outcome::GroupError { code: *code },
+            Error::Smp(_) => Outcome::DecodeError,
+            _ => Outcome::TransportError,
+        }
+    }
+}
+
+/// Something the receive path dealt with outside of a single response
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TransportEvent {
+    /// the transport timed out waiting for a frame
+    Timeout,
+    /// a response no request was waiting for was dropped, e.g. a late one
+    UnexpectedFrame,
+    /// a copy of a response that was already received was dropped
+    DuplicateFrame,
+    /// the transport timed out before a frame was complete
+    IncompleteFrame,
+    /// the connection was closed
+    Closed,
+}
+
+impl TransportEvent {
+    /// A label for the event, e.g. `unexpected_frame`
+    pub fn label(&self) -> &'static str {
+        match self {
+            TransportEvent::Timeout => "timeout",
+            TransportEvent::UnexpectedFrame => "unexpected_frame",
+            TransportEvent::DuplicateFrame => "duplicate_frame",
+            TransportEvent::IncompleteFrame => "incomplete_frame",
+            TransportEvent::Closed => "closed",
+        }
+    }
+}
+
+/// Receives the metrics of a transport. All methods do nothing by default.
+///
+/// The methods are called on the path of every request, so they should only update counters
+/// and not block.
+pub trait MetricsSink: Send + Sync {
+    /// a request with the command `id` of `group` is sent
+    fn on_request(&self, _group: Group, _id: u8) {}
+
+    /// the request ended after `duration`, measured from before it was sent
+    fn on_response(&self, _group: Group, _id: u8, _outcome: Outcome, _duration: Duration) {}
+
+    /// the transport dropped a frame or timed out
+    fn on_transport_event(&self, _kind: TransportEvent) {}
+}
+
+/// A label for the group, the short name mcumgr uses or `custom`
+pub fn group_label(group: Group) -> &'static str {
+    group.name().unwrap_or("custom")
+}
+
+/// The outcome of a response by its `rc` or `err` field
+#[cfg(feature = "payload-cbor")]
+fn response_outcome(frame: &[u8]) -> Outcome {
+    #[derive(serde::Deserialize)]
+    struct GroupRc {
+        rc: i32,
+    }
+
+    #[derive(serde::Deserialize)]
+    struct Status {
+        #[serde(default)]
+        rc: i32,
+        #[serde(default)]
+        err: Option<GroupRc>,
+    }
+
+    match frame
+        .get(8..)
+        .and_then(|payload| crate::cbor::decode::<Status>(payload).ok())
+    {
+        Some(Status { err: Some(err), .. }) if err.rc != 0 => Outcome::GroupError { code: err.rc },
+        Some(Status { rc, .. }) if rc != 0 => Outcome::DeviceError { rc },
+        _ => Outcome::Ok,
+    }
+}
+
+/// Decode a response and report its outcome, used by the CBOR transports
+#[cfg(feature = "payload-cbor")]
+pub(crate) fn decode_response<T: serde::de::DeserializeOwned>(
+    sink: Option<&dyn MetricsSink>,
+    group: Group,
+    id: u8,
+    start: std::time::Instant,
+    response: Result<Vec<u8>, Error>,
+    decoding: crate::smp::DecodeOptions,
+) -> Result<crate::smp::SmpFrame<T>, Error> {
+    let decode = |bytes| crate::smp::SmpFrame::decode_with_cbor_as(bytes, decoding);
+    match response {
+        Ok(bytes) => decode_response_with(sink, group, id, start, Ok(&bytes), decode),
+        Err(e) => decode_response_with(sink, group, id, start, Err(e), decode),
+    }
+}
+
+/// Like [decode_response], decoding the frame with `decode`
+#[cfg(feature = "payload-cbor")]
+pub(crate) fn decode_response_with<'b, T>(
+    sink: Option<&dyn MetricsSink>,
+    group: Group,
+    id: u8,
+    start: std::time::Instant,
+    response: Result<&'b [u8], Error>,
+    decode: impl FnOnce(&'b [u8]) -> Result<crate::smp::SmpFrame<T>, crate::smp::SmpError>,
+) -> Result<crate::smp::SmpFrame<T>, Error> {
+    let Some(sink) = sink else {
+        return Ok(decode(response?)?);
+    };
+
+    let (outcome, ret) = match response {
+        Ok(bytes) => match decode(bytes) {
+            Ok(frame) => (response_outcome(bytes), Ok(frame)),
+            Err(e) => (Outcome::DecodeError, Err(e.into())),
+        },
+        Err(e) => (Outcome::of_error(&e), Err(e)),
+    };
+    sink.on_response(group, id, outcome, start.elapsed());
+    ret
+}
+
+/// Report the outcome of a request started at `start`
+#[cfg(feature = "payload-cbor")]
+pub(crate) fn report(
+    sink: Option<&dyn MetricsSink>,
+    group: Group,
+    id: u8,
+    start: std::time::Instant,
+    outcome: Outcome,
+) {
+    if let Some(sink) = sink {
+        sink.on_response(group, id, outcome, start.elapsed());
+    }
+}
+
+/// Report a request that failed before a response was received
+#[cfg(feature = "payload-cbor")]
+pub(crate) fn failed(
+    sink: Option<&dyn MetricsSink>,
+    group: Group,
+    id: u8,
+    start: std::time::Instant,
+    error: Error,
+) -> Error {
+    report(sink, group, id, start, Outcome::of_error(&error));
+    error
+}
+
+/// Report the events the [ResponseFilter](crate::transport::filter::ResponseFilter) counted
+/// while it was deciding about a frame
+#[cfg(feature = "payload-cbor")]
+pub(crate) fn filter_events(
+    sink: Option<&dyn MetricsSink>,
+    before: crate::transport::filter::TransportStats,
+    after: crate::transport::filter::TransportStats,
+) {
+    let Some(sink) = sink else {
+        return;
+    };
+    if after.unexpected_frames > before.unexpected_frames {
+        sink.on_transport_event(TransportEvent::UnexpectedFrame);
+    }
+    if after.duplicate_frames > before.duplicate_frames {
+        sink.on_transport_event(TransportEvent::DuplicateFrame);
+    }
+}
+
+/// Report a failed receive as a transport event
+#[cfg(feature = "payload-cbor")]
+pub(crate) fn receive_error(sink: Option<&dyn MetricsSink>, error: &Error) {
+    let Some(sink) = sink else {
+        return;
+    };
+    if is_lost(error) {
+        sink.on_transport_event(TransportEvent::Timeout);
+    } else if matches!(error, Error::Smp(SmpError::IncompleteFrame { .. })) {
+        sink.on_transport_event(TransportEvent::IncompleteFrame);
+    }
+}
+
+/// A [MetricsSink] recording to the [metrics](https://docs.rs/metrics) facade, for any exporter
+/// installed with it, e.g. `metrics-exporter-prometheus`.
+///
+/// Records the counters `smp_requests_total` and `smp_responses_total` by `group` and `outcome`,
+/// `smp_device_errors_total` by `group` and `rc`, `smp_transport_events_total` by `kind` and the
+/// histogram `smp_request_duration_seconds` by `group`. Device errors are counted with the name of
+/// their [ReturnCode](crate::smp::ReturnCode) or group error as `rc`, `other` for unknown codes.
+#[cfg(feature = "metrics")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MetricsRecorder;
+
+#[cfg(feature = "metrics")]
+impl MetricsSink for MetricsRecorder {
+    fn on_request(&self, group: Group, _id: u8) {
+        metrics::counter!("smp_requests_total", "group" => group_label(group)).increment(1);
+    }
+
+    fn on_response(&self, group: Group, _id: u8, outcome: Outcome, duration: Duration) {
+        let label = group_label(group);
+        metrics::counter!("smp_responses_total", "group" => label, "outcome" => outcome.label())
+            .increment(1);
+        metrics::histogram!("smp_request_duration_seconds", "group" => label)
+            .record(duration.as_secs_f64());
+
+        let rc = match outcome {
+            Outcome::DeviceError { rc } => {
+                crate::smp::ReturnCode::try_from(rc).map_or("other", |rc| rc.name())
+            }
+            Outcome::GroupError { code } => {
+                crate::group_error::decode_group_error(group, code).unwrap_or("other")
+            }
+            _ => return,
+        };
+        metrics::counter!("smp_device_errors_total", "group" => label, "rc" => rc).increment(1);
+    }
+
+    fn on_transport_event(&self, kind: TransportEvent) {
+        metrics::counter!("smp_transport_events_total", "kind" => kind.label()).increment(1);
+    }
+}
+
+#[cfg(all(test, feature = "payload-cbor"))]
+mod tests {
+    use super::*;
+    use crate::os_management::{self, EchoResult};
+    use crate::smp::{CborEncoding, DecodeOptions, OpCode, SmpFrame};
+    use crate::transport::filter::ResponseFilter;
+    use crate::transport::smp::{CborSmpTransport, SmpTransport};
+    use std::collections::VecDeque;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Debug, PartialEq)]
+    enum Call {
+        Request(Group, u8),
+        Response(Group, u8, Outcome),
+        Event(TransportEvent),
+    }
+
+    /// Records the calls in order, without the durations
+    #[derive(Default)]
+    struct Recording(Mutex<Vec<Call>>);
+
+    impl MetricsSink for Recording {
+        fn on_request(&self, group: Group, id: u8) {
+            self.0.lock().unwrap().push(Call::Request(group, id));
+        }
+
+        fn on_response(&self, group: Group, id: u8, outcome: Outcome, _duration: Duration) {
+            self.0
+                .lock()
+                .unwrap()
+                .push(Call::Response(group, id, outcome));
+        }
+
+        fn on_transport_event(&self, kind: TransportEvent) {
+            self.0.lock().unwrap().push(Call::Event(kind));
+        }
+    }
+
+    /// Returns the given frames, one per receive, then times out
+    struct Scripted(VecDeque<Vec<u8>>);
+
+    impl SmpTransport for Scripted {
+        fn send(&mut self, _frame: Vec<u8>) -> Result<(), Error> {
+            Ok(())
+        }
+
+        fn receive(&mut self) -> Result<Vec<u8>, Error> {
+            self.0
+                .pop_front()
+                .ok_or_else(|| std::io::Error::from(std::io::ErrorKind::TimedOut).into())
+        }
+    }
+
+    fn echo_response(sequence: u8, data: EchoResult) -> Vec<u8> {
+        SmpFrame::new(OpCode::WriteResponse, sequence, Group::Default, 0, data).encode_with_cbor()
+    }
+
+    /// Send an echo with sequence 1, answered by `responses`, and return the recorded calls
+    fn record(responses: Vec<Vec<u8>>) -> Vec<Call> {
+        let sink = Arc::new(Recording::default());
+        let mut transport = CborSmpTransport {
+            transport: Box::new(Scripted(responses.into())),
+            encoding: CborEncoding::Plain,
+            decoding: DecodeOptions::default(),
+            filter: ResponseFilter::default(),
+            metrics: Some(sink.clone()),
+        };
+        let _ = transport
+            .transceive_cbor::<_, EchoResult>(&os_management::echo(1, "hi".to_string()), true);
+        let calls = std::mem::take(&mut *sink.0.lock().unwrap());
+        calls
+    }
+
+    #[test]
+    fn success() {
+        let calls = record(vec![echo_response(
+            1,
+            EchoResult::Ok {
+                r: "hi".to_string(),
+            },
+        )]);
+        assert_eq!(
+            calls,
+            [
+                Call::Request(Group::Default, 0),
+                Call::Response(Group::Default, 0, Outcome::Ok),
+            ]
+        );
+    }
+
+    #[test]
+    fn rc_error() {
+        let calls = record(vec![echo_response(1, EchoResult::Err { rc: 8 })]);
+        assert_eq!(
+            calls,
+            [
+                Call::Request(Group::Default, 0),
+                Call::Response(Group::Default, 0, Outcome::DeviceError { rc: 8 }),
+            ]
+        );
+    }
+
+    #[test]
+    fn late_frame_and_timeout_are_events() {
+        let late = echo_response(0, EchoResult::Err { rc: 8 });
+        let calls = record(vec![late]);
+        assert_eq!(
+            calls,
+            [
+                Call::Request(Group::Default, 0),
+                Call::Event(TransportEvent::UnexpectedFrame),
+                Call::Event(TransportEvent::Timeout),
+                Call::Response(Group::Default, 0, Outcome::Timeout),
+            ]
+        );
+    }
+}
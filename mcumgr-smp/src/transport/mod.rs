@@ -5,9 +5,17 @@
 #[cfg(feature = "transport-serial")]
 pub mod serial;
 /// Support for the [SMP text console transport](https://github.com/apache/mynewt-mcumgr/blob/master/transport/smp-console.md)
-#[cfg(feature = "transport-serial")]
+#[cfg(any(feature = "transport-serial", feature = "transport-embedded"))]
 pub mod smp_framing;
 
+/// Console framing over embedded-io, without allocations
+#[cfg(feature = "transport-embedded")]
+pub mod embedded;
+
+/// A tokio codec for the serial console framing
+#[cfg(feature = "codec")]
+pub mod codec;
+
 /// UDP transport implementation
 #[cfg(any(feature = "transport-udp", feature = "transport-udp-async"))]
 pub mod udp;
@@ -18,4 +26,30 @@ pub mod ble;
 
 pub mod error;
 
+/// The async runtime the transports are built on
+#[cfg(feature = "async")]
+pub mod runtime;
+
+/// A cloneable client sharing one connection between tasks
+#[cfg(all(feature = "async", feature = "payload-cbor"))]
+pub mod handle;
+
+/// Dropping late, duplicate and foreign responses
+pub mod filter;
+
+/// Observing the frames of a transport, e.g. for logging
+pub mod observer;
+
+/// Request counts, errors and latencies, e.g. for Prometheus
+pub mod metrics;
+
+/// Deciding which requests may be repeated after their response got lost
+pub mod retry;
+
+/// Deadlines for fast requests, slow ones like erasing and resets
+pub mod timeouts;
+
+/// Recording sessions and replaying them without the device
+pub mod replay;
+
 pub mod smp;
@@ -0,0 +1,99 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::transport::error::Error;
+use crate::transport::filter::TransportStats;
+use crate::transport::smp::SmpTransport;
+
+/// Whether a frame was sent to or received from the device
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Sent,
+    Received,
+}
+
+/// Gets to see every frame passing through an [ObservedTransport], e.g. for logging.
+pub trait TransportObserver: Send + Sync {
+    /// a complete SMP frame, header included
+    fn frame(&self, direction: Direction, frame: &[u8]);
+
+    /// the bytes as they went over the wire, for transports that wrap frames in an
+    /// encapsulation like the serial console framing. Called before [TransportObserver::frame]
+    /// for received frames and after it for sent ones.
+    fn encapsulated(&self, _direction: Direction, _bytes: &[u8]) {}
+}
+
+/// Wraps a transport and reports all frames to an observer
+pub struct ObservedTransport<T> {
+    inner: T,
+    observer: Arc<dyn TransportObserver>,
+}
+
+impl<T> ObservedTransport<T> {
+    pub fn new(inner: T, observer: Arc<dyn TransportObserver>) -> Self {
+        Self { inner, observer }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+impl<T: SmpTransport> SmpTransport for ObservedTransport<T> {
+    fn send(&mut self, frame: Vec<u8>) -> Result<(), Error> {
+        self.observer.frame(Direction::Sent, &frame);
+        self.inner.send(frame)
+    }
+
+    fn send_slice(&mut self, frame: &[u8]) -> Result<(), Error> {
+        self.observer.frame(Direction::Sent, frame);
+        self.inner.send_slice(frame)
+    }
+
+    fn receive(&mut self) -> Result<Vec<u8>, Error> {
+        let frame = self.inner.receive()?;
+        self.observer.frame(Direction::Received, &frame);
+        Ok(frame)
+    }
+
+    fn set_receive_timeout(
+        &mut self,
+        timeout: Option<Duration>,
+    ) -> Result<Option<Duration>, Error> {
+        self.inner.set_receive_timeout(timeout)
+    }
+
+    fn stats(&self) -> TransportStats {
+        self.inner.stats()
+    }
+}
+
+#[cfg(feature = "async")]
+#[async_trait::async_trait]
+impl<T: crate::transport::smp::SmpTransportAsync + Send> crate::transport::smp::SmpTransportAsync
+    for ObservedTransport<T>
+{
+    async fn send(&mut self, frame: Vec<u8>) -> Result<(), Error> {
+        self.observer.frame(Direction::Sent, &frame);
+        self.inner.send(frame).await
+    }
+
+    async fn send_slice(&mut self, frame: &[u8]) -> Result<(), Error> {
+        self.observer.frame(Direction::Sent, frame);
+        self.inner.send_slice(frame).await
+    }
+
+    async fn receive(&mut self) -> Result<Vec<u8>, Error> {
+        let frame = self.inner.receive().await?;
+        self.observer.frame(Direction::Received, &frame);
+        Ok(frame)
+    }
+
+    async fn close(&mut self) -> Result<(), Error> {
+        self.inner.close().await
+    }
+
+    fn stats(&self) -> TransportStats {
+        self.inner.stats()
+    }
+}
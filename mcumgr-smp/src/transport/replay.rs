@@ -0,0 +1,332 @@
+//! Recording the frames of a session and replaying them without the device.
+//!
+//! A recording is a text file with one frame per line: the time since the start of the
+//! recording in seconds, `>` for a request or `<` for a response, and the frame as hex.
+//! Lines starting with `#` are comments, e.g. to annotate a recording attached to a bug
+//! report.
+//!
+//! ```text
+//! # smp recording 1
+//! 0.000000 > 0a000005000042000aa16164...
+//! 0.012407 < 0b000005000042000ba16172...
+//! ```
+
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::smp::HexBytes;
+use crate::transport::error::Error;
+use crate::transport::observer::{Direction, ObservedTransport, TransportObserver};
+use crate::transport::smp::SmpTransport;
+
+/// First line of a recording
+const HEADER: &str = "# smp recording 1";
+
+/// Byte of the header holding the sequence number
+const SEQUENCE: usize = 6;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecordedFrame {
+    /// time since the start of the recording
+    pub time: Duration,
+    pub direction: Direction,
+    /// a complete SMP frame, header included
+    pub frame: Vec<u8>,
+}
+
+/// The frames of a recorded session in the order they were sent and received
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Recording {
+    pub frames: Vec<RecordedFrame>,
+}
+
+impl Recording {
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::parse(&std::fs::read_to_string(path)?)
+    }
+
+    pub fn parse(text: &str) -> io::Result<Self> {
+        let mut frames = Vec::new();
+        for (index, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let invalid = |what: &str| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("line {}: {}", index + 1, what),
+                )
+            };
+
+            let mut fields = line.split_whitespace();
+            let (Some(time), Some(direction), Some(hex), None) =
+                (fields.next(), fields.next(), fields.next(), fields.next())
+            else {
+                return Err(invalid("expected time, direction and frame"));
+            };
+            let time = time
+                .parse::<f64>()
+                .ok()
+                .and_then(|secs| Duration::try_from_secs_f64(secs).ok())
+                .ok_or_else(|| invalid("invalid time"))?;
+            let direction = match direction {
+                ">" => Direction::Sent,
+                "<" => Direction::Received,
+                _ => return Err(invalid("direction is neither > nor <")),
+            };
+            let frame = decode_hex(hex).ok_or_else(|| invalid("frame isn't hex"))?;
+
+            frames.push(RecordedFrame {
+                time,
+                direction,
+                frame,
+            });
+        }
+        Ok(Self { frames })
+    }
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// Writes every frame passing through a transport to a recording.
+///
+/// Each frame is written as it passes, so the recording is usable up to the last frame
+/// even if the program is killed. Write errors can't be returned to the transport, they
+/// end the recording and are reported by [Recorder::failed].
+pub struct Recorder {
+    out: Mutex<Box<dyn Write + Send>>,
+    start: Instant,
+    failed: AtomicBool,
+}
+
+impl Recorder {
+    pub fn new(out: impl Write + Send + 'static) -> io::Result<Self> {
+        let mut out: Box<dyn Write + Send> = Box::new(out);
+        writeln!(out, "{}", HEADER)?;
+        Ok(Self {
+            out: Mutex::new(out),
+            start: Instant::now(),
+            failed: AtomicBool::new(false),
+        })
+    }
+
+    /// Record into a new file, replacing an existing one
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::new(File::create(path)?)
+    }
+
+    /// Whether writing the recording failed, it is incomplete then
+    pub fn failed(&self) -> bool {
+        self.failed.load(Ordering::Relaxed)
+    }
+}
+
+impl TransportObserver for Recorder {
+    fn frame(&self, direction: Direction, frame: &[u8]) {
+        if self.failed() {
+            return;
+        }
+        let arrow = match direction {
+            Direction::Sent => '>',
+            Direction::Received => '<',
+        };
+        let mut line = format!("{:.6} {} ", self.start.elapsed().as_secs_f64(), arrow);
+        for byte in frame {
+            line.push_str(&format!("{:02x}", byte));
+        }
+        line.push('\n');
+
+        let mut out = self.out.lock().unwrap_or_else(|e| e.into_inner());
+        // one write per line, so a killed program leaves complete lines
+        if out
+            .write_all(line.as_bytes())
+            .and_then(|_| out.flush())
+            .is_err()
+        {
+            self.failed.store(true, Ordering::Relaxed);
+        }
+    }
+}
+
+/// A transport whose frames are written to a recording
+pub type RecordingTransport<T> = ObservedTransport<T>;
+
+/// Wrap a transport, so its frames are written to the recording of `recorder`
+pub fn record<T>(inner: T, recorder: Arc<Recorder>) -> RecordingTransport<T> {
+    ObservedTransport::new(inner, recorder)
+}
+
+/// How a request is compared to the recorded one
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RequestMatch {
+    /// byte for byte, except for the sequence number
+    #[default]
+    Exact,
+    /// operation, version, flags, group and command, the payload may differ
+    Header,
+}
+
+impl RequestMatch {
+    fn matches(self, recorded: &[u8], request: &[u8]) -> bool {
+        if recorded.len() < 8 || request.len() < 8 {
+            return recorded == request;
+        }
+        let header = |frame: &[u8]| [frame[0], frame[1], frame[4], frame[5], frame[7]];
+        match self {
+            RequestMatch::Exact => {
+                header(recorded) == header(request)
+                    && recorded[2..4] == request[2..4]
+                    && recorded[8..] == request[8..]
+            }
+            RequestMatch::Header => header(recorded) == header(request),
+        }
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum ReplayError {
+    #[error("request {index} doesn't match the recording: expected {expected}, got {got}")]
+    UnexpectedRequest {
+        /// the number of the request in the recording, starting at 0
+        index: usize,
+        expected: String,
+        got: String,
+    },
+    #[error("request {index} goes beyond the end of the recording: {got}")]
+    EndOfRecording { index: usize, got: String },
+}
+
+/// Serves the responses of a recording to the requests it recorded, without a device.
+///
+/// Requests have to arrive in the recorded order. Each one is answered by the responses
+/// that were received after it, with their sequence numbers moved by the difference between
+/// the sequence numbers of the request and the recorded one, so a different first sequence
+/// number doesn't matter. A request without recorded responses times out, like it did when
+/// it was recorded. Responses are served at once, the recorded timing isn't reproduced.
+pub struct ReplayTransport {
+    frames: VecDeque<RecordedFrame>,
+    matching: RequestMatch,
+    responses: VecDeque<Vec<u8>>,
+    requests: usize,
+}
+
+impl ReplayTransport {
+    pub fn new(recording: Recording, matching: RequestMatch) -> Self {
+        let mut replay = Self {
+            frames: recording.frames.into(),
+            matching,
+            responses: VecDeque::new(),
+            requests: 0,
+        };
+        // responses recorded before the first request, e.g. late ones from an earlier session
+        replay.queue_responses(0);
+        replay
+    }
+
+    pub fn load(path: impl AsRef<Path>, matching: RequestMatch) -> io::Result<Self> {
+        Ok(Self::new(Recording::load(path)?, matching))
+    }
+
+    /// Recorded requests that weren't sent yet
+    pub fn remaining_requests(&self) -> usize {
+        self.frames
+            .iter()
+            .filter(|f| f.direction == Direction::Sent)
+            .count()
+    }
+
+    /// Move the responses up to the next request to the ones being served
+    fn queue_responses(&mut self, sequence_offset: u8) {
+        while let Some(frame) = self.frames.pop_front() {
+            if frame.direction == Direction::Sent {
+                self.frames.push_front(frame);
+                break;
+            }
+            let mut response = frame.frame;
+            if let Some(sequence) = response.get_mut(SEQUENCE) {
+                *sequence = sequence.wrapping_add(sequence_offset);
+            }
+            self.responses.push_back(response);
+        }
+    }
+
+    fn request(&mut self, frame: &[u8]) -> Result<(), Error> {
+        let index = self.requests;
+        self.requests += 1;
+
+        let Some(recorded) = self.frames.pop_front() else {
+            return Err(ReplayError::EndOfRecording {
+                index,
+                got: HexBytes(frame).to_string(),
+            }
+            .into());
+        };
+        if !self.matching.matches(&recorded.frame, frame) {
+            let expected = HexBytes(&recorded.frame).to_string();
+            self.frames.push_front(recorded);
+            return Err(ReplayError::UnexpectedRequest {
+                index,
+                expected,
+                got: HexBytes(frame).to_string(),
+            }
+            .into());
+        }
+
+        let sequence_offset = match (frame.get(SEQUENCE), recorded.frame.get(SEQUENCE)) {
+            (Some(sent), Some(recorded)) => sent.wrapping_sub(*recorded),
+            _ => 0,
+        };
+        self.queue_responses(sequence_offset);
+        Ok(())
+    }
+
+    fn response(&mut self) -> Result<Vec<u8>, Error> {
+        self.responses
+            .pop_front()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::TimedOut, "no recorded response").into())
+    }
+}
+
+impl SmpTransport for ReplayTransport {
+    fn send(&mut self, frame: Vec<u8>) -> Result<(), Error> {
+        self.request(&frame)
+    }
+
+    fn send_slice(&mut self, frame: &[u8]) -> Result<(), Error> {
+        self.request(frame)
+    }
+
+    fn receive(&mut self) -> Result<Vec<u8>, Error> {
+        self.response()
+    }
+}
+
+#[cfg(feature = "async")]
+#[async_trait::async_trait]
+impl crate::transport::smp::SmpTransportAsync for ReplayTransport {
+    async fn send(&mut self, frame: Vec<u8>) -> Result<(), Error> {
+        self.request(&frame)
+    }
+
+    async fn send_slice(&mut self, frame: &[u8]) -> Result<(), Error> {
+        self.request(frame)
+    }
+
+    async fn receive(&mut self) -> Result<Vec<u8>, Error> {
+        self.response()
+    }
+}
@@ -0,0 +1,231 @@
+use std::io::ErrorKind;
+use std::time::Duration;
+
+use crate::smp::{Group, OpCode, SmpFrame};
+use crate::transport::error::Error;
+
+/// Whether a request may be sent again when its response didn't arrive
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Idempotency {
+    /// Sending the request twice has the same effect as sending it once, e.g. reads,
+    /// setting writes and image upload chunks, which the device accepts again at their offset
+    Idempotent,
+    /// May only be sent again if nothing at all was received, e.g. a reset.
+    /// A late or garbled response shows that the device executed the request.
+    IfNoResponse,
+    /// Never sent again automatically
+    Unsafe,
+}
+
+impl Idempotency {
+    /// Classify a request by its header
+    pub fn of(operation: OpCode, group: Group, command: u8) -> Self {
+        match (operation, group, command) {
+            (OpCode::ReadRequest, _, _) => Idempotency::Idempotent,
+            // echo
+            (OpCode::WriteRequest, Group::Default, 0) => Idempotency::Idempotent,
+            // reset
+            (OpCode::WriteRequest, Group::Default, 5) => Idempotency::IfNoResponse,
+            // image upload
+            (OpCode::WriteRequest, Group::ApplicationManagement, 1) => Idempotency::Idempotent,
+            // setting write
+            (OpCode::WriteRequest, Group::SettingManagement, 0) => Idempotency::Idempotent,
+            // file upload, resumable at the offset like image uploads
+            (OpCode::WriteRequest, Group::FileManagement, 0) => Idempotency::Idempotent,
+            _ => Idempotency::Unsafe,
+        }
+    }
+
+    /// Classify the request of a frame
+    pub fn of_frame<T>(frame: &SmpFrame<T>) -> Self {
+        Self::of(frame.operation, frame.group, frame.command)
+    }
+}
+
+/// How often and when requests are repeated after a transport error
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RetryPolicy {
+    /// Number of additional attempts, 0 disables retries
+    pub retries: u32,
+    /// Time between a failed attempt and the next one
+    pub delay: Duration,
+}
+
+impl RetryPolicy {
+    pub fn new(retries: u32, delay: Duration) -> Self {
+        Self { retries, delay }
+    }
+
+    /// Whether a request that failed with `error` in attempt number `attempt`, starting at 0,
+    /// may be sent again
+    pub fn should_retry(&self, idempotency: Idempotency, attempt: u32, error: &Error) -> bool {
+        if attempt >= self.retries {
+            return false;
+        }
+
+        match idempotency {
+            Idempotency::Idempotent => is_lost(error) || is_garbled(error),
+            Idempotency::IfNoResponse => is_lost(error),
+            Idempotency::Unsafe => false,
+        }
+    }
+}
+
+/// The response didn't arrive in time
+pub fn is_lost(error: &Error) -> bool {
+    matches!(error, Error::Io(e) if matches!(e.kind(), ErrorKind::TimedOut | ErrorKind::WouldBlock))
+}
+
+/// Something was received, but it wasn't a valid frame
+fn is_garbled(error: &Error) -> bool {
+    match error {
+        #[cfg(feature = "transport-serial")]
+        Error::SmpTransport(_) => true,
+        Error::Smp(
+            crate::smp::SmpError::InvalidFrame
+            | crate::smp::SmpError::LengthMismatch { .. }
+            | crate::smp::SmpError::UnknownOpCode(_)
+            | crate::smp::SmpError::IncompleteFrame { .. },
+        ) => true,
+        _ => false,
+    }
+}
+
+/// Whether opening a transport failed because the device isn't there yet and may succeed
+/// later, e.g. a serial port that doesn't exist while the board boots, a BLE device that
+/// doesn't advertise yet or a device that doesn't answer. Permanent failures like a port
+/// without permission are not.
+///
+/// The error and its sources are checked, so errors wrapped by the caller are recognized.
+pub fn is_retryable_open(error: &(dyn std::error::Error + 'static)) -> bool {
+    let mut current = Some(error);
+    while let Some(err) = current {
+        if let Some(err) = err.downcast_ref::<std::io::Error>() {
+            return is_retryable_kind(err.kind());
+        }
+        match err.downcast_ref::<Error>() {
+            Some(Error::Io(err)) => return is_retryable_kind(err.kind()),
+            #[cfg(feature = "transport-ble-async")]
+            Some(Error::BLE(err)) => {
+                return matches!(
+                    err,
+                    btleplug::Error::DeviceNotFound
+                        | btleplug::Error::NotConnected
+                        | btleplug::Error::TimedOut(_)
+                )
+            }
+            Some(_) => return false,
+            None => {}
+        }
+        current = err.source();
+    }
+    false
+}
+
+fn is_retryable_kind(kind: ErrorKind) -> bool {
+    matches!(
+        kind,
+        ErrorKind::NotFound
+            | ErrorKind::TimedOut
+            | ErrorKind::WouldBlock
+            | ErrorKind::ConnectionRefused
+            | ErrorKind::ConnectionReset
+            | ErrorKind::NotConnected
+            | ErrorKind::BrokenPipe
+            | ErrorKind::AddrNotAvailable
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::smp::SmpError;
+
+    fn lost() -> Error {
+        std::io::Error::from(ErrorKind::TimedOut).into()
+    }
+
+    fn garbled() -> Error {
+        SmpError::InvalidFrame.into()
+    }
+
+    fn refused() -> Error {
+        std::io::Error::from(ErrorKind::ConnectionRefused).into()
+    }
+
+    #[test]
+    fn requests_are_classified() {
+        use Group::*;
+        use OpCode::*;
+
+        for (operation, group, command, expected) in [
+            (ReadRequest, Default, 0, Idempotency::Idempotent),
+            (
+                ReadRequest,
+                ApplicationManagement,
+                0,
+                Idempotency::Idempotent,
+            ),
+            (WriteRequest, Default, 0, Idempotency::Idempotent),
+            (WriteRequest, Default, 5, Idempotency::IfNoResponse),
+            (
+                WriteRequest,
+                ApplicationManagement,
+                1,
+                Idempotency::Idempotent,
+            ),
+            (WriteRequest, SettingManagement, 0, Idempotency::Idempotent),
+            (WriteRequest, FileManagement, 0, Idempotency::Idempotent),
+            // image state, erase, shell exec, datetime
+            (WriteRequest, ApplicationManagement, 0, Idempotency::Unsafe),
+            (WriteRequest, ApplicationManagement, 5, Idempotency::Unsafe),
+            (WriteRequest, ShellManagement, 0, Idempotency::Unsafe),
+            (WriteRequest, Default, 4, Idempotency::Unsafe),
+        ] {
+            assert_eq!(
+                Idempotency::of(operation, group, command),
+                expected,
+                "{:?} {:?} {}",
+                operation,
+                group,
+                command
+            );
+        }
+    }
+
+    #[test]
+    fn only_lost_responses_of_idempotent_requests_are_retried() {
+        let policy = RetryPolicy::new(2, Duration::ZERO);
+
+        assert!(policy.should_retry(Idempotency::Idempotent, 0, &lost()));
+        assert!(policy.should_retry(Idempotency::Idempotent, 1, &garbled()));
+        assert!(!policy.should_retry(Idempotency::Idempotent, 0, &refused()));
+
+        // a garbled response shows the device executed the request
+        assert!(policy.should_retry(Idempotency::IfNoResponse, 0, &lost()));
+        assert!(!policy.should_retry(Idempotency::IfNoResponse, 0, &garbled()));
+
+        assert!(!policy.should_retry(Idempotency::Unsafe, 0, &lost()));
+        assert!(!policy.should_retry(Idempotency::Unsafe, 0, &garbled()));
+    }
+
+    #[test]
+    fn retries_stop_after_the_limit() {
+        let policy = RetryPolicy::new(2, Duration::ZERO);
+        assert!(policy.should_retry(Idempotency::Idempotent, 1, &lost()));
+        assert!(!policy.should_retry(Idempotency::Idempotent, 2, &lost()));
+
+        let disabled = RetryPolicy::default();
+        assert!(!disabled.should_retry(Idempotency::Idempotent, 0, &lost()));
+    }
+
+    #[test]
+    fn missing_devices_are_retried_when_opening() {
+        let not_found = std::io::Error::from(ErrorKind::NotFound);
+        assert!(is_retryable_open(&not_found));
+        assert!(is_retryable_open(&refused()));
+        let denied = std::io::Error::from(ErrorKind::PermissionDenied);
+        assert!(!is_retryable_open(&denied));
+        assert!(!is_retryable_open(&garbled()));
+    }
+}
@@ -0,0 +1,47 @@
+//! The async runtime the transports are built on.
+//!
+//! `runtime-tokio` uses tokio, `runtime-smol` uses the `async-io` reactor of smol, which
+//! async-std runs on as well. If both are enabled, tokio is used.
+
+use std::future::Future;
+use std::time::Duration;
+
+#[cfg(feature = "runtime-tokio")]
+pub use tokio::net::ToSocketAddrs;
+
+#[cfg(all(feature = "runtime-smol", not(feature = "runtime-tokio")))]
+pub use async_net::AsyncToSocketAddrs as ToSocketAddrs;
+
+#[cfg(feature = "runtime-tokio")]
+pub(crate) use tokio::net::UdpSocket;
+
+#[cfg(all(feature = "runtime-smol", not(feature = "runtime-tokio")))]
+pub(crate) use async_net::UdpSocket;
+
+/// Returned by [timeout] if the future didn't complete in time
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Elapsed;
+
+/// Run `future` for at most `duration`
+#[cfg(feature = "runtime-tokio")]
+pub(crate) async fn timeout<F: Future>(
+    duration: Duration,
+    future: F,
+) -> Result<F::Output, Elapsed> {
+    tokio::time::timeout(duration, future)
+        .await
+        .map_err(|_| Elapsed)
+}
+
+/// Run `future` for at most `duration`
+#[cfg(all(feature = "runtime-smol", not(feature = "runtime-tokio")))]
+pub(crate) async fn timeout<F: Future>(
+    duration: Duration,
+    future: F,
+) -> Result<F::Output, Elapsed> {
+    futures_lite::future::or(async { Ok(future.await) }, async {
+        async_io::Timer::after(duration).await;
+        Err(Elapsed)
+    })
+    .await
+}
@@ -1,62 +1,505 @@
 // Author: Sascha Zenglein <zenglein@gessler.de>
 // Copyright (c) 2023 Gessler GmbH.
 
+use super::observer::{Direction, TransportObserver};
 use super::smp::SmpTransport;
-use super::smp_framing;
+use super::smp_framing::{self, ConsoleDecoder};
 use crate::transport::error::Error;
-use serialport::SerialPort;
-use std::io::{BufRead, BufReader};
-use std::time::Duration;
+use crate::transport::filter::TransportStats;
+use serialport::{SerialPort, SerialPortType};
+use std::fmt::{Display, Formatter};
+use std::io::ErrorKind;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+pub use serialport::{DataBits, FlowControl, Parity, StopBits};
+
+/// Bytes requested from the port per read, a few lines at the usual baud rates
+const READ_CHUNK_LEN: usize = 1024;
+
+/// Longest time a single read of the port blocks. Receiving loops over reads until the frame
+/// is complete or the receive timeout has passed, so the timeout doesn't depend on how the OS
+/// treats read timeouts, e.g. Windows returning no bytes instead of an error
+const READ_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Shortest read timeout set on the port, a zero timeout would make reads return at once
+const MIN_READ_TIMEOUT: Duration = Duration::from_millis(1);
+
+/// The name `port` is opened with on this platform.
+///
+/// Windows only accepts `COM1` to `COM9` as they are, other ports need the device namespace
+/// prefix as in `\\.\COM10`. It is added to every `COM` port there, names on other platforms are
+/// kept.
+pub fn normalize_port_name(port: &str) -> String {
+    if cfg!(windows) {
+        windows_port_name(port)
+    } else {
+        port.to_string()
+    }
+}
+
+/// `\\.\COMn` for a `COMn` port, case insensitive, other names unchanged
+fn windows_port_name(port: &str) -> String {
+    let is_com = match (port.get(..3), port.get(3..)) {
+        (Some(prefix), Some(number)) => {
+            prefix.eq_ignore_ascii_case("COM")
+                && !number.is_empty()
+                && number.bytes().all(|b| b.is_ascii_digit())
+        }
+        _ => false,
+    };
+    if is_com {
+        format!(r"\\.\{}", port)
+    } else {
+        port.to_string()
+    }
+}
+
+/// Line settings of a serial port
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SerialSettings {
+    pub baud_rate: u32,
+    pub data_bits: DataBits,
+    pub parity: Parity,
+    pub stop_bits: StopBits,
+    pub flow_control: FlowControl,
+}
+
+impl SerialSettings {
+    /// 8 data bits, no parity, 1 stop bit and no flow control, as used by [SerialTransport::new]
+    pub fn new(baud_rate: u32) -> Self {
+        Self {
+            baud_rate,
+            data_bits: DataBits::Eight,
+            parity: Parity::None,
+            stop_bits: StopBits::One,
+            flow_control: FlowControl::None,
+        }
+    }
+}
+
+impl Display for SerialSettings {
+    /// e.g. `115200 8N1` or `9600 8E1 with hardware flow control`
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let data_bits = match self.data_bits {
+            DataBits::Five => 5,
+            DataBits::Six => 6,
+            DataBits::Seven => 7,
+            DataBits::Eight => 8,
+        };
+        let parity = match self.parity {
+            Parity::None => 'N',
+            Parity::Odd => 'O',
+            Parity::Even => 'E',
+        };
+        let stop_bits = match self.stop_bits {
+            StopBits::One => 1,
+            StopBits::Two => 2,
+        };
+        write!(f, "{} {}{}{}", self.baud_rate, data_bits, parity, stop_bits)?;
+        match self.flow_control {
+            FlowControl::None => Ok(()),
+            FlowControl::Software => write!(f, " with software flow control"),
+            FlowControl::Hardware => write!(f, " with hardware flow control"),
+        }
+    }
+}
 
 pub struct SerialTransport {
     serial_device: Box<dyn SerialPort>,
     buf: Vec<u8>,
+    /// the console lines of the frame being sent, written at once
+    tx_buf: Vec<u8>,
+    /// bytes read from the port that aren't part of a returned line yet, kept across frames
+    rx_buf: Vec<u8>,
+    observer: Option<Arc<dyn TransportObserver>>,
+    /// the frame being received, kept when a receive times out in the middle of it
+    console: ConsoleDecoder,
+    /// how long a receive waits for a complete frame, forever if `None`
+    timeout: Option<Duration>,
+    /// when the current receive gives up
+    deadline: Option<Instant>,
 }
 
 impl SerialTransport {
     pub fn new(port: String, baud_rate: u32) -> Result<Self, Box<dyn std::error::Error>> {
-        let serial = serialport::new(port, baud_rate).open_native()?;
-        let buf = vec![0; 128];
-        Ok(Self {
-            serial_device: Box::new(serial),
-            buf,
-        })
+        Self::with_settings(port, SerialSettings::new(baud_rate))
+    }
+
+    /// Open a port with other line settings than 8N1, e.g. with parity or flow control.
+    ///
+    /// Settings the OS or adapter rejects fail with an error naming them, an [std::io::Error]
+    /// of the kind the OS reported, e.g. [ErrorKind::NotFound]. The port name is
+    /// adjusted by [normalize_port_name]. A receive waits forever until [Self::recv_timeout]
+    /// is set.
+    pub fn with_settings(
+        port: String,
+        settings: SerialSettings,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let serial = serialport::new(normalize_port_name(&port), settings.baud_rate)
+            .data_bits(settings.data_bits)
+            .parity(settings.parity)
+            .stop_bits(settings.stop_bits)
+            .flow_control(settings.flow_control)
+            .timeout(READ_POLL_INTERVAL)
+            .open_native()
+            .map_err(|e| {
+                // keep the kind, so a port that doesn't exist yet can be told from one
+                // without permission
+                let kind = match e.kind {
+                    serialport::ErrorKind::NoDevice => ErrorKind::NotFound,
+                    serialport::ErrorKind::InvalidInput => ErrorKind::InvalidInput,
+                    serialport::ErrorKind::Io(kind) => kind,
+                    serialport::ErrorKind::Unknown => ErrorKind::Other,
+                };
+                std::io::Error::new(
+                    kind,
+                    format!("can't open {} with {}: {}", port, settings, e),
+                )
+            })?;
+        Ok(Self::from_port(Box::new(serial)))
+    }
+
+    /// A transport over a port that is already open and set up
+    fn from_port(serial_device: Box<dyn SerialPort>) -> Self {
+        Self {
+            serial_device,
+            buf: vec![0; 128],
+            tx_buf: Vec::new(),
+            rx_buf: Vec::with_capacity(READ_CHUNK_LEN),
+            observer: None,
+            console: ConsoleDecoder::new(),
+            timeout: None,
+            deadline: None,
+        }
     }
 
+    /// Report every line of the console framing as it is written or read
+    pub fn set_observer(&mut self, observer: Arc<dyn TransportObserver>) {
+        self.observer = Some(observer);
+    }
+
+    /// How long a receive waits for a complete frame, forever with `None`. A receive that
+    /// times out fails with [ErrorKind::TimedOut], the part of a frame received so far is kept
+    /// for the next one.
     pub fn recv_timeout(&mut self, timeout: Option<Duration>) -> Result<(), Error> {
+        self.timeout = timeout;
+        // the port only ever blocks for a short time, the deadline is checked between reads
+        let read_timeout = timeout
+            .unwrap_or(READ_POLL_INTERVAL)
+            .clamp(MIN_READ_TIMEOUT, READ_POLL_INTERVAL);
         self.serial_device
-            .set_timeout(timeout.unwrap_or(Duration::MAX))
+            .set_timeout(read_timeout)
             .map_err(|e| Error::Io(e.into()))
     }
+
+    /// Move the next line, including its newline, into `buf`, reading from the port in
+    /// chunks until the deadline of the receive has passed.
+    fn next_line(&mut self) -> Result<(), Error> {
+        loop {
+            if let Some(len) = smp_framing::line_len(&self.rx_buf) {
+                self.buf.clear();
+                self.buf.extend(self.rx_buf.drain(..len));
+                return Ok(());
+            }
+
+            let remaining = match self.deadline {
+                Some(deadline) => match deadline.checked_duration_since(Instant::now()) {
+                    Some(remaining) if !remaining.is_zero() => Some(remaining),
+                    _ => {
+                        return Err(std::io::Error::new(
+                            ErrorKind::TimedOut,
+                            "no complete frame within the receive timeout",
+                        )
+                        .into())
+                    }
+                },
+                None => None,
+            };
+
+            let filled = self.rx_buf.len();
+            self.rx_buf.resize(filled + READ_CHUNK_LEN, 0);
+            let started = Instant::now();
+            let read = self.serial_device.read(&mut self.rx_buf[filled..]);
+            self.rx_buf.truncate(filled + *read.as_ref().unwrap_or(&0));
+            match read {
+                // some platforms return no bytes at once instead of blocking until the read
+                // timeout, wait out the rest of it so the loop doesn't spin
+                Ok(0) => {
+                    let wait = remaining
+                        .unwrap_or(READ_POLL_INTERVAL)
+                        .min(READ_POLL_INTERVAL)
+                        .saturating_sub(started.elapsed());
+                    std::thread::sleep(wait);
+                }
+                Ok(_) => {}
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        ErrorKind::Interrupted | ErrorKind::TimedOut | ErrorKind::WouldBlock
+                    ) => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+}
+
+/// A serial port of a USB device, as listed by [usb_ports]
+#[derive(Debug, Clone)]
+pub struct UsbPort {
+    /// the name to open the port with, e.g. `/dev/ttyACM0` or `COM3`
+    pub name: String,
+    pub vid: u16,
+    pub pid: u16,
+    pub manufacturer: Option<String>,
+    pub product: Option<String>,
+    pub serial_number: Option<String>,
+}
+
+/// List the serial ports of USB devices. Development kits usually expose their console as a
+/// USB CDC ACM device.
+///
+/// macOS lists every port twice, as `/dev/tty.*` and `/dev/cu.*`. Opening the `tty` device
+/// waits for the carrier detect line, so only the `cu` device is listed.
+pub fn usb_ports() -> Result<Vec<UsbPort>, Error> {
+    let ports = serialport::available_ports().map_err(|e| Error::Io(e.into()))?;
+    let names: Vec<String> = ports.iter().map(|port| port.port_name.clone()).collect();
+
+    Ok(ports
+        .into_iter()
+        .filter(|port| match port.port_name.strip_prefix("/dev/tty.") {
+            Some(name) => !names.contains(&format!("/dev/cu.{}", name)),
+            None => true,
+        })
+        .filter_map(|port| match port.port_type {
+            SerialPortType::UsbPort(info) => Some(UsbPort {
+                name: port.port_name,
+                vid: info.vid,
+                pid: info.pid,
+                manufacturer: info.manufacturer,
+                product: info.product,
+                serial_number: info.serial_number,
+            }),
+            _ => None,
+        })
+        .collect())
 }
 
 impl SmpTransport for SerialTransport {
     fn send(&mut self, frame: Vec<u8>) -> Result<(), Error> {
-        let mut encoder = smp_framing::SmpTransportEncoder::new(&frame);
+        self.send_slice(&frame)
+    }
 
-        self.buf.resize(128, 0);
-        while !encoder.is_complete() {
-            let len = encoder
-                .write_line(&mut self.buf)
-                .expect("Buffer too small!");
-            self.serial_device.write_all(&self.buf[0..len])?;
+    fn send_slice(&mut self, frame: &[u8]) -> Result<(), Error> {
+        // all lines in a single write, small writes cost a syscall each
+        smp_framing::encode_lines_into(frame, &mut self.tx_buf);
+        if let Some(observer) = &self.observer {
+            for line in self.tx_buf.split_inclusive(|b| *b == b'\n') {
+                observer.encapsulated(Direction::Sent, line);
+            }
         }
+        self.serial_device.write_all(&self.tx_buf)?;
 
         Ok(())
     }
 
     fn receive(&mut self) -> Result<Vec<u8>, Error> {
-        let mut decoder = smp_framing::SmpTransportDecoder::new();
-        let mut buf_reader = BufReader::new(&mut self.serial_device);
-        while !decoder.is_complete() {
-            self.buf.clear();
-            let len = buf_reader.read_until(0xa, &mut self.buf)?;
+        self.deadline = self.timeout.map(|timeout| Instant::now() + timeout);
+        loop {
+            self.next_line()?;
+            if let Some(observer) = &self.observer {
+                observer.encapsulated(Direction::Received, &self.buf);
+            }
+
+            if let Some(frame) = self.console.input_line(&self.buf)? {
+                return Ok(frame);
+            }
+        }
+    }
+
+    fn set_receive_timeout(
+        &mut self,
+        timeout: Option<Duration>,
+    ) -> Result<Option<Duration>, Error> {
+        let previous = self.timeout;
+        self.recv_timeout(timeout)?;
+        Ok(previous)
+    }
+
+    fn stats(&self) -> TransportStats {
+        TransportStats {
+            skipped_bytes: self.console.skipped_bytes(),
+            ..TransportStats::default()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn com_ports_get_the_device_namespace_prefix() {
+        assert_eq!(windows_port_name("COM3"), r"\\.\COM3");
+        assert_eq!(windows_port_name("com12"), r"\\.\com12");
+        assert_eq!(windows_port_name("COM"), "COM");
+        assert_eq!(windows_port_name("COMX1"), "COMX1");
+        assert_eq!(windows_port_name(r"\\.\COM10"), r"\\.\COM10");
+        assert_eq!(windows_port_name("/dev/ttyACM0"), "/dev/ttyACM0");
+    }
+
+    /// Loopback tests over a pseudo terminal, the transport owns the slave side and the test
+    /// plays the device on the master side
+    #[cfg(unix)]
+    mod pty {
+        use std::io::{Read, Write};
+
+        use serialport::TTYPort;
+
+        use super::*;
+
+        /// A frame long enough for several console lines
+        fn frame(seed: u8) -> Vec<u8> {
+            (0..300).map(|i| (i as u8).wrapping_mul(7) ^ seed).collect()
+        }
+
+        fn open() -> (TTYPort, SerialTransport) {
+            let (master, slave) = TTYPort::pair().expect("can't open a pseudo terminal");
+            (master, SerialTransport::from_port(Box::new(slave)))
+        }
+
+        fn read_len(master: &mut TTYPort, len: usize) -> Vec<u8> {
+            let deadline = Instant::now() + Duration::from_secs(5);
+            let mut buf = vec![0; len];
+            let mut filled = 0;
+            while filled < len {
+                assert!(
+                    Instant::now() < deadline,
+                    "only {} of {} bytes",
+                    filled,
+                    len
+                );
+                match master.read(&mut buf[filled..]) {
+                    Ok(n) => filled += n,
+                    Err(e) if e.kind() == ErrorKind::TimedOut => {}
+                    Err(e) => panic!("{}", e),
+                }
+            }
+            buf
+        }
+
+        #[test]
+        fn frames_are_sent_as_console_lines() {
+            let (mut master, mut transport) = open();
+            let lines = smp_framing::encode_lines(&frame(1));
+            assert!(lines.iter().filter(|b| **b == b'\n').count() > 1);
+
+            transport.send(frame(1)).unwrap();
+            assert_eq!(read_len(&mut master, lines.len()), lines);
+        }
+
+        #[test]
+        fn frames_are_received_across_and_within_reads() {
+            let (mut master, mut transport) = open();
+            transport
+                .recv_timeout(Some(Duration::from_secs(5)))
+                .unwrap();
+
+            // console output and two frames in a single write
+            let mut written = b"uart:~$ \r\n[00:00:01.000] <inf> app: up\r\n".to_vec();
+            written.extend(smp_framing::encode_lines(&frame(1)));
+            written.extend(smp_framing::encode_lines(&frame(2)));
+            master.write_all(&written).unwrap();
+            assert_eq!(transport.receive().unwrap(), frame(1));
+            assert_eq!(transport.receive().unwrap(), frame(2));
+            assert!(transport.stats().skipped_bytes > 0);
 
-            decoder.input_line(&self.buf[0..len])?;
+            // a frame trickling in a few bytes at a time
+            let lines = smp_framing::encode_lines(&frame(3));
+            let writer = std::thread::spawn(move || {
+                for chunk in lines.chunks(13) {
+                    master.write_all(chunk).unwrap();
+                    master.flush().unwrap();
+                    std::thread::sleep(Duration::from_millis(2));
+                }
+                master
+            });
+            assert_eq!(transport.receive().unwrap(), frame(3));
+            writer.join().unwrap();
         }
 
-        let resp = decoder.into_frame_payload()?;
+        #[test]
+        fn receive_times_out_and_keeps_the_partial_frame() {
+            let (mut master, mut transport) = open();
+            let timeout = Duration::from_millis(300);
+            transport.recv_timeout(Some(timeout)).unwrap();
 
-        Ok(resp)
+            let lines = smp_framing::encode_lines(&frame(4));
+            let first_line = smp_framing::line_len(&lines).unwrap();
+            master.write_all(&lines[..first_line]).unwrap();
+
+            let start = Instant::now();
+            match transport.receive() {
+                Err(Error::Io(e)) => assert_eq!(e.kind(), ErrorKind::TimedOut),
+                other => panic!("{:?}", other),
+            }
+            let waited = start.elapsed();
+            assert!(waited >= timeout, "{:?}", waited);
+            assert!(waited < timeout + Duration::from_secs(1), "{:?}", waited);
+
+            master.write_all(&lines[first_line..]).unwrap();
+            assert_eq!(transport.receive().unwrap(), frame(4));
+        }
+
+        /// The bytes per second of 921600 baud, 10 bits per byte with start and stop bit
+        const BYTES_PER_SEC_921600_BAUD: f64 = 92160.0;
+
+        #[test]
+        fn framing_keeps_up_with_921600_baud() {
+            let (mut master, mut transport) = open();
+            transport
+                .recv_timeout(Some(Duration::from_secs(5)))
+                .unwrap();
+            // 60 KB of frames, 80 KB as console lines
+            let frames: Vec<_> = (0..200).map(|i| frame(i as u8)).collect();
+            let lines: Vec<u8> = frames
+                .iter()
+                .flat_map(|frame| smp_framing::encode_lines(frame))
+                .collect();
+            let rate = |elapsed: Duration| lines.len() as f64 / elapsed.as_secs_f64();
+
+            let start = Instant::now();
+            let len = lines.len();
+            let reader = std::thread::spawn(move || {
+                let read = read_len(&mut master, len);
+                (master, read)
+            });
+            for frame in &frames {
+                transport.send(frame.clone()).unwrap();
+            }
+            let (mut master, read) = reader.join().unwrap();
+            let sent = rate(start.elapsed());
+            assert_eq!(read, lines);
+
+            let start = Instant::now();
+            let written = lines.clone();
+            // the master is kept open until everything is read, closing it hangs up the port
+            let writer = std::thread::spawn(move || {
+                master.write_all(&written).unwrap();
+                master
+            });
+            for frame in &frames {
+                assert_eq!(&transport.receive().unwrap(), frame);
+            }
+            writer.join().unwrap();
+            let received = rate(start.elapsed());
+
+            assert!(
+                sent >= BYTES_PER_SEC_921600_BAUD && received >= BYTES_PER_SEC_921600_BAUD,
+                "sent {:.0} B/s, received {:.0} B/s",
+                sent,
+                received
+            );
+        }
     }
 }
@@ -1,57 +1,172 @@
 use crate::transport::error::Error;
+use crate::transport::filter::TransportStats;
 use async_trait::async_trait;
 
+/// A transport for async code. Transports are `Send`, so a connection can be shared between
+/// tasks, e.g. by [SmpHandle](crate::transport::handle::SmpHandle)
 #[async_trait]
-pub trait SmpTransportAsync {
+pub trait SmpTransportAsync: Send {
     /// send a single frame
     async fn send(&mut self, frame: Vec<u8>) -> Result<(), Error>;
 
+    /// send a single frame from a buffer the caller keeps, e.g. to reuse it for the next frame
+    async fn send_slice(&mut self, frame: &[u8]) -> Result<(), Error> {
+        self.send(frame.to_vec()).await
+    }
+
     /// receive a single frame
     async fn receive(&mut self) -> Result<Vec<u8>, Error>;
+
+    /// close the connection, transports without a connection don't need to do anything
+    async fn close(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// counters of what the transport dropped while receiving, e.g. console output
+    fn stats(&self) -> TransportStats {
+        TransportStats::default()
+    }
 }
 
 #[cfg(feature = "payload-cbor")]
 pub mod cbor {
     use crate::transport::error::Error;
+    use crate::transport::filter::{ResponseFilter, TransportStats};
+    use crate::transport::metrics::{self, MetricsSink, Outcome, TransportEvent};
+    use crate::transport::retry::is_lost;
+    use crate::transport::runtime;
     use crate::transport::smp::SmpTransportAsync;
-    use crate::SmpFrame;
+    use crate::{missing_bytes, CborEncoding, DecodeOptions, SmpError, SmpFrame};
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
 
+    /// Sends requests and decodes responses as CBOR over a transport.
+    ///
+    /// Build it with [CborSmpTransportAsync::new] and change the public fields afterwards,
+    /// fields added later get their defaults there.
+    #[non_exhaustive]
     pub struct CborSmpTransportAsync {
         pub transport: Box<dyn SmpTransportAsync>,
+        /// how requests are encoded, [CborEncoding::Canonical] for deterministic frames
+        pub encoding: CborEncoding,
+        /// how responses are decoded, e.g. how much of an undecodable payload errors keep
+        pub decoding: DecodeOptions,
+        /// drops late and duplicate responses instead of failing the next request
+        pub filter: ResponseFilter,
+        /// gets the outcome and duration of every request, `None` to not collect metrics
+        pub metrics: Option<Arc<dyn MetricsSink>>,
     }
 
     impl CborSmpTransportAsync {
+        /// A client of `transport` encoding plainly, with default [DecodeOptions], a default
+        /// [ResponseFilter] and no metrics
+        pub fn new(transport: Box<dyn SmpTransportAsync>) -> Self {
+            Self {
+                transport,
+                encoding: CborEncoding::default(),
+                decoding: DecodeOptions::default(),
+                filter: ResponseFilter::default(),
+                metrics: None,
+            }
+        }
+
         pub async fn send(&mut self, frame: Vec<u8>) -> Result<(), Error> {
             self.transport.send(frame).await
         }
+        /// Receive a complete frame. Transports may deliver a frame in parts, e.g. BLE
+        /// notifications, which are read until the length in the header is reached.
+        /// A transport timing out before fails with [SmpError::IncompleteFrame].
         pub async fn receive(&mut self) -> Result<Vec<u8>, Error> {
-            self.transport.receive().await
+            let ret = self.receive_parts().await;
+            if let Err(e) = &ret {
+                metrics::receive_error(self.metrics.as_deref(), e);
+            }
+            ret
+        }
+
+        async fn receive_parts(&mut self) -> Result<Vec<u8>, Error> {
+            let mut frame = self.transport.receive().await?;
+            while let Some(missing) = missing_bytes(&frame) {
+                match self.transport.receive().await {
+                    Ok(part) => frame.extend_from_slice(&part),
+                    Err(e) if is_lost(&e) => Err(SmpError::IncompleteFrame { missing })?,
+                    Err(e) => return Err(e),
+                }
+            }
+            Ok(frame)
+        }
+
+        pub async fn close(&mut self) -> Result<(), Error> {
+            if let Some(metrics) = &self.metrics {
+                metrics.on_transport_event(TransportEvent::Closed);
+            }
+            self.transport.close().await
         }
 
         pub async fn transceive(&mut self, frame: Vec<u8>) -> Result<Vec<u8>, Error> {
             self.transport.send(frame).await?;
-            self.transport.receive().await
+            self.receive().await
         }
 
+        /// Send a frame without waiting for a response
         pub async fn send_cbor<T: serde::Serialize>(
             &mut self,
             frame: &SmpFrame<T>,
         ) -> Result<(), Error> {
-            let bytes = frame.encode_with_cbor();
+            self.filter.sent(frame.sequence);
+            if let Some(metrics) = &self.metrics {
+                metrics.on_request(frame.group, frame.command);
+            }
+            let bytes = frame.encode_with_cbor_as(self.encoding);
             self.send(bytes).await
         }
         pub async fn receive_cbor<T: serde::de::DeserializeOwned>(
             &mut self,
             expected_sequence: Option<u8>,
         ) -> Result<SmpFrame<T>, Error> {
-            let bytes = self.receive().await?;
-            let frame = SmpFrame::<T>::decode_with_cbor(&bytes)?;
-            if let Some(expected_sequence) = expected_sequence {
-                if frame.sequence != expected_sequence {
-                    Err(Error::Smp(crate::SmpError::UnexpectedSeq))?;
+            let bytes = self.receive_response(expected_sequence).await?;
+            Ok(SmpFrame::<T>::decode_with_cbor_as(&bytes, self.decoding)?)
+        }
+
+        /// Receive until the [ResponseFilter] accepts a frame
+        async fn receive_response(
+            &mut self,
+            expected_sequence: Option<u8>,
+        ) -> Result<Vec<u8>, Error> {
+            let mut bytes = self.receive().await?;
+            loop {
+                let before = self.filter.stats();
+                if self.filter.accept(&bytes, expected_sequence) {
+                    return Ok(bytes);
                 }
+                metrics::filter_events(self.metrics.as_deref(), before, self.filter.stats());
+                bytes = self.receive().await?;
             }
-            Ok(frame)
+        }
+
+        /// Receive the response to `frame` and report its outcome
+        async fn finish<Req, Resp: serde::de::DeserializeOwned>(
+            &mut self,
+            frame: &SmpFrame<Req>,
+            start: Instant,
+            sent: Result<(), Error>,
+            check_sequence: bool,
+        ) -> Result<SmpFrame<Resp>, Error> {
+            let response = match sent {
+                Ok(()) => {
+                    self.receive_response(check_sequence.then_some(frame.sequence))
+                        .await
+                }
+                Err(e) => Err(e),
+            };
+            metrics::decode_response(
+                self.metrics.as_deref(),
+                frame.group,
+                frame.command,
+                start,
+                response,
+                self.decoding,
+            )
         }
 
         pub async fn transceive_cbor<Req: serde::Serialize, Resp: serde::de::DeserializeOwned>(
@@ -59,9 +174,181 @@ pub mod cbor {
             frame: &SmpFrame<Req>,
             check_sequence: bool,
         ) -> Result<SmpFrame<Resp>, Error> {
-            self.send_cbor(frame).await?;
-            self.receive_cbor(check_sequence.then_some(frame.sequence))
-                .await
+            let start = Instant::now();
+            let sent = self.send_cbor(frame).await;
+            self.finish(frame, start, sent, check_sequence).await
+        }
+
+        /// Like [Self::send_cbor], but encoded into `buf`, which is reused for the next frame
+        /// instead of allocating a new one
+        pub async fn send_cbor_with<T: serde::Serialize>(
+            &mut self,
+            frame: &SmpFrame<T>,
+            buf: &mut Vec<u8>,
+        ) -> Result<(), Error> {
+            self.filter.sent(frame.sequence);
+            if let Some(metrics) = &self.metrics {
+                metrics.on_request(frame.group, frame.command);
+            }
+            frame.encode_into_as(buf, self.encoding);
+            self.transport.send_slice(buf).await
+        }
+
+        /// Like [Self::transceive_cbor], but the request is encoded into `buf`, see
+        /// [Self::send_cbor_with]
+        pub async fn transceive_cbor_with<
+            Req: serde::Serialize,
+            Resp: serde::de::DeserializeOwned,
+        >(
+            &mut self,
+            frame: &SmpFrame<Req>,
+            check_sequence: bool,
+            buf: &mut Vec<u8>,
+        ) -> Result<SmpFrame<Resp>, Error> {
+            let start = Instant::now();
+            let sent = self.send_cbor_with(frame, buf).await;
+            self.finish(frame, start, sent, check_sequence).await
+        }
+
+        /// Send a request whose response may never arrive, e.g. a reset that takes effect
+        /// before the response is sent.
+        ///
+        /// Waits at most `grace` for the response and returns `None` if it didn't arrive by
+        /// then or the transport timed out earlier. A response arriving later is discarded by
+        /// the next receive instead of being taken for the response to the next request.
+        pub async fn transceive_cbor_optional<
+            Req: serde::Serialize,
+            Resp: serde::de::DeserializeOwned,
+        >(
+            &mut self,
+            frame: &SmpFrame<Req>,
+            grace: Duration,
+        ) -> Result<Option<SmpFrame<Resp>>, Error> {
+            let start = Instant::now();
+            if let Err(e) = self.send_cbor(frame).await {
+                let sink = self.metrics.as_deref();
+                return Err(metrics::failed(sink, frame.group, frame.command, start, e));
+            }
+            match runtime::timeout(grace, self.receive_response(Some(frame.sequence))).await {
+                Ok(Err(e)) if !is_lost(&e) => {
+                    let sink = self.metrics.as_deref();
+                    Err(metrics::failed(sink, frame.group, frame.command, start, e))
+                }
+                Ok(Err(_)) | Err(_) => {
+                    self.filter.stale_sequence = Some(frame.sequence);
+                    let sink = self.metrics.as_deref();
+                    metrics::report(sink, frame.group, frame.command, start, Outcome::Timeout);
+                    Ok(None)
+                }
+                Ok(response) => {
+                    let sink = self.metrics.as_deref();
+                    let id = frame.command;
+                    metrics::decode_response(sink, frame.group, id, start, response, self.decoding)
+                        .map(Some)
+                }
+            }
+        }
+
+        /// Frames dropped by the [ResponseFilter] and the transport
+        pub fn stats(&self) -> TransportStats {
+            let mut stats = self.filter.stats();
+            stats.skipped_bytes = self.transport.stats().skipped_bytes;
+            stats
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::os_management::{self, EchoResult};
+        use crate::{Group, OpCode};
+        use async_trait::async_trait;
+        use std::collections::VecDeque;
+        use std::future::Future;
+        use std::io::ErrorKind;
+        use std::pin::pin;
+        use std::task::{Context, Poll, Waker};
+
+        /// Returns what it was given one byte per read, then times out
+        struct OneByteReads(VecDeque<u8>);
+
+        #[async_trait]
+        impl SmpTransportAsync for OneByteReads {
+            async fn send(&mut self, _frame: Vec<u8>) -> Result<(), Error> {
+                Ok(())
+            }
+
+            async fn receive(&mut self) -> Result<Vec<u8>, Error> {
+                match self.0.pop_front() {
+                    Some(byte) => Ok(vec![byte]),
+                    None => Err(std::io::Error::from(ErrorKind::TimedOut).into()),
+                }
+            }
+        }
+
+        /// Run a future that never waits, as nothing in these tests does
+        fn ready<T>(future: impl Future<Output = T>) -> T {
+            match pin!(future).poll(&mut Context::from_waker(Waker::noop())) {
+                Poll::Ready(output) => output,
+                Poll::Pending => panic!("future is waiting"),
+            }
+        }
+
+        fn transport(input: &[u8]) -> CborSmpTransportAsync {
+            CborSmpTransportAsync {
+                transport: Box::new(OneByteReads(input.iter().copied().collect())),
+                encoding: CborEncoding::Plain,
+                decoding: DecodeOptions::default(),
+                filter: ResponseFilter::default(),
+                metrics: None,
+            }
+        }
+
+        fn echo_response(sequence: u8) -> Vec<u8> {
+            let r = "hello".repeat(100);
+            SmpFrame::new(
+                OpCode::WriteResponse,
+                sequence,
+                Group::Default,
+                0,
+                EchoResult::Ok { r },
+            )
+            .encode_with_cbor()
+        }
+
+        #[test]
+        fn one_byte_reads_are_reassembled() {
+            let mut transport = transport(&echo_response(1));
+            let request = os_management::echo(1, "hello".repeat(100));
+            let response: SmpFrame<EchoResult> =
+                ready(transport.transceive_cbor(&request, true)).unwrap();
+            assert_eq!(
+                response.data,
+                EchoResult::Ok {
+                    r: "hello".repeat(100)
+                }
+            );
+        }
+
+        #[test]
+        fn frames_are_not_merged() {
+            let first = echo_response(1);
+            let second = echo_response(2);
+            let mut transport = transport(&[first.as_slice(), &second].concat());
+            assert_eq!(ready(transport.receive()).unwrap(), first);
+            assert_eq!(ready(transport.receive()).unwrap(), second);
+        }
+
+        #[test]
+        fn missing_bytes_are_reported() {
+            let response = echo_response(1);
+            for (len, missing) in [(5, 3), (8, response.len() - 8), (response.len() - 3, 3)] {
+                let mut transport = transport(&response[..len]);
+                assert!(matches!(
+                    ready(transport.receive()),
+                    Err(Error::Smp(SmpError::IncompleteFrame { missing: m })) if m == missing
+                ));
+            }
         }
     }
 }
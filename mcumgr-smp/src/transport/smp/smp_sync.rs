@@ -1,52 +1,156 @@
 use crate::transport::error::Error;
+use crate::transport::filter::TransportStats;
+use std::time::Duration;
 
-pub trait SmpTransport {
+/// A blocking transport. Transports are `Send`, so a connection can be used from another
+/// thread, e.g. while Python bindings release the GIL
+pub trait SmpTransport: Send {
     /// send a single frame
     fn send(&mut self, frame: Vec<u8>) -> Result<(), Error>;
 
+    /// send a single frame from a buffer the caller keeps, e.g. to reuse it for the next frame
+    fn send_slice(&mut self, frame: &[u8]) -> Result<(), Error> {
+        self.send(frame.to_vec())
+    }
+
     /// receive a single frame
     fn receive(&mut self) -> Result<Vec<u8>, Error>;
+
+    /// Set how long a receive waits for a frame, forever with `None`, and return the timeout
+    /// it replaces. Transports without a receive timeout ignore it and return `None`.
+    fn set_receive_timeout(
+        &mut self,
+        _timeout: Option<Duration>,
+    ) -> Result<Option<Duration>, Error> {
+        Ok(None)
+    }
+
+    /// counters of what the transport dropped while receiving, e.g. console output
+    fn stats(&self) -> TransportStats {
+        TransportStats::default()
+    }
 }
 
 #[cfg(feature = "payload-cbor")]
 pub mod cbor {
-    use crate::smp::SmpFrame;
+    use crate::smp::{missing_bytes, CborEncoding, DecodeOptions, SmpError, SmpFrame};
     use crate::transport::error::Error;
+    use crate::transport::filter::{ResponseFilter, TransportStats};
+    use crate::transport::metrics::{self, MetricsSink, Outcome};
+    use crate::transport::retry::is_lost;
     use crate::transport::smp::SmpTransport;
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
 
+    /// Sends requests and decodes responses as CBOR over a transport.
+    ///
+    /// Build it with [CborSmpTransport::new] and change the public fields afterwards, fields added
+    /// later get their defaults there.
+    #[non_exhaustive]
     pub struct CborSmpTransport {
         pub transport: Box<dyn SmpTransport>,
+        /// how requests are encoded, [CborEncoding::Canonical] for deterministic frames
+        pub encoding: CborEncoding,
+        /// how responses are decoded, e.g. how much of an undecodable payload errors keep
+        pub decoding: DecodeOptions,
+        /// drops late and duplicate responses instead of failing the next request
+        pub filter: ResponseFilter,
+        /// gets the outcome and duration of every request, `None` to not collect metrics
+        pub metrics: Option<Arc<dyn MetricsSink>>,
     }
 
     impl CborSmpTransport {
+        /// A client of `transport` encoding plainly, with default [DecodeOptions], a default
+        /// [ResponseFilter] and no metrics
+        pub fn new(transport: Box<dyn SmpTransport>) -> Self {
+            Self {
+                transport,
+                encoding: CborEncoding::default(),
+                decoding: DecodeOptions::default(),
+                filter: ResponseFilter::default(),
+                metrics: None,
+            }
+        }
+
         pub fn send(&mut self, frame: Vec<u8>) -> Result<(), Error> {
             self.transport.send(frame)
         }
+        /// Receive a complete frame. Transports may deliver a frame in parts, e.g. BLE
+        /// notifications, which are read until the length in the header is reached.
+        /// A transport timing out before fails with [SmpError::IncompleteFrame].
         pub fn receive(&mut self) -> Result<Vec<u8>, Error> {
-            self.transport.receive()
+            let ret = self.receive_parts();
+            if let Err(e) = &ret {
+                metrics::receive_error(self.metrics.as_deref(), e);
+            }
+            ret
+        }
+
+        fn receive_parts(&mut self) -> Result<Vec<u8>, Error> {
+            let mut frame = self.transport.receive()?;
+            while let Some(missing) = missing_bytes(&frame) {
+                match self.transport.receive() {
+                    Ok(part) => frame.extend_from_slice(&part),
+                    Err(e) if is_lost(&e) => Err(SmpError::IncompleteFrame { missing })?,
+                    Err(e) => return Err(e),
+                }
+            }
+            Ok(frame)
         }
 
         pub fn transceive(&mut self, frame: Vec<u8>) -> Result<Vec<u8>, Error> {
             self.transport.send(frame)?;
-            self.transport.receive()
+            self.receive()
         }
 
+        /// Send a frame without waiting for a response
         pub fn send_cbor<T: serde::Serialize>(&mut self, frame: &SmpFrame<T>) -> Result<(), Error> {
-            let bytes = frame.encode_with_cbor();
+            self.filter.sent(frame.sequence);
+            if let Some(metrics) = &self.metrics {
+                metrics.on_request(frame.group, frame.command);
+            }
+            let bytes = frame.encode_with_cbor_as(self.encoding);
             self.send(bytes)
         }
         pub fn receive_cbor<T: serde::de::DeserializeOwned>(
             &mut self,
             expected_sequence: Option<u8>,
         ) -> Result<SmpFrame<T>, Error> {
-            let bytes = self.receive()?;
-            let frame = SmpFrame::<T>::decode_with_cbor(&bytes)?;
-            if let Some(expected_sequence) = expected_sequence {
-                if frame.sequence != expected_sequence {
-                    Err(Error::Smp(crate::SmpError::UnexpectedSeq))?;
+            let bytes = self.receive_response(expected_sequence)?;
+            Ok(SmpFrame::<T>::decode_with_cbor_as(&bytes, self.decoding)?)
+        }
+
+        /// Receive until the [ResponseFilter] accepts a frame
+        fn receive_response(&mut self, expected_sequence: Option<u8>) -> Result<Vec<u8>, Error> {
+            let mut bytes = self.receive()?;
+            loop {
+                let before = self.filter.stats();
+                if self.filter.accept(&bytes, expected_sequence) {
+                    return Ok(bytes);
                 }
+                metrics::filter_events(self.metrics.as_deref(), before, self.filter.stats());
+                bytes = self.receive()?;
             }
-            Ok(frame)
+        }
+
+        /// Receive the response to `frame` and report its outcome
+        fn finish<Req, Resp: serde::de::DeserializeOwned>(
+            &mut self,
+            frame: &SmpFrame<Req>,
+            start: Instant,
+            sent: Result<(), Error>,
+            check_sequence: bool,
+        ) -> Result<SmpFrame<Resp>, Error> {
+            let response =
+                sent.and_then(|_| self.receive_response(check_sequence.then_some(frame.sequence)));
+            metrics::decode_response(
+                self.metrics.as_deref(),
+                frame.group,
+                frame.command,
+                start,
+                response,
+                self.decoding,
+            )
         }
 
         pub fn transceive_cbor<Req: serde::Serialize, Resp: serde::de::DeserializeOwned>(
@@ -54,8 +158,357 @@ pub mod cbor {
             frame: &SmpFrame<Req>,
             check_sequence: bool,
         ) -> Result<SmpFrame<Resp>, Error> {
-            self.send_cbor(frame)?;
-            self.receive_cbor(check_sequence.then_some(frame.sequence))
+            let start = Instant::now();
+            let sent = self.send_cbor(frame);
+            self.finish(frame, start, sent, check_sequence)
+        }
+
+        /// Like [Self::send_cbor], but encoded into `buf`, which is reused for the next frame
+        /// instead of allocating a new one
+        pub fn send_cbor_with<T: serde::Serialize>(
+            &mut self,
+            frame: &SmpFrame<T>,
+            buf: &mut Vec<u8>,
+        ) -> Result<(), Error> {
+            self.filter.sent(frame.sequence);
+            if let Some(metrics) = &self.metrics {
+                metrics.on_request(frame.group, frame.command);
+            }
+            frame.encode_into_as(buf, self.encoding);
+            self.transport.send_slice(buf)
+        }
+
+        /// Like [Self::transceive_cbor], but the request is encoded into `buf`, see
+        /// [Self::send_cbor_with]
+        pub fn transceive_cbor_with<Req: serde::Serialize, Resp: serde::de::DeserializeOwned>(
+            &mut self,
+            frame: &SmpFrame<Req>,
+            check_sequence: bool,
+            buf: &mut Vec<u8>,
+        ) -> Result<SmpFrame<Resp>, Error> {
+            let start = Instant::now();
+            let sent = self.send_cbor_with(frame, buf);
+            self.finish(frame, start, sent, check_sequence)
+        }
+
+        /// Like [Self::transceive_cbor], for a response borrowing from its frame, e.g.
+        /// [TaskStatsResultRef](crate::os_management::TaskStatsResultRef). The frame is
+        /// received into `response`, which can be reused once the response is dropped.
+        #[cfg(feature = "payload-cbor-borrowed")]
+        pub fn transceive_cbor_borrowed<'b, Req: serde::Serialize, Resp: serde::Deserialize<'b>>(
+            &mut self,
+            frame: &SmpFrame<Req>,
+            check_sequence: bool,
+            response: &'b mut Vec<u8>,
+        ) -> Result<SmpFrame<Resp>, Error> {
+            let start = Instant::now();
+            let decoding = self.decoding;
+            let received = self
+                .send_cbor(frame)
+                .and_then(|_| self.receive_response(check_sequence.then_some(frame.sequence)));
+            let received = received.map(|bytes| {
+                *response = bytes;
+                response.as_slice()
+            });
+            metrics::decode_response_with(
+                self.metrics.as_deref(),
+                frame.group,
+                frame.command,
+                start,
+                received,
+                |bytes| SmpFrame::decode_with(bytes, decoding, crate::cbor::decode_borrowed),
+            )
+        }
+
+        /// Send a request whose response may never arrive, e.g. a reset that takes effect
+        /// before the response is sent.
+        ///
+        /// The receive timeout of the transport is lowered to `grace` while waiting, see
+        /// [SmpTransport::set_receive_timeout], and `None` is returned if no response arrived
+        /// by then. A response arriving later is discarded by the next receive instead of being
+        /// taken for the response to the next request.
+        pub fn transceive_cbor_optional<
+            Req: serde::Serialize,
+            Resp: serde::de::DeserializeOwned,
+        >(
+            &mut self,
+            frame: &SmpFrame<Req>,
+            grace: Duration,
+        ) -> Result<Option<SmpFrame<Resp>>, Error> {
+            let start = Instant::now();
+            let previous = match self
+                .send_cbor(frame)
+                .and_then(|_| self.lower_timeout(grace))
+            {
+                Ok(previous) => previous,
+                Err(e) => {
+                    let sink = self.metrics.as_deref();
+                    return Err(metrics::failed(sink, frame.group, frame.command, start, e));
+                }
+            };
+            let response = self.receive_response(Some(frame.sequence));
+            self.transport.set_receive_timeout(previous)?;
+
+            match response {
+                Err(e) if is_lost(&e) => {
+                    self.filter.stale_sequence = Some(frame.sequence);
+                    let sink = self.metrics.as_deref();
+                    metrics::report(sink, frame.group, frame.command, start, Outcome::Timeout);
+                    Ok(None)
+                }
+                response => {
+                    let sink = self.metrics.as_deref();
+                    let id = frame.command;
+                    metrics::decode_response(sink, frame.group, id, start, response, self.decoding)
+                        .map(Some)
+                }
+            }
+        }
+
+        /// Set the receive timeout of the transport to `timeout` unless it is shorter, and
+        /// return the timeout to restore
+        fn lower_timeout(&mut self, timeout: Duration) -> Result<Option<Duration>, Error> {
+            let previous = self.transport.set_receive_timeout(Some(timeout))?;
+            if previous.is_some_and(|previous| previous < timeout) {
+                self.transport.set_receive_timeout(previous)?;
+            }
+            Ok(previous)
+        }
+
+        /// Frames dropped by the [ResponseFilter] and the transport
+        pub fn stats(&self) -> TransportStats {
+            let mut stats = self.filter.stats();
+            stats.skipped_bytes = self.transport.stats().skipped_bytes;
+            stats
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::os_management::{self, EchoResult};
+        use crate::{Group, OpCode};
+        use std::collections::VecDeque;
+        use std::io::ErrorKind;
+
+        /// Returns what it was given one byte per read, then times out
+        struct OneByteReads(VecDeque<u8>);
+
+        impl SmpTransport for OneByteReads {
+            fn send(&mut self, _frame: Vec<u8>) -> Result<(), Error> {
+                Ok(())
+            }
+
+            fn receive(&mut self) -> Result<Vec<u8>, Error> {
+                match self.0.pop_front() {
+                    Some(byte) => Ok(vec![byte]),
+                    None => Err(std::io::Error::from(ErrorKind::TimedOut).into()),
+                }
+            }
+        }
+
+        fn transport(input: &[u8]) -> CborSmpTransport {
+            CborSmpTransport {
+                transport: Box::new(OneByteReads(input.iter().copied().collect())),
+                encoding: CborEncoding::Plain,
+                decoding: DecodeOptions::default(),
+                filter: ResponseFilter::default(),
+                metrics: None,
+            }
+        }
+
+        fn echo_response(sequence: u8) -> Vec<u8> {
+            let r = "hello".repeat(100);
+            SmpFrame::new(
+                OpCode::WriteResponse,
+                sequence,
+                Group::Default,
+                0,
+                EchoResult::Ok { r },
+            )
+            .encode_with_cbor()
+        }
+
+        #[test]
+        fn one_byte_reads_are_reassembled() {
+            let mut transport = transport(&echo_response(1));
+            let request = os_management::echo(1, "hello".repeat(100));
+            let response: SmpFrame<EchoResult> = transport.transceive_cbor(&request, true).unwrap();
+            assert_eq!(
+                response.data,
+                EchoResult::Ok {
+                    r: "hello".repeat(100)
+                }
+            );
+        }
+
+        #[cfg(feature = "payload-cbor-borrowed")]
+        #[test]
+        fn borrowed_responses_point_into_the_buffer() {
+            use crate::stat_management::{self, ListGroupsResultRef};
+
+            let response = SmpFrame::new(
+                OpCode::ReadResponse,
+                1,
+                Group::Statistics,
+                1,
+                ListGroupsResultRef::Ok {
+                    stat_list: vec!["ble_phy", "ble_ll"],
+                },
+            )
+            .encode_with_cbor();
+            let mut transport = transport(&response);
+
+            let mut buf = Vec::new();
+            let received: SmpFrame<ListGroupsResultRef> = transport
+                .transceive_cbor_borrowed(&stat_management::list_groups(1), true, &mut buf)
+                .unwrap();
+            let ListGroupsResultRef::Ok { stat_list } = received.data else {
+                panic!("the group list is an error");
+            };
+            assert_eq!(stat_list, ["ble_phy", "ble_ll"]);
+            let names: Vec<*const u8> = stat_list.iter().map(|name| name.as_ptr()).collect();
+            assert!(names.iter().all(|name| buf.as_ptr_range().contains(name)));
+        }
+
+        #[test]
+        fn frames_are_not_merged() {
+            let first = echo_response(1);
+            let second = echo_response(2);
+            let mut transport = transport(&[first.as_slice(), &second].concat());
+            assert_eq!(transport.receive().unwrap(), first);
+            assert_eq!(transport.receive().unwrap(), second);
+        }
+
+        /// Never answers, and notes the receive timeout of every receive
+        #[derive(Default)]
+        struct Silent {
+            timeout: Option<Duration>,
+            waited: Arc<std::sync::Mutex<Vec<Option<Duration>>>>,
+        }
+
+        impl SmpTransport for Silent {
+            fn send(&mut self, _frame: Vec<u8>) -> Result<(), Error> {
+                Ok(())
+            }
+
+            fn receive(&mut self) -> Result<Vec<u8>, Error> {
+                self.waited.lock().unwrap().push(self.timeout);
+                Err(std::io::Error::from(ErrorKind::TimedOut).into())
+            }
+
+            fn set_receive_timeout(
+                &mut self,
+                timeout: Option<Duration>,
+            ) -> Result<Option<Duration>, Error> {
+                Ok(std::mem::replace(&mut self.timeout, timeout))
+            }
+        }
+
+        #[test]
+        fn grace_lowers_the_timeout_for_one_request() {
+            for (timeout, grace, waited) in [
+                (None, 100, 100),
+                (Some(5000), 100, 100),
+                (Some(50), 100, 50),
+            ] {
+                let silent = Silent {
+                    timeout: timeout.map(Duration::from_millis),
+                    ..Default::default()
+                };
+                let log = silent.waited.clone();
+                let mut transport = CborSmpTransport {
+                    transport: Box::new(silent),
+                    encoding: CborEncoding::Plain,
+                    decoding: DecodeOptions::default(),
+                    filter: ResponseFilter::default(),
+                    metrics: None,
+                };
+
+                let reset = crate::os_management::reset(1, false);
+                let response: Option<SmpFrame<crate::os_management::ResetResult>> = transport
+                    .transceive_cbor_optional(&reset, Duration::from_millis(grace))
+                    .unwrap();
+                assert!(response.is_none());
+                assert_eq!(*log.lock().unwrap(), [Some(Duration::from_millis(waited))]);
+
+                // the timeout of the transport is back for the next request
+                let _ = transport.receive();
+                assert_eq!(log.lock().unwrap()[1], timeout.map(Duration::from_millis));
+            }
+        }
+
+        /// Returns the given frames, one per receive, then times out
+        struct Scripted(VecDeque<Vec<u8>>);
+
+        impl SmpTransport for Scripted {
+            fn send(&mut self, _frame: Vec<u8>) -> Result<(), Error> {
+                Ok(())
+            }
+
+            fn receive(&mut self) -> Result<Vec<u8>, Error> {
+                self.0
+                    .pop_front()
+                    .ok_or_else(|| std::io::Error::from(ErrorKind::TimedOut).into())
+            }
+        }
+
+        fn echo(sequence: u8, r: &str) -> Vec<u8> {
+            let r = r.to_string();
+            SmpFrame::new(
+                OpCode::WriteResponse,
+                sequence,
+                Group::Default,
+                0,
+                EchoResult::Ok { r },
+            )
+            .encode_with_cbor()
+        }
+
+        #[test]
+        fn duplicates_and_foreign_frames_are_skipped() {
+            let script = [
+                echo(200, "other traffic"),
+                echo(1, "first"),
+                echo(1, "first"),
+                echo(9, "other traffic"),
+                echo(2, "second"),
+            ];
+            let mut transport = CborSmpTransport {
+                transport: Box::new(Scripted(script.into_iter().collect())),
+                encoding: CborEncoding::Plain,
+                decoding: DecodeOptions::default(),
+                filter: ResponseFilter::default(),
+                metrics: None,
+            };
+
+            for (sequence, r) in [(1, "first"), (2, "second")] {
+                let request = os_management::echo(sequence, r.to_string());
+                let response: SmpFrame<EchoResult> =
+                    transport.transceive_cbor(&request, true).unwrap();
+                assert_eq!(response.sequence, sequence);
+                assert_eq!(response.data, EchoResult::Ok { r: r.to_string() });
+            }
+            assert_eq!(
+                transport.stats(),
+                TransportStats {
+                    unexpected_frames: 2,
+                    duplicate_frames: 1,
+                    skipped_bytes: 0,
+                }
+            );
+        }
+
+        #[test]
+        fn missing_bytes_are_reported() {
+            let response = echo_response(1);
+            for (len, missing) in [(5, 3), (8, response.len() - 8), (response.len() - 3, 3)] {
+                let mut transport = transport(&response[..len]);
+                assert!(matches!(
+                    transport.receive(),
+                    Err(Error::Smp(SmpError::IncompleteFrame { missing: m })) if m == missing
+                ));
+            }
         }
     }
 }
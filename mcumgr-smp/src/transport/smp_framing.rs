@@ -7,7 +7,7 @@ use crc::Crc;
 use std::cmp::min;
 
 /// there are multiple possible CRC implementations. This matches the results from mcumgr
-const CALC_CRC: Crc<u16> = crc::Crc::<u16>::new(&crc::CRC_16_XMODEM);
+pub(crate) const CALC_CRC: Crc<u16> = crc::Crc::<u16>::new(&crc::CRC_16_XMODEM);
 
 #[derive(thiserror::Error, Debug)]
 pub enum SmpTransportError {
@@ -17,12 +17,39 @@ pub enum SmpTransportError {
     UnknownFrameStart([u8; 2]),
     #[error("packet length invalid")]
     PacketLength(u16, usize),
+    #[error("line too short: {0} bytes")]
+    ShortLine(usize),
     #[error("wrong crc")]
     CRCError,
     #[error("base64 decoding error: {0}")]
     Base64DecodeError(#[from] base64::DecodeError),
 }
 
+/// Lines of SMP frames are at most 127 bytes, longer lines are cut off here so a device that
+/// never sends a newline can't fill up the memory
+pub const MAX_LINE_LEN: usize = 4096;
+
+/// The length of the first line in `buf` including its newline, or [MAX_LINE_LEN] if there
+/// is no newline within that many bytes. `None` while the line is incomplete.
+pub fn line_len(buf: &[u8]) -> Option<usize> {
+    match buf[..buf.len().min(MAX_LINE_LEN)]
+        .iter()
+        .position(|b| *b == b'\n')
+    {
+        Some(newline) => Some(newline + 1),
+        // a line without a newline ends at the limit and fails to decode
+        None if buf.len() >= MAX_LINE_LEN => Some(MAX_LINE_LEN),
+        None => None,
+    }
+}
+
+/// Position of the first frame or continuation marker in a line, other console output may
+/// precede it on a shared line
+pub fn frame_start(line: &[u8]) -> Option<usize> {
+    line.windows(2)
+        .position(|marker| marker == [0x06, 0x09] || marker == [0x04, 0x14])
+}
+
 pub struct SmpTransportDecoder {
     /// length + 2 bytes CRC
     content_length: u16,
@@ -44,19 +71,35 @@ impl SmpTransportDecoder {
     }
 
     /// attempt to parse a packet from the input buffer and return whether the frame is complete
+    ///
+    /// The line includes its terminating newline. Malformed lines are rejected with an error,
+    /// the buffered data never grows beyond the length announced in the first line.
     pub fn input_line(&mut self, input: &[u8]) -> Result<bool, SmpTransportError> {
-        let start = (input[0], input[1]);
-        let base64_packet = general_purpose::STANDARD.decode(&input[2..input.len() - 1])?;
+        let Some(([a, b], line)) = input.split_first_chunk::<2>() else {
+            return Err(SmpTransportError::ShortLine(input.len()));
+        };
+        let Some((_newline, base64_line)) = line.split_last() else {
+            return Err(SmpTransportError::ShortLine(input.len()));
+        };
+        let base64_packet = general_purpose::STANDARD.decode(base64_line)?;
 
-        let packet_body = match start {
+        let packet_body = match (*a, *b) {
             (0x06, 0x09) => {
                 if self.content_length > 0 {
                     return Err(SmpTransportError::UnexpectedFrame);
                 }
 
-                self.content_length = u16::from_be_bytes([base64_packet[0], base64_packet[1]]);
+                let Some((length, body)) = base64_packet.split_first_chunk::<2>() else {
+                    return Err(SmpTransportError::PacketLength(0, base64_packet.len()));
+                };
+                let content_length = u16::from_be_bytes(*length);
+                // the content includes the CRC
+                if content_length < 2 {
+                    return Err(SmpTransportError::PacketLength(content_length, body.len()));
+                }
+                self.content_length = content_length;
 
-                &base64_packet[2..]
+                body
             }
             (0x04, 0x14) => {
                 if self.content_length == 0 {
@@ -108,6 +151,64 @@ impl SmpTransportDecoder {
     }
 }
 
+/// Reassembles frames from console lines that may be mixed with other console output, like
+/// log messages or a shell sharing the line.
+///
+/// Other output is skipped and counted, a frame cut off by the start of another one is
+/// dropped. After an error, the next frame starts from scratch.
+#[derive(Default)]
+pub struct ConsoleDecoder {
+    decoder: SmpTransportDecoder,
+    skipped_bytes: u64,
+}
+
+impl ConsoleDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Feed a line including its newline, returns the frame once its last line is in
+    pub fn input_line(&mut self, line: &[u8]) -> Result<Option<Vec<u8>>, SmpTransportError> {
+        let Some(start) = frame_start(line) else {
+            self.skipped_bytes += line.len() as u64;
+            return Ok(None);
+        };
+        self.skipped_bytes += start as u64;
+        let line = &line[start..];
+
+        let complete = match self.decoder.input_line(line) {
+            Ok(complete) => Ok(complete),
+            // a new frame cutting off an incomplete one
+            Err(SmpTransportError::UnexpectedFrame) if line[0] == 0x06 => {
+                self.decoder = SmpTransportDecoder::new();
+                self.decoder.input_line(line)
+            }
+            // a continuation without its start
+            Err(SmpTransportError::UnexpectedFrame) => {
+                self.skipped_bytes += line.len() as u64;
+                return Ok(None);
+            }
+            Err(e) => Err(e),
+        };
+
+        match complete {
+            Ok(false) => Ok(None),
+            Ok(true) => std::mem::take(&mut self.decoder)
+                .into_frame_payload()
+                .map(Some),
+            Err(e) => {
+                self.decoder = SmpTransportDecoder::new();
+                Err(e)
+            }
+        }
+    }
+
+    /// Bytes of other console output skipped so far
+    pub fn skipped_bytes(&self) -> u64 {
+        self.skipped_bytes
+    }
+}
+
 pub struct SmpTransportEncoder<'a> {
     written_len: usize,
     payload: &'a [u8],
@@ -126,7 +227,13 @@ impl<'a> SmpTransportEncoder<'a> {
     pub fn write_line(&mut self, out_buf: &mut [u8]) -> Result<usize, EncodeSliceError> {
         // max 127 with header and newline in base64 encoding
         const MAX_RAW_BODY_LEN: usize = 93; // 124.0 / 4.0 * 3.0 as usize;
-        let mut base64_payload = Vec::with_capacity(MAX_RAW_BODY_LEN);
+
+        // on the stack, a line doesn't allocate
+        let mut raw = [0u8; MAX_RAW_BODY_LEN];
+        let mut base64_payload = RawBody {
+            buf: &mut raw,
+            len: 0,
+        };
 
         // println!(
         //     "write_line: Written: {}; Total: {}",
@@ -149,7 +256,8 @@ impl<'a> SmpTransportEncoder<'a> {
         let payload_len = if last_frame {
             remaining_len
         } else {
-            min(MAX_RAW_BODY_LEN - base64_payload.len(), remaining_len)
+            // keep at least one byte for the last line, which has to carry the CRC
+            min(MAX_RAW_BODY_LEN - base64_payload.len(), remaining_len - 1)
         };
 
         base64_payload
@@ -170,7 +278,7 @@ impl<'a> SmpTransportEncoder<'a> {
         // println!("base64 payload: {:x?}", base64_payload);
 
         let base64_len =
-            general_purpose::STANDARD.encode_slice(base64_payload, &mut out_buf[2..])?;
+            general_purpose::STANDARD.encode_slice(base64_payload.as_slice(), &mut out_buf[2..])?;
 
         // println!(
         //     "resulting base64 string: {}",
@@ -188,3 +296,76 @@ impl<'a> SmpTransportEncoder<'a> {
         self.written_len >= self.payload.len()
     }
 }
+
+/// The raw bytes of a line before base64 encoding
+struct RawBody<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+impl RawBody<'_> {
+    fn extend_from_slice(&mut self, bytes: &[u8]) {
+        self.buf[self.len..self.len + bytes.len()].copy_from_slice(bytes);
+        self.len += bytes.len();
+    }
+
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    fn as_slice(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+}
+
+/// Encode a complete frame into console lines.  
+/// This is the same data [SmpTransportEncoder] produces line by line.
+pub fn encode_lines(payload: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    encode_lines_into(payload, &mut out);
+    out
+}
+
+/// Like [encode_lines], but into `out`, replacing its contents, so the buffer can be reused
+/// for every frame.
+pub fn encode_lines_into(payload: &[u8], out: &mut Vec<u8>) {
+    let mut encoder = SmpTransportEncoder::new(payload);
+    let mut buf = [0; 128];
+    out.clear();
+
+    while !encoder.is_complete() {
+        let len = encoder
+            .write_line(&mut buf)
+            .expect("buffer is large enough for a line");
+        out.extend_from_slice(&buf[0..len]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Feed the lines of `input` to the decoder, collecting the frames
+    fn decode_lines(decoder: &mut ConsoleDecoder, input: &[u8]) -> Vec<Vec<u8>> {
+        input
+            .split_inclusive(|&b| b == b'\n')
+            .filter_map(|line| decoder.input_line(line).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn console_output_between_frames_is_skipped_and_counted() {
+        let frame = vec![0x03, 0x00, 0x00, 0x01, 0x00, 0x00, 0x01, 0x00, 0xa0];
+        let junk = b"[00:00:01.000] <inf> main: tick\n";
+        let prompt = b"uart:~$ ";
+
+        let mut input = junk.to_vec();
+        input.extend_from_slice(&encode_lines(&frame));
+        input.extend_from_slice(prompt);
+        input.extend_from_slice(&encode_lines(&frame));
+
+        let mut decoder = ConsoleDecoder::new();
+        assert_eq!(decode_lines(&mut decoder, &input), [frame.clone(), frame]);
+        assert_eq!(decoder.skipped_bytes(), (junk.len() + prompt.len()) as u64);
+    }
+}
@@ -0,0 +1,102 @@
+use std::time::Duration;
+
+use crate::smp::{Group, OpCode, SmpFrame};
+
+/// How long the device may take to answer a request
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OperationClass {
+    /// answered right away, e.g. echo, reads of the image state or settings
+    Fast,
+    /// the device erases flash before answering, e.g. an image erase or the first chunk of an
+    /// image upload
+    Slow,
+    /// the device may reset before answering, e.g. a reset
+    Reset,
+}
+
+impl OperationClass {
+    /// Classify a request by its header. The first chunk of an image upload can't be told
+    /// apart from the others by its header, see [OperationClass::of_upload_chunk].
+    pub fn of(operation: OpCode, group: Group, command: u8) -> Self {
+        match (operation, group, command) {
+            // reset
+            (OpCode::WriteRequest, Group::Default, 5) => OperationClass::Reset,
+            // image erase
+            (OpCode::WriteRequest, Group::ApplicationManagement, 5) => OperationClass::Slow,
+            _ => OperationClass::Fast,
+        }
+    }
+
+    /// Classify the request of a frame
+    pub fn of_frame<T>(frame: &SmpFrame<T>) -> Self {
+        Self::of(frame.operation, frame.group, frame.command)
+    }
+
+    /// Classify an image upload chunk by its offset. Many devices erase the slot when they
+    /// receive the first chunk.
+    pub fn of_upload_chunk(offset: usize) -> Self {
+        match offset {
+            0 => OperationClass::Slow,
+            _ => OperationClass::Fast,
+        }
+    }
+}
+
+/// Default deadline of [OperationClass::Fast] requests
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+/// Default deadline of [OperationClass::Slow] requests, long enough to erase a slot
+pub const DEFAULT_SLOW_TIMEOUT: Duration = Duration::from_secs(30);
+/// Default time to wait for the response to a reset, the device may reset before sending it
+pub const DEFAULT_RESET_GRACE: Duration = Duration::from_secs(2);
+
+/// A deadline per [OperationClass], so a dead connection is noticed after the short deadline
+/// of fast requests while erasing flash may still take much longer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timeouts {
+    pub fast: Duration,
+    pub slow: Duration,
+    /// how long to wait for the response to a reset. No response within this time is not an
+    /// error, the device may have reset before answering.
+    pub reset_grace: Duration,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            fast: DEFAULT_TIMEOUT,
+            slow: DEFAULT_SLOW_TIMEOUT,
+            reset_grace: DEFAULT_RESET_GRACE,
+        }
+    }
+}
+
+impl Timeouts {
+    /// The given deadlines, the reset grace period is [DEFAULT_RESET_GRACE] or `fast` if that
+    /// is shorter
+    pub fn new(fast: Duration, slow: Duration) -> Self {
+        Self {
+            fast,
+            slow,
+            reset_grace: DEFAULT_RESET_GRACE.min(fast),
+        }
+    }
+
+    /// The same deadline for fast and slow requests, the behaviour of a single timeout
+    pub fn uniform(timeout: Duration) -> Self {
+        Self::new(timeout, timeout)
+    }
+
+    /// The deadline of requests of the given class
+    pub fn of(&self, class: OperationClass) -> Duration {
+        match class {
+            OperationClass::Fast => self.fast,
+            OperationClass::Slow => self.slow,
+            OperationClass::Reset => self.reset_grace,
+        }
+    }
+
+    /// The deadline of the request of a frame
+    pub fn of_frame<T>(&self, frame: &SmpFrame<T>) -> Duration {
+        self.of(OperationClass::of_frame(frame))
+    }
+}
@@ -7,3 +7,6 @@ pub use udp_async::UdpTransportAsync;
 pub mod udp_sync;
 #[cfg(feature = "transport-udp")]
 pub use udp_sync::UdpTransport;
+
+/// The largest UDP datagram, responses are received into a buffer of this size
+const MAX_DATAGRAM_LEN: usize = 65535;
@@ -1,12 +1,13 @@
 // Author: Sascha Zenglein <zenglein@gessler.de>
 // Copyright (c) 2023 Gessler GmbH.
 
+use super::MAX_DATAGRAM_LEN;
 use crate::transport::error::Error;
+use crate::transport::runtime::{ToSocketAddrs, UdpSocket};
 use crate::transport::smp::SmpTransportAsync;
 use async_trait::async_trait;
 use std::io;
 use std::net::{Ipv6Addr, SocketAddr};
-use tokio::net::{ToSocketAddrs, UdpSocket};
 
 pub struct UdpTransportAsync {
     socket: UdpSocket,
@@ -18,7 +19,8 @@ impl UdpTransportAsync {
         let socket = UdpSocket::bind(SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), 0)).await?;
         socket.connect(target).await?;
 
-        let buf = vec![0; 1500];
+        // a datagram can hold the largest SMP frame, more than the usual MTU
+        let buf = vec![0; MAX_DATAGRAM_LEN];
 
         Ok(Self { socket, buf })
     }
@@ -27,7 +29,11 @@ impl UdpTransportAsync {
 #[async_trait]
 impl SmpTransportAsync for UdpTransportAsync {
     async fn send(&mut self, frame: Vec<u8>) -> Result<(), Error> {
-        self.socket.send(&frame).await?;
+        self.send_slice(&frame).await
+    }
+
+    async fn send_slice(&mut self, frame: &[u8]) -> Result<(), Error> {
+        self.socket.send(frame).await?;
         Ok(())
     }
 
@@ -37,3 +43,85 @@ impl SmpTransportAsync for UdpTransportAsync {
         Ok(Vec::from(&self.buf[0..len]))
     }
 }
+
+#[cfg(all(test, feature = "payload-cbor"))]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::os_management::{self, EchoRequest, EchoResult};
+    use crate::transport::runtime;
+    use crate::transport::smp::CborSmpTransportAsync;
+    use crate::{Group, OpCode, SmpFrame};
+
+    /// A device on a thread of its own, so it doesn't depend on the runtime under test. It
+    /// answers echo requests until it gets "quiet", which it leaves unanswered
+    fn device() -> SocketAddr {
+        let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        std::thread::spawn(move || {
+            let mut buf = [0; 1024];
+            loop {
+                let (len, peer) = socket.recv_from(&mut buf).unwrap();
+                let request: SmpFrame<EchoRequest> =
+                    SmpFrame::decode_with_cbor(&buf[..len]).unwrap();
+                if request.data.d == "quiet" {
+                    continue;
+                }
+                let response = SmpFrame::new(
+                    OpCode::WriteResponse,
+                    request.sequence,
+                    Group::Default,
+                    0,
+                    EchoResult::Ok { r: request.data.d },
+                );
+                socket.send_to(&response.encode_with_cbor(), peer).unwrap();
+            }
+        });
+        addr
+    }
+
+    async fn echo_and_timeout() {
+        let mut transport = CborSmpTransportAsync {
+            transport: Box::new(UdpTransportAsync::new(device()).await.unwrap()),
+            encoding: Default::default(),
+            decoding: Default::default(),
+            filter: Default::default(),
+            metrics: None,
+        };
+
+        let response: SmpFrame<EchoResult> = transport
+            .transceive_cbor(&os_management::echo(7, "hello".to_string()), true)
+            .await
+            .unwrap();
+        assert_eq!(response.sequence, 7);
+        assert_eq!(
+            response.data,
+            EchoResult::Ok {
+                r: "hello".to_string()
+            }
+        );
+
+        let unanswered = runtime::timeout(
+            Duration::from_millis(100),
+            transport.transceive_cbor::<_, EchoResult>(
+                &os_management::echo(8, "quiet".to_string()),
+                true,
+            ),
+        )
+        .await;
+        assert!(unanswered.is_err());
+    }
+
+    #[cfg(feature = "runtime-tokio")]
+    #[tokio::test]
+    async fn echo_on_tokio() {
+        echo_and_timeout().await;
+    }
+
+    #[cfg(all(feature = "runtime-smol", not(feature = "runtime-tokio")))]
+    #[test]
+    fn echo_on_smol() {
+        async_io::block_on(echo_and_timeout());
+    }
+}
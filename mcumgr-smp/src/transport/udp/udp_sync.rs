@@ -1,6 +1,7 @@
 // Author: Sascha Zenglein <zenglein@gessler.de>
 // Copyright (c) 2023 Gessler GmbH.
 
+use super::MAX_DATAGRAM_LEN;
 use crate::transport::error::Error;
 use crate::transport::smp::SmpTransport;
 use std::io;
@@ -17,7 +18,8 @@ impl UdpTransport {
         let socket = UdpSocket::bind(SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), 0))?;
         socket.connect(target)?;
 
-        let buf = vec![0; 1500];
+        // a datagram can hold the largest SMP frame, more than the usual MTU
+        let buf = vec![0; MAX_DATAGRAM_LEN];
 
         Ok(Self { socket, buf })
     }
@@ -30,7 +32,11 @@ impl UdpTransport {
 
 impl SmpTransport for UdpTransport {
     fn send(&mut self, frame: Vec<u8>) -> Result<(), Error> {
-        self.socket.send(&frame)?;
+        self.send_slice(&frame)
+    }
+
+    fn send_slice(&mut self, frame: &[u8]) -> Result<(), Error> {
+        self.socket.send(frame)?;
         Ok(())
     }
 
@@ -39,4 +45,13 @@ impl SmpTransport for UdpTransport {
 
         Ok(Vec::from(&self.buf[0..len]))
     }
+
+    fn set_receive_timeout(
+        &mut self,
+        timeout: Option<Duration>,
+    ) -> Result<Option<Duration>, Error> {
+        let previous = self.socket.read_timeout()?;
+        self.recv_timeout(timeout)?;
+        Ok(previous)
+    }
 }
@@ -0,0 +1,334 @@
+// Every payload type decodes to an equal value from the frame it encodes to. Clones are equal
+// and hash the same, so payloads can be compared, deduplicated and used as map keys.
+#![cfg(feature = "payload-cbor")]
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::BTreeMap;
+use std::fmt::Debug;
+use std::hash::{Hash, Hasher};
+
+use mcumgr_smp::application_management::{
+    EraseImagePayload, EraseImageResult, GetImageStateError, GetImageStatePayload,
+    GetImageStateResult, GetStatePayload, ImageState, SetStatePayload, SplitStatus,
+    WriteImageChunkError, WriteImageChunkPayload, WriteImageChunkResult,
+};
+use mcumgr_smp::fs_management::{
+    FileCloseRequest, FileCloseResult, FileDownloadRequest, FileDownloadResult, FileHashRequest,
+    FileHashResult, FileStatusRequest, FileStatusResult, FileUploadResult, HashOutput, HashType,
+    SupportedHashesRequest, SupportedHashesResult,
+};
+use mcumgr_smp::log_management::{
+    ClearRequest, ClearResult, Log, LogEntry, LogMessage, ModuleListRequest, ModuleListResult,
+    ShowRequest, ShowResult,
+};
+use mcumgr_smp::os_management::{
+    BootloaderInfoRequest, BootloaderInfoResult, EchoRequest, EchoResult, GetDateTimeRequest,
+    GetDateTimeResult, GetInfoRequest, GetInfoResult, McumgrParamsRequest, McumgrParamsResult,
+    ResetRequest, ResetResult, SetDateTimeRequest, SetDateTimeResult, TaskStats, TaskStatsRequest,
+    TaskStatsResult,
+};
+use mcumgr_smp::setting_management::{
+    ReadSettingRequest, ReadSettingResult, SaveSettingRequest, SaveSettingResult,
+    WriteSettingRequest, WriteSettingResult,
+};
+use mcumgr_smp::shell_management::{ShellCommand, ShellResult};
+use mcumgr_smp::stat_management::{
+    GroupDataRequest, GroupDataResult, ListGroupsRequest, ListGroupsResult,
+};
+use mcumgr_smp::{CborValue, Group, OpCode, SmpFrame};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+/// Send `value` in a frame and decode it again, the result has to be equal, and so does a clone
+fn round_trip<T>(value: T) -> T
+where
+    T: Serialize + DeserializeOwned + Clone + PartialEq + Debug,
+{
+    let frame = SmpFrame::new(OpCode::ReadResponse, 3, Group::Default, 0, value.clone());
+    let decoded = SmpFrame::<T>::decode_with_cbor(&frame.encode_with_cbor())
+        .unwrap()
+        .data;
+    assert_eq!(decoded, value);
+    assert_eq!(decoded.clone(), decoded);
+    decoded
+}
+
+fn hash<T: Hash>(value: &T) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Like [round_trip], and the decoded value hashes like the original
+fn round_trip_hashed<T>(value: T)
+where
+    T: Serialize + DeserializeOwned + Clone + Eq + Hash + Debug,
+{
+    let expected = hash(&value);
+    assert_eq!(hash(&round_trip(value)), expected);
+}
+
+fn extra() -> BTreeMap<String, CborValue> {
+    [
+        ("vendor".to_string(), CborValue::Text("x".to_string())),
+        ("count".to_string(), CborValue::Integer(3.into())),
+    ]
+    .into()
+}
+
+#[test]
+fn application_management() {
+    let image = ImageState {
+        image: Some(1),
+        slot: 0,
+        version: "1.2.3+4".to_string(),
+        hash: (0..32).collect(),
+        bootable: true,
+        pending: false,
+        confirmed: true,
+        active: true,
+        permanent: false,
+        extra: extra(),
+    };
+    round_trip(GetImageStateResult::Ok(GetImageStatePayload {
+        images: vec![
+            image.clone(),
+            ImageState {
+                image: None,
+                slot: 1,
+                extra: Default::default(),
+                ..image
+            },
+        ],
+        split_status: Some(SplitStatus::Other(7)),
+        extra: extra(),
+    }));
+    round_trip(GetImageStateResult::Err(GetImageStateError {
+        rc: 5,
+        rsn: Some("no image".to_string()),
+    }));
+    round_trip_hashed(SplitStatus::Matching);
+    round_trip_hashed(GetStatePayload {});
+    round_trip_hashed(SetStatePayload {
+        hash: Some(vec![0xaa; 32]),
+        confirm: false,
+    });
+    round_trip_hashed(SetStatePayload {
+        hash: None,
+        confirm: true,
+    });
+    round_trip_hashed(EraseImagePayload { slot: Some(1) });
+    round_trip_hashed(EraseImageResult::Ok {});
+    round_trip_hashed(EraseImageResult::Err {
+        rc: 6,
+        rsn: Some("busy".to_string()),
+    });
+    round_trip_hashed(WriteImageChunkResult::Ok(WriteImageChunkPayload {
+        off: 4096,
+        match_: Some(true),
+    }));
+    round_trip_hashed(WriteImageChunkResult::Err(WriteImageChunkError {
+        rc: 3,
+        rsn: None,
+        off: Some(512),
+        match_: None,
+    }));
+}
+
+#[test]
+fn fs_management() {
+    round_trip_hashed(FileDownloadRequest {
+        off: 128,
+        name: "/lfs/log.txt".to_string(),
+    });
+    round_trip_hashed(FileDownloadResult::Ok {
+        off: 0,
+        data: vec![0, 0xff, b'\n'],
+        len: Some(3),
+    });
+    round_trip_hashed(FileDownloadResult::Err { rc: 5 });
+    round_trip_hashed(FileUploadResult::Ok { off: 3 });
+    round_trip_hashed(FileUploadResult::Err { rc: 2 });
+    round_trip_hashed(FileStatusRequest {
+        name: "/lfs/a".to_string(),
+    });
+    round_trip_hashed(FileStatusResult::Ok { len: 1 << 40 });
+    round_trip_hashed(FileHashRequest {
+        name: "/lfs/a".to_string(),
+        type_: Some("sha256".to_string()),
+        off: Some(0),
+        len: None,
+    });
+    round_trip_hashed(FileHashResult::Ok {
+        type_: "sha256".to_string(),
+        off: Some(0),
+        len: 3,
+        output: HashOutput::Hash(vec![0x5a; 32]),
+    });
+    round_trip_hashed(FileHashResult::Ok {
+        type_: "crc32".to_string(),
+        off: None,
+        len: 3,
+        output: HashOutput::Checksum(0xcbf43926),
+    });
+    round_trip_hashed(SupportedHashesRequest {});
+    round_trip_hashed(SupportedHashesResult::Ok {
+        types: [
+            ("crc32".to_string(), HashType { format: 0, size: 4 }),
+            (
+                "sha256".to_string(),
+                HashType {
+                    format: 1,
+                    size: 32,
+                },
+            ),
+        ]
+        .into(),
+    });
+    round_trip_hashed(FileCloseRequest {});
+    round_trip_hashed(FileCloseResult::Ok {});
+    round_trip_hashed(FileCloseResult::Err { rc: 8 });
+}
+
+#[test]
+fn log_management() {
+    round_trip_hashed(ShowRequest {
+        log_name: Some("reboot".to_string()),
+        index: 7,
+    });
+    round_trip_hashed(ShowResult::Ok {
+        next_index: 9,
+        logs: vec![Log {
+            name: "reboot".to_string(),
+            type_: Some(1),
+            entries: vec![
+                LogEntry {
+                    msg: LogMessage::Text("booted".to_string()),
+                    ts: -1,
+                    level: 1,
+                    index: 7,
+                    module: 0,
+                },
+                LogEntry {
+                    msg: LogMessage::Binary(vec![0, 1, 2]),
+                    ts: 1_700_000_000_000,
+                    level: 3,
+                    index: 8,
+                    module: 4,
+                },
+            ],
+        }],
+    });
+    round_trip_hashed(ShowResult::Err { rc: 8 });
+    round_trip_hashed(ClearRequest {});
+    round_trip_hashed(ClearResult::Ok {});
+    round_trip_hashed(ModuleListRequest {});
+    round_trip_hashed(ModuleListResult::Ok {
+        module_map: [("DEFAULT".to_string(), 0), ("OS".to_string(), 1)].into(),
+    });
+}
+
+#[test]
+fn os_management() {
+    round_trip_hashed(EchoRequest {
+        d: "hello".to_string(),
+    });
+    round_trip_hashed(EchoResult::Ok {
+        r: "hello".to_string(),
+    });
+    round_trip_hashed(EchoResult::Err { rc: 7 });
+    round_trip_hashed(TaskStatsRequest {});
+    round_trip_hashed(TaskStatsResult::Ok {
+        tasks: [
+            (
+                "idle".to_string(),
+                TaskStats {
+                    prio: Some(15),
+                    tid: Some(1),
+                    stkuse: Some(64),
+                    stksiz: Some(320),
+                    ..Default::default()
+                },
+            ),
+            ("main".to_string(), TaskStats::default()),
+        ]
+        .into(),
+    });
+    round_trip_hashed(GetDateTimeRequest {});
+    round_trip_hashed(GetDateTimeResult::Ok {
+        datetime: "2024-01-02T03:04:05".to_string(),
+    });
+    round_trip_hashed(SetDateTimeRequest {
+        datetime: "2024-01-02T03:04:05".to_string(),
+    });
+    round_trip_hashed(SetDateTimeResult::Err { rc: 3 });
+    round_trip_hashed(GetInfoRequest {
+        format: "a".to_string(),
+    });
+    round_trip(GetInfoResult::Ok {
+        output: "Zephyr unknown 3.7.0".to_string(),
+        extra: extra(),
+    });
+    round_trip(GetInfoResult::Err { rc: 8 });
+    round_trip_hashed(BootloaderInfoRequest {
+        query: Some("mode".to_string()),
+    });
+    round_trip(BootloaderInfoResult::Ok {
+        bootloader: Some("MCUboot".to_string()),
+        mode: Some(2),
+        no_downgrade: Some(false),
+        extra: extra(),
+    });
+    round_trip_hashed(McumgrParamsRequest {});
+    round_trip_hashed(McumgrParamsResult::Ok {
+        buf_size: 2475,
+        buf_count: 4,
+    });
+    round_trip_hashed(ResetRequest { force: 1 });
+    round_trip_hashed(ResetResult::Ok {});
+}
+
+#[test]
+fn setting_management() {
+    round_trip_hashed(ReadSettingRequest {
+        name: "app/name".to_string(),
+    });
+    round_trip_hashed(ReadSettingResult::Ok {
+        val: vec![0, b'a', 0xff],
+    });
+    round_trip_hashed(ReadSettingResult::Err { rc: 5 });
+    round_trip_hashed(WriteSettingRequest {
+        name: "app/name".to_string(),
+        val: vec![],
+    });
+    round_trip_hashed(WriteSettingResult::Ok {});
+    round_trip_hashed(SaveSettingRequest {});
+    round_trip_hashed(SaveSettingResult::Err { rc: 1 });
+}
+
+#[test]
+fn shell_and_stat_management() {
+    round_trip_hashed(ShellCommand {
+        argv: vec!["kernel".to_string(), "uptime".to_string()],
+    });
+    round_trip_hashed(ShellResult::Ok {
+        o: "Uptime: 1234 ms".to_string(),
+        ret: Some(0),
+    });
+    round_trip_hashed(ShellResult::Ok {
+        o: String::new(),
+        ret: None,
+    });
+    round_trip_hashed(ShellResult::Err { rc: 8 });
+    round_trip_hashed(GroupDataRequest {
+        name: "smp_svr_stats".to_string(),
+    });
+    round_trip_hashed(GroupDataResult::Ok {
+        name: "smp_svr_stats".to_string(),
+        fields: [("ticks".to_string(), u64::MAX)].into(),
+    });
+    round_trip_hashed(ListGroupsRequest {});
+    round_trip_hashed(ListGroupsResult::Ok {
+        stat_list: vec!["smp_svr_stats".to_string()],
+    });
+}
@@ -0,0 +1,47 @@
+#include <stdio.h>
+#include "smp_ffi.h"
+
+static void on_progress(size_t sent, size_t total, void *user_data) {
+    (void)user_data;
+    printf("\r%zu/%zu", sent, total);
+}
+
+int main(void) {
+    SmpClient *client = NULL;
+    if (smp_client_new_udp("192.0.2.1", 1337, 2000, &client) != SMP_OK) {
+        fprintf(stderr, "error: %s\n", smp_last_error_message());
+        return 1;
+    }
+
+    char *response = NULL;
+    if (smp_echo(client, "hello", &response) == SMP_OK) {
+        printf("%s\n", response);
+        smp_string_free(response);
+    }
+
+    uint8_t *value = NULL;
+    size_t len = 0;
+    SmpStatus status = smp_setting_read(client, "app/name", &value, &len);
+    if (status == SMP_OK) {
+        printf("%.*s\n", (int)len, (const char *)value);
+        smp_buffer_free(value, len);
+    } else if (status == SMP_ERR_DEVICE) {
+        fprintf(stderr, "error: %s (rc %d)\n", smp_last_error_message(), smp_last_device_rc());
+    }
+
+    static const uint8_t image[] = {0x3d, 0xb8, 0xf3, 0x96};
+    smp_image_upload(client, image, sizeof(image), -1, 256, on_progress, NULL);
+
+    SmpImageState *images = NULL;
+    size_t count = 0;
+    if (smp_image_state(client, &images, &count) == SMP_OK) {
+        for (size_t i = 0; i < count; i++) {
+            printf("slot %d: %s%s\n", images[i].slot, images[i].version,
+                   images[i].active ? " (active)" : "");
+        }
+        smp_image_state_free(images, count);
+    }
+
+    smp_client_free(client);
+    return 0;
+}
@@ -0,0 +1,162 @@
+#ifndef SMP_FFI_H
+#define SMP_FFI_H
+
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+
+// Longest version string of [SmpImageState], including the terminating NUL
+#define SMP_VERSION_LEN 48
+
+// Longest image hash of [SmpImageState]
+#define SMP_HASH_LEN 64
+
+// Result of every call, details are available from [smp_last_error_message]
+typedef enum SmpStatus {
+  SMP_OK = 0,
+  // a null pointer or a string that isn't UTF-8
+  SMP_ERR_INVALID_ARGUMENT = 1,
+  // the connection failed
+  SMP_ERR_TRANSPORT = 2,
+  // the device didn't respond in time
+  SMP_ERR_TIMEOUT = 3,
+  // the device returned an error, its rc is available from [smp_last_device_rc]
+  SMP_ERR_DEVICE = 4,
+  // a bug in the library, the call panicked
+  SMP_ERR_INTERNAL = 5,
+} SmpStatus;
+
+// A connection to a device, opaque to C
+typedef struct SmpClient SmpClient;
+
+// An image on the device, as returned by [smp_image_state]
+typedef struct SmpImageState {
+  // the image number, -1 if the device doesn't report it
+  int32_t image;
+  int32_t slot;
+  // NUL-terminated, cut off if longer
+  char version[SMP_VERSION_LEN];
+  uint8_t hash[SMP_HASH_LEN];
+  size_t hash_len;
+  bool bootable;
+  bool pending;
+  bool confirmed;
+  bool active;
+  bool permanent;
+} SmpImageState;
+
+// Called by [smp_image_upload] with the bytes sent and the total after every chunk
+typedef void (*SmpProgressCallback)(size_t sent, size_t total, void *user_data);
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+// Connect to a device on a serial port. On success `*out` is a client to be freed with
+// [smp_client_free].
+//
+// # Safety
+// `device` is a NUL-terminated string and `out` points to writable memory.
+SmpStatus smp_client_new_serial(const char *device,
+                                uint32_t baud_rate,
+                                uint32_t timeout_ms,
+                                SmpClient **out);
+
+// Connect to a device over UDP. On success `*out` is a client to be freed with
+// [smp_client_free].
+//
+// # Safety
+// `host` is a NUL-terminated string and `out` points to writable memory.
+SmpStatus smp_client_new_udp(const char *host, uint16_t port, uint32_t timeout_ms, SmpClient **out);
+
+// Close the connection and free the client, NULL is ignored
+//
+// # Safety
+// `client` was returned by `smp_client_new_*` and isn't used afterwards.
+void smp_client_free(SmpClient *client);
+
+// Send a message that the device returns. On success `*out_response` is the returned
+// message, to be freed with [smp_string_free].
+//
+// # Safety
+// `client` is a valid client, `message` a NUL-terminated string and `out_response` points to
+// writable memory.
+SmpStatus smp_echo(SmpClient *client, const char *message, char **out_response);
+
+// Read the value of a setting. On success `*out_value` and `*out_len` are the value, to be
+// freed with [smp_buffer_free].
+//
+// # Safety
+// `client` is a valid client, `name` a NUL-terminated string and `out_value` and `out_len`
+// point to writable memory.
+SmpStatus smp_setting_read(SmpClient *client,
+                           const char *name,
+                           uint8_t **out_value,
+                           size_t *out_len);
+
+// Write the value of a setting, it is applied but not saved
+//
+// # Safety
+// `client` is a valid client, `name` a NUL-terminated string and `value` points to `len`
+// bytes.
+SmpStatus smp_setting_write(SmpClient *client,
+                            const char *name,
+                            const uint8_t *value,
+                            size_t len);
+
+// Save the settings to persistent storage
+//
+// # Safety
+// `client` is a valid client.
+SmpStatus smp_settings_save(SmpClient *client);
+
+// Read the images on the device. On success `*out_images` and `*out_count` are an array of
+// them, to be freed with [smp_image_state_free].
+//
+// # Safety
+// `client` is a valid client and `out_images` and `out_count` point to writable memory.
+SmpStatus smp_image_state(SmpClient *client, SmpImageState **out_images, size_t *out_count);
+
+// Upload an image. `image` is the image number, or -1 for the device's default.
+// `progress` may be NULL, otherwise it is called with `user_data` after every chunk.
+//
+// # Safety
+// `client` is a valid client and `data` points to `len` bytes.
+SmpStatus smp_image_upload(SmpClient *client,
+                           const uint8_t *data,
+                           size_t len,
+                           int32_t image,
+                           size_t chunk_size,
+                           SmpProgressCallback progress,
+                           void *user_data);
+
+// The message of the last failed call on this thread, NULL if there was none. It is owned
+// by the library and valid until the next call on this thread.
+const char *smp_last_error_message(void);
+
+// The rc of the last call on this thread that failed with `SMP_ERR_DEVICE`
+int32_t smp_last_device_rc(void);
+
+// Free a string returned by the library, NULL is ignored
+//
+// # Safety
+// `s` was returned by the library and isn't used afterwards.
+void smp_string_free(char *s);
+
+// Free a buffer returned by the library together with its length, NULL is ignored
+//
+// # Safety
+// `data` and `len` were returned by the library and `data` isn't used afterwards.
+void smp_buffer_free(uint8_t *data, size_t len);
+
+// Free an image state array returned by [smp_image_state], NULL is ignored
+//
+// # Safety
+// `images` and `count` were returned by [smp_image_state] and `images` isn't used afterwards.
+void smp_image_state_free(SmpImageState *images, size_t count);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* SMP_FFI_H */
@@ -0,0 +1,540 @@
+//! A C interface to the SMP client, for host applications that can't use the Rust library.
+//!
+//! The header `include/smp_ffi.h` is generated with cbindgen, see `cbindgen.toml`.
+//!
+//! # Ownership
+//! * a client is created by `smp_client_new_*` and freed by [smp_client_free]
+//! * strings, buffers and image state arrays returned by the library are owned by the caller
+//!   and freed with [smp_string_free], [smp_buffer_free] and [smp_image_state_free]
+//! * arguments are borrowed for the duration of the call only
+//! * the message of [smp_last_error_message] is owned by the library
+
+use std::cell::{Cell, RefCell};
+use std::ffi::{c_char, c_void, CStr, CString};
+use std::io::ErrorKind;
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+use std::time::Duration;
+
+use mcumgr_smp::application_management::{
+    self, GetImageStateResult, ImageState, ImageWriter, UploadAction, WriteImageChunkResult,
+};
+use mcumgr_smp::os_management::{self, EchoResult};
+use mcumgr_smp::setting_management::{
+    self, ReadSettingResult, SaveSettingResult, WriteSettingResult,
+};
+use mcumgr_smp::transport::error::Error;
+use mcumgr_smp::transport::serial::SerialTransport;
+use mcumgr_smp::transport::smp::{CborSmpTransport, SmpTransport};
+use mcumgr_smp::transport::udp::UdpTransport;
+use mcumgr_smp::{ReturnCode, SmpFrame};
+
+/// Result of every call, details are available from [smp_last_error_message]
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SmpStatus {
+    SmpOk = 0,
+    /// a null pointer or a string that isn't UTF-8
+    SmpErrInvalidArgument = 1,
+    /// the connection failed
+    SmpErrTransport = 2,
+    /// the device didn't respond in time
+    SmpErrTimeout = 3,
+    /// the device returned an error, its rc is available from [smp_last_device_rc]
+    SmpErrDevice = 4,
+    /// a bug in the library, the call panicked
+    SmpErrInternal = 5,
+}
+
+/// Longest version string of [SmpImageState], including the terminating NUL
+pub const SMP_VERSION_LEN: usize = 48;
+/// Longest image hash of [SmpImageState]
+pub const SMP_HASH_LEN: usize = 64;
+
+/// An image on the device, as returned by [smp_image_state]
+#[repr(C)]
+pub struct SmpImageState {
+    /// the image number, -1 if the device doesn't report it
+    pub image: i32,
+    pub slot: i32,
+    /// NUL-terminated, cut off if longer
+    pub version: [c_char; SMP_VERSION_LEN],
+    pub hash: [u8; SMP_HASH_LEN],
+    pub hash_len: usize,
+    pub bootable: bool,
+    pub pending: bool,
+    pub confirmed: bool,
+    pub active: bool,
+    pub permanent: bool,
+}
+
+/// Called by [smp_image_upload] with the bytes sent and the total after every chunk
+pub type SmpProgressCallback =
+    Option<unsafe extern "C" fn(sent: usize, total: usize, user_data: *mut c_void)>;
+
+/// A connection to a device, opaque to C
+pub struct SmpClient {
+    transport: CborSmpTransport,
+    sequence: u8,
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+    static LAST_RC: Cell<i32> = const { Cell::new(0) };
+}
+
+/// Remember the message of a failed call for [smp_last_error_message]
+fn fail(status: SmpStatus, msg: impl Into<String>) -> SmpStatus {
+    let msg = CString::new(msg.into().replace('\0', " ")).expect("NUL bytes were replaced");
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(msg));
+    status
+}
+
+fn transport_error(err: Error) -> SmpStatus {
+    match &err {
+        Error::Io(e) if matches!(e.kind(), ErrorKind::TimedOut | ErrorKind::WouldBlock) => {
+            fail(SmpStatus::SmpErrTimeout, err.to_string())
+        }
+        _ => fail(SmpStatus::SmpErrTransport, err.to_string()),
+    }
+}
+
+fn device_error(rc: i32) -> SmpStatus {
+    LAST_RC.with(|last| last.set(rc));
+    let msg = match ReturnCode::try_from(rc) {
+        Ok(code) => format!("{} (rc={}) — {}", code.short_name(), rc, code.description()),
+        Err(_) => format!("device returned rc: {}", rc),
+    };
+    fail(SmpStatus::SmpErrDevice, msg)
+}
+
+/// Borrow a C string argument
+unsafe fn str_arg<'a>(s: *const c_char, what: &str) -> Result<&'a str, SmpStatus> {
+    if s.is_null() {
+        return Err(fail(
+            SmpStatus::SmpErrInvalidArgument,
+            format!("{} is NULL", what),
+        ));
+    }
+    CStr::from_ptr(s).to_str().map_err(|_| {
+        fail(
+            SmpStatus::SmpErrInvalidArgument,
+            format!("{} is not UTF-8", what),
+        )
+    })
+}
+
+/// Borrow the client argument
+unsafe fn client_arg<'a>(client: *mut SmpClient) -> Result<&'a mut SmpClient, SmpStatus> {
+    client
+        .as_mut()
+        .ok_or_else(|| fail(SmpStatus::SmpErrInvalidArgument, "client is NULL"))
+}
+
+/// Hand a buffer over to C, to be freed with [smp_buffer_free]
+unsafe fn into_buffer(data: Vec<u8>, out: *mut *mut u8, out_len: *mut usize) {
+    let data = Box::into_raw(data.into_boxed_slice());
+    *out_len = data.len();
+    *out = data as *mut u8;
+}
+
+/// Run the body of a call and collect its result into the status. A panic must not unwind
+/// into C, it fails the call with [SmpStatus::SmpErrInternal] instead.
+fn call(body: impl FnOnce() -> Result<(), SmpStatus>) -> SmpStatus {
+    match panic::catch_unwind(AssertUnwindSafe(body)) {
+        Ok(Ok(())) => SmpStatus::SmpOk,
+        Ok(Err(status)) => status,
+        Err(panic) => {
+            let msg = match panic.downcast_ref::<&str>() {
+                Some(msg) => msg,
+                None => panic.downcast_ref::<String>().map_or("", String::as_str),
+            };
+            fail(
+                SmpStatus::SmpErrInternal,
+                format!("internal error: {}", msg),
+            )
+        }
+    }
+}
+
+impl SmpClient {
+    fn new(transport: Box<dyn SmpTransport>) -> Self {
+        Self {
+            transport: CborSmpTransport::new(transport),
+            sequence: 0,
+        }
+    }
+
+    fn next_sequence(&mut self) -> u8 {
+        self.sequence = self.sequence.wrapping_add(1);
+        self.sequence
+    }
+
+    fn transceive<Req: serde::Serialize, Resp: serde::de::DeserializeOwned>(
+        &mut self,
+        frame: &SmpFrame<Req>,
+    ) -> Result<Resp, SmpStatus> {
+        let ret: SmpFrame<Resp> = self
+            .transport
+            .transceive_cbor(frame, true)
+            .map_err(transport_error)?;
+        Ok(ret.data)
+    }
+}
+
+impl From<&ImageState> for SmpImageState {
+    fn from(image: &ImageState) -> Self {
+        let mut state = SmpImageState {
+            image: image.image.unwrap_or(-1),
+            slot: image.slot,
+            version: [0; SMP_VERSION_LEN],
+            hash: [0; SMP_HASH_LEN],
+            hash_len: image.hash.len().min(SMP_HASH_LEN),
+            bootable: image.bootable,
+            pending: image.pending,
+            confirmed: image.confirmed,
+            active: image.active,
+            permanent: image.permanent,
+        };
+        // the last byte stays NUL
+        for (dst, src) in state.version[..SMP_VERSION_LEN - 1]
+            .iter_mut()
+            .zip(image.version.bytes())
+        {
+            *dst = src as c_char;
+        }
+        state.hash[..state.hash_len].copy_from_slice(&image.hash[..state.hash_len]);
+        state
+    }
+}
+
+/// Connect to a device on a serial port. On success `*out` is a client to be freed with
+/// [smp_client_free].
+///
+/// # Safety
+/// `device` is a NUL-terminated string and `out` points to writable memory.
+#[no_mangle]
+pub unsafe extern "C" fn smp_client_new_serial(
+    device: *const c_char,
+    baud_rate: u32,
+    timeout_ms: u32,
+    out: *mut *mut SmpClient,
+) -> SmpStatus {
+    call(|| {
+        let device = str_arg(device, "device")?;
+        let mut transport = SerialTransport::new(device.to_string(), baud_rate)
+            .map_err(|e| fail(SmpStatus::SmpErrTransport, e.to_string()))?;
+        transport
+            .recv_timeout(Some(Duration::from_millis(timeout_ms.into())))
+            .map_err(transport_error)?;
+        *out = Box::into_raw(Box::new(SmpClient::new(Box::new(transport))));
+        Ok(())
+    })
+}
+
+/// Connect to a device over UDP. On success `*out` is a client to be freed with
+/// [smp_client_free].
+///
+/// # Safety
+/// `host` is a NUL-terminated string and `out` points to writable memory.
+#[no_mangle]
+pub unsafe extern "C" fn smp_client_new_udp(
+    host: *const c_char,
+    port: u16,
+    timeout_ms: u32,
+    out: *mut *mut SmpClient,
+) -> SmpStatus {
+    call(|| {
+        let host = str_arg(host, "host")?;
+        let mut transport = UdpTransport::new((host, port)).map_err(|e| {
+            fail(
+                SmpStatus::SmpErrTransport,
+                format!("can't connect to {}: {}", host, e),
+            )
+        })?;
+        transport
+            .recv_timeout(Some(Duration::from_millis(timeout_ms.into())))
+            .map_err(transport_error)?;
+        *out = Box::into_raw(Box::new(SmpClient::new(Box::new(transport))));
+        Ok(())
+    })
+}
+
+/// Close the connection and free the client, NULL is ignored
+///
+/// # Safety
+/// `client` was returned by `smp_client_new_*` and isn't used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn smp_client_free(client: *mut SmpClient) {
+    if !client.is_null() {
+        drop(Box::from_raw(client));
+    }
+}
+
+/// Send a message that the device returns. On success `*out_response` is the returned
+/// message, to be freed with [smp_string_free].
+///
+/// # Safety
+/// `client` is a valid client, `message` a NUL-terminated string and `out_response` points to
+/// writable memory.
+#[no_mangle]
+pub unsafe extern "C" fn smp_echo(
+    client: *mut SmpClient,
+    message: *const c_char,
+    out_response: *mut *mut c_char,
+) -> SmpStatus {
+    call(|| {
+        let client = client_arg(client)?;
+        let message = str_arg(message, "message")?;
+        let sequence = client.next_sequence();
+        match client.transceive(&os_management::echo(sequence, message.to_string()))? {
+            EchoResult::Ok { r } => {
+                let r = CString::new(r.replace('\0', " ")).expect("NUL bytes were replaced");
+                *out_response = r.into_raw();
+                Ok(())
+            }
+            EchoResult::Err { rc } => Err(device_error(rc)),
+        }
+    })
+}
+
+/// Read the value of a setting. On success `*out_value` and `*out_len` are the value, to be
+/// freed with [smp_buffer_free].
+///
+/// # Safety
+/// `client` is a valid client, `name` a NUL-terminated string and `out_value` and `out_len`
+/// point to writable memory.
+#[no_mangle]
+pub unsafe extern "C" fn smp_setting_read(
+    client: *mut SmpClient,
+    name: *const c_char,
+    out_value: *mut *mut u8,
+    out_len: *mut usize,
+) -> SmpStatus {
+    call(|| {
+        let client = client_arg(client)?;
+        let name = str_arg(name, "name")?;
+        let sequence = client.next_sequence();
+        match client.transceive(&setting_management::read_setting(
+            sequence,
+            name.to_string(),
+        ))? {
+            ReadSettingResult::Ok { val } => {
+                into_buffer(val, out_value, out_len);
+                Ok(())
+            }
+            ReadSettingResult::Err { rc } => Err(device_error(rc)),
+        }
+    })
+}
+
+/// Write the value of a setting, it is applied but not saved
+///
+/// # Safety
+/// `client` is a valid client, `name` a NUL-terminated string and `value` points to `len`
+/// bytes.
+#[no_mangle]
+pub unsafe extern "C" fn smp_setting_write(
+    client: *mut SmpClient,
+    name: *const c_char,
+    value: *const u8,
+    len: usize,
+) -> SmpStatus {
+    call(|| {
+        let client = client_arg(client)?;
+        let name = str_arg(name, "name")?;
+        if value.is_null() && len > 0 {
+            return Err(fail(SmpStatus::SmpErrInvalidArgument, "value is NULL"));
+        }
+        let value = if len == 0 {
+            &[][..]
+        } else {
+            std::slice::from_raw_parts(value, len)
+        };
+        let sequence = client.next_sequence();
+        let frame = setting_management::write_setting(sequence, name.to_string(), value);
+        match client.transceive(&frame)? {
+            WriteSettingResult::Ok {} => Ok(()),
+            WriteSettingResult::Err { rc } => Err(device_error(rc)),
+        }
+    })
+}
+
+/// Save the settings to persistent storage
+///
+/// # Safety
+/// `client` is a valid client.
+#[no_mangle]
+pub unsafe extern "C" fn smp_settings_save(client: *mut SmpClient) -> SmpStatus {
+    call(|| {
+        let client = client_arg(client)?;
+        let sequence = client.next_sequence();
+        match client.transceive(&setting_management::save_setting(sequence))? {
+            SaveSettingResult::Ok {} => Ok(()),
+            SaveSettingResult::Err { rc } => Err(device_error(rc)),
+        }
+    })
+}
+
+/// Read the images on the device. On success `*out_images` and `*out_count` are an array of
+/// them, to be freed with [smp_image_state_free].
+///
+/// # Safety
+/// `client` is a valid client and `out_images` and `out_count` point to writable memory.
+#[no_mangle]
+pub unsafe extern "C" fn smp_image_state(
+    client: *mut SmpClient,
+    out_images: *mut *mut SmpImageState,
+    out_count: *mut usize,
+) -> SmpStatus {
+    call(|| {
+        let client = client_arg(client)?;
+        let sequence = client.next_sequence();
+        let state = match client.transceive(&application_management::get_state(sequence))? {
+            GetImageStateResult::Ok(state) => state,
+            GetImageStateResult::Err(err) => return Err(device_error(err.rc)),
+        };
+
+        let images: Box<[SmpImageState]> = state.images.iter().map(Into::into).collect();
+        *out_count = images.len();
+        *out_images = Box::into_raw(images) as *mut SmpImageState;
+        Ok(())
+    })
+}
+
+/// Upload an image. `image` is the image number, or -1 for the device's default.
+/// `progress` may be NULL, otherwise it is called with `user_data` after every chunk.
+///
+/// # Safety
+/// `client` is a valid client and `data` points to `len` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn smp_image_upload(
+    client: *mut SmpClient,
+    data: *const u8,
+    len: usize,
+    image: i32,
+    chunk_size: usize,
+    progress: SmpProgressCallback,
+    user_data: *mut c_void,
+) -> SmpStatus {
+    call(|| {
+        let client = client_arg(client)?;
+        if data.is_null() || len == 0 {
+            return Err(fail(SmpStatus::SmpErrInvalidArgument, "no image data"));
+        }
+        if chunk_size == 0 {
+            return Err(fail(SmpStatus::SmpErrInvalidArgument, "chunk_size is 0"));
+        }
+        let image = match image {
+            -1 => None,
+            image => Some(u8::try_from(image).map_err(|_| {
+                fail(
+                    SmpStatus::SmpErrInvalidArgument,
+                    format!("invalid image number {}", image),
+                )
+            })?),
+        };
+        let data = std::slice::from_raw_parts(data, len);
+
+        let mut writer = ImageWriter::new(image, data.len(), None, false);
+        let mut request_buf = Vec::new();
+        while writer.offset < data.len() {
+            let offset = writer.offset;
+            let end = data.len().min(offset + chunk_size);
+            let sequence = client.next_sequence();
+            let mut frame = writer.write_chunk(&data[offset..end]);
+            frame.sequence = sequence;
+
+            let ret: SmpFrame<WriteImageChunkResult> = client
+                .transport
+                .transceive_cbor_with(&frame, true, &mut request_buf)
+                .map_err(transport_error)?;
+            if let WriteImageChunkResult::Err(err) = &ret.data {
+                return Err(device_error(err.rc));
+            }
+            if let UploadAction::InvalidOffset { off, sent } = writer.handle_response(&ret.data) {
+                return Err(fail(
+                    SmpStatus::SmpErrDevice,
+                    format!(
+                        "the device reported offset {} beyond the {} bytes sent",
+                        off, sent
+                    ),
+                ));
+            }
+            if writer.offset <= offset {
+                return Err(fail(
+                    SmpStatus::SmpErrDevice,
+                    format!("the device didn't accept the chunk at offset {}", offset),
+                ));
+            }
+
+            if let Some(progress) = progress {
+                progress(writer.offset, data.len(), user_data);
+            }
+        }
+        Ok(())
+    })
+}
+
+/// The message of the last failed call on this thread, NULL if there was none. It is owned
+/// by the library and valid until the next call on this thread.
+#[no_mangle]
+pub extern "C" fn smp_last_error_message() -> *const c_char {
+    LAST_ERROR.with(|last| {
+        last.borrow()
+            .as_ref()
+            .map_or(ptr::null(), |msg| msg.as_ptr())
+    })
+}
+
+/// The rc of the last call on this thread that failed with `SMP_ERR_DEVICE`
+#[no_mangle]
+pub extern "C" fn smp_last_device_rc() -> i32 {
+    LAST_RC.with(Cell::get)
+}
+
+/// Free a string returned by the library, NULL is ignored
+///
+/// # Safety
+/// `s` was returned by the library and isn't used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn smp_string_free(s: *mut c_char) {
+    if !s.is_null() {
+        drop(CString::from_raw(s));
+    }
+}
+
+/// Free a buffer returned by the library together with its length, NULL is ignored
+///
+/// # Safety
+/// `data` and `len` were returned by the library and `data` isn't used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn smp_buffer_free(data: *mut u8, len: usize) {
+    if !data.is_null() {
+        drop(Box::from_raw(ptr::slice_from_raw_parts_mut(data, len)));
+    }
+}
+
+/// Free an image state array returned by [smp_image_state], NULL is ignored
+///
+/// # Safety
+/// `images` and `count` were returned by [smp_image_state] and `images` isn't used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn smp_image_state_free(images: *mut SmpImageState, count: usize) {
+    if !images.is_null() {
+        drop(Box::from_raw(ptr::slice_from_raw_parts_mut(images, count)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn panics_fail_the_call() {
+        let status = call(|| panic!("boom"));
+        assert_eq!(status, SmpStatus::SmpErrInternal);
+        let msg = unsafe { CStr::from_ptr(smp_last_error_message()) };
+        assert_eq!(msg.to_str().unwrap(), "internal error: boom");
+    }
+}
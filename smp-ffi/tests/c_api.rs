@@ -0,0 +1,168 @@
+// Builds tests/ownership.c against the shared library and runs it against a device on a UDP
+// port of localhost. `CC` selects another compiler than `cc`.
+#![cfg(unix)]
+
+use std::collections::HashMap;
+use std::env;
+use std::net::UdpSocket;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::thread;
+
+use mcumgr_smp::{CborValue, Group, OpCode, SmpFrame};
+
+const ENOENT: i32 = 5;
+
+fn field<'v>(payload: &'v CborValue, name: &str) -> &'v CborValue {
+    payload
+        .as_map()
+        .and_then(|map| map.iter().find(|(key, _)| key.as_text() == Some(name)))
+        .map(|(_, value)| value)
+        .unwrap_or_else(|| panic!("request without {}", name))
+}
+
+fn map(entries: Vec<(&str, CborValue)>) -> CborValue {
+    CborValue::Map(
+        entries
+            .into_iter()
+            .map(|(key, value)| (key.into(), value))
+            .collect(),
+    )
+}
+
+fn image(slot: i32, version: &str, hash: Vec<u8>, active: bool) -> CborValue {
+    map(vec![
+        ("slot", slot.into()),
+        ("version", version.into()),
+        ("hash", hash.into()),
+        ("bootable", true.into()),
+        ("active", active.into()),
+        ("confirmed", active.into()),
+    ])
+}
+
+/// Answers echo, settings, image state and upload, like Zephyr's smp_svr sample
+#[derive(Default)]
+struct Device {
+    settings: HashMap<String, Vec<u8>>,
+    image: Vec<u8>,
+}
+
+impl Device {
+    fn answer(&mut self, request: &SmpFrame<CborValue>) -> CborValue {
+        let write = request.operation == OpCode::WriteRequest;
+        let payload = &request.data;
+        match (request.group, request.command, write) {
+            (Group::Default, 0, true) => map(vec![("r", field(payload, "d").clone())]),
+            (Group::SettingManagement, 0, false) => {
+                let name = field(payload, "name").as_text().unwrap();
+                match self.settings.get(name) {
+                    Some(val) => map(vec![("val", val.clone().into())]),
+                    None => map(vec![("rc", ENOENT.into())]),
+                }
+            }
+            (Group::SettingManagement, 0, true) => {
+                let name = field(payload, "name").as_text().unwrap().to_string();
+                let val = field(payload, "val").as_bytes().unwrap().clone();
+                self.settings.insert(name, val);
+                map(vec![])
+            }
+            (Group::ApplicationManagement, 0, false) => {
+                let mut images = vec![image(0, "1.0.0", (0..32).collect(), true)];
+                if !self.image.is_empty() {
+                    images.push(image(1, "1.0.1", vec![0xaa; 32], false));
+                }
+                map(vec![("images", CborValue::Array(images))])
+            }
+            (Group::ApplicationManagement, 1, true) => {
+                let off = usize::try_from(field(payload, "off").as_integer().unwrap()).unwrap();
+                if off == 0 {
+                    self.image.clear();
+                }
+                if off == self.image.len() {
+                    let data = field(payload, "data").as_bytes().unwrap();
+                    self.image.extend_from_slice(data);
+                }
+                map(vec![("off", (self.image.len() as u64).into())])
+            }
+            other => panic!("unexpected request {:?}", other),
+        }
+    }
+
+    /// Serve requests on a port of localhost from a thread, until the test process exits
+    fn start() -> u16 {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let port = socket.local_addr().unwrap().port();
+        thread::spawn(move || {
+            let mut device = Device::default();
+            let mut buf = [0; 4096];
+            loop {
+                let (len, peer) = socket.recv_from(&mut buf).unwrap();
+                let request = SmpFrame::<CborValue>::decode_with_cbor(&buf[..len]).unwrap();
+                let response = SmpFrame::new(
+                    request.operation.response_of(),
+                    request.sequence,
+                    request.group,
+                    request.command,
+                    device.answer(&request),
+                );
+                socket.send_to(&response.encode_with_cbor(), peer).unwrap();
+            }
+        });
+        port
+    }
+}
+
+/// Build the library and return the directory it is in.
+///
+/// `cargo test` only builds the test harness, not the cdylib, so it is built by a nested cargo
+/// with a target directory of its own, which the cargo running the tests doesn't lock.
+fn build_library() -> PathBuf {
+    let target_dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("smp-ffi");
+    let status = Command::new(env::var("CARGO").unwrap_or_else(|_| "cargo".to_string()))
+        .args(["build", "--lib", "--manifest-path"])
+        .arg(Path::new(env!("CARGO_MANIFEST_DIR")).join("Cargo.toml"))
+        .arg("--target-dir")
+        .arg(&target_dir)
+        .status()
+        .expect("cargo can't be run");
+    assert!(status.success(), "building smp-ffi failed: {}", status);
+    target_dir.join("debug")
+}
+
+fn compile(source: &str, output: &Path, library_dir: &Path, sanitize: bool) -> bool {
+    let manifest_dir = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let compiler = env::var("CC").unwrap_or_else(|_| "cc".to_string());
+    let mut cc = Command::new(compiler);
+    cc.args(["-std=c99", "-Wall", "-Wextra", "-Werror", "-g"])
+        .arg("-I")
+        .arg(manifest_dir.join("include"))
+        .arg(manifest_dir.join(source))
+        .arg("-o")
+        .arg(output)
+        .arg("-L")
+        .arg(library_dir)
+        .arg("-lsmp_ffi");
+    if sanitize {
+        cc.arg("-fsanitize=address");
+    }
+    cc.status().expect("no C compiler").success()
+}
+
+#[test]
+fn c_program_follows_the_ownership_rules() {
+    let library_dir = build_library();
+    let exe = Path::new(env!("CARGO_TARGET_TMPDIR")).join("ownership");
+    // not every compiler ships AddressSanitizer
+    let sanitized = compile("tests/ownership.c", &exe, &library_dir, true);
+    assert!(sanitized || compile("tests/ownership.c", &exe, &library_dir, false));
+
+    let port = Device::start();
+    let status = Command::new(&exe)
+        .arg(port.to_string())
+        .env("LD_LIBRARY_PATH", &library_dir)
+        .env("DYLD_LIBRARY_PATH", &library_dir)
+        .status()
+        .unwrap();
+    assert!(status.success(), "{} failed: {}", exe.display(), status);
+}
@@ -0,0 +1,122 @@
+// Follows the ownership rules of the README against the device of c_api.rs:
+//
+//     ownership <port>
+//
+// Every check prints what failed and exits with 1. Built with AddressSanitizer, a string,
+// buffer or array the library hands over and that isn't freed here is reported as a leak.
+
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+#include "smp_ffi.h"
+
+#define CHECK(cond)                                                                    \
+    do {                                                                               \
+        if (!(cond)) {                                                                 \
+            const char *msg = smp_last_error_message();                                \
+            fprintf(stderr, "%s:%d: %s failed, last error: %s\n", __FILE__, __LINE__,  \
+                    #cond, msg ? msg : "none");                                        \
+            exit(1);                                                                   \
+        }                                                                              \
+    } while (0)
+
+struct progress {
+    size_t calls;
+    size_t sent;
+};
+
+static void on_progress(size_t sent, size_t total, void *user_data) {
+    struct progress *progress = user_data;
+    CHECK(sent > progress->sent && sent <= total);
+    progress->calls++;
+    progress->sent = sent;
+}
+
+// arguments are borrowed: the library doesn't keep them after the call
+static void echo(SmpClient *client) {
+    const char hello[] = "hello from C";
+    char *message = malloc(sizeof(hello));
+    CHECK(message != NULL);
+    memcpy(message, hello, sizeof(hello));
+    char *response = NULL;
+    CHECK(smp_echo(client, message, &response) == SMP_OK);
+    memset(message, 'x', strlen(message));
+    free(message);
+    CHECK(response != NULL && strcmp(response, "hello from C") == 0);
+    smp_string_free(response);
+}
+
+static void setting(SmpClient *client) {
+    static const uint8_t value[] = {'C', 0, 'C'};
+    CHECK(smp_setting_write(client, "app/name", value, sizeof(value)) == SMP_OK);
+
+    uint8_t *read = NULL;
+    size_t len = 0;
+    CHECK(smp_setting_read(client, "app/name", &read, &len) == SMP_OK);
+    CHECK(len == sizeof(value) && memcmp(read, value, len) == 0);
+    smp_buffer_free(read, len);
+}
+
+// the message of the last error belongs to the library and stays valid until the next call
+static void errors(SmpClient *client) {
+    uint8_t *read = NULL;
+    size_t len = 0;
+    CHECK(smp_setting_read(client, "no/such/setting", &read, &len) == SMP_ERR_DEVICE);
+    CHECK(read == NULL && len == 0);
+    CHECK(smp_last_device_rc() == 5);
+    const char *msg = smp_last_error_message();
+    CHECK(msg != NULL && strstr(msg, "rc=5") != NULL);
+
+    char *response = NULL;
+    CHECK(smp_echo(client, NULL, &response) == SMP_ERR_INVALID_ARGUMENT);
+    CHECK(response == NULL);
+    CHECK(strstr(smp_last_error_message(), "message is NULL") != NULL);
+    CHECK(smp_echo(NULL, "hello", &response) == SMP_ERR_INVALID_ARGUMENT);
+    CHECK(response == NULL);
+}
+
+static void upload_and_state(SmpClient *client) {
+    uint8_t image[1000];
+    for (size_t i = 0; i < sizeof(image); i++) {
+        image[i] = (uint8_t)i;
+    }
+    struct progress progress = {0, 0};
+    CHECK(smp_image_upload(client, image, sizeof(image), -1, 256, on_progress, &progress) ==
+          SMP_OK);
+    CHECK(progress.calls == 4 && progress.sent == sizeof(image));
+    CHECK(smp_image_upload(client, image, sizeof(image), 0, 256, NULL, NULL) == SMP_OK);
+
+    SmpImageState *images = NULL;
+    size_t count = 0;
+    CHECK(smp_image_state(client, &images, &count) == SMP_OK);
+    CHECK(count == 2);
+    CHECK(images[0].slot == 0 && images[0].active && strcmp(images[0].version, "1.0.0") == 0);
+    CHECK(images[0].hash_len == 32 && images[0].hash[31] == 31);
+    CHECK(images[1].slot == 1 && images[1].image == -1 && !images[1].active);
+    smp_image_state_free(images, count);
+}
+
+int main(int argc, char **argv) {
+    CHECK(argc == 2);
+    uint16_t port = (uint16_t)atoi(argv[1]);
+
+    SmpClient *client = NULL;
+    CHECK(smp_client_new_udp(NULL, port, 1000, &client) == SMP_ERR_INVALID_ARGUMENT);
+    CHECK(client == NULL);
+    CHECK(smp_client_new_udp("127.0.0.1", port, 1000, &client) == SMP_OK);
+    CHECK(client != NULL);
+
+    echo(client);
+    setting(client);
+    errors(client);
+    upload_and_state(client);
+
+    // freeing NULL is allowed
+    smp_string_free(NULL);
+    smp_buffer_free(NULL, 0);
+    smp_image_state_free(NULL, 0);
+    smp_client_free(NULL);
+
+    smp_client_free(client);
+    return 0;
+}
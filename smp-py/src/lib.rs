@@ -0,0 +1,288 @@
+//! Python bindings for the SMP client, built with [maturin](https://www.maturin.rs/).
+//!
+//! The operations are blocking. They release the GIL while waiting for the device, so other
+//! Python threads keep running.
+
+// the methods generated by pyo3 0.22 convert the `PyErr` of a `PyResult` into itself
+#![allow(clippy::useless_conversion)]
+
+use std::io::ErrorKind;
+use std::time::Duration;
+
+use mcumgr_smp::application_management::{
+    self, GetImageStateResult, ImageWriter, UploadAction, WriteImageChunkResult,
+};
+use mcumgr_smp::os_management::{self, EchoResult};
+use mcumgr_smp::setting_management::{
+    self, ReadSettingResult, SaveSettingResult, WriteSettingResult,
+};
+use mcumgr_smp::transport::error::Error;
+use mcumgr_smp::transport::serial::SerialTransport;
+use mcumgr_smp::transport::smp::{CborSmpTransport, SmpTransport};
+use mcumgr_smp::transport::udp::UdpTransport;
+use mcumgr_smp::{ReturnCode, SmpFrame};
+use pyo3::create_exception;
+use pyo3::exceptions::PyException;
+use pyo3::prelude::*;
+use pyo3::types::{PyBytes, PyDict, PyList};
+
+create_exception!(
+    smp_py,
+    SmpError,
+    PyException,
+    "Base class of the errors of this module"
+);
+create_exception!(
+    smp_py,
+    TransportError,
+    SmpError,
+    "The connection to the device failed or is closed"
+);
+create_exception!(
+    smp_py,
+    ResponseTimeout,
+    TransportError,
+    "The device didn't respond in time"
+);
+create_exception!(
+    smp_py,
+    DeviceError,
+    SmpError,
+    "The device returned an error, the message and the rc are its arguments"
+);
+
+fn transport_error(err: Error) -> PyErr {
+    match &err {
+        Error::Io(e) if matches!(e.kind(), ErrorKind::TimedOut | ErrorKind::WouldBlock) => {
+            ResponseTimeout::new_err(err.to_string())
+        }
+        _ => TransportError::new_err(err.to_string()),
+    }
+}
+
+fn device_error(rc: i32) -> PyErr {
+    let msg = match ReturnCode::try_from(rc) {
+        Ok(code) => format!("{} (rc={}) — {}", code.short_name(), rc, code.description()),
+        Err(_) => format!("device returned rc: {}", rc),
+    };
+    DeviceError::new_err((msg, rc))
+}
+
+/// A connection to a device, closed by `close()` or at the end of a `with` block
+#[pyclass(unsendable)]
+pub struct Client {
+    transport: Option<CborSmpTransport>,
+    sequence: u8,
+}
+
+impl Client {
+    fn new(transport: Box<dyn SmpTransport>) -> Self {
+        Self {
+            transport: Some(CborSmpTransport::new(transport)),
+            sequence: 0,
+        }
+    }
+
+    fn next_sequence(&mut self) -> u8 {
+        self.sequence = self.sequence.wrapping_add(1);
+        self.sequence
+    }
+
+    fn transport(&mut self) -> PyResult<&mut CborSmpTransport> {
+        self.transport
+            .as_mut()
+            .ok_or_else(|| TransportError::new_err("the connection is closed"))
+    }
+
+    /// Send a request and wait for its response without holding the GIL
+    fn transceive<Req, Resp>(&mut self, py: Python<'_>, frame: &SmpFrame<Req>) -> PyResult<Resp>
+    where
+        Req: serde::Serialize + Sync,
+        Resp: serde::de::DeserializeOwned + Send,
+    {
+        let transport = self.transport()?;
+        let ret: SmpFrame<Resp> = py
+            .allow_threads(|| transport.transceive_cbor(frame, true))
+            .map_err(transport_error)?;
+        Ok(ret.data)
+    }
+}
+
+#[pymethods]
+impl Client {
+    /// Connect to a device on a serial port
+    #[staticmethod]
+    #[pyo3(signature = (device, baud_rate = 115200, timeout_ms = 5000))]
+    fn serial(py: Python<'_>, device: String, baud_rate: u32, timeout_ms: u64) -> PyResult<Self> {
+        let mut transport = py
+            .allow_threads(|| SerialTransport::new(device, baud_rate).map_err(|e| e.to_string()))
+            .map_err(TransportError::new_err)?;
+        transport
+            .recv_timeout(Some(Duration::from_millis(timeout_ms)))
+            .map_err(transport_error)?;
+        Ok(Self::new(Box::new(transport)))
+    }
+
+    /// Connect to a device over UDP
+    #[staticmethod]
+    #[pyo3(signature = (host, port = 1337, timeout_ms = 5000))]
+    fn udp(py: Python<'_>, host: &str, port: u16, timeout_ms: u64) -> PyResult<Self> {
+        let mut transport = py
+            .allow_threads(|| UdpTransport::new((host, port)))
+            .map_err(|e| TransportError::new_err(format!("can't connect to {}: {}", host, e)))?;
+        transport
+            .recv_timeout(Some(Duration::from_millis(timeout_ms)))
+            .map_err(transport_error)?;
+        Ok(Self::new(Box::new(transport)))
+    }
+
+    /// Send a message that the device returns
+    fn echo(&mut self, py: Python<'_>, message: String) -> PyResult<String> {
+        let sequence = self.next_sequence();
+        match self.transceive(py, &os_management::echo(sequence, message))? {
+            EchoResult::Ok { r } => Ok(r),
+            EchoResult::Err { rc } => Err(device_error(rc)),
+        }
+    }
+
+    /// Read the value of a setting as bytes
+    fn read_setting<'py>(
+        &mut self,
+        py: Python<'py>,
+        name: String,
+    ) -> PyResult<Bound<'py, PyBytes>> {
+        let sequence = self.next_sequence();
+        match self.transceive(py, &setting_management::read_setting(sequence, name))? {
+            ReadSettingResult::Ok { val } => Ok(PyBytes::new_bound(py, &val)),
+            ReadSettingResult::Err { rc } => Err(device_error(rc)),
+        }
+    }
+
+    /// Write the value of a setting, it is applied but not saved
+    fn write_setting(&mut self, py: Python<'_>, name: String, value: &[u8]) -> PyResult<()> {
+        let sequence = self.next_sequence();
+        match self.transceive(
+            py,
+            &setting_management::write_setting(sequence, name, value),
+        )? {
+            WriteSettingResult::Ok {} => Ok(()),
+            WriteSettingResult::Err { rc } => Err(device_error(rc)),
+        }
+    }
+
+    /// Save the settings to persistent storage
+    fn save_settings(&mut self, py: Python<'_>) -> PyResult<()> {
+        let sequence = self.next_sequence();
+        match self.transceive(py, &setting_management::save_setting(sequence))? {
+            SaveSettingResult::Ok {} => Ok(()),
+            SaveSettingResult::Err { rc } => Err(device_error(rc)),
+        }
+    }
+
+    /// The images on the device, as a list of dicts with the fields of the image state
+    fn image_state<'py>(&mut self, py: Python<'py>) -> PyResult<Bound<'py, PyList>> {
+        let sequence = self.next_sequence();
+        let state = match self.transceive(py, &application_management::get_state(sequence))? {
+            GetImageStateResult::Ok(state) => state,
+            GetImageStateResult::Err(err) => return Err(device_error(err.rc)),
+        };
+
+        let images = PyList::empty_bound(py);
+        for image in state.images {
+            let dict = PyDict::new_bound(py);
+            dict.set_item("image", image.image)?;
+            dict.set_item("slot", image.slot)?;
+            dict.set_item("version", image.version)?;
+            dict.set_item("hash", PyBytes::new_bound(py, &image.hash))?;
+            dict.set_item("bootable", image.bootable)?;
+            dict.set_item("pending", image.pending)?;
+            dict.set_item("confirmed", image.confirmed)?;
+            dict.set_item("active", image.active)?;
+            dict.set_item("permanent", image.permanent)?;
+            images.append(dict)?;
+        }
+        Ok(images)
+    }
+
+    /// Upload an image. `progress` is called with the bytes sent and the total after every
+    /// chunk, an exception it raises stops the upload.
+    #[pyo3(signature = (data, image = None, chunk_size = 256, progress = None))]
+    fn upload(
+        &mut self,
+        py: Python<'_>,
+        data: &[u8],
+        image: Option<u8>,
+        chunk_size: usize,
+        progress: Option<Bound<'_, PyAny>>,
+    ) -> PyResult<()> {
+        let mut writer = ImageWriter::new(image, data.len(), None, false);
+        let mut request_buf = Vec::new();
+
+        while writer.offset < data.len() {
+            let offset = writer.offset;
+            let end = data.len().min(offset + chunk_size);
+            let sequence = self.next_sequence();
+            let mut frame = writer.write_chunk(&data[offset..end]);
+            frame.sequence = sequence;
+
+            let transport = self.transport()?;
+            let ret: SmpFrame<WriteImageChunkResult> = py
+                .allow_threads(|| transport.transceive_cbor_with(&frame, true, &mut request_buf))
+                .map_err(transport_error)?;
+            if let WriteImageChunkResult::Err(err) = &ret.data {
+                return Err(device_error(err.rc));
+            }
+            if let UploadAction::InvalidOffset { off, sent } = writer.handle_response(&ret.data) {
+                return Err(SmpError::new_err(format!(
+                    "the device reported offset {} beyond the {} bytes sent",
+                    off, sent
+                )));
+            }
+            if writer.offset <= offset {
+                return Err(SmpError::new_err(format!(
+                    "the device didn't accept the chunk at offset {}",
+                    offset
+                )));
+            }
+
+            if let Some(progress) = &progress {
+                progress.call1((writer.offset, data.len()))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Close the connection, later operations fail with a `TransportError`
+    fn close(&mut self) {
+        self.transport = None;
+    }
+
+    fn __enter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+
+    #[pyo3(signature = (_exc_type = None, _exc_value = None, _traceback = None))]
+    fn __exit__(
+        &mut self,
+        _exc_type: Option<Bound<'_, PyAny>>,
+        _exc_value: Option<Bound<'_, PyAny>>,
+        _traceback: Option<Bound<'_, PyAny>>,
+    ) -> bool {
+        self.close();
+        false
+    }
+}
+
+#[pymodule]
+fn smp_py(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<Client>()?;
+    m.add("SmpError", m.py().get_type_bound::<SmpError>())?;
+    m.add("TransportError", m.py().get_type_bound::<TransportError>())?;
+    m.add(
+        "ResponseTimeout",
+        m.py().get_type_bound::<ResponseTimeout>(),
+    )?;
+    m.add("DeviceError", m.py().get_type_bound::<DeviceError>())?;
+    Ok(())
+}
@@ -0,0 +1,122 @@
+"""The Python bindings against the mock device of the smp-tool tests.
+
+    maturin develop -m smp-py/Cargo.toml && pytest smp-py/tests
+"""
+
+import hashlib
+import pathlib
+import sys
+import threading
+import time
+
+import pytest
+
+sys.path.insert(0, str(pathlib.Path(__file__).resolve().parents[2] / "smp-tool" / "tests"))
+from mock_device import ENOENT, MockDevice  # noqa: E402
+
+smp_py = pytest.importorskip("smp_py")
+
+
+class SlowDevice(MockDevice):
+    """Answers echo after `delay` seconds, or never for `None`"""
+
+    def __init__(self, delay):
+        super().__init__()
+        self.delay = delay
+
+    def answer(self, op, group, command, payload):
+        if (group, command) == (0, 0):
+            if self.delay is None:
+                return None
+            time.sleep(self.delay)
+        return super().answer(op, group, command, payload)
+
+
+@pytest.fixture
+def device():
+    with MockDevice() as device:
+        yield device
+
+
+@pytest.fixture
+def client(device):
+    with smp_py.Client.udp("127.0.0.1", device.port, timeout_ms=2000) as client:
+        yield client
+
+
+def test_echo(client):
+    assert client.echo("hello") == "hello"
+
+
+def test_setting_written_and_read(client, device):
+    client.write_setting("foo/bar", b"baz")
+    assert device.settings["foo/bar"] == b"baz"
+    assert client.read_setting("foo/bar") == b"baz"
+
+
+def test_device_error(client):
+    with pytest.raises(smp_py.DeviceError) as error:
+        client.read_setting("no/such/setting")
+    assert isinstance(error.value, smp_py.SmpError)
+    assert error.value.args[1] == ENOENT
+
+
+def test_upload_with_progress(client, device):
+    firmware = bytes(range(256)) * 12
+    events = []
+    client.upload(firmware, chunk_size=512, progress=lambda sent, total: events.append(sent))
+
+    assert device.image == firmware
+    assert events == list(range(512, len(firmware) + 1, 512))
+    images = client.image_state()
+    assert [image["slot"] for image in images] == [0, 1]
+    assert images[1]["hash"] == hashlib.sha256(firmware).digest()
+    assert images[0]["active"] and not images[1]["active"]
+
+
+def test_progress_exception_stops_upload(client, device):
+    def progress(sent, total):
+        raise RuntimeError("stop")
+
+    with pytest.raises(RuntimeError):
+        client.upload(bytes(2048), chunk_size=512, progress=progress)
+    assert len(device.image) == 512
+
+
+def test_closed_client(client):
+    client.close()
+    with pytest.raises(smp_py.TransportError):
+        client.echo("hello")
+
+
+def test_timeout():
+    with SlowDevice(None) as device:
+        client = smp_py.Client.udp("127.0.0.1", device.port, timeout_ms=200)
+        with pytest.raises(smp_py.ResponseTimeout):
+            client.echo("hello")
+
+
+def test_gil_released_while_waiting():
+    with SlowDevice(1.0) as device:
+        waiting = threading.Event()
+        responses = []
+
+        def echo():
+            client = smp_py.Client.udp("127.0.0.1", device.port, timeout_ms=5000)
+            waiting.set()
+            responses.append(client.echo("hello"))
+
+        thread = threading.Thread(target=echo)
+        thread.start()
+        waiting.wait()
+
+        # holding the GIL, the echo would block this thread until the device answers
+        ticks = 0
+        deadline = time.monotonic() + 0.2
+        while time.monotonic() < deadline:
+            ticks += 1
+        assert thread.is_alive()
+        assert ticks > 0
+
+        thread.join()
+        assert responses == ["hello"]
@@ -0,0 +1,443 @@
+use std::error::Error;
+use std::path::PathBuf;
+
+use clap::CommandFactory;
+use serde::{Deserialize, Serialize};
+
+use crate::context::Context;
+use crate::error::{CliError, EXIT_TRANSPORT};
+use crate::output::{self, OutputFormat};
+use crate::{
+    describe_target, execute, open_transport, script, status, transcript, Cli, Commands, LogCmd,
+    OsCmd, ShellCmd, StatCmd, UsedTransport,
+};
+
+pub const AGENT_HELP: &str = "\
+Keep the connection to the device open and execute the commands of clients on a unix socket.
+
+Connecting takes seconds over BLE, with an agent it is only done once. Clients send one JSON
+object per line and get one JSON object per line back:
+  {\"id\": 1, \"args\": [\"app\", \"info\"], \"format\": \"json\"}
+  {\"id\": 1, \"exit_code\": 0, \"stdout\": \"...\", \"result\": {...}}
+`args` is the command like on the command line, without the global options. `format`
+defaults to json, `result` is the parsed output of json commands, `error` is the error as
+printed with --format json and `connection_lost` tells that the agent reconnects to the
+device. Relative paths are resolved in `cwd` if given.
+
+Commands are executed one at a time. Commands that run until Ctrl-C is pressed and
+reading firmware from stdin aren't supported.
+
+The normal command line sends its command to an agent with --via SOCKET.";
+
+/// A command for the agent, one JSON object per line
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Request {
+    /// Copied to the response, so clients can match responses to requests
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<serde_json::Value>,
+    /// The command like on the command line, e.g. `["app", "info"]`
+    pub args: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub format: Option<OutputFormat>,
+    /// Directory relative paths of the command are resolved in
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cwd: Option<PathBuf>,
+}
+
+/// The outcome of a command executed by the agent
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Response {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<serde_json::Value>,
+    pub exit_code: u8,
+    /// Everything the command printed on stdout
+    pub stdout: String,
+    /// The parsed output of a command with JSON output
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub result: Option<serde_json::Value>,
+    /// The error as reported with `--format json`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<serde_json::Value>,
+    /// The connection to the device was lost, the agent reconnects
+    #[serde(default)]
+    pub connection_lost: bool,
+}
+
+impl Response {
+    fn failed(id: Option<serde_json::Value>, err: &(dyn Error + 'static)) -> Self {
+        let exit_code = crate::error::exit_code(err);
+        Self {
+            id,
+            exit_code,
+            stdout: String::new(),
+            result: None,
+            error: Some(crate::error::to_json(err, exit_code)),
+            connection_lost: false,
+        }
+    }
+}
+
+/// Commands that only end when Ctrl-C is pressed, which a client can't do
+fn runs_until_interrupted(command: &Commands) -> bool {
+    matches!(
+        command,
+        Commands::Shell(ShellCmd::Interactive)
+            | Commands::Os(OsCmd::Ping { count: None, .. })
+            | Commands::Os(OsCmd::Taskstat { watch: Some(_), .. })
+            | Commands::Stat(StatCmd::Read {
+                poll: Some(_),
+                count: None,
+                ..
+            })
+            | Commands::Log(LogCmd::Tail { follow: true, .. })
+            | Commands::Dashboard { .. }
+    )
+}
+
+/// The words of this process' command line from the command on, without the global
+/// options before it
+pub fn command_words() -> Vec<String> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let cli = Cli::command();
+
+    let mut i = 0;
+    while let Some(arg) = args.get(i) {
+        if arg == "-" || arg == "--" || !arg.starts_with('-') {
+            break;
+        }
+        i += 1;
+        if takes_next_word(&cli, arg) {
+            i += 1;
+        }
+    }
+
+    args.get(i..).unwrap_or_default().to_vec()
+}
+
+/// Whether a global option is followed by its value as a separate word
+fn takes_next_word(cli: &clap::Command, arg: &str) -> bool {
+    let takes_values = |a: &clap::Arg| a.get_action().takes_values();
+
+    if let Some(long) = arg.strip_prefix("--") {
+        return !long.contains('=')
+            && cli
+                .get_arguments()
+                .any(|a| a.get_long() == Some(long) && takes_values(a));
+    }
+
+    // short flags can be combined, e.g. `-vv` or `-qt serial`, only the last one can take
+    // the next word and a value can be attached, e.g. `-tserial`
+    let shorts = &arg[1..];
+    for (pos, c) in shorts.char_indices() {
+        if cli
+            .get_arguments()
+            .any(|a| a.get_short() == Some(c) && takes_values(a))
+        {
+            return pos + c.len_utf8() == shorts.len();
+        }
+    }
+    false
+}
+
+/// Execute the command of a request over the shared connection. `source` is what received
+/// the request, e.g. `agent`, for messages and the transcript.
+pub async fn handle(
+    ctx: &Context,
+    cli: &Cli,
+    connection: &mut Option<UsedTransport>,
+    request: Request,
+    source: &str,
+) -> Response {
+    let through = format!("through the {}", source);
+    let command = match script::parse_command(&request.args, &through) {
+        Ok(command) => command,
+        Err(e) => return Response::failed(request.id, &e),
+    };
+    if runs_until_interrupted(&command) {
+        let e = CliError::Usage(format!(
+            "`{}` runs until Ctrl-C is pressed, which isn't possible {}",
+            request.args.join(" "),
+            through
+        ));
+        return Response::failed(request.id, &e);
+    }
+    if let Some(cwd) = &request.cwd {
+        if let Err(e) = std::env::set_current_dir(cwd) {
+            let e = format!("can't change to {}: {}", cwd.display(), e);
+            return Response::failed(request.id, Box::<dyn Error>::from(e).as_ref());
+        }
+    }
+
+    let format = request.format.unwrap_or(OutputFormat::Json);
+    transcript::command(ctx, source, &request.args, Some(&command));
+    output::start_capture(ctx);
+    let ret = execute(
+        ctx,
+        Cli {
+            command,
+            format,
+            ..cli.clone()
+        },
+        connection,
+    )
+    .await;
+    let stdout = output::end_capture(ctx);
+    transcript::outcome(ctx, ret.as_ref().err().map(|e| e.as_ref()));
+
+    let result = if format == OutputFormat::Json {
+        serde_json::from_str(&stdout).ok()
+    } else {
+        None
+    };
+    let mut response = match ret {
+        Ok(()) => Response {
+            id: None,
+            exit_code: 0,
+            stdout: String::new(),
+            result: None,
+            error: None,
+            connection_lost: false,
+        },
+        Err(e) => Response::failed(None, e.as_ref()),
+    };
+    response.id = request.id;
+    response.stdout = stdout;
+    response.result = result;
+
+    if response.exit_code == EXIT_TRANSPORT {
+        response.connection_lost = true;
+        reconnect(ctx, cli, connection).await;
+    }
+    response
+}
+
+/// Replace a lost connection. If that fails, the next request tries again
+async fn reconnect(ctx: &Context, cli: &Cli, connection: &mut Option<UsedTransport>) {
+    if let Some(mut transport) = connection.take() {
+        let _ = transport.close().await;
+    }
+    eprintln!(
+        "warning: lost the connection to {}, reconnecting",
+        describe_target(cli)
+    );
+
+    match open_transport(ctx, cli).await {
+        Ok(transport) => {
+            status!(ctx, "reconnected to {}", describe_target(cli));
+            *connection = Some(transport);
+        }
+        Err(e) => eprintln!(
+            "warning: reconnecting failed, retrying with the next request: {}",
+            e
+        ),
+    }
+}
+
+#[cfg(unix)]
+pub use self::unix::{forward, serve};
+
+#[cfg(not(unix))]
+pub use self::fallback::{forward, serve};
+
+#[cfg(not(unix))]
+mod fallback {
+    use std::error::Error;
+    use std::path::Path;
+
+    use crate::context::Context;
+    use crate::error::CliError;
+    use crate::Cli;
+
+    pub async fn serve(_ctx: &Context, _cli: &Cli, _path: &Path) -> Result<(), Box<dyn Error>> {
+        Err(CliError::Usage(
+            "the agent needs unix domain sockets, which this platform doesn't support".to_string(),
+        ))?
+    }
+
+    pub fn forward(_cli: &Cli, _socket: &Path, _words: Vec<String>) -> Result<(), Box<dyn Error>> {
+        Err(CliError::Usage(
+            "--via needs unix domain sockets, which this platform doesn't support".to_string(),
+        ))?
+    }
+}
+
+#[cfg(unix)]
+mod unix {
+    use std::error::Error;
+    use std::io::{BufRead, BufReader, Write};
+    use std::os::unix::net::{UnixListener, UnixStream};
+    use std::path::Path;
+    use std::sync::mpsc;
+
+    use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+
+    use super::{handle, Request, Response};
+    use crate::context::Context;
+    use crate::error::CliError;
+    use crate::output::OutputFormat;
+    use crate::{describe_target, open_transport, status, Cli};
+
+    type Job = (Request, mpsc::Sender<Response>);
+
+    /// Connect to the device and execute the commands of clients until Ctrl-C is pressed.
+    ///
+    /// Every client is served by a thread that passes its requests to the connection here,
+    /// so commands are executed one at a time.
+    pub async fn serve(ctx: &Context, cli: &Cli, path: &Path) -> Result<(), Box<dyn Error>> {
+        let listener = bind(path)?;
+        let mut connection = match open_transport(ctx, cli).await {
+            Ok(transport) => Some(transport),
+            Err(e) => {
+                let _ = std::fs::remove_file(path);
+                return Err(e);
+            }
+        };
+        status!(
+            ctx,
+            "connected to {}, listening on {}",
+            describe_target(cli),
+            path.display()
+        );
+
+        let (tx, mut jobs) = unbounded_channel::<Job>();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        let tx = tx.clone();
+                        std::thread::spawn(move || serve_client(stream, tx));
+                    }
+                    Err(e) => eprintln!("warning: accepting a client failed: {}", e),
+                }
+            }
+        });
+
+        let ctrl_c = tokio::signal::ctrl_c();
+        tokio::pin!(ctrl_c);
+
+        loop {
+            let (request, reply) = tokio::select! {
+                _ = &mut ctrl_c => break,
+                job = jobs.recv() => match job {
+                    Some(job) => job,
+                    None => break,
+                },
+            };
+            let _ = reply.send(handle(ctx, cli, &mut connection, request, "agent").await);
+        }
+
+        if let Some(transport) = &mut connection {
+            if let Err(e) = transport.close().await {
+                eprintln!("warning: closing the connection failed: {}", e);
+            }
+        }
+        let _ = std::fs::remove_file(path);
+        Ok(())
+    }
+
+    /// Listen on `path`, replacing the socket of an agent that didn't exit cleanly
+    fn bind(path: &Path) -> Result<UnixListener, Box<dyn Error>> {
+        if path.exists() {
+            if UnixStream::connect(path).is_ok() {
+                Err(CliError::Usage(format!(
+                    "an agent is already listening on {}",
+                    path.display()
+                )))?;
+            }
+            std::fs::remove_file(path)
+                .map_err(|e| format!("can't remove stale socket {}: {}", path.display(), e))?;
+        }
+
+        let listener = UnixListener::bind(path)
+            .map_err(|e| format!("can't listen on {}: {}", path.display(), e))?;
+        Ok(listener)
+    }
+
+    /// Read the requests of a client, one per line, and write the responses
+    fn serve_client(stream: UnixStream, jobs: UnboundedSender<Job>) {
+        let Ok(mut writer) = stream.try_clone() else {
+            return;
+        };
+
+        for line in BufReader::new(stream).lines() {
+            let Ok(line) = line else {
+                return;
+            };
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            let response = match serde_json::from_str::<Request>(&line) {
+                Ok(request) => {
+                    let (reply, response) = mpsc::channel();
+                    if jobs.send((request, reply)).is_err() {
+                        return;
+                    }
+                    let Ok(response) = response.recv() else {
+                        return;
+                    };
+                    response
+                }
+                Err(e) => {
+                    Response::failed(None, &CliError::Usage(format!("invalid request: {}", e)))
+                }
+            };
+
+            let json = serde_json::to_string(&response).expect("serializing to string can't fail");
+            if writeln!(writer, "{}", json).is_err() {
+                return;
+            }
+        }
+    }
+
+    /// Let the agent listening on `socket` execute a command and print its outcome like the
+    /// command would
+    pub fn forward(cli: &Cli, socket: &Path, words: Vec<String>) -> Result<(), Box<dyn Error>> {
+        let mut stream = UnixStream::connect(socket).map_err(|e| {
+            CliError::Connect(format!("no agent on {}: {}", socket.display(), e).into())
+        })?;
+
+        let request = Request {
+            id: None,
+            args: words,
+            format: Some(cli.format),
+            cwd: std::env::current_dir().ok(),
+        };
+        writeln!(
+            stream,
+            "{}",
+            serde_json::to_string(&request).expect("serializing to string can't fail")
+        )?;
+
+        let mut line = String::new();
+        BufReader::new(&stream).read_line(&mut line)?;
+        if line.is_empty() {
+            Err(CliError::Connect(
+                "the agent closed the connection without a response".into(),
+            ))?;
+        }
+        let response: Response = serde_json::from_str(&line)
+            .map_err(|e| format!("invalid response from the agent: {}", e))?;
+
+        print!("{}", response.stdout);
+        if response.connection_lost {
+            eprintln!("warning: the agent lost the connection to the device and is reconnecting");
+        }
+        if let Some(error) = response.error {
+            match cli.format {
+                OutputFormat::Text => {
+                    eprintln!(
+                        "error: {}",
+                        error["error"].as_str().unwrap_or("unknown error")
+                    );
+                    if let Some(hint) = error["hint"].as_str() {
+                        eprintln!("hint: {}", hint);
+                    }
+                }
+                OutputFormat::Json => eprintln!("{}", error),
+            }
+            Err(CliError::Verdict(response.exit_code))?;
+        }
+        Ok(())
+    }
+}
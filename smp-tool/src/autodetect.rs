@@ -0,0 +1,106 @@
+use std::error::Error;
+
+use mcumgr_smp::transport::serial::{self, UsbPort};
+
+use crate::context::Context;
+use crate::error::CliError;
+use crate::{status, Cli, Transport};
+
+/// Whether a port is likely a USB CDC ACM device, the usual console of a development kit.
+///
+/// The port list doesn't include the USB class, so the name the OS gives these ports is
+/// checked. Windows names every serial port COMn, there all USB ports are candidates.
+fn is_cdc_acm(port: &UsbPort) -> bool {
+    if cfg!(target_os = "linux") {
+        port.name.contains("ttyACM")
+    } else if cfg!(target_os = "macos") {
+        // every device shows up as tty.* and cu.*, only cu.* doesn't wait for carrier detect
+        port.name.starts_with("/dev/cu.usbmodem")
+    } else {
+        true
+    }
+}
+
+/// Whether a USB serial device that [apply] would select is connected, e.g. once a board
+/// that was power cycled is back
+pub fn any_candidate() -> bool {
+    serial::usb_ports().is_ok_and(|ports| ports.iter().any(is_cdc_acm))
+}
+
+/// Port name with the USB device description, e.g. `/dev/ttyACM0 (SEGGER J-Link, 1366:1051)`
+fn describe(port: &UsbPort) -> String {
+    let product: Vec<&str> = [port.manufacturer.as_deref(), port.product.as_deref()]
+        .into_iter()
+        .flatten()
+        .collect();
+    if product.is_empty() {
+        format!("{} ({:04x}:{:04x})", port.name, port.vid, port.pid)
+    } else {
+        format!(
+            "{} ({}, {:04x}:{:04x})",
+            port.name,
+            product.join(" "),
+            port.vid,
+            port.pid
+        )
+    }
+}
+
+/// Use the serial transport for a command without `--transport`: the given `--serial-device`
+/// or the only connected USB CDC ACM device. BLE and UDP are never selected, they can't be
+/// told apart without slow probing.
+///
+/// The selected port is reported on stderr, with no or several candidates the error lists
+/// the flags to select one.
+pub fn apply(ctx: &Context, cli: &mut Cli) -> Result<(), Box<dyn Error>> {
+    if cli.serial_device.is_some() {
+        cli.transport = Some(Transport::Serial);
+        return Ok(());
+    }
+
+    let ports = serial::usb_ports().map_err(|e| format!("can't list the serial ports: {}", e))?;
+    let (candidates, others): (Vec<_>, Vec<_>) = ports.into_iter().partition(is_cdc_acm);
+
+    match candidates.as_slice() {
+        [port] => {
+            status!(
+                ctx,
+                "using {} at {} baud, set --transport or --no-autodetect to avoid this",
+                describe(port),
+                cli.serial_baud
+            );
+            cli.transport = Some(Transport::Serial);
+            cli.serial_device = Some(port.name.clone());
+            Ok(())
+        }
+        [] => {
+            let mut msg = "--transport is required, no USB serial device to use instead was found"
+                .to_string();
+            if !others.is_empty() {
+                msg.push_str("\nother USB serial ports:");
+                for port in &others {
+                    msg.push_str(&format!(
+                        "\n  --transport serial --serial-device {}  {}",
+                        port.name,
+                        describe(port)
+                    ));
+                }
+            }
+            Err(CliError::Usage(msg).into())
+        }
+        _ => {
+            let mut msg = format!(
+                "--transport is required, {} USB serial devices found, select one with:",
+                candidates.len()
+            );
+            for port in &candidates {
+                msg.push_str(&format!(
+                    "\n  --transport serial --serial-device {}  {}",
+                    port.name,
+                    describe(port)
+                ));
+            }
+            Err(CliError::Usage(msg).into())
+        }
+    }
+}
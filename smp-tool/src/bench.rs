@@ -0,0 +1,218 @@
+use std::cmp::min;
+use std::error::Error;
+use std::time::{Duration, Instant};
+
+use mcumgr_smp::{
+    application_management::{self, WriteImageChunkResult},
+    os_management::{self, EchoResult},
+    smp::SmpFrame,
+    ReturnCode,
+};
+use serde::Serialize;
+use tracing::debug;
+
+use crate::context::Context;
+use crate::error::CliError;
+use crate::output::OutputFormat;
+use crate::{flash, outln, UsedTransport};
+
+/// Parameters of a benchmark run
+pub struct BenchOptions {
+    pub payload_size: usize,
+    pub count: usize,
+    pub upload_bytes: Option<usize>,
+    pub chunk_size: usize,
+}
+
+/// Distribution of the echo round-trip times
+#[derive(Serialize, Debug)]
+pub struct Latency {
+    pub count: usize,
+    pub payload_size: usize,
+    pub min_ms: f64,
+    pub median_ms: f64,
+    pub p95_ms: f64,
+    pub max_ms: f64,
+    pub mean_ms: f64,
+}
+
+/// Sustained throughput of an image upload
+#[derive(Serialize, Debug)]
+pub struct Throughput {
+    pub bytes: usize,
+    pub chunk_size: usize,
+    pub chunks: usize,
+    pub seconds: f64,
+    pub bytes_per_second: f64,
+}
+
+#[derive(Serialize, Debug)]
+pub struct BenchResult {
+    pub latency: Latency,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub upload: Option<Throughput>,
+}
+
+impl BenchResult {
+    pub fn print(&self, ctx: &Context, format: OutputFormat) {
+        match format {
+            OutputFormat::Text => {
+                let l = &self.latency;
+                outln!(
+                    ctx,
+                    "echo ({} requests, {} bytes payload):",
+                    l.count,
+                    l.payload_size
+                );
+                outln!(ctx, "  min     {:>9.2} ms", l.min_ms);
+                outln!(ctx, "  median  {:>9.2} ms", l.median_ms);
+                outln!(ctx, "  p95     {:>9.2} ms", l.p95_ms);
+                outln!(ctx, "  max     {:>9.2} ms", l.max_ms);
+                outln!(ctx, "  mean    {:>9.2} ms", l.mean_ms);
+
+                if let Some(u) = &self.upload {
+                    outln!(
+                        ctx,
+                        "upload ({} bytes in {} chunks of {} bytes):",
+                        u.bytes,
+                        u.chunks,
+                        u.chunk_size
+                    );
+                    outln!(ctx, "  time    {:>9.2} s", u.seconds);
+                    outln!(ctx, "  rate    {:>9.2} KiB/s", u.bytes_per_second / 1024.0);
+                }
+            }
+            OutputFormat::Json => {
+                outln!(
+                    ctx,
+                    "{}",
+                    serde_json::to_string_pretty(self).expect("serializing to string can't fail")
+                );
+            }
+        }
+    }
+}
+
+/// Measure the echo latency and optionally the upload throughput
+pub async fn bench(
+    ctx: &Context,
+    transport: &mut UsedTransport,
+    options: &BenchOptions,
+) -> Result<BenchResult, Box<dyn Error>> {
+    if options.count == 0 {
+        Err(CliError::Usage("--count must be at least 1".to_string()))?;
+    }
+    if options.chunk_size == 0 {
+        Err(CliError::Usage(
+            "--chunk-size must be at least 1".to_string(),
+        ))?;
+    }
+
+    let latency = echo_latency(ctx, transport, options.payload_size, options.count).await?;
+
+    let upload = match options.upload_bytes {
+        Some(bytes) => Some(upload_throughput(ctx, transport, bytes, options.chunk_size).await?),
+        None => None,
+    };
+
+    Ok(BenchResult { latency, upload })
+}
+
+async fn echo_latency(
+    ctx: &Context,
+    transport: &mut UsedTransport,
+    payload_size: usize,
+    count: usize,
+) -> Result<Latency, Box<dyn Error>> {
+    let msg = "x".repeat(payload_size);
+    let mut samples = Vec::with_capacity(count);
+
+    for i in 0..count {
+        let start = Instant::now();
+        let ret: SmpFrame<EchoResult> = transport
+            .transceive_cbor(&os_management::echo(ctx.sequence.next(), msg.clone()))
+            .await?;
+        samples.push(start.elapsed());
+
+        match ret.data {
+            EchoResult::Ok { r } if r == msg => {}
+            EchoResult::Ok { r } => Err(format!(
+                "echo response {} differs from the request, {} instead of {} bytes",
+                i,
+                r.len(),
+                msg.len()
+            ))?,
+            EchoResult::Err { rc } => Err(CliError::device(rc))?,
+        }
+    }
+
+    samples.sort();
+    let ms = |d: Duration| d.as_secs_f64() * 1000.0;
+    let total: Duration = samples.iter().sum();
+
+    Ok(Latency {
+        count,
+        payload_size,
+        min_ms: ms(samples[0]),
+        median_ms: ms(percentile(&samples, 50)),
+        p95_ms: ms(percentile(&samples, 95)),
+        max_ms: ms(samples[count - 1]),
+        mean_ms: ms(total) / count as f64,
+    })
+}
+
+/// Nearest-rank percentile of sorted samples
+fn percentile(sorted: &[Duration], p: usize) -> Duration {
+    let rank = (p * sorted.len()).div_ceil(100).max(1);
+    sorted[rank - 1]
+}
+
+/// Upload dummy data to the secondary slot of image 0.
+///
+/// The data isn't a valid image and is never marked for test, so the device keeps
+/// running its current image, but whatever was in the secondary slot is lost.
+async fn upload_throughput(
+    ctx: &Context,
+    transport: &mut UsedTransport,
+    bytes: usize,
+    chunk_size: usize,
+) -> Result<Throughput, Box<dyn Error>> {
+    let data: Vec<u8> = (0..bytes).map(|i| i as u8).collect();
+    let mut writer = application_management::ImageWriter::new(None, data.len(), None, false);
+
+    let mut request_buf = Vec::new();
+    let start = Instant::now();
+    let mut chunks = 0;
+    let mut offset = 0;
+    while offset < data.len() {
+        let chunk = &data[offset..min(data.len(), offset + chunk_size)];
+        let mut request = writer.write_chunk(chunk);
+        request.sequence = ctx.sequence.next();
+        let ret: SmpFrame<WriteImageChunkResult> = transport
+            .transceive_cbor_with(&request, &mut request_buf)
+            .await?;
+        chunks += 1;
+
+        match ret.data {
+            WriteImageChunkResult::Ok(payload) => {
+                offset = payload.off as usize;
+                writer.offset = offset;
+            }
+            WriteImageChunkResult::Err(err) => Err(CliError::Device {
+                rc: err.rc,
+                rsn: err.rsn,
+            }
+            .hint_on(ReturnCode::InvalidValue, flash::UPLOAD_EINVAL_HINT))?,
+        }
+    }
+    let elapsed = start.elapsed();
+    debug!("uploaded {} bytes in {:?}", bytes, elapsed);
+
+    Ok(Throughput {
+        bytes,
+        chunk_size,
+        chunks,
+        seconds: elapsed.as_secs_f64(),
+        bytes_per_second: bytes as f64 / elapsed.as_secs_f64(),
+    })
+}
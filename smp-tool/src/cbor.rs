@@ -0,0 +1,114 @@
+use std::error::Error;
+use std::io::Cursor;
+
+use ciborium::Value;
+
+use crate::image;
+
+/// Convert a JSON document to its CBOR encoding
+pub fn json_to_cbor(json: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+    let value: serde_json::Value =
+        serde_json::from_str(json).map_err(|e| format!("invalid JSON payload: {}", e))?;
+
+    let mut buf = Vec::new();
+    ciborium::ser::into_writer(&value, &mut buf)?;
+    Ok(buf)
+}
+
+/// Decode a single CBOR item and return it together with the number of bytes it used
+pub fn decode(data: &[u8]) -> Result<(Value, usize), ciborium::de::Error<std::io::Error>> {
+    let mut cursor = Cursor::new(data);
+    let value = ciborium::de::from_reader(&mut cursor)?;
+    Ok((value, cursor.position() as usize))
+}
+
+/// Render a CBOR value in diagnostic notation.
+///
+/// This is JSON for everything JSON can represent, byte strings are rendered as `h'0a0b'`.
+pub fn diag(value: &Value) -> String {
+    let mut out = String::new();
+    write_diag(&mut out, value, 0);
+    out
+}
+
+/// Convert a CBOR value to JSON, e.g. a field of a response that has no type of its own.
+///
+/// Byte strings become hex strings like the hashes elsewhere in the output, map keys that
+/// aren't text are written in diagnostic notation and tags are dropped.
+pub fn to_json(value: &Value) -> serde_json::Value {
+    match value {
+        Value::Integer(i) => {
+            let i = i128::from(*i);
+            match i64::try_from(i) {
+                Ok(i) => i.into(),
+                Err(_) => u64::try_from(i).map_or_else(|_| i.to_string().into(), Into::into),
+            }
+        }
+        Value::Bytes(b) => image::hex(b).into(),
+        Value::Float(f) => {
+            serde_json::Number::from_f64(*f).map_or(serde_json::Value::Null, Into::into)
+        }
+        Value::Text(s) => s.clone().into(),
+        Value::Bool(b) => (*b).into(),
+        Value::Null => serde_json::Value::Null,
+        Value::Tag(_, value) => to_json(value),
+        Value::Array(items) => items.iter().map(to_json).collect(),
+        Value::Map(entries) => entries
+            .iter()
+            .map(|(key, value)| {
+                let key = match key {
+                    Value::Text(key) => key.clone(),
+                    key => diag(key),
+                };
+                (key, to_json(value))
+            })
+            .collect::<serde_json::Map<_, _>>()
+            .into(),
+        // the value type is non-exhaustive
+        other => format!("{:?}", other).into(),
+    }
+}
+
+fn write_diag(out: &mut String, value: &Value, indent: usize) {
+    let pad = |out: &mut String, indent: usize| out.push_str(&"  ".repeat(indent));
+
+    match value {
+        Value::Integer(i) => out.push_str(&i128::from(*i).to_string()),
+        Value::Bytes(b) => out.push_str(&format!("h'{}'", image::hex(b))),
+        Value::Float(f) => out.push_str(&f.to_string()),
+        Value::Text(s) => out.push_str(&serde_json::to_string(s).expect("string is valid JSON")),
+        Value::Bool(b) => out.push_str(&b.to_string()),
+        Value::Null => out.push_str("null"),
+        Value::Tag(tag, value) => {
+            out.push_str(&format!("{}(", tag));
+            write_diag(out, value, indent);
+            out.push(')');
+        }
+        Value::Array(items) if items.is_empty() => out.push_str("[]"),
+        Value::Array(items) => {
+            out.push_str("[\n");
+            for (i, item) in items.iter().enumerate() {
+                pad(out, indent + 1);
+                write_diag(out, item, indent + 1);
+                out.push_str(if i + 1 < items.len() { ",\n" } else { "\n" });
+            }
+            pad(out, indent);
+            out.push(']');
+        }
+        Value::Map(entries) if entries.is_empty() => out.push_str("{}"),
+        Value::Map(entries) => {
+            out.push_str("{\n");
+            for (i, (key, value)) in entries.iter().enumerate() {
+                pad(out, indent + 1);
+                write_diag(out, key, indent + 1);
+                out.push_str(": ");
+                write_diag(out, value, indent + 1);
+                out.push_str(if i + 1 < entries.len() { ",\n" } else { "\n" });
+            }
+            pad(out, indent);
+            out.push('}');
+        }
+        // the value type is non-exhaustive
+        other => out.push_str(&format!("{:?}", other)),
+    }
+}
@@ -0,0 +1,273 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::path::{Path, PathBuf};
+
+use clap::parser::ValueSource;
+use clap::ArgMatches;
+use serde::Deserialize;
+
+use crate::serial::{Flow, LineConfig};
+use crate::{Cli, Transport};
+
+/// Contents of the configuration file
+#[derive(Deserialize, Debug, Default)]
+pub struct Config {
+    #[serde(default)]
+    pub profiles: BTreeMap<String, Profile>,
+    #[serde(flatten)]
+    unknown: BTreeMap<String, toml::Value>,
+}
+
+/// A named set of connection parameters
+#[derive(Deserialize, Debug, Default, Clone)]
+pub struct Profile {
+    pub transport: Option<Transport>,
+    pub serial_device: Option<String>,
+    pub serial_baud: Option<u32>,
+    /// e.g. `8E1`, see `--serial-config`
+    pub serial_config: Option<LineConfig>,
+    pub serial_flow: Option<Flow>,
+    pub dest_host: Option<String>,
+    pub udp_port: Option<u16>,
+    pub timeout_ms: Option<u64>,
+    pub slow_timeout_ms: Option<u64>,
+    pub name: Option<String>,
+    #[serde(flatten)]
+    unknown: BTreeMap<String, toml::Value>,
+}
+
+/// A loaded configuration and the file it came from
+pub struct LoadedConfig {
+    pub path: Option<PathBuf>,
+    pub config: Config,
+}
+
+/// `$XDG_CONFIG_HOME/smp-tool/config.toml`, falling back to `~/.config`
+pub fn default_path() -> Option<PathBuf> {
+    let config_dir = match std::env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(std::env::var_os("HOME")?).join(".config"),
+    };
+    Some(config_dir.join("smp-tool").join("config.toml"))
+}
+
+/// Load the given configuration file, or the default one if it exists.
+///
+/// Unknown keys only produce a warning, so newer config files keep working with older versions.
+pub fn load(path: Option<&Path>) -> Result<LoadedConfig, Box<dyn Error>> {
+    let path = match path {
+        Some(path) => path.to_path_buf(),
+        None => match default_path() {
+            Some(path) if path.exists() => path,
+            _ => {
+                return Ok(LoadedConfig {
+                    path: None,
+                    config: Config::default(),
+                })
+            }
+        },
+    };
+
+    let content = std::fs::read_to_string(&path)
+        .map_err(|e| format!("can't read config file {}: {}", path.display(), e))?;
+    let config: Config = toml::from_str(&content)
+        .map_err(|e| format!("invalid config file {}: {}", path.display(), e))?;
+
+    for key in config.unknown.keys() {
+        eprintln!("warning: {}: unknown key {}", path.display(), key);
+    }
+    for (name, profile) in &config.profiles {
+        for key in profile.unknown.keys() {
+            eprintln!(
+                "warning: {}: unknown key {} in profile {}",
+                path.display(),
+                key,
+                name
+            );
+        }
+    }
+
+    Ok(LoadedConfig {
+        path: Some(path),
+        config,
+    })
+}
+
+impl LoadedConfig {
+    pub fn profile(&self, name: &str) -> Result<&Profile, Box<dyn Error>> {
+        self.config.profiles.get(name).ok_or_else(|| {
+            match &self.path {
+                Some(path) => format!("profile {} not found in {}", name, path.display()),
+                None => format!("profile {} not found, there is no config file", name),
+            }
+            .into()
+        })
+    }
+
+    /// Print all profiles and the file they are defined in
+    pub fn print_profiles(&self) {
+        match &self.path {
+            Some(path) => println!("{}:", path.display()),
+            None => {
+                println!("no config file found");
+                return;
+            }
+        }
+
+        for (name, profile) in &self.config.profiles {
+            let mut settings = Vec::new();
+            if let Some(transport) = profile.transport {
+                settings.push(format!("transport={:?}", transport).to_lowercase());
+            }
+            if let Some(serial_device) = &profile.serial_device {
+                settings.push(format!("serial_device={}", serial_device));
+            }
+            if let Some(serial_baud) = profile.serial_baud {
+                settings.push(format!("serial_baud={}", serial_baud));
+            }
+            if let Some(serial_config) = profile.serial_config {
+                settings.push(format!("serial_config={}", serial_config));
+            }
+            if let Some(serial_flow) = profile.serial_flow {
+                settings.push(format!("serial_flow={:?}", serial_flow).to_lowercase());
+            }
+            if let Some(dest_host) = &profile.dest_host {
+                settings.push(format!("dest_host={}", dest_host));
+            }
+            if let Some(udp_port) = profile.udp_port {
+                settings.push(format!("udp_port={}", udp_port));
+            }
+            if let Some(timeout_ms) = profile.timeout_ms {
+                settings.push(format!("timeout_ms={}", timeout_ms));
+            }
+            if let Some(slow_timeout_ms) = profile.slow_timeout_ms {
+                settings.push(format!("slow_timeout_ms={}", slow_timeout_ms));
+            }
+            if let Some(device_name) = &profile.name {
+                settings.push(format!("name={}", device_name));
+            }
+            println!("  {}: {}", name, settings.join(", "));
+        }
+    }
+}
+
+/// Fill in the connection parameters from a profile.
+///
+/// Values given on the command line or through the environment take precedence,
+/// the profile only replaces defaults and missing values.
+pub fn apply_profile(cli: &mut Cli, matches: &ArgMatches, profile: &Profile) {
+    let from_profile = |id: &str| {
+        matches!(
+            matches.value_source(id),
+            None | Some(ValueSource::DefaultValue)
+        )
+    };
+
+    if from_profile("transport") && profile.transport.is_some() {
+        cli.transport = profile.transport;
+    }
+    if from_profile("serial_device") && profile.serial_device.is_some() {
+        cli.serial_device.clone_from(&profile.serial_device);
+    }
+    if let Some(serial_baud) = profile.serial_baud.filter(|_| from_profile("serial_baud")) {
+        cli.serial_baud = serial_baud;
+    }
+    if let Some(serial_config) = profile
+        .serial_config
+        .filter(|_| from_profile("serial_config"))
+    {
+        cli.serial_config = serial_config;
+    }
+    if let Some(serial_flow) = profile.serial_flow.filter(|_| from_profile("serial_flow")) {
+        cli.serial_flow = serial_flow;
+    }
+    if from_profile("dest_host") && profile.dest_host.is_some() {
+        cli.dest_host.clone_from(&profile.dest_host);
+    }
+    if let Some(udp_port) = profile.udp_port.filter(|_| from_profile("udp_port")) {
+        cli.udp_port = udp_port;
+    }
+    if let Some(timeout_ms) = profile.timeout_ms.filter(|_| from_profile("timeout_ms")) {
+        cli.timeout_ms = timeout_ms;
+    }
+    if let Some(slow_timeout_ms) = profile
+        .slow_timeout_ms
+        .filter(|_| from_profile("slow_timeout_ms"))
+    {
+        cli.slow_timeout_ms = slow_timeout_ms;
+    }
+    if from_profile("name") && profile.name.is_some() {
+        cli.name.clone_from(&profile.name);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::{CommandFactory, FromArgMatches};
+
+    fn profile() -> Profile {
+        let config: Config = toml::from_str(
+            r#"
+            [profiles.lab]
+            transport = "udp"
+            dest_host = "192.0.2.1"
+            udp_port = 2000
+            timeout_ms = 300
+            "#,
+        )
+        .unwrap();
+        config.profiles["lab"].clone()
+    }
+
+    /// The command line with `args` after the profile is applied, with `SMP_UDP_PORT` set to
+    /// `env` while it is parsed
+    fn resolve(args: &[&str], env: Option<&str>, profile: Option<&Profile>) -> Cli {
+        match env {
+            Some(port) => std::env::set_var("SMP_UDP_PORT", port),
+            None => std::env::remove_var("SMP_UDP_PORT"),
+        }
+        let args = ["smp-tool"].iter().chain(args).chain(&["os", "echo", "hi"]);
+        let matches = Cli::command().try_get_matches_from(args);
+        std::env::remove_var("SMP_UDP_PORT");
+
+        let matches = matches.unwrap();
+        let mut cli = Cli::from_arg_matches(&matches).unwrap();
+        if let Some(profile) = profile {
+            apply_profile(&mut cli, &matches, profile);
+        }
+        cli
+    }
+
+    // one test, the environment is shared by all threads
+    #[test]
+    fn command_line_over_environment_over_profile_over_default() {
+        let profile = profile();
+
+        let cli = resolve(&[], None, None);
+        assert_eq!((cli.udp_port, cli.timeout_ms), (1337, 5000));
+        assert_eq!(cli.dest_host, None);
+
+        let cli = resolve(&[], None, Some(&profile));
+        assert_eq!((cli.udp_port, cli.timeout_ms), (2000, 300));
+        assert_eq!(cli.dest_host.as_deref(), Some("192.0.2.1"));
+        assert!(matches!(cli.transport, Some(Transport::Udp)));
+
+        let cli = resolve(&[], Some("3000"), Some(&profile));
+        assert_eq!(cli.udp_port, 3000);
+        assert_eq!(cli.timeout_ms, 300);
+
+        let cli = resolve(
+            &["-p", "4000", "-d", "198.51.100.7"],
+            Some("3000"),
+            Some(&profile),
+        );
+        assert_eq!(cli.udp_port, 4000);
+        assert_eq!(cli.dest_host.as_deref(), Some("198.51.100.7"));
+        assert_eq!(cli.timeout_ms, 300);
+
+        // a value on the command line wins even if it equals the default
+        let cli = resolve(&["--timeout-ms", "5000"], None, Some(&profile));
+        assert_eq!(cli.timeout_ms, 5000);
+    }
+}
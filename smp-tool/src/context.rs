@@ -0,0 +1,67 @@
+use std::sync::{Arc, Mutex, OnceLock};
+
+use mcumgr_smp::os_management::ProbeInfo;
+use mcumgr_smp::setting_management::NameRules;
+use mcumgr_smp::transport::replay::{Recorder, ReplayTransport};
+use tokio::time::Instant;
+
+use crate::output::Capture;
+use crate::progress::ProgressFormat;
+use crate::retry::Retry;
+use crate::sequence::Sequence;
+use crate::transcript::Transcript;
+
+/// The state of a session, shared by all commands it executes: one command on the command
+/// line, or the commands of a script, an agent or `serve --stdio`.
+///
+/// It is filled in from the global options before the first command and passed to every
+/// command, so nothing is kept in process globals.
+pub struct Context {
+    /// The sequence numbers of requests, continued across reconnects
+    pub sequence: Sequence,
+    /// Status messages are suppressed with `--quiet`, warnings and errors are still printed
+    pub quiet: bool,
+    /// Results collected instead of printed, see [crate::output::start_capture]
+    pub capture: Capture,
+    /// How progress is reported
+    pub progress: ProgressFormat,
+    /// Progress events are sent as notifications of `serve --stdio` instead of printed
+    pub notify_progress: bool,
+    /// The id of the JSON-RPC request being executed, sent with its notifications
+    pub request: Mutex<Option<serde_json::Value>>,
+    /// The rules setting names are checked against, `None` with `--no-name-check`
+    pub name_rules: Option<NameRules>,
+    /// The retry policy and the deadlines of requests, shared with every connection
+    pub retry: Arc<Retry>,
+    /// When `--wait-for-device` gives up
+    pub deadline: Option<Instant>,
+    /// The transcript of `--log-file`
+    pub transcript: Option<Arc<Transcript>>,
+    /// The recorder of `--record`
+    pub recorder: Option<Arc<Recorder>>,
+    /// The recording of `--replay-file`, shared by all connections, so a reconnect continues
+    /// where the last connection stopped
+    pub replay: OnceLock<Arc<Mutex<ReplayTransport>>>,
+    /// What the probe of the current connection told about the device
+    pub connected: Mutex<Option<ProbeInfo>>,
+}
+
+impl Default for Context {
+    fn default() -> Self {
+        Self {
+            sequence: Sequence::default(),
+            quiet: false,
+            capture: Capture::default(),
+            progress: ProgressFormat::default(),
+            notify_progress: false,
+            request: Mutex::new(None),
+            name_rules: Some(NameRules::default()),
+            retry: Arc::default(),
+            deadline: None,
+            transcript: None,
+            recorder: None,
+            replay: OnceLock::new(),
+            connected: Mutex::new(None),
+        }
+    }
+}
@@ -0,0 +1,623 @@
+use std::collections::{HashMap, VecDeque};
+use std::error::Error;
+use std::time::Duration;
+
+use mcumgr_smp::application_management::{self, GetImageStateResult};
+use mcumgr_smp::smp::SmpFrame;
+use mcumgr_smp::ReturnCode;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
+use ratatui::layout::{Constraint, Layout, Rect};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, Paragraph};
+use ratatui::{DefaultTerminal, Frame};
+use tokio::time::Instant;
+use tracing::debug;
+
+use crate::context::Context;
+use crate::dump::timestamp;
+use crate::error::{self, CliError, EXIT_TRANSPORT};
+use crate::logs::{self, Level};
+use crate::taskstat::{self, SortBy, TaskstatOptions};
+use crate::{describe_target, flash, open_transport, output, reset, stats, Cli, UsedTransport};
+
+/// Parameters of `dashboard`
+pub struct DashboardOptions {
+    pub interval: Duration,
+    /// statistics groups to show, all groups of the device if empty
+    pub stats: Vec<String>,
+    /// stack usage in percent above which a task is highlighted
+    pub threshold: f64,
+}
+
+/// Limits of the refresh interval, changed with + and -
+const MIN_INTERVAL: Duration = Duration::from_millis(250);
+const MAX_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How often key presses are handled between refreshes
+const INPUT_POLL: Duration = Duration::from_millis(50);
+
+/// Log entries shown when the dashboard starts, and kept for scrolling back
+const LOG_BACKLOG: u32 = 100;
+const LOG_LINES: usize = 1000;
+
+/// How long to wait for the device to come back after `R`
+const RESET_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Pane {
+    Images,
+    Tasks,
+    Stats,
+    Log,
+}
+
+impl Pane {
+    const ALL: [Pane; 4] = [Pane::Images, Pane::Tasks, Pane::Stats, Pane::Log];
+
+    fn title(self) -> &'static str {
+        match self {
+            Pane::Images => " images ",
+            Pane::Tasks => " tasks ",
+            Pane::Stats => " statistics ",
+            Pane::Log => " log ",
+        }
+    }
+
+    fn index(self) -> usize {
+        self as usize
+    }
+
+    fn next(self) -> Self {
+        Pane::ALL[(self.index() + 1) % Pane::ALL.len()]
+    }
+
+    fn previous(self) -> Self {
+        Pane::ALL[(self.index() + Pane::ALL.len() - 1) % Pane::ALL.len()]
+    }
+}
+
+/// What a pane shows
+enum Content {
+    /// not queried yet
+    Loading,
+    Lines(Vec<Line<'static>>),
+    /// the device doesn't implement the group, the pane is greyed out and no longer queried
+    Unsupported,
+    Failed(String),
+}
+
+/// A command to the device that waits for `y`
+#[derive(Copy, Clone)]
+enum Action {
+    Reset,
+    Confirm,
+}
+
+impl Action {
+    fn name(self) -> &'static str {
+        match self {
+            Action::Reset => "reset",
+            Action::Confirm => "confirm",
+        }
+    }
+
+    fn prompt(self) -> &'static str {
+        match self {
+            Action::Reset => "reset the device? (y/n)",
+            Action::Confirm => "confirm the running image? (y/n)",
+        }
+    }
+}
+
+/// Whether the device doesn't implement the group of a failed request
+fn unsupported(err: &(dyn Error + 'static)) -> bool {
+    matches!(
+        err.downcast_ref::<CliError>(),
+        Some(CliError::Device { rc, .. }) if *rc == ReturnCode::NotSupported as i32
+    )
+}
+
+/// The content of a pane from the result of its query. A lost connection is returned as
+/// error, so it is reopened before the next refresh
+fn content(ret: Result<Vec<Line<'static>>, Box<dyn Error>>) -> Result<Content, Box<dyn Error>> {
+    match ret {
+        Ok(lines) => Ok(Content::Lines(lines)),
+        Err(e) if unsupported(e.as_ref()) => Ok(Content::Unsupported),
+        Err(e) if error::exit_code(e.as_ref()) == EXIT_TRANSPORT => Err(e),
+        Err(e) => Ok(Content::Failed(e.to_string())),
+    }
+}
+
+fn level_style(level: Level) -> Style {
+    match level {
+        Level::Debug => Style::new().fg(Color::DarkGray),
+        Level::Info => Style::new().fg(Color::Green),
+        Level::Warn => Style::new().fg(Color::Yellow),
+        Level::Error => Style::new().fg(Color::Red),
+        Level::Critical => Style::new().fg(Color::Red).add_modifier(Modifier::BOLD),
+    }
+}
+
+struct Dashboard {
+    options: DashboardOptions,
+    target: String,
+    interval: Duration,
+    panes: [Content; 4],
+    focus: Pane,
+    /// lines scrolled down, for the log lines scrolled back from its end
+    scroll: [u16; 4],
+    /// statistics groups, listed by the first refresh unless given with `--stat`
+    groups: Option<Vec<String>>,
+    /// module names of the log, queried by the first refresh
+    modules: Option<HashMap<u16, String>>,
+    log: VecDeque<Line<'static>>,
+    /// index of the next log entry to read
+    log_from: Option<u32>,
+    pending: Option<Action>,
+    status: String,
+    updated: Option<String>,
+}
+
+impl Dashboard {
+    fn new(cli: &Cli, options: DashboardOptions) -> Self {
+        let groups = (!options.stats.is_empty()).then(|| options.stats.clone());
+        Self {
+            interval: options.interval.clamp(MIN_INTERVAL, MAX_INTERVAL),
+            options,
+            target: describe_target(cli),
+            panes: [
+                Content::Loading,
+                Content::Loading,
+                Content::Loading,
+                Content::Loading,
+            ],
+            focus: Pane::Images,
+            scroll: [0; 4],
+            groups,
+            modules: None,
+            log: VecDeque::new(),
+            log_from: None,
+            pending: None,
+            status: String::new(),
+            updated: None,
+        }
+    }
+
+    fn pane(&self, pane: Pane) -> &Content {
+        &self.panes[pane.index()]
+    }
+
+    /// Query all panes the device supports
+    async fn refresh(
+        &mut self,
+        ctx: &Context,
+        transport: &mut UsedTransport,
+    ) -> Result<(), Box<dyn Error>> {
+        for pane in Pane::ALL {
+            if matches!(self.pane(pane), Content::Unsupported) {
+                continue;
+            }
+            let ret = match pane {
+                Pane::Images => image_lines(ctx, transport).await,
+                Pane::Tasks => task_lines(ctx, transport, self.options.threshold).await,
+                Pane::Stats => self.stat_lines(ctx, transport).await,
+                Pane::Log => self.log_lines(ctx, transport).await,
+            };
+            self.panes[pane.index()] = content(ret)?;
+        }
+        self.updated = Some(timestamp());
+        Ok(())
+    }
+
+    async fn stat_lines(
+        &mut self,
+        ctx: &Context,
+        transport: &mut UsedTransport,
+    ) -> Result<Vec<Line<'static>>, Box<dyn Error>> {
+        let groups = match &self.groups {
+            Some(groups) => groups.clone(),
+            None => self
+                .groups
+                .insert(stats::group_names(ctx, transport).await?)
+                .clone(),
+        };
+
+        let mut lines = Vec::new();
+        for group in groups {
+            lines.push(Line::styled(
+                group.clone(),
+                Style::new().add_modifier(Modifier::BOLD),
+            ));
+            match stats::read_group(ctx, transport, &group).await {
+                Ok(counters) => {
+                    let width = counters.keys().map(String::len).max().unwrap_or(0);
+                    for (name, value) in counters {
+                        lines.push(Line::from(format!("  {:<width$}  {}", name, value)));
+                    }
+                }
+                // a group that doesn't exist only fails its own lines
+                Err(e)
+                    if !unsupported(e.as_ref())
+                        && error::exit_code(e.as_ref()) != EXIT_TRANSPORT =>
+                {
+                    lines.push(Line::styled(
+                        format!("  {}", e),
+                        Style::new().fg(Color::Red),
+                    ))
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(lines)
+    }
+
+    /// Read the log entries since the last refresh and return all lines kept
+    async fn log_lines(
+        &mut self,
+        ctx: &Context,
+        transport: &mut UsedTransport,
+    ) -> Result<Vec<Line<'static>>, Box<dyn Error>> {
+        let from = match self.log_from {
+            Some(from) => from,
+            None => {
+                // a request beyond the newest entry returns only the next index
+                let (next_index, _) = logs::show(ctx, transport, None, u32::MAX).await?;
+                next_index.saturating_sub(LOG_BACKLOG)
+            }
+        };
+        if self.modules.is_none() {
+            let names = logs::module_names(ctx, transport).await;
+            self.modules = Some(names.into_iter().map(|(name, id)| (id, name)).collect());
+        }
+
+        let mut from = from;
+        loop {
+            let (next_index, logs) = logs::show(ctx, transport, None, from).await?;
+            if next_index < from {
+                self.push_log(Line::styled(
+                    "--- log index went backwards, the device rebooted or the logs were cleared ---",
+                    Style::new().fg(Color::Yellow),
+                ));
+                from = 0;
+                continue;
+            }
+
+            let mut entries: Vec<_> = logs
+                .into_iter()
+                .flat_map(|log| log.entries)
+                .filter(|entry| entry.index >= from)
+                .collect();
+            entries.sort_by_key(|entry| entry.index);
+
+            let newest = entries.last().map(|entry| entry.index);
+            for entry in entries {
+                let level = Level::from_u8(entry.level);
+                let module = self
+                    .modules
+                    .as_ref()
+                    .and_then(|modules| modules.get(&entry.module).cloned())
+                    .unwrap_or_else(|| entry.module.to_string());
+                self.push_log(Line::styled(
+                    format!(
+                        "{} {:<5} {}: {}",
+                        logs::format_ts(entry.ts),
+                        level.name(),
+                        module,
+                        logs::format_msg(&entry.msg)
+                    ),
+                    level_style(level),
+                ));
+            }
+
+            from = match newest {
+                Some(newest) => newest + 1,
+                None => next_index,
+            };
+            // the response was limited by the buffer size, fetch the rest right away
+            if newest.is_none() || from >= next_index {
+                break;
+            }
+        }
+        self.log_from = Some(from);
+        Ok(self.log.iter().cloned().collect())
+    }
+
+    fn push_log(&mut self, line: Line<'static>) {
+        if self.log.len() == LOG_LINES {
+            self.log.pop_front();
+        }
+        self.log.push_back(line);
+    }
+
+    fn scroll_by(&mut self, lines: i32) {
+        let len = match self.pane(self.focus) {
+            Content::Lines(lines) => lines.len().min(u16::MAX as usize) as i32,
+            _ => 0,
+        };
+        // the log is scrolled back from its end, so up moves away from it
+        let lines = if self.focus == Pane::Log {
+            -lines
+        } else {
+            lines
+        };
+        let scroll = &mut self.scroll[self.focus.index()];
+        *scroll = (*scroll as i32 + lines).clamp(0, len) as u16;
+    }
+
+    fn draw(&self, frame: &mut Frame) {
+        let [top, log, status] = Layout::vertical([
+            Constraint::Min(8),
+            Constraint::Percentage(40),
+            Constraint::Length(1),
+        ])
+        .areas(frame.area());
+        let [left, tasks] =
+            Layout::horizontal([Constraint::Percentage(50), Constraint::Percentage(50)]).areas(top);
+        let images_height = match self.pane(Pane::Images) {
+            // the table has a header, the borders take 2 lines
+            Content::Lines(lines) => lines.len() as u16 + 2,
+            _ => 3,
+        };
+        let [images, stats] =
+            Layout::vertical([Constraint::Length(images_height), Constraint::Min(3)]).areas(left);
+
+        self.draw_pane(frame, Pane::Images, images);
+        self.draw_pane(frame, Pane::Tasks, tasks);
+        self.draw_pane(frame, Pane::Stats, stats);
+        self.draw_pane(frame, Pane::Log, log);
+        self.draw_status(frame, status);
+    }
+
+    fn draw_pane(&self, frame: &mut Frame, pane: Pane, area: Rect) {
+        let mut block = Block::bordered().title(pane.title());
+        if pane == self.focus {
+            block = block.border_style(Style::new().fg(Color::Cyan));
+        }
+
+        let (lines, style) = match self.pane(pane) {
+            Content::Loading => (vec![Line::from("loading...")], Style::new()),
+            Content::Lines(lines) => (lines.clone(), Style::new()),
+            Content::Unsupported => (
+                vec![Line::from("not supported by the device")],
+                Style::new().fg(Color::DarkGray),
+            ),
+            Content::Failed(e) => (vec![Line::from(e.clone())], Style::new().fg(Color::Red)),
+        };
+
+        let height = area.height.saturating_sub(2);
+        let scroll = self.scroll[pane.index()];
+        let offset = match pane {
+            // follow the end of the log unless scrolled back
+            Pane::Log => (lines.len() as u16)
+                .saturating_sub(height)
+                .saturating_sub(scroll),
+            _ => scroll.min((lines.len() as u16).saturating_sub(height)),
+        };
+        let paragraph = Paragraph::new(lines)
+            .block(block.style(style))
+            .scroll((offset, 0));
+        frame.render_widget(paragraph, area);
+    }
+
+    fn draw_status(&self, frame: &mut Frame, area: Rect) {
+        let line = match self.pending {
+            Some(action) => Line::styled(
+                action.prompt(),
+                Style::new().fg(Color::Yellow).add_modifier(Modifier::BOLD),
+            ),
+            None => {
+                let mut text = format!(
+                    "{}  every {:.2}s  updated {}",
+                    self.target,
+                    self.interval.as_secs_f64(),
+                    self.updated.as_deref().unwrap_or("-")
+                );
+                if !self.status.is_empty() {
+                    text += &format!("  {}", self.status);
+                }
+                text +=
+                    "  | tab pane  ↑↓ scroll  +/- interval  r refresh  R reset  c confirm  q quit";
+                Line::from(text)
+            }
+        };
+        frame.render_widget(
+            Paragraph::new(line).style(Style::new().add_modifier(Modifier::REVERSED)),
+            area,
+        );
+    }
+}
+
+async fn image_lines(
+    ctx: &Context,
+    transport: &mut UsedTransport,
+) -> Result<Vec<Line<'static>>, Box<dyn Error>> {
+    let state = flash::get_image_state(ctx, transport).await?;
+    Ok(output::image_state_table(&state)
+        .lines()
+        .map(|line| Line::from(line.to_string()))
+        .collect())
+}
+
+async fn task_lines(
+    ctx: &Context,
+    transport: &mut UsedTransport,
+    threshold: f64,
+) -> Result<Vec<Line<'static>>, Box<dyn Error>> {
+    let tasks = taskstat::query(ctx, transport).await?;
+    let options = TaskstatOptions {
+        sort: SortBy::Stkuse,
+        watch: None,
+        threshold,
+    };
+    // without color, tasks above the threshold are marked with a trailing `!`
+    Ok(taskstat::render(&tasks, &options, false)
+        .lines()
+        .map(|line| match line.strip_suffix("  !") {
+            Some(line) => Line::styled(line.to_string(), Style::new().fg(Color::Red)),
+            None => Line::from(line.to_string()),
+        })
+        .collect())
+}
+
+/// What a key press asks for
+enum Input {
+    None,
+    Refresh,
+    Run(Action),
+    Quit,
+}
+
+fn handle_key(dashboard: &mut Dashboard, code: KeyCode, modifiers: KeyModifiers) -> Input {
+    if code == KeyCode::Char('c') && modifiers.contains(KeyModifiers::CONTROL) {
+        return Input::Quit;
+    }
+    if let Some(action) = dashboard.pending.take() {
+        return match code {
+            KeyCode::Char('y') | KeyCode::Char('Y') => Input::Run(action),
+            _ => {
+                dashboard.status = "cancelled".to_string();
+                Input::None
+            }
+        };
+    }
+
+    match code {
+        KeyCode::Char('q') | KeyCode::Esc => return Input::Quit,
+        KeyCode::Tab => dashboard.focus = dashboard.focus.next(),
+        KeyCode::BackTab => dashboard.focus = dashboard.focus.previous(),
+        KeyCode::Up => dashboard.scroll_by(-1),
+        KeyCode::Down => dashboard.scroll_by(1),
+        KeyCode::PageUp => dashboard.scroll_by(-10),
+        KeyCode::PageDown => dashboard.scroll_by(10),
+        KeyCode::Home => dashboard.scroll_by(-i32::from(u16::MAX)),
+        KeyCode::End => dashboard.scroll_by(i32::from(u16::MAX)),
+        KeyCode::Char('+') => {
+            dashboard.interval = (dashboard.interval * 2).min(MAX_INTERVAL);
+        }
+        KeyCode::Char('-') => {
+            dashboard.interval = (dashboard.interval / 2).max(MIN_INTERVAL);
+        }
+        KeyCode::Char('r') => return Input::Refresh,
+        KeyCode::Char('R') => dashboard.pending = Some(Action::Reset),
+        KeyCode::Char('c') => dashboard.pending = Some(Action::Confirm),
+        _ => {}
+    }
+    Input::None
+}
+
+/// Run a confirmed action, the message tells the outcome
+async fn run_action(
+    ctx: &Context,
+    cli: &Cli,
+    connection: &mut Option<UsedTransport>,
+    action: Action,
+) -> Result<String, Box<dyn Error>> {
+    let transport = match connection.take() {
+        Some(transport) => transport,
+        None => open_transport(ctx, cli).await?,
+    };
+
+    match action {
+        Action::Reset => {
+            let (transport, downtime) =
+                reset::reset_and_wait(ctx, cli, transport, RESET_TIMEOUT).await?;
+            *connection = Some(transport);
+            Ok(format!(
+                "device reset, back after {:.1}s",
+                downtime.as_secs_f64()
+            ))
+        }
+        Action::Confirm => {
+            let transport = connection.insert(transport);
+            let ret: SmpFrame<GetImageStateResult> = transport
+                .transceive_cbor(&application_management::confirm(None, ctx.sequence.next()))
+                .await?;
+            debug!("{:?}", ret);
+
+            match ret.data {
+                GetImageStateResult::Ok(_) => Ok("running image confirmed".to_string()),
+                GetImageStateResult::Err(err) => Err(CliError::Device {
+                    rc: err.rc,
+                    rsn: err.rsn,
+                })?,
+            }
+        }
+    }
+}
+
+/// Show the state of the device until `q` is pressed.
+///
+/// All panes are queried one after another over the connection of the command. A lost
+/// connection is reopened before the next refresh.
+pub async fn dashboard(
+    ctx: &Context,
+    cli: &Cli,
+    connection: &mut Option<UsedTransport>,
+    options: DashboardOptions,
+) -> Result<(), Box<dyn Error>> {
+    let mut terminal = ratatui::try_init()?;
+    let ret = run(ctx, cli, connection, options, &mut terminal).await;
+    ratatui::restore();
+    ret
+}
+
+async fn run(
+    ctx: &Context,
+    cli: &Cli,
+    connection: &mut Option<UsedTransport>,
+    options: DashboardOptions,
+    terminal: &mut DefaultTerminal,
+) -> Result<(), Box<dyn Error>> {
+    let mut dashboard = Dashboard::new(cli, options);
+    let mut next_refresh = Instant::now();
+
+    loop {
+        terminal.draw(|frame| dashboard.draw(frame))?;
+
+        let mut refresh = Instant::now() >= next_refresh;
+        while event::poll(Duration::ZERO)? {
+            let Event::Key(key) = event::read()? else {
+                continue;
+            };
+            if key.kind != KeyEventKind::Press {
+                continue;
+            }
+            match handle_key(&mut dashboard, key.code, key.modifiers) {
+                Input::None => {}
+                Input::Refresh => refresh = true,
+                Input::Quit => return Ok(()),
+                Input::Run(action) => {
+                    dashboard.status = format!("{}...", action.name());
+                    terminal.draw(|frame| dashboard.draw(frame))?;
+                    dashboard.status = match run_action(ctx, cli, connection, action).await {
+                        Ok(message) => message,
+                        Err(e) => format!("{} failed: {}", action.name(), e),
+                    };
+                    refresh = true;
+                }
+            }
+        }
+
+        if refresh {
+            if connection.is_none() {
+                match open_transport(ctx, cli).await {
+                    Ok(transport) => *connection = Some(transport),
+                    Err(e) => dashboard.status = format!("reconnecting failed: {}", e),
+                }
+            }
+            if let Some(transport) = connection.as_mut() {
+                if let Err(e) = dashboard.refresh(ctx, transport).await {
+                    dashboard.status = format!("lost the connection, reconnecting: {}", e);
+                    if let Some(mut transport) = connection.take() {
+                        let _ = transport.close().await;
+                    }
+                }
+            }
+            next_refresh = Instant::now() + dashboard.interval;
+            terminal.draw(|frame| dashboard.draw(frame))?;
+        }
+
+        tokio::time::sleep(INPUT_POLL).await;
+    }
+}
@@ -0,0 +1,306 @@
+use std::error::Error;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use mcumgr_smp::{
+    os_management::{self, GetDateTimeResult, SetDateTimeResult},
+    smp::SmpFrame,
+};
+use serde::Serialize;
+use tracing::debug;
+
+use crate::context::Context;
+use crate::error::CliError;
+use crate::output::OutputFormat;
+use crate::{outln, UsedTransport};
+
+const US_PER_DAY: i64 = 86_400_000_000;
+
+/// Convert days since the Unix epoch to year, month and day
+pub fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+/// Convert a date to days since the Unix epoch
+pub fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let mp = if month > 2 { month - 3 } else { month + 9 };
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+fn days_in_month(year: i64, month: i64) -> i64 {
+    match month {
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+/// The current time in microseconds since the Unix epoch
+pub fn now_us() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_micros() as i64
+}
+
+/// Format microseconds since the Unix epoch as `yyyy-MM-ddTHH:mm:ss.SSSSSS` in UTC
+pub fn format_us(us: i64) -> String {
+    let (year, month, day) = civil_from_days(us.div_euclid(US_PER_DAY));
+    let us = us.rem_euclid(US_PER_DAY);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:06}",
+        year,
+        month,
+        day,
+        us / 3_600_000_000,
+        us / 60_000_000 % 60,
+        us / 1_000_000 % 60,
+        us % 1_000_000
+    )
+}
+
+/// Parse a fixed number of digits
+fn digits(s: &str, range: std::ops::Range<usize>) -> Option<i64> {
+    let part = s.get(range)?;
+    if !part.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    part.parse().ok()
+}
+
+/// Parse a date and time like `2024-05-01T12:00:00.5+02:00` into microseconds since the
+/// Unix epoch.
+///
+/// The UTC offset may only be left out if `offset_required` is false, the time is UTC then.
+/// Devices report their time without an offset.
+pub fn parse(s: &str, offset_required: bool) -> Result<i64, String> {
+    let invalid = || {
+        format!(
+            "invalid time {}, expected an RFC 3339 time like 2024-05-01T12:00:00Z or 2024-05-01T14:00:00.250+02:00",
+            s
+        )
+    };
+
+    let bytes = s.as_bytes();
+    if bytes.len() < 19
+        || bytes[4] != b'-'
+        || bytes[7] != b'-'
+        || !matches!(bytes[10], b'T' | b't' | b' ')
+        || bytes[13] != b':'
+        || bytes[16] != b':'
+    {
+        return Err(invalid());
+    }
+
+    let year = digits(s, 0..4).ok_or_else(invalid)?;
+    let month = digits(s, 5..7).ok_or_else(invalid)?;
+    let day = digits(s, 8..10).ok_or_else(invalid)?;
+    let hour = digits(s, 11..13).ok_or_else(invalid)?;
+    let minute = digits(s, 14..16).ok_or_else(invalid)?;
+    let second = digits(s, 17..19).ok_or_else(invalid)?;
+
+    if !(1..=12).contains(&month)
+        || !(1..=days_in_month(year, month)).contains(&day)
+        || hour > 23
+        || minute > 59
+        || second > 59
+    {
+        return Err(format!("{} is not a valid date and time", s));
+    }
+
+    let mut rest = &s[19..];
+    let mut fraction_us = 0;
+    if let Some(fraction) = rest.strip_prefix('.') {
+        let len = fraction
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(fraction.len());
+        if len == 0 {
+            return Err(invalid());
+        }
+        // digits beyond microseconds are cut off
+        let us: String = fraction[..len]
+            .chars()
+            .chain("000000".chars())
+            .take(6)
+            .collect();
+        fraction_us = us.parse::<i64>().map_err(|_| invalid())?;
+        rest = &fraction[len..];
+    }
+
+    let offset_s = match rest {
+        "Z" | "z" => 0,
+        "" if !offset_required => 0,
+        "" => {
+            return Err(format!(
+                "{} has no UTC offset, append Z for UTC or e.g. +02:00",
+                s
+            ))
+        }
+        _ => {
+            let sign = match rest.as_bytes()[0] {
+                b'+' => 1,
+                b'-' => -1,
+                _ => return Err(invalid()),
+            };
+            if rest.len() != 6 || rest.as_bytes()[3] != b':' {
+                return Err(invalid());
+            }
+            let hours = digits(rest, 1..3).ok_or_else(invalid)?;
+            let minutes = digits(rest, 4..6).ok_or_else(invalid)?;
+            if hours > 23 || minutes > 59 {
+                return Err(invalid());
+            }
+            sign * (hours * 3600 + minutes * 60)
+        }
+    };
+
+    let seconds =
+        days_from_civil(year, month, day) * 86_400 + hour * 3600 + minute * 60 + second - offset_s;
+    Ok(seconds * 1_000_000 + fraction_us)
+}
+
+/// Command line parser for `os datetime set`
+pub fn parse_rfc3339(s: &str) -> Result<i64, String> {
+    parse(s, true)
+}
+
+/// The device clock compared to the host clock
+#[derive(Serialize, Debug)]
+pub struct Drift {
+    pub device: String,
+    pub host: String,
+    /// device time minus host time, positive if the device is ahead
+    pub offset_ms: f64,
+    pub rtt_ms: f64,
+}
+
+impl Drift {
+    fn print(&self, ctx: &Context, format: OutputFormat, offset_label: &str) {
+        match format {
+            OutputFormat::Text => {
+                outln!(ctx, "device: {}", self.device);
+                outln!(ctx, "host:   {}", self.host);
+                outln!(
+                    ctx,
+                    "{}: {:+.3} ms ({}), round trip {:.3} ms",
+                    offset_label,
+                    self.offset_ms,
+                    if self.offset_ms >= 0.0 {
+                        "device ahead"
+                    } else {
+                        "device behind"
+                    },
+                    self.rtt_ms
+                );
+            }
+            OutputFormat::Json => outln!(
+                ctx,
+                "{}",
+                serde_json::to_string_pretty(self).expect("serializing to string can't fail")
+            ),
+        }
+    }
+}
+
+/// Read the device clock and compare it with the host clock.
+///
+/// The device time is compared with the host time in the middle of the round trip.
+pub async fn measure(
+    ctx: &Context,
+    transport: &mut UsedTransport,
+) -> Result<Drift, Box<dyn Error>> {
+    let sent = now_us();
+    let ret: SmpFrame<GetDateTimeResult> = transport
+        .transceive_cbor(&os_management::get_datetime(ctx.sequence.next()))
+        .await?;
+    let received = now_us();
+    debug!("{:?}", ret);
+
+    let datetime = match ret.data {
+        GetDateTimeResult::Ok { datetime } => datetime,
+        GetDateTimeResult::Err { rc } => Err(CliError::device(rc))?,
+    };
+    let device = parse(&datetime, false)
+        .map_err(|e| format!("the device reported an unexpected time: {}", e))?;
+    let host = sent + (received - sent) / 2;
+
+    Ok(Drift {
+        device: format_us(device) + "Z",
+        host: format_us(host) + "Z",
+        offset_ms: (device - host) as f64 / 1000.0,
+        rtt_ms: (received - sent) as f64 / 1000.0,
+    })
+}
+
+async fn set(ctx: &Context, transport: &mut UsedTransport, us: i64) -> Result<(), Box<dyn Error>> {
+    let ret: SmpFrame<SetDateTimeResult> = transport
+        .transceive_cbor(&os_management::set_datetime(
+            ctx.sequence.next(),
+            format_us(us),
+        ))
+        .await?;
+    debug!("{:?}", ret);
+
+    match ret.data {
+        SetDateTimeResult::Ok {} => Ok(()),
+        SetDateTimeResult::Err { rc } => Err(CliError::device(rc).into()),
+    }
+}
+
+/// Print the device time and its offset to the host time
+pub async fn get(
+    ctx: &Context,
+    transport: &mut UsedTransport,
+    format: OutputFormat,
+) -> Result<(), Box<dyn Error>> {
+    measure(ctx, transport).await?.print(ctx, format, "offset");
+    Ok(())
+}
+
+/// Set the device clock to the given time in microseconds since the Unix epoch
+pub async fn set_time(
+    ctx: &Context,
+    transport: &mut UsedTransport,
+    us: i64,
+    format: OutputFormat,
+) -> Result<(), Box<dyn Error>> {
+    set(ctx, transport, us).await?;
+    match format {
+        OutputFormat::Text => outln!(ctx, "success"),
+        OutputFormat::Json => {
+            outln!(
+                ctx,
+                "{}",
+                serde_json::json!({ "datetime": format_us(us) + "Z" })
+            )
+        }
+    }
+    Ok(())
+}
+
+/// Set the device clock to the host time and report the remaining offset
+pub async fn sync(
+    ctx: &Context,
+    transport: &mut UsedTransport,
+    format: OutputFormat,
+) -> Result<(), Box<dyn Error>> {
+    set(ctx, transport, now_us()).await?;
+    measure(ctx, transport)
+        .await?
+        .print(ctx, format, "residual offset");
+    Ok(())
+}
@@ -0,0 +1,98 @@
+use std::error::Error;
+use std::io::{Cursor, Read};
+
+use serde::Deserialize;
+
+/// A single image contained in a `dfu_application.zip`
+#[derive(Debug)]
+pub struct PackageImage {
+    /// file name inside the package
+    pub name: String,
+    /// target image number
+    pub image: u8,
+    pub version: Option<String>,
+    pub data: Vec<u8>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Manifest {
+    files: Vec<ManifestFile>,
+}
+
+#[derive(Deserialize, Debug)]
+struct ManifestFile {
+    file: String,
+    #[serde(default)]
+    image_index: Option<serde_json::Value>,
+    #[serde(default, rename = "version_MCUBOOT")]
+    version_mcuboot: Option<String>,
+    #[serde(default)]
+    version: Option<String>,
+}
+
+/// Check for the zip local file header magic
+pub fn is_package(data: &[u8]) -> bool {
+    data.starts_with(b"PK\x03\x04")
+}
+
+/// Read all images of a Zephyr `dfu_application.zip`.
+///
+/// All referenced files are read up front, so a broken package is reported
+/// before anything is sent to the device.
+pub fn read_package(
+    data: Vec<u8>,
+    only: Option<&str>,
+) -> Result<Vec<PackageImage>, Box<dyn Error>> {
+    let mut archive = zip::ZipArchive::new(Cursor::new(data))
+        .map_err(|e| format!("invalid dfu package: {}", e))?;
+
+    let manifest: Manifest = {
+        let file = archive
+            .by_name("manifest.json")
+            .map_err(|e| format!("invalid dfu package: manifest.json: {}", e))?;
+        serde_json::from_reader(file)
+            .map_err(|e| format!("invalid dfu package: manifest.json: {}", e))?
+    };
+
+    if let Some(only) = only {
+        if !manifest.files.iter().any(|f| f.file == only) {
+            Err(format!("dfu package does not contain {}", only))?;
+        }
+    }
+
+    let mut images = Vec::new();
+    for entry in manifest.files {
+        if only.is_some_and(|only| only != entry.file) {
+            continue;
+        }
+
+        let image = match &entry.image_index {
+            None => Some(0),
+            Some(serde_json::Value::Number(n)) => n.as_u64().and_then(|n| n.try_into().ok()),
+            Some(serde_json::Value::String(s)) => s.parse().ok(),
+            Some(_) => None,
+        }
+        .ok_or_else(|| {
+            format!(
+                "invalid image_index for {}: {:?}",
+                entry.file, entry.image_index
+            )
+        })?;
+
+        let mut data = Vec::new();
+        archive
+            .by_name(&entry.file)
+            .map_err(|e| format!("invalid dfu package: {}: {}", entry.file, e))?
+            .read_to_end(&mut data)
+            .map_err(|e| format!("invalid dfu package: {}: {}", entry.file, e))?;
+
+        images.push(PackageImage {
+            name: entry.file,
+            image,
+            version: entry.version_mcuboot.or(entry.version),
+            data,
+        });
+    }
+
+    Ok(images)
+}
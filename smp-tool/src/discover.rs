@@ -0,0 +1,337 @@
+use std::collections::BTreeSet;
+use std::error::Error;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::time::Duration;
+
+use mcumgr_smp::os_management::{self, GetInfoResult};
+use mcumgr_smp::smp::SmpFrame;
+use mcumgr_smp::transport::smp::SmpTransportAsync;
+use mcumgr_smp::transport::udp::UdpTransportAsync;
+use serde::Serialize;
+use tokio::net::UdpSocket;
+use tokio::task::JoinSet;
+use tracing::debug;
+
+use crate::context::Context;
+use crate::error::CliError;
+use crate::frame::Header;
+use crate::output::OutputFormat;
+use crate::status;
+
+/// `--dest-host` value that selects the only device answering a discovery broadcast
+pub const AUTO: &str = "auto";
+
+/// How long `--dest-host auto` waits for answers
+const AUTO_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// How long a discovered device may take to send its identity
+const IDENTIFY_TIMEOUT: Duration = Duration::from_millis(1000);
+
+const MDNS_ADDR: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(224, 0, 0, 251)), 5353);
+/// DNS-SD service type of the SMP UDP server
+const MDNS_SERVICE: &str = "_mcumgr._udp.local";
+
+const DNS_TYPE_A: u16 = 1;
+const DNS_TYPE_PTR: u16 = 12;
+const DNS_TYPE_SRV: u16 = 33;
+
+/// How devices are found
+#[derive(Debug, Clone)]
+pub enum Method {
+    /// An echo request to each of these broadcast addresses, e.g. one per network interface
+    Broadcast(Vec<Ipv4Addr>),
+    /// A DNS-SD query for the SMP service
+    Mdns,
+}
+
+/// A device that answered the discovery
+#[derive(Serialize, Debug)]
+pub struct Device {
+    pub address: SocketAddr,
+    /// all fields of `os info`, if the device answered it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub identity: Option<String>,
+}
+
+/// Find the devices on the local network, each address only once.
+///
+/// Answers are collected until the timeout expires, so an empty network gives an empty list.
+pub async fn discover(
+    ctx: &Context,
+    method: &Method,
+    port: u16,
+    timeout: Duration,
+) -> Result<Vec<SocketAddr>, Box<dyn Error>> {
+    let deadline = tokio::time::Instant::now() + timeout;
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+
+    match method {
+        Method::Broadcast(addresses) => {
+            socket.set_broadcast(true)?;
+            let sequence = ctx.sequence.next();
+            let request = os_management::echo(sequence, "discover".to_string()).encode_with_cbor();
+
+            let addresses = if addresses.is_empty() {
+                vec![Ipv4Addr::BROADCAST]
+            } else {
+                addresses.clone()
+            };
+            for address in addresses {
+                socket
+                    .send_to(&request, (address, port))
+                    .await
+                    .map_err(|e| format!("can't send to {}: {}", address, e))?;
+            }
+
+            collect(&socket, deadline, |packet, from| {
+                let header = Header::parse(packet)?;
+                // an echo response to this request, other traffic on the port is ignored
+                (header.op == 3
+                    && header.group == 0
+                    && header.id == 0
+                    && header.sequence == sequence)
+                    .then_some(from)
+            })
+            .await
+        }
+        Method::Mdns => {
+            socket.set_multicast_ttl_v4(255)?;
+            socket
+                .send_to(&mdns_query(), MDNS_ADDR)
+                .await
+                .map_err(|e| format!("can't send the mDNS query: {}", e))?;
+
+            collect(&socket, deadline, |packet, from| {
+                let (ip, srv_port) = parse_mdns_response(packet)?;
+                Some(SocketAddr::new(
+                    ip.unwrap_or(from.ip()),
+                    srv_port.unwrap_or(port),
+                ))
+            })
+            .await
+        }
+    }
+}
+
+/// Receive until the deadline and keep the addresses `parse` returns for the packets
+async fn collect(
+    socket: &UdpSocket,
+    deadline: tokio::time::Instant,
+    parse: impl Fn(&[u8], SocketAddr) -> Option<SocketAddr>,
+) -> Result<Vec<SocketAddr>, Box<dyn Error>> {
+    let mut found = BTreeSet::new();
+    let mut buf = vec![0; 1500];
+
+    while let Ok(ret) = tokio::time::timeout_at(deadline, socket.recv_from(&mut buf)).await {
+        let (len, from) = match ret {
+            Ok(ret) => ret,
+            // e.g. an ICMP port unreachable from a host without the service
+            Err(e) => {
+                debug!("receive failed: {}", e);
+                continue;
+            }
+        };
+        if let Some(address) = parse(&buf[..len], from) {
+            found.insert(address);
+        }
+    }
+
+    Ok(found.into_iter().collect())
+}
+
+/// Ask every device for its `os info` at the same time
+pub async fn identify(ctx: &Context, addresses: Vec<SocketAddr>) -> Vec<Device> {
+    let mut tasks = JoinSet::new();
+    for address in addresses {
+        let sequence = ctx.sequence.next();
+        tasks.spawn(async move {
+            let identity = tokio::time::timeout(IDENTIFY_TIMEOUT, os_info(address, sequence))
+                .await
+                .ok()
+                .flatten();
+            Device { address, identity }
+        });
+    }
+
+    let mut devices = Vec::new();
+    while let Some(device) = tasks.join_next().await {
+        if let Ok(device) = device {
+            devices.push(device);
+        }
+    }
+    devices.sort_by_key(|device| device.address);
+    devices
+}
+
+async fn os_info(address: SocketAddr, sequence: u8) -> Option<String> {
+    // the UDP transport itself, the boxed one of CborSmpTransportAsync can't be sent to a task
+    let mut transport = UdpTransportAsync::new(address).await.ok()?;
+    let request = os_management::get_info(sequence, "a".to_string());
+    transport.send(request.encode_with_cbor()).await.ok()?;
+    let ret = SmpFrame::<GetInfoResult>::decode_with_cbor(&transport.receive().await.ok()?).ok()?;
+    if ret.sequence != request.sequence {
+        return None;
+    }
+
+    match ret.data {
+        GetInfoResult::Ok { output, .. } => Some(output.trim().to_string()),
+        GetInfoResult::Err { rc } => {
+            debug!("{} doesn't support os info: rc {}", address, rc);
+            None
+        }
+    }
+}
+
+/// List the devices answering the discovery
+pub async fn list(
+    ctx: &Context,
+    method: &Method,
+    port: u16,
+    timeout: Duration,
+    format: OutputFormat,
+) -> Result<(), Box<dyn Error>> {
+    let devices = identify(ctx, discover(ctx, method, port, timeout).await?).await;
+
+    match format {
+        OutputFormat::Text => {
+            if devices.is_empty() {
+                status!(ctx, "no devices answered");
+            }
+            for device in &devices {
+                println!(
+                    "{:<24} {}",
+                    device.address,
+                    device.identity.as_deref().unwrap_or("-")
+                );
+            }
+        }
+        OutputFormat::Json => println!(
+            "{}",
+            serde_json::to_string_pretty(&devices).expect("serializing to string can't fail")
+        ),
+    }
+
+    Ok(())
+}
+
+/// The address of the only device answering a broadcast on `port`, for `--dest-host auto`
+pub async fn auto(ctx: &Context, port: u16) -> Result<SocketAddr, Box<dyn Error>> {
+    let found = discover(ctx, &Method::Broadcast(Vec::new()), port, AUTO_TIMEOUT).await?;
+
+    match found.as_slice() {
+        [address] => {
+            debug!("discovered {}", address);
+            Ok(*address)
+        }
+        [] => Err(format!(
+            "no device answered the discovery broadcast on port {}",
+            port
+        )
+        .into()),
+        _ => {
+            let devices = identify(ctx, found).await;
+            let mut msg = format!(
+                "{} devices answered the discovery, select one with --dest-host:",
+                devices.len()
+            );
+            for device in devices {
+                msg.push_str(&format!(
+                    "\n  {:<24} {}",
+                    device.address,
+                    device.identity.as_deref().unwrap_or("-")
+                ));
+            }
+            Err(CliError::Usage(msg).into())
+        }
+    }
+}
+
+/// A DNS query for the pointers to all instances of the SMP service, answered by unicast
+fn mdns_query() -> Vec<u8> {
+    // id, flags, one question, no answer, authority or additional records
+    let mut query = vec![0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0];
+    for label in MDNS_SERVICE.split('.') {
+        query.push(label.len() as u8);
+        query.extend_from_slice(label.as_bytes());
+    }
+    query.push(0);
+    query.extend_from_slice(&DNS_TYPE_PTR.to_be_bytes());
+    // class IN with the unicast-response bit
+    query.extend_from_slice(&0x8001u16.to_be_bytes());
+    query
+}
+
+/// The IPv4 address and port of a DNS-SD response for the SMP service, `None` for other
+/// packets. The records may be missing, in that case the sender and the default port are used.
+fn parse_mdns_response(packet: &[u8]) -> Option<(Option<IpAddr>, Option<u16>)> {
+    let u16_at = |offset: usize| -> Option<u16> {
+        Some(u16::from_be_bytes(
+            packet.get(offset..offset + 2)?.try_into().ok()?,
+        ))
+    };
+
+    // only responses
+    if u16_at(2)? & 0x8000 == 0 {
+        return None;
+    }
+    let questions = u16_at(4)?;
+    let records = u16_at(6)? as usize + u16_at(8)? as usize + u16_at(10)? as usize;
+
+    let mut offset = 12;
+    for _ in 0..questions {
+        offset = read_name(packet, offset)?.1 + 4;
+    }
+
+    let mut service = false;
+    let mut ip = None;
+    let mut port = None;
+    for _ in 0..records {
+        let (name, next) = read_name(packet, offset)?;
+        let record_type = u16_at(next)?;
+        let len = u16_at(next + 8)? as usize;
+        let data = packet.get(next + 10..next + 10 + len)?;
+        offset = next + 10 + len;
+
+        match record_type {
+            DNS_TYPE_PTR if name.eq_ignore_ascii_case(MDNS_SERVICE) => service = true,
+            DNS_TYPE_SRV if data.len() >= 6 => {
+                port = port.or(Some(u16::from_be_bytes([data[4], data[5]])))
+            }
+            DNS_TYPE_A if data.len() == 4 => {
+                ip = ip.or(Some(IpAddr::V4(Ipv4Addr::new(
+                    data[0], data[1], data[2], data[3],
+                ))))
+            }
+            _ => {}
+        }
+    }
+
+    service.then_some((ip, port))
+}
+
+/// Read a possibly compressed DNS name, returning it with the offset after it
+fn read_name(packet: &[u8], mut offset: usize) -> Option<(String, usize)> {
+    let mut labels = Vec::new();
+    let mut end = None;
+    // compression pointers must not loop
+    for _ in 0..128 {
+        let len = *packet.get(offset)? as usize;
+        match len {
+            0 => {
+                let name = labels.join(".");
+                return Some((name, end.unwrap_or(offset + 1)));
+            }
+            len if len & 0xc0 == 0xc0 => {
+                let pointer = ((len & 0x3f) << 8) | *packet.get(offset + 1)? as usize;
+                end.get_or_insert(offset + 2);
+                offset = pointer;
+            }
+            len => {
+                let label = packet.get(offset + 1..offset + 1 + len)?;
+                labels.push(String::from_utf8_lossy(label).into_owned());
+                offset += 1 + len;
+            }
+        }
+    }
+    None
+}
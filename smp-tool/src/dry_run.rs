@@ -0,0 +1,581 @@
+use std::cmp::min;
+use std::error::Error;
+
+use mcumgr_smp::{
+    application_management, fs_management, log_management, os_management, setting_management,
+    shell_management, smp::SmpFrame, stat_management,
+};
+
+use crate::context::Context;
+use crate::error::CliError;
+use crate::{
+    datetime, echo, flash, frame, fs, image, probe, script, settings, shell, ApplicationCmd, Cli,
+    Commands, DatetimeCmd, FsCmd, LogCmd, OsCmd, SettingCmd, ShellCmd, StatCmd, UdpCmd,
+};
+
+/// Print the requests a command would send, without connecting to a device
+pub fn dry_run(ctx: &Context, cli: &Cli) -> Result<(), Box<dyn Error>> {
+    let verbose = cli.verbose > 0;
+
+    match &cli.command {
+        Commands::Os(OsCmd::Echo {
+            msg, count, size, ..
+        }) => {
+            let msg = match size {
+                Some(size) => echo::payload(*size, 0),
+                None => msg.clone().unwrap_or_default(),
+            };
+            print_request(&os_management::echo(ctx.sequence.next(), msg), verbose);
+            if let Some(count) = count.filter(|count| *count > 1) {
+                println!("then {} more echo requests", count - 1);
+            }
+        }
+        Commands::Os(OsCmd::Ping { count, .. }) => {
+            print_request(
+                &os_management::echo(ctx.sequence.next(), "ping".to_string()),
+                verbose,
+            );
+            match count {
+                Some(count) if *count > 1 => println!("then {} more echo requests", count - 1),
+                Some(_) => {}
+                None => println!("then more echo requests until Ctrl-C is pressed"),
+            }
+        }
+        Commands::Os(OsCmd::Taskstat { watch, .. }) => {
+            print_request(&os_management::task_stats(ctx.sequence.next()), verbose);
+            if watch.is_some() {
+                println!("then the same request periodically");
+            }
+        }
+        Commands::Os(OsCmd::Info { format }) => {
+            for letter in probe::info_letters(format.as_deref().unwrap_or("a")) {
+                print_request(
+                    &os_management::get_info(ctx.sequence.next(), letter.to_string()),
+                    verbose,
+                );
+            }
+        }
+        Commands::Os(OsCmd::BootloaderInfo { query }) => {
+            print_request(
+                &os_management::bootloader_info(ctx.sequence.next(), query.clone()),
+                verbose,
+            );
+        }
+        Commands::Os(OsCmd::Datetime { action: None }) => {
+            print_request(&os_management::get_datetime(ctx.sequence.next()), verbose);
+        }
+        Commands::Os(OsCmd::Datetime {
+            action: Some(DatetimeCmd::Set { time }),
+        }) => {
+            print_request(
+                &os_management::set_datetime(ctx.sequence.next(), datetime::format_us(*time)),
+                verbose,
+            );
+        }
+        Commands::Os(OsCmd::Datetime {
+            action: Some(DatetimeCmd::Sync),
+        }) => {
+            print_request(
+                &os_management::set_datetime(
+                    ctx.sequence.next(),
+                    datetime::format_us(datetime::now_us()),
+                ),
+                verbose,
+            );
+            println!("then a read of the device time to report the remaining offset");
+        }
+        Commands::Os(OsCmd::Reset { .. }) => {
+            print_request(&os_management::reset(ctx.sequence.next(), false), verbose);
+        }
+        Commands::Shell(ShellCmd::Exec { cmd, command, .. }) => {
+            let argv = shell::exec_argv(cmd, command.as_deref())?;
+            print_request(
+                &shell_management::shell_command(ctx.sequence.next(), argv),
+                verbose,
+            );
+        }
+        Commands::Shell(ShellCmd::Interactive) => {
+            Err(CliError::Usage(
+                "the interactive shell can't be used with --dry-run".to_string(),
+            ))?;
+        }
+        Commands::App(ApplicationCmd::Info) | Commands::App(ApplicationCmd::Compare { .. }) => {
+            print_request(
+                &application_management::get_state(ctx.sequence.next()),
+                verbose,
+            );
+        }
+        Commands::App(ApplicationCmd::Test { hash, slot, image }) => match (hash, slot) {
+            (Some(hash), _) => {
+                let hash = image::parse_hex(hash)?;
+                print_request(
+                    &application_management::set_pending(hash, ctx.sequence.next()),
+                    verbose,
+                );
+            }
+            (None, slot) => {
+                print_request(
+                    &application_management::get_state(ctx.sequence.next()),
+                    verbose,
+                );
+                println!(
+                    "then a set-pending request for the hash found in image {} slot {}",
+                    image,
+                    slot.unwrap_or_default()
+                );
+            }
+        },
+        Commands::App(ApplicationCmd::Confirm { hash }) => {
+            let hash = hash.as_deref().map(image::parse_hex).transpose()?;
+            print_request(
+                &application_management::confirm(hash, ctx.sequence.next()),
+                verbose,
+            );
+        }
+        Commands::App(ApplicationCmd::Erase { slot }) => {
+            print_request(
+                &application_management::erase_image(*slot, ctx.sequence.next()),
+                verbose,
+            );
+        }
+        Commands::App(ApplicationCmd::Flash {
+            update_file,
+            images,
+            slot,
+            chunk_size,
+            upgrade,
+            test,
+            confirm,
+            only,
+            erase_first,
+            verify,
+            skip_if_same,
+            skip_if_newer,
+            ..
+        }) => {
+            let images =
+                flash::load_images(ctx, update_file.as_deref(), images, *slot, only.as_deref())?;
+            for img in &images {
+                println!("flashing {}", img.name);
+                if *skip_if_same || *skip_if_newer {
+                    print_request(
+                        &application_management::get_state(ctx.sequence.next()),
+                        verbose,
+                    );
+                }
+                if *erase_first {
+                    let erase_slot = img.image.unwrap_or(0) as u32 * 2 + 1;
+                    print_request(
+                        &application_management::erase_image(Some(erase_slot), ctx.sequence.next()),
+                        verbose,
+                    );
+                }
+                print_upload(ctx, &img.data, img.image, *chunk_size, *upgrade, verbose);
+                if *verify {
+                    print_request(
+                        &application_management::get_state(ctx.sequence.next()),
+                        verbose,
+                    );
+                }
+            }
+            // the images are marked once all of them are uploaded
+            if *test || *confirm {
+                for img in &images {
+                    print_request(
+                        &application_management::get_state(ctx.sequence.next()),
+                        verbose,
+                    );
+                    print_request(
+                        &application_management::set_state(
+                            flash::image_hash(&img.data),
+                            *confirm,
+                            ctx.sequence.next(),
+                        ),
+                        verbose,
+                    );
+                }
+            }
+        }
+        Commands::App(ApplicationCmd::Update {
+            update_file,
+            slot,
+            chunk_size,
+            upgrade,
+            no_confirm,
+            skip_if_same,
+            skip_if_newer,
+            ..
+        }) => {
+            let firmware = std::fs::read(update_file)?;
+            let hash = flash::image_hash(&firmware);
+
+            if *skip_if_same || *skip_if_newer {
+                print_request(
+                    &application_management::get_state(ctx.sequence.next()),
+                    verbose,
+                );
+            }
+            // without a device the chunk size can't be negotiated
+            let chunk_size = chunk_size.unwrap_or(fs::DEFAULT_CHUNK_SIZE);
+            print_upload(ctx, &firmware, *slot, chunk_size, *upgrade, verbose);
+            print_request(
+                &application_management::get_state(ctx.sequence.next()),
+                verbose,
+            );
+            print_request(
+                &application_management::set_pending(hash.clone(), ctx.sequence.next()),
+                verbose,
+            );
+            print_request(&os_management::reset(ctx.sequence.next(), false), verbose);
+            println!("then image state requests until the new image is active");
+            if !no_confirm {
+                print_request(
+                    &application_management::confirm(Some(hash), ctx.sequence.next()),
+                    verbose,
+                );
+            }
+        }
+        Commands::Setting(SettingCmd::Read { name, .. }) => {
+            settings::check_name(ctx, name)?;
+            print_request(
+                &setting_management::read_setting(ctx.sequence.next(), name.clone()),
+                verbose,
+            );
+        }
+        Commands::Setting(SettingCmd::WriteString { name, val }) => {
+            settings::check_name(ctx, name)?;
+            print_request(
+                &setting_management::write_setting(ctx.sequence.next(), name.clone(), val.as_str()),
+                verbose,
+            );
+        }
+        Commands::Setting(SettingCmd::WriteInt {
+            name,
+            val,
+            width,
+            endian,
+        }) => {
+            settings::check_name(ctx, name)?;
+            let val = settings::int_arg(*val, *width, *endian)?;
+            print_request(
+                &setting_management::write_setting(ctx.sequence.next(), name.clone(), val),
+                verbose,
+            );
+        }
+        Commands::Setting(SettingCmd::WriteBytes { name, val }) => {
+            settings::check_name(ctx, name)?;
+            let val = settings::parse_bytes_arg(val)?;
+            print_request(
+                &setting_management::write_setting(ctx.sequence.next(), name.clone(), val),
+                verbose,
+            );
+        }
+        Commands::Setting(SettingCmd::Save {}) => {
+            print_request(
+                &setting_management::save_setting(ctx.sequence.next()),
+                verbose,
+            );
+        }
+        Commands::Setting(SettingCmd::Export {
+            names, names_file, ..
+        })
+        | Commands::Setting(SettingCmd::ReadMany {
+            names, names_file, ..
+        }) => {
+            for name in settings::read_names(ctx, names, names_file.as_deref())? {
+                print_request(
+                    &setting_management::read_setting(ctx.sequence.next(), name),
+                    verbose,
+                );
+            }
+        }
+        Commands::Setting(SettingCmd::Import { file, save }) => {
+            for (name, value) in settings::read_document(file)? {
+                settings::check_name(ctx, &name)?;
+                let val = value
+                    .to_bytes()
+                    .map_err(|e| format!("invalid value for {}: {}", name, e))?;
+                print_request(
+                    &setting_management::write_setting(ctx.sequence.next(), name, val),
+                    verbose,
+                );
+            }
+            if *save {
+                print_request(
+                    &setting_management::save_setting(ctx.sequence.next()),
+                    verbose,
+                );
+            }
+        }
+        Commands::Raw {
+            op,
+            group,
+            id,
+            payload,
+            payload_cbor_hex,
+            seq,
+        } => {
+            let payload = frame::build_payload(payload.as_deref(), payload_cbor_hex.as_deref())?;
+            print_bytes(
+                &frame::encode_frame(
+                    *op,
+                    *group,
+                    *id,
+                    seq.unwrap_or_else(|| ctx.sequence.next()),
+                    payload,
+                ),
+                verbose,
+            );
+        }
+        Commands::Bench {
+            payload_size,
+            count,
+            upload_bytes,
+            chunk_size,
+        } => {
+            print_request(
+                &os_management::echo(ctx.sequence.next(), "x".repeat(*payload_size)),
+                verbose,
+            );
+            if *count > 1 {
+                println!("then {} more echo requests", count - 1);
+            }
+            if let Some(upload_bytes) = upload_bytes {
+                let data: Vec<u8> = (0..*upload_bytes).map(|i| i as u8).collect();
+                print_upload(ctx, &data, None, *chunk_size, false, verbose);
+            }
+        }
+        Commands::Probe => {
+            for letter in probe::info_letters("a") {
+                print_request(
+                    &os_management::get_info(ctx.sequence.next(), letter.to_string()),
+                    verbose,
+                );
+            }
+            print_request(&os_management::mcumgr_params(ctx.sequence.next()), verbose);
+            print_request(
+                &os_management::bootloader_info(ctx.sequence.next(), None),
+                verbose,
+            );
+            println!("then the mode query if the bootloader is MCUboot");
+            print_request(
+                &application_management::get_state(ctx.sequence.next()),
+                verbose,
+            );
+        }
+        Commands::Udp(UdpCmd::Discover { mdns: true, .. }) => {
+            println!("an mDNS query for _mcumgr._udp.local");
+            println!("then os info to every device that answers");
+        }
+        Commands::Udp(UdpCmd::Discover { broadcast, .. }) => {
+            print_request(
+                &os_management::echo(ctx.sequence.next(), "discover".to_string()),
+                verbose,
+            );
+            if broadcast.is_empty() {
+                println!("sent to 255.255.255.255 port {}", cli.udp_port);
+            }
+            for address in broadcast {
+                println!("sent to {} port {}", address, cli.udp_port);
+            }
+            print_request(
+                &os_management::get_info(ctx.sequence.next(), "a".to_string()),
+                verbose,
+            );
+            println!("to every device that answers");
+        }
+        Commands::Fs(FsCmd::Download {
+            remote,
+            offset,
+            length,
+            ..
+        }) => {
+            print_request(
+                &fs_management::download(ctx.sequence.next(), remote.clone(), *offset),
+                verbose,
+            );
+            match length {
+                Some(length) => println!(
+                    "then more requests until {} bytes are read or the file ends",
+                    length
+                ),
+                None => println!("then more requests until the file is complete"),
+            }
+        }
+        Commands::Fs(FsCmd::Tail { remote, bytes }) => {
+            print_request(
+                &fs_management::status(ctx.sequence.next(), remote.clone()),
+                verbose,
+            );
+            println!(
+                "then download requests for the last {} bytes of the file",
+                bytes
+            );
+        }
+        Commands::Fs(FsCmd::Upload {
+            local,
+            remote,
+            chunk_size,
+            ..
+        }) => {
+            let data = std::fs::read(local)
+                .map_err(|e| format!("can't read {}: {}", local.display(), e))?;
+            // without a device the chunk size can't be negotiated
+            let chunk_size = chunk_size.unwrap_or(fs::DEFAULT_CHUNK_SIZE);
+            let first = &data[0..min(data.len(), chunk_size)];
+            print_request(
+                &fs_management::upload(
+                    ctx.sequence.next(),
+                    remote.clone(),
+                    0,
+                    first,
+                    Some(data.len() as u64),
+                ),
+                verbose,
+            );
+            let remaining = data.len() - first.len();
+            if remaining > 0 {
+                println!(
+                    "then {} more chunks of {} bytes",
+                    remaining.div_ceil(chunk_size),
+                    chunk_size
+                );
+            }
+        }
+        Commands::Log(LogCmd::Tail { follow, log, .. }) => {
+            print_request(
+                &log_management::show(ctx.sequence.next(), log.clone(), u32::MAX),
+                verbose,
+            );
+            println!("then requests for the newest entries");
+            if *follow {
+                println!("then the same request periodically");
+            }
+        }
+        Commands::Log(LogCmd::Clear) => {
+            print_request(&log_management::clear(ctx.sequence.next()), verbose);
+        }
+        Commands::Stat(StatCmd::List) => {
+            print_request(&stat_management::list_groups(ctx.sequence.next()), verbose);
+        }
+        Commands::Stat(StatCmd::Read { group, poll, .. }) => {
+            print_request(
+                &stat_management::group_data(ctx.sequence.next(), group.clone()),
+                verbose,
+            );
+            if poll.is_some() {
+                println!("then the same request periodically");
+            }
+        }
+        Commands::Fs(FsCmd::Stat { remote }) => {
+            print_request(
+                &fs_management::status(ctx.sequence.next(), remote.clone()),
+                verbose,
+            );
+        }
+        Commands::Fs(FsCmd::Ls { path, .. }) => {
+            print_request(
+                &shell_management::shell_command(ctx.sequence.next(), fs::ls_argv(path)),
+                verbose,
+            );
+        }
+        Commands::Fs(FsCmd::Hash {
+            remote, hash_type, ..
+        }) => {
+            if hash_type.is_none() {
+                print_request(
+                    &fs_management::supported_hashes(ctx.sequence.next()),
+                    verbose,
+                );
+            }
+            print_request(
+                &fs_management::hash(
+                    ctx.sequence.next(),
+                    remote.clone(),
+                    hash_type.map(|t| t.name().to_string()),
+                ),
+                verbose,
+            );
+        }
+        Commands::Run {
+            script, commands, ..
+        } => {
+            for step in script::load_steps(script.as_deref(), commands.as_deref())? {
+                println!("# {}", step.text);
+                dry_run(
+                    ctx,
+                    &Cli {
+                        command: step.command,
+                        ..cli.clone()
+                    },
+                )?;
+            }
+        }
+        Commands::Agent { .. } => {
+            Err(CliError::Usage(
+                "`agent` can't be used with --dry-run".to_string(),
+            ))?;
+        }
+        Commands::Serve { .. } => {
+            Err(CliError::Usage(
+                "`serve` can't be used with --dry-run".to_string(),
+            ))?;
+        }
+        Commands::Proxy { .. } => {
+            Err(CliError::Usage(
+                "`proxy` can't be used with --dry-run".to_string(),
+            ))?;
+        }
+        Commands::Dashboard { .. } => {
+            Err(CliError::Usage(
+                "`dashboard` can't be used with --dry-run".to_string(),
+            ))?;
+        }
+        Commands::Decode { .. } | Commands::Encode { .. } | Commands::Profiles(_) => {
+            unreachable!("handled without a transport")
+        }
+    }
+
+    Ok(())
+}
+
+/// Print the first chunk of an upload and a summary of the rest
+fn print_upload(
+    ctx: &Context,
+    firmware: &[u8],
+    image: Option<u8>,
+    chunk_size: usize,
+    upgrade: bool,
+    verbose: bool,
+) {
+    let hash = <sha2::Sha256 as sha2::Digest>::digest(firmware).to_vec();
+    let mut writer =
+        application_management::ImageWriter::new(image, firmware.len(), Some(&hash), upgrade);
+
+    let first = &firmware[0..min(firmware.len(), chunk_size)];
+    let mut request = writer.write_chunk(first);
+    request.sequence = ctx.sequence.next();
+    print_request(&request, verbose);
+
+    let remaining = firmware.len() - first.len();
+    if remaining > 0 {
+        println!(
+            "then {} more chunks of {} bytes",
+            remaining.div_ceil(chunk_size),
+            chunk_size
+        );
+    }
+}
+
+fn print_request<T: serde::Serialize>(frame: &SmpFrame<T>, verbose: bool) {
+    print_bytes(&frame.encode_with_cbor(), verbose);
+}
+
+fn print_bytes(bytes: &[u8], verbose: bool) {
+    println!("request:");
+    frame::print_frame(bytes);
+    if verbose {
+        println!("{}", image::hex(bytes));
+    }
+    println!();
+}
@@ -0,0 +1,161 @@
+use std::fmt::Write;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use mcumgr_smp::transport::observer::{Direction, ObservedTransport, TransportObserver};
+use mcumgr_smp::transport::smp::{SmpTransport, SmpTransportAsync};
+
+use crate::frame::Header;
+
+/// Prints every frame to stderr as a header summary and a hex dump
+pub struct FrameDump {
+    /// bytes shown per frame, 0 shows everything
+    limit: usize,
+}
+
+/// An observer dumping every frame, at most `limit` bytes of it
+pub fn observer(limit: usize) -> Arc<dyn TransportObserver> {
+    Arc::new(FrameDump { limit })
+}
+
+impl FrameDump {
+    fn print(&self, direction: Direction, title: &str, bytes: &[u8]) {
+        let arrow = match direction {
+            Direction::Sent => ">>",
+            Direction::Received => "<<",
+        };
+        let shown = match self.limit {
+            0 => bytes,
+            limit => &bytes[..bytes.len().min(limit)],
+        };
+
+        // one write, so the lines of concurrent dumps don't interleave
+        let timestamp = timestamp();
+        let mut out = format!("{} {} {}\n", timestamp, arrow, title);
+        for line in hex_dump(shown) {
+            let _ = writeln!(out, "{} {}   {}", timestamp, arrow, line);
+        }
+        if shown.len() < bytes.len() {
+            let _ = writeln!(
+                out,
+                "{} {}   ... {} more bytes",
+                timestamp,
+                arrow,
+                bytes.len() - shown.len()
+            );
+        }
+        eprint!("{}", out);
+    }
+}
+
+impl TransportObserver for FrameDump {
+    fn frame(&self, direction: Direction, frame: &[u8]) {
+        let title = match Header::parse(frame) {
+            Some(header) => format!("{} bytes, {}", frame.len(), header),
+            None => format!("{} bytes, too short for a header", frame.len()),
+        };
+        self.print(direction, &title, frame);
+    }
+
+    fn encapsulated(&self, direction: Direction, bytes: &[u8]) {
+        self.print(
+            direction,
+            &format!("{} bytes on the wire", bytes.len()),
+            bytes,
+        );
+    }
+}
+
+/// Reports frames to several observers
+struct Observers(Vec<Arc<dyn TransportObserver>>);
+
+impl TransportObserver for Observers {
+    fn frame(&self, direction: Direction, frame: &[u8]) {
+        for observer in &self.0 {
+            observer.frame(direction, frame);
+        }
+    }
+
+    fn encapsulated(&self, direction: Direction, bytes: &[u8]) {
+        for observer in &self.0 {
+            observer.encapsulated(direction, bytes);
+        }
+    }
+}
+
+/// A single observer reporting to all given ones, `None` if there are none
+pub fn combine(
+    mut observers: Vec<Arc<dyn TransportObserver>>,
+) -> Option<Arc<dyn TransportObserver>> {
+    match observers.len() {
+        0 | 1 => observers.pop(),
+        _ => Some(Arc::new(Observers(observers))),
+    }
+}
+
+/// Wrap a transport, so its frames are reported to the observer if there is one
+pub fn observe<T: SmpTransport + 'static>(
+    transport: T,
+    observer: Option<&Arc<dyn TransportObserver>>,
+) -> Box<dyn SmpTransport> {
+    match observer {
+        Some(observer) => Box::new(ObservedTransport::new(transport, observer.clone())),
+        None => Box::new(transport),
+    }
+}
+
+/// Like [observe], for async transports
+pub fn observe_async<T: SmpTransportAsync + Send + 'static>(
+    transport: T,
+    observer: Option<&Arc<dyn TransportObserver>>,
+) -> Box<dyn SmpTransportAsync> {
+    match observer {
+        Some(observer) => Box::new(ObservedTransport::new(transport, observer.clone())),
+        None => Box::new(transport),
+    }
+}
+
+/// Wall clock time of day in UTC with milliseconds, like the timestamps of the log output
+pub fn timestamp() -> String {
+    let ms = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis()
+        % (24 * 60 * 60 * 1000);
+
+    format!(
+        "{:02}:{:02}:{:02}.{:03}",
+        ms / (60 * 60 * 1000),
+        ms / (60 * 1000) % 60,
+        ms / 1000 % 60,
+        ms % 1000
+    )
+}
+
+/// Format bytes like `hexdump -C`: offset, 16 bytes in hex and the printable characters
+fn hex_dump(bytes: &[u8]) -> Vec<String> {
+    bytes
+        .chunks(16)
+        .enumerate()
+        .map(|(i, chunk)| {
+            let mut hex = String::new();
+            for (j, b) in chunk.iter().enumerate() {
+                if j == 8 {
+                    hex.push(' ');
+                }
+                let _ = write!(hex, "{:02x} ", b);
+            }
+            let ascii: String = chunk
+                .iter()
+                .map(|&b| {
+                    if b.is_ascii_graphic() || b == b' ' {
+                        b as char
+                    } else {
+                        '.'
+                    }
+                })
+                .collect();
+            format!("{:08x}  {:<49} |{}|", i * 16, hex, ascii)
+        })
+        .collect()
+}
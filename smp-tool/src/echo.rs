@@ -0,0 +1,236 @@
+use std::error::Error;
+use std::io::ErrorKind;
+use std::time::Duration;
+
+use serde::Serialize;
+
+use crate::context::Context;
+use crate::error::CliError;
+use crate::output::OutputFormat;
+use crate::{outln, ping, UsedTransport};
+
+const PAYLOAD_CHARS: &[u8] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+
+/// Parameters of a repeated `os echo`
+pub struct EchoOptions {
+    pub msg: Option<String>,
+    pub count: u64,
+    pub size: Option<usize>,
+    pub interval: Duration,
+    /// How long to wait for each response
+    pub timeout: Duration,
+}
+
+/// Generate the payload of the `index`th request. The pattern is shifted with every request,
+/// so a response to an earlier request doesn't match by accident
+pub fn payload(size: usize, index: u64) -> String {
+    let shift = (index % PAYLOAD_CHARS.len() as u64) as usize;
+    (0..size)
+        .map(|i| PAYLOAD_CHARS[(i + shift) % PAYLOAD_CHARS.len()] as char)
+        .collect()
+}
+
+/// The outcome of a single request, printed as one JSON line per request
+#[derive(Serialize, Debug)]
+struct Reply {
+    seq: u64,
+    status: Status,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    time_ms: Option<f64>,
+    sent_len: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    received_len: Option<usize>,
+    /// byte offset of the first difference, if the lengths match
+    #[serde(skip_serializing_if = "Option::is_none")]
+    first_difference: Option<usize>,
+}
+
+#[derive(Serialize, Debug, Copy, Clone, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum Status {
+    Ok,
+    Mismatch,
+    Lost,
+}
+
+/// Statistics printed when the requests are done
+#[derive(Serialize, Debug, Default)]
+struct Summary {
+    transmitted: u64,
+    received: u64,
+    matched: u64,
+    mismatched: u64,
+    lost: u64,
+    loss_percent: f64,
+}
+
+fn compare(sent: &str, received: &str) -> (Status, Option<usize>) {
+    if sent == received {
+        return (Status::Ok, None);
+    }
+    if sent.len() != received.len() {
+        return (Status::Mismatch, None);
+    }
+    let offset = sent.bytes().zip(received.bytes()).position(|(a, b)| a != b);
+    (Status::Mismatch, offset)
+}
+
+impl Reply {
+    fn print(&self, ctx: &Context, format: OutputFormat) {
+        match format {
+            OutputFormat::Text => {
+                let time = self
+                    .time_ms
+                    .map(|time_ms| format!(" time={:.2} ms", time_ms))
+                    .unwrap_or_default();
+                match (self.status, self.received_len, self.first_difference) {
+                    (Status::Ok, ..) => outln!(ctx, "seq={} ok{}", self.seq, time),
+                    (Status::Lost, ..) => outln!(ctx, "seq={} lost", self.seq),
+                    (Status::Mismatch, _, Some(offset)) => outln!(
+                        ctx,
+                        "seq={} mismatch: content differs at byte {}{}",
+                        self.seq,
+                        offset,
+                        time
+                    ),
+                    (Status::Mismatch, received_len, None) => outln!(
+                        ctx,
+                        "seq={} mismatch: sent {} bytes, received {} bytes{}",
+                        self.seq,
+                        self.sent_len,
+                        received_len.unwrap_or(0),
+                        time
+                    ),
+                }
+            }
+            OutputFormat::Json => outln!(
+                ctx,
+                "{}",
+                serde_json::to_string(self).expect("serializing to string can't fail")
+            ),
+        }
+    }
+}
+
+/// Send echo requests, compare every response with its request and print a summary.
+///
+/// Lost requests and responses that differ from the request, e.g. because the device truncated
+/// them, make the command fail after the summary was printed.
+pub async fn echo(
+    ctx: &Context,
+    transport: &mut UsedTransport,
+    options: &EchoOptions,
+    format: OutputFormat,
+) -> Result<(), Box<dyn Error>> {
+    if options.count == 0 {
+        Err(CliError::Usage("--count must be at least 1".to_string()))?;
+    }
+
+    let mut summary = Summary::default();
+    let mut failure = None;
+
+    let ctrl_c = tokio::signal::ctrl_c();
+    tokio::pin!(ctrl_c);
+
+    for index in 0..options.count {
+        if index > 0 && !options.interval.is_zero() {
+            tokio::select! {
+                _ = &mut ctrl_c => break,
+                _ = tokio::time::sleep(options.interval) => {}
+            }
+        }
+
+        let sent = match (&options.msg, options.size) {
+            (_, Some(size)) => payload(size, index),
+            (Some(msg), None) => msg.clone(),
+            (None, None) => String::new(),
+        };
+
+        let ret = tokio::select! {
+            biased;
+            _ = &mut ctrl_c => break,
+            ret = ping::echo(transport, ctx.sequence.next(), sent.clone(), options.timeout) => ret,
+        };
+        summary.transmitted += 1;
+        let response = match ret {
+            Ok(response) => response,
+            Err(e) => {
+                failure = Some(e);
+                break;
+            }
+        };
+
+        let reply = match response {
+            Some((rtt, received)) => {
+                summary.received += 1;
+                let (status, first_difference) = compare(&sent, &received);
+                match status {
+                    Status::Ok => summary.matched += 1,
+                    _ => summary.mismatched += 1,
+                }
+                Reply {
+                    seq: index,
+                    status,
+                    time_ms: Some(rtt.as_secs_f64() * 1000.0),
+                    sent_len: sent.len(),
+                    received_len: Some(received.len()),
+                    first_difference,
+                }
+            }
+            None => {
+                summary.lost += 1;
+                Reply {
+                    seq: index,
+                    status: Status::Lost,
+                    time_ms: None,
+                    sent_len: sent.len(),
+                    received_len: None,
+                    first_difference: None,
+                }
+            }
+        };
+        reply.print(ctx, format);
+    }
+
+    summary.loss_percent = ping::loss_percent(summary.transmitted, summary.received);
+    match format {
+        OutputFormat::Text => {
+            outln!(ctx);
+            outln!(
+                ctx,
+                "{} requests transmitted, {} received, {} matched, {} mismatched, {:.1}% loss",
+                summary.transmitted,
+                summary.received,
+                summary.matched,
+                summary.mismatched,
+                summary.loss_percent
+            );
+        }
+        OutputFormat::Json => outln!(
+            ctx,
+            "{}",
+            serde_json::to_string(&summary).expect("serializing to string can't fail")
+        ),
+    }
+
+    if let Some(e) = failure {
+        return Err(e);
+    }
+    if summary.mismatched > 0 {
+        Err(format!(
+            "{} of {} echo responses didn't match the request",
+            summary.mismatched, summary.received
+        ))?;
+    }
+    if summary.lost > 0 {
+        Err(std::io::Error::new(
+            ErrorKind::TimedOut,
+            format!(
+                "{} of {} echo requests lost",
+                summary.lost, summary.transmitted
+            ),
+        ))?;
+    }
+
+    Ok(())
+}
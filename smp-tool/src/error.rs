@@ -0,0 +1,270 @@
+use std::error::Error;
+use std::io::ErrorKind;
+
+use mcumgr_smp::ReturnCode;
+
+use crate::output::{self, OutputFormat};
+
+/// Process exit codes, see [EXIT_CODES_HELP]
+pub const EXIT_OTHER: u8 = 1;
+pub const EXIT_USAGE: u8 = 2;
+pub const EXIT_TRANSPORT: u8 = 3;
+pub const EXIT_TIMEOUT: u8 = 4;
+pub const EXIT_DEVICE: u8 = 5;
+pub const EXIT_UPGRADE_AVAILABLE: u8 = 10;
+pub const EXIT_DEVICE_NEWER: u8 = 11;
+pub const EXIT_VERSION_UNKNOWN: u8 = 12;
+pub const EXIT_INTERRUPTED: u8 = 130;
+
+pub const EXIT_CODES_HELP: &str = "\
+Exit codes:
+  0  success
+  1  other error, e.g. an unreadable file
+  2  usage error
+  3  transport error
+  4  timeout waiting for the device
+  5  the device returned an error rc
+ 10  `app compare`: the file is newer than the image on the device
+ 11  `app compare`: the device runs a newer image
+ 12  `app compare`: the versions can't be compared
+130  a transfer was stopped with Ctrl-C, the message tells how to continue it
+`shell exec` exits with the status of the remote command instead, see `shell exec --help`";
+
+#[derive(thiserror::Error, Debug)]
+pub enum CliError {
+    #[error("{0}")]
+    Usage(String),
+    #[error("can't connect: {0}")]
+    Connect(Box<dyn Error>),
+    #[error("{}", format_device_error(.rc, .rsn))]
+    Device { rc: i32, rsn: Option<String> },
+    /// A remote shell command failed, its output was already printed
+    #[error("remote command exited with status {0}")]
+    RemoteStatus(u8),
+    /// The result of a query encoded in the exit code, e.g. the verdict of `app compare`.
+    /// It was already printed
+    #[error("exit status {0}")]
+    Verdict(u8),
+    /// The user stopped a transfer, the message tells how to continue it
+    #[error("{0}")]
+    Interrupted(String),
+    /// An error with a hint at its likely cause, which is printed on its own line
+    #[error("{source}")]
+    Hint {
+        hint: &'static str,
+        #[source]
+        source: Box<dyn Error>,
+    },
+    /// An error with an explanation of how to recover from it
+    #[error("{msg}")]
+    Context {
+        msg: String,
+        #[source]
+        source: Box<dyn Error>,
+    },
+}
+
+impl CliError {
+    pub fn device(rc: i32) -> Self {
+        CliError::Device { rc, rsn: None }
+    }
+
+    /// Add a hint to a device error with the given rc, other errors are kept as they are
+    pub fn hint_on(self, rc: ReturnCode, hint: &'static str) -> Self {
+        match self {
+            CliError::Device { rc: device_rc, .. } if device_rc == rc as i32 => CliError::Hint {
+                hint,
+                source: Box::new(self),
+            },
+            _ => self,
+        }
+    }
+
+    pub fn context(source: Box<dyn Error>, msg: impl FnOnce(&dyn Error) -> String) -> Self {
+        CliError::Context {
+            msg: msg(source.as_ref()),
+            source,
+        }
+    }
+}
+
+fn format_device_error(rc: &i32, rsn: &Option<String>) -> String {
+    match rsn {
+        Some(rsn) => format!("{}, rsn: {}", output::format_rc(*rc), rsn),
+        None => output::format_rc(*rc),
+    }
+}
+
+/// The first hint in the chain of sources
+pub fn hint(err: &(dyn Error + 'static)) -> Option<&'static str> {
+    let mut current = Some(err);
+    while let Some(err) = current {
+        if let Some(CliError::Hint { hint, .. }) = err.downcast_ref::<CliError>() {
+            return Some(hint);
+        }
+        current = err.source();
+    }
+    None
+}
+
+/// Classify an error by walking its chain of sources
+pub fn exit_code(err: &(dyn Error + 'static)) -> u8 {
+    let mut current = Some(err);
+
+    while let Some(err) = current {
+        if let Some(err) = err.downcast_ref::<CliError>() {
+            match err {
+                CliError::Usage(_) => return EXIT_USAGE,
+                CliError::Device { .. } => return EXIT_DEVICE,
+                CliError::Connect(_) => return EXIT_TRANSPORT,
+                CliError::RemoteStatus(status) | CliError::Verdict(status) => return *status,
+                CliError::Interrupted(_) => return EXIT_INTERRUPTED,
+                CliError::Hint { .. } | CliError::Context { .. } => {}
+            }
+        }
+
+        if let Some(err) = err.downcast_ref::<mcumgr_smp::transport::error::Error>() {
+            return match err {
+                mcumgr_smp::transport::error::Error::Io(e)
+                    if matches!(e.kind(), ErrorKind::TimedOut | ErrorKind::WouldBlock) =>
+                {
+                    EXIT_TIMEOUT
+                }
+                _ => EXIT_TRANSPORT,
+            };
+        }
+
+        if let Some(err) = err.downcast_ref::<std::io::Error>() {
+            if err.kind() == ErrorKind::TimedOut {
+                return EXIT_TIMEOUT;
+            }
+        }
+
+        current = err.source();
+    }
+
+    EXIT_OTHER
+}
+
+/// Report an error to the user, as JSON if that output format is selected
+pub fn report(err: &(dyn Error + 'static), format: OutputFormat) {
+    let code = exit_code(err);
+
+    // the status of `shell exec` is passed through like ssh does and the verdict of
+    // `app compare` was printed already, both without a message
+    if let Some(CliError::RemoteStatus(_) | CliError::Verdict(_)) = err.downcast_ref::<CliError>() {
+        return;
+    }
+
+    match format {
+        OutputFormat::Text => {
+            eprintln!("error: {}", err);
+            if let Some(hint) = hint(err) {
+                eprintln!("hint: {}", hint);
+            }
+        }
+        OutputFormat::Json => eprintln!("{}", to_json(err, code)),
+    }
+}
+
+/// The error message, exit code and the rc of the device if there is one
+pub fn to_json(err: &(dyn Error + 'static), code: u8) -> serde_json::Value {
+    let mut json = serde_json::json!({
+        "error": err.to_string(),
+        "exit_code": code,
+    });
+
+    let mut current = Some(err);
+    while let Some(err) = current {
+        if let Some(CliError::Device { rc, rsn }) = err.downcast_ref::<CliError>() {
+            json["rc"] = (*rc).into();
+            if let Ok(name) = ReturnCode::try_from(*rc) {
+                json["rc_name"] = name.name().into();
+            }
+            if let Some(rsn) = rsn {
+                json["rsn"] = rsn.as_str().into();
+            }
+            break;
+        }
+        current = err.source();
+    }
+    if let Some(hint) = hint(err) {
+        json["hint"] = hint.into();
+    }
+
+    json
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn device_errors_carry_the_symbolic_name() {
+        for (rc, name) in [
+            (0, "MGMT_ERR_EOK"),
+            (1, "MGMT_ERR_EUNKNOWN"),
+            (2, "MGMT_ERR_ENOMEM"),
+            (3, "MGMT_ERR_EINVAL"),
+            (4, "MGMT_ERR_ETIMEOUT"),
+            (5, "MGMT_ERR_ENOENT"),
+            (6, "MGMT_ERR_EBADSTATE"),
+            (7, "MGMT_ERR_EMSGSIZE"),
+            (8, "MGMT_ERR_ENOTSUP"),
+            (9, "MGMT_ERR_ECORRUPT"),
+            (10, "MGMT_ERR_EBUSY"),
+            (11, "MGMT_ERR_EACCESSDENIED"),
+            (12, "MGMT_ERR_UNSUPPORTED_TOO_OLD"),
+            (13, "MGMT_ERR_UNSUPPORTED_TOO_NEW"),
+            (256, "MGMT_ERR_EPERUSER"),
+        ] {
+            let err = CliError::device(rc);
+            let short = name.trim_start_matches("MGMT_ERR_");
+            assert!(
+                err.to_string()
+                    .starts_with(&format!("{} (rc={}) — ", short, rc)),
+                "{}",
+                err
+            );
+            assert_eq!(exit_code(&err), EXIT_DEVICE);
+
+            let json = to_json(&err, EXIT_DEVICE);
+            assert_eq!(json["rc"], rc);
+            assert_eq!(json["rc_name"], name);
+        }
+    }
+
+    #[test]
+    fn unknown_codes_are_printed_as_numbers() {
+        for rc in [14, 255, 257, -1] {
+            let err = CliError::device(rc);
+            assert_eq!(err.to_string(), format!("device returned rc: {}", rc));
+            let json = to_json(&err, EXIT_DEVICE);
+            assert_eq!(json["rc"], rc);
+            assert!(json.get("rc_name").is_none());
+        }
+    }
+
+    #[test]
+    fn reason_and_hint_are_kept() {
+        let err = CliError::Device {
+            rc: 5,
+            rsn: Some("no such file".to_string()),
+        }
+        .hint_on(ReturnCode::NoEntry, "check the name");
+        assert_eq!(
+            err.to_string(),
+            "ENOENT (rc=5) — no such entry, rsn: no such file"
+        );
+        assert_eq!(hint(&err), Some("check the name"));
+        assert_eq!(exit_code(&err), EXIT_DEVICE);
+
+        let json = to_json(&err, EXIT_DEVICE);
+        assert_eq!(json["rsn"], "no such file");
+        assert_eq!(json["hint"], "check the name");
+
+        // only the given rc gets the hint
+        let err = CliError::device(8).hint_on(ReturnCode::NoEntry, "check the name");
+        assert_eq!(hint(&err), None);
+    }
+}
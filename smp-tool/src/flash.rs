@@ -0,0 +1,1182 @@
+use std::cmp::min;
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::io::{ErrorKind, IsTerminal, Read};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use mcumgr_smp::{
+    application_management::{
+        self, EraseImageResult, GetImageStatePayload, GetImageStateResult, UploadAction,
+        WriteImageChunkResult,
+    },
+    os_management::{self, ResetResult},
+    smp::SmpFrame,
+    transport::timeouts::OperationClass,
+    ReturnCode,
+};
+use serde::Serialize;
+use sha2::Digest;
+use tracing::debug;
+
+use crate::context::Context;
+use crate::error::{self, CliError};
+use crate::image::ImageVersion;
+use crate::interrupt::{self, Resume};
+use crate::output::{self, OutputFormat};
+use crate::pacing::{Pacer, RateLimit, UploadReport};
+use crate::progress::Progress;
+use crate::{
+    dfu_package, fs, image, open_transport, outln, progress, status, Cli, Transport, UsedTransport,
+};
+
+/// Upper limit for firmware read from stdin
+const MAX_STDIN_FIRMWARE_SIZE: u64 = 64 * 1024 * 1024;
+
+/// Read a complete firmware from stdin.
+///
+/// The first upload frame contains the total length and hash, so the whole image has to be
+/// buffered before the upload can start.
+pub fn read_stdin() -> Result<Vec<u8>, Box<dyn Error>> {
+    let stdin = std::io::stdin();
+    if stdin.is_terminal() {
+        Err("refusing to read firmware from a terminal, pipe the image into stdin")?;
+    }
+
+    let mut firmware = Vec::new();
+    stdin
+        .lock()
+        .take(MAX_STDIN_FIRMWARE_SIZE + 1)
+        .read_to_end(&mut firmware)?;
+
+    if firmware.len() as u64 > MAX_STDIN_FIRMWARE_SIZE {
+        Err(format!(
+            "firmware on stdin exceeds {} bytes",
+            MAX_STDIN_FIRMWARE_SIZE
+        ))?;
+    }
+    if firmware.is_empty() {
+        Err("no firmware received on stdin")?;
+    }
+
+    Ok(firmware)
+}
+
+/// A firmware to upload to an image number
+pub struct FlashImage {
+    pub name: String,
+    pub image: Option<u8>,
+    pub data: Vec<u8>,
+}
+
+impl FlashImage {
+    /// e.g. `image 1 (net_core_app_update.bin)`
+    pub fn describe(&self) -> String {
+        format!("image {} ({})", self.image.unwrap_or(0), self.name)
+    }
+}
+
+fn describe_all(images: &[&FlashImage]) -> String {
+    images
+        .iter()
+        .map(|img| img.describe())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Explain the state of the device when uploading one of several images failed.
+///
+/// `uploaded` are the images uploaded before, none of them is marked yet. `remaining` starts
+/// with the image that failed. `from_files` tells whether the images were given with `--image`
+/// or come from a dfu package, to suggest the flags to upload the rest.
+pub fn upload_failure(
+    err: Box<dyn Error>,
+    uploaded: &[&FlashImage],
+    remaining: &[&FlashImage],
+    from_files: bool,
+) -> Box<dyn Error> {
+    let retry = if from_files {
+        let flags: Vec<String> = remaining
+            .iter()
+            .map(|img| format!("--image {}={}", img.image.unwrap_or(0), img.name))
+            .collect();
+        format!("re-run with `{}` to upload the rest", flags.join(" "))
+    } else {
+        let files: Vec<&str> = remaining.iter().map(|img| img.name.as_str()).collect();
+        format!(
+            "re-run with `--only FILE` for each of {} to upload the rest",
+            files.join(", ")
+        )
+    };
+
+    Box::new(CliError::context(err, |e| {
+        let mut msg = format!("flashing {} failed: {}", remaining[0].describe(), e);
+        if !uploaded.is_empty() {
+            msg.push_str(&format!(
+                "\n{} uploaded but not marked, the device keeps running the current firmware",
+                describe_all(uploaded)
+            ));
+        }
+        msg.push('\n');
+        msg.push_str(&retry);
+        msg
+    }))
+}
+
+/// Explain the state of the device when marking one of several uploaded images failed.
+///
+/// `marked` are the images marked before, `remaining` starts with the image that failed.
+pub fn mark_failure(
+    err: Box<dyn Error>,
+    marked: &[&FlashImage],
+    remaining: &[&FlashImage],
+    confirm: bool,
+) -> Box<dyn Error> {
+    let (state, command) = if confirm {
+        ("confirmed", "app confirm")
+    } else {
+        ("marked for test", "app test")
+    };
+
+    Box::new(CliError::context(err, |e| {
+        let mut msg = format!(
+            "marking {} failed: {}\nall images are uploaded",
+            remaining[0].describe(),
+            e
+        );
+        if !marked.is_empty() {
+            msg.push_str(&format!(", {} {}", describe_all(marked), state));
+        }
+        msg.push_str("\nbefore the next reset, mark the rest or only some images are swapped in:");
+        for img in remaining {
+            msg.push_str(&format!(
+                "\n  {} {}",
+                command,
+                image::hex(&image_hash(&img.data))
+            ));
+        }
+        msg
+    }))
+}
+
+/// Parse an `--image` value, an image number and a firmware file, e.g. `1=net_core_app_update.bin`
+pub fn parse_image_file(s: &str) -> Result<(u8, PathBuf), String> {
+    let (image, path) = s.split_once('=').ok_or_else(|| {
+        format!(
+            "invalid image {}, expected e.g. 1=net_core_app_update.bin",
+            s
+        )
+    })?;
+    let image = image
+        .parse()
+        .map_err(|_| format!("invalid image number {}, expected 0 to 255", image))?;
+    if path.is_empty() {
+        return Err(format!("missing file for image {}", image));
+    }
+    Ok((image, PathBuf::from(path)))
+}
+
+/// Load the firmware to flash: the files given with `--image` in their order, or else a file,
+/// a dfu package, or stdin if the path is `-`
+pub fn load_images(
+    ctx: &Context,
+    update_file: Option<&Path>,
+    files: &[(u8, PathBuf)],
+    slot: Option<u8>,
+    only: Option<&str>,
+) -> Result<Vec<FlashImage>, Box<dyn Error>> {
+    if !files.is_empty() {
+        return load_image_files(ctx, files);
+    }
+    let update_file = update_file.expect("clap requires a file without --image");
+
+    let firmware = if update_file.as_os_str() == "-" {
+        let firmware = read_stdin()?;
+        status!(
+            ctx,
+            "read {} bytes from stdin, sha256: {}",
+            firmware.len(),
+            image::hex(&sha2::Sha256::digest(&firmware))
+        );
+        firmware
+    } else {
+        std::fs::read(update_file)?
+    };
+
+    if !dfu_package::is_package(&firmware) {
+        if only.is_some() {
+            Err(CliError::Usage(
+                "--only can only be used with a dfu package".to_string(),
+            ))?;
+        }
+        return Ok(vec![FlashImage {
+            name: update_file.display().to_string(),
+            image: slot,
+            data: firmware,
+        }]);
+    }
+
+    if slot.is_some() {
+        Err(CliError::Usage(
+            "--slot can't be used with a dfu package, the manifest defines the image numbers"
+                .to_string(),
+        ))?;
+    }
+
+    let images = dfu_package::read_package(firmware, only)?;
+    for img in &images {
+        status!(
+            ctx,
+            "{}: image {}, version {}, {} bytes",
+            img.name,
+            img.image,
+            img.version.as_deref().unwrap_or("unknown"),
+            img.data.len()
+        );
+    }
+
+    Ok(images
+        .into_iter()
+        .map(|img| FlashImage {
+            name: img.name,
+            image: Some(img.image),
+            data: img.data,
+        })
+        .collect())
+}
+
+/// Load the firmware of every `--image IMAGE=FILE`
+fn load_image_files(
+    ctx: &Context,
+    files: &[(u8, PathBuf)],
+) -> Result<Vec<FlashImage>, Box<dyn Error>> {
+    let mut images: Vec<FlashImage> = Vec::new();
+    for (image, path) in files {
+        if images.iter().any(|img| img.image == Some(*image)) {
+            Err(CliError::Usage(format!("--image {} is given twice", image)))?;
+        }
+        if path.as_os_str() == "-" {
+            Err(CliError::Usage(
+                "--image can't read firmware from stdin".to_string(),
+            ))?;
+        }
+
+        let data =
+            std::fs::read(path).map_err(|e| format!("can't read {}: {}", path.display(), e))?;
+        if dfu_package::is_package(&data) {
+            Err(CliError::Usage(format!(
+                "{} is a dfu package, flash it without --image",
+                path.display()
+            )))?;
+        }
+
+        status!(
+            ctx,
+            "{}: image {}, version {}, {} bytes",
+            path.display(),
+            image,
+            image::parse_image(&data)
+                .map(|info| info.version.to_string())
+                .unwrap_or_else(|| "unknown".to_string()),
+            data.len()
+        );
+        images.push(FlashImage {
+            name: path.display().to_string(),
+            image: Some(*image),
+            data,
+        });
+    }
+
+    Ok(images)
+}
+
+/// Time before the first repetition of a failed chunk, doubled for every further attempt up
+/// to [MAX_CHUNK_RETRY_DELAY]
+const FIRST_CHUNK_RETRY_DELAY: Duration = Duration::from_millis(200);
+const MAX_CHUNK_RETRY_DELAY: Duration = Duration::from_secs(5);
+/// The chunk size isn't halved below this after ENOMEM responses
+const MIN_CHUNK_SIZE: usize = 32;
+
+/// Room for the SMP header and the CBOR encoding of the first chunk of a firmware upload,
+/// which carries the length, hash and image number besides the data
+const UPLOAD_OVERHEAD: usize = 8 + 96;
+
+/// How the firmware is split into chunks and how failed chunks are handled
+#[derive(Debug, Clone, Copy)]
+pub struct Chunking {
+    /// bytes of firmware per request
+    pub size: usize,
+    /// how often a chunk is repeated after a timeout, a transient device error or an error
+    /// along with the offset to resume at
+    pub retries: u32,
+}
+
+impl Chunking {
+    /// Chunks of `size` bytes that aren't repeated
+    pub fn new(size: usize) -> Self {
+        Self { size, retries: 0 }
+    }
+}
+
+/// Where and how an image is uploaded
+#[derive(Debug, Clone, Copy)]
+pub struct UploadOptions {
+    /// the image number, `None` for the default image
+    pub image: Option<u8>,
+    pub chunking: Chunking,
+    /// only allow newer firmware versions
+    pub upgrade: bool,
+    pub limit: RateLimit,
+}
+
+/// Printed below an upload that the device rejected as invalid
+pub const UPLOAD_EINVAL_HINT: &str = "chunk too large or malformed";
+
+/// Why a chunk failed, and whether sending it again may help
+enum ChunkError {
+    Transport(mcumgr_smp::transport::error::Error),
+    Device(CliError),
+    /// The response doesn't fit the upload, e.g. an offset beyond the data sent
+    Protocol(String),
+}
+
+impl ChunkError {
+    fn is_transient(&self) -> bool {
+        match self {
+            // a disconnected port doesn't come back by sending again
+            ChunkError::Transport(mcumgr_smp::transport::error::Error::Io(e)) => {
+                matches!(e.kind(), ErrorKind::TimedOut | ErrorKind::WouldBlock)
+            }
+            ChunkError::Transport(_) => true,
+            ChunkError::Device(CliError::Device { rc, .. }) => matches!(
+                ReturnCode::try_from(*rc),
+                Ok(ReturnCode::OutOfMemory | ReturnCode::Timeout | ReturnCode::Busy)
+            ),
+            ChunkError::Device(_) | ChunkError::Protocol(_) => false,
+        }
+    }
+
+    fn is_out_of_memory(&self) -> bool {
+        matches!(self, ChunkError::Device(CliError::Device { rc, .. })
+            if ReturnCode::try_from(*rc) == Ok(ReturnCode::OutOfMemory))
+    }
+
+    fn into_error(self) -> Box<dyn Error> {
+        match self {
+            ChunkError::Transport(e) => e.into(),
+            ChunkError::Device(e) => e
+                .hint_on(ReturnCode::InvalidValue, UPLOAD_EINVAL_HINT)
+                .into(),
+            ChunkError::Protocol(msg) => msg.into(),
+        }
+    }
+}
+
+impl Display for ChunkError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ChunkError::Transport(e) => e.fmt(f),
+            ChunkError::Device(e) => e.fmt(f),
+            ChunkError::Protocol(msg) => f.write_str(msg),
+        }
+    }
+}
+
+/// Upload a firmware file to the given image number.
+///
+/// The offset reported by the device decides which chunk is sent next, so a chunk that is
+/// repeated after a lost response neither duplicates nor skips data. That includes the offset
+/// some devices report along with an error.
+pub async fn upload(
+    ctx: &Context,
+    transport: &mut UsedTransport,
+    firmware: &[u8],
+    options: &UploadOptions,
+    mut progress: Progress<'_>,
+) -> Result<UploadReport, Box<dyn Error>> {
+    let UploadOptions {
+        image,
+        chunking,
+        upgrade,
+        limit,
+    } = *options;
+    let mut hasher = sha2::Sha256::new();
+    hasher.update(firmware);
+    let hash = hasher.finalize();
+
+    status!(ctx, "Image sha256: {:x}", hash);
+
+    let mut updater =
+        application_management::ImageWriter::new(image, firmware.len(), Some(&hash), upgrade);
+
+    let mut verified = None;
+    let mut pacer = Pacer::new(limit);
+    let repeated = ctx.retry.repeated();
+    let mut bytes_sent = 0;
+    let mut chunk_retries = 0;
+
+    let mut chunk_size = chunking.size;
+    let mut request_buf = Vec::new();
+    debug!(
+        "uploading {} bytes in chunks of {} bytes, window 1",
+        firmware.len(),
+        chunk_size
+    );
+    let mut attempt = 0;
+    let mut offset = 0;
+    while offset < firmware.len() {
+        if interrupt::requested().await {
+            let stopped = format!("upload stopped at {} of {} bytes", offset, firmware.len());
+            Err(interrupt::stop(transport, stopped, Resume::Rerun).await)?;
+        }
+        pacer.wait().await;
+        if !progress::is_json(ctx) {
+            status!(ctx, "writing {}/{}", offset, firmware.len());
+        }
+        let chunk = &firmware[offset..min(firmware.len(), offset + chunk_size)];
+
+        updater.offset = offset;
+        let mut request = updater.write_chunk(chunk);
+        request.sequence = ctx.sequence.next();
+        bytes_sent += chunk.len() as u64;
+        let mut resume_at = None;
+        // many devices erase the slot before answering the first chunk
+        let response = match OperationClass::of_upload_chunk(offset) {
+            OperationClass::Fast => {
+                transport
+                    .transceive_cbor_with(&request, &mut request_buf)
+                    .await
+            }
+            class => transport.transceive_cbor_as(&request, class).await,
+        };
+        let ret = match response {
+            Ok(SmpFrame { data, .. }) => {
+                let action = updater.handle_response(&data);
+                match data {
+                    WriteImageChunkResult::Ok(payload) => {
+                        verified = payload.match_;
+                        match action {
+                            UploadAction::Restarted => {
+                                progress.warn(
+                                    offset as u64,
+                                    Some(firmware.len() as u64),
+                                    "the device started the upload over, e.g. after a reboot, sending the image again",
+                                );
+                                Ok(0)
+                            }
+                            UploadAction::Rewind { off } => {
+                                progress.warn(
+                                    offset as u64,
+                                    Some(firmware.len() as u64),
+                                    &format!(
+                                        "the device expects offset {} again, resending from there",
+                                        off
+                                    ),
+                                );
+                                Ok(off)
+                            }
+                            UploadAction::InvalidOffset { off, sent } => {
+                                Err(ChunkError::Protocol(format!(
+                                    "device reported offset {} beyond the {} bytes sent",
+                                    off, sent
+                                )))
+                            }
+                            _ => Ok(payload.off as usize),
+                        }
+                    }
+                    WriteImageChunkResult::Err(err) => {
+                        if let UploadAction::RetryFrom { off } = action {
+                            resume_at = Some(off);
+                        }
+                        Err(ChunkError::Device(CliError::Device {
+                            rc: err.rc,
+                            rsn: err.rsn,
+                        }))
+                    }
+                }
+            }
+            Err(e) => Err(ChunkError::Transport(e)),
+        };
+
+        match ret {
+            Ok(next_off) => {
+                pacer.confirmed(offset as u64, chunk.len() as u64, next_off as u64);
+                offset = next_off;
+                attempt = 0;
+                progress.update(offset as u64, Some(firmware.len() as u64));
+            }
+            // the device said where to continue, so the chunk can be repeated whatever the error
+            Err(e) if attempt < chunking.retries && (resume_at.is_some() || e.is_transient()) => {
+                attempt += 1;
+                chunk_retries += 1;
+                if e.is_out_of_memory() && chunk_size > MIN_CHUNK_SIZE {
+                    chunk_size = (chunk_size / 2).max(MIN_CHUNK_SIZE);
+                    eprintln!(
+                        "warning: device is out of memory, continuing with {} byte chunks",
+                        chunk_size
+                    );
+                }
+                let delay = (FIRST_CHUNK_RETRY_DELAY * 2u32.saturating_pow(attempt - 1))
+                    .min(MAX_CHUNK_RETRY_DELAY);
+                eprintln!(
+                    "warning: chunk at offset {} failed: {}, retrying {}/{} in {:.1}s",
+                    offset,
+                    e,
+                    attempt,
+                    chunking.retries,
+                    delay.as_secs_f64()
+                );
+                tokio::time::sleep(delay).await;
+                if let Some(off) = resume_at.filter(|off| *off != offset) {
+                    debug!("device expects offset {} after the error", off);
+                    offset = off;
+                }
+            }
+            Err(e) => {
+                let retries = attempt;
+                Err(CliError::context(e.into_error(), |e| {
+                    if retries > 0 {
+                        format!(
+                            "chunk at offset {} failed after {} retries: {}",
+                            offset, retries, e
+                        )
+                    } else {
+                        format!("chunk at offset {} failed: {}", offset, e)
+                    }
+                }))?;
+            }
+        }
+    }
+
+    if let Some(verified) = verified {
+        if verified {
+            status!(ctx, "Image verified");
+        } else {
+            eprintln!("warning: image verification failed");
+        }
+    }
+
+    Ok(UploadReport {
+        size: firmware.len() as u64,
+        bytes_sent,
+        duration_secs: pacer.elapsed().as_secs_f64(),
+        average_bytes_per_second: pacer.rate(),
+        peak_bytes_per_second: pacer.peak_rate(),
+        retries: chunk_retries + ctx.retry.repeated() - repeated,
+        final_offset: offset as u64,
+        window: 1,
+        matched: verified,
+        sha256: format!("{:x}", hash),
+    })
+}
+
+/// The hash the device reports for the given firmware in its image state.
+///
+/// This is the hash from the image TLVs, not the hash of the whole file.
+pub fn image_hash(firmware: &[u8]) -> Vec<u8> {
+    match image::parse_image(firmware) {
+        Some(info) => info.hash,
+        None => sha2::Sha256::digest(firmware).to_vec(),
+    }
+}
+
+/// The hashes a device may report for a firmware in its image state: the hash from the image
+/// TLVs, or the hash of the whole file, e.g. for files without TLVs
+struct FirmwareHashes {
+    tlv: Option<Vec<u8>>,
+    file: Vec<u8>,
+}
+
+impl FirmwareHashes {
+    fn of(firmware: &[u8]) -> Self {
+        Self {
+            tlv: image::parse_image(firmware).map(|info| info.hash),
+            file: sha2::Sha256::digest(firmware).to_vec(),
+        }
+    }
+
+    /// Whether a hash of the image state is one of them
+    fn matches(&self, hash: &[u8]) -> bool {
+        hash == self.file || self.tlv.as_deref() == Some(hash)
+    }
+
+    /// The hash the device is expected to report, see [image_hash]
+    fn expected(&self) -> &[u8] {
+        self.tlv.as_deref().unwrap_or(&self.file)
+    }
+}
+
+/// Result of comparing a firmware file with the images on the device
+#[derive(Serialize, Debug)]
+pub struct Comparison {
+    pub image: u8,
+    pub skip: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<&'static str>,
+    pub device_version: Option<String>,
+    pub file_version: Option<String>,
+}
+
+impl Comparison {
+    pub fn print(&self, ctx: &Context, format: OutputFormat) {
+        match format {
+            OutputFormat::Text => {
+                if self.skip {
+                    status!(
+                        ctx,
+                        "image {}: {}, device runs {}, skipping",
+                        self.image,
+                        self.reason.unwrap_or("skipped"),
+                        self.device_version.as_deref().unwrap_or("unknown version")
+                    );
+                }
+            }
+            OutputFormat::Json => outln!(
+                ctx,
+                "{}",
+                serde_json::to_string(self).expect("serializing to string can't fail")
+            ),
+        }
+    }
+}
+
+/// Check whether the device already runs the given firmware.
+///
+/// The active or pending image is compared by hash. If `skip_if_newer` is set, the firmware
+/// is also skipped if the active image has a higher version.
+pub async fn compare_with_device(
+    ctx: &Context,
+    transport: &mut UsedTransport,
+    firmware: &[u8],
+    image: Option<u8>,
+    skip_if_newer: bool,
+) -> Result<Comparison, Box<dyn Error>> {
+    let state = get_image_state(ctx, transport).await?;
+    let image = image.unwrap_or(0);
+    let hashes = FirmwareHashes::of(firmware);
+    let file_version = image::parse_image(firmware).map(|info| info.version);
+
+    let slots: Vec<_> = state
+        .images
+        .iter()
+        .filter(|img| img.image.unwrap_or(0) == image as i32)
+        .collect();
+    let active = slots.iter().find(|img| img.active);
+
+    let same = slots
+        .iter()
+        .any(|img| (img.active || img.pending) && hashes.matches(&img.hash));
+
+    let device_newer = match (
+        active.and_then(|img| img.version.parse::<ImageVersion>().ok()),
+        file_version,
+    ) {
+        (Some(device), Some(file)) => device > file,
+        _ => false,
+    };
+
+    let reason = if same {
+        Some("already up to date")
+    } else if skip_if_newer && device_newer {
+        Some("device runs a newer version")
+    } else {
+        None
+    };
+
+    Ok(Comparison {
+        image,
+        skip: reason.is_some(),
+        reason,
+        device_version: active.map(|img| img.version.clone()),
+        file_version: file_version.map(|version| version.to_string()),
+    })
+}
+
+/// How a firmware file relates to the image running on the device
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Verdict {
+    /// the device runs the file
+    UpToDate,
+    /// the file has a higher version
+    UpgradeAvailable,
+    /// the device runs a higher version
+    DeviceNewer,
+    /// no running image, a version the device reports in an unknown format, or the same
+    /// version with a different hash
+    Unknown,
+}
+
+impl Verdict {
+    /// The exit code of `app compare`
+    pub fn exit_code(self) -> u8 {
+        match self {
+            Verdict::UpToDate => 0,
+            Verdict::UpgradeAvailable => error::EXIT_UPGRADE_AVAILABLE,
+            Verdict::DeviceNewer => error::EXIT_DEVICE_NEWER,
+            Verdict::Unknown => error::EXIT_VERSION_UNKNOWN,
+        }
+    }
+}
+
+impl Display for Verdict {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Verdict::UpToDate => "up to date",
+            Verdict::UpgradeAvailable => "upgrade available",
+            Verdict::DeviceNewer => "device newer",
+            Verdict::Unknown => "unknown",
+        })
+    }
+}
+
+/// The versions and hashes of a firmware file and the image running on the device
+#[derive(Serialize, Debug)]
+pub struct VersionReport {
+    pub image: u8,
+    pub device_version: Option<String>,
+    pub device_hash: Option<String>,
+    pub file_version: String,
+    pub file_hash: String,
+    pub verdict: Verdict,
+}
+
+impl VersionReport {
+    pub fn print(&self, ctx: &Context, format: OutputFormat) {
+        match format {
+            OutputFormat::Text => {
+                outln!(ctx, "image    {}", self.image);
+                outln!(
+                    ctx,
+                    "device   {}  {}",
+                    self.device_version.as_deref().unwrap_or("-"),
+                    self.device_hash.as_deref().unwrap_or("no running image")
+                );
+                outln!(ctx, "file     {}  {}", self.file_version, self.file_hash);
+                outln!(ctx, "verdict  {}", self.verdict);
+            }
+            OutputFormat::Json => outln!(
+                ctx,
+                "{}",
+                serde_json::to_string_pretty(self).expect("serializing to string can't fail")
+            ),
+        }
+    }
+}
+
+/// Compare the MCUboot header of a firmware file with the image running on the device.
+///
+/// Files without an MCUboot header fail, their version is unknown.
+pub async fn compare_versions(
+    ctx: &Context,
+    transport: &mut UsedTransport,
+    path: &Path,
+    image: u8,
+) -> Result<VersionReport, Box<dyn Error>> {
+    let firmware =
+        std::fs::read(path).map_err(|e| format!("can't read {}: {}", path.display(), e))?;
+    let info = image::parse_image(&firmware).ok_or_else(|| {
+        format!(
+            "{} is not a signed MCUboot image, its version is unknown",
+            path.display()
+        )
+    })?;
+
+    let state = get_image_state(ctx, transport).await?;
+    let active = state
+        .images
+        .iter()
+        .find(|img| img.image.unwrap_or(0) == image as i32 && img.active);
+
+    let verdict = match active {
+        None => Verdict::Unknown,
+        Some(active) if active.hash == info.hash => Verdict::UpToDate,
+        Some(active) => match active.version.parse::<ImageVersion>() {
+            Ok(device) if info.version > device => Verdict::UpgradeAvailable,
+            Ok(device) if info.version < device => Verdict::DeviceNewer,
+            _ => Verdict::Unknown,
+        },
+    };
+
+    Ok(VersionReport {
+        image,
+        device_version: active.map(|img| img.version.clone()),
+        device_hash: active.map(|img| image::hex(&img.hash)),
+        file_version: info.version.to_string(),
+        file_hash: image::hex(&info.hash),
+        verdict,
+    })
+}
+
+/// Erase the secondary slot of the given image number.
+///
+/// Devices that don't support erasing are only warned about, the upload erases on the fly there.
+pub async fn erase_secondary_slot(
+    ctx: &Context,
+    transport: &mut UsedTransport,
+    image: Option<u8>,
+) -> Result<(), Box<dyn Error>> {
+    // every image has a primary and a secondary slot
+    let slot = image.unwrap_or(0) as u32 * 2 + 1;
+
+    status!(ctx, "erasing slot {}", slot);
+    let start = Instant::now();
+
+    // waits as long as --slow-timeout-ms, erasing a whole slot takes a while
+    let ret: SmpFrame<EraseImageResult> = transport
+        .transceive_cbor(&application_management::erase_image(
+            Some(slot),
+            ctx.sequence.next(),
+        ))
+        .await?;
+    debug!("{:?}", ret);
+
+    match ret.data {
+        EraseImageResult::Ok {} => {
+            status!(
+                ctx,
+                "erased slot {} in {:.2}s",
+                slot,
+                start.elapsed().as_secs_f32()
+            );
+        }
+        EraseImageResult::Err { rc, .. } if rc == ReturnCode::NotSupported as i32 => {
+            eprintln!("warning: device does not support erasing, continuing with the upload");
+        }
+        EraseImageResult::Err { rc, rsn } => {
+            Err(CliError::context(
+                Box::new(CliError::Device { rc, rsn }),
+                |e| format!("erasing slot {} failed: {}", slot, e),
+            ))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Read the current image state of the device
+pub async fn get_image_state(
+    ctx: &Context,
+    transport: &mut UsedTransport,
+) -> Result<GetImageStatePayload, Box<dyn Error>> {
+    let ret: SmpFrame<GetImageStateResult> = transport
+        .transceive_cbor(&application_management::get_state(ctx.sequence.next()))
+        .await?;
+    debug!("{:?}", ret);
+
+    match ret.data {
+        GetImageStateResult::Ok(payload) => Ok(payload),
+        GetImageStateResult::Err(err) => Err(CliError::Device {
+            rc: err.rc,
+            rsn: err.rsn,
+        })?,
+    }
+}
+
+/// Check that the device reports the uploaded firmware in its image state.
+///
+/// The reported hash is usually the hash from the image TLVs, but some devices report the hash
+/// of the whole file, so both are accepted.
+pub async fn verify(
+    ctx: &Context,
+    transport: &mut UsedTransport,
+    firmware: &[u8],
+) -> Result<(), Box<dyn Error>> {
+    let state = get_image_state(ctx, transport).await?;
+
+    let hashes = FirmwareHashes::of(firmware);
+
+    if let Some(img) = state.images.iter().find(|img| hashes.matches(&img.hash)) {
+        status!(
+            ctx,
+            "verified: image {} slot {} reports hash {}",
+            img.image.unwrap_or(0),
+            img.slot,
+            image::hex(&img.hash)
+        );
+        return Ok(());
+    }
+
+    let mut msg = String::from("verification failed, no slot reports the uploaded image\n");
+    msg.push_str(&format!("expected: {}\n", image::hex(hashes.expected())));
+    for img in &state.images {
+        msg.push_str(&format!(
+            "   found: {} (image {} slot {})\n",
+            image::hex(&img.hash),
+            img.image.unwrap_or(0),
+            img.slot
+        ));
+    }
+    Err(msg.trim_end().into())
+}
+
+/// Mark a freshly uploaded firmware for test or as confirmed.
+///
+/// Fails if the image can't be found in the image state of the device. It is found by the same
+/// hashes as in [verify] and marked by the hash the device reports, which is returned.
+pub async fn mark(
+    ctx: &Context,
+    transport: &mut UsedTransport,
+    firmware: &[u8],
+    confirm: bool,
+    format: OutputFormat,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let hashes = FirmwareHashes::of(firmware);
+
+    let state = get_image_state(ctx, transport).await?;
+
+    let Some(img) = state.images.iter().find(|img| hashes.matches(&img.hash)) else {
+        return Err(format!(
+            "uploaded image {} not found in image state, refusing to mark it",
+            image::hex(hashes.expected())
+        )
+        .into());
+    };
+
+    let hash = img.hash.clone();
+    let ret: SmpFrame<GetImageStateResult> = transport
+        .transceive_cbor(&application_management::set_state(
+            hash.clone(),
+            confirm,
+            ctx.sequence.next(),
+        ))
+        .await?;
+    debug!("{:?}", ret);
+
+    match ret.data {
+        GetImageStateResult::Ok(payload) => {
+            output::print_image_state(ctx, &payload, format);
+        }
+        GetImageStateResult::Err(err) => {
+            Err(CliError::Device {
+                rc: err.rc,
+                rsn: err.rsn,
+            })?;
+        }
+    }
+
+    Ok(hash)
+}
+
+pub struct UpdateOptions {
+    pub image: Option<u8>,
+    /// bytes of firmware per request, derived from the buffer size of the device by default
+    pub chunk_size: Option<usize>,
+    pub upgrade: bool,
+    /// confirm the new image once it is running
+    pub confirm: bool,
+    /// how long to wait for the device to come back after the reset
+    pub confirm_timeout: Duration,
+}
+
+/// Run the complete swap-mode update: upload, test, reset, wait for the new image, confirm.
+///
+/// Every step reports what has to be redone manually if it fails.
+pub async fn update(
+    ctx: &Context,
+    cli: &Cli,
+    mut transport: UsedTransport,
+    firmware: &[u8],
+    options: &UpdateOptions,
+) -> Result<UsedTransport, Box<dyn Error>> {
+    let hash_bytes = image_hash(firmware);
+    let hash = image::hex(&hash_bytes);
+    let chunk_size =
+        fs::upload_chunk_size(ctx, &mut transport, options.chunk_size, UPLOAD_OVERHEAD).await?;
+
+    status!(ctx, "[1/5] uploading image {}", hash);
+    let upload_options = UploadOptions {
+        image: options.image,
+        chunking: Chunking::new(chunk_size),
+        upgrade: options.upgrade,
+        limit: RateLimit::default(),
+    };
+    upload(
+        ctx,
+        &mut transport,
+        firmware,
+        &upload_options,
+        // only JSON progress events, the text output has its own lines
+        Progress::new(ctx, "upload", false).with_step("1/5"),
+    )
+    .await
+    .map_err(|e| {
+        // an interrupted upload already tells how to continue
+        if let Some(CliError::Interrupted(_)) = e.downcast_ref::<CliError>() {
+            return e;
+        }
+        CliError::context(e, |e| {
+            format!(
+                "upload failed: {}\nre-run the update, the upload resumes where it stopped",
+                e
+            )
+        })
+        .into()
+    })?
+    .print(ctx, cli.format);
+
+    status!(ctx, "[2/5] marking image for test");
+    progress::step(ctx, "mark", "2/5");
+    // the device may report the hash of the whole file instead of the one from the TLVs
+    let hash_bytes = mark(ctx, &mut transport, firmware, false, cli.format)
+        .await
+        .map_err(|e| {
+            CliError::context(e, |e| {
+                format!(
+                    "marking the image for test failed: {}\nthe image is uploaded, run `app test {}` and `os reset`",
+                    e, hash
+                )
+            })
+        })?;
+    let hash = image::hex(&hash_bytes);
+
+    status!(ctx, "[3/5] resetting device");
+    progress::step(ctx, "reset", "3/5");
+    // the device may reset before the response is sent
+    let ret = transport
+        .transceive_cbor_optional::<_, ResetResult>(
+            &os_management::reset(ctx.sequence.next(), false),
+            ctx.retry.timeouts().reset_grace,
+        )
+        .await;
+    debug!("{:?}", ret);
+    if let Ok(Some(SmpFrame {
+        data: ResetResult::Err { rc },
+        ..
+    })) = ret
+    {
+        Err(CliError::context(Box::new(CliError::device(rc)), |e| {
+            format!(
+                "reset failed: {}\nthe image is marked for test, run `os reset`",
+                e
+            )
+        }))?;
+    }
+
+    status!(ctx, "[4/5] waiting for device to boot the new image");
+    progress::step(ctx, "wait", "4/5");
+    let mut transport = wait_for_image(ctx, cli, transport, &hash, options.confirm_timeout)
+        .await
+        .map_err(|e| {
+            CliError::context(e, |e| {
+                format!(
+                    "{}\ncheck `app info`, if image {} is active run `app confirm {}`",
+                    e, hash, hash
+                )
+            })
+        })?;
+
+    if !options.confirm {
+        status!(
+            ctx,
+            "skipping confirm, the image will be reverted on the next reset"
+        );
+        return Ok(transport);
+    }
+
+    status!(ctx, "[5/5] confirming image");
+    progress::step(ctx, "confirm", "5/5");
+    let ret: SmpFrame<GetImageStateResult> = transport
+        .transceive_cbor(&application_management::confirm(Some(hash_bytes), ctx.sequence.next()))
+        .await
+        .map_err(|e| {
+            CliError::context(e.into(), |e| {
+                format!(
+                    "confirm failed: {}\nrun `app confirm {}` before the next reset, or the device reverts to the old image",
+                    e, hash
+                )
+            })
+        })?;
+    debug!("{:?}", ret);
+
+    match ret.data {
+        GetImageStateResult::Ok(payload) => output::print_image_state(ctx, &payload, cli.format),
+        GetImageStateResult::Err(err) => Err(CliError::context(
+            Box::new(CliError::Device {
+                rc: err.rc,
+                rsn: err.rsn,
+            }),
+            |e| {
+                format!(
+                    "confirm failed: {}\nrun `app confirm {}` before the next reset, or the device reverts to the old image",
+                    e, hash
+                )
+            },
+        ))?,
+    }
+
+    Ok(transport)
+}
+
+/// Poll the image state until the image with the given hash is active.
+///
+/// Serial and BLE connections don't survive a reset, so these are reconnected
+/// whenever the device doesn't respond. A device that answers before it booted the new image,
+/// e.g. while the bootloader swaps the slots, is polled until the deadline as well.
+async fn wait_for_image(
+    ctx: &Context,
+    cli: &Cli,
+    transport: UsedTransport,
+    hash: &str,
+    timeout: Duration,
+) -> Result<UsedTransport, Box<dyn Error>> {
+    let deadline = tokio::time::Instant::now() + timeout;
+    let poll_timeout = Duration::from_millis(cli.timeout_ms);
+    let mut transport = Some(transport);
+    // the last image state the device reported, without the new image active
+    let mut last_state = None;
+
+    loop {
+        if tokio::time::Instant::now() >= deadline {
+            if let Some(state) = &last_state {
+                output::print_image_state(ctx, state, cli.format);
+                Err(format!(
+                    "device is running, but the new image is not active after {}s. It was probably reverted by the bootloader",
+                    timeout.as_secs()
+                ))?;
+            }
+            Err(std::io::Error::new(
+                std::io::ErrorKind::TimedOut,
+                format!(
+                    "device did not come back with the new image within {}s",
+                    timeout.as_secs()
+                ),
+            ))?;
+        }
+
+        tokio::time::sleep(Duration::from_secs(1)).await;
+
+        if transport.is_none() {
+            match tokio::time::timeout(poll_timeout, open_transport(ctx, cli)).await {
+                Ok(Ok(t)) => transport = Some(t),
+                _ => {
+                    debug!("reconnect failed, retrying");
+                    continue;
+                }
+            }
+        }
+
+        let t = transport.as_mut().expect("transport is connected");
+        let state = match tokio::time::timeout(poll_timeout, get_image_state(ctx, t)).await {
+            Ok(Ok(state)) => state,
+            _ => {
+                debug!("device not responding yet, retrying");
+                if !matches!(cli.transport, Some(Transport::Udp)) {
+                    transport = None;
+                }
+                continue;
+            }
+        };
+
+        if state
+            .images
+            .iter()
+            .any(|img| img.active && image::hex(&img.hash) == hash)
+        {
+            return Ok(transport.expect("transport is connected"));
+        }
+
+        debug!("new image not active yet, retrying");
+        last_state = Some(state);
+    }
+}
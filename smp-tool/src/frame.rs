@@ -0,0 +1,401 @@
+use std::convert::Infallible;
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+
+use base64::Engine;
+use clap::ValueEnum;
+use mcumgr_smp::transport::smp_framing::{self, SmpTransportDecoder};
+use mcumgr_smp::{Group, OpCode, SmpFrame};
+
+use crate::{cbor, image};
+
+pub const HEADER_LEN: usize = 8;
+
+/// Request operations that can be sent to a device
+#[derive(ValueEnum, Copy, Clone, Debug)]
+pub enum Op {
+    Read,
+    Write,
+}
+
+impl From<Op> for OpCode {
+    fn from(op: Op) -> Self {
+        match op {
+            Op::Read => OpCode::ReadRequest,
+            Op::Write => OpCode::WriteRequest,
+        }
+    }
+}
+
+/// How frames are wrapped on the wire
+#[derive(ValueEnum, Copy, Clone, Debug, Default)]
+pub enum Encapsulation {
+    /// plain SMP frames, as sent over UDP and BLE
+    #[default]
+    Raw,
+    /// SMP console framing with base64 lines and CRC, as sent over serial
+    Serial,
+}
+
+/// Build the CBOR payload from either a JSON document or hex encoded CBOR bytes.
+///
+/// Without either, an empty map is sent.
+pub fn build_payload(
+    json: Option<&str>,
+    cbor_hex: Option<&str>,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    match (json, cbor_hex) {
+        (Some(json), _) => cbor::json_to_cbor(json),
+        (None, Some(cbor_hex)) => Ok(image::parse_hex(cbor_hex)?),
+        (None, None) => cbor::json_to_cbor("{}"),
+    }
+}
+
+/// Encode a frame with an already encoded payload
+pub fn encode_frame(op: Op, group: u16, id: u8, sequence: u8, payload: Vec<u8>) -> Vec<u8> {
+    SmpFrame::new(op.into(), sequence, Group::from(group), id, payload)
+        .encode(|data| Ok::<_, Infallible>(data.clone()))
+        .unwrap_or_else(|e| match e {})
+}
+
+/// A request frame with an already encoded payload, decoded again to be sent like any other
+/// request, with its timeout, sequence check and response filter
+pub fn request_frame(
+    op: Op,
+    group: u16,
+    id: u8,
+    sequence: u8,
+    payload: &[u8],
+) -> Result<SmpFrame<ciborium::Value>, Box<dyn Error>> {
+    let (value, used) =
+        cbor::decode(payload).map_err(|e| format!("payload is not valid CBOR: {}", e))?;
+    if used < payload.len() {
+        return Err(format!("{} trailing bytes after the payload", payload.len() - used).into());
+    }
+    Ok(SmpFrame::new(
+        op.into(),
+        sequence,
+        Group::from(group),
+        id,
+        value,
+    ))
+}
+
+/// The raw header fields of an SMP frame
+#[derive(Debug, Clone, Copy)]
+pub struct Header {
+    pub version: u8,
+    /// bits 5 to 7 of the first byte, reserved by the protocol
+    pub reserved: u8,
+    pub op: u8,
+    pub flags: u8,
+    pub len: u16,
+    pub group: u16,
+    pub sequence: u8,
+    pub id: u8,
+}
+
+impl Header {
+    /// Parse the header without any validation of its fields
+    pub fn parse(buf: &[u8]) -> Option<Self> {
+        if buf.len() < HEADER_LEN {
+            return None;
+        }
+
+        Some(Header {
+            version: (buf[0] >> 3) & 0x03,
+            reserved: buf[0] >> 5,
+            op: buf[0] & 0x07,
+            flags: buf[1],
+            len: u16::from_be_bytes([buf[2], buf[3]]),
+            group: u16::from_be_bytes([buf[4], buf[5]]),
+            sequence: buf[6],
+            id: buf[7],
+        })
+    }
+}
+
+impl Display for Header {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let op = match self.op {
+            0 => "read request",
+            1 => "read response",
+            2 => "write request",
+            3 => "write response",
+            _ => "unknown",
+        };
+
+        write!(f, "op: {} ({}), ", self.op, op)?;
+        write!(f, "version: {}, ", self.version)?;
+        if self.reserved != 0 {
+            write!(f, "reserved: {:#05b}, ", self.reserved)?;
+        }
+        write!(f, "flags: {:#04x}, ", self.flags)?;
+        write!(f, "len: {}, ", self.len)?;
+        write!(f, "group: {} ({:?}), ", self.group, Group::from(self.group))?;
+        write!(f, "seq: {}, ", self.sequence)?;
+        match command_name(self.group, self.id) {
+            Some(name) => write!(f, "id: {} ({})", self.id, name),
+            None => write!(f, "id: {}", self.id),
+        }
+    }
+}
+
+/// Names of the commands defined for the standard groups
+pub fn command_name(group: u16, id: u8) -> Option<&'static str> {
+    let name = match (Group::from(group), id) {
+        (Group::Default, 0) => "echo",
+        (Group::Default, 1) => "console echo control",
+        (Group::Default, 2) => "task statistics",
+        (Group::Default, 3) => "memory pool statistics",
+        (Group::Default, 4) => "date-time",
+        (Group::Default, 5) => "reset",
+        (Group::Default, 6) => "mcumgr parameters",
+        (Group::Default, 7) => "os info",
+        (Group::Default, 8) => "bootloader info",
+        (Group::ApplicationManagement, 0) => "image state",
+        (Group::ApplicationManagement, 1) => "image upload",
+        (Group::ApplicationManagement, 5) => "image erase",
+        (Group::Statistics, 0) => "group data",
+        (Group::Statistics, 1) => "list of groups",
+        (Group::SettingManagement, 0) => "read/write setting",
+        (Group::SettingManagement, 1) => "delete setting",
+        (Group::SettingManagement, 2) => "commit settings",
+        (Group::SettingManagement, 3) => "load/save settings",
+        (Group::LogManagement, 0) => "show logs",
+        (Group::LogManagement, 1) => "clear logs",
+        (Group::LogManagement, 3) => "module list",
+        (Group::LogManagement, 4) => "level list",
+        (Group::LogManagement, 5) => "log list",
+        (Group::FileManagement, 0) => "file download/upload",
+        (Group::FileManagement, 1) => "file status",
+        (Group::FileManagement, 2) => "file hash/checksum",
+        (Group::FileManagement, 3) => "supported hash/checksum types",
+        (Group::FileManagement, 4) => "file close",
+        (Group::ShellManagement, 0) => "shell command line execute",
+        _ => return None,
+    };
+    Some(name)
+}
+
+/// Print the header and the decoded payload of a frame.
+///
+/// Inconsistencies like a wrong length field or trailing bytes are reported, not rejected.
+pub fn print_frame(buf: &[u8]) {
+    let Some(header) = Header::parse(buf) else {
+        println!(
+            "frame too short: {} bytes, header needs {}",
+            buf.len(),
+            HEADER_LEN
+        );
+        return;
+    };
+
+    println!("{}", header);
+
+    let payload = &buf[HEADER_LEN..];
+    if payload.len() != header.len as usize {
+        println!(
+            "warning: length field is {}, but {} payload bytes follow the header",
+            header.len,
+            payload.len()
+        );
+    }
+
+    match cbor::decode(payload) {
+        Ok((value, used)) => {
+            println!("{}", cbor::diag(&value));
+            if used < payload.len() {
+                println!(
+                    "warning: {} trailing bytes after the payload: {}",
+                    payload.len() - used,
+                    image::hex(&payload[used..])
+                );
+            }
+        }
+        Err(e) => {
+            println!("payload is not valid CBOR ({}): {}", e, image::hex(payload));
+        }
+    }
+}
+
+/// Parse hex input, ignoring whitespace and `:` separators as found in captures
+pub fn parse_hex_input(input: &str) -> Result<Vec<u8>, String> {
+    let hex: String = input
+        .chars()
+        .filter(|c| !c.is_whitespace() && *c != ':')
+        .collect();
+    image::parse_hex(&hex)
+}
+
+/// Extract the frames from serial console data.
+///
+/// Lines that don't belong to an SMP frame, e.g. regular log output, are skipped.
+pub fn decode_serial(data: &[u8]) -> Result<Vec<Vec<u8>>, Box<dyn Error>> {
+    let mut frames = Vec::new();
+    let mut decoder = SmpTransportDecoder::new();
+    let mut partial = false;
+
+    for line in data.split(|b| *b == b'\n') {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        if !(line.starts_with(&[0x06, 0x09]) || line.starts_with(&[0x04, 0x14])) {
+            continue;
+        }
+
+        // the decoder expects the terminating newline
+        let mut line = line.to_vec();
+        line.push(b'\n');
+        partial = !decoder.input_line(&line)?;
+
+        if !partial {
+            let decoder = std::mem::take(&mut decoder);
+            frames.push(decoder.into_frame_payload()?);
+        }
+    }
+
+    if partial {
+        eprintln!("warning: input ends with an incomplete frame");
+    }
+
+    Ok(frames)
+}
+
+/// Decode and print all frames in the input
+pub fn decode(input: &[u8], encapsulation: Encapsulation) -> Result<(), Box<dyn Error>> {
+    let frames = match encapsulation {
+        Encapsulation::Raw => vec![input.to_vec()],
+        Encapsulation::Serial => decode_serial(input)?,
+    };
+
+    if frames.is_empty() {
+        Err("no SMP frame found in input")?;
+    }
+
+    for (i, frame) in frames.iter().enumerate() {
+        if i > 0 {
+            println!();
+        }
+        print_frame(frame);
+    }
+
+    Ok(())
+}
+
+/// Print the bytes that go on the wire for a request
+pub fn print_encoded(frame: &[u8], serial_framing: bool, base64: bool) {
+    let data = if serial_framing {
+        smp_framing::encode_lines(frame)
+    } else {
+        frame.to_vec()
+    };
+
+    if base64 {
+        println!(
+            "{}",
+            base64::engine::general_purpose::STANDARD.encode(&data)
+        );
+    } else {
+        println!("{}", image::hex(&data));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn setting_write(sequence: u8, val: &str) -> Vec<u8> {
+        let json = format!(r#"{{"name": "app/value", "val": "{}"}}"#, val);
+        let payload = build_payload(Some(&json), None).unwrap();
+        encode_frame(Op::Write, 3, 0, sequence, payload)
+    }
+
+    #[test]
+    fn encoded_frames_decode_to_their_fields() {
+        let frame = setting_write(42, "hello");
+        let header = Header::parse(&frame).unwrap();
+        assert_eq!(
+            (header.version, header.op, header.flags),
+            (0, OpCode::WriteRequest as u8, 0)
+        );
+        assert_eq!((header.group, header.id, header.sequence), (3, 0, 42));
+        assert_eq!(header.len as usize, frame.len() - HEADER_LEN);
+
+        let (value, used) = cbor::decode(&frame[HEADER_LEN..]).unwrap();
+        assert_eq!(used, frame.len() - HEADER_LEN);
+        assert_eq!(
+            cbor::to_json(&value),
+            serde_json::json!({"name": "app/value", "val": "hello"})
+        );
+        assert_eq!(
+            header.to_string(),
+            "op: 2 (write request), version: 0, flags: 0x00, len: 26, group: 3 \
+             (SettingManagement), seq: 42, id: 0 (read/write setting)"
+        );
+
+        // reserved bits are only shown when they are set
+        let mut frame = frame;
+        frame[0] |= 0x20;
+        let header = Header::parse(&frame).unwrap();
+        assert_eq!((header.version, header.op, header.reserved), (0, 2, 1));
+        assert!(header
+            .to_string()
+            .starts_with("op: 2 (write request), version: 0, reserved: 0b001, flags: 0x00,"));
+    }
+
+    #[test]
+    fn payload_from_hex_is_used_as_is() {
+        let payload = build_payload(None, Some("a16164656865796c6c6f")).unwrap();
+        let frame = encode_frame(Op::Read, 0, 0, 1, payload.clone());
+        assert_eq!(&frame[HEADER_LEN..], payload);
+        assert_eq!(build_payload(None, None).unwrap(), [0xa0]);
+    }
+
+    #[test]
+    fn request_frames_keep_the_payload() {
+        let payload = build_payload(None, Some("a161646568656c6c6f")).unwrap();
+        let request = request_frame(Op::Read, 0, 0, 1, &payload).unwrap();
+        assert_eq!(
+            request.encode_with_cbor(),
+            encode_frame(Op::Read, 0, 0, 1, payload)
+        );
+
+        assert!(request_frame(Op::Read, 0, 0, 1, &[0xa1]).is_err());
+        assert!(request_frame(Op::Read, 0, 0, 1, &[0xa0, 0x00]).is_err());
+    }
+
+    #[test]
+    fn serial_framed_frames_round_trip() {
+        // one line, and one frame spread over several lines
+        let short = setting_write(1, "x");
+        let long = setting_write(2, &"y".repeat(300));
+
+        let mut input = b"*** Booting Zephyr OS ***\r\n".to_vec();
+        input.extend_from_slice(&smp_framing::encode_lines(&short));
+        input.extend_from_slice(b"[00:00:01.000] <inf> main: tick\n");
+        // lines ending with CRLF, like from a terminal
+        let lines = smp_framing::encode_lines(&long);
+        for line in lines.split_inclusive(|b| *b == b'\n') {
+            input.extend_from_slice(&line[..line.len() - 1]);
+            input.extend_from_slice(b"\r\n");
+        }
+
+        assert_eq!(decode_serial(&input).unwrap(), [short, long]);
+    }
+
+    #[test]
+    fn incomplete_serial_frames_are_left_out() {
+        let frame = setting_write(1, &"y".repeat(300));
+        let lines = smp_framing::encode_lines(&frame);
+        let first_line = lines.iter().position(|b| *b == b'\n').unwrap() + 1;
+        assert!(decode_serial(&lines[..first_line]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn hex_input_with_separators() {
+        assert_eq!(
+            parse_hex_input("02 00:00 0a\n00 03").unwrap(),
+            [0x02, 0x00, 0x00, 0x0a, 0x00, 0x03]
+        );
+        assert!(parse_hex_input("0g").is_err());
+    }
+}
@@ -0,0 +1,1049 @@
+use std::cmp::min;
+use std::error::Error;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use clap::ValueEnum;
+use mcumgr_smp::{
+    fs_management::{
+        self, FileCloseResult, FileDownloadResult, FileHashResult, FileStatusResult,
+        FileUploadResult, HashOutput, RangeReader, ReadAction, SupportedHashesResult,
+    },
+    os_management::{self, McumgrParamsResult},
+    shell_management::{self, ShellResult},
+    smp::SmpFrame,
+    ReturnCode,
+};
+use serde::Serialize;
+use sha2::Digest;
+use tracing::debug;
+
+use crate::context::Context;
+use crate::error::CliError;
+use crate::interrupt::{self, Resume};
+use crate::output::OutputFormat;
+use crate::pacing::{Pacer, RateLimit, UploadReport};
+use crate::progress::Progress;
+use crate::{image, out, outln, probe, shell, status, UsedTransport};
+
+/// Chunk size of uploads if the device doesn't report its buffer size
+pub const DEFAULT_CHUNK_SIZE: usize = 256;
+
+/// Room for the SMP header and the CBOR encoding of an upload request, besides data and name
+const UPLOAD_OVERHEAD: usize = 8 + 48;
+
+/// Smallest chunk size derived from the device buffer size
+const MIN_CHUNK_SIZE: usize = 32;
+
+/// Turn an error rc of a file request into an error naming the file
+pub fn file_error(rc: i32, path: &str) -> Box<dyn Error> {
+    let err = Box::new(CliError::device(rc));
+    let err = match ReturnCode::try_from(rc) {
+        Ok(ReturnCode::NoEntry) => CliError::context(err, |_| {
+            format!("{}: no such file or directory on the device", path)
+        }),
+        _ => CliError::context(err, |e| format!("{}: {}", path, e)),
+    };
+    Box::new(err)
+}
+
+/// Pick the upload chunk size, from the command line or the buffer size of the device
+pub async fn chunk_size(
+    ctx: &Context,
+    transport: &mut UsedTransport,
+    chunk_size: Option<usize>,
+    remote: &str,
+) -> Result<usize, Box<dyn Error>> {
+    upload_chunk_size(ctx, transport, chunk_size, UPLOAD_OVERHEAD + remote.len()).await
+}
+
+/// Like [chunk_size], for uploads whose requests take `overhead` bytes besides the data, e.g.
+/// firmware uploads
+pub async fn upload_chunk_size(
+    ctx: &Context,
+    transport: &mut UsedTransport,
+    chunk_size: Option<usize>,
+    overhead: usize,
+) -> Result<usize, Box<dyn Error>> {
+    if let Some(chunk_size) = chunk_size {
+        if chunk_size == 0 {
+            Err(CliError::Usage(
+                "--chunk-size must be at least 1".to_string(),
+            ))?;
+        }
+        return Ok(chunk_size);
+    }
+
+    // the probe after connecting already asked for the buffer size
+    let buf_size = match probe::connected(ctx) {
+        Some(info) => info.buf_size,
+        None => {
+            let ret: Result<SmpFrame<McumgrParamsResult>, _> = transport
+                .transceive_cbor(&os_management::mcumgr_params(ctx.sequence.next()))
+                .await;
+            debug!("{:?}", ret);
+            match ret {
+                Ok(SmpFrame {
+                    data: McumgrParamsResult::Ok { buf_size, .. },
+                    ..
+                }) => Some(buf_size),
+                _ => None,
+            }
+        }
+    };
+
+    match buf_size {
+        Some(buf_size) => {
+            let chunk_size = (buf_size as usize)
+                .saturating_sub(overhead)
+                .max(MIN_CHUNK_SIZE);
+            debug!("device buffer size {}, chunk size {}", buf_size, chunk_size);
+            Ok(chunk_size)
+        }
+        None => {
+            debug!(
+                "buffer size unknown, using chunk size {}",
+                DEFAULT_CHUNK_SIZE
+            );
+            Ok(DEFAULT_CHUNK_SIZE)
+        }
+    }
+}
+
+/// Query the length of a file on the device
+pub async fn file_len(
+    ctx: &Context,
+    transport: &mut UsedTransport,
+    remote: &str,
+) -> Result<u64, Box<dyn Error>> {
+    let ret: SmpFrame<FileStatusResult> = transport
+        .transceive_cbor(&fs_management::status(
+            ctx.sequence.next(),
+            remote.to_string(),
+        ))
+        .await?;
+    debug!("{:?}", ret);
+
+    match ret.data {
+        FileStatusResult::Ok { len } => Ok(len),
+        FileStatusResult::Err { rc } => Err(file_error(rc, remote)),
+    }
+}
+
+/// Release the file the device keeps open after a transfer.
+///
+/// Older devices don't support this and close the file after a timeout, so errors are ignored.
+async fn close(ctx: &Context, transport: &mut UsedTransport) {
+    let ret: Result<SmpFrame<FileCloseResult>, _> = transport
+        .transceive_cbor(&fs_management::close(ctx.sequence.next()))
+        .await;
+    debug!("close: {:?}", ret);
+}
+
+/// Result of comparing the hash computed by the device with the local data
+#[derive(Serialize, Debug)]
+pub struct HashCheck {
+    #[serde(rename = "type")]
+    pub type_: String,
+    pub device: String,
+    pub local: String,
+    #[serde(rename = "match")]
+    pub matches: bool,
+}
+
+/// Format the output of the hash command, checksums as fixed width hex like the hashes
+pub fn format_hash_output(output: &HashOutput) -> String {
+    match output {
+        HashOutput::Hash(hash) => image::hex(hash),
+        HashOutput::Checksum(checksum) => format!("{:08x}", checksum),
+    }
+}
+
+/// Hash and checksum types that can also be computed locally, best first
+#[derive(ValueEnum, Copy, Clone, Debug, PartialEq, Eq)]
+pub enum HashType {
+    Sha256,
+    Crc32,
+}
+
+impl HashType {
+    /// The name of the type in the hash command
+    pub fn name(self) -> &'static str {
+        match self {
+            HashType::Sha256 => "sha256",
+            HashType::Crc32 => "crc32",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        HashType::value_variants()
+            .iter()
+            .copied()
+            .find(|t| t.name() == name)
+    }
+
+    /// Compute the hash of local data in the format the device uses
+    pub fn compute(self, data: &[u8]) -> HashOutput {
+        match self {
+            HashType::Sha256 => HashOutput::Hash(sha2::Sha256::digest(data).to_vec()),
+            HashType::Crc32 => HashOutput::Checksum(
+                crc::Crc::<u32>::new(&crc::CRC_32_ISO_HDLC).checksum(data) as u64,
+            ),
+        }
+    }
+}
+
+/// Choose the hash type: the forced one, or the best one the device supports.
+///
+/// Returns `None` for devices that can't list their types, they pick one themselves.
+pub async fn pick_hash_type(
+    ctx: &Context,
+    transport: &mut UsedTransport,
+    forced: Option<HashType>,
+) -> Result<Option<HashType>, Box<dyn Error>> {
+    if forced.is_some() {
+        return Ok(forced);
+    }
+
+    let ret: Result<SmpFrame<SupportedHashesResult>, _> = transport
+        .transceive_cbor(&fs_management::supported_hashes(ctx.sequence.next()))
+        .await;
+    debug!("{:?}", ret);
+
+    match ret {
+        Ok(SmpFrame {
+            data: SupportedHashesResult::Ok { types },
+            ..
+        }) => {
+            let best = HashType::value_variants()
+                .iter()
+                .copied()
+                .find(|t| types.contains_key(t.name()));
+            match best {
+                Some(best) => Ok(Some(best)),
+                None => Err(format!(
+                    "the device supports none of the hash types smp-tool can compute: {}",
+                    types.keys().cloned().collect::<Vec<_>>().join(", ")
+                ))?,
+            }
+        }
+        ret => {
+            debug!(
+                "supported hash types unknown, the device picks one: {:?}",
+                ret
+            );
+            Ok(None)
+        }
+    }
+}
+
+/// Let the device compute the hash of a file.
+///
+/// Returns the type the device used and the hash.
+pub async fn device_hash(
+    ctx: &Context,
+    transport: &mut UsedTransport,
+    remote: &str,
+    hash_type: Option<HashType>,
+) -> Result<(String, u64, HashOutput), Box<dyn Error>> {
+    let ret: SmpFrame<FileHashResult> = transport
+        .transceive_cbor(&fs_management::hash(
+            ctx.sequence.next(),
+            remote.to_string(),
+            hash_type.map(|t| t.name().to_string()),
+        ))
+        .await?;
+    debug!("{:?}", ret);
+
+    match ret.data {
+        FileHashResult::Ok {
+            type_, len, output, ..
+        } => Ok((type_, len, output)),
+        FileHashResult::Err { rc } => Err(file_error(rc, remote)),
+    }
+}
+
+/// Let the device compute the hash of a file and compare it with the local data.
+///
+/// Without a forced type, the best type supported by both sides is used.
+pub async fn verify_hash(
+    ctx: &Context,
+    transport: &mut UsedTransport,
+    remote: &str,
+    data: &[u8],
+    hash_type: Option<HashType>,
+) -> Result<HashCheck, Box<dyn Error>> {
+    let hash_type = pick_hash_type(ctx, transport, hash_type).await?;
+    let (type_, _, output) = device_hash(ctx, transport, remote, hash_type).await?;
+
+    let local_type = HashType::from_name(&type_).ok_or_else(|| {
+        format!(
+            "the device used hash type {}, which can't be computed locally",
+            type_
+        )
+    })?;
+    let device = format_hash_output(&output);
+    let local = format_hash_output(&local_type.compute(data));
+
+    Ok(HashCheck {
+        type_,
+        matches: device == local,
+        device,
+        local,
+    })
+}
+
+/// Result of `fs stat`
+#[derive(Serialize, Debug)]
+struct Stat<'a> {
+    remote: &'a str,
+    len: u64,
+}
+
+/// Print the length of a file on the device
+pub async fn stat(
+    ctx: &Context,
+    transport: &mut UsedTransport,
+    remote: &str,
+    format: OutputFormat,
+) -> Result<(), Box<dyn Error>> {
+    let len = file_len(ctx, transport, remote).await?;
+
+    match format {
+        OutputFormat::Text => outln!(ctx, "{}: {} bytes", remote, len),
+        OutputFormat::Json => outln!(
+            ctx,
+            "{}",
+            serde_json::to_string(&Stat { remote, len }).expect("serializing to string can't fail")
+        ),
+    }
+
+    Ok(())
+}
+
+/// Result of `fs hash` without `--compare`
+#[derive(Serialize, Debug)]
+struct Hash<'a> {
+    remote: &'a str,
+    #[serde(rename = "type")]
+    type_: String,
+    len: u64,
+    output: String,
+}
+
+/// Print the hash of a file computed by the device, or compare it with a local file.
+///
+/// A mismatch makes the command fail.
+pub async fn hash(
+    ctx: &Context,
+    transport: &mut UsedTransport,
+    remote: &str,
+    hash_type: Option<HashType>,
+    compare: Option<&Path>,
+    format: OutputFormat,
+) -> Result<(), Box<dyn Error>> {
+    if let Some(compare) = compare {
+        let data = std::fs::read(compare)
+            .map_err(|e| format!("can't read {}: {}", compare.display(), e))?;
+        let check = verify_hash(ctx, transport, remote, &data, hash_type).await?;
+
+        match format {
+            OutputFormat::Text => match check.matches {
+                true => outln!(
+                    ctx,
+                    "{} {}: matches {}",
+                    check.type_,
+                    check.device,
+                    compare.display()
+                ),
+                false => outln!(
+                    ctx,
+                    "{} mismatch: device {}, {} {}",
+                    check.type_,
+                    check.device,
+                    compare.display(),
+                    check.local
+                ),
+            },
+            OutputFormat::Json => outln!(
+                ctx,
+                "{}",
+                serde_json::to_string(&check).expect("serializing to string can't fail")
+            ),
+        }
+
+        if !check.matches {
+            Err(format!(
+                "{} of {} differs from {}",
+                check.type_,
+                remote,
+                compare.display()
+            ))?;
+        }
+        return Ok(());
+    }
+
+    let hash_type = pick_hash_type(ctx, transport, hash_type).await?;
+    let (type_, len, output) = device_hash(ctx, transport, remote, hash_type).await?;
+    let hash = Hash {
+        remote,
+        type_,
+        len,
+        output: format_hash_output(&output),
+    };
+
+    match format {
+        OutputFormat::Text => outln!(ctx, "{} {}  {}", hash.type_, hash.output, remote),
+        OutputFormat::Json => outln!(
+            ctx,
+            "{}",
+            serde_json::to_string(&hash).expect("serializing to string can't fail")
+        ),
+    }
+
+    Ok(())
+}
+
+/// Outcome of a download or upload
+#[derive(Serialize, Debug)]
+pub struct Transfer {
+    pub remote: String,
+    pub local: PathBuf,
+    pub size: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub upload: Option<UploadReport>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hash: Option<HashCheck>,
+}
+
+impl Transfer {
+    /// Print the result and fail if the hash check didn't match
+    pub fn finish(
+        self,
+        ctx: &Context,
+        summary: String,
+        format: OutputFormat,
+    ) -> Result<(), Box<dyn Error>> {
+        match format {
+            OutputFormat::Text => {
+                outln!(ctx, "{}", summary);
+                if let Some(hash) = &self.hash {
+                    match hash.matches {
+                        true => outln!(ctx, "{} verified: {}", hash.type_, hash.device),
+                        false => outln!(
+                            ctx,
+                            "{} mismatch: device {}, local {}",
+                            hash.type_,
+                            hash.device,
+                            hash.local
+                        ),
+                    }
+                }
+            }
+            OutputFormat::Json => outln!(
+                ctx,
+                "{}",
+                serde_json::to_string_pretty(&self).expect("serializing to string can't fail")
+            ),
+        }
+
+        if let Some(hash) = &self.hash {
+            if !hash.matches {
+                Err(format!(
+                    "{} of {} differs between device and {}",
+                    hash.type_,
+                    self.remote,
+                    self.local.display()
+                ))?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// The local file a download is written to, a directory gets the name of the remote file
+fn download_target(remote: &str, local: &Path) -> PathBuf {
+    if local.is_dir() {
+        let name = remote.rsplit('/').next().unwrap_or(remote);
+        local.join(name)
+    } else {
+        local.to_path_buf()
+    }
+}
+
+/// The file a download is written to until it is complete
+fn part_path(target: &Path) -> PathBuf {
+    let mut name = target.file_name().unwrap_or_default().to_os_string();
+    name.push(".part");
+    target.with_file_name(name)
+}
+
+/// Part of a remote file to download
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Range {
+    /// offset of the first byte
+    pub offset: u64,
+    /// bytes to read at most, `None` reads up to the end of the file
+    pub length: Option<u64>,
+}
+
+/// The data of a response that belongs to the range being read
+fn range_data<'r>(action: ReadAction<'r>, remote: &str) -> Result<&'r [u8], Box<dyn Error>> {
+    match action {
+        ReadAction::Continue { data } | ReadAction::Done { data } => Ok(data),
+        ReadAction::Fatal { rc } => Err(file_error(rc, remote)),
+        ReadAction::InvalidOffset { off, expected } => Err(format!(
+            "device sent offset {} instead of the requested {}",
+            off, expected
+        )
+        .into()),
+    }
+}
+
+/// How a file is downloaded
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DownloadOptions {
+    /// the part of the file to download
+    pub range: Range,
+    /// continue an existing partial file instead of starting over
+    pub resume: bool,
+    /// compare the hash of the downloaded data with the one the device reports
+    pub hash: bool,
+}
+
+/// Download a file from the device, or the part of it given by the range of `options`.
+///
+/// The data is written to `<local>.part` first and renamed once complete, so an
+/// interrupted download never looks like a complete file. With `resume`, an existing
+/// partial file is continued instead of starting over.
+pub async fn download(
+    ctx: &Context,
+    transport: &mut UsedTransport,
+    remote: &str,
+    local: &Path,
+    options: &DownloadOptions,
+    format: OutputFormat,
+) -> Result<(), Box<dyn Error>> {
+    let DownloadOptions {
+        range,
+        resume,
+        hash,
+    } = *options;
+    let target = download_target(remote, local);
+    let part = part_path(&target);
+
+    let resumed = match resume {
+        true => std::fs::metadata(&part).map(|m| m.len()).unwrap_or(0),
+        false => 0,
+    };
+    let mut file = match resumed {
+        0 => File::create(&part),
+        _ => OpenOptions::new().append(true).open(&part),
+    }
+    .map_err(|e| format!("can't write {}: {}", part.display(), e))?;
+
+    let mut reader = RangeReader::new(
+        remote,
+        range.offset + resumed,
+        range.length.map(|length| length.saturating_sub(resumed)),
+    );
+    // only the response for offset 0 contains the length of the file
+    if reader.offset > 0 && !reader.is_done() {
+        let len = file_len(ctx, transport, remote).await?;
+        if resumed > 0 {
+            if reader.offset > len {
+                Err(format!(
+                    "{} is larger than {} on the device, remove it to start over",
+                    part.display(),
+                    remote
+                ))?;
+            }
+            status!(ctx, "resuming at {} of {} bytes", reader.offset, len);
+        }
+        reader.file_len = Some(len);
+    }
+
+    let mut progress = Progress::new(ctx, "download", format == OutputFormat::Text);
+    while !reader.is_done() {
+        if interrupt::requested().await {
+            file.sync_all()?;
+            close(ctx, transport).await;
+            let done = reader.offset - range.offset;
+            let total = reader.end().map(|end| end.saturating_sub(range.offset));
+            let stopped = match total {
+                Some(total) => format!("download stopped at {} of {} bytes", done, total),
+                None => format!("download stopped at {} bytes", done),
+            };
+            Err(interrupt::stop(transport, stopped, Resume::Flag("--resume")).await)?;
+        }
+        let mut request = reader.read_chunk();
+        request.sequence = ctx.sequence.next();
+        let ret: SmpFrame<FileDownloadResult> = transport.transceive_cbor(&request).await?;
+
+        let data = range_data(reader.handle_response(&ret.data), remote)?;
+        if data.is_empty() {
+            if let Some(len) = reader.file_len.filter(|len| reader.offset < *len) {
+                Err(format!(
+                    "device sent no data at offset {} of {}",
+                    reader.offset, len
+                ))?;
+            }
+        }
+
+        file.write_all(data)
+            .map_err(|e| format!("can't write {}: {}", part.display(), e))?;
+        progress.update(
+            reader.offset - range.offset,
+            reader.end().map(|end| end.saturating_sub(range.offset)),
+        );
+    }
+    progress.finish();
+    close(ctx, transport).await;
+
+    file.sync_all()?;
+    drop(file);
+    std::fs::rename(&part, &target).map_err(|e| {
+        format!(
+            "can't rename {} to {}: {}",
+            part.display(),
+            target.display(),
+            e
+        )
+    })?;
+
+    let hash = match hash {
+        true => Some(verify_hash(ctx, transport, remote, &std::fs::read(&target)?, None).await?),
+        false => None,
+    };
+
+    let size = reader.offset - range.offset;
+    let summary = match range.offset {
+        0 => format!(
+            "downloaded {} bytes from {} to {}",
+            size,
+            remote,
+            target.display()
+        ),
+        offset => format!(
+            "downloaded {} bytes at offset {} from {} to {}",
+            size,
+            offset,
+            remote,
+            target.display()
+        ),
+    };
+    Transfer {
+        remote: remote.to_string(),
+        local: target,
+        size,
+        upload: None,
+        hash,
+    }
+    .finish(ctx, summary, format)
+}
+
+/// Result of `fs tail`
+#[derive(Serialize, Debug)]
+struct Tail<'a> {
+    remote: &'a str,
+    offset: u64,
+    len: u64,
+    data: String,
+}
+
+/// Print the last `bytes` bytes of a file on the device, the whole file if it is shorter
+pub async fn tail(
+    ctx: &Context,
+    transport: &mut UsedTransport,
+    remote: &str,
+    bytes: u64,
+    format: OutputFormat,
+) -> Result<(), Box<dyn Error>> {
+    let len = file_len(ctx, transport, remote).await?;
+    let offset = len.saturating_sub(bytes);
+    let mut reader = RangeReader::new(remote, offset, None);
+    reader.file_len = Some(len);
+
+    let mut data = Vec::new();
+    while !reader.is_done() {
+        let mut request = reader.read_chunk();
+        request.sequence = ctx.sequence.next();
+        let ret: SmpFrame<FileDownloadResult> = transport.transceive_cbor(&request).await?;
+        data.extend_from_slice(range_data(reader.handle_response(&ret.data), remote)?);
+    }
+    close(ctx, transport).await;
+
+    let data = String::from_utf8_lossy(&data);
+    match format {
+        OutputFormat::Text => out!(ctx, "{}", data),
+        OutputFormat::Json => outln!(
+            ctx,
+            "{}",
+            serde_json::to_string(&Tail {
+                remote,
+                offset,
+                len,
+                data: data.into_owned(),
+            })
+            .expect("serializing to string can't fail")
+        ),
+    }
+
+    Ok(())
+}
+
+/// How a file is uploaded
+#[derive(Debug, Clone, Copy, Default)]
+pub struct UploadOptions {
+    /// bytes of data per request, `None` to size them to the buffers of the device
+    pub chunk_size: Option<usize>,
+    /// compare the hash the device reports with the one of the local file
+    pub hash: bool,
+    pub limit: RateLimit,
+}
+
+/// Upload a file to the device, replacing an existing file
+pub async fn upload(
+    ctx: &Context,
+    transport: &mut UsedTransport,
+    local: &Path,
+    remote: &str,
+    options: &UploadOptions,
+    format: OutputFormat,
+) -> Result<(), Box<dyn Error>> {
+    let UploadOptions {
+        chunk_size,
+        hash,
+        limit,
+    } = *options;
+    let data =
+        std::fs::read(local).map_err(|e| format!("can't read {}: {}", local.display(), e))?;
+    let chunk_size = self::chunk_size(ctx, transport, chunk_size, remote).await?;
+    let len = data.len();
+
+    let mut progress = Progress::new(ctx, "upload", format == OutputFormat::Text);
+    let mut pacer = Pacer::new(limit);
+    let repeated = ctx.retry.repeated();
+    let mut bytes_sent = 0;
+    let mut off = 0;
+    loop {
+        if interrupt::requested().await {
+            close(ctx, transport).await;
+            let stopped = format!("upload stopped at {} of {} bytes", off, len);
+            Err(interrupt::stop(transport, stopped, Resume::Restart).await)?;
+        }
+        pacer.wait().await;
+        // an empty file is still sent as one empty chunk, which creates the file
+        let chunk = &data[off..min(len, off + chunk_size)];
+        bytes_sent += chunk.len() as u64;
+        let ret: SmpFrame<FileUploadResult> = transport
+            .transceive_cbor(&fs_management::upload(
+                ctx.sequence.next(),
+                remote.to_string(),
+                off as u64,
+                chunk,
+                (off == 0).then_some(len as u64),
+            ))
+            .await?;
+
+        match ret.data {
+            FileUploadResult::Ok { off: next } => {
+                let next = next as usize;
+                if next > off + chunk.len() {
+                    Err(format!(
+                        "device reported offset {} beyond the {} bytes sent",
+                        next,
+                        off + chunk.len()
+                    ))?;
+                }
+                if next == 0 && off > 0 {
+                    progress.warn(
+                        off as u64,
+                        Some(len as u64),
+                        "the device started the upload over, e.g. after a reboot, sending the file again",
+                    );
+                } else if next < off {
+                    progress.warn(
+                        off as u64,
+                        Some(len as u64),
+                        &format!(
+                            "the device expects offset {} again, resending from there",
+                            next
+                        ),
+                    );
+                } else if next == off && !chunk.is_empty() {
+                    Err(format!("device didn't accept data at offset {}", off))?;
+                }
+                pacer.confirmed(off as u64, chunk.len() as u64, next as u64);
+                off = next;
+            }
+            FileUploadResult::Err { rc } => Err(file_error(rc, remote))?,
+        }
+        progress.update(off as u64, Some(len as u64));
+
+        if off >= len {
+            break;
+        }
+    }
+    progress.finish();
+    close(ctx, transport).await;
+
+    let hash = match hash {
+        true => Some(verify_hash(ctx, transport, remote, &data, None).await?),
+        false => None,
+    };
+
+    let report = UploadReport {
+        size: len as u64,
+        bytes_sent,
+        duration_secs: pacer.elapsed().as_secs_f64(),
+        average_bytes_per_second: pacer.rate(),
+        peak_bytes_per_second: pacer.peak_rate(),
+        retries: ctx.retry.repeated() - repeated,
+        final_offset: off as u64,
+        window: 1,
+        matched: None,
+        sha256: format!("{:x}", sha2::Sha256::digest(&data)),
+    };
+    let summary = format!(
+        "uploaded {} to {}: {}",
+        local.display(),
+        remote,
+        report.summary()
+    );
+    Transfer {
+        remote: remote.to_string(),
+        local: local.to_path_buf(),
+        size: len as u64,
+        upload: Some(report),
+        hash,
+    }
+    .finish(ctx, summary, format)
+}
+
+/// Printed below a failed `fs ls` on a device without the shell group
+const NO_SHELL_HINT: &str =
+    "listing files requires shell support on the device (CONFIG_MCUMGR_GRP_SHELL)";
+
+/// Printed below output of `fs ls` that can't be parsed
+const LS_PARSE_HINT: &str = "use --raw to print the output of the device as it is";
+
+/// A directory entry parsed from the output of `fs ls` on the device shell
+#[derive(Serialize, Debug, PartialEq, Eq)]
+pub struct DirEntry {
+    pub name: String,
+    /// Only known if the device prints it
+    pub size: Option<u64>,
+    pub dir: bool,
+}
+
+impl DirEntry {
+    /// A trailing `/` marks a directory
+    fn new(name: &str, size: Option<u64>, dir: bool) -> Option<Self> {
+        let dir = dir || name.ends_with('/');
+        let name = name.trim_end_matches('/');
+        if name.is_empty() {
+            return None;
+        }
+        Some(DirEntry {
+            name: name.to_string(),
+            size,
+            dir,
+        })
+    }
+}
+
+/// Parse one line of the output of `fs ls`, `None` if it isn't an entry.
+///
+/// Zephyr prints one entry per line, depending on its version either the bare name with a `/`
+/// after directories, the size before the name, e.g. `     123 file.txt`, or a type tag as in
+/// `[DIR ] logs` and `[FILE] file.txt (123)`.
+pub fn parse_ls_line(line: &str) -> Option<DirEntry> {
+    let trimmed = line.trim();
+
+    if let Some(name) = trimmed
+        .strip_prefix("[DIR ]")
+        .or_else(|| trimmed.strip_prefix("[DIR]"))
+    {
+        return DirEntry::new(name.trim(), None, true);
+    }
+
+    if let Some(rest) = trimmed.strip_prefix("[FILE]") {
+        let rest = rest.trim();
+        let sized = rest
+            .strip_suffix(')')
+            .and_then(|rest| rest.rsplit_once(" ("))
+            .and_then(|(name, size)| {
+                let size = size.trim_end_matches(" bytes").parse().ok()?;
+                Some((name.trim_end(), size))
+            });
+        return match sized {
+            Some((name, size)) => DirEntry::new(name, Some(size), false),
+            None => DirEntry::new(rest, None, false),
+        };
+    }
+
+    // a size column is right-aligned, so these lines start with whitespace
+    if line.starts_with(char::is_whitespace) {
+        let (size, name) = trimmed.split_once(char::is_whitespace)?;
+        return DirEntry::new(name.trim_start(), Some(size.parse().ok()?), false);
+    }
+
+    DirEntry::new(trimmed, None, false)
+}
+
+/// Parse the output of `fs ls`, or return the first line that isn't an entry
+pub fn parse_ls(output: &str) -> Result<Vec<DirEntry>, &str> {
+    output
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| parse_ls_line(line).ok_or(line))
+        .collect()
+}
+
+/// The device shell command run by `fs ls`
+pub fn ls_argv(path: &str) -> Vec<String> {
+    shell::quote_args(&["fs".to_string(), "ls".to_string(), path.to_string()])
+}
+
+/// Result of `fs ls`
+#[derive(Serialize, Debug)]
+struct Listing<'a> {
+    path: &'a str,
+    entries: Vec<DirEntry>,
+}
+
+/// List a directory on the device by running `fs ls` on its shell.
+///
+/// SMP has no request to list a directory, so this needs the shell group and the file system
+/// shell commands on the device. With `raw` the output is printed as it is instead of parsed.
+pub async fn ls(
+    ctx: &Context,
+    transport: &mut UsedTransport,
+    path: &str,
+    raw: bool,
+    format: OutputFormat,
+) -> Result<(), Box<dyn Error>> {
+    let ret: SmpFrame<ShellResult> = transport
+        .transceive_cbor(&shell_management::shell_command(
+            ctx.sequence.next(),
+            ls_argv(path),
+        ))
+        .await?;
+    debug!("{:?}", ret);
+
+    let output = match ret.data {
+        ShellResult::Ok { o, ret: Some(ret) } if ret != 0 => {
+            let err = Box::new(CliError::RemoteStatus(shell::exit_status(ret)));
+            Err(CliError::context(err, |_| {
+                format!("{}: fs ls failed: {}", path, o.trim())
+            }))?
+        }
+        ShellResult::Ok { o, .. } => o,
+        ShellResult::Err { rc } => {
+            Err(CliError::device(rc).hint_on(ReturnCode::NotSupported, NO_SHELL_HINT))?
+        }
+    };
+
+    if raw {
+        out!(ctx, "{}", output);
+        if !output.is_empty() && !output.ends_with('\n') {
+            outln!(ctx);
+        }
+        return Ok(());
+    }
+
+    let entries = parse_ls(&output).map_err(|line| CliError::Hint {
+        hint: LS_PARSE_HINT,
+        source: format!("can't parse the output of fs ls: {:?}", line).into(),
+    })?;
+
+    match format {
+        OutputFormat::Text => {
+            for entry in &entries {
+                let size = match (entry.dir, entry.size) {
+                    (true, _) => "<dir>".to_string(),
+                    (false, Some(size)) => size.to_string(),
+                    (false, None) => "-".to_string(),
+                };
+                let slash = if entry.dir { "/" } else { "" };
+                outln!(ctx, "{:>10}  {}{}", size, entry.name, slash);
+            }
+        }
+        OutputFormat::Json => outln!(
+            ctx,
+            "{}",
+            serde_json::to_string(&Listing { path, entries })
+                .expect("serializing to string can't fail")
+        ),
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(name: &str, size: Option<u64>, dir: bool) -> DirEntry {
+        DirEntry {
+            name: name.to_string(),
+            size,
+            dir,
+        }
+    }
+
+    #[test]
+    fn ls_of_bare_names() {
+        let output = "logs/\nsettings\nboot count.txt\n";
+        assert_eq!(
+            parse_ls(output),
+            Ok(vec![
+                entry("logs", None, true),
+                entry("settings", None, false),
+                entry("boot count.txt", None, false),
+            ])
+        );
+    }
+
+    #[test]
+    fn ls_with_a_size_column() {
+        let output = "\r\n       0 logs/\r\n     123 settings\r\n   65536 boot count.txt\r\n";
+        assert_eq!(
+            parse_ls(output),
+            Ok(vec![
+                entry("logs", Some(0), true),
+                entry("settings", Some(123), false),
+                entry("boot count.txt", Some(65536), false),
+            ])
+        );
+    }
+
+    #[test]
+    fn ls_with_type_tags() {
+        let output = "[DIR ] logs\n[DIR] cache/\n[FILE] settings (123)\n\
+                      [FILE] boot count.txt (65536 bytes)\n[FILE] (odd) name\n";
+        assert_eq!(
+            parse_ls(output),
+            Ok(vec![
+                entry("logs", None, true),
+                entry("cache", None, true),
+                entry("settings", Some(123), false),
+                entry("boot count.txt", Some(65536), false),
+                entry("(odd) name", None, false),
+            ])
+        );
+    }
+
+    #[test]
+    fn ls_output_that_isnt_a_listing() {
+        assert_eq!(
+            parse_ls("settings\n  fs: command not found\n"),
+            Err("  fs: command not found")
+        );
+        assert_eq!(parse_ls("[DIR ] /\n"), Err("[DIR ] /"));
+        assert_eq!(parse_ls("\n\n"), Ok(vec![]));
+    }
+}
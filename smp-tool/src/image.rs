@@ -0,0 +1,164 @@
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+
+const IMAGE_MAGIC: u32 = 0x96f3b83d;
+const IMAGE_HEADER_SIZE: usize = 32;
+
+const IMAGE_TLV_INFO_MAGIC: u16 = 0x6907;
+const IMAGE_TLV_PROT_INFO_MAGIC: u16 = 0x6908;
+const IMAGE_TLV_SHA256: u16 = 0x10;
+const IMAGE_TLV_SHA384: u16 = 0x11;
+const IMAGE_TLV_SHA512: u16 = 0x12;
+
+/// Version as stored in the MCUboot image header
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ImageVersion {
+    pub major: u8,
+    pub minor: u8,
+    pub revision: u16,
+    pub build_num: u32,
+}
+
+impl Display for ImageVersion {
+    /// Formats the version the same way Zephyr reports it in the image state
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.revision)?;
+        if self.build_num != 0 {
+            write!(f, ".{}", self.build_num)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for ImageVersion {
+    type Err = String;
+
+    /// Parse versions like `1.2.3`, `1.2.3.4` or `1.2.3+4`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || format!("invalid image version: {}", s);
+
+        let (version, build_num) = match s.split_once('+') {
+            Some((version, build_num)) => (version, Some(build_num)),
+            None => (s, None),
+        };
+
+        let mut parts = version.split('.');
+        let mut next = || parts.next().ok_or_else(err);
+
+        let major = next()?.parse().map_err(|_| err())?;
+        let minor = next()?.parse().map_err(|_| err())?;
+        let revision = next()?.parse().map_err(|_| err())?;
+        let build_num = match (parts.next(), build_num) {
+            (None, None) => 0,
+            (Some(build_num), None) | (None, Some(build_num)) => {
+                build_num.parse().map_err(|_| err())?
+            }
+            (Some(_), Some(_)) => return Err(err()),
+        };
+
+        if parts.next().is_some() {
+            return Err(err());
+        }
+
+        Ok(ImageVersion {
+            major,
+            minor,
+            revision,
+            build_num,
+        })
+    }
+}
+
+/// The parts of a signed MCUboot image that are relevant for image management
+#[derive(Debug, Clone)]
+pub struct ImageInfo {
+    pub version: ImageVersion,
+    /// Image hash from the TLV area. This is what the device reports in the image state.
+    pub hash: Vec<u8>,
+}
+
+/// Parse the header and TLV area of an MCUboot image.
+///
+/// Returns None if the data does not look like a signed MCUboot image.
+pub fn parse_image(data: &[u8]) -> Option<ImageInfo> {
+    if data.len() < IMAGE_HEADER_SIZE || read_u32(data, 0)? != IMAGE_MAGIC {
+        return None;
+    }
+
+    let hdr_size = read_u16(data, 8)? as usize;
+    let protect_tlv_size = read_u16(data, 10)? as usize;
+    let img_size = read_u32(data, 12)? as usize;
+
+    let version = ImageVersion {
+        major: data[20],
+        minor: data[21],
+        revision: read_u16(data, 22)?,
+        build_num: read_u32(data, 24)?,
+    };
+
+    let mut tlv_offset = hdr_size.checked_add(img_size)?;
+
+    if protect_tlv_size > 0 {
+        if read_u16(data, tlv_offset)? != IMAGE_TLV_PROT_INFO_MAGIC {
+            return None;
+        }
+        tlv_offset = tlv_offset.checked_add(protect_tlv_size)?;
+    }
+
+    if read_u16(data, tlv_offset)? != IMAGE_TLV_INFO_MAGIC {
+        return None;
+    }
+    let tlv_end = tlv_offset.checked_add(read_u16(data, tlv_offset + 2)? as usize)?;
+
+    let mut offset = tlv_offset + 4;
+    while offset + 4 <= tlv_end {
+        let tlv_type = read_u16(data, offset)?;
+        let tlv_len = read_u16(data, offset + 2)? as usize;
+        let value = data.get(offset + 4..offset + 4 + tlv_len)?;
+
+        if matches!(
+            tlv_type,
+            IMAGE_TLV_SHA256 | IMAGE_TLV_SHA384 | IMAGE_TLV_SHA512
+        ) {
+            return Some(ImageInfo {
+                version,
+                hash: value.to_vec(),
+            });
+        }
+
+        offset += 4 + tlv_len;
+    }
+
+    None
+}
+
+/// Format bytes as a lowercase hex string
+pub fn hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Parse a hex string, e.g. an image hash given on the command line
+pub fn parse_hex(s: &str) -> Result<Vec<u8>, String> {
+    if !s.len().is_multiple_of(2) {
+        return Err(format!("hex string has odd length: {}", s));
+    }
+
+    (0..s.len())
+        .step_by(2)
+        .map(|i| {
+            s.get(i..i + 2)
+                .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+                .ok_or_else(|| format!("invalid hex string: {}", s))
+        })
+        .collect()
+}
+
+fn read_u16(data: &[u8], offset: usize) -> Option<u16> {
+    let bytes = data.get(offset..offset + 2)?;
+    Some(u16::from_le_bytes([bytes[0], bytes[1]]))
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Option<u32> {
+    let bytes = data.get(offset..offset + 4)?;
+    Some(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
@@ -0,0 +1,95 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::error::{CliError, EXIT_INTERRUPTED};
+use crate::UsedTransport;
+
+/// Set by the first Ctrl-C while a transfer is running
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+static WATCHING: AtomicBool = AtomicBool::new(false);
+
+/// How an interrupted transfer can be continued
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resume {
+    /// Running the same command again continues where it stopped, e.g. image uploads
+    /// which the device resumes at its offset
+    Rerun,
+    /// The command continues where it stopped with this flag
+    Flag(&'static str),
+    /// The transfer has to start over
+    Restart,
+}
+
+/// Handle Ctrl-C for the rest of the process: a transfer stops after the chunk in flight,
+/// a second Ctrl-C exits immediately.
+///
+/// The handler runs as a task of the current-thread runtime, transfers give it a chance to
+/// run between chunks through [requested].
+pub fn watch() {
+    if WATCHING.swap(true, Ordering::Relaxed) {
+        return;
+    }
+    tokio::spawn(async {
+        if tokio::signal::ctrl_c().await.is_err() {
+            return;
+        }
+        INTERRUPTED.store(true, Ordering::Relaxed);
+        eprintln!("interrupted, stopping after the current chunk, press Ctrl-C again to quit");
+
+        if tokio::signal::ctrl_c().await.is_ok() {
+            std::process::exit(EXIT_INTERRUPTED.into());
+        }
+    });
+}
+
+/// Whether the transfer should stop before sending the next chunk
+pub async fn requested() -> bool {
+    // lets the handler task see a signal that arrived during a blocking transceive
+    tokio::task::yield_now().await;
+    INTERRUPTED.load(Ordering::Relaxed)
+}
+
+/// Close the connection of an interrupted transfer and explain how to continue it.
+///
+/// `progress` describes how far the transfer got, e.g. `upload stopped at 1024 of 4096 bytes`.
+pub async fn stop(transport: &mut UsedTransport, progress: String, resume: Resume) -> CliError {
+    if let Err(e) = transport.close().await {
+        eprintln!("warning: can't close the connection: {}", e);
+    }
+
+    let command = command_line(match resume {
+        Resume::Flag(flag) => Some(flag),
+        Resume::Rerun | Resume::Restart => None,
+    });
+    let hint = match resume {
+        Resume::Rerun | Resume::Flag(_) => "continue with",
+        Resume::Restart => "this transfer can't be resumed, start over with",
+    };
+    CliError::Interrupted(format!("{}\n{}: {}", progress, hint, command))
+}
+
+/// The command line of this process, with `flag` appended unless it is already given
+fn command_line(flag: Option<&str>) -> String {
+    let mut args: Vec<String> = std::env::args().collect();
+    if let Some(flag) = flag {
+        if !args.iter().any(|arg| arg == flag) {
+            args.push(flag.to_string());
+        }
+    }
+    args.iter()
+        .map(|arg| quote(arg))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Quote an argument for POSIX shells, if necessary
+fn quote(arg: &str) -> String {
+    let plain = !arg.is_empty()
+        && arg
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_./:=,@+%".contains(c));
+    if plain {
+        arg.to_string()
+    } else {
+        format!("'{}'", arg.replace('\'', r"'\''"))
+    }
+}
@@ -0,0 +1,323 @@
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::time::Duration;
+
+use clap::ValueEnum;
+use mcumgr_smp::{
+    log_management::{
+        self, ClearResult, LogEntry, LogItem, LogMessage, LogReader, ModuleListResult, ShowResult,
+    },
+    smp::SmpFrame,
+};
+use serde::Serialize;
+use tracing::debug;
+
+use crate::context::Context;
+use crate::datetime::civil_from_days;
+use crate::error::CliError;
+use crate::output::{self, OutputFormat};
+use crate::{image, outln, UsedTransport};
+
+/// Log levels, ordered by severity
+#[derive(ValueEnum, Serialize, Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Level {
+    #[default]
+    Debug,
+    Info,
+    Warn,
+    Error,
+    Critical,
+}
+
+impl Level {
+    pub fn from_u8(level: u8) -> Self {
+        match level {
+            0 => Level::Debug,
+            1 => Level::Info,
+            2 => Level::Warn,
+            3 => Level::Error,
+            _ => Level::Critical,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Level::Debug => "DEBUG",
+            Level::Info => "INFO",
+            Level::Warn => "WARN",
+            Level::Error => "ERROR",
+            Level::Critical => "CRIT",
+        }
+    }
+
+    /// ANSI color of the level in terminal output
+    fn color(self) -> &'static str {
+        match self {
+            Level::Debug => "\x1b[2m",
+            Level::Info => "\x1b[32m",
+            Level::Warn => "\x1b[33m",
+            Level::Error => "\x1b[31m",
+            Level::Critical => "\x1b[1;31m",
+        }
+    }
+}
+
+/// Parameters of `log tail`
+pub struct TailOptions {
+    pub lines: u32,
+    pub follow: bool,
+    pub interval: Duration,
+    pub module: Option<String>,
+    pub min_level: Level,
+    pub log: Option<String>,
+}
+
+/// Timestamps before this are taken as time since boot, in microseconds
+const EPOCH_2000_US: i64 = 946_684_800_000_000;
+
+/// Format a log timestamp as UTC date and time, or as uptime for devices without a clock
+pub fn format_ts(ts: i64) -> String {
+    let ms = ts.div_euclid(1000);
+    if ts < EPOCH_2000_US {
+        return format!("+{}.{:03}s", ms / 1000, ms % 1000);
+    }
+
+    let (year, month, day) = civil_from_days(ms.div_euclid(86_400_000));
+    let ms = ms.rem_euclid(86_400_000);
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}.{:03}",
+        year,
+        month,
+        day,
+        ms / 3_600_000,
+        ms / 60_000 % 60,
+        ms / 1000 % 60,
+        ms % 1000
+    )
+}
+
+pub fn format_msg(msg: &LogMessage) -> String {
+    match msg {
+        LogMessage::Text(text) => text.trim_end().to_string(),
+        LogMessage::Binary(data) => image::hex(data),
+    }
+}
+
+/// Query the module names, empty if the device doesn't support it
+pub async fn module_names(ctx: &Context, transport: &mut UsedTransport) -> BTreeMap<String, u16> {
+    let ret: Result<SmpFrame<ModuleListResult>, _> = transport
+        .transceive_cbor(&log_management::module_list(ctx.sequence.next()))
+        .await;
+    debug!("{:?}", ret);
+
+    match ret {
+        Ok(SmpFrame {
+            data: ModuleListResult::Ok { module_map },
+            ..
+        }) => module_map,
+        _ => BTreeMap::new(),
+    }
+}
+
+/// Resolve `--module`, given as name or number
+fn module_id(module: &str, names: &BTreeMap<String, u16>) -> Result<u16, CliError> {
+    if let Ok(id) = module.parse() {
+        return Ok(id);
+    }
+
+    names.get(module).copied().ok_or_else(|| {
+        CliError::Usage(format!(
+            "unknown module {}, known modules: {}",
+            module,
+            names.keys().cloned().collect::<Vec<_>>().join(", ")
+        ))
+    })
+}
+
+pub async fn show(
+    ctx: &Context,
+    transport: &mut UsedTransport,
+    log: Option<&str>,
+    index: u32,
+) -> Result<(u32, Vec<log_management::Log>), Box<dyn Error>> {
+    let ret: SmpFrame<ShowResult> = transport
+        .transceive_cbor(&log_management::show(
+            ctx.sequence.next(),
+            log.map(str::to_string),
+            index,
+        ))
+        .await?;
+    debug!("{:?}", ret);
+
+    match ret.data {
+        ShowResult::Ok { next_index, logs } => Ok((next_index, logs)),
+        ShowResult::Err { rc } => Err(CliError::device(rc).into()),
+    }
+}
+
+/// A log entry, printed as one JSON line
+#[derive(Serialize, Debug)]
+struct EntryJson<'a> {
+    log: &'a str,
+    index: u32,
+    ts: i64,
+    level: Level,
+    module: String,
+    msg: String,
+}
+
+struct Printer {
+    format: OutputFormat,
+    color: bool,
+    modules: HashMap<u16, String>,
+}
+
+impl Printer {
+    fn module(&self, id: u16) -> String {
+        self.modules
+            .get(&id)
+            .cloned()
+            .unwrap_or_else(|| id.to_string())
+    }
+
+    fn entry(&self, ctx: &Context, log: &str, entry: &LogEntry) {
+        let level = Level::from_u8(entry.level);
+        match self.format {
+            OutputFormat::Text => {
+                let (color, reset) = match self.color {
+                    true => (level.color(), "\x1b[0m"),
+                    false => ("", ""),
+                };
+                outln!(
+                    ctx,
+                    "{} {}{:<5}{} {}: {}",
+                    format_ts(entry.ts),
+                    color,
+                    level.name(),
+                    reset,
+                    self.module(entry.module),
+                    format_msg(&entry.msg)
+                );
+            }
+            OutputFormat::Json => outln!(
+                ctx,
+                "{}",
+                serde_json::to_string(&EntryJson {
+                    log,
+                    index: entry.index,
+                    ts: entry.ts,
+                    level,
+                    module: self.module(entry.module),
+                    msg: format_msg(&entry.msg),
+                })
+                .expect("serializing to string can't fail")
+            ),
+        }
+    }
+
+    fn note(&self, ctx: &Context, text: &str, json: serde_json::Value) {
+        match self.format {
+            OutputFormat::Text => outln!(ctx, "--- {} ---", text),
+            OutputFormat::Json => outln!(ctx, "{}", json),
+        }
+    }
+}
+
+/// Print the newest log entries and, with `follow`, poll for new ones until Ctrl-C is pressed.
+///
+/// Entries are read with a [LogReader], so they are never printed twice and entries that were
+/// overwritten in the device buffer before they could be read are reported as missed.
+pub async fn tail(
+    ctx: &Context,
+    transport: &mut UsedTransport,
+    options: &TailOptions,
+    format: OutputFormat,
+) -> Result<(), Box<dyn Error>> {
+    let names = module_names(ctx, transport).await;
+    let module = match &options.module {
+        Some(module) => Some(module_id(module, &names)?),
+        None => None,
+    };
+    let printer = Printer {
+        format,
+        color: format == OutputFormat::Text && output::stdout_is_terminal(ctx),
+        modules: names.into_iter().map(|(name, id)| (id, name)).collect(),
+    };
+    let log = options.log.as_deref();
+
+    // a request beyond the newest entry returns only the next index
+    let (next_index, _) = show(ctx, transport, log, u32::MAX).await?;
+    let mut reader = LogReader::new(
+        log.map(str::to_string),
+        next_index.saturating_sub(options.lines),
+    );
+
+    let ctrl_c = tokio::signal::ctrl_c();
+    tokio::pin!(ctrl_c);
+
+    loop {
+        let mut request = reader.next_batch();
+        request.sequence = ctx.sequence.next();
+        let ret: SmpFrame<ShowResult> = tokio::select! {
+            biased;
+            _ = &mut ctrl_c => break,
+            ret = transport.transceive_cbor(&request) => ret?,
+        };
+        debug!("{:?}", ret);
+        let items = reader
+            .handle_response(&ret.data)
+            .map_err(CliError::device)?;
+
+        for item in &items {
+            match item {
+                LogItem::Entry { log, entry } => {
+                    let level = Level::from_u8(entry.level);
+                    if level >= options.min_level && module.is_none_or(|m| m == entry.module) {
+                        printer.entry(ctx, log, entry);
+                    }
+                }
+                LogItem::Gap { missed } => printer.note(
+                    ctx,
+                    &format!("missed {} entries", missed),
+                    serde_json::json!({ "missed": missed }),
+                ),
+                LogItem::Restarted => printer.note(
+                    ctx,
+                    "log index went backwards, the device rebooted or the logs were cleared",
+                    serde_json::json!({ "restarted": true }),
+                ),
+            }
+        }
+
+        // the response was limited by the buffer size, fetch the rest right away
+        if reader.has_more() {
+            continue;
+        }
+        if !options.follow {
+            break;
+        }
+
+        tokio::select! {
+            _ = &mut ctrl_c => break,
+            _ = tokio::time::sleep(options.interval) => {}
+        }
+    }
+
+    Ok(())
+}
+
+/// Delete all log entries
+pub async fn clear(ctx: &Context, transport: &mut UsedTransport) -> Result<(), Box<dyn Error>> {
+    let ret: SmpFrame<ClearResult> = transport
+        .transceive_cbor(&log_management::clear(ctx.sequence.next()))
+        .await?;
+    debug!("{:?}", ret);
+
+    match ret.data {
+        ClearResult::Ok {} => outln!(ctx, "success"),
+        ClearResult::Err { rc } => Err(CliError::device(rc))?,
+    }
+
+    Ok(())
+}
@@ -1,74 +1,337 @@
 // Author: Sascha Zenglein <zenglein@gessler.de>
 // Copyright (c) 2023 Gessler GmbH.
 
-use std::cmp::min;
 use std::error::Error;
+use std::io::Read;
+use std::net::{Ipv4Addr, SocketAddr};
 use std::path::PathBuf;
+use std::process::ExitCode;
+use std::sync::Arc;
 use std::time::Duration;
 
-use clap::{Parser, Subcommand, ValueEnum};
+use clap::{ArgAction, ArgMatches, CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum};
 use mcumgr_smp::{
-    application_management::{self, GetImageStateResult, WriteImageChunkResult},
+    application_management::{self, EraseImageResult, GetImageStateResult},
     os_management::{self, EchoResult, ResetResult},
-    setting_management::{self, ReadSettingResult, SaveSettingResult, WriteSettingResult},
+    setting_management::{
+        self, NameRules, ReadSettingResult, SaveSettingResult, WriteSettingResult,
+    },
     shell_management::{self, ShellResult},
     smp::SmpFrame,
     transport::{
-        ble::BleTransport,
+        ble::{BleTimeouts, BleTransport},
+        filter::TransportStats,
+        retry::RetryPolicy,
         serial::SerialTransport,
         smp::{CborSmpTransport, CborSmpTransportAsync},
+        timeouts::{OperationClass, Timeouts},
         udp::UdpTransportAsync,
     },
 };
-use sha2::Digest;
+use serde::{Deserialize, Serialize};
 use tracing::debug;
 use tracing_subscriber::prelude::*;
 
+use context::Context;
+use error::CliError;
+use output::OutputFormat;
+use progress::{Progress, ProgressFormat};
+use retry::Retry;
+use sequence::Sequence;
+
+/// Serving commands over a held connection on a local socket
+pub mod agent;
+/// Selecting the serial port of the only connected development kit
+pub mod autodetect;
+/// Link latency and throughput measurement
+pub mod bench;
+/// JSON and CBOR payload conversion
+pub mod cbor;
+/// Configuration file with device profiles
+pub mod config;
+/// State of a session, passed to every command
+pub mod context;
+/// Interactive overview of images, tasks, statistics and logs
+pub mod dashboard;
+/// Reading and setting the device clock
+pub mod datetime;
+/// Zephyr dfu_application.zip support
+pub mod dfu_package;
+/// Finding devices on the local network
+pub mod discover;
+/// Printing requests instead of sending them
+pub mod dry_run;
+/// Hex dumps of the frames on the wire
+pub mod dump;
+/// Repeated echo requests with response checks
+pub mod echo;
+/// Error types and process exit codes
+pub mod error;
+/// Image upload helpers
+pub mod flash;
+/// Raw frame encoding and inspection
+pub mod frame;
+/// File transfers
+pub mod fs;
+/// MCUboot image parsing
+pub mod image;
+/// Stopping transfers with Ctrl-C
+pub mod interrupt;
+/// Reading the logs of the device
+pub mod logs;
+/// output formatting
+pub mod output;
+/// Rate limiting of uploads
+pub mod pacing;
+/// Periodic echo requests for link supervision
+pub mod ping;
+/// Device information and the combined probe
+pub mod probe;
+/// Progress bars for transfers
+pub mod progress;
+/// Relaying frames of UDP clients to the device
+pub mod proxy;
+/// Recording sessions and replaying them without the device
+pub mod replay;
+/// Resetting the device and waiting for it to come back
+pub mod reset;
+/// Repeating requests whose response got lost
+pub mod retry;
+/// Serving commands as JSON-RPC on stdio
+pub mod rpc;
+/// Executing several commands over one connection
+pub mod script;
+/// Sequence numbers of outgoing requests
+pub mod sequence;
+/// Line settings of the serial transport
+pub mod serial;
+/// Bulk export and import of settings
+pub mod settings;
 /// interactive shell support
 pub mod shell;
+/// Reading and polling statistics counters
+pub mod stats;
+/// Running a command against many devices at once
+pub mod targets;
+/// Task statistics table
+pub mod taskstat;
+/// JSON-lines transcript of a session
+pub mod transcript;
+/// Waiting for a device that isn't there yet
+pub mod wait;
 
-#[derive(ValueEnum, Copy, Clone, Debug)]
+#[derive(ValueEnum, Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum Transport {
     Serial,
     Udp,
     Ble,
+    Replay,
 }
 
-#[derive(Parser, Debug)]
+#[derive(Parser, Debug, Clone)]
 #[command(
     author,
     version,
     about = "Command-line tool to send and receive SMP messages.",
     before_help = "Copyright (c) 2023 Gessler GmbH.",
-    help_template = "{about-with-newline}\nAuthor: {author-with-newline}{before-help}{usage-heading} {usage}\n\n{all-args}"
+    help_template = "{about-with-newline}\nAuthor: {author-with-newline}{before-help}{usage-heading} {usage}\n\n{all-args}{after-help}",
+    after_help = error::EXIT_CODES_HELP
 )]
-struct Cli {
-    #[arg(short, long, value_enum)]
-    transport: Transport,
+pub struct Cli {
+    /// Transport to the device. Defaults to serial if --serial-device is given, or else the
+    /// only connected USB CDC ACM device
+    #[arg(short, long, value_enum, env = "SMP_TRANSPORT")]
+    transport: Option<Transport>,
+
+    /// Require --transport instead of selecting a USB serial device
+    #[arg(long, env = "SMP_NO_AUTODETECT")]
+    no_autodetect: bool,
+
+    /// Wait up to this long for the device before running the command, e.g. for a board that
+    /// was just power cycled, retrying to open the port, find the BLE device or get an answer
+    /// to the probe. Without a value 60s, a value needs `=`, e.g. `--wait-for-device=2m`
+    #[arg(
+        long,
+        value_name = "TIMEOUT",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "60s",
+        value_parser = ping::parse_duration
+    )]
+    wait_for_device: Option<Duration>,
 
-    #[arg(short, long, required_if_eq("transport", "serial"))]
+    /// Don't check that the device answers SMP right after connecting. Without the check a
+    /// wrong port or baud rate is only noticed by the first request of the command
+    #[arg(long, env = "SMP_NO_PROBE")]
+    no_probe: bool,
+
+    /// Send setting names as they are. By default names with empty segments, a trailing `/`
+    /// or more than Zephyr's 64 bytes or 8 segments are rejected before anything is sent
+    #[arg(long, env = "SMP_NO_NAME_CHECK")]
+    no_name_check: bool,
+
+    /// Serial port, required for the serial transport
+    #[arg(short, long, env = "SMP_SERIAL_DEVICE")]
     serial_device: Option<String>,
 
-    #[arg(short = 'b', long, default_value_t = 115200)]
+    #[arg(short = 'b', long, default_value_t = 115200, env = "SMP_SERIAL_BAUD")]
     serial_baud: u32,
 
-    #[arg(short = 'd', long, required_if_eq("transport", "udp"))]
+    /// Data bits, parity and stop bits of the serial port, e.g. 8E1 for RS-485 adapters
+    #[arg(
+        long,
+        default_value = "8N1",
+        value_parser = serial::parse_line_config,
+        env = "SMP_SERIAL_CONFIG"
+    )]
+    serial_config: serial::LineConfig,
+
+    /// Flow control of the serial port
+    #[arg(long, value_enum, default_value_t, env = "SMP_SERIAL_FLOW")]
+    serial_flow: serial::Flow,
+
+    /// Host, required for the udp transport. `auto` connects to the only device answering
+    /// a discovery broadcast
+    #[arg(short = 'd', long, env = "SMP_DEST_HOST")]
     dest_host: Option<String>,
 
-    #[arg(short = 'p', long, default_value_t = 1337)]
+    #[arg(short = 'p', long, default_value_t = 1337, env = "SMP_UDP_PORT")]
     udp_port: u16,
 
-    #[arg(long, default_value_t = 5000)]
+    /// How long to wait for responses to fast requests like echo, setting and image state
+    /// reads. A dead connection is noticed after this long
+    #[arg(long, default_value_t = 5000, env = "SMP_TIMEOUT_MS")]
     timeout_ms: u64,
 
-    #[arg(short, long, required_if_eq("transport", "ble"))]
+    /// How long to wait for responses to requests that erase flash, i.e. an image erase and
+    /// the first chunk of an upload
+    #[arg(long, default_value_t = 30000, env = "SMP_SLOW_TIMEOUT_MS")]
+    slow_timeout_ms: u64,
+
+    /// Repeat requests whose response got lost up to this many times. Only reads, echo,
+    /// setting writes and upload chunks are repeated, a reset only if nothing was received
+    #[arg(long, default_value_t = 0, env = "SMP_RETRIES")]
+    retries: u32,
+
+    /// Wait this long before repeating a request, a late response arriving meanwhile is used
+    #[arg(
+        long,
+        value_name = "MS",
+        default_value_t = 500,
+        env = "SMP_RETRY_DELAY_MS"
+    )]
+    retry_delay_ms: u64,
+
+    /// Sequence number of the first request, later requests count up from it.
+    /// Defaults to a random number
+    #[arg(long, env = "SMP_SEQ")]
+    seq: Option<u8>,
+
+    /// Advertised device name, required for the ble transport
+    #[arg(short, long, env = "SMP_NAME")]
     name: Option<String>,
 
+    /// How long each step of a BLE connection may take: connecting, service discovery,
+    /// subscribing and writing a request. Scanning takes --timeout-ms
+    #[arg(long, default_value_t = 10000, env = "SMP_BLE_STEP_TIMEOUT_MS")]
+    ble_step_timeout_ms: u64,
+
+    /// Configuration file, defaults to ~/.config/smp-tool/config.toml
+    #[arg(long, env = "SMP_CONFIG")]
+    config: Option<PathBuf>,
+
+    /// Use the connection settings of a profile from the configuration file.
+    /// Command line flags and environment variables take precedence
+    #[arg(long, env = "SMP_PROFILE")]
+    profile: Option<String>,
+
+    /// Output format
+    #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+    format: OutputFormat,
+
+    /// How progress is reported on stderr
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = ProgressFormat::Bar,
+        long_help = progress::PROGRESS_HELP
+    )]
+    progress: ProgressFormat,
+
+    /// Also print the raw responses to stderr. Twice (-vv) dumps every frame sent and
+    /// received, including the serial console framing
+    #[arg(short, long, action = ArgAction::Count)]
+    verbose: u8,
+
+    /// Only print the result on stdout and warnings and errors on stderr, no status
+    /// messages or progress bars
+    #[arg(short, long, env = "SMP_QUIET", conflicts_with = "verbose")]
+    quiet: bool,
+
+    /// Bytes of each frame shown by -vv, longer frames like upload chunks are truncated.
+    /// 0 shows complete frames
+    #[arg(long, default_value_t = 256)]
+    dump_limit: usize,
+
+    /// Print the requests instead of sending them. No transport is needed
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Append a JSON-lines transcript of the session to this file
+    #[arg(long, env = "SMP_LOG_FILE", long_help = transcript::LOG_FILE_HELP)]
+    log_file: Option<PathBuf>,
+
+    /// Include every frame as hex in the transcript
+    #[arg(long, requires = "log_file")]
+    log_frames: bool,
+
+    /// Replace setting values in the transcript. Without it they are logged verbatim
+    #[arg(long, requires = "log_file")]
+    redact_values: bool,
+
+    /// Record every frame sent and received to this file, to be replayed with
+    /// `--transport replay --replay-file FILE` without the device
+    #[arg(long, value_name = "FILE")]
+    record: Option<PathBuf>,
+
+    /// Recording served by the replay transport. Requests have to match the recorded ones
+    /// in order, an unexpected request fails
+    #[arg(long, value_name = "FILE", env = "SMP_REPLAY_FILE")]
+    replay_file: Option<PathBuf>,
+
+    /// How requests are compared to the recorded ones by the replay transport
+    #[arg(long, value_enum, default_value_t, requires = "replay_file")]
+    replay_match: replay::ReplayMatch,
+
+    /// Send the command to an agent listening on this socket, see `agent --help`, instead
+    /// of connecting to the device
+    #[arg(long, value_name = "SOCKET", env = "SMP_VIA")]
+    via: Option<PathBuf>,
+
+    /// Run the command against all targets of this file concurrently, for app flash,
+    /// app update and setting import. One target per line: a profile name or e.g.
+    /// `serial:/dev/ttyACM0@115200`, `udp:192.168.1.10:1337` or `ble:name`
+    #[arg(long)]
+    targets: Option<PathBuf>,
+
+    /// Maximum number of targets handled at the same time
+    #[arg(long, default_value_t = 4, requires = "targets")]
+    parallel: usize,
+
+    /// Maximum number of concurrent targets of a transport type, e.g. ble=3. Can be repeated
+    #[arg(long, value_parser = targets::parse_limit, requires = "targets")]
+    transport_limit: Vec<(Transport, usize)>,
+
+    /// The target of a single process of a --targets run
+    #[arg(long, env = "SMP_TARGET", hide = true)]
+    target: Option<String>,
+
     #[command(subcommand)]
     command: Commands,
 }
 
-#[derive(Subcommand, Debug)]
+#[derive(Subcommand, Debug, Clone)]
 enum Commands {
     /// Send a command in the os group
     #[command(subcommand)]
@@ -82,308 +345,1784 @@ enum Commands {
     /// Send a command in the settings group
     #[command(subcommand)]
     Setting(SettingCmd),
+    /// Transfer files from and to the file system of the device
+    #[command(subcommand)]
+    Fs(FsCmd),
+    /// Read statistics counters
+    #[command(subcommand)]
+    Stat(StatCmd),
+    /// Read the logs of the device
+    #[command(subcommand)]
+    Log(LogCmd),
+    /// Decode a captured frame without connecting to a device
+    Decode {
+        /// Frame as hex. With serial encapsulation, the captured console bytes as hex
+        #[arg(required_unless_present = "stdin")]
+        hex: Option<String>,
+        /// Read the input from stdin instead: hex for raw frames, the captured bytes for serial encapsulation
+        #[arg(long, conflicts_with = "hex")]
+        stdin: bool,
+        #[arg(long, value_enum, default_value_t = frame::Encapsulation::Raw)]
+        encapsulation: frame::Encapsulation,
+    },
+    /// Encode a request without connecting to a device and print its bytes
+    Encode {
+        #[arg(long, value_enum)]
+        op: frame::Op,
+        #[arg(long)]
+        group: u16,
+        #[arg(long)]
+        id: u8,
+        #[arg(long, default_value_t = 0)]
+        seq: u8,
+        /// Payload as JSON, converted to CBOR. Defaults to an empty map
+        #[arg(long)]
+        payload: Option<String>,
+        /// Payload as hex encoded CBOR
+        #[arg(long, conflicts_with = "payload")]
+        payload_cbor_hex: Option<String>,
+        /// Print base64 instead of hex
+        #[arg(long)]
+        base64: bool,
+        /// Wrap the frame in the serial console framing
+        #[arg(long)]
+        serial_framing: bool,
+    },
+    /// Show the profiles of the configuration file
+    #[command(subcommand)]
+    Profiles(ProfilesCmd),
+    /// Send an arbitrary request and print the response
+    Raw {
+        #[arg(long, value_enum)]
+        op: frame::Op,
+        #[arg(long)]
+        group: u16,
+        #[arg(long)]
+        id: u8,
+        /// Payload as JSON, converted to CBOR. Defaults to an empty map
+        #[arg(long)]
+        payload: Option<String>,
+        /// Payload as hex encoded CBOR
+        #[arg(long, conflicts_with = "payload")]
+        payload_cbor_hex: Option<String>,
+        /// Sequence number of the request
+        #[arg(long)]
+        seq: Option<u8>,
+    },
+    /// Execute several commands over one connection
+    Run {
+        /// File with one command per line, e.g. `os echo hi`. Text after # is ignored
+        #[arg(required_unless_present = "commands")]
+        script: Option<PathBuf>,
+        /// Commands separated by ; instead of a script file
+        #[arg(long, conflicts_with = "script")]
+        commands: Option<String>,
+        /// Execute the remaining commands after one failed
+        #[arg(long)]
+        continue_on_error: bool,
+    },
+    /// Keep the connection to the device open and execute the commands of clients
+    #[command(long_about = agent::AGENT_HELP)]
+    Agent {
+        /// Unix socket to listen on, e.g. /tmp/smp-agent.sock
+        #[arg(long, value_name = "SOCKET")]
+        listen: PathBuf,
+    },
+    /// Execute commands sent as JSON-RPC requests, e.g. by an editor plugin
+    #[command(long_about = rpc::SERVE_HELP)]
+    Serve {
+        /// Read requests from stdin and write responses to stdout
+        #[arg(long, required = true)]
+        stdio: bool,
+    },
+    /// Relay SMP frames received over UDP to the device selected by the transport options,
+    /// e.g. to reach a BLE device from machines without Bluetooth
+    Proxy {
+        /// Address to receive the frames of clients on
+        #[arg(long, value_name = "ADDR", default_value = "0.0.0.0:1337")]
+        listen_udp: SocketAddr,
+    },
+    /// Measure the echo round-trip time and optionally the upload throughput
+    Bench {
+        /// Size of the echo payload in bytes
+        #[arg(long, default_value_t = 32)]
+        payload_size: usize,
+        /// Number of echo requests
+        #[arg(long, default_value_t = 100)]
+        count: usize,
+        /// Also upload this many bytes of dummy data to the secondary slot of image 0.
+        /// The data is never marked for test, but overwrites the slot
+        #[arg(long)]
+        upload_bytes: Option<usize>,
+        /// Chunk size of the upload
+        #[arg(long, default_value_t = 256)]
+        chunk_size: usize,
+    },
+    /// Print a summary of the device for bug reports: OS and build, SMP buffers, bootloader
+    /// and active images. Commands the device doesn't support are shown as unsupported
+    Probe,
+    /// Show images, tasks, statistics and the log of the device in an interactive terminal UI,
+    /// refreshed periodically. Press q to quit
+    Dashboard {
+        /// Refresh interval, e.g. 2s. Changed with + and - while running
+        #[arg(long, value_parser = ping::parse_duration, default_value = "2s")]
+        interval: Duration,
+        /// Statistics group to show, can be repeated. Defaults to all groups
+        #[arg(long = "stat", value_name = "GROUP")]
+        stats: Vec<String>,
+        /// Highlight tasks using at least this percentage of their stack
+        #[arg(long, default_value_t = 80.0)]
+        threshold: f64,
+    },
+    /// Find devices on the local network
+    #[command(subcommand)]
+    Udp(UdpCmd),
+}
+
+#[derive(Subcommand, Debug, Clone)]
+enum UdpCmd {
+    /// List the devices answering an echo broadcast or an mDNS query, with their `os info`
+    /// if they answer it. `--dest-host auto` connects to the only device answering a broadcast
+    Discover {
+        /// Query the _mcumgr._udp service with mDNS instead of broadcasting
+        #[arg(long, conflicts_with = "broadcast")]
+        mdns: bool,
+        /// Broadcast address, e.g. 192.168.1.255. Repeat it to search several interfaces.
+        /// Defaults to 255.255.255.255
+        #[arg(long)]
+        broadcast: Vec<Ipv4Addr>,
+        /// How long to collect answers
+        #[arg(long, value_name = "SECONDS", default_value_t = 3)]
+        timeout: u64,
+    },
+}
+
+#[derive(Subcommand, Debug, Clone)]
+enum ProfilesCmd {
+    /// List all profiles
+    List,
 }
 
-#[derive(Subcommand, Debug)]
+#[derive(Subcommand, Debug, Clone)]
 enum OsCmd {
-    /// Send an SMP Echo request
+    /// Send an SMP Echo request. With --count or --size, every response is compared with its
+    /// request and a summary of mismatches and losses is printed
     Echo {
-        msg: String,
+        #[arg(required_unless_present = "size")]
+        msg: Option<String>,
+        /// Send this many requests
+        #[arg(long)]
+        count: Option<u64>,
+        /// Send a generated payload of this many bytes instead of a message
+        #[arg(long, conflicts_with = "msg")]
+        size: Option<usize>,
+        /// Time between requests in milliseconds
+        #[arg(long, value_name = "MS", default_value_t = 0, requires = "count")]
+        interval: u64,
+    },
+    Reset {
+        /// Wait until the device answers an echo again and print how long it was unreachable,
+        /// at most TIMEOUT, e.g. 500ms, 10s or 2m
+        #[arg(
+            long,
+            value_name = "TIMEOUT",
+            num_args = 0..=1,
+            default_missing_value = "30s",
+            value_parser = ping::parse_duration
+        )]
+        wait: Option<Duration>,
+    },
+    /// Send echo requests periodically and report round-trip times and losses
+    Ping {
+        /// Time between requests, e.g. 500ms, 1s or 2m
+        #[arg(long, default_value = "1s", value_parser = ping::parse_duration)]
+        interval: Duration,
+        /// Stop after this many requests, by default ping until Ctrl-C is pressed
+        #[arg(long)]
+        count: Option<u64>,
+        /// Exit with an error after this many consecutive lost requests
+        #[arg(long)]
+        fail_after: Option<u64>,
+        /// Reconnect when the transport fails instead of exiting
+        #[arg(long)]
+        reconnect: bool,
+    },
+    /// Show the statistics of all tasks, tasks with high stack usage are highlighted
+    Taskstat {
+        #[arg(long, value_enum, default_value_t)]
+        sort: taskstat::SortBy,
+        /// Redraw the table periodically with this interval, e.g. 2s
+        #[arg(long, value_parser = ping::parse_duration)]
+        watch: Option<Duration>,
+        /// Highlight tasks using at least this percentage of their stack
+        #[arg(long, default_value_t = 80.0)]
+        threshold: f64,
+    },
+    /// Print OS and build information, one line per field
+    Info {
+        /// Fields to query as `uname` like letters, e.g. `srv`. All fields by default
+        format: Option<String>,
+    },
+    /// Print the bootloader name
+    BootloaderInfo {
+        /// Ask the bootloader for details instead, e.g. `mode` for the MCUboot mode
+        #[arg(long)]
+        query: Option<String>,
+    },
+    /// Print the device time and its offset to the host time
+    Datetime {
+        #[command(subcommand)]
+        action: Option<DatetimeCmd>,
+    },
+}
+#[derive(Subcommand, Debug, Clone)]
+enum DatetimeCmd {
+    /// Set the device time
+    Set {
+        /// RFC 3339 time with UTC offset, e.g. 2024-05-01T12:00:00Z
+        #[arg(value_parser = datetime::parse_rfc3339)]
+        time: i64,
     },
-    Reset {},
+    /// Set the device time to the host time and report the remaining offset
+    Sync,
 }
-#[derive(Subcommand, Debug)]
+#[derive(Subcommand, Debug, Clone)]
 enum ShellCmd {
-    /// Send a shell command via SMP and read the response
-    Exec { cmd: Vec<String> },
+    /// Send a shell command via SMP and print its output
+    #[command(long_about = shell::EXEC_HELP, after_help = shell::EXIT_STATUS_HELP)]
+    Exec {
+        /// Command and arguments, sent to the device unchanged
+        #[arg(
+            required_unless_present = "command",
+            conflicts_with = "command",
+            trailing_var_arg = true,
+            allow_hyphen_values = true
+        )]
+        cmd: Vec<String>,
+        /// Command line to split into arguments on the host, with POSIX shell quoting
+        #[arg(short = 'c', long, value_name = "LINE")]
+        command: Option<String>,
+        /// Exit with 0 and print `ret: N, o: ...` instead of passing through the command status
+        #[arg(long)]
+        no_status_passthrough: bool,
+    },
     /// Start a remote interactive shell using SMP as the backend
     Interactive,
 }
-#[derive(Subcommand, Debug)]
+#[derive(Subcommand, Debug, Clone)]
 enum ApplicationCmd {
     /// Request firmware info
     Info,
-    // /// Erase a partition
-    // Erase {
-    //     #[arg(short, long)]
-    //     slot: u8,
-    // },
-    /// Flash a firmware to an image slot
-    Flash {
+    /// Compare the version of a firmware file with the running image, the exit code tells
+    /// the verdict: 0 up to date, 10 upgrade available, 11 device newer, 12 unknown
+    Compare {
+        /// Signed MCUboot image
+        file: PathBuf,
+        /// Image number to compare with
+        #[arg(short, long, default_value_t = 0)]
+        image: u8,
+    },
+    /// Mark an image for test on the next boot
+    Test {
+        /// Image hash in hex
+        #[arg(required_unless_present = "slot")]
+        hash: Option<String>,
+        /// Look up the hash of the image in this slot instead
+        #[arg(short, long, conflicts_with = "hash")]
+        slot: Option<i32>,
+        /// Image number used for the slot lookup
+        #[arg(short, long, default_value_t = 0, requires = "slot")]
+        image: i32,
+    },
+    /// Make an image permanent
+    Confirm {
+        /// Image hash in hex, defaults to the running image
+        hash: Option<String>,
+    },
+    /// Erase an image slot
+    Erase {
+        /// Slot to erase, defaults to the secondary slot
+        #[arg(short, long)]
+        slot: Option<u32>,
+    },
+    /// Upload a firmware, test it, reset the device and confirm the new image once it is running
+    Update {
         #[arg()]
         update_file: PathBuf,
         #[arg(short, long)]
         slot: Option<u8>,
+        /// Bytes of firmware per request, derived from the buffer size of the device by default
+        #[arg(short, long)]
+        chunk_size: Option<usize>,
+        /// Only allow newer firmware versions
+        #[arg(long)]
+        upgrade: bool,
+        /// Leave the new image in test mode, it will be reverted on the next reset
+        #[arg(long)]
+        no_confirm: bool,
+        /// How long to wait for the device to come back with the new image
+        #[arg(long, default_value_t = 60)]
+        confirm_timeout: u64,
+        /// Skip the upload if the device already runs or has a pending image with the same hash
+        #[arg(long)]
+        skip_if_same: bool,
+        /// Skip the upload if the device runs a newer version
+        #[arg(long)]
+        skip_if_newer: bool,
+    },
+    /// Flash a firmware to an image slot
+    Flash {
+        /// Firmware binary or Zephyr dfu_application.zip package.
+        /// Use `-` to read from stdin, which is buffered completely before the upload starts.
+        #[arg(required_unless_present = "images")]
+        update_file: Option<PathBuf>,
+        #[arg(short, long)]
+        slot: Option<u8>,
+        /// Upload a file to an image number, e.g. `--image 0=app_update.bin --image
+        /// 1=net_core_app_update.bin`. Can be repeated, the images are uploaded in the given
+        /// order before any of them is marked
+        #[arg(
+            long = "image",
+            value_name = "IMAGE=FILE",
+            value_parser = flash::parse_image_file,
+            conflicts_with_all = ["update_file", "slot", "only"]
+        )]
+        images: Vec<(u8, PathBuf)>,
         #[arg(short, long, default_value_t = 256)]
         chunk_size: usize,
+        /// Repeat a chunk this many times after a timeout or a transient device error, with
+        /// increasing delays. Out of memory errors halve the chunk size for the rest of the upload
+        #[arg(long, value_name = "N", default_value_t = 0)]
+        chunk_retries: u32,
         /// Only allow newer firmware versions
         #[arg(long)]
         upgrade: bool,
+        /// Mark the uploaded image for test after a successful upload
+        #[arg(long, conflicts_with = "confirm")]
+        test: bool,
+        /// Mark the uploaded image as permanent after a successful upload
+        #[arg(long)]
+        confirm: bool,
+        /// Only flash the named file of a dfu_application.zip package
+        #[arg(long)]
+        only: Option<String>,
+        /// Erase the target slot before starting the upload
+        #[arg(long)]
+        erase_first: bool,
+        /// Check the image state for the uploaded image after the upload
+        #[arg(long)]
+        verify: bool,
+        /// Skip the upload if the device already runs or has a pending image with the same hash
+        #[arg(long)]
+        skip_if_same: bool,
+        /// Skip the upload if the device runs a newer version
+        #[arg(long)]
+        skip_if_newer: bool,
+        /// Limit the upload to this many confirmed bytes per second
+        #[arg(long, value_name = "BYTES_PER_SEC")]
+        rate_limit: Option<u64>,
+        /// Wait this long after every confirmed chunk
+        #[arg(long, value_name = "MS", default_value_t = 0)]
+        chunk_delay_ms: u64,
     },
 }
 
-#[derive(Subcommand, Debug)]
+#[derive(Subcommand, Debug, Clone)]
+enum FsCmd {
+    /// Download a file from the device
+    Download {
+        /// Path on the device
+        remote: String,
+        /// Local file or directory. The file only appears once the download is complete
+        local: PathBuf,
+        /// Continue an interrupted download from the .part file next to the local file
+        #[arg(long)]
+        resume: bool,
+        /// Compare the hash computed by the device with the downloaded file
+        #[arg(long, conflicts_with_all = ["offset", "length"])]
+        hash: bool,
+        /// Start reading at this offset of the remote file
+        #[arg(long, default_value_t = 0)]
+        offset: u64,
+        /// Read at most this many bytes, fewer if the file ends earlier
+        #[arg(long)]
+        length: Option<u64>,
+    },
+    /// Print the end of a file, e.g. the newest lines of a log
+    Tail {
+        /// Path on the device
+        remote: String,
+        /// How many bytes from the end of the file to print
+        #[arg(short = 'c', long, default_value_t = 4096)]
+        bytes: u64,
+    },
+    /// Upload a file to the device, replacing an existing file
+    Upload {
+        local: PathBuf,
+        /// Path on the device
+        remote: String,
+        /// Chunk size, by default derived from the buffer size reported by the device
+        #[arg(short, long)]
+        chunk_size: Option<usize>,
+        /// Compare the hash computed by the device with the local file
+        #[arg(long)]
+        hash: bool,
+        /// Limit the upload to this many confirmed bytes per second
+        #[arg(long, value_name = "BYTES_PER_SEC")]
+        rate_limit: Option<u64>,
+        /// Wait this long after every confirmed chunk
+        #[arg(long, value_name = "MS", default_value_t = 0)]
+        chunk_delay_ms: u64,
+    },
+    /// List a directory via the device shell, which needs the shell group on the device
+    ///
+    /// SMP can't list directories, so this runs `fs ls` on the device shell and parses its
+    /// output into name, size and type of each entry. Sizes are only shown if the Zephyr
+    /// version of the device prints them.
+    Ls {
+        /// Directory on the device
+        path: String,
+        /// Print the output of the device as it is, e.g. if it can't be parsed
+        #[arg(long)]
+        raw: bool,
+    },
+    /// Print the length of a file
+    Stat {
+        /// Path on the device
+        remote: String,
+    },
+    /// Print the hash or checksum of a file computed by the device
+    Hash {
+        /// Path on the device
+        remote: String,
+        /// Hash type, by default the best one the device supports
+        #[arg(long = "type", value_enum)]
+        hash_type: Option<fs::HashType>,
+        /// Compare with the hash of this local file and fail if they differ
+        #[arg(long)]
+        compare: Option<PathBuf>,
+    },
+}
+
+#[derive(Subcommand, Debug, Clone)]
+enum StatCmd {
+    /// List the statistics groups
+    List,
+    /// Print the counters of a group, once or periodically
+    Read {
+        group: String,
+        /// Read the counters periodically with this interval, e.g. 500ms, 1s or 2m
+        #[arg(long, value_parser = ping::parse_duration)]
+        poll: Option<Duration>,
+        /// Stop after this many samples, by default poll until Ctrl-C is pressed
+        #[arg(long, requires = "poll")]
+        count: Option<u64>,
+        /// Print the increase since the previous sample. The first sample is the baseline
+        #[arg(long, requires = "poll")]
+        diff: bool,
+    },
+}
+
+#[derive(Subcommand, Debug, Clone)]
+enum LogCmd {
+    /// Print the newest log entries
+    Tail {
+        /// Number of entries printed before following
+        #[arg(long, default_value_t = 10)]
+        lines: u32,
+        /// Keep polling for new entries until Ctrl-C is pressed
+        #[arg(short, long)]
+        follow: bool,
+        /// Time between polls, e.g. 500ms, 1s or 2m
+        #[arg(long, default_value = "1s", value_parser = ping::parse_duration)]
+        interval: Duration,
+        /// Only print entries of this module, by name or number
+        #[arg(long)]
+        module: Option<String>,
+        /// Only print entries of at least this level
+        #[arg(long, value_enum, default_value_t)]
+        min_level: logs::Level,
+        /// Only read this log, all logs by default
+        #[arg(long)]
+        log: Option<String>,
+    },
+    /// Delete all log entries
+    Clear,
+}
+
+#[derive(Subcommand, Debug, Clone)]
 enum SettingCmd {
-    Read { name: String },
-    WriteString { name: String, val: String },
-    WriteInt { name: String, val: i32 },
+    Read {
+        name: String,
+        /// How the value is printed
+        #[arg(long = "as", value_enum, default_value = "hex")]
+        value_format: settings::ValueFormat,
+    },
+    /// Read several settings over one connection and print them as `name=value` lines
+    ReadMany {
+        /// Names of the settings to read
+        names: Vec<String>,
+        /// File with one setting name per line
+        #[arg(long)]
+        names_file: Option<PathBuf>,
+        /// How the values are printed
+        #[arg(long = "as", value_enum, default_value_t)]
+        value_format: settings::ValueFormat,
+    },
+    WriteString {
+        name: String,
+        val: String,
+    },
+    /// Write an integer, by default as 32 bit little-endian
+    WriteInt {
+        name: String,
+        /// Signed or unsigned value that fits into the width
+        #[arg(allow_negative_numbers = true)]
+        val: i128,
+        #[arg(long, value_enum, default_value_t)]
+        width: settings::Width,
+        #[arg(long, value_enum, default_value_t)]
+        endian: settings::ByteOrder,
+    },
+    /// Write binary data, given as hex:DEADBEEF, base64:3q2+7w== or @file
+    WriteBytes {
+        name: String,
+        val: String,
+    },
     Save {},
+    /// Read settings and write them to a TOML file, or JSON if the file name ends in .json
+    Export {
+        file: PathBuf,
+        /// Names of the settings to export
+        names: Vec<String>,
+        /// File with one setting name per line
+        #[arg(long)]
+        names_file: Option<PathBuf>,
+    },
+    /// Write all settings from a file created by export
+    Import {
+        file: PathBuf,
+        /// Save the settings to persistent storage afterwards
+        #[arg(long)]
+        save: bool,
+    },
 }
 
-pub enum UsedTransport {
+/// The connection to the device
+pub struct UsedTransport {
+    link: Link,
+    /// The retry policy and deadlines of the session
+    retry: Arc<Retry>,
+}
+
+/// The transport of a connection, blocking like serial or async like UDP and BLE
+enum Link {
     SyncTransport(CborSmpTransport),
     AsyncTransport(CborSmpTransportAsync),
 }
 
 impl UsedTransport {
+    /// Send a request and wait for its response, as long as `--slow-timeout-ms` for requests
+    /// that erase flash
     pub async fn transceive_cbor<Req: serde::Serialize, Resp: serde::de::DeserializeOwned>(
         &mut self,
         frame: &SmpFrame<Req>,
     ) -> Result<SmpFrame<Resp>, mcumgr_smp::transport::error::Error> {
-        match self {
-            UsedTransport::SyncTransport(ref mut t) => t.transceive_cbor(frame, false),
-            UsedTransport::AsyncTransport(ref mut t) => t.transceive_cbor(frame, false).await,
+        self.transceive_cbor_as(frame, OperationClass::of_frame(frame))
+            .await
+    }
+
+    /// Like [UsedTransport::transceive_cbor], but wait as long as the deadline of `class`,
+    /// e.g. for the first chunk of an upload
+    pub async fn transceive_cbor_as<Req: serde::Serialize, Resp: serde::de::DeserializeOwned>(
+        &mut self,
+        frame: &SmpFrame<Req>,
+        class: OperationClass,
+    ) -> Result<SmpFrame<Resp>, mcumgr_smp::transport::error::Error> {
+        let timeouts = self.retry.timeouts();
+        // responses that may never arrive are handled by transceive_cbor_optional
+        let timeout = match class {
+            OperationClass::Slow => timeouts.slow,
+            OperationClass::Fast | OperationClass::Reset => timeouts.fast,
+        };
+
+        let before = self.stats();
+        let ret = if self.retry.enabled() {
+            self.retry
+                .clone()
+                .transceive_cbor(self, frame, timeout)
+                .await
+        } else if class == OperationClass::Slow {
+            self.transceive_cbor_timeout(frame, timeout).await
+        } else {
+            match self.link {
+                Link::SyncTransport(ref mut t) => t.transceive_cbor(frame, true),
+                Link::AsyncTransport(ref mut t) => {
+                    within(timeout, t.transceive_cbor(frame, true)).await
+                }
+            }
+        };
+
+        let stats = self.stats();
+        if stats != before {
+            debug!("dropped frames so far: {:?}", stats);
+        }
+        ret
+    }
+
+    /// Like [UsedTransport::transceive_cbor], but the request is encoded into `buf`, which is
+    /// reused for the next request, e.g. the chunks of an upload
+    pub async fn transceive_cbor_with<Req: serde::Serialize, Resp: serde::de::DeserializeOwned>(
+        &mut self,
+        frame: &SmpFrame<Req>,
+        buf: &mut Vec<u8>,
+    ) -> Result<SmpFrame<Resp>, mcumgr_smp::transport::error::Error> {
+        if self.retry.enabled() {
+            return self.transceive_cbor(frame).await;
+        }
+
+        match self.link {
+            Link::SyncTransport(ref mut t) => t.transceive_cbor_with(frame, true, buf),
+            Link::AsyncTransport(ref mut t) => {
+                let timeout = self.retry.timeouts().of_frame(frame);
+                within(timeout, t.transceive_cbor_with(frame, true, buf)).await
+            }
+        }
+    }
+
+    /// Late, duplicate and foreign frames dropped while receiving
+    pub fn stats(&self) -> TransportStats {
+        match self.link {
+            Link::SyncTransport(ref t) => t.stats(),
+            Link::AsyncTransport(ref t) => t.stats(),
+        }
+    }
+
+    pub async fn send_cbor<Req: serde::Serialize>(
+        &mut self,
+        frame: &SmpFrame<Req>,
+    ) -> Result<(), mcumgr_smp::transport::error::Error> {
+        match self.link {
+            Link::SyncTransport(ref mut t) => t.send_cbor(frame),
+            Link::AsyncTransport(ref mut t) => t.send_cbor(frame).await,
+        }
+    }
+
+    pub async fn receive_cbor<Resp: serde::de::DeserializeOwned>(
+        &mut self,
+    ) -> Result<SmpFrame<Resp>, mcumgr_smp::transport::error::Error> {
+        match self.link {
+            Link::SyncTransport(ref mut t) => t.receive_cbor(None),
+            Link::AsyncTransport(ref mut t) => t.receive_cbor(None).await,
+        }
+    }
+
+    /// Close the connection, e.g. disconnect from a BLE device
+    pub async fn close(&mut self) -> Result<(), mcumgr_smp::transport::error::Error> {
+        match self.link {
+            Link::SyncTransport(_) => Ok(()),
+            Link::AsyncTransport(ref mut t) => t.close().await,
+        }
+    }
+
+    /// Send an encoded frame as it is, e.g. one relayed for another client
+    pub async fn send(
+        &mut self,
+        frame: Vec<u8>,
+    ) -> Result<(), mcumgr_smp::transport::error::Error> {
+        match self.link {
+            Link::SyncTransport(ref mut t) => t.send(frame),
+            Link::AsyncTransport(ref mut t) => t.send(frame).await,
+        }
+    }
+
+    /// Receive the next frame without decoding it
+    pub async fn receive(&mut self) -> Result<Vec<u8>, mcumgr_smp::transport::error::Error> {
+        match self.link {
+            Link::SyncTransport(ref mut t) => t.receive(),
+            Link::AsyncTransport(ref mut t) => t.receive().await,
+        }
+    }
+
+    pub async fn transceive(
+        &mut self,
+        frame: Vec<u8>,
+    ) -> Result<Vec<u8>, mcumgr_smp::transport::error::Error> {
+        match self.link {
+            Link::SyncTransport(ref mut t) => t.transceive(frame),
+            Link::AsyncTransport(ref mut t) => t.transceive(frame).await,
+        }
+    }
+
+    /// Send a request whose response may never arrive, e.g. a reset. Returns `None` if there
+    /// was no response within `grace` or the transport timed out earlier.
+    /// Requests are not retried, as the device may have acted on them already.
+    pub async fn transceive_cbor_optional<
+        Req: serde::Serialize,
+        Resp: serde::de::DeserializeOwned,
+    >(
+        &mut self,
+        frame: &SmpFrame<Req>,
+        grace: Duration,
+    ) -> Result<Option<SmpFrame<Resp>>, mcumgr_smp::transport::error::Error> {
+        match self.link {
+            Link::SyncTransport(ref mut t) => t.transceive_cbor_optional(frame, grace),
+            Link::AsyncTransport(ref mut t) => t.transceive_cbor_optional(frame, grace).await,
+        }
+    }
+
+    /// Like [UsedTransport::transceive_cbor], but keep waiting for the response until the
+    /// timeout expires, even if the transport itself times out earlier.
+    pub async fn transceive_cbor_timeout<
+        Req: serde::Serialize,
+        Resp: serde::de::DeserializeOwned,
+    >(
+        &mut self,
+        frame: &SmpFrame<Req>,
+        timeout: Duration,
+    ) -> Result<SmpFrame<Resp>, mcumgr_smp::transport::error::Error> {
+        let deadline = tokio::time::Instant::now() + timeout;
+
+        match self.link {
+            Link::SyncTransport(ref mut t) => t.send_cbor(frame)?,
+            Link::AsyncTransport(ref mut t) => t.send_cbor(frame).await?,
+        }
+
+        self.receive_cbor_until(frame.sequence, deadline).await
+    }
+
+    /// Wait for the response with the given sequence number until the deadline. Sync
+    /// transports make at least one attempt, limited by their own timeout.
+    pub async fn receive_cbor_until<Resp: serde::de::DeserializeOwned>(
+        &mut self,
+        sequence: u8,
+        deadline: tokio::time::Instant,
+    ) -> Result<SmpFrame<Resp>, mcumgr_smp::transport::error::Error> {
+        loop {
+            let ret = match self.link {
+                Link::SyncTransport(ref mut t) => t.receive_cbor(Some(sequence)),
+                Link::AsyncTransport(ref mut t) => {
+                    match tokio::time::timeout_at(deadline, t.receive_cbor(Some(sequence))).await {
+                        Ok(ret) => ret,
+                        Err(_) => Err(std::io::Error::from(std::io::ErrorKind::TimedOut).into()),
+                    }
+                }
+            };
+
+            match ret {
+                Err(mcumgr_smp::transport::error::Error::Io(e))
+                    if matches!(
+                        e.kind(),
+                        std::io::ErrorKind::TimedOut | std::io::ErrorKind::WouldBlock
+                    ) && tokio::time::Instant::now() < deadline =>
+                {
+                    continue
+                }
+                ret => return ret,
+            }
         }
     }
 }
 
-#[tokio::main(flavor = "current_thread")]
-async fn main() -> Result<(), Box<dyn Error>> {
-    tracing_subscriber::registry()
-        .with(tracing_subscriber::EnvFilter::try_from_default_env().unwrap_or_else(|_| "".into()))
-        .with(tracing_subscriber::fmt::layer())
-        .init();
+/// The result of `request`, or a timeout once `timeout` expired. Sync transports time out
+/// on their own, async ones like UDP would wait forever for a lost response.
+async fn within<T>(
+    timeout: Duration,
+    request: impl std::future::Future<Output = Result<T, mcumgr_smp::transport::error::Error>>,
+) -> Result<T, mcumgr_smp::transport::error::Error> {
+    tokio::time::timeout(timeout, request)
+        .await
+        .unwrap_or_else(|_| Err(std::io::Error::from(std::io::ErrorKind::TimedOut).into()))
+}
+
+/// Short description of the device selected on the command line, e.g. `serial:/dev/ttyACM0`
+fn describe_target(cli: &Cli) -> String {
+    match cli.transport {
+        Some(Transport::Serial) => {
+            format!(
+                "serial:{}",
+                cli.serial_device.as_deref().unwrap_or_default()
+            )
+        }
+        Some(Transport::Udp) => format!(
+            "udp:{}:{}",
+            cli.dest_host.as_deref().unwrap_or_default(),
+            cli.udp_port
+        ),
+        Some(Transport::Ble) => format!("ble:{}", cli.name.as_deref().unwrap_or_default()),
+        Some(Transport::Replay) => format!(
+            "replay:{}",
+            cli.replay_file.clone().unwrap_or_default().display()
+        ),
+        None => "smp".to_string(),
+    }
+}
 
-    let cli: Cli = Cli::parse();
+/// Connect to the device selected on the command line
+///
+/// Unless `--no-probe` is given, the device has to answer a probe. It is skipped for the replay
+/// transport and while recording, as recordings are replayed without it.
+async fn open_transport(ctx: &Context, cli: &Cli) -> Result<UsedTransport, Box<dyn Error>> {
+    let mut transport = connect(ctx, cli)
+        .await
+        .map_err(|e| match e.downcast::<CliError>() {
+            Ok(e) => e,
+            Err(e) => Box::new(CliError::Connect(e)),
+        })?;
 
-    let mut transport = match cli.transport {
+    *ctx.connected.lock().expect("probe lock poisoned") = None;
+    if !cli.no_probe && cli.record.is_none() && cli.transport != Some(Transport::Replay) {
+        let serial = cli.transport == Some(Transport::Serial);
+        probe::on_connect(ctx, &mut transport, &describe_target(cli), serial).await?;
+    }
+
+    Ok(transport)
+}
+
+async fn connect(ctx: &Context, cli: &Cli) -> Result<UsedTransport, Box<dyn Error>> {
+    let Some(transport) = cli.transport else {
+        return Err(CliError::Usage("--transport is required for this command".to_string()).into());
+    };
+
+    let observer = dump::combine(
+        [
+            (cli.verbose >= 2).then(|| dump::observer(cli.dump_limit)),
+            transcript::observer(ctx),
+            replay::observer(ctx),
+        ]
+        .into_iter()
+        .flatten()
+        .collect(),
+    );
+    let observer = observer.as_ref();
+
+    let link = match transport {
         Transport::Serial => {
-            let mut t = SerialTransport::new(
-                cli.serial_device.expect("serial device required"),
-                cli.serial_baud,
-            )?;
+            let serial_device = cli.serial_device.clone().ok_or_else(|| {
+                CliError::Usage("--serial-device is required for the serial transport".to_string())
+            })?;
+            let settings = cli.serial_config.settings(cli.serial_baud, cli.serial_flow);
+            let mut t = SerialTransport::with_settings(serial_device, settings)?;
             t.recv_timeout(Some(Duration::from_millis(cli.timeout_ms)))?;
-            UsedTransport::SyncTransport(CborSmpTransport {
-                transport: Box::new(t),
-            })
+            if let Some(observer) = observer {
+                t.set_observer(observer.clone());
+            }
+            Link::SyncTransport(CborSmpTransport::new(dump::observe(t, observer)))
         }
         Transport::Udp => {
-            let host = cli.dest_host.expect("dest_host required");
+            let host = cli.dest_host.clone().ok_or_else(|| {
+                CliError::Usage("--dest-host is required for the udp transport".to_string())
+            })?;
             let port = cli.udp_port;
 
-            debug!("connecting to {} at port {}", host, port);
+            let udp = if host == discover::AUTO {
+                let address = discover::auto(ctx, port).await?;
+                debug!("connecting to discovered device {}", address);
+                UdpTransportAsync::new(address).await?
+            } else {
+                debug!("connecting to {} at port {}", host, port);
+                UdpTransportAsync::new((host, port)).await?
+            };
 
-            UsedTransport::AsyncTransport(CborSmpTransportAsync {
-                transport: Box::new(UdpTransportAsync::new((host, port)).await?),
-            })
+            Link::AsyncTransport(CborSmpTransportAsync::new(dump::observe_async(
+                udp, observer,
+            )))
         }
         Transport::Ble => {
+            let name = cli.name.clone().ok_or_else(|| {
+                CliError::Usage("--name is required for the ble transport".to_string())
+            })?;
             let adapters = BleTransport::adapters().await?;
             debug!("found {} adapter(s): {:?}:", adapters.len(), adapters);
             let adapter = adapters.first().ok_or("BLE adapters not found")?;
             debug!("selecting first adapter: {:?}:", adapter);
-            UsedTransport::AsyncTransport(CborSmpTransportAsync {
-                transport: Box::new(
-                    BleTransport::new(
-                        cli.name.unwrap(),
-                        adapter,
-                        Duration::from_millis(cli.timeout_ms),
-                    )
-                    .await?,
-                ),
-            })
+            let ble_timeouts = BleTimeouts::new(
+                Duration::from_millis(cli.timeout_ms),
+                Duration::from_millis(cli.ble_step_timeout_ms),
+            );
+            Link::AsyncTransport(CborSmpTransportAsync::new(dump::observe_async(
+                BleTransport::with_timeouts(name, adapter, ble_timeouts).await?,
+                observer,
+            )))
+        }
+        Transport::Replay => {
+            let path = cli.replay_file.as_deref().ok_or_else(|| {
+                CliError::Usage("--replay-file is required for the replay transport".to_string())
+            })?;
+            Link::SyncTransport(CborSmpTransport::new(dump::observe(
+                replay::open(ctx, path, cli.replay_match)?,
+                observer,
+            )))
         }
     };
 
+    Ok(UsedTransport {
+        link,
+        retry: ctx.retry.clone(),
+    })
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> ExitCode {
+    tracing_subscriber::registry()
+        .with(tracing_subscriber::EnvFilter::try_from_default_env().unwrap_or_else(|_| "".into()))
+        .with(tracing_subscriber::fmt::layer().with_writer(std::io::stderr))
+        .init();
+
+    let matches = Cli::command().get_matches();
+    let cli = Cli::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    let format = cli.format;
+
+    let mut ctx = Context::default();
+    let ret = run(&mut ctx, cli, &matches).await;
+    transcript::outcome(&ctx, ret.as_ref().err().map(|e| e.as_ref()));
+    replay::finish(&ctx);
+    match ret {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            error::report(err.as_ref(), format);
+            ExitCode::from(error::exit_code(err.as_ref()))
+        }
+    }
+}
+
+async fn run(ctx: &mut Context, mut cli: Cli, matches: &ArgMatches) -> Result<(), Box<dyn Error>> {
+    ctx.sequence = Sequence::new(cli.seq);
+    ctx.progress = cli.progress;
+    ctx.name_rules = (!cli.no_name_check).then(NameRules::default);
+    ctx.quiet = cli.quiet;
+
+    let config = config::load(cli.config.as_deref())?;
+    if let Commands::Profiles(ProfilesCmd::List) = cli.command {
+        config.print_profiles();
+        return Ok(());
+    }
+    if let Some(profile) = &cli.profile {
+        let profile = config.profile(profile)?.clone();
+        config::apply_profile(&mut cli, matches, &profile);
+    }
+    if let Some(target) = cli.target.clone() {
+        targets::apply(&mut cli, matches, &config, &target)?;
+    }
+    // device commands are executed by the agent, which holds the connection
+    if let Some(socket) = &cli.via {
+        if !cli.dry_run
+            && !matches!(
+                cli.command,
+                Commands::Decode { .. }
+                    | Commands::Encode { .. }
+                    | Commands::Profiles(_)
+                    | Commands::Udp(_)
+            )
+        {
+            return agent::forward(&cli, socket, agent::command_words());
+        }
+    }
+    ctx.deadline = cli
+        .wait_for_device
+        .map(|timeout| tokio::time::Instant::now() + timeout);
+    // commands for a device fall back to the only connected development kit
+    if cli.transport.is_none()
+        && !cli.no_autodetect
+        && !cli.dry_run
+        && cli.targets.is_none()
+        && !matches!(
+            cli.command,
+            Commands::Decode { .. }
+                | Commands::Encode { .. }
+                | Commands::Profiles(_)
+                | Commands::Udp(_)
+        )
+    {
+        wait::usb_device(ctx).await;
+        autodetect::apply(ctx, &mut cli)?;
+    }
+    ctx.retry = Arc::new(Retry::new(
+        RetryPolicy::new(cli.retries, Duration::from_millis(cli.retry_delay_ms)),
+        Timeouts::new(
+            Duration::from_millis(cli.timeout_ms),
+            Duration::from_millis(cli.slow_timeout_ms),
+        ),
+    ));
+    if let Some(path) = &cli.record {
+        ctx.recorder = Some(replay::record(path)?);
+    }
+    if let Some(path) = &cli.log_file {
+        ctx.transcript = Some(transcript::open(&cli, path)?);
+        let args: Vec<String> = std::env::args().skip(1).collect();
+        transcript::command(ctx, "cli", &args, Some(&cli.command));
+    }
+
+    // commands that work without a device
+    match &cli.command {
+        Commands::Decode {
+            hex,
+            stdin,
+            encapsulation,
+        } => {
+            let input = if *stdin {
+                let mut input = Vec::new();
+                std::io::stdin().read_to_end(&mut input)?;
+                match encapsulation {
+                    frame::Encapsulation::Raw => {
+                        frame::parse_hex_input(&String::from_utf8(input)?)?
+                    }
+                    frame::Encapsulation::Serial => input,
+                }
+            } else {
+                frame::parse_hex_input(hex.as_deref().unwrap_or_default())?
+            };
+
+            return frame::decode(&input, *encapsulation);
+        }
+        Commands::Encode {
+            op,
+            group,
+            id,
+            seq,
+            payload,
+            payload_cbor_hex,
+            base64,
+            serial_framing,
+        } => {
+            let payload = frame::build_payload(payload.as_deref(), payload_cbor_hex.as_deref())?;
+            let request = frame::encode_frame(*op, *group, *id, *seq, payload);
+            frame::print_encoded(&request, *serial_framing, *base64);
+            return Ok(());
+        }
+        _ => {}
+    }
+
+    if cli.dry_run {
+        return dry_run::dry_run(ctx, &cli);
+    }
+
+    if let Commands::Udp(UdpCmd::Discover {
+        mdns,
+        broadcast,
+        timeout,
+    }) = &cli.command
+    {
+        let method = if *mdns {
+            discover::Method::Mdns
+        } else {
+            discover::Method::Broadcast(broadcast.clone())
+        };
+        return discover::list(
+            ctx,
+            &method,
+            cli.udp_port,
+            Duration::from_secs(*timeout),
+            cli.format,
+        )
+        .await;
+    }
+
+    if let (Some(path), None) = (&cli.targets, &cli.target) {
+        return targets::run(&cli, &config, path);
+    }
+
+    if let Commands::Run {
+        script,
+        commands,
+        continue_on_error,
+    } = &cli.command
+    {
+        return script::run(
+            ctx,
+            &cli,
+            script.as_deref(),
+            commands.as_deref(),
+            *continue_on_error,
+        )
+        .await;
+    }
+
+    if let Commands::Agent { listen } = &cli.command {
+        return agent::serve(ctx, &cli, listen).await;
+    }
+    if let Commands::Serve { stdio: true } = &cli.command {
+        return rpc::serve_stdio(ctx, &cli).await;
+    }
+
+    execute(ctx, cli, &mut None).await
+}
+
+/// Execute a command that talks to the device.
+///
+/// The connection is opened on first use and kept for further commands. Commands that
+/// reset the device may have to reconnect, if that fails the connection is left empty.
+async fn execute(
+    ctx: &Context,
+    cli: Cli,
+    connection: &mut Option<UsedTransport>,
+) -> Result<(), Box<dyn Error>> {
+    if connection.is_none() {
+        *connection = Some(wait::open(ctx, &cli).await?);
+    }
+    let transport = connection.as_mut().expect("connection is open");
+
     match cli.command {
-        Commands::Os(OsCmd::Echo { msg }) => {
+        Commands::Os(OsCmd::Echo {
+            msg,
+            count,
+            size,
+            interval,
+        }) if count.is_some() || size.is_some() => {
+            let options = echo::EchoOptions {
+                msg,
+                count: count.unwrap_or(1),
+                size,
+                interval: Duration::from_millis(interval),
+                timeout: Duration::from_millis(cli.timeout_ms),
+            };
+            echo::echo(ctx, transport, &options, cli.format).await?;
+        }
+        Commands::Os(OsCmd::Echo { msg, .. }) => {
+            let msg = msg.expect("required unless --size is given");
             let ret: SmpFrame<EchoResult> = transport
-                .transceive_cbor(&os_management::echo(42, msg))
+                .transceive_cbor(&os_management::echo(ctx.sequence.next(), msg))
                 .await?;
             debug!("{:?}", ret);
 
             match ret.data {
                 EchoResult::Ok { r } => {
-                    println!("{}", r);
+                    outln!(ctx, "{}", r);
                 }
                 EchoResult::Err { rc } => {
-                    eprintln!("rc: {}", rc);
+                    Err(CliError::device(rc))?;
                 }
             }
         }
-        Commands::Os(OsCmd::Reset {}) => {
-            let ret: SmpFrame<ResetResult> = transport
-                .transceive_cbor(&os_management::reset(42, false))
+        Commands::Os(OsCmd::Ping {
+            interval,
+            count,
+            fail_after,
+            reconnect,
+        }) => {
+            let options = ping::PingOptions {
+                interval,
+                count,
+                fail_after,
+                reconnect,
+            };
+            let transport = connection.take().expect("connection is open");
+            *connection = ping::ping(ctx, &cli, transport, &options).await?;
+        }
+        Commands::Os(OsCmd::Taskstat {
+            sort,
+            watch,
+            threshold,
+        }) => {
+            let options = taskstat::TaskstatOptions {
+                sort,
+                watch,
+                threshold,
+            };
+            taskstat::taskstat(ctx, transport, &options, cli.format).await?;
+        }
+        Commands::Os(OsCmd::Info { format }) => {
+            probe::os_info(ctx, transport, format.as_deref(), cli.format).await?;
+        }
+        Commands::Os(OsCmd::BootloaderInfo { query }) => {
+            probe::bootloader_info(ctx, transport, query.as_deref(), cli.format).await?;
+        }
+        Commands::Os(OsCmd::Datetime { action: None }) => {
+            datetime::get(ctx, transport, cli.format).await?;
+        }
+        Commands::Os(OsCmd::Datetime {
+            action: Some(DatetimeCmd::Set { time }),
+        }) => {
+            datetime::set_time(ctx, transport, time, cli.format).await?;
+        }
+        Commands::Os(OsCmd::Datetime {
+            action: Some(DatetimeCmd::Sync),
+        }) => {
+            datetime::sync(ctx, transport, cli.format).await?;
+        }
+        Commands::Os(OsCmd::Reset {
+            wait: Some(timeout),
+        }) => {
+            let transport = connection.take().expect("connection is open");
+            let (transport, downtime) =
+                reset::reset_and_wait(ctx, &cli, transport, timeout).await?;
+            *connection = Some(transport);
+            reset::print_downtime(ctx, downtime, cli.format);
+        }
+        Commands::Os(OsCmd::Reset { wait: None }) => {
+            let ret: Option<SmpFrame<ResetResult>> = transport
+                .transceive_cbor_optional(
+                    &os_management::reset(ctx.sequence.next(), false),
+                    ctx.retry.timeouts().reset_grace,
+                )
                 .await?;
             debug!("{:?}", ret);
 
-            match ret.data {
-                ResetResult::Ok {} => {
-                    println!("success");
+            match ret.map(|ret| ret.data) {
+                Some(ResetResult::Ok {}) => {
+                    outln!(ctx, "success");
+                }
+                Some(ResetResult::Err { rc }) => {
+                    Err(CliError::device(rc))?;
                 }
-                ResetResult::Err { rc } => {
-                    eprintln!("rc: {}", rc);
+                None => {
+                    outln!(ctx, "reset sent (no confirmation received)");
                 }
             }
         }
-        Commands::Shell(ShellCmd::Exec { cmd }) => {
+        Commands::Shell(ShellCmd::Exec {
+            cmd,
+            command,
+            no_status_passthrough,
+        }) => {
+            let argv = shell::exec_argv(&cmd, command.as_deref())?;
             let ret: SmpFrame<ShellResult> = transport
-                .transceive_cbor(&shell_management::shell_command(42, cmd))
+                .transceive_cbor(&shell_management::shell_command(ctx.sequence.next(), argv))
                 .await?;
             debug!("{:?}", ret);
 
             match ret.data {
+                ShellResult::Ok { o, ret } if no_status_passthrough => match ret {
+                    Some(ret) => outln!(ctx, "ret: {}, o: {}", ret, o),
+                    None => outln!(ctx, "o: {}", o),
+                },
                 ShellResult::Ok { o, ret } => {
-                    println!("ret: {}, o: {}", ret, o);
+                    out!(ctx, "{}", o);
+                    if !o.is_empty() && !o.ends_with('\n') {
+                        outln!(ctx);
+                    }
+                    // devices that don't report the status are assumed to succeed
+                    if let Some(ret) = ret.filter(|ret| *ret != 0) {
+                        if cli.verbose > 0 {
+                            eprintln!("remote command returned {}", ret);
+                        }
+                        Err(CliError::RemoteStatus(shell::exit_status(ret)))?;
+                    }
                 }
                 ShellResult::Err { rc } => {
-                    eprintln!("rc: {}", rc);
+                    Err(CliError::device(rc))?;
                 }
             }
         }
         Commands::Shell(ShellCmd::Interactive) => {
-            shell::shell(&mut transport).await?;
+            shell::shell(
+                ctx,
+                transport,
+                &describe_target(&cli),
+                Duration::from_millis(cli.timeout_ms),
+            )
+            .await?;
         }
         Commands::App(ApplicationCmd::Flash {
             slot,
             update_file,
+            images,
             chunk_size,
+            chunk_retries,
             upgrade,
+            test,
+            confirm,
+            only,
+            erase_first,
+            verify,
+            skip_if_same,
+            skip_if_newer,
+            rate_limit,
+            chunk_delay_ms,
         }) => {
-            let firmware = std::fs::read(&update_file)?;
+            let from_files = !images.is_empty();
+            let images =
+                flash::load_images(ctx, update_file.as_deref(), &images, slot, only.as_deref())?;
+            let limit = pacing::RateLimit {
+                bytes_per_sec: rate_limit,
+                chunk_delay: Duration::from_millis(chunk_delay_ms),
+            };
+            let total = images.iter().map(|img| img.data.len() as u64).sum();
+            interrupt::watch();
 
-            let mut hasher = sha2::Sha256::new();
-            hasher.update(&firmware);
-            let hash = hasher.finalize();
-
-            println!("Image sha256: {:x}", hash);
-
-            let mut updater = mcumgr_smp::application_management::ImageWriter::new(
-                slot,
-                firmware.len(),
-                Some(&hash),
-                upgrade,
-            );
-
-            let mut verified = None;
-
-            let mut offset = 0;
-            while offset < firmware.len() {
-                println!("writing {}/{}", offset, firmware.len());
-                let chunk = &firmware[offset..min(firmware.len(), offset + chunk_size)];
+            // every image is uploaded before any is marked, so a failed upload doesn't leave
+            // a marked image without its counterpart, e.g. the app and net core of an nRF53
+            let mut uploaded: Vec<&flash::FlashImage> = Vec::new();
+            let mut done = 0;
+            for (i, img) in images.iter().enumerate() {
+                let ret = async {
+                    if skip_if_same || skip_if_newer {
+                        let comparison = flash::compare_with_device(
+                            ctx,
+                            transport,
+                            &img.data,
+                            img.image,
+                            skip_if_newer,
+                        )
+                        .await?;
+                        comparison.print(ctx, cli.format);
+                        if comparison.skip {
+                            return Ok(false);
+                        }
+                    }
 
-                let resp_frame: SmpFrame<WriteImageChunkResult> = transport
-                    .transceive_cbor(&updater.write_chunk(chunk))
-                    .await?;
+                    if images.len() > 1 {
+                        status!(
+                            ctx,
+                            "[{}/{}] flashing {}",
+                            i + 1,
+                            images.len(),
+                            img.describe()
+                        );
+                    } else {
+                        status!(ctx, "flashing {}", img.name);
+                    }
+                    if erase_first {
+                        progress::step(ctx, "erase", &img.name);
+                        flash::erase_secondary_slot(ctx, transport, img.image).await?;
+                    }
+                    let options = flash::UploadOptions {
+                        image: img.image,
+                        chunking: flash::Chunking {
+                            size: chunk_size,
+                            retries: chunk_retries,
+                        },
+                        upgrade,
+                        limit,
+                    };
+                    flash::upload(
+                        ctx,
+                        transport,
+                        &img.data,
+                        &options,
+                        // only JSON progress events, the text output has its own lines
+                        Progress::new(ctx, "upload", false)
+                            .with_step(&img.name)
+                            .with_offset(done, total),
+                    )
+                    .await?
+                    .print(ctx, cli.format);
 
-                match resp_frame.data {
-                    WriteImageChunkResult::Ok(payload) => {
-                        offset = payload.off as usize;
-                        updater.offset = offset;
-                        verified = payload.match_;
+                    if verify {
+                        progress::step(ctx, "verify", &img.name);
+                        flash::verify(ctx, transport, &img.data).await?;
                     }
-                    WriteImageChunkResult::Err(err) => {
-                        Err(format!("Err from MCU: {:?}", err))?;
+                    Ok::<_, Box<dyn Error>>(true)
+                }
+                .await;
+                done += img.data.len() as u64;
+
+                match ret {
+                    Ok(true) => uploaded.push(img),
+                    Ok(false) => {}
+                    Err(e) if images.len() > 1 => {
+                        let remaining: Vec<_> = images[i..].iter().collect();
+                        Err(flash::upload_failure(e, &uploaded, &remaining, from_files))?;
                     }
+                    Err(e) => Err(e)?,
                 }
             }
 
-            println!("sent all bytes: {}", offset);
+            if test || confirm {
+                for (i, img) in uploaded.iter().enumerate() {
+                    progress::step(ctx, "mark", &img.name);
+                    if let Err(e) =
+                        flash::mark(ctx, transport, &img.data, confirm, cli.format).await
+                    {
+                        let err = if uploaded.len() > 1 {
+                            flash::mark_failure(e, &uploaded[..i], &uploaded[i..], confirm)
+                        } else {
+                            e
+                        };
+                        Err(err)?;
+                    }
+                }
+            }
+        }
+        Commands::App(ApplicationCmd::Update {
+            ref update_file,
+            slot,
+            chunk_size,
+            upgrade,
+            no_confirm,
+            confirm_timeout,
+            skip_if_same,
+            skip_if_newer,
+        }) => {
+            let firmware = std::fs::read(update_file)?;
 
-            if let Some(verified) = verified {
-                if verified {
-                    println!("Image verified");
-                } else {
-                    eprintln!("Image verification failed!");
+            if skip_if_same || skip_if_newer {
+                let comparison =
+                    flash::compare_with_device(ctx, transport, &firmware, slot, skip_if_newer)
+                        .await?;
+                comparison.print(ctx, cli.format);
+                if comparison.skip {
+                    return Ok(());
                 }
             }
+
+            let options = flash::UpdateOptions {
+                image: slot,
+                chunk_size,
+                upgrade,
+                confirm: !no_confirm,
+                confirm_timeout: Duration::from_secs(confirm_timeout),
+            };
+
+            interrupt::watch();
+            let transport = connection.take().expect("connection is open");
+            *connection = Some(flash::update(ctx, &cli, transport, &firmware, &options).await?);
         }
         Commands::App(ApplicationCmd::Info) => {
             let ret: SmpFrame<GetImageStateResult> = transport
-                .transceive_cbor(&application_management::get_state(42))
+                .transceive_cbor(&application_management::get_state(ctx.sequence.next()))
+                .await?;
+            debug!("{:?}", ret);
+
+            if cli.verbose > 0 {
+                eprintln!("{:?}", ret.data);
+            }
+            output::print_image_state_result(ctx, ret.data, cli.format)?;
+        }
+        Commands::App(ApplicationCmd::Test { hash, slot, image }) => {
+            let state = flash::get_image_state(ctx, transport).await?;
+
+            let hash = match (hash, slot) {
+                (Some(hash), _) => image::parse_hex(&hash)?,
+                (None, Some(slot)) => state
+                    .images
+                    .iter()
+                    .find(|img| img.image.unwrap_or(0) == image && img.slot == slot)
+                    .ok_or_else(|| format!("no image found in image {} slot {}", image, slot))?
+                    .hash
+                    .clone(),
+                (None, None) => unreachable!("either hash or slot is required"),
+            };
+
+            match state.images.iter().find(|img| img.hash == hash) {
+                Some(img) if !img.bootable => {
+                    eprintln!("warning: image {} is not bootable", image::hex(&hash))
+                }
+                None => eprintln!(
+                    "warning: image {} not found in image state",
+                    image::hex(&hash)
+                ),
+                Some(_) => {}
+            }
+
+            let ret: SmpFrame<GetImageStateResult> = transport
+                .transceive_cbor(&application_management::set_pending(
+                    hash,
+                    ctx.sequence.next(),
+                ))
+                .await?;
+            debug!("{:?}", ret);
+
+            output::print_image_state_result(ctx, ret.data, cli.format)?;
+        }
+        Commands::App(ApplicationCmd::Confirm { hash }) => {
+            let hash = hash.as_deref().map(image::parse_hex).transpose()?;
+
+            let ret: SmpFrame<GetImageStateResult> = transport
+                .transceive_cbor(&application_management::confirm(hash, ctx.sequence.next()))
+                .await?;
+            debug!("{:?}", ret);
+
+            output::print_image_state_result(ctx, ret.data, cli.format)?;
+        }
+        Commands::App(ApplicationCmd::Compare { file, image }) => {
+            let report = flash::compare_versions(ctx, transport, &file, image).await?;
+            report.print(ctx, cli.format);
+            if report.verdict != flash::Verdict::UpToDate {
+                Err(CliError::Verdict(report.verdict.exit_code()))?;
+            }
+        }
+        Commands::App(ApplicationCmd::Erase { slot }) => {
+            let ret: SmpFrame<EraseImageResult> = transport
+                .transceive_cbor(&application_management::erase_image(
+                    slot,
+                    ctx.sequence.next(),
+                ))
                 .await?;
             debug!("{:?}", ret);
 
             match ret.data {
-                GetImageStateResult::Ok(payload) => {
-                    println!("{:?}", payload)
+                EraseImageResult::Ok {} => {
+                    let state = flash::get_image_state(ctx, transport).await?;
+                    output::print_image_state(ctx, &state, cli.format);
                 }
-                GetImageStateResult::Err(err) => {
-                    eprintln!("rc: {}", err.rc);
-                    if let Some(msg) = err.rsn {
-                        eprintln!("rsn: {:?}", msg);
-                    }
+                EraseImageResult::Err { rc, rsn } => {
+                    Err(CliError::Device { rc, rsn })?;
                 }
             }
         }
-        Commands::Setting(SettingCmd::Read { name }) => {
+        Commands::Setting(SettingCmd::Read { name, value_format }) => {
+            settings::check_name(ctx, &name)?;
             let ret: SmpFrame<ReadSettingResult> = transport
-                .transceive_cbor(&setting_management::read_setting(42, name.clone()))
+                .transceive_cbor(&setting_management::read_setting(
+                    ctx.sequence.next(),
+                    name.clone(),
+                ))
                 .await?;
             debug!("{:?}", ret);
 
             match ret.data {
                 ReadSettingResult::Ok { val } => {
-                    println!("{}={:?}", name, val)
+                    settings::print_value(ctx, &name, &val, value_format, cli.format)?;
                 }
                 ReadSettingResult::Err { rc } => {
-                    eprintln!("rc: {}", rc);
+                    Err(settings::read_error(rc))?;
                 }
             }
         }
+        Commands::Setting(SettingCmd::ReadMany {
+            names,
+            names_file,
+            value_format,
+        }) => {
+            let names = settings::read_names(ctx, &names, names_file.as_deref())?;
+            settings::read_many(ctx, transport, &names, value_format, cli.format).await?;
+        }
         Commands::Setting(SettingCmd::WriteString { name, val }) => {
+            settings::check_name(ctx, &name)?;
+            let ret: SmpFrame<WriteSettingResult> = transport
+                .transceive_cbor(&setting_management::write_setting(
+                    ctx.sequence.next(),
+                    name.clone(),
+                    val,
+                ))
+                .await?;
+            debug!("{:?}", ret);
+
+            match ret.data {
+                WriteSettingResult::Ok {} => {
+                    outln!(ctx, "success");
+                }
+                WriteSettingResult::Err { rc } => {
+                    Err(CliError::device(rc))?;
+                }
+            }
+        }
+        Commands::Setting(SettingCmd::WriteInt {
+            name,
+            val,
+            width,
+            endian,
+        }) => {
+            settings::check_name(ctx, &name)?;
+            let val = settings::int_arg(val, width, endian)?;
             let ret: SmpFrame<WriteSettingResult> = transport
                 .transceive_cbor(&setting_management::write_setting(
-                    42,
+                    ctx.sequence.next(),
                     name.clone(),
-                    val.as_bytes().to_vec(),
+                    val,
                 ))
                 .await?;
             debug!("{:?}", ret);
 
             match ret.data {
                 WriteSettingResult::Ok {} => {
-                    println!("success");
+                    outln!(ctx, "success");
                 }
                 WriteSettingResult::Err { rc } => {
-                    eprintln!("rc: {}", rc);
+                    Err(CliError::device(rc))?;
                 }
             }
         }
-        Commands::Setting(SettingCmd::WriteInt { name, val }) => {
+        Commands::Setting(SettingCmd::WriteBytes { name, val }) => {
+            settings::check_name(ctx, &name)?;
+            let val = settings::parse_bytes_arg(&val)?;
             let ret: SmpFrame<WriteSettingResult> = transport
                 .transceive_cbor(&setting_management::write_setting(
-                    42,
+                    ctx.sequence.next(),
                     name.clone(),
-                    val.to_le_bytes().to_vec(),
+                    val,
                 ))
                 .await?;
             debug!("{:?}", ret);
 
             match ret.data {
                 WriteSettingResult::Ok {} => {
-                    println!("success");
+                    outln!(ctx, "success");
                 }
                 WriteSettingResult::Err { rc } => {
-                    eprintln!("rc: {}", rc);
+                    Err(CliError::device(rc))?;
                 }
             }
         }
         Commands::Setting(SettingCmd::Save {}) => {
             let ret: SmpFrame<SaveSettingResult> = transport
-                .transceive_cbor(&setting_management::save_setting(42))
+                .transceive_cbor(&setting_management::save_setting(ctx.sequence.next()))
                 .await?;
             debug!("{:?}", ret);
 
             match ret.data {
                 SaveSettingResult::Ok {} => {
-                    println!("success");
+                    outln!(ctx, "success");
                 }
                 SaveSettingResult::Err { rc } => {
-                    eprintln!("rc: {}", rc);
+                    Err(CliError::device(rc))?;
                 }
             }
         }
+        Commands::Fs(FsCmd::Download {
+            remote,
+            local,
+            resume,
+            hash,
+            offset,
+            length,
+        }) => {
+            interrupt::watch();
+            let options = fs::DownloadOptions {
+                range: fs::Range { offset, length },
+                resume,
+                hash,
+            };
+            fs::download(ctx, transport, &remote, &local, &options, cli.format).await?;
+        }
+        Commands::Fs(FsCmd::Tail { remote, bytes }) => {
+            fs::tail(ctx, transport, &remote, bytes, cli.format).await?;
+        }
+        Commands::Fs(FsCmd::Upload {
+            local,
+            remote,
+            chunk_size,
+            hash,
+            rate_limit,
+            chunk_delay_ms,
+        }) => {
+            let options = fs::UploadOptions {
+                chunk_size,
+                hash,
+                limit: pacing::RateLimit {
+                    bytes_per_sec: rate_limit,
+                    chunk_delay: Duration::from_millis(chunk_delay_ms),
+                },
+            };
+            interrupt::watch();
+            fs::upload(ctx, transport, &local, &remote, &options, cli.format).await?;
+        }
+        Commands::Stat(StatCmd::List) => {
+            stats::list(ctx, transport, cli.format).await?;
+        }
+        Commands::Log(LogCmd::Tail {
+            lines,
+            follow,
+            interval,
+            module,
+            min_level,
+            log,
+        }) => {
+            let options = logs::TailOptions {
+                lines,
+                follow,
+                interval,
+                module,
+                min_level,
+                log,
+            };
+            logs::tail(ctx, transport, &options, cli.format).await?;
+        }
+        Commands::Log(LogCmd::Clear) => {
+            logs::clear(ctx, transport).await?;
+        }
+        Commands::Stat(StatCmd::Read {
+            group,
+            poll,
+            count,
+            diff,
+        }) => {
+            let options = stats::ReadOptions { poll, count, diff };
+            stats::read(ctx, transport, &group, &options, cli.format).await?;
+        }
+        Commands::Fs(FsCmd::Stat { remote }) => {
+            fs::stat(ctx, transport, &remote, cli.format).await?;
+        }
+        Commands::Fs(FsCmd::Ls { path, raw }) => {
+            fs::ls(ctx, transport, &path, raw, cli.format).await?;
+        }
+        Commands::Fs(FsCmd::Hash {
+            remote,
+            hash_type,
+            compare,
+        }) => {
+            fs::hash(
+                ctx,
+                transport,
+                &remote,
+                hash_type,
+                compare.as_deref(),
+                cli.format,
+            )
+            .await?;
+        }
+        Commands::Setting(SettingCmd::Export {
+            file,
+            names,
+            names_file,
+        }) => {
+            let names = settings::read_names(ctx, &names, names_file.as_deref())?;
+            settings::export(ctx, transport, &names, &file).await?;
+        }
+        Commands::Setting(SettingCmd::Import { file, save }) => {
+            settings::import(ctx, transport, &file, save, cli.format).await?;
+        }
+        Commands::Proxy { listen_udp } => {
+            let transport = connection.take().expect("connection is open");
+            proxy::proxy(ctx, &cli, transport, listen_udp).await?;
+        }
+        Commands::Agent { .. }
+        | Commands::Serve { .. }
+        | Commands::Decode { .. }
+        | Commands::Encode { .. }
+        | Commands::Profiles(_)
+        | Commands::Run { .. }
+        | Commands::Udp(_) => {
+            unreachable!("handled without a transport")
+        }
+        Commands::Raw {
+            op,
+            group,
+            id,
+            payload,
+            payload_cbor_hex,
+            seq,
+        } => {
+            let payload = frame::build_payload(payload.as_deref(), payload_cbor_hex.as_deref())?;
+            let sequence = seq.unwrap_or_else(|| ctx.sequence.next());
+            let request = frame::request_frame(op, group, id, sequence, &payload)?;
+            debug!("request: {}", image::hex(&request.encode_with_cbor()));
+
+            let response: SmpFrame<ciborium::Value> = transport.transceive_cbor(&request).await?;
+            frame::print_frame(&response.encode_with_cbor());
+        }
+        Commands::Bench {
+            payload_size,
+            count,
+            upload_bytes,
+            chunk_size,
+        } => {
+            let options = bench::BenchOptions {
+                payload_size,
+                count,
+                upload_bytes,
+                chunk_size,
+            };
+            bench::bench(ctx, transport, &options)
+                .await?
+                .print(ctx, cli.format);
+        }
+        Commands::Probe => {
+            probe::probe(ctx, transport).await?.print(ctx, cli.format);
+        }
+        Commands::Dashboard {
+            interval,
+            ref stats,
+            threshold,
+        } => {
+            let options = dashboard::DashboardOptions {
+                interval,
+                stats: stats.clone(),
+                threshold,
+            };
+            dashboard::dashboard(ctx, &cli, connection, options).await?;
+        }
     }
     Ok(())
 }
@@ -0,0 +1,359 @@
+use std::fmt::Write;
+use std::io::IsTerminal;
+use std::sync::Mutex;
+
+use clap::ValueEnum;
+use mcumgr_smp::application_management::{GetImageStatePayload, GetImageStateResult, ImageState};
+use mcumgr_smp::{ExtraFields, ReturnCode};
+use serde::{Deserialize, Serialize};
+
+use crate::context::Context;
+use crate::error::CliError;
+use crate::{cbor, image};
+
+/// Print a status message on stderr unless `--quiet` is given, e.g. the steps of a command.
+///
+/// Stdout only carries the result of a command, so it can be captured by scripts.
+#[macro_export]
+macro_rules! status {
+    ($ctx:expr, $($arg:tt)*) => {
+        if !$ctx.quiet {
+            eprintln!($($arg)*);
+        }
+    };
+}
+
+/// Results collected instead of printed, while the agent executes a command for a client
+#[derive(Debug, Default)]
+pub struct Capture(Mutex<Option<String>>);
+
+/// Collect the results of the following commands instead of printing them, until
+/// [end_capture]
+pub fn start_capture(ctx: &Context) {
+    *ctx.capture.0.lock().expect("capture lock poisoned") = Some(String::new());
+}
+
+/// Stop collecting results and return what was collected
+pub fn end_capture(ctx: &Context) -> String {
+    ctx.capture
+        .0
+        .lock()
+        .expect("capture lock poisoned")
+        .take()
+        .unwrap_or_default()
+}
+
+/// Print part of the result of a command on stdout, or collect it while capturing
+pub fn write_stdout(ctx: &Context, args: std::fmt::Arguments) {
+    match ctx
+        .capture
+        .0
+        .lock()
+        .expect("capture lock poisoned")
+        .as_mut()
+    {
+        Some(captured) => {
+            let _ = captured.write_fmt(args);
+        }
+        None => print!("{}", args),
+    }
+}
+
+/// Whether results end up on a terminal, e.g. to decide about colors
+pub fn stdout_is_terminal(ctx: &Context) -> bool {
+    ctx.capture
+        .0
+        .lock()
+        .expect("capture lock poisoned")
+        .is_none()
+        && std::io::stdout().is_terminal()
+}
+
+/// Like `print!`, but collected instead while the agent captures the result for a client
+#[macro_export]
+macro_rules! out {
+    ($ctx:expr, $($arg:tt)*) => {
+        $crate::output::write_stdout($ctx, format_args!($($arg)*))
+    };
+}
+
+/// Like `println!`, but collected instead while the agent captures the result for a client
+#[macro_export]
+macro_rules! outln {
+    ($ctx:expr $(,)?) => {
+        $crate::output::write_stdout($ctx, format_args!("\n"))
+    };
+    ($ctx:expr, $($arg:tt)*) => {
+        $crate::output::write_stdout($ctx, format_args!("{}\n", format_args!($($arg)*)))
+    };
+}
+
+#[derive(ValueEnum, Serialize, Deserialize, Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    /// human readable output
+    #[default]
+    Text,
+    /// structured output for scripts
+    Json,
+}
+
+#[derive(Serialize)]
+struct ImageStateJson<'a> {
+    image: i32,
+    slot: i32,
+    version: &'a str,
+    hash: String,
+    active: bool,
+    pending: bool,
+    confirmed: bool,
+    bootable: bool,
+    permanent: bool,
+    #[serde(flatten)]
+    extra: serde_json::Map<String, serde_json::Value>,
+}
+
+/// Fields of a response without a type of their own, as JSON
+pub fn extra_json(extra: &ExtraFields) -> serde_json::Map<String, serde_json::Value> {
+    extra
+        .iter()
+        .map(|(name, value)| (name.clone(), cbor::to_json(value)))
+        .collect()
+}
+
+impl<'a> From<&'a ImageState> for ImageStateJson<'a> {
+    fn from(img: &'a ImageState) -> Self {
+        Self {
+            image: img.image.unwrap_or(0),
+            slot: img.slot,
+            version: &img.version,
+            hash: image::hex(&img.hash),
+            active: img.active,
+            pending: img.pending,
+            confirmed: img.confirmed,
+            bootable: img.bootable,
+            permanent: img.permanent,
+            extra: extra_json(&img.extra),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ImageStatePayloadJson<'a> {
+    images: Vec<ImageStateJson<'a>>,
+    #[serde(rename = "splitStatus", skip_serializing_if = "Option::is_none")]
+    split_status: Option<i32>,
+    #[serde(flatten)]
+    extra: serde_json::Map<String, serde_json::Value>,
+}
+
+/// Format an rc value together with its symbolic name, if known
+pub fn format_rc(rc: i32) -> String {
+    match ReturnCode::try_from(rc) {
+        Ok(code) => format!("{} (rc={}) — {}", code.short_name(), rc, code.description()),
+        Err(_) => format!("device returned rc: {}", rc),
+    }
+}
+
+/// Print an image state response in the requested format
+pub fn print_image_state(ctx: &Context, state: &GetImageStatePayload, format: OutputFormat) {
+    match format {
+        OutputFormat::Text => out!(ctx, "{}", image_state_table(state)),
+        OutputFormat::Json => {
+            let json = ImageStatePayloadJson {
+                images: state.images.iter().map(ImageStateJson::from).collect(),
+                split_status: state.split_status.map(i32::from),
+                extra: extra_json(&state.extra),
+            };
+            outln!(
+                ctx,
+                "{}",
+                serde_json::to_string_pretty(&json).expect("serializing to string can't fail")
+            );
+        }
+    }
+}
+
+/// Print an image state response, or return the error reported by the device
+pub fn print_image_state_result(
+    ctx: &Context,
+    result: GetImageStateResult,
+    format: OutputFormat,
+) -> Result<(), CliError> {
+    match result {
+        GetImageStateResult::Ok(payload) => {
+            print_image_state(ctx, &payload, format);
+            Ok(())
+        }
+        GetImageStateResult::Err(err) => Err(CliError::Device {
+            rc: err.rc,
+            rsn: err.rsn,
+        }),
+    }
+}
+
+/// Render the image state as a table with one row per image slot
+pub fn image_state_table(state: &GetImageStatePayload) -> String {
+    const HEADER: [&str; 9] = [
+        "image",
+        "slot",
+        "version",
+        "hash",
+        "active",
+        "pending",
+        "confirmed",
+        "bootable",
+        "permanent",
+    ];
+
+    let yes_no = |flag: bool| if flag { "yes" } else { "no" }.to_string();
+
+    let rows: Vec<[String; 9]> = state
+        .images
+        .iter()
+        .map(|img| {
+            [
+                img.image.unwrap_or(0).to_string(),
+                img.slot.to_string(),
+                img.version.clone(),
+                image::hex(&img.hash),
+                yes_no(img.active),
+                yes_no(img.pending),
+                yes_no(img.confirmed),
+                yes_no(img.bootable),
+                yes_no(img.permanent),
+            ]
+        })
+        .collect();
+
+    let mut widths = HEADER.map(str::len);
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+
+    let mut out = String::new();
+    let mut push_row = |cells: &[&str]| {
+        let line: Vec<String> = cells
+            .iter()
+            .zip(widths)
+            .map(|(cell, width)| format!("{:<width$}", cell, width = width))
+            .collect();
+        out.push_str(line.join("  ").trim_end());
+        out.push('\n');
+    };
+
+    push_row(&HEADER);
+    for row in &rows {
+        let cells: Vec<&str> = row.iter().map(String::as_str).collect();
+        push_row(&cells);
+    }
+
+    if let Some(split_status) = state.split_status {
+        out.push_str(&format!(
+            "split status: {} ({})\n",
+            split_status,
+            i32::from(split_status)
+        ));
+    }
+    for (name, value) in &state.extra {
+        out.push_str(&format!("{}: {}\n", name, cbor::diag(value)));
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use mcumgr_smp::application_management::SplitStatus;
+    use mcumgr_smp::CborValue;
+
+    use super::*;
+
+    fn state() -> GetImageStatePayload {
+        let image = |image, slot: i32, version: &str, hash: Vec<u8>| ImageState {
+            image,
+            slot,
+            version: version.to_string(),
+            hash,
+            bootable: true,
+            pending: false,
+            confirmed: false,
+            active: false,
+            permanent: false,
+            extra: Default::default(),
+        };
+
+        GetImageStatePayload {
+            images: vec![
+                ImageState {
+                    active: true,
+                    confirmed: true,
+                    ..image(None, 0, "1.2.3", (0..8).collect())
+                },
+                ImageState {
+                    pending: true,
+                    permanent: true,
+                    bootable: false,
+                    ..image(Some(1), 1, "1.3.0-rc.1", (0xf8..=0xff).rev().collect())
+                },
+            ],
+            split_status: Some(SplitStatus::Matching),
+            extra: [("vendor".to_string(), CborValue::Text("x".to_string()))].into(),
+        }
+    }
+
+    #[test]
+    fn image_state_table_snapshot() {
+        assert_eq!(
+            image_state_table(&state()),
+            "\
+image  slot  version     hash              active  pending  confirmed  bootable  permanent
+0      0     1.2.3       0001020304050607  yes     no       yes        yes       no
+1      1     1.3.0-rc.1  fffefdfcfbfaf9f8  no      yes      no         no        yes
+split status: matching (2)
+vendor: \"x\"
+"
+        );
+    }
+
+    #[test]
+    fn image_state_json_snapshot() {
+        let ctx = Context::default();
+        start_capture(&ctx);
+        print_image_state(&ctx, &state(), OutputFormat::Json);
+        let json: serde_json::Value = serde_json::from_str(&end_capture(&ctx)).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "images": [
+                    {
+                        "image": 0,
+                        "slot": 0,
+                        "version": "1.2.3",
+                        "hash": "0001020304050607",
+                        "active": true,
+                        "pending": false,
+                        "confirmed": true,
+                        "bootable": true,
+                        "permanent": false,
+                    },
+                    {
+                        "image": 1,
+                        "slot": 1,
+                        "version": "1.3.0-rc.1",
+                        "hash": "fffefdfcfbfaf9f8",
+                        "active": false,
+                        "pending": true,
+                        "confirmed": false,
+                        "bootable": false,
+                        "permanent": true,
+                    },
+                ],
+                "splitStatus": 2,
+                "vendor": "x",
+            })
+        );
+    }
+}
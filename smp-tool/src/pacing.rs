@@ -0,0 +1,136 @@
+use std::time::Duration;
+
+use serde::Serialize;
+use tokio::time::Instant;
+
+use crate::context::Context;
+use crate::output::OutputFormat;
+use crate::progress::format_size;
+use crate::{outln, status};
+
+/// Limits of an upload, for devices that can't keep up with back-to-back chunks
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RateLimit {
+    pub bytes_per_sec: Option<u64>,
+    /// minimum time between a confirmed chunk and the next request
+    pub chunk_delay: Duration,
+}
+
+/// Paces the chunks of an upload and measures the achieved rate.
+///
+/// Only bytes the device confirmed count, so chunks that have to be resent and offsets the
+/// device skips ahead to when resuming neither slow down nor speed up the upload. The wait is
+/// measured from the previous confirmation, so time lost to retries isn't made up with a burst.
+pub struct Pacer {
+    limit: RateLimit,
+    start: Instant,
+    /// when the previous chunk was sent
+    last: Instant,
+    /// when the next chunk may be sent
+    next: Instant,
+    bytes: u64,
+    /// bytes per second of the fastest chunk
+    peak: f64,
+}
+
+impl Pacer {
+    pub fn new(limit: RateLimit) -> Self {
+        let now = Instant::now();
+        Self {
+            limit,
+            start: now,
+            last: now,
+            next: now,
+            bytes: 0,
+            peak: 0.0,
+        }
+    }
+
+    /// Wait until the next chunk may be sent
+    pub async fn wait(&mut self) {
+        tokio::time::sleep_until(self.next).await;
+        self.last = Instant::now();
+    }
+
+    /// The device confirmed the chunk of `sent` bytes at `offset` by reporting `confirmed` as
+    /// its new offset
+    pub fn confirmed(&mut self, offset: u64, sent: u64, confirmed: u64) {
+        let bytes = confirmed.saturating_sub(offset).min(sent);
+        self.bytes += bytes;
+        let rate = bytes as f64 / self.last.elapsed().as_secs_f64().max(0.001);
+        self.peak = self.peak.max(rate);
+
+        self.next = Instant::now() + self.limit.chunk_delay;
+        if let Some(bytes_per_sec) = self.limit.bytes_per_sec.filter(|rate| *rate > 0) {
+            let due = self.last + Duration::from_secs_f64(bytes as f64 / bytes_per_sec as f64);
+            self.next = self.next.max(due);
+        }
+    }
+
+    /// Confirmed bytes per second since the upload started
+    pub fn rate(&self) -> f64 {
+        self.bytes as f64 / self.start.elapsed().as_secs_f64().max(0.001)
+    }
+
+    /// Confirmed bytes per second of the fastest chunk, from sending it to its confirmation
+    pub fn peak_rate(&self) -> f64 {
+        self.peak
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        self.start.elapsed()
+    }
+}
+
+/// Outcome of a completed upload
+#[derive(Serialize, Debug, Clone)]
+pub struct UploadReport {
+    /// size of the uploaded data
+    pub size: u64,
+    /// bytes sent in chunks, including chunks that were sent again
+    pub bytes_sent: u64,
+    pub duration_secs: f64,
+    pub average_bytes_per_second: f64,
+    pub peak_bytes_per_second: f64,
+    /// chunks and requests that were sent again
+    pub retries: u64,
+    /// the offset the device reported for the last chunk
+    pub final_offset: u64,
+    /// the most chunks in flight at the same time, 1 when each chunk waits for the response to
+    /// the one before
+    pub window: usize,
+    /// whether the device found the upload to match the hash sent with it, if it said so
+    #[serde(rename = "match", skip_serializing_if = "Option::is_none")]
+    pub matched: Option<bool>,
+    /// sha256 of the data, as hex
+    pub sha256: String,
+}
+
+impl UploadReport {
+    /// One line summary of the transfer
+    pub fn summary(&self) -> String {
+        let mut summary = format!(
+            "{} bytes in {:.1}s, {}/s average, {}/s peak",
+            self.size,
+            self.duration_secs,
+            format_size(self.average_bytes_per_second as u64),
+            format_size(self.peak_bytes_per_second as u64)
+        );
+        if self.retries > 0 {
+            summary += &format!(", {} retries, {} bytes sent", self.retries, self.bytes_sent);
+        }
+        summary
+    }
+
+    /// Print the report as the end of an upload: a status line, or a JSON line on stdout
+    pub fn print(&self, ctx: &Context, format: OutputFormat) {
+        match format {
+            OutputFormat::Text => status!(ctx, "sent all bytes: {}", self.summary()),
+            OutputFormat::Json => outln!(
+                ctx,
+                "{}",
+                serde_json::to_string(self).expect("serializing to string can't fail")
+            ),
+        }
+    }
+}
@@ -0,0 +1,296 @@
+use std::error::Error;
+use std::io::ErrorKind;
+use std::time::Duration;
+
+use mcumgr_smp::{
+    os_management::{self, EchoResult},
+    smp::SmpFrame,
+};
+use serde::Serialize;
+use tokio::time::Instant;
+use tracing::debug;
+
+use crate::context::Context;
+use crate::error::CliError;
+use crate::output::OutputFormat;
+use crate::{describe_target, open_transport, outln, Cli, UsedTransport};
+
+/// Parse a duration like `500ms`, `1s`, `1.5s` or `2m`, plain numbers are seconds
+pub fn parse_duration(s: &str) -> Result<Duration, String> {
+    let s = s.trim();
+    let split = s
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(s.len());
+    let (value, unit) = s.split_at(split);
+
+    let value: f64 = value
+        .parse()
+        .map_err(|_| format!("invalid duration: {}", s))?;
+    let seconds = match unit.trim() {
+        "ms" => value / 1000.0,
+        "" | "s" => value,
+        "m" | "min" => value * 60.0,
+        unit => return Err(format!("invalid duration unit {}, use ms, s or m", unit)),
+    };
+
+    Duration::try_from_secs_f64(seconds).map_err(|e| format!("invalid duration {}: {}", s, e))
+}
+
+/// Parameters of `os ping`
+pub struct PingOptions {
+    pub interval: Duration,
+    pub count: Option<u64>,
+    pub fail_after: Option<u64>,
+    pub reconnect: bool,
+}
+
+/// A single ping result, printed as one JSON line per request
+#[derive(Serialize, Debug)]
+struct Reply {
+    seq: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    time_ms: Option<f64>,
+    loss_percent: f64,
+}
+
+/// Statistics printed when pinging stops
+#[derive(Serialize, Debug, Default)]
+struct Summary {
+    transmitted: u64,
+    received: u64,
+    loss_percent: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    min_ms: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    avg_ms: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_ms: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    mdev_ms: Option<f64>,
+}
+
+/// Send echo requests until the count is reached, Ctrl-C is pressed or too many are lost.
+///
+/// Unanswered requests are counted as lost. Responses that arrive after their request
+/// timed out are recognized by their sequence number and discarded.
+/// Returns the connection, unless it failed and couldn't be reopened.
+pub async fn ping(
+    ctx: &Context,
+    cli: &Cli,
+    transport: UsedTransport,
+    options: &PingOptions,
+) -> Result<Option<UsedTransport>, Box<dyn Error>> {
+    let timeout = Duration::from_millis(cli.timeout_ms);
+    let start = Instant::now();
+    let mut transport = Some(transport);
+
+    let mut transmitted = 0u64;
+    let mut rtts = Vec::new();
+    let mut consecutive_lost = 0u64;
+    let mut failure = None;
+
+    let ctrl_c = tokio::signal::ctrl_c();
+    tokio::pin!(ctrl_c);
+
+    while options.count.is_none_or(|count| transmitted < count) {
+        if transmitted > 0 {
+            tokio::select! {
+                _ = &mut ctrl_c => break,
+                _ = tokio::time::sleep(options.interval) => {}
+            }
+        }
+
+        if transport.is_none() {
+            match open_transport(ctx, cli).await {
+                Ok(t) => transport = Some(t),
+                Err(e) => debug!("reconnect failed: {}", e),
+            }
+        }
+
+        let seq = transmitted;
+        transmitted += 1;
+
+        let rtt = match &mut transport {
+            Some(t) => {
+                let ret = tokio::select! {
+                    biased;
+                    _ = &mut ctrl_c => break,
+                    ret = echo(t, ctx.sequence.next(), "ping".to_string(), timeout) => ret,
+                };
+                match ret {
+                    Ok(reply) => reply.map(|(rtt, _)| rtt),
+                    Err(e) if options.reconnect => {
+                        debug!("request {} failed, reconnecting: {}", seq, e);
+                        transport = None;
+                        None
+                    }
+                    Err(e) => {
+                        failure = Some(e);
+                        break;
+                    }
+                }
+            }
+            None => None,
+        };
+
+        match rtt {
+            Some(rtt) => {
+                consecutive_lost = 0;
+                rtts.push(rtt.as_secs_f64() * 1000.0);
+            }
+            None => consecutive_lost += 1,
+        }
+
+        let reply = Reply {
+            seq,
+            time_ms: rtt.map(|rtt| rtt.as_secs_f64() * 1000.0),
+            loss_percent: loss_percent(transmitted, rtts.len() as u64),
+        };
+        match cli.format {
+            OutputFormat::Text => match reply.time_ms {
+                Some(time_ms) => outln!(
+                    ctx,
+                    "seq={} time={:.2} ms loss={:.1}%",
+                    reply.seq,
+                    time_ms,
+                    reply.loss_percent
+                ),
+                None => outln!(
+                    ctx,
+                    "seq={} lost loss={:.1}%",
+                    reply.seq,
+                    reply.loss_percent
+                ),
+            },
+            OutputFormat::Json => outln!(
+                ctx,
+                "{}",
+                serde_json::to_string(&reply).expect("serializing to string can't fail")
+            ),
+        }
+
+        if options
+            .fail_after
+            .is_some_and(|fail_after| consecutive_lost >= fail_after)
+        {
+            failure = Some(
+                std::io::Error::new(
+                    ErrorKind::TimedOut,
+                    format!("{} consecutive requests lost", consecutive_lost),
+                )
+                .into(),
+            );
+            break;
+        }
+    }
+
+    print_summary(ctx, cli, transmitted, &rtts, start.elapsed());
+
+    match failure {
+        Some(e) => Err(e),
+        None => Ok(transport),
+    }
+}
+
+/// Send one echo request and wait for its response.
+///
+/// Returns the round-trip time and the echoed message, or `None` if no response with the
+/// right sequence number arrived in time.
+pub async fn echo(
+    transport: &mut UsedTransport,
+    seq: u8,
+    msg: String,
+    timeout: Duration,
+) -> Result<Option<(Duration, String)>, Box<dyn Error>> {
+    let start = Instant::now();
+    let deadline = start + timeout;
+    transport.send_cbor(&os_management::echo(seq, msg)).await?;
+
+    loop {
+        let ret: SmpFrame<EchoResult> =
+            match tokio::time::timeout_at(deadline, transport.receive_cbor()).await {
+                Err(_) => return Ok(None),
+                Ok(Err(mcumgr_smp::transport::error::Error::Io(e)))
+                    if matches!(e.kind(), ErrorKind::TimedOut | ErrorKind::WouldBlock) =>
+                {
+                    if Instant::now() >= deadline {
+                        return Ok(None);
+                    }
+                    continue;
+                }
+                Ok(ret) => ret?,
+            };
+        debug!("{:?}", ret);
+
+        if ret.sequence != seq {
+            debug!("discarding late response with sequence {}", ret.sequence);
+            continue;
+        }
+
+        return match ret.data {
+            EchoResult::Ok { r } => Ok(Some((start.elapsed(), r))),
+            EchoResult::Err { rc } => Err(CliError::device(rc).into()),
+        };
+    }
+}
+
+pub fn loss_percent(transmitted: u64, received: u64) -> f64 {
+    if transmitted == 0 {
+        return 0.0;
+    }
+    (transmitted - received) as f64 * 100.0 / transmitted as f64
+}
+
+fn print_summary(ctx: &Context, cli: &Cli, transmitted: u64, rtts: &[f64], elapsed: Duration) {
+    let mut summary = Summary {
+        transmitted,
+        received: rtts.len() as u64,
+        loss_percent: loss_percent(transmitted, rtts.len() as u64),
+        ..Default::default()
+    };
+
+    if !rtts.is_empty() {
+        let n = rtts.len() as f64;
+        let avg = rtts.iter().sum::<f64>() / n;
+        let mean_square = rtts.iter().map(|rtt| rtt * rtt).sum::<f64>() / n;
+        summary.min_ms = rtts.iter().copied().reduce(f64::min);
+        summary.max_ms = rtts.iter().copied().reduce(f64::max);
+        summary.avg_ms = Some(avg);
+        summary.mdev_ms = Some((mean_square - avg * avg).max(0.0).sqrt());
+    }
+
+    match cli.format {
+        OutputFormat::Text => {
+            outln!(ctx);
+            outln!(ctx, "--- {} ping statistics ---", describe_target(cli));
+            outln!(
+                ctx,
+                "{} requests transmitted, {} received, {:.1}% loss, time {}ms",
+                summary.transmitted,
+                summary.received,
+                summary.loss_percent,
+                elapsed.as_millis()
+            );
+            if let (Some(min), Some(avg), Some(max), Some(mdev)) = (
+                summary.min_ms,
+                summary.avg_ms,
+                summary.max_ms,
+                summary.mdev_ms,
+            ) {
+                outln!(
+                    ctx,
+                    "rtt min/avg/max/mdev = {:.2}/{:.2}/{:.2}/{:.2} ms",
+                    min,
+                    avg,
+                    max,
+                    mdev
+                );
+            }
+        }
+        OutputFormat::Json => outln!(
+            ctx,
+            "{}",
+            serde_json::to_string(&summary).expect("serializing to string can't fail")
+        ),
+    }
+}
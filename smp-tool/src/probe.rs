@@ -0,0 +1,495 @@
+use std::error::Error;
+use std::fmt::Display;
+use std::time::Instant;
+
+use mcumgr_smp::{
+    os_management::{self, BootloaderInfoResult, GetInfoResult, McumgrParamsResult, ProbeInfo},
+    smp::SmpFrame,
+    transport::retry as policy,
+    ReturnCode,
+};
+use serde::{Serialize, Serializer};
+use tracing::debug;
+
+use crate::context::Context;
+use crate::error::CliError;
+use crate::output::{self, OutputFormat};
+use crate::{flash, image, outln, UsedTransport};
+
+/// The `os info` format letters and the names of their fields, in the order of the `a` format
+const INFO_FIELDS: [(char, &str); 9] = [
+    ('s', "kernel_name"),
+    ('n', "node_name"),
+    ('r', "release"),
+    ('v', "version"),
+    ('b', "build_date"),
+    ('m', "machine"),
+    ('p', "processor"),
+    ('i', "platform"),
+    ('o', "os"),
+];
+
+/// A value that is "unsupported" if the device doesn't implement the command
+#[derive(Debug)]
+pub enum Probed<T> {
+    Value(T),
+    Unsupported,
+}
+
+impl<T: Serialize> Serialize for Probed<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Probed::Value(value) => value.serialize(serializer),
+            Probed::Unsupported => serializer.serialize_str("unsupported"),
+        }
+    }
+}
+
+impl<T: Display> Display for Probed<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Probed::Value(value) => value.fmt(f),
+            Probed::Unsupported => f.write_str("unsupported"),
+        }
+    }
+}
+
+/// `os info` fields in the order they were requested, serialized as a JSON object
+#[derive(Debug, Default)]
+pub struct InfoFields(pub Vec<(String, Probed<String>)>);
+
+impl Serialize for InfoFields {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_map(self.0.iter().map(|(name, value)| (name, value)))
+    }
+}
+
+impl InfoFields {
+    /// Field names and values for [print_fields]
+    fn text(&self) -> Vec<(String, String)> {
+        self.0
+            .iter()
+            .map(|(name, value)| (name.replace('_', " "), value.to_string()))
+            .collect()
+    }
+}
+
+/// Turn the ENOTSUP error of a device into [Probed::Unsupported]
+fn probed<T>(ret: Result<T, Box<dyn Error>>) -> Result<Probed<T>, Box<dyn Error>> {
+    match ret {
+        Ok(value) => Ok(Probed::Value(value)),
+        Err(err) => match err.downcast_ref::<CliError>() {
+            Some(CliError::Device { rc, .. }) if *rc == ReturnCode::NotSupported as i32 => {
+                Ok(Probed::Unsupported)
+            }
+            _ => Err(err),
+        },
+    }
+}
+
+/// Query a single `os info` format letter
+async fn info_field(
+    ctx: &Context,
+    transport: &mut UsedTransport,
+    letter: char,
+) -> Result<String, Box<dyn Error>> {
+    let ret: SmpFrame<GetInfoResult> = transport
+        .transceive_cbor(&os_management::get_info(
+            ctx.sequence.next(),
+            letter.to_string(),
+        ))
+        .await?;
+    debug!("{:?}", ret);
+
+    match ret.data {
+        GetInfoResult::Ok { output, .. } => Ok(output),
+        GetInfoResult::Err { rc } => Err(CliError::device(rc).into()),
+    }
+}
+
+/// The letters of an `os info` format, with `a` expanded to all fields and duplicates removed.
+/// Unknown letters are passed to the device as is
+pub fn info_letters(format: &str) -> Vec<char> {
+    let mut letters: Vec<char> = Vec::new();
+    for letter in format.chars() {
+        let expanded = match letter {
+            'a' => INFO_FIELDS.iter().map(|(letter, _)| *letter).collect(),
+            letter => vec![letter],
+        };
+        for letter in expanded {
+            if !letters.contains(&letter) {
+                letters.push(letter);
+            }
+        }
+    }
+    letters
+}
+
+/// Query the `os info` fields selected by `format` one by one, so values containing spaces
+/// can be told apart
+async fn info_fields(
+    ctx: &Context,
+    transport: &mut UsedTransport,
+    format: &str,
+) -> Result<InfoFields, Box<dyn Error>> {
+    let mut fields = Vec::new();
+    for letter in info_letters(format) {
+        let name = INFO_FIELDS
+            .iter()
+            .find(|(l, _)| *l == letter)
+            .map_or_else(|| letter.to_string(), |(_, name)| name.to_string());
+        let value = probed(info_field(ctx, transport, letter).await)?;
+        fields.push((name, value));
+    }
+
+    Ok(InfoFields(fields))
+}
+
+/// Print `name: value` lines with aligned values
+fn print_fields(ctx: &Context, fields: &[(String, String)]) {
+    let width = fields.iter().map(|(name, _)| name.len()).max().unwrap_or(0) + 1;
+    for (name, value) in fields {
+        outln!(
+            ctx,
+            "{:<width$} {}",
+            format!("{}:", name),
+            value,
+            width = width
+        );
+    }
+}
+
+fn print_json(ctx: &Context, json: &impl Serialize) {
+    outln!(
+        ctx,
+        "{}",
+        serde_json::to_string_pretty(json).expect("serializing to string can't fail")
+    );
+}
+
+/// Print the OS and application information selected by `format`, all fields by default
+pub async fn os_info(
+    ctx: &Context,
+    transport: &mut UsedTransport,
+    format: Option<&str>,
+    output: OutputFormat,
+) -> Result<(), Box<dyn Error>> {
+    let fields = info_fields(ctx, transport, format.unwrap_or("a")).await?;
+
+    match output {
+        OutputFormat::Text => print_fields(ctx, &fields.text()),
+        OutputFormat::Json => print_json(ctx, &fields),
+    }
+
+    Ok(())
+}
+
+/// Name of an MCUboot mode reported by the bootloader info `mode` query
+pub fn mcuboot_mode_name(mode: i32) -> Option<&'static str> {
+    Some(match mode {
+        0 => "single application",
+        1 => "swap using scratch",
+        2 => "overwrite only",
+        3 => "swap without scratch",
+        4 => "direct XIP without revert",
+        5 => "direct XIP with revert",
+        6 => "RAM loader",
+        7 => "firmware loader",
+        _ => return None,
+    })
+}
+
+#[derive(Serialize, Debug, Default)]
+pub struct BootloaderInfo {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bootloader: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mode: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mode_name: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub no_downgrade: Option<bool>,
+    /// answers to other queries
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
+}
+
+async fn query_bootloader(
+    ctx: &Context,
+    transport: &mut UsedTransport,
+    query: Option<&str>,
+) -> Result<BootloaderInfo, Box<dyn Error>> {
+    let ret: SmpFrame<BootloaderInfoResult> = transport
+        .transceive_cbor(&os_management::bootloader_info(
+            ctx.sequence.next(),
+            query.map(str::to_string),
+        ))
+        .await?;
+    debug!("{:?}", ret);
+
+    match ret.data {
+        BootloaderInfoResult::Ok {
+            bootloader,
+            mode,
+            no_downgrade,
+            extra,
+        } => Ok(BootloaderInfo {
+            bootloader,
+            mode,
+            mode_name: mode.and_then(mcuboot_mode_name),
+            no_downgrade,
+            extra: output::extra_json(&extra),
+        }),
+        BootloaderInfoResult::Err { rc } => Err(CliError::device(rc).into()),
+    }
+}
+
+impl BootloaderInfo {
+    /// e.g. `MCUboot, swap using scratch`
+    fn summary(&self) -> String {
+        let mut parts: Vec<String> = self.bootloader.iter().cloned().collect();
+        match (self.mode, self.mode_name) {
+            (_, Some(name)) => parts.push(name.to_string()),
+            (Some(mode), None) => parts.push(format!("mode {}", mode)),
+            _ => {}
+        }
+        if self.no_downgrade == Some(true) {
+            parts.push("no downgrade".to_string());
+        }
+        parts.join(", ")
+    }
+}
+
+/// Print the bootloader name, or the answer to `query`
+pub async fn bootloader_info(
+    ctx: &Context,
+    transport: &mut UsedTransport,
+    query: Option<&str>,
+    format: OutputFormat,
+) -> Result<(), Box<dyn Error>> {
+    let info = query_bootloader(ctx, transport, query).await?;
+
+    match format {
+        OutputFormat::Text => {
+            let mut fields = Vec::new();
+            if let Some(bootloader) = &info.bootloader {
+                fields.push(("bootloader".to_string(), bootloader.clone()));
+            }
+            if let Some(mode) = info.mode {
+                let value = match info.mode_name {
+                    Some(name) => format!("{} ({})", mode, name),
+                    None => mode.to_string(),
+                };
+                fields.push(("mode".to_string(), value));
+            }
+            if let Some(no_downgrade) = info.no_downgrade {
+                let value = if no_downgrade { "yes" } else { "no" };
+                fields.push(("no downgrade".to_string(), value.to_string()));
+            }
+            for (name, value) in &info.extra {
+                fields.push((name.replace('_', " "), value.to_string()));
+            }
+            print_fields(ctx, &fields);
+        }
+        OutputFormat::Json => print_json(ctx, &info),
+    }
+
+    Ok(())
+}
+
+#[derive(Serialize, Debug)]
+pub struct Params {
+    pub buf_size: u32,
+    pub buf_count: u32,
+}
+
+impl Display for Params {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} x {} bytes", self.buf_count, self.buf_size)
+    }
+}
+
+async fn params(ctx: &Context, transport: &mut UsedTransport) -> Result<Params, Box<dyn Error>> {
+    let ret: SmpFrame<McumgrParamsResult> = transport
+        .transceive_cbor(&os_management::mcumgr_params(ctx.sequence.next()))
+        .await?;
+    debug!("{:?}", ret);
+
+    match ret.data {
+        McumgrParamsResult::Ok {
+            buf_size,
+            buf_count,
+        } => Ok(Params {
+            buf_size,
+            buf_count,
+        }),
+        McumgrParamsResult::Err { rc } => Err(CliError::device(rc).into()),
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct ActiveImage {
+    pub image: i32,
+    pub slot: i32,
+    pub version: String,
+    pub hash: String,
+    pub confirmed: bool,
+}
+
+/// Summary of a device for bug reports
+#[derive(Serialize, Debug)]
+pub struct Probe {
+    pub os: InfoFields,
+    pub params: Probed<Params>,
+    pub bootloader: Probed<BootloaderInfo>,
+    pub images: Probed<Vec<ActiveImage>>,
+}
+
+impl Probe {
+    pub fn print(&self, ctx: &Context, format: OutputFormat) {
+        match format {
+            OutputFormat::Text => {
+                let mut fields = self.os.text();
+
+                fields.push(("buffers".to_string(), self.params.to_string()));
+                let bootloader = match &self.bootloader {
+                    Probed::Value(info) => info.summary(),
+                    Probed::Unsupported => "unsupported".to_string(),
+                };
+                fields.push(("bootloader".to_string(), bootloader));
+
+                match &self.images {
+                    Probed::Value(images) if images.is_empty() => {
+                        fields.push(("image".to_string(), "no active image".to_string()))
+                    }
+                    Probed::Value(images) => {
+                        for img in images {
+                            fields.push((
+                                format!("image {}", img.image),
+                                format!(
+                                    "{} slot {}{} {}",
+                                    img.version,
+                                    img.slot,
+                                    if img.confirmed { "" } else { " (unconfirmed)" },
+                                    img.hash
+                                ),
+                            ));
+                        }
+                    }
+                    Probed::Unsupported => {
+                        fields.push(("image".to_string(), "unsupported".to_string()))
+                    }
+                }
+
+                print_fields(ctx, &fields);
+            }
+            OutputFormat::Json => print_json(ctx, self),
+        }
+    }
+}
+
+/// Collect the OS information, SMP buffer sizes, bootloader and active images of the device.
+///
+/// Commands the device doesn't implement are reported as unsupported, other errors abort.
+pub async fn probe(ctx: &Context, transport: &mut UsedTransport) -> Result<Probe, Box<dyn Error>> {
+    let os = info_fields(ctx, transport, "a").await?;
+
+    let params = probed(params(ctx, transport).await)?;
+
+    let mut bootloader = probed(query_bootloader(ctx, transport, None).await)?;
+    if let Probed::Value(info) = &mut bootloader {
+        if info.bootloader.as_deref() == Some("MCUboot") {
+            if let Probed::Value(mode) =
+                probed(query_bootloader(ctx, transport, Some("mode")).await)?
+            {
+                info.mode = mode.mode;
+                info.mode_name = mode.mode_name;
+                info.no_downgrade = mode.no_downgrade;
+            }
+        }
+    }
+
+    let images = probed(flash::get_image_state(ctx, transport).await)?;
+    let images = match images {
+        Probed::Value(state) => Probed::Value(
+            state
+                .images
+                .iter()
+                .filter(|img| img.active)
+                .map(|img| ActiveImage {
+                    image: img.image.unwrap_or(0),
+                    slot: img.slot,
+                    version: img.version.clone(),
+                    hash: image::hex(&img.hash),
+                    confirmed: img.confirmed,
+                })
+                .collect(),
+        ),
+        Probed::Unsupported => Probed::Unsupported,
+    };
+
+    Ok(Probe {
+        os,
+        params,
+        bootloader,
+        images,
+    })
+}
+
+/// Printed below a failed probe of a serial device
+const SERIAL_PROBE_HINT: &str = "check the baud rate (--serial-baud) and that the firmware \
+serves SMP on this port, or skip the check with --no-probe";
+
+/// Printed below a failed probe of other devices
+const PROBE_HINT: &str = "check that the firmware serves SMP on this transport, or skip the \
+check with --no-probe";
+
+/// What the probe after connecting told about the device, `None` with `--no-probe`
+pub fn connected(ctx: &Context) -> Option<ProbeInfo> {
+    *ctx.connected.lock().expect("probe lock poisoned")
+}
+
+/// Check that the device answers SMP right after the transport was opened, which succeeds for
+/// UDP and for serial ports of firmware without SMP as well.
+///
+/// A parameters request is sent with the fast timeout, its result is kept for [connected].
+pub async fn on_connect(
+    ctx: &Context,
+    transport: &mut UsedTransport,
+    target: &str,
+    serial: bool,
+) -> Result<ProbeInfo, Box<dyn Error>> {
+    let start = Instant::now();
+    let ret: Result<SmpFrame<McumgrParamsResult>, _> = transport
+        .transceive_cbor(&os_management::mcumgr_params(ctx.sequence.next()))
+        .await;
+    debug!("probe: {:?}", ret);
+
+    let ret = match ret {
+        Ok(ret) => ret,
+        Err(e) if policy::is_lost(&e) => {
+            let err = CliError::Connect(Box::new(std::io::Error::new(
+                std::io::ErrorKind::TimedOut,
+                format!("device did not respond to SMP probe on {}", target),
+            )));
+            Err(CliError::Hint {
+                hint: if serial {
+                    SERIAL_PROBE_HINT
+                } else {
+                    PROBE_HINT
+                },
+                source: Box::new(err),
+            })?
+        }
+        Err(e) => Err(e)?,
+    };
+
+    let info = ProbeInfo::from_response(&ret, start.elapsed());
+    debug!(
+        "device answered the probe after {:?}, SMP version {}",
+        info.rtt,
+        info.version + 1
+    );
+    *ctx.connected.lock().expect("probe lock poisoned") = Some(info);
+    Ok(info)
+}
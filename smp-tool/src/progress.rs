@@ -0,0 +1,251 @@
+use std::io::{IsTerminal, Write};
+use std::time::{Duration, Instant};
+
+use clap::ValueEnum;
+use serde::Serialize;
+
+use crate::context::Context;
+
+const WIDTH: usize = 30;
+const REDRAW_INTERVAL: Duration = Duration::from_millis(100);
+const EVENT_INTERVAL: Duration = Duration::from_millis(250);
+
+#[derive(ValueEnum, Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum ProgressFormat {
+    /// progress bar on stderr if it is a terminal
+    #[default]
+    Bar,
+    /// one JSON event per line on stderr
+    Json,
+}
+
+/// Long help of `--progress`, the event schema is a stable interface
+pub const PROGRESS_HELP: &str = "How progress is reported on stderr
+
+`json` prints one object per line and leaves stdout to the result of the command:
+  phase         string, what is in progress, e.g. upload, download, mark, reset, wait, confirm
+  step          string, only in commands with several steps, e.g. the image being flashed
+  bytes_done    number of bytes transferred so far, of all images when flashing several
+  bytes_total   number or null if the size is unknown
+  rate          bytes per second since the phase started
+  eta_seconds   number or null if it can't be estimated
+  warning       string, only in events about something unexpected, e.g. the device
+                restarting an upload after a reboot
+Events are sent at most 4 times per second, plus one when a transfer is complete.";
+
+/// Send the JSON events as `progress` notifications on stdout, for `serve --stdio`
+pub fn init_notifications(ctx: &mut Context) {
+    ctx.progress = ProgressFormat::Json;
+    ctx.notify_progress = true;
+}
+
+/// Whether progress is reported as JSON events instead of text
+pub fn is_json(ctx: &Context) -> bool {
+    ctx.progress == ProgressFormat::Json
+}
+
+#[derive(Serialize)]
+struct Event<'a> {
+    phase: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    step: Option<&'a str>,
+    bytes_done: u64,
+    bytes_total: Option<u64>,
+    rate: f64,
+    eta_seconds: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    warning: Option<&'a str>,
+}
+
+impl Event<'_> {
+    fn emit(&self, ctx: &Context) {
+        if ctx.notify_progress {
+            let params = serde_json::to_value(self).expect("serializing to a value can't fail");
+            crate::rpc::notify(ctx, "progress", params);
+            return;
+        }
+        let line = serde_json::to_string(self).expect("serializing to string can't fail");
+        let mut stderr = std::io::stderr().lock();
+        let _ = writeln!(stderr, "{}", line);
+        let _ = stderr.flush();
+    }
+}
+
+/// Report the start of a step without transferred bytes, only as a JSON event
+pub fn step(ctx: &Context, phase: &str, step: &str) {
+    if !is_json(ctx) {
+        return;
+    }
+    Event {
+        phase,
+        step: Some(step),
+        bytes_done: 0,
+        bytes_total: None,
+        rate: 0.0,
+        eta_seconds: None,
+        warning: None,
+    }
+    .emit(ctx);
+}
+
+/// A progress bar on stderr, drawn only if stderr is a terminal, or JSON progress events
+pub struct Progress<'a> {
+    ctx: &'a Context,
+    label: String,
+    step: Option<String>,
+    /// bytes of earlier transfers and the total of all, for transfers reported together
+    offset: u64,
+    overall_total: Option<u64>,
+    enabled: bool,
+    json: bool,
+    start: Instant,
+    last_draw: Option<Instant>,
+}
+
+impl<'a> Progress<'a> {
+    /// `enabled` allows callers to suppress the bar, e.g. for JSON output. `--quiet` suppresses
+    /// it too, JSON progress events are always reported
+    pub fn new(ctx: &'a Context, label: impl Into<String>, enabled: bool) -> Self {
+        let json = is_json(ctx);
+        Self {
+            ctx,
+            label: label.into(),
+            step: None,
+            offset: 0,
+            overall_total: None,
+            enabled: json || (enabled && !ctx.quiet && std::io::stderr().is_terminal()),
+            json,
+            start: Instant::now(),
+            last_draw: None,
+        }
+    }
+
+    /// Name the step of a command with several steps in the JSON events
+    pub fn with_step(mut self, step: impl Into<String>) -> Self {
+        self.step = Some(step.into());
+        self
+    }
+
+    /// Report this transfer as part of several, e.g. the images of a multi-image flash:
+    /// `done` bytes of the earlier transfers are counted in, out of `total` bytes of all
+    pub fn with_offset(mut self, done: u64, total: u64) -> Self {
+        self.offset = done;
+        self.overall_total = Some(total);
+        self
+    }
+
+    /// Show the transferred bytes, redrawing at most every 100ms unless the transfer is done
+    pub fn update(&mut self, done: u64, total: Option<u64>) {
+        if !self.enabled {
+            return;
+        }
+        let complete = total.is_some_and(|total| done >= total);
+        let interval = if self.json {
+            EVENT_INTERVAL
+        } else {
+            REDRAW_INTERVAL
+        };
+        if !complete && self.last_draw.is_some_and(|last| last.elapsed() < interval) {
+            return;
+        }
+        self.last_draw = Some(Instant::now());
+
+        let rate = done as f64 / self.start.elapsed().as_secs_f64().max(0.001);
+        let done = self.offset + done;
+        let total = self.overall_total.or(total);
+        if self.json {
+            Event {
+                phase: &self.label,
+                step: self.step.as_deref(),
+                bytes_done: done,
+                bytes_total: total,
+                rate,
+                eta_seconds: total
+                    .filter(|_| rate > 0.0)
+                    .map(|total| total.saturating_sub(done) as f64 / rate),
+                warning: None,
+            }
+            .emit(self.ctx);
+            return;
+        }
+
+        let line = match total {
+            Some(total) if total > 0 => {
+                let fraction = (done as f64 / total as f64).min(1.0);
+                let filled = (fraction * WIDTH as f64) as usize;
+                format!(
+                    "{} [{}{}] {:>3}% {}/{} {}/s",
+                    self.label,
+                    "#".repeat(filled),
+                    "-".repeat(WIDTH - filled),
+                    (fraction * 100.0) as u32,
+                    format_size(done),
+                    format_size(total),
+                    format_size(rate as u64)
+                )
+            }
+            _ => format!(
+                "{} {} {}/s",
+                self.label,
+                format_size(done),
+                format_size(rate as u64)
+            ),
+        };
+
+        let mut stderr = std::io::stderr().lock();
+        let _ = write!(stderr, "\r{}\x1b[K", line);
+        let _ = stderr.flush();
+    }
+
+    /// Report something unexpected during the transfer, as an event with `warning` or as a
+    /// warning on stderr
+    pub fn warn(&mut self, done: u64, total: Option<u64>, message: &str) {
+        if !self.json {
+            self.finish();
+            eprintln!("warning: {}", message);
+            return;
+        }
+        Event {
+            phase: &self.label,
+            step: self.step.as_deref(),
+            bytes_done: self.offset + done,
+            bytes_total: self.overall_total.or(total),
+            rate: done as f64 / self.start.elapsed().as_secs_f64().max(0.001),
+            eta_seconds: None,
+            warning: Some(message),
+        }
+        .emit(self.ctx);
+    }
+
+    /// End the line of the progress bar, so following output starts on a new line
+    pub fn finish(&mut self) {
+        if self.enabled && !self.json && self.last_draw.is_some() {
+            eprintln!();
+        }
+        self.last_draw = None;
+    }
+}
+
+impl Drop for Progress<'_> {
+    /// Errors that abort a transfer start on a new line too
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
+
+/// Format a byte count with a binary unit, e.g. `12.3 KiB`
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["B", "KiB", "MiB", "GiB"];
+
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+
+    match unit {
+        0 => format!("{} B", bytes),
+        _ => format!("{:.1} {}", value, UNITS[unit]),
+    }
+}
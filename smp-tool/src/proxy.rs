@@ -0,0 +1,233 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use mcumgr_smp::transport::error::Error as TransportError;
+use mcumgr_smp::transport::retry;
+use tokio::net::UdpSocket;
+use tokio::time::Instant;
+use tracing::debug;
+
+use crate::context::Context;
+use crate::{describe_target, open_transport, status, Cli, UsedTransport};
+
+/// Length of the SMP header, the sequence number is its 7th byte
+const HEADER_LEN: usize = 8;
+const SEQ_OFFSET: usize = 6;
+
+/// A request forwarded to the device whose response hasn't arrived yet
+struct Pending {
+    client: SocketAddr,
+    /// the sequence number chosen by the client, restored in the response
+    client_seq: u8,
+    deadline: Instant,
+}
+
+/// Requests in flight, by the sequence number they were forwarded with
+#[derive(Default)]
+struct Outstanding {
+    pending: HashMap<u8, Pending>,
+}
+
+impl Outstanding {
+    /// The sequence number to forward a request with.
+    ///
+    /// Frames are forwarded verbatim unless another client already waits for a response with
+    /// the same sequence number, then a free one is used instead. A request sent again by its
+    /// client, e.g. after a lost response, keeps the number it was forwarded with.
+    fn assign(&mut self, client: SocketAddr, client_seq: u8, deadline: Instant) -> Option<u8> {
+        let seq = (0..=u8::MAX)
+            .map(|i| client_seq.wrapping_add(i))
+            .find(|seq| match self.pending.get(seq) {
+                Some(p) => p.client == client && p.client_seq == client_seq,
+                None => true,
+            })?;
+        self.pending.insert(
+            seq,
+            Pending {
+                client,
+                client_seq,
+                deadline,
+            },
+        );
+        Some(seq)
+    }
+
+    /// Drop requests whose response didn't arrive in time, e.g. because their client is gone
+    fn expire(&mut self, now: Instant) -> usize {
+        let before = self.pending.len();
+        self.pending.retain(|seq, p| {
+            if p.deadline <= now {
+                debug!("request {} of {} timed out", seq, p.client);
+            }
+            p.deadline > now
+        });
+        before - self.pending.len()
+    }
+
+    fn next_deadline(&self) -> Option<Instant> {
+        self.pending.values().map(|p| p.deadline).min()
+    }
+}
+
+/// Relay SMP frames received on a UDP socket to the device and its responses back to the
+/// client that sent the request, until Ctrl-C is pressed.
+///
+/// Several clients can share the device, their requests are matched to the responses by
+/// sequence number. Requests that aren't answered within the timeout are forgotten, so a
+/// client that went away doesn't block its sequence numbers. When the connection to the
+/// device fails, it is reopened for the next request.
+pub async fn proxy(
+    ctx: &Context,
+    cli: &Cli,
+    transport: UsedTransport,
+    listen: SocketAddr,
+) -> Result<(), Box<dyn Error>> {
+    let timeout = Duration::from_millis(cli.timeout_ms);
+    let socket = UdpSocket::bind(listen)
+        .await
+        .map_err(|e| format!("can't listen on {}: {}", listen, e))?;
+    status!(
+        ctx,
+        "relaying SMP over UDP on {} to {}",
+        socket.local_addr()?,
+        describe_target(cli)
+    );
+
+    let mut transport = Some(transport);
+    let mut outstanding = Outstanding::default();
+    let mut forwarded = 0u64;
+    let mut answered = 0u64;
+    let mut timed_out = 0u64;
+    let mut buf = vec![0u8; u16::MAX as usize];
+
+    let ctrl_c = tokio::signal::ctrl_c();
+    tokio::pin!(ctrl_c);
+
+    loop {
+        let waiting = !outstanding.pending.is_empty();
+        let deadline = outstanding
+            .next_deadline()
+            .unwrap_or_else(|| Instant::now() + timeout);
+
+        tokio::select! {
+            _ = &mut ctrl_c => break,
+            ret = socket.recv_from(&mut buf) => {
+                // e.g. a port unreachable message for a client that went away
+                let (len, client) = match ret {
+                    Ok(ret) => ret,
+                    Err(e) => {
+                        debug!("receiving from the clients failed: {}", e);
+                        continue;
+                    }
+                };
+                let mut frame = buf[..len].to_vec();
+                if frame.len() < HEADER_LEN {
+                    debug!("ignoring {} bytes from {}, not an SMP frame", len, client);
+                    continue;
+                }
+
+                if transport.is_none() {
+                    match open_transport(ctx, cli).await {
+                        Ok(t) => {
+                            status!(ctx, "reconnected to {}", describe_target(cli));
+                            transport = Some(t);
+                        }
+                        Err(e) => {
+                            eprintln!(
+                                "warning: reconnecting failed, dropping the request of {}: {}",
+                                client, e
+                            );
+                            continue;
+                        }
+                    }
+                }
+
+                let client_seq = frame[SEQ_OFFSET];
+                let deadline = Instant::now() + timeout;
+                let Some(seq) = outstanding.assign(client, client_seq, deadline) else {
+                    eprintln!(
+                        "warning: 256 requests in flight, dropping the request of {}",
+                        client
+                    );
+                    continue;
+                };
+                frame[SEQ_OFFSET] = seq;
+                debug!("{} seq {} -> seq {}", client, client_seq, seq);
+
+                let t = transport.as_mut().expect("transport is connected");
+                if let Err(e) = t.send(frame).await {
+                    lost_connection(&mut transport, &mut outstanding, e).await;
+                    continue;
+                }
+                forwarded += 1;
+            }
+            ret = receive(transport.as_mut()), if waiting => {
+                let mut frame = match ret {
+                    Ok(frame) => frame,
+                    Err(e) if retry::is_lost(&e) => continue,
+                    Err(e) => {
+                        lost_connection(&mut transport, &mut outstanding, e).await;
+                        continue;
+                    }
+                };
+                if frame.len() < HEADER_LEN {
+                    debug!("ignoring {} bytes from the device, not an SMP frame", frame.len());
+                    continue;
+                }
+
+                let seq = frame[SEQ_OFFSET];
+                let Some(request) = outstanding.pending.remove(&seq) else {
+                    debug!("dropping response with seq {}, no request waits for it", seq);
+                    continue;
+                };
+                frame[SEQ_OFFSET] = request.client_seq;
+                if let Err(e) = socket.send_to(&frame, request.client).await {
+                    debug!("can't send the response to {}: {}", request.client, e);
+                    continue;
+                }
+                answered += 1;
+            }
+            _ = tokio::time::sleep_until(deadline), if waiting => {}
+        }
+
+        timed_out += outstanding.expire(Instant::now()) as u64;
+    }
+
+    if let Some(t) = &mut transport {
+        let _ = t.close().await;
+    }
+    status!(
+        ctx,
+        "forwarded {} requests, {} answered, {} timed out",
+        forwarded,
+        answered,
+        timed_out
+    );
+    Ok(())
+}
+
+async fn receive(transport: Option<&mut UsedTransport>) -> Result<Vec<u8>, TransportError> {
+    match transport {
+        Some(t) => t.receive().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Forget the connection and the requests sent over it, it is reopened for the next request
+async fn lost_connection(
+    transport: &mut Option<UsedTransport>,
+    outstanding: &mut Outstanding,
+    err: TransportError,
+) {
+    eprintln!(
+        "warning: connection to the device failed, dropping {} requests in flight: {}",
+        outstanding.pending.len(),
+        err
+    );
+    outstanding.pending.clear();
+    if let Some(mut t) = transport.take() {
+        let _ = t.close().await;
+    }
+}
@@ -0,0 +1,105 @@
+use std::error::Error;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use clap::ValueEnum;
+use mcumgr_smp::transport::error::Error as TransportError;
+use mcumgr_smp::transport::observer::TransportObserver;
+use mcumgr_smp::transport::replay::{Recorder, ReplayTransport, RequestMatch};
+use mcumgr_smp::transport::smp::SmpTransport;
+
+use crate::context::Context;
+
+/// How requests are compared to the recording by `--transport replay`
+#[derive(ValueEnum, Copy, Clone, Debug, Default)]
+pub enum ReplayMatch {
+    /// the complete request except for the sequence number
+    #[default]
+    Exact,
+    /// only the header: operation, group and command
+    Header,
+}
+
+impl From<ReplayMatch> for RequestMatch {
+    fn from(matching: ReplayMatch) -> Self {
+        match matching {
+            ReplayMatch::Exact => RequestMatch::Exact,
+            ReplayMatch::Header => RequestMatch::Header,
+        }
+    }
+}
+
+/// Start recording the frames of all connections of this session to `path`
+pub fn record(path: &Path) -> Result<Arc<Recorder>, Box<dyn Error>> {
+    let recorder =
+        Recorder::create(path).map_err(|e| format!("can't create {}: {}", path.display(), e))?;
+    Ok(Arc::new(recorder))
+}
+
+/// The recorder of `--record`, to be added to the observers of a connection
+pub fn observer(ctx: &Context) -> Option<Arc<dyn TransportObserver>> {
+    ctx.recorder
+        .clone()
+        .map(|recorder| recorder as Arc<dyn TransportObserver>)
+}
+
+/// Warn if the recording is incomplete or a replay didn't send all recorded requests, at the
+/// end of the session
+pub fn finish(ctx: &Context) {
+    if ctx
+        .recorder
+        .as_ref()
+        .is_some_and(|recorder| recorder.failed())
+    {
+        eprintln!("warning: writing the recording failed, it is incomplete");
+    }
+    if let Some(replay) = ctx.replay.get() {
+        let remaining = replay
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .remaining_requests();
+        if remaining > 0 {
+            eprintln!(
+                "warning: {} recorded request(s) weren't sent by this session",
+                remaining
+            );
+        }
+    }
+}
+
+/// A transport serving the recording of `--replay-file`, loaded by the first connection
+pub fn open(
+    ctx: &Context,
+    path: &Path,
+    matching: ReplayMatch,
+) -> Result<SharedReplay, Box<dyn Error>> {
+    let replay = match ctx.replay.get() {
+        Some(replay) => replay,
+        None => {
+            let replay = ReplayTransport::load(path, matching.into())
+                .map_err(|e| format!("can't read {}: {}", path.display(), e))?;
+            ctx.replay.get_or_init(|| Arc::new(Mutex::new(replay)))
+        }
+    };
+    Ok(SharedReplay(replay.clone()))
+}
+
+/// A replay transport of all connections of the session
+pub struct SharedReplay(Arc<Mutex<ReplayTransport>>);
+
+impl SmpTransport for SharedReplay {
+    fn send(&mut self, frame: Vec<u8>) -> Result<(), TransportError> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner()).send(frame)
+    }
+
+    fn send_slice(&mut self, frame: &[u8]) -> Result<(), TransportError> {
+        self.0
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .send_slice(frame)
+    }
+
+    fn receive(&mut self) -> Result<Vec<u8>, TransportError> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner()).receive()
+    }
+}
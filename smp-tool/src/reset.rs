@@ -0,0 +1,152 @@
+use std::error::Error;
+use std::time::Duration;
+
+use mcumgr_smp::os_management::{self, EchoResult, ResetResult};
+use mcumgr_smp::smp::SmpFrame;
+use mcumgr_smp::transport::error::Error as TransportError;
+use serde_json::json;
+use tokio::time::Instant;
+use tracing::debug;
+
+use crate::context::Context;
+use crate::error::CliError;
+use crate::output::OutputFormat;
+use crate::{datetime, open_transport, outln, Cli, Transport, UsedTransport};
+
+/// Zephyr sends the response to a reset request before it resets, a probe sent earlier than
+/// this may still be answered by the old firmware
+const RESET_GRACE: Duration = Duration::from_secs(1);
+/// Time between failed probes, doubled after every attempt up to [MAX_PROBE_DELAY] so a
+/// BLE stack that is still initializing isn't flooded with connection attempts
+const FIRST_PROBE_DELAY: Duration = Duration::from_millis(250);
+const MAX_PROBE_DELAY: Duration = Duration::from_secs(4);
+
+/// Reset the device and wait until it answers an echo again.
+///
+/// The response to the reset may never arrive if the device resets first. Serial and BLE
+/// connections don't survive a reset, so they are reconnected for every probe until one
+/// is answered. Returns the connection to the restarted device and the time it took.
+pub async fn reset_and_wait(
+    ctx: &Context,
+    cli: &Cli,
+    mut transport: UsedTransport,
+    timeout: Duration,
+) -> Result<(UsedTransport, Duration), Box<dyn Error>> {
+    let poll_timeout = Duration::from_millis(cli.timeout_ms);
+    let start = Instant::now();
+    let deadline = start + timeout;
+
+    let ret = transport
+        .transceive_cbor_optional::<_, ResetResult>(
+            &os_management::reset(ctx.sequence.next(), false),
+            ctx.retry.timeouts().reset_grace,
+        )
+        .await;
+    debug!("{:?}", ret);
+    if let Ok(Some(SmpFrame {
+        data: ResetResult::Err { rc },
+        ..
+    })) = ret
+    {
+        Err(CliError::device(rc))?;
+    }
+
+    let reconnect = !matches!(cli.transport, Some(Transport::Udp));
+    let mut transport = if reconnect {
+        let _ = transport.close().await;
+        None
+    } else {
+        Some(transport)
+    };
+
+    // a token unique to this run, so a late answer to another process can't be mistaken
+    let token = format!("reset {}", datetime::now_us());
+    let mut delay = RESET_GRACE;
+    let mut backoff = FIRST_PROBE_DELAY;
+    loop {
+        if Instant::now() + delay >= deadline {
+            Err(std::io::Error::new(
+                std::io::ErrorKind::TimedOut,
+                format!(
+                    "device did not answer within {:.1}s after the reset",
+                    timeout.as_secs_f64()
+                ),
+            ))?;
+        }
+        tokio::time::sleep(delay).await;
+        delay = backoff;
+        backoff = (backoff * 2).min(MAX_PROBE_DELAY);
+
+        if transport.is_none() {
+            match tokio::time::timeout(poll_timeout, open_transport(ctx, cli)).await {
+                Ok(Ok(t)) => transport = Some(t),
+                _ => {
+                    debug!("reconnect failed, retrying");
+                    continue;
+                }
+            }
+        }
+
+        let t = transport.as_mut().expect("transport is connected");
+        let probe_timeout = poll_timeout.min(deadline.saturating_duration_since(Instant::now()));
+        match probe(ctx, t, &token, probe_timeout).await {
+            Ok(()) => {
+                let transport = transport.expect("transport is connected");
+                return Ok((transport, start.elapsed()));
+            }
+            Err(e) => {
+                debug!("device not responding yet: {}", e);
+                if reconnect {
+                    transport = None;
+                }
+            }
+        }
+    }
+}
+
+/// Send an echo and wait for its answer. Other frames, e.g. a late response to the reset,
+/// are skipped until the timeout expires
+async fn probe(
+    ctx: &Context,
+    transport: &mut UsedTransport,
+    token: &str,
+    timeout: Duration,
+) -> Result<(), TransportError> {
+    let request = os_management::echo(ctx.sequence.next(), token.to_string());
+    transport.send_cbor(&request).await?;
+
+    let deadline = Instant::now() + timeout;
+    loop {
+        let ret = transport
+            .receive_cbor_until::<EchoResult>(request.sequence, deadline)
+            .await;
+        match ret {
+            // an error rc still shows the device is up and running the SMP server
+            Ok(SmpFrame {
+                data: EchoResult::Err { .. },
+                ..
+            }) => return Ok(()),
+            Ok(SmpFrame {
+                data: EchoResult::Ok { r },
+                ..
+            }) if r == token => return Ok(()),
+            Ok(frame) => debug!("skipping unexpected echo response {:?}", frame.data),
+            Err(e @ TransportError::Io(_)) => return Err(e),
+            Err(e) if Instant::now() < deadline => debug!("skipping frame: {}", e),
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Print how long the device was unreachable
+pub fn print_downtime(ctx: &Context, downtime: Duration, format: OutputFormat) {
+    match format {
+        OutputFormat::Text => outln!(ctx, "device back after {:.1}s", downtime.as_secs_f64()),
+        OutputFormat::Json => outln!(
+            ctx,
+            "{}",
+            serde_json::to_string_pretty(&json!({ "downtime_seconds": downtime.as_secs_f64() }))
+                .expect("serializing to string can't fail")
+        ),
+    }
+}
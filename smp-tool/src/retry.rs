@@ -0,0 +1,109 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use mcumgr_smp::smp::SmpFrame;
+use mcumgr_smp::transport::error::Error;
+use mcumgr_smp::transport::retry::{self as policy, Idempotency, RetryPolicy};
+use mcumgr_smp::transport::timeouts::Timeouts;
+use tracing::debug;
+
+use crate::{frame, UsedTransport};
+
+/// The retry policy and the deadlines of the requests of a session
+#[derive(Debug, Default)]
+pub struct Retry {
+    policy: RetryPolicy,
+    timeouts: Timeouts,
+    /// Requests repeated by [Retry::transceive_cbor] so far
+    repeated: AtomicU64,
+}
+
+impl Retry {
+    /// Repeat requests according to `policy` and wait for responses as long as `timeouts`
+    /// allows
+    pub fn new(policy: RetryPolicy, timeouts: Timeouts) -> Self {
+        Self {
+            policy,
+            timeouts,
+            repeated: AtomicU64::new(0),
+        }
+    }
+
+    /// The deadlines of requests
+    pub fn timeouts(&self) -> Timeouts {
+        self.timeouts
+    }
+
+    /// Whether requests are repeated at all
+    pub fn enabled(&self) -> bool {
+        self.policy.retries > 0
+    }
+
+    /// The number of requests repeated so far in this session
+    pub fn repeated(&self) -> u64 {
+        self.repeated.load(Ordering::Relaxed)
+    }
+
+    /// Send a request and repeat it as long as the retry policy allows.
+    ///
+    /// The request is repeated with the same sequence number, so a late response to an
+    /// earlier attempt is accepted as well. Before each repetition the retry delay is spent
+    /// listening for such a late response, a request that may only be repeated without any
+    /// response is not sent again if one arrives. Each attempt waits at most `timeout` for its
+    /// response.
+    pub async fn transceive_cbor<Req: serde::Serialize, Resp: serde::de::DeserializeOwned>(
+        &self,
+        transport: &mut UsedTransport,
+        request: &SmpFrame<Req>,
+        timeout: Duration,
+    ) -> Result<SmpFrame<Resp>, Error> {
+        let policy = self.policy;
+        let idempotency = Idempotency::of_frame(request);
+
+        let mut ret = transport.transceive_cbor_timeout(request, timeout).await;
+        let mut attempt = 0;
+        while let Err(e) = &ret {
+            if !policy.should_retry(idempotency, attempt, e) {
+                break;
+            }
+            attempt += 1;
+
+            let deadline = tokio::time::Instant::now() + policy.delay;
+            match transport
+                .receive_cbor_until(request.sequence, deadline)
+                .await
+            {
+                Ok(response) => {
+                    eprintln!(
+                        "warning: late response to {} (seq {}), not sending it again",
+                        name(request),
+                        request.sequence
+                    );
+                    return Ok(response);
+                }
+                Err(e) if !policy::is_lost(&e) && idempotency == Idempotency::IfNoResponse => {
+                    debug!("garbled late response: {}", e);
+                    return Err(e);
+                }
+                Err(_) => {}
+            }
+            tokio::time::sleep_until(deadline).await;
+
+            eprintln!(
+                "warning: no response to {} (seq {}), retrying {}/{}",
+                name(request),
+                request.sequence,
+                attempt,
+                policy.retries
+            );
+            self.repeated.fetch_add(1, Ordering::Relaxed);
+            ret = transport.transceive_cbor_timeout(request, timeout).await;
+        }
+
+        ret
+    }
+}
+
+fn name<T>(request: &SmpFrame<T>) -> &'static str {
+    frame::command_name(request.group.into(), request.command).unwrap_or("request")
+}
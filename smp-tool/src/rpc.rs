@@ -0,0 +1,260 @@
+use std::error::Error;
+use std::io::{BufRead, Write};
+
+use clap::CommandFactory;
+use serde_json::{json, Value};
+use tokio::sync::mpsc;
+
+use crate::agent::{self, Request};
+use crate::context::Context;
+use crate::error::{EXIT_DEVICE, EXIT_TIMEOUT, EXIT_TRANSPORT, EXIT_USAGE};
+use crate::output::OutputFormat;
+use crate::{progress, status, Cli, UsedTransport};
+
+pub const SERVE_HELP: &str = "\
+Execute commands sent as JSON-RPC 2.0 requests on stdin, one per line, and write the
+responses to stdout, one per line.
+
+The method is the command with its words joined by dots, e.g. `os.echo`, `app.flash` or
+`setting.read`, `image` is accepted for `app`. `params` are the arguments of the command:
+  an array    the words after the command, e.g. [\"hello\"] for `os echo hello`
+  an object   options by their long name, e.g. {\"chunk_size\": 512, \"test\": true} for
+              `--chunk-size 512 --test`; positional arguments go in `args`
+The result is the output of the command with --format json, or null for commands without
+output. Errors have these codes, with the error as printed by --format json as `data`:
+  -32700  the line isn't JSON          -32600  not a valid request
+  -32601  unknown command              -32602  invalid arguments
+  -32000  the command failed           -32001  the device returned an error, see data.rc
+  -32002  the connection failed        -32003  the device didn't respond in time
+While a request is executed, progress is sent as `progress` notifications whose params
+are the events of --progress json and the `id` of the request.
+
+Requests are executed one at a time over one connection, opened by the first request and
+reopened if it is lost. Global options like the transport apply to all requests. The
+server ends when stdin is closed or Ctrl-C is pressed.";
+
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const COMMAND_FAILED: i64 = -32000;
+const DEVICE_ERROR: i64 = -32001;
+const TRANSPORT_ERROR: i64 = -32002;
+const TIMEOUT: i64 = -32003;
+
+/// Write a message to stdout as one line
+fn send(message: &Value) {
+    let mut stdout = std::io::stdout().lock();
+    let _ = writeln!(stdout, "{}", message);
+    let _ = stdout.flush();
+}
+
+/// Send a notification about the request being executed
+pub fn notify(ctx: &Context, method: &str, mut params: Value) {
+    if let (Some(params), Some(id)) = (
+        params.as_object_mut(),
+        ctx.request.lock().expect("request lock poisoned").clone(),
+    ) {
+        params.insert("id".to_string(), id);
+    }
+    send(&json!({"jsonrpc": "2.0", "method": method, "params": params}));
+}
+
+fn error(id: Value, code: i64, message: impl Into<String>, data: Option<Value>) -> Value {
+    let mut error = json!({"code": code, "message": message.into()});
+    if let Some(data) = data {
+        error["data"] = data;
+    }
+    json!({"jsonrpc": "2.0", "id": id, "error": error})
+}
+
+/// The words of the command of a method, `None` if there is no such command
+fn method_words(method: &str) -> Option<Vec<String>> {
+    let mut words: Vec<String> = method.split('.').map(str::to_string).collect();
+    if words[0] == "image" {
+        words[0] = "app".to_string();
+    }
+
+    let mut command = Cli::command();
+    for word in &words {
+        command = command.find_subcommand(word)?.clone();
+    }
+    // only complete commands, not groups like `os`
+    let leaf = command.get_subcommands().next().is_none();
+    leaf.then_some(words)
+}
+
+/// The words of the arguments in `params`
+fn param_words(params: Option<&Value>) -> Result<Vec<String>, String> {
+    let word = |value: &Value| match value {
+        Value::String(s) => Ok(s.clone()),
+        Value::Number(n) => Ok(n.to_string()),
+        Value::Bool(b) => Ok(b.to_string()),
+        _ => Err(format!("{} isn't a string, number or boolean", value)),
+    };
+
+    match params {
+        None | Some(Value::Null) => Ok(Vec::new()),
+        Some(Value::Array(values)) => values.iter().map(word).collect(),
+        Some(Value::Object(options)) => {
+            let mut words = Vec::new();
+            let mut positional = Vec::new();
+            for (name, value) in options {
+                if name == "args" {
+                    let Value::Array(args) = value else {
+                        return Err("`args` has to be an array".to_string());
+                    };
+                    positional.push("--".to_string());
+                    for arg in args {
+                        positional.push(word(arg)?);
+                    }
+                    continue;
+                }
+                let flag = format!("--{}", name.replace('_', "-"));
+                match value {
+                    Value::Bool(true) => words.push(flag),
+                    Value::Bool(false) | Value::Null => {}
+                    Value::Array(values) => {
+                        for value in values {
+                            words.push(flag.clone());
+                            words.push(word(value)?);
+                        }
+                    }
+                    value => {
+                        words.push(flag);
+                        words.push(word(value)?);
+                    }
+                }
+            }
+            // positional arguments after `--` have to come last
+            words.extend(positional);
+            Ok(words)
+        }
+        Some(_) => Err("params have to be an array or an object".to_string()),
+    }
+}
+
+/// Handle one line of input, the response is `None` for notifications
+async fn handle_line(
+    ctx: &Context,
+    cli: &Cli,
+    connection: &mut Option<UsedTransport>,
+    line: &str,
+) -> Option<Value> {
+    let message: Value = match serde_json::from_str(line) {
+        Ok(message) => message,
+        Err(e) => return Some(error(Value::Null, PARSE_ERROR, e.to_string(), None)),
+    };
+    let id = message.get("id").cloned();
+    let reply_id = id.clone().unwrap_or(Value::Null);
+
+    let (Some("2.0"), Some(method)) = (
+        message.get("jsonrpc").and_then(Value::as_str),
+        message.get("method").and_then(Value::as_str),
+    ) else {
+        return Some(error(
+            reply_id,
+            INVALID_REQUEST,
+            "expected a JSON-RPC 2.0 request with a method",
+            None,
+        ));
+    };
+    let Some(mut words) = method_words(method) else {
+        let response = error(
+            reply_id,
+            METHOD_NOT_FOUND,
+            format!("unknown method `{}`", method),
+            None,
+        );
+        return id.map(|_| response);
+    };
+    match param_words(message.get("params")) {
+        Ok(params) => words.extend(params),
+        Err(e) => return id.map(|_| error(reply_id, INVALID_PARAMS, e, None)),
+    }
+
+    *ctx.request.lock().expect("request lock poisoned") = id.clone();
+    let request = Request {
+        id: None,
+        args: words,
+        format: Some(OutputFormat::Json),
+        cwd: None,
+    };
+    let response = agent::handle(ctx, cli, connection, request, "server").await;
+    *ctx.request.lock().expect("request lock poisoned") = None;
+
+    let id = id?;
+    let Some(mut data) = response.error else {
+        let result = match response.result {
+            Some(result) => result,
+            None if response.stdout.trim().is_empty() => Value::Null,
+            None => Value::String(response.stdout),
+        };
+        return Some(json!({"jsonrpc": "2.0", "id": id, "result": result}));
+    };
+
+    let code = match response.exit_code {
+        EXIT_USAGE => INVALID_PARAMS,
+        EXIT_DEVICE => DEVICE_ERROR,
+        EXIT_TRANSPORT => TRANSPORT_ERROR,
+        EXIT_TIMEOUT => TIMEOUT,
+        _ => COMMAND_FAILED,
+    };
+    let message = data["error"]
+        .as_str()
+        .unwrap_or("command failed")
+        .to_string();
+    if !response.stdout.is_empty() {
+        data["stdout"] = response.stdout.into();
+    }
+    if response.connection_lost {
+        data["connection_lost"] = true.into();
+    }
+    Some(error(id, code, message, Some(data)))
+}
+
+/// Execute the requests on stdin until it is closed or Ctrl-C is pressed
+pub async fn serve_stdio(ctx: &mut Context, cli: &Cli) -> Result<(), Box<dyn Error>> {
+    progress::init_notifications(ctx);
+    let ctx = &*ctx;
+    status!(ctx, "serving JSON-RPC on stdin, see `serve --help`");
+
+    let (tx, mut lines) = mpsc::unbounded_channel::<String>();
+    std::thread::spawn(move || {
+        for line in std::io::stdin().lock().lines() {
+            let Ok(line) = line else {
+                return;
+            };
+            if tx.send(line).is_err() {
+                return;
+            }
+        }
+    });
+
+    let ctrl_c = tokio::signal::ctrl_c();
+    tokio::pin!(ctrl_c);
+
+    let mut connection = None;
+    loop {
+        let line = tokio::select! {
+            _ = &mut ctrl_c => break,
+            line = lines.recv() => match line {
+                Some(line) => line,
+                None => break,
+            },
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+        if let Some(response) = handle_line(ctx, cli, &mut connection, &line).await {
+            send(&response);
+        }
+    }
+
+    if let Some(transport) = &mut connection {
+        if let Err(e) = transport.close().await {
+            eprintln!("warning: closing the connection failed: {}", e);
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,268 @@
+use std::error::Error;
+use std::path::Path;
+
+use clap::Parser;
+use serde::Serialize;
+
+use crate::context::Context;
+use crate::error::{self, CliError};
+use crate::output::OutputFormat;
+use crate::{execute, status, transcript, Cli, Commands};
+
+/// A single line of a script, parsed like the command line without the global options
+#[derive(Parser, Debug)]
+#[command(no_binary_name = true)]
+struct ScriptLine {
+    #[command(subcommand)]
+    command: Commands,
+}
+
+/// A parsed command of a script
+pub struct Step {
+    /// The command as written in the script
+    pub text: String,
+    pub words: Vec<String>,
+    pub(crate) command: Commands,
+}
+
+/// Split a script into the words of each command.
+///
+/// Commands are separated by newlines or `;`. Words can be quoted with `'` or `"`,
+/// a backslash escapes the next character outside of single quotes and `#` at the
+/// start of a word comments out the rest of the line.
+pub fn split_commands(text: &str) -> Result<Vec<Vec<String>>, String> {
+    let mut commands = Vec::new();
+    let mut words = Vec::new();
+    let mut word: Option<String> = None;
+    let mut chars = text.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\n' | ';' => {
+                words.extend(word.take());
+                if !words.is_empty() {
+                    commands.push(std::mem::take(&mut words));
+                }
+            }
+            '#' if word.is_none() => {
+                for c in chars.by_ref() {
+                    if c == '\n' {
+                        break;
+                    }
+                }
+                words.extend(word.take());
+                if !words.is_empty() {
+                    commands.push(std::mem::take(&mut words));
+                }
+            }
+            c if c.is_whitespace() => words.extend(word.take()),
+            '\'' => {
+                let word = word.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some(c) => word.push(c),
+                        None => return Err("unterminated ' quote".to_string()),
+                    }
+                }
+            }
+            '"' => {
+                let word = word.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some(c) => word.push(c),
+                            None => return Err("unterminated \" quote".to_string()),
+                        },
+                        Some(c) => word.push(c),
+                        None => return Err("unterminated \" quote".to_string()),
+                    }
+                }
+            }
+            '\\' => match chars.next() {
+                Some(c) => word.get_or_insert_with(String::new).push(c),
+                None => return Err("backslash at the end of the input".to_string()),
+            },
+            c => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+
+    words.extend(word.take());
+    if !words.is_empty() {
+        commands.push(words);
+    }
+
+    Ok(commands)
+}
+
+/// Parse one command, without the global options.
+///
+/// Only commands that talk to the device over an existing connection are accepted, `usage`
+/// tells where the others can't be used, e.g. `in a script`.
+pub(crate) fn parse_command(words: &[String], usage: &str) -> Result<Commands, CliError> {
+    let text = words.join(" ");
+    let line = ScriptLine::try_parse_from(words)
+        .map_err(|e| CliError::Usage(format!("invalid command `{}`: {}", text, e)))?;
+
+    if matches!(
+        line.command,
+        Commands::Run { .. }
+            | Commands::Agent { .. }
+            | Commands::Serve { .. }
+            | Commands::Proxy { .. }
+            | Commands::Decode { .. }
+            | Commands::Encode { .. }
+            | Commands::Profiles(_)
+            | Commands::Udp(_)
+    ) {
+        Err(CliError::Usage(format!(
+            "`{}` can't be used {}, only commands that talk to the device",
+            text, usage
+        )))?;
+    }
+
+    Ok(line.command)
+}
+
+/// Parse all commands of a script, so syntax errors are found before anything is sent
+pub fn parse_steps(text: &str) -> Result<Vec<Step>, Box<dyn Error>> {
+    let commands = split_commands(text).map_err(CliError::Usage)?;
+
+    let mut steps = Vec::with_capacity(commands.len());
+    for words in commands {
+        let command = parse_command(&words, "in a script")?;
+        steps.push(Step {
+            text: words.join(" "),
+            words,
+            command,
+        });
+    }
+
+    Ok(steps)
+}
+
+/// Read the commands from a script file or the `--commands` option
+pub fn load_steps(
+    script: Option<&Path>,
+    commands: Option<&str>,
+) -> Result<Vec<Step>, Box<dyn Error>> {
+    let text = match (script, commands) {
+        (_, Some(commands)) => commands.to_string(),
+        (Some(script), None) => std::fs::read_to_string(script)
+            .map_err(|e| format!("can't read {}: {}", script.display(), e))?,
+        (None, None) => unreachable!("clap requires a script or --commands"),
+    };
+
+    parse_steps(&text)
+}
+
+/// Outcome of a script step
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "lowercase")]
+enum Status {
+    Ok,
+    Failed,
+    Skipped,
+}
+
+#[derive(Serialize, Debug)]
+struct StepResult {
+    command: String,
+    status: Status,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// Execute all commands of a script over one connection
+pub async fn run(
+    ctx: &Context,
+    cli: &Cli,
+    script: Option<&Path>,
+    commands: Option<&str>,
+    continue_on_error: bool,
+) -> Result<(), Box<dyn Error>> {
+    let steps = load_steps(script, commands)?;
+    let count = steps.len();
+
+    let mut connection = None;
+    let mut results = Vec::with_capacity(count);
+    let mut first_error = None;
+
+    for (i, step) in steps.into_iter().enumerate() {
+        if first_error.is_some() && !continue_on_error {
+            results.push(StepResult {
+                command: step.text,
+                status: Status::Skipped,
+                error: None,
+            });
+            continue;
+        }
+
+        if cli.format == OutputFormat::Text {
+            status!(ctx, "[{}/{}] {}", i + 1, count, step.text);
+        }
+
+        transcript::command(ctx, "script", &step.words, Some(&step.command));
+        let step_cli = Cli {
+            command: step.command,
+            ..cli.clone()
+        };
+        let ret = execute(ctx, step_cli, &mut connection).await;
+        transcript::outcome(ctx, ret.as_ref().err().map(|e| e.as_ref()));
+        match ret {
+            Ok(()) => results.push(StepResult {
+                command: step.text,
+                status: Status::Ok,
+                error: None,
+            }),
+            Err(e) => {
+                if cli.format == OutputFormat::Text {
+                    eprintln!("error: {}", e);
+                    if let Some(hint) = error::hint(e.as_ref()) {
+                        eprintln!("hint: {}", hint);
+                    }
+                }
+                results.push(StepResult {
+                    command: step.text.clone(),
+                    status: Status::Failed,
+                    error: Some(e.to_string()),
+                });
+                if first_error.is_none() {
+                    first_error = Some(CliError::context(e, |e| {
+                        format!("step {} `{}` failed: {}", i + 1, step.text, e)
+                    }));
+                }
+            }
+        }
+    }
+
+    if let Some(transport) = &mut connection {
+        if let Err(e) = transport.close().await {
+            eprintln!("warning: closing the connection failed: {}", e);
+        }
+    }
+
+    match cli.format {
+        OutputFormat::Text => {
+            println!();
+            for (i, result) in results.iter().enumerate() {
+                let status = match result.status {
+                    Status::Ok => "ok",
+                    Status::Failed => "failed",
+                    Status::Skipped => "skipped",
+                };
+                println!("{:>3}  {:<8} {}", i + 1, status, result.command);
+            }
+        }
+        OutputFormat::Json => println!(
+            "{}",
+            serde_json::to_string_pretty(&results).expect("serializing to string can't fail")
+        ),
+    }
+
+    match first_error {
+        Some(e) => Err(e.into()),
+        None => Ok(()),
+    }
+}
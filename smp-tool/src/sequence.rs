@@ -0,0 +1,29 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::atomic::{AtomicU8, Ordering};
+
+/// The sequence numbers of the requests of a session
+#[derive(Debug)]
+pub struct Sequence(AtomicU8);
+
+impl Sequence {
+    /// Start at `start`, a random number if it is `None`.
+    ///
+    /// Starting at a random number keeps a stale response left over from an earlier
+    /// invocation from being mistaken for the answer to the first request.
+    pub fn new(start: Option<u8>) -> Self {
+        let start = start.unwrap_or_else(|| RandomState::new().build_hasher().finish() as u8);
+        Self(AtomicU8::new(start))
+    }
+
+    /// The sequence number for the next request, wrapping around after 255
+    pub fn next(&self) -> u8 {
+        self.0.fetch_add(1, Ordering::Relaxed)
+    }
+}
+
+impl Default for Sequence {
+    fn default() -> Self {
+        Self::new(None)
+    }
+}
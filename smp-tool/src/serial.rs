@@ -0,0 +1,117 @@
+use std::fmt::{Display, Formatter};
+
+use clap::ValueEnum;
+use mcumgr_smp::transport::serial::{DataBits, FlowControl, Parity, SerialSettings, StopBits};
+use serde::{Deserialize, Serialize};
+
+/// Data bits, parity and stop bits of `--serial-config`
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(try_from = "String")]
+pub struct LineConfig {
+    pub data_bits: DataBits,
+    pub parity: Parity,
+    pub stop_bits: StopBits,
+}
+
+impl Display for LineConfig {
+    /// The short form accepted by [parse_line_config]
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let data_bits = match self.data_bits {
+            DataBits::Five => 5,
+            DataBits::Six => 6,
+            DataBits::Seven => 7,
+            DataBits::Eight => 8,
+        };
+        let parity = match self.parity {
+            Parity::None => 'N',
+            Parity::Even => 'E',
+            Parity::Odd => 'O',
+        };
+        let stop_bits = match self.stop_bits {
+            StopBits::One => 1,
+            StopBits::Two => 2,
+        };
+        write!(f, "{}{}{}", data_bits, parity, stop_bits)
+    }
+}
+
+impl LineConfig {
+    /// The settings to open the port with
+    pub fn settings(self, baud_rate: u32, flow: Flow) -> SerialSettings {
+        SerialSettings {
+            baud_rate,
+            data_bits: self.data_bits,
+            parity: self.parity,
+            stop_bits: self.stop_bits,
+            flow_control: flow.into(),
+        }
+    }
+}
+
+impl TryFrom<String> for LineConfig {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        parse_line_config(&s)
+    }
+}
+
+/// Parse the usual short form of the line settings, e.g. `8N1` or `7E2`
+pub fn parse_line_config(s: &str) -> Result<LineConfig, String> {
+    let err = || {
+        format!(
+            "invalid serial config {}, expected e.g. 8N1: data bits 5-8, parity N, E or O, stop bits 1 or 2",
+            s
+        )
+    };
+
+    let &[data_bits, parity, stop_bits] = s.as_bytes() else {
+        return Err(err());
+    };
+    let data_bits = match data_bits {
+        b'5' => DataBits::Five,
+        b'6' => DataBits::Six,
+        b'7' => DataBits::Seven,
+        b'8' => DataBits::Eight,
+        _ => return Err(err()),
+    };
+    let parity = match parity.to_ascii_uppercase() {
+        b'N' => Parity::None,
+        b'E' => Parity::Even,
+        b'O' => Parity::Odd,
+        _ => return Err(err()),
+    };
+    let stop_bits = match stop_bits {
+        b'1' => StopBits::One,
+        b'2' => StopBits::Two,
+        _ => return Err(err()),
+    };
+
+    Ok(LineConfig {
+        data_bits,
+        parity,
+        stop_bits,
+    })
+}
+
+/// Flow control accepted on the command line
+#[derive(ValueEnum, Serialize, Deserialize, Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Flow {
+    #[default]
+    None,
+    /// RTS/CTS
+    Hardware,
+    /// XON/XOFF
+    Software,
+}
+
+impl From<Flow> for FlowControl {
+    fn from(flow: Flow) -> Self {
+        match flow {
+            Flow::None => FlowControl::None,
+            Flow::Hardware => FlowControl::Hardware,
+            Flow::Software => FlowControl::Software,
+        }
+    }
+}
@@ -0,0 +1,601 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::path::Path;
+
+use clap::ValueEnum;
+use mcumgr_smp::{
+    setting_management::{
+        self, Endian, IntWidth, ReadSettingResult, SaveSettingResult, SettingValue,
+        WriteSettingResult,
+    },
+    smp::SmpFrame,
+    ReturnCode,
+};
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+
+use crate::context::Context;
+use crate::error::{self, CliError};
+use crate::output::OutputFormat;
+use crate::{image, outln, status, UsedTransport};
+
+/// Printed below a failed read of a setting the device doesn't know
+const NO_SETTING_HINT: &str = "setting does not exist";
+
+/// Printed below a setting name the device would reject
+const NAME_CHECK_HINT: &str = "pass --no-name-check to send the name as it is";
+
+/// Fail with what is wrong with a setting name before it is sent, instead of the bare error
+/// code the device answers with
+pub fn check_name(ctx: &Context, name: &str) -> Result<(), CliError> {
+    let Some(rules) = &ctx.name_rules else {
+        return Ok(());
+    };
+    rules.validate(name).map_err(|e| CliError::Hint {
+        hint: NAME_CHECK_HINT,
+        source: Box::new(CliError::Usage(format!(
+            "invalid setting name {:?}: {}",
+            name, e
+        ))),
+    })
+}
+
+/// The error of a failed setting read, with a hint if the setting doesn't exist
+pub fn read_error(rc: i32) -> CliError {
+    CliError::device(rc).hint_on(ReturnCode::NoEntry, NO_SETTING_HINT)
+}
+
+/// Integer widths accepted on the command line
+#[derive(ValueEnum, Copy, Clone, Debug, Default)]
+pub enum Width {
+    #[value(name = "8")]
+    W8,
+    #[value(name = "16")]
+    W16,
+    #[default]
+    #[value(name = "32")]
+    W32,
+    #[value(name = "64")]
+    W64,
+}
+
+impl From<Width> for IntWidth {
+    fn from(width: Width) -> Self {
+        match width {
+            Width::W8 => IntWidth::W8,
+            Width::W16 => IntWidth::W16,
+            Width::W32 => IntWidth::W32,
+            Width::W64 => IntWidth::W64,
+        }
+    }
+}
+
+/// Byte orders accepted on the command line
+#[derive(ValueEnum, Copy, Clone, Debug, Default)]
+pub enum ByteOrder {
+    #[default]
+    Little,
+    Big,
+}
+
+impl From<ByteOrder> for Endian {
+    fn from(order: ByteOrder) -> Self {
+        match order {
+            ByteOrder::Little => Endian::Little,
+            ByteOrder::Big => Endian::Big,
+        }
+    }
+}
+
+/// How `setting read` and `setting read-many` print the values
+#[derive(ValueEnum, Copy, Clone, Debug, Default)]
+pub enum ValueFormat {
+    /// text if the value is printable, otherwise hex with a `hex:` prefix
+    #[default]
+    Auto,
+    /// UTF-8 text, invalid sequences are replaced
+    String,
+    /// bytes as hex
+    Hex,
+    /// little-endian signed integer of 1, 2, 4 or 8 bytes, as written by `setting write-int`
+    Int,
+    /// little-endian unsigned integer of 1, 2, 4 or 8 bytes
+    Uint,
+    /// a single byte, false if it is 0
+    Bool,
+}
+
+impl ValueFormat {
+    /// Format a value read from the device, fails if it has no valid integer width
+    pub fn format(self, val: &[u8]) -> Result<serde_json::Value, String> {
+        let value = SettingValue::from_bytes(val);
+        Ok(match self {
+            ValueFormat::Auto => match EntryValue::from_bytes(val) {
+                EntryValue::String(s) => s.into(),
+                _ => format!("hex:{}", image::hex(val)).into(),
+            },
+            ValueFormat::String => String::from_utf8_lossy(val).into(),
+            ValueFormat::Hex => image::hex(val).into(),
+            ValueFormat::Int => value.as_i64_le().map_err(|e| e.to_string())?.into(),
+            ValueFormat::Uint => value.as_u64_le().map_err(|e| e.to_string())?.into(),
+            ValueFormat::Bool => value.as_bool().map_err(|e| e.to_string())?.into(),
+        })
+    }
+}
+
+/// Parse the value of `setting write-bytes`: `hex:...`, `base64:...` or `@file`
+pub fn parse_bytes_arg(value: &str) -> Result<SettingValue, Box<dyn Error>> {
+    if let Some(path) = value.strip_prefix('@') {
+        let bytes = std::fs::read(path).map_err(|e| format!("can't read {}: {}", path, e))?;
+        return Ok(SettingValue::from_bytes(bytes));
+    }
+
+    Ok(SettingValue::parse_bytes(value)
+        .map_err(|e| CliError::Usage(format!("invalid value {}: {}", value, e)))?)
+}
+
+/// The value of `setting write-int`, checked to fit the width
+pub fn int_arg(
+    value: i128,
+    width: Width,
+    order: ByteOrder,
+) -> Result<SettingValue, Box<dyn Error>> {
+    Ok(SettingValue::int(value, width.into(), order.into())
+        .map_err(|e| CliError::Usage(e.to_string()))?)
+}
+
+/// The value of a setting in an export file, tagged with its encoding
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum EntryValue {
+    /// UTF-8 text without control characters
+    String(String),
+    /// arbitrary bytes as hex
+    Hex(String),
+    /// a 32 bit little-endian integer, as written by `setting write-int`
+    Int(i64),
+}
+
+impl EntryValue {
+    /// Choose the encoding for a value read from the device.
+    ///
+    /// Only text that survives the round trip unchanged is exported as a string,
+    /// everything else, including NUL bytes, as hex.
+    pub fn from_bytes(val: &[u8]) -> Self {
+        match std::str::from_utf8(val) {
+            Ok(s) if !s.chars().any(char::is_control) => EntryValue::String(s.to_string()),
+            _ => EntryValue::Hex(image::hex(val)),
+        }
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, String> {
+        match self {
+            EntryValue::String(s) => Ok(s.as_bytes().to_vec()),
+            EntryValue::Hex(hex) => SettingValue::from_hex(hex)
+                .map(|val| val.to_bytes())
+                .map_err(|e| e.to_string()),
+            EntryValue::Int(i) => SettingValue::int(*i as i128, IntWidth::W32, Endian::Little)
+                .map(|val| val.to_bytes())
+                .map_err(|e| e.to_string()),
+        }
+    }
+}
+
+/// Setting names mapped to their values
+pub type Document = BTreeMap<String, EntryValue>;
+
+fn is_json(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext == "json")
+}
+
+/// Read an export file, JSON if the file name ends in `.json` and TOML otherwise
+pub fn read_document(path: &Path) -> Result<Document, Box<dyn Error>> {
+    let content = std::fs::read_to_string(path)
+        .map_err(|e| format!("can't read {}: {}", path.display(), e))?;
+
+    let document = if is_json(path) {
+        serde_json::from_str(&content).map_err(|e| format!("invalid {}: {}", path.display(), e))?
+    } else {
+        toml::from_str(&content).map_err(|e| format!("invalid {}: {}", path.display(), e))?
+    };
+    Ok(document)
+}
+
+/// Write an export file, JSON if the file name ends in `.json` and TOML otherwise
+pub fn write_document(path: &Path, document: &Document) -> Result<(), Box<dyn Error>> {
+    let content = if is_json(path) {
+        serde_json::to_string_pretty(document)? + "\n"
+    } else {
+        toml::to_string(document)?
+    };
+
+    std::fs::write(path, content).map_err(|e| format!("can't write {}: {}", path.display(), e))?;
+    Ok(())
+}
+
+/// Collect the setting names from the command line and a file with one name per line.
+///
+/// Empty lines and lines starting with `#` are ignored.
+pub fn read_names(
+    ctx: &Context,
+    names: &[String],
+    names_file: Option<&Path>,
+) -> Result<Vec<String>, Box<dyn Error>> {
+    let mut all = names.to_vec();
+
+    if let Some(names_file) = names_file {
+        let content = std::fs::read_to_string(names_file)
+            .map_err(|e| format!("can't read {}: {}", names_file.display(), e))?;
+        all.extend(
+            content
+                .lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                .map(str::to_string),
+        );
+    }
+
+    if all.is_empty() {
+        Err(CliError::Usage(
+            "no setting names given, pass them as arguments or with --names-file".to_string(),
+        ))?;
+    }
+    for name in &all {
+        check_name(ctx, name)?;
+    }
+
+    Ok(all)
+}
+
+/// Read every setting and write them to an export file
+pub async fn export(
+    ctx: &Context,
+    transport: &mut UsedTransport,
+    names: &[String],
+    path: &Path,
+) -> Result<(), Box<dyn Error>> {
+    let mut document = Document::new();
+
+    for name in names {
+        let ret: SmpFrame<ReadSettingResult> = transport
+            .transceive_cbor(&setting_management::read_setting(
+                ctx.sequence.next(),
+                name.clone(),
+            ))
+            .await?;
+        debug!("{:?}", ret);
+
+        match ret.data {
+            ReadSettingResult::Ok { val } => {
+                document.insert(name.clone(), EntryValue::from_bytes(&val));
+            }
+            ReadSettingResult::Err { rc } => {
+                Err(CliError::context(Box::new(read_error(rc)), |e| {
+                    format!("reading {} failed: {}", name, e)
+                }))?;
+            }
+        }
+    }
+
+    write_document(path, &document)?;
+    status!(
+        ctx,
+        "exported {} settings to {}",
+        document.len(),
+        path.display()
+    );
+
+    Ok(())
+}
+
+/// Outcome of reading a single setting, the value or why it failed
+#[derive(Serialize, Debug)]
+struct ReadResult {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    value: Option<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rc: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// Read several settings over one connection and print them as `name=value` lines,
+/// or one JSON object keyed by name.
+///
+/// Failing settings don't stop the others, they are summarized on stderr and make
+/// the command fail at the end.
+pub async fn read_many(
+    ctx: &Context,
+    transport: &mut UsedTransport,
+    names: &[String],
+    value_format: ValueFormat,
+    format: OutputFormat,
+) -> Result<(), Box<dyn Error>> {
+    let mut results = Vec::with_capacity(names.len());
+    for name in names {
+        let ret: Result<SmpFrame<ReadSettingResult>, _> = transport
+            .transceive_cbor(&setting_management::read_setting(
+                ctx.sequence.next(),
+                name.clone(),
+            ))
+            .await;
+        debug!("{:?}", ret);
+
+        let result = match ret {
+            Ok(SmpFrame {
+                data: ReadSettingResult::Ok { val },
+                ..
+            }) => match value_format.format(&val) {
+                Ok(value) => ReadResult {
+                    value: Some(value),
+                    rc: None,
+                    error: None,
+                },
+                Err(e) => ReadResult {
+                    value: None,
+                    rc: None,
+                    error: Some(e),
+                },
+            },
+            Ok(SmpFrame {
+                data: ReadSettingResult::Err { rc },
+                ..
+            }) => ReadResult {
+                value: None,
+                rc: Some(rc),
+                error: Some({
+                    let err = read_error(rc);
+                    match error::hint(&err) {
+                        Some(hint) => format!("{} ({})", err, hint),
+                        None => err.to_string(),
+                    }
+                }),
+            },
+            Err(e) => ReadResult {
+                value: None,
+                rc: None,
+                error: Some(e.to_string()),
+            },
+        };
+        results.push((name, result));
+    }
+
+    print_results(ctx, &results, format);
+
+    let failed: Vec<_> = results
+        .iter()
+        .filter_map(|(name, result)| Some((name, result.error.as_ref()?)))
+        .collect();
+    if !failed.is_empty() {
+        for (name, error) in &failed {
+            eprintln!("{}: {}", name, error);
+        }
+        Err(format!(
+            "{} of {} settings failed",
+            failed.len(),
+            results.len()
+        ))?;
+    }
+
+    Ok(())
+}
+
+/// Print the values as `name=value` lines or one JSON object keyed by name
+fn print_results<N: AsRef<str>>(ctx: &Context, results: &[(N, ReadResult)], format: OutputFormat) {
+    match format {
+        OutputFormat::Text => {
+            for (name, result) in results {
+                match &result.value {
+                    // strings without quotes, numbers as they are
+                    Some(serde_json::Value::String(s)) => outln!(ctx, "{}={}", name.as_ref(), s),
+                    Some(value) => outln!(ctx, "{}={}", name.as_ref(), value),
+                    None => {}
+                }
+            }
+        }
+        OutputFormat::Json => {
+            let object: BTreeMap<&str, &ReadResult> = results
+                .iter()
+                .map(|(name, result)| (name.as_ref(), result))
+                .collect();
+            outln!(
+                ctx,
+                "{}",
+                serde_json::to_string_pretty(&object).expect("serializing to string can't fail")
+            );
+        }
+    }
+}
+
+/// Print the value of `setting read` like an entry of `setting read-many`
+pub fn print_value(
+    ctx: &Context,
+    name: &str,
+    val: &[u8],
+    value_format: ValueFormat,
+    format: OutputFormat,
+) -> Result<(), Box<dyn Error>> {
+    let value = value_format
+        .format(val)
+        .map_err(|e| format!("{}: {}", name, e))?;
+    let result = ReadResult {
+        value: Some(value),
+        rc: None,
+        error: None,
+    };
+    print_results(ctx, &[(name, result)], format);
+    Ok(())
+}
+
+/// Outcome of importing a single setting
+#[derive(Serialize, Debug)]
+struct ImportResult {
+    name: String,
+    ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// Write every setting of an export file.
+///
+/// Failing entries don't stop the import, they are listed in the summary and make
+/// the command fail at the end.
+pub async fn import(
+    ctx: &Context,
+    transport: &mut UsedTransport,
+    path: &Path,
+    save: bool,
+    format: OutputFormat,
+) -> Result<(), Box<dyn Error>> {
+    let document = read_document(path)?;
+
+    // validate everything before the first write
+    let mut values = Vec::with_capacity(document.len());
+    for (name, value) in &document {
+        check_name(ctx, name)?;
+        let val = value
+            .to_bytes()
+            .map_err(|e| format!("invalid value for {}: {}", name, e))?;
+        values.push((name, val));
+    }
+
+    let mut results = Vec::with_capacity(values.len());
+    for (name, val) in values {
+        let ret: Result<SmpFrame<WriteSettingResult>, _> = transport
+            .transceive_cbor(&setting_management::write_setting(
+                ctx.sequence.next(),
+                name.clone(),
+                val,
+            ))
+            .await;
+        debug!("{:?}", ret);
+
+        let error = match ret {
+            Ok(SmpFrame {
+                data: WriteSettingResult::Ok {},
+                ..
+            }) => None,
+            Ok(SmpFrame {
+                data: WriteSettingResult::Err { rc },
+                ..
+            }) => Some(CliError::device(rc).to_string()),
+            Err(e) => Some(e.to_string()),
+        };
+        results.push(ImportResult {
+            name: name.clone(),
+            ok: error.is_none(),
+            error,
+        });
+    }
+
+    let failed = results.iter().filter(|r| !r.ok).count();
+
+    match format {
+        OutputFormat::Text => {
+            for result in &results {
+                match &result.error {
+                    None => outln!(ctx, "{}: ok", result.name),
+                    Some(e) => outln!(ctx, "{}: failed: {}", result.name, e),
+                }
+            }
+            outln!(
+                ctx,
+                "{} of {} settings written",
+                results.len() - failed,
+                results.len()
+            );
+        }
+        OutputFormat::Json => outln!(
+            ctx,
+            "{}",
+            serde_json::to_string_pretty(&results).expect("serializing to string can't fail")
+        ),
+    }
+
+    // the successful writes are saved even if others failed, they are applied already
+    if save {
+        let ret: SmpFrame<SaveSettingResult> = transport
+            .transceive_cbor(&setting_management::save_setting(ctx.sequence.next()))
+            .await?;
+        debug!("{:?}", ret);
+
+        if let SaveSettingResult::Err { rc } = ret.data {
+            Err(CliError::context(Box::new(CliError::device(rc)), |e| {
+                format!("saving the settings failed: {}", e)
+            }))?;
+        }
+    }
+
+    if failed > 0 {
+        Err(format!("{} of {} settings failed", failed, results.len()))?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Values that are no plain text, and text next to them
+    fn values() -> Vec<Vec<u8>> {
+        vec![
+            b"hello world".to_vec(),
+            vec![0x00],
+            vec![0xff, 0xff, 0xff, 0xff],
+            vec![0x00, 0xff, 0x00, 0x7f],
+            b"text\0with nul".to_vec(),
+            b"line\nbreak".to_vec(),
+            "grüße".as_bytes().to_vec(),
+            vec![0xc3, 0x28],
+            Vec::new(),
+        ]
+    }
+
+    #[test]
+    fn only_printable_text_is_exported_as_string() {
+        let entries: Vec<_> = values().iter().map(|v| EntryValue::from_bytes(v)).collect();
+        assert_eq!(entries[0], EntryValue::String("hello world".to_string()));
+        assert_eq!(entries[1], EntryValue::Hex("00".to_string()));
+        assert_eq!(entries[2], EntryValue::Hex("ffffffff".to_string()));
+        assert_eq!(entries[6], EntryValue::String("grüße".to_string()));
+        assert_eq!(entries[8], EntryValue::String(String::new()));
+        for entry in &entries[3..6] {
+            assert!(matches!(entry, EntryValue::Hex(_)), "{:?}", entry);
+        }
+        assert!(matches!(entries[7], EntryValue::Hex(_)));
+    }
+
+    #[test]
+    fn export_files_round_trip() {
+        let document: Document = values()
+            .iter()
+            .enumerate()
+            .map(|(i, val)| (format!("app/value{}", i), EntryValue::from_bytes(val)))
+            .collect();
+
+        let dir = std::env::temp_dir().join(format!("smp-tool-settings-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        for file in ["export.toml", "export.json"] {
+            let path = dir.join(file);
+            write_document(&path, &document).unwrap();
+            let read = read_document(&path).unwrap();
+            assert_eq!(read, document, "{}", file);
+
+            let bytes: Vec<_> = read.values().map(|e| e.to_bytes().unwrap()).collect();
+            assert_eq!(bytes, values(), "{}", file);
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn integers_are_imported_as_32_bit_little_endian() {
+        assert_eq!(
+            EntryValue::Int(-2).to_bytes().unwrap(),
+            [0xfe, 0xff, 0xff, 0xff]
+        );
+        assert_eq!(EntryValue::Int(1 << 8).to_bytes().unwrap(), [0, 1, 0, 0]);
+        assert!(EntryValue::Int(1 << 40).to_bytes().is_err());
+        assert!(EntryValue::Hex("0g".to_string()).to_bytes().is_err());
+    }
+}
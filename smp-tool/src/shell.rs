@@ -1,11 +1,19 @@
 // Author: Sascha Zenglein <zenglein@gessler.de>
 // Copyright (c) 2023 Gessler GmbH.
 
+use std::collections::HashMap;
 use std::error::Error;
+use std::io::Write;
+use std::path::PathBuf;
+use std::time::Duration;
 
 use reedline::{
-    default_emacs_keybindings, DefaultPrompt, DefaultPromptSegment, Emacs, Reedline, Signal,
+    default_emacs_keybindings, DefaultPrompt, DefaultPromptSegment, EditCommand, Emacs,
+    ExternalPrinter, FileBackedHistory, History, KeyCode, KeyModifiers, Reedline, ReedlineEvent,
+    Signal,
 };
+use tokio::sync::mpsc;
+use tokio::time::Instant;
 use tracing::debug;
 
 use mcumgr_smp::{
@@ -13,52 +21,768 @@ use mcumgr_smp::{
     smp::SmpFrame,
 };
 
-use crate::UsedTransport;
+use crate::context::Context;
+use crate::error::CliError;
+use crate::{transcript, UsedTransport};
 
-pub async fn shell(transport: &mut UsedTransport) -> Result<(), Box<dyn Error>> {
-    let keybindings = default_emacs_keybindings();
+/// How `shell exec` builds the argv sent to the device
+pub const EXEC_HELP: &str = "\
+Send a shell command via SMP and print its output
+
+The arguments are sent to the device as they are, as the argv of the command. Zephyr joins them \
+with spaces and parses the line again, so `shell exec \"log enable dbg modem\"` and \
+`shell exec log enable dbg modem` run the same command. Put `--` before the command if it \
+starts with `-`, e.g. `shell exec -- -h`.
+
+With -c, the line is split into arguments on the host the way a POSIX shell does, with `'`, \
+`\"` and `\\` quoting. Arguments containing spaces or quotes after that are quoted again for \
+the device shell, so `-c 'log enable dbg \"my module\"'` passes `my module` as one argument.";
+
+/// Documentation of [exit_status]
+pub const EXIT_STATUS_HELP: &str = "\
+Exit status:
+  The status returned by the device shell is passed through:
+  0         the command succeeded
+  1..125    the command returned this value, larger values are clamped to 125
+  -1..-125  the command returned a negative errno like -ENOEXEC (-8), it is negated
+            and clamped to 125, so -8 exits with 8
+  Errors of smp-tool itself use the codes listed in `smp-tool --help`.";
+
+/// Map the return value of a device shell command to a process exit status.
+///
+/// Zephyr shell commands return negative errno values, exit codes above 125
+/// are reserved by POSIX shells.
+pub fn exit_status(ret: i32) -> u8 {
+    ret.unsigned_abs().min(125) as u8
+}
+
+/// Quote arguments for the device shell.
+///
+/// The device joins argv with spaces and splits the line again, so arguments
+/// with whitespace or quotes would be split. These are wrapped in double quotes
+/// with `"` and `\` escaped, which the Zephyr shell parser unescapes again.
+/// Used for the words of `shell exec -c`, plain arguments are sent unchanged.
+pub fn quote_args(args: &[String]) -> Vec<String> {
+    args.iter()
+        .map(|arg| {
+            if !arg.is_empty()
+                && !arg
+                    .chars()
+                    .any(|c| c.is_whitespace() || c == '"' || c == '\'' || c == '\\')
+            {
+                return arg.clone();
+            }
+
+            let mut quoted = String::with_capacity(arg.len() + 2);
+            quoted.push('"');
+            for c in arg.chars() {
+                if c == '"' || c == '\\' {
+                    quoted.push('\\');
+                }
+                quoted.push(c);
+            }
+            quoted.push('"');
+            quoted
+        })
+        .collect()
+}
+
+/// The argv of `shell exec`: the arguments unchanged, or the split and quoted `-c` line
+pub fn exec_argv(cmd: &[String], line: Option<&str>) -> Result<Vec<String>, CliError> {
+    match line {
+        Some(line) => {
+            let argv = split_line(line).map_err(|e| CliError::Usage(format!("-c: {}", e)))?;
+            if argv.is_empty() {
+                return Err(CliError::Usage("-c: the command line is empty".to_string()));
+            }
+            Ok(quote_args(&argv))
+        }
+        None => Ok(cmd.to_vec()),
+    }
+}
+
+/// Split a command line into words like a POSIX shell.
+///
+/// Words are separated by whitespace. Single quotes keep everything up to the next `'`,
+/// in double quotes a backslash only escapes `"`, `\`, `$` and `` ` ``, and outside of quotes
+/// it escapes any character.
+pub fn split_line(line: &str) -> Result<Vec<String>, String> {
+    let mut words = Vec::new();
+    let mut word: Option<String> = None;
+    let mut chars = line.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => words.extend(word.take()),
+            '\'' => {
+                let word = word.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some(c) => word.push(c),
+                        None => return Err("unterminated ' quote".to_string()),
+                    }
+                }
+            }
+            '"' => {
+                let word = word.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.peek() {
+                            Some(&c @ ('"' | '\\' | '$' | '`')) => {
+                                chars.next();
+                                word.push(c);
+                            }
+                            Some(_) => word.push('\\'),
+                            None => return Err("unterminated \" quote".to_string()),
+                        },
+                        Some(c) => word.push(c),
+                        None => return Err("unterminated \" quote".to_string()),
+                    }
+                }
+            }
+            '\\' => match chars.next() {
+                Some(c) => word.get_or_insert_with(String::new).push(c),
+                None => return Err("backslash at the end of the line".to_string()),
+            },
+            c => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+    words.extend(word.take());
+
+    Ok(words)
+}
+
+/// Number of lines kept in the history file
+const HISTORY_SIZE: usize = 1000;
+
+/// `$XDG_DATA_HOME/smp-tool/history`, falling back to `~/.local/share`
+pub fn history_path() -> Option<PathBuf> {
+    let data_dir = match std::env::var_os("XDG_DATA_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(std::env::var_os("HOME")?)
+            .join(".local")
+            .join("share"),
+    };
+    Some(data_dir.join("smp-tool").join("history"))
+}
+
+/// Open the persistent history, or an in-memory one if the file can't be used
+fn open_history() -> Box<dyn History> {
+    let file_history = history_path()
+        .ok_or_else(|| "HOME is not set".to_string())
+        .and_then(|path| {
+            if let Some(dir) = path.parent() {
+                std::fs::create_dir_all(dir).map_err(|e| format!("{}: {}", dir.display(), e))?;
+            }
+            FileBackedHistory::with_file(HISTORY_SIZE, path.clone())
+                .map_err(|e| format!("{}: {}", path.display(), e))
+        });
+
+    match file_history {
+        Ok(history) => Box::new(history),
+        Err(e) => {
+            eprintln!(
+                "warning: can't open shell history, it won't be saved: {}",
+                e
+            );
+            Box::new(FileBackedHistory::new(HISTORY_SIZE).expect("history capacity is valid"))
+        }
+    }
+}
+
+/// Returned by the line editor when Tab is pressed, can't be typed by the user
+const COMPLETE_EVENT: &str = "\0complete";
+
+/// How long responses to cancelled or timed out requests are still printed
+const LATE_RESPONSE_WINDOW: Duration = Duration::from_secs(30);
+
+/// What the line editor hands to the session
+enum Input {
+    Line(String),
+    /// Tab was pressed with the cursor at the byte offset in the buffer
+    Complete {
+        buffer: String,
+        cursor: usize,
+    },
+    CtrlC,
+    CtrlD,
+    Failed(std::io::Error),
+}
+
+/// Answer of the session to an [Input], the line editor reads the next line after it
+enum Reply {
+    Done,
+    Insert(String),
+    /// Completion candidates to list below the line
+    List(Vec<String>),
+    Exit,
+}
+
+/// Read lines until the session replies with [Reply::Exit] or goes away.
+///
+/// The editor runs on its own thread, so responses arriving while the user types are printed
+/// above the prompt by the external printer and the line being edited is redrawn below them.
+/// A terminal resize (SIGWINCH) reaches the editor as an event and repaints the prompt for
+/// the new width.
+fn edit(
+    mut line_editor: Reedline,
+    prompt: DefaultPrompt,
+    inputs: mpsc::UnboundedSender<Input>,
+    replies: std::sync::mpsc::Receiver<Reply>,
+) {
+    loop {
+        // reedline restores the terminal before returning, also on errors
+        let input = match line_editor.read_line(&prompt) {
+            Ok(Signal::Success(buffer)) if buffer == COMPLETE_EVENT => Input::Complete {
+                buffer: line_editor.current_buffer_contents().to_string(),
+                cursor: line_editor.current_insertion_point(),
+            },
+            Ok(Signal::Success(buffer)) => Input::Line(buffer),
+            Ok(Signal::CtrlC) => Input::CtrlC,
+            Ok(Signal::CtrlD) => Input::CtrlD,
+            Err(e) => Input::Failed(e),
+        };
+        if inputs.send(input).is_err() {
+            return;
+        }
+
+        match replies.recv() {
+            Ok(Reply::Done) => {}
+            Ok(Reply::Insert(insert)) => {
+                line_editor.run_edit_commands(&[EditCommand::InsertString(insert)]);
+            }
+            Ok(Reply::List(candidates)) => {
+                println!();
+                for row in columns(&candidates, terminal_width()) {
+                    println!("{}", row);
+                }
+            }
+            Ok(Reply::Exit) | Err(_) => return,
+        }
+    }
+}
+
+/// Run an interactive shell on the device.
+///
+/// `target` describes the connected device and is shown in the prompt.
+/// Ctrl-C cancels the current line or stops waiting for a response, a second
+/// Ctrl-C at the prompt, Ctrl-D or `exit` end the shell and close the transport.
+/// A response that arrives after its request was cancelled or timed out is still
+/// printed, above the prompt if the user is typing.
+///
+/// Tab completes commands and subcommands from the `help` output of the device,
+/// `:refresh` discards the cached completions.
+pub async fn shell(
+    ctx: &Context,
+    transport: &mut UsedTransport,
+    target: &str,
+    timeout: Duration,
+) -> Result<(), Box<dyn Error>> {
+    let mut keybindings = default_emacs_keybindings();
+    // the completions may have to be requested from the device, which needs the transport,
+    // so Tab leaves the line editor and the buffer is completed here
+    keybindings.add_binding(
+        KeyModifiers::NONE,
+        KeyCode::Tab,
+        ReedlineEvent::ExecuteHostCommand(COMPLETE_EVENT.to_string()),
+    );
     let edit_mode = Box::new(Emacs::new(keybindings));
 
     let prompt = DefaultPrompt::new(
-        DefaultPromptSegment::Basic("SMP Shell: ".to_string()),
+        DefaultPromptSegment::Basic(target.to_string()),
         DefaultPromptSegment::Empty,
     );
 
-    let mut line_editor = Reedline::create().with_edit_mode(edit_mode);
+    // pasted text arrives as one buffer, so multiple lines are sent one after the other
+    let printer = ExternalPrinter::default();
+    let line_editor = Reedline::create()
+        .with_edit_mode(edit_mode)
+        .with_history(open_history())
+        .with_external_printer(printer.clone())
+        .use_bracketed_paste(true);
 
-    loop {
-        let sig = line_editor.read_line(&prompt)?;
-
-        match sig {
-            Signal::Success(buffer) => 'succ: {
-                let argv: Vec<_> = buffer.split_whitespace().map(|s| s.to_owned()).collect();
-
-                let ret: Result<SmpFrame<ShellResult>, _> = transport
-                    .transceive_cbor(&shell_management::shell_command(42, argv))
-                    .await;
-                debug!("{:?}", ret);
-
-                let data = match ret {
-                    Ok(smp_frame) => smp_frame.data,
-                    Err(err) => {
-                        println!("transport error: {}", err);
-                        break 'succ;
-                    }
-                };
+    let mut session = Session {
+        transport,
+        sequence: 0,
+        timeout,
+        completions: HashMap::new(),
+        late: Vec::new(),
+        late_until: Instant::now(),
+        printer,
+    };
+    session.subcommands(ctx, &[]).await;
+    let mut interrupted = false;
+
+    let (input_tx, mut inputs) = mpsc::unbounded_channel();
+    let (replies, reply_rx) = std::sync::mpsc::channel();
+    let editor = std::thread::spawn(move || edit(line_editor, prompt, input_tx, reply_rx));
 
-                match data {
-                    ShellResult::Ok { o, ret: _ } => {
-                        println!("{}", o);
+    let ret = loop {
+        let Some(input) = session.next_input(&mut inputs).await else {
+            break Ok(());
+        };
+
+        let reply = match input {
+            Input::Complete { buffer, cursor } => session.complete(ctx, &buffer, cursor).await,
+            Input::Line(buffer) => {
+                interrupted = false;
+                match buffer.trim() {
+                    "exit" => break Ok(()),
+                    ":refresh" => {
+                        session.completions.clear();
+                        let count = session.subcommands(ctx, &[]).await.len();
+                        println!("{} commands available", count);
                     }
-                    ShellResult::Err { rc } => {
-                        eprintln!("SMP Error: rc: {}", rc);
+                    _ => {
+                        for line in buffer.lines() {
+                            if !session.execute_line(ctx, line).await {
+                                // the rest of a pasted block is dropped as well
+                                break;
+                            }
+                        }
                     }
                 }
+                Reply::Done
+            }
+            Input::CtrlD => {
+                println!();
+                break Ok(());
             }
-            Signal::CtrlD | Signal::CtrlC => {
-                println!("\nAborted!");
+            Input::CtrlC if interrupted => {
                 break Ok(());
             }
+            Input::CtrlC => {
+                println!("(press Ctrl-C again, Ctrl-D or type exit to quit)");
+                interrupted = true;
+                Reply::Done
+            }
+            Input::Failed(e) => break Err(e.into()),
+        };
+
+        if replies.send(reply).is_err() {
+            break Ok(());
+        }
+    };
+
+    // the history is saved when the editor is dropped at the end of its thread
+    let _ = replies.send(Reply::Exit);
+    let _ = editor.join();
+
+    std::io::stdout().flush()?;
+    if let Err(e) = session.transport.close().await {
+        eprintln!("warning: closing the connection failed: {}", e);
+    }
+
+    ret
+}
+
+/// Result of a request to the device shell
+enum Outcome {
+    Response(ShellResult),
+    Cancelled,
+    Failed(mcumgr_smp::transport::error::Error),
+}
+
+/// The state of an interactive shell session
+struct Session<'a> {
+    transport: &'a mut UsedTransport,
+    /// Sequence number of the last request, responses to older requests are discarded
+    sequence: u8,
+    /// Upper bound for fetching completions
+    timeout: Duration,
+    /// Subcommands of each command path, an empty path holds the top level commands
+    completions: HashMap<Vec<String>, Vec<String>>,
+    /// Sequence numbers of cancelled or timed out commands whose responses are still printed
+    late: Vec<u8>,
+    /// When to stop waiting for the responses in `late`
+    late_until: Instant,
+    /// Prints above the prompt while the user is typing
+    printer: ExternalPrinter<String>,
+}
+
+impl Session<'_> {
+    /// Send a command and wait for its response, until the user presses Ctrl-C
+    async fn request(&mut self, ctx: &Context, argv: Vec<String>) -> Outcome {
+        self.sequence = ctx.sequence.next();
+        let frame = shell_management::shell_command(self.sequence, argv);
+        if let Err(err) = self.transport.send_cbor(&frame).await {
+            return Outcome::Failed(err);
+        }
+
+        let ctrl_c = tokio::signal::ctrl_c();
+        tokio::pin!(ctrl_c);
+
+        loop {
+            // ctrl_c is polled first so the handler is installed before a blocking receive
+            let ret: Result<SmpFrame<ShellResult>, _> = tokio::select! {
+                biased;
+                _ = &mut ctrl_c => return Outcome::Cancelled,
+                ret = self.transport.receive_cbor() => ret,
+            };
+            debug!("{:?}", ret);
+
+            match ret {
+                Ok(smp_frame) if self.take_late(smp_frame.sequence) => {
+                    for line in late_lines(smp_frame.data) {
+                        println!("{}", line);
+                    }
+                }
+                Ok(smp_frame) if smp_frame.sequence != self.sequence => {
+                    debug!(
+                        "discarding late response with sequence {}",
+                        smp_frame.sequence
+                    );
+                }
+                Ok(smp_frame) => return Outcome::Response(smp_frame.data),
+                Err(err) => return Outcome::Failed(err),
+            }
+
+            // sync transports block in receive, give the runtime a chance to deliver Ctrl-C
+            tokio::task::yield_now().await;
+        }
+    }
+
+    /// Print the response to the last request if it arrives later
+    fn expect_late(&mut self) {
+        self.late.push(self.sequence);
+        self.late_until = Instant::now() + LATE_RESPONSE_WINDOW;
+    }
+
+    /// Whether a response is one of the late ones, which is then no longer waited for
+    fn take_late(&mut self, sequence: u8) -> bool {
+        let index = self.late.iter().position(|&late| late == sequence);
+        index.map(|index| self.late.remove(index)).is_some()
+    }
+
+    /// The next input of the user.
+    ///
+    /// Meanwhile late responses are printed above the prompt. With a sync transport
+    /// waiting for them blocks, so a line entered meanwhile is executed once the receive
+    /// timed out.
+    async fn next_input(&mut self, inputs: &mut mpsc::UnboundedReceiver<Input>) -> Option<Input> {
+        loop {
+            if self.late.is_empty() {
+                return inputs.recv().await;
+            }
+
+            let ret: Result<SmpFrame<ShellResult>, _> = tokio::select! {
+                biased;
+                input = inputs.recv() => return input,
+                _ = tokio::time::sleep_until(self.late_until) => {
+                    self.late.clear();
+                    continue;
+                }
+                ret = self.transport.receive_cbor() => ret,
+            };
+            debug!("{:?}", ret);
+
+            match ret {
+                Ok(smp_frame) if self.take_late(smp_frame.sequence) => {
+                    for line in late_lines(smp_frame.data) {
+                        // fails only if the editor is gone, the session ends then
+                        let _ = self.printer.print(line);
+                    }
+                }
+                Ok(smp_frame) => {
+                    debug!(
+                        "discarding late response with sequence {}",
+                        smp_frame.sequence
+                    );
+                }
+                Err(err) if timed_out(&err) => {
+                    if Instant::now() >= self.late_until {
+                        self.late.clear();
+                    }
+                }
+                Err(err) => {
+                    let _ = self.printer.print(format!("transport error: {}", err));
+                    self.late.clear();
+                }
+            }
+        }
+    }
+
+    /// Send one line to the device shell and print the response.
+    ///
+    /// Returns false if the user cancelled waiting for the response.
+    async fn execute_line(&mut self, ctx: &Context, line: &str) -> bool {
+        let argv: Vec<_> = line.split_whitespace().map(|s| s.to_owned()).collect();
+        if argv.is_empty() {
+            return true;
+        }
+        transcript::command(ctx, "shell", &argv, None);
+
+        match self.request(ctx, argv).await {
+            Outcome::Response(ShellResult::Ok { o, ret }) => {
+                transcript::shell_outcome(ctx, Ok(ret));
+                for line in output_lines(&o) {
+                    println!("{}", line);
+                }
+            }
+            Outcome::Response(ShellResult::Err { rc }) => {
+                transcript::shell_outcome(ctx, Err(rc));
+                eprintln!("error: {}", CliError::device(rc));
+            }
+            Outcome::Cancelled => {
+                transcript::outcome(
+                    ctx,
+                    Some(&std::io::Error::from(std::io::ErrorKind::Interrupted)),
+                );
+                println!("^C, not waiting for the response");
+                self.expect_late();
+                return false;
+            }
+            Outcome::Failed(err) => {
+                transcript::outcome(ctx, Some(&err));
+                println!("transport error: {}", err);
+                if timed_out(&err) {
+                    self.expect_late();
+                }
+            }
+        }
+
+        true
+    }
+
+    /// The subcommands of a command path, requested from the device on first use.
+    ///
+    /// Failures are cached as an empty list, so a device without a parsable `help`
+    /// output doesn't get asked again on every Tab.
+    async fn subcommands(&mut self, ctx: &Context, path: &[String]) -> &[String] {
+        if !self.completions.contains_key(path) {
+            let argv = if path.is_empty() {
+                vec!["help".to_string()]
+            } else {
+                path.iter().cloned().chain(["-h".to_string()]).collect()
+            };
+
+            let commands = match tokio::time::timeout(self.timeout, self.request(ctx, argv)).await {
+                Ok(Outcome::Response(ShellResult::Ok { o, .. })) => parse_help(&o),
+                _ => None,
+            };
+            if commands.is_none() {
+                debug!("no completions for {:?}", path);
+            }
+
+            self.completions
+                .insert(path.to_vec(), commands.unwrap_or_default());
+        }
+
+        &self.completions[path]
+    }
+
+    /// Complete the word before the cursor, or list the candidates if it is ambiguous
+    async fn complete(&mut self, ctx: &Context, buffer: &str, cursor: usize) -> Reply {
+        let before = &buffer[..cursor];
+
+        let mut path: Vec<String> = before.split_whitespace().map(|s| s.to_owned()).collect();
+        let word = if before.is_empty() || before.ends_with(char::is_whitespace) {
+            String::new()
+        } else {
+            path.pop().unwrap_or_default()
+        };
+
+        let candidates: Vec<String> = self
+            .subcommands(ctx, &path)
+            .await
+            .iter()
+            .filter(|c| c.starts_with(&word))
+            .cloned()
+            .collect();
+
+        match candidates.as_slice() {
+            [] => Reply::Done,
+            [single] => Reply::Insert(format!("{} ", &single[word.len()..])),
+            _ => {
+                let prefix = common_prefix(&candidates);
+                if prefix.len() == word.len() {
+                    return Reply::List(candidates);
+                }
+                Reply::Insert(prefix[word.len()..].to_string())
+            }
+        }
+    }
+}
+
+/// Whether a receive gave up waiting, the response may still arrive
+fn timed_out(err: &mcumgr_smp::transport::error::Error) -> bool {
+    matches!(
+        err,
+        mcumgr_smp::transport::error::Error::Io(e)
+            if matches!(e.kind(), std::io::ErrorKind::TimedOut | std::io::ErrorKind::WouldBlock)
+    )
+}
+
+/// The lines of a command output without line endings, so a multi-line output keeps its
+/// layout whether it is printed above the prompt or not
+fn output_lines(output: &str) -> impl Iterator<Item = &str> {
+    output.lines().map(|line| line.trim_end_matches('\r'))
+}
+
+/// The lines printed for a response that arrived after its request was given up
+fn late_lines(result: ShellResult) -> Vec<String> {
+    let mut lines = vec!["(late response)".to_string()];
+    match result {
+        ShellResult::Ok { o, .. } => lines.extend(output_lines(&o).map(str::to_string)),
+        ShellResult::Err { rc } => lines.push(format!("error: {}", CliError::device(rc))),
+    }
+    lines
+}
+
+/// Width of the terminal in characters, 80 if it isn't a terminal
+fn terminal_width() -> usize {
+    ratatui::crossterm::terminal::size().map_or(80, |(width, _)| width as usize)
+}
+
+/// Lay out words in rows no wider than `width`, separated by two spaces
+fn columns(words: &[String], width: usize) -> Vec<String> {
+    let mut rows = Vec::new();
+    let mut row = String::new();
+    for word in words {
+        if !row.is_empty() && row.len() + 2 + word.len() > width {
+            rows.push(std::mem::take(&mut row));
+        }
+        if !row.is_empty() {
+            row.push_str("  ");
+        }
+        row.push_str(word);
+    }
+    rows.push(row);
+    rows
+}
+
+/// Extract the command names from the output of `help` or `<command> -h`.
+///
+/// The Zephyr shell lists them after `Available commands:` or `Subcommands:`,
+/// one per line as `  name  :description`.
+fn parse_help(output: &str) -> Option<Vec<String>> {
+    let mut lines = output
+        .lines()
+        .skip_while(|line| !matches!(line.trim(), "Available commands:" | "Subcommands:"));
+    lines.next()?;
+
+    Some(
+        lines
+            .take_while(|line| line.starts_with("  "))
+            .filter_map(|line| line.split_whitespace().next())
+            .map(|name| name.trim_end_matches(':').to_string())
+            .collect(),
+    )
+}
+
+/// The longest common prefix of all strings, which must not be empty
+fn common_prefix(strings: &[String]) -> &str {
+    let first = &strings[0];
+    let len = strings[1..].iter().fold(first.len(), |len, s| {
+        first[..len]
+            .char_indices()
+            .zip(s.chars())
+            .find(|((_, a), b)| a != b)
+            .map_or(len.min(s.len()), |((i, _), _)| i)
+    });
+    &first[..len]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    fn strings(words: &[&str]) -> Vec<String> {
+        words.iter().map(|w| w.to_string()).collect()
+    }
+
+    #[test]
+    fn return_values_map_to_exit_statuses() {
+        for (ret, status) in [
+            (0, 0),
+            (1, 1),
+            (125, 125),
+            (126, 125),
+            (255, 125),
+            (-8, 8),
+            (-125, 125),
+            (-126, 125),
+            (i32::MAX, 125),
+            (i32::MIN, 125),
+        ] {
+            assert_eq!(exit_status(ret), status, "{}", ret);
+        }
+    }
+
+    #[test]
+    fn lines_are_split_like_a_posix_shell() {
+        for (line, words) in [
+            ("log  level\tset 3", &["log", "level", "set", "3"][..]),
+            ("echo 'a b'  \"c d\"", &["echo", "a b", "c d"]),
+            (
+                r#"echo "say \"hi\"" 'it''s'"#,
+                &["echo", r#"say "hi""#, "its"],
+            ),
+            (r#"echo a\ b \"c"#, &["echo", "a b", "\"c"]),
+            (r#"echo "a\nb" 'a\nb'"#, &["echo", r"a\nb", r"a\nb"]),
+            ("echo '' x\"\"", &["echo", "", "x"]),
+            ("kernel -v --all", &["kernel", "-v", "--all"]),
+            ("", &[]),
+        ] {
+            assert_eq!(split_line(line).unwrap(), strings(words), "{}", line);
+        }
+
+        for line in ["echo 'a", "echo \"a", "echo \\"] {
+            assert!(split_line(line).is_err(), "{}", line);
+        }
+    }
+
+    #[test]
+    fn words_with_spaces_or_quotes_are_quoted() {
+        for (arg, quoted) in [
+            ("plain", "plain"),
+            ("-v", "-v"),
+            ("--level=3", "--level=3"),
+            ("a b", r#""a b""#),
+            ("tab\there", "\"tab\there\""),
+            (r#"say "hi""#, r#""say \"hi\"""#),
+            ("it's", r#""it's""#),
+            (r"back\slash", r#""back\\slash""#),
+            ("", r#""""#),
+        ] {
+            assert_eq!(quote_args(&strings(&[arg])), [quoted], "{}", arg);
+        }
+    }
+
+    #[test]
+    fn exec_sends_arguments_unchanged_or_the_quoted_line() {
+        let cmd = strings(&["echo", "a b", "-n"]);
+        assert_eq!(exec_argv(&cmd, None).unwrap(), cmd);
+        assert_eq!(
+            exec_argv(&[], Some("echo 'a b' -n")).unwrap(),
+            strings(&["echo", r#""a b""#, "-n"])
+        );
+        assert!(matches!(
+            exec_argv(&[], Some("  ")),
+            Err(CliError::Usage(_))
+        ));
+        assert!(matches!(
+            exec_argv(&[], Some("echo 'a")),
+            Err(CliError::Usage(_))
+        ));
+    }
+
+    #[test]
+    fn leading_dashes_reach_the_device() {
+        for (args, expected) in [
+            (&["kernel", "-v"][..], &["kernel", "-v"][..]),
+            (&["--", "-h"], &["-h"]),
+            (&["log", "--", "--all"], &["log", "--", "--all"]),
+        ] {
+            let cli = crate::Cli::try_parse_from(["smp-tool", "shell", "exec"].iter().chain(args))
+                .unwrap();
+            let crate::Commands::Shell(crate::ShellCmd::Exec { cmd, .. }) = cli.command else {
+                panic!("not shell exec: {:?}", args);
+            };
+            assert_eq!(cmd, strings(expected), "{:?}", args);
         }
     }
 }
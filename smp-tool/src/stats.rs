@@ -0,0 +1,252 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use mcumgr_smp::{
+    smp::SmpFrame,
+    stat_management::{self, GroupDataResult, ListGroupsResult},
+    ReturnCode,
+};
+use serde::Serialize;
+use tracing::debug;
+
+use crate::context::Context;
+use crate::dump::timestamp;
+use crate::error::CliError;
+use crate::output::OutputFormat;
+use crate::{outln, UsedTransport};
+
+/// Counters of a statistics group by name
+type Counters = BTreeMap<String, u64>;
+
+/// Parameters of `stat read`
+pub struct ReadOptions {
+    pub poll: Option<Duration>,
+    pub count: Option<u64>,
+    pub diff: bool,
+}
+
+/// Query the names of all statistics groups
+pub async fn group_names(
+    ctx: &Context,
+    transport: &mut UsedTransport,
+) -> Result<Vec<String>, Box<dyn Error>> {
+    let ret: SmpFrame<ListGroupsResult> = transport
+        .transceive_cbor(&stat_management::list_groups(ctx.sequence.next()))
+        .await?;
+    debug!("{:?}", ret);
+
+    match ret.data {
+        ListGroupsResult::Ok { stat_list } => Ok(stat_list),
+        ListGroupsResult::Err { rc } => Err(CliError::device(rc).into()),
+    }
+}
+
+/// Print the names of all statistics groups
+pub async fn list(
+    ctx: &Context,
+    transport: &mut UsedTransport,
+    format: OutputFormat,
+) -> Result<(), Box<dyn Error>> {
+    let stat_list = group_names(ctx, transport).await?;
+    match format {
+        OutputFormat::Text => {
+            for name in stat_list {
+                outln!(ctx, "{}", name);
+            }
+        }
+        OutputFormat::Json => outln!(
+            ctx,
+            "{}",
+            serde_json::to_string_pretty(&stat_list).expect("serializing to string can't fail")
+        ),
+    }
+
+    Ok(())
+}
+
+pub async fn read_group(
+    ctx: &Context,
+    transport: &mut UsedTransport,
+    group: &str,
+) -> Result<Counters, Box<dyn Error>> {
+    let ret: SmpFrame<GroupDataResult> = transport
+        .transceive_cbor(&stat_management::group_data(
+            ctx.sequence.next(),
+            group.to_string(),
+        ))
+        .await?;
+    debug!("{:?}", ret);
+
+    match ret.data {
+        GroupDataResult::Ok { fields, .. } => Ok(fields),
+        GroupDataResult::Err { rc } if ReturnCode::try_from(rc) == Ok(ReturnCode::NoEntry) => {
+            Err(CliError::context(Box::new(CliError::device(rc)), |_| {
+                format!("no statistics group {}, see `stat list`", group)
+            })
+            .into())
+        }
+        GroupDataResult::Err { rc } => Err(CliError::device(rc).into()),
+    }
+}
+
+/// One sample of a group, printed as one JSON line while polling
+#[derive(Serialize, Debug)]
+struct Sample<'a> {
+    /// milliseconds since the Unix epoch
+    time_ms: u64,
+    group: &'a str,
+    /// set if the counters are differences to the previous sample
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    diff: bool,
+    /// set if the counters went backwards or changed, e.g. because the device rebooted.
+    /// The sample becomes the new baseline and has no differences
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    reset: bool,
+    fields: Counters,
+}
+
+impl Sample<'_> {
+    fn print(&self, ctx: &Context, format: OutputFormat) {
+        match format {
+            OutputFormat::Text => {
+                let time = timestamp();
+                if self.reset {
+                    outln!(ctx, "{} counters reset, new baseline", time);
+                    return;
+                }
+                let fields: Vec<String> = self
+                    .fields
+                    .iter()
+                    .map(|(name, value)| match self.diff {
+                        true => format!("{}=+{}", name, value),
+                        false => format!("{}={}", name, value),
+                    })
+                    .collect();
+                outln!(ctx, "{} {}", time, fields.join(" "));
+            }
+            OutputFormat::Json => outln!(
+                ctx,
+                "{}",
+                serde_json::to_string(self).expect("serializing to string can't fail")
+            ),
+        }
+    }
+}
+
+/// Differences between two samples.
+///
+/// Returns `None` if a counter went backwards or the set of counters changed, then
+/// differences would be meaningless.
+fn deltas(previous: &Counters, current: &Counters) -> Option<Counters> {
+    if previous.len() != current.len() {
+        return None;
+    }
+
+    current
+        .iter()
+        .map(|(name, value)| {
+            let previous = previous.get(name)?;
+            Some((name.clone(), value.checked_sub(*previous)?))
+        })
+        .collect()
+}
+
+fn unix_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+/// Read a statistics group once, or periodically until the count is reached or Ctrl-C
+/// is pressed.
+///
+/// With `diff`, the first sample is only the baseline and every further sample prints
+/// the increase since the previous one.
+pub async fn read(
+    ctx: &Context,
+    transport: &mut UsedTransport,
+    group: &str,
+    options: &ReadOptions,
+    format: OutputFormat,
+) -> Result<(), Box<dyn Error>> {
+    let Some(interval) = options.poll else {
+        let fields = read_group(ctx, transport, group).await?;
+        match format {
+            OutputFormat::Text => {
+                for (name, value) in &fields {
+                    outln!(ctx, "{}: {}", name, value);
+                }
+            }
+            OutputFormat::Json => outln!(
+                ctx,
+                "{}",
+                serde_json::to_string_pretty(&fields).expect("serializing to string can't fail")
+            ),
+        }
+        return Ok(());
+    };
+
+    let ctrl_c = tokio::signal::ctrl_c();
+    tokio::pin!(ctrl_c);
+
+    let mut baseline: Option<Counters> = None;
+    let mut samples = 0u64;
+
+    while options.count.is_none_or(|count| samples < count) {
+        if samples > 0 {
+            tokio::select! {
+                _ = &mut ctrl_c => break,
+                _ = tokio::time::sleep(interval) => {}
+            }
+        }
+
+        let fields = tokio::select! {
+            biased;
+            _ = &mut ctrl_c => break,
+            ret = read_group(ctx, transport, group) => ret?,
+        };
+        samples += 1;
+        let time_ms = unix_ms();
+
+        if !options.diff {
+            Sample {
+                time_ms,
+                group,
+                diff: false,
+                reset: false,
+                fields,
+            }
+            .print(ctx, format);
+            continue;
+        }
+
+        let sample = match &baseline {
+            // the first sample only sets the baseline
+            None => None,
+            Some(previous) => match deltas(previous, &fields) {
+                Some(deltas) => Some(Sample {
+                    time_ms,
+                    group,
+                    diff: true,
+                    reset: false,
+                    fields: deltas,
+                }),
+                None => Some(Sample {
+                    time_ms,
+                    group,
+                    diff: false,
+                    reset: true,
+                    fields: fields.clone(),
+                }),
+            },
+        };
+        if let Some(sample) = sample {
+            sample.print(ctx, format);
+        }
+        baseline = Some(fields);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,488 @@
+use std::error::Error;
+use std::io::{BufRead, BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::mpsc;
+use std::time::Instant;
+
+use clap::parser::ValueSource;
+use clap::{ArgMatches, ValueEnum};
+use serde::Serialize;
+
+use crate::config::LoadedConfig;
+use crate::error::CliError;
+use crate::output::OutputFormat;
+use crate::{ApplicationCmd, Cli, Commands, SettingCmd, Transport};
+
+/// Environment variable that selects the target of a child process
+pub const TARGET_ENV: &str = "SMP_TARGET";
+
+/// A line of a targets file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Spec {
+    Profile(String),
+    Serial { device: String, baud: Option<u32> },
+    Udp { host: String, port: Option<u16> },
+    Ble { name: String },
+}
+
+/// Parse a target like `serial:/dev/ttyACM0@115200`, `udp:192.168.1.10:1337`,
+/// `udp:[fe80::1]:1337` or `ble:name`. Anything else is a profile name
+pub fn parse_spec(spec: &str) -> Result<Spec, String> {
+    let invalid = |what: &str| format!("invalid target {}: {}", spec, what);
+
+    let Some((transport, rest)) = spec.split_once(':') else {
+        return Ok(Spec::Profile(spec.to_string()));
+    };
+    if rest.is_empty() {
+        return Err(invalid("nothing after the transport"));
+    }
+
+    match transport {
+        "serial" => match rest.rsplit_once('@') {
+            Some((device, baud)) => Ok(Spec::Serial {
+                device: device.to_string(),
+                baud: Some(baud.parse().map_err(|_| invalid("invalid baud rate"))?),
+            }),
+            None => Ok(Spec::Serial {
+                device: rest.to_string(),
+                baud: None,
+            }),
+        },
+        "udp" => {
+            let (host, port) = match rest.strip_prefix('[') {
+                Some(bracketed) => match bracketed.split_once(']') {
+                    Some((host, "")) => (host, None),
+                    Some((host, port)) => match port.strip_prefix(':') {
+                        Some(port) => (host, Some(port)),
+                        None => return Err(invalid("expected :port after the address")),
+                    },
+                    None => return Err(invalid("missing ]")),
+                },
+                None => match rest.split_once(':') {
+                    Some((host, port)) if !port.contains(':') => (host, Some(port)),
+                    // IPv6 addresses with a port need brackets
+                    Some(_) => (rest, None),
+                    None => (rest, None),
+                },
+            };
+            let port = port
+                .map(|port| port.parse().map_err(|_| invalid("invalid port")))
+                .transpose()?;
+            Ok(Spec::Udp {
+                host: host.to_string(),
+                port,
+            })
+        }
+        "ble" => Ok(Spec::Ble {
+            name: rest.to_string(),
+        }),
+        transport => Err(invalid(&format!(
+            "unknown transport {}, use serial, udp or ble",
+            transport
+        ))),
+    }
+}
+
+/// Parse a `--transport-limit` like `ble=3`
+pub fn parse_limit(s: &str) -> Result<(Transport, usize), String> {
+    let (transport, limit) = s
+        .split_once('=')
+        .ok_or_else(|| format!("invalid limit {}, expected e.g. ble=3", s))?;
+    let transport = Transport::from_str(transport, true)?;
+    let limit = limit
+        .parse()
+        .ok()
+        .filter(|limit| *limit > 0)
+        .ok_or_else(|| format!("invalid limit {}, expected a number of at least 1", limit))?;
+    Ok((transport, limit))
+}
+
+/// The lines of a targets file, without empty lines and lines starting with `#`
+pub fn parse_targets(text: &str) -> Result<Vec<String>, String> {
+    let mut targets: Vec<String> = Vec::new();
+    for line in text.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        // two processes can't share a serial port or BLE connection
+        if targets.iter().any(|target| target == line) {
+            return Err(format!("target {} is listed twice", line));
+        }
+        targets.push(line.to_string());
+    }
+    Ok(targets)
+}
+
+/// Select the target of a child process. The target replaces the connection options of
+/// the command line, the timeout of a profile is only used if none was given explicitly
+pub fn apply(
+    cli: &mut Cli,
+    matches: &ArgMatches,
+    config: &LoadedConfig,
+    target: &str,
+) -> Result<(), Box<dyn Error>> {
+    cli.serial_device = None;
+    cli.dest_host = None;
+    cli.name = None;
+
+    match parse_spec(target).map_err(CliError::Usage)? {
+        Spec::Profile(name) => {
+            let profile = config.profile(&name)?;
+            cli.transport = profile.transport;
+            cli.serial_device.clone_from(&profile.serial_device);
+            if let Some(serial_baud) = profile.serial_baud {
+                cli.serial_baud = serial_baud;
+            }
+            if let Some(serial_config) = profile.serial_config {
+                cli.serial_config = serial_config;
+            }
+            if let Some(serial_flow) = profile.serial_flow {
+                cli.serial_flow = serial_flow;
+            }
+            cli.dest_host.clone_from(&profile.dest_host);
+            if let Some(udp_port) = profile.udp_port {
+                cli.udp_port = udp_port;
+            }
+            cli.name.clone_from(&profile.name);
+            let default_timeout = matches!(
+                matches.value_source("timeout_ms"),
+                None | Some(ValueSource::DefaultValue)
+            );
+            if let Some(timeout_ms) = profile.timeout_ms.filter(|_| default_timeout) {
+                cli.timeout_ms = timeout_ms;
+            }
+            let default_slow_timeout = matches!(
+                matches.value_source("slow_timeout_ms"),
+                None | Some(ValueSource::DefaultValue)
+            );
+            if let Some(slow_timeout_ms) = profile.slow_timeout_ms.filter(|_| default_slow_timeout)
+            {
+                cli.slow_timeout_ms = slow_timeout_ms;
+            }
+        }
+        Spec::Serial { device, baud } => {
+            cli.transport = Some(Transport::Serial);
+            cli.serial_device = Some(device);
+            if let Some(baud) = baud {
+                cli.serial_baud = baud;
+            }
+        }
+        Spec::Udp { host, port } => {
+            cli.transport = Some(Transport::Udp);
+            cli.dest_host = Some(host);
+            if let Some(port) = port {
+                cli.udp_port = port;
+            }
+        }
+        Spec::Ble { name } => {
+            cli.transport = Some(Transport::Ble);
+            cli.name = Some(name);
+        }
+    }
+
+    Ok(())
+}
+
+/// A target of a `--targets` run
+struct Target {
+    spec: String,
+    transport: Option<Transport>,
+}
+
+/// Outcome of the command on one target
+#[derive(Serialize, Debug)]
+struct TargetResult {
+    target: String,
+    ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    exit_code: Option<i32>,
+    seconds: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    /// the JSON output of the command
+    #[serde(skip_serializing_if = "Option::is_none")]
+    output: Option<serde_json::Value>,
+}
+
+/// Start as many of the `pending` targets as the limits allow, in the order of the file.
+/// Targets of a transport type at its limit are passed over, so they don't hold up others
+pub fn schedule(
+    pending: &mut Vec<usize>,
+    transports: &[Option<Transport>],
+    running: &mut Vec<Option<Transport>>,
+    parallel: usize,
+    limits: &[(Transport, usize)],
+) -> Vec<usize> {
+    let mut started = Vec::new();
+    while running.len() < parallel {
+        let allowed = |transport: Option<Transport>| {
+            let Some(transport) = transport else {
+                return true;
+            };
+            let limit = limits
+                .iter()
+                .filter(|(t, _)| *t == transport)
+                .map(|(_, limit)| *limit)
+                .min();
+            limit.is_none_or(|limit| {
+                running.iter().filter(|t| **t == Some(transport)).count() < limit
+            })
+        };
+
+        let Some(pos) = pending.iter().position(|&i| allowed(transports[i])) else {
+            break;
+        };
+        let index = pending.remove(pos);
+        running.push(transports[index]);
+        started.push(index);
+    }
+    started
+}
+
+/// The JSON output of a command: a single value, one value per line or otherwise the text
+fn parse_output(stdout: &str) -> Option<serde_json::Value> {
+    if stdout.trim().is_empty() {
+        return None;
+    }
+    if let Ok(value) = serde_json::from_str(stdout) {
+        return Some(value);
+    }
+    let lines: Result<Vec<serde_json::Value>, _> = stdout
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(serde_json::from_str)
+        .collect();
+    Some(lines.map_or_else(|_| stdout.into(), serde_json::Value::Array))
+}
+
+/// The error message a child process printed last
+fn error_message(line: &str) -> Option<String> {
+    if let Some(message) = line.strip_prefix("error: ") {
+        return Some(message.to_string());
+    }
+    let json: serde_json::Value = serde_json::from_str(line).ok()?;
+    json.get("error")?.as_str().map(str::to_string)
+}
+
+/// The lines of a child's output. Invalid UTF-8 is replaced, so the pipe is always drained
+fn lines(output: impl Read) -> impl Iterator<Item = String> {
+    BufReader::new(output)
+        .split(b'\n')
+        .map_while(Result::ok)
+        .map(|line| {
+            String::from_utf8_lossy(&line)
+                .trim_end_matches('\r')
+                .to_string()
+        })
+}
+
+/// Run the command as a child process for one target and prefix its output with the target.
+///
+/// With JSON output the standard output is collected for the summary instead.
+fn run_target(exe: &Path, spec: &str, format: OutputFormat) -> TargetResult {
+    let start = Instant::now();
+    let failed = |error: String| TargetResult {
+        target: spec.to_string(),
+        ok: false,
+        exit_code: None,
+        seconds: start.elapsed().as_secs_f64(),
+        error: Some(error),
+        output: None,
+    };
+
+    let child = Command::new(exe)
+        .args(std::env::args_os().skip(1))
+        .env(TARGET_ENV, spec)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn();
+    let mut child = match child {
+        Ok(child) => child,
+        Err(e) => return failed(format!("can't start {}: {}", exe.display(), e)),
+    };
+
+    let stderr = child.stderr.take().expect("stderr is piped");
+    let prefix = format!("[{}]", spec);
+    let stderr_prefix = prefix.clone();
+    let stderr_reader = std::thread::spawn(move || {
+        let mut last_error = None;
+        let mut last_line = None;
+        for line in lines(stderr) {
+            eprintln!("{} {}", stderr_prefix, line);
+            if let Some(error) = error_message(&line) {
+                last_error = Some(error);
+            }
+            if !line.trim().is_empty() {
+                last_line = Some(line);
+            }
+        }
+        last_error.or(last_line)
+    });
+
+    let stdout = child.stdout.take().expect("stdout is piped");
+    let mut collected = String::new();
+    match format {
+        OutputFormat::Text => {
+            for line in lines(stdout) {
+                println!("{} {}", prefix, line);
+            }
+        }
+        OutputFormat::Json => {
+            for line in lines(stdout) {
+                collected.push_str(&line);
+                collected.push('\n');
+            }
+        }
+    }
+
+    let status = child.wait();
+    let last_error = stderr_reader.join().unwrap_or_default();
+    let status = match status {
+        Ok(status) => status,
+        Err(e) => return failed(e.to_string()),
+    };
+
+    TargetResult {
+        target: spec.to_string(),
+        ok: status.success(),
+        exit_code: status.code(),
+        seconds: start.elapsed().as_secs_f64(),
+        error: match status.success() {
+            true => None,
+            false => Some(last_error.unwrap_or_else(|| format!("exited with {}", status))),
+        },
+        output: parse_output(&collected),
+    }
+}
+
+/// Run the command against all targets of a targets file concurrently.
+///
+/// Every target is handled by a child process of this program, so blocking transports
+/// don't hold up each other. Prints a pass/fail table at the end and fails if any target failed.
+pub fn run(cli: &Cli, config: &LoadedConfig, path: &Path) -> Result<(), Box<dyn Error>> {
+    match &cli.command {
+        Commands::App(ApplicationCmd::Flash {
+            update_file: Some(update_file),
+            ..
+        }) if update_file.as_os_str() == "-" => {
+            Err(CliError::Usage(
+                "--targets can't be used with firmware from stdin".to_string(),
+            ))?;
+        }
+        Commands::App(ApplicationCmd::Flash { .. })
+        | Commands::App(ApplicationCmd::Update { .. })
+        | Commands::Setting(SettingCmd::Import { .. }) => {}
+        _ => Err(CliError::Usage(
+            "--targets can only be used with app flash, app update and setting import".to_string(),
+        ))?,
+    }
+    if cli.parallel == 0 {
+        Err(CliError::Usage("--parallel must be at least 1".to_string()))?;
+    }
+
+    let text = std::fs::read_to_string(path)
+        .map_err(|e| format!("can't read {}: {}", path.display(), e))?;
+    let specs = parse_targets(&text).map_err(CliError::Usage)?;
+    if specs.is_empty() {
+        Err(CliError::Usage(format!(
+            "{} lists no targets",
+            path.display()
+        )))?;
+    }
+
+    // resolve the transports up front, so unknown profiles fail before anything is started
+    let mut targets = Vec::with_capacity(specs.len());
+    for spec in specs {
+        let transport = match parse_spec(&spec).map_err(CliError::Usage)? {
+            Spec::Profile(name) => config.profile(&name)?.transport,
+            Spec::Serial { .. } => Some(Transport::Serial),
+            Spec::Udp { .. } => Some(Transport::Udp),
+            Spec::Ble { .. } => Some(Transport::Ble),
+        };
+        targets.push(Target { spec, transport });
+    }
+
+    let exe: PathBuf = std::env::current_exe()?;
+    let transports: Vec<Option<Transport>> = targets.iter().map(|t| t.transport).collect();
+    let mut pending: Vec<usize> = (0..targets.len()).collect();
+    let mut running = Vec::new();
+    let mut results: Vec<Option<TargetResult>> = targets.iter().map(|_| None).collect();
+    let (tx, rx) = mpsc::channel();
+
+    loop {
+        for index in schedule(
+            &mut pending,
+            &transports,
+            &mut running,
+            cli.parallel,
+            &cli.transport_limit,
+        ) {
+            let tx = tx.clone();
+            let exe = exe.clone();
+            let spec = targets[index].spec.clone();
+            let format = cli.format;
+            std::thread::spawn(move || {
+                let _ = tx.send((index, run_target(&exe, &spec, format)));
+            });
+        }
+
+        if running.is_empty() {
+            break;
+        }
+        let (index, result) = rx.recv().expect("a target is running");
+        let pos = running
+            .iter()
+            .position(|t| *t == targets[index].transport)
+            .expect("the target is running");
+        running.remove(pos);
+        results[index] = Some(result);
+    }
+
+    let results: Vec<TargetResult> = results
+        .into_iter()
+        .map(|result| result.expect("all targets are done"))
+        .collect();
+    let failed = results.iter().filter(|result| !result.ok).count();
+
+    match cli.format {
+        OutputFormat::Text => {
+            let width = results
+                .iter()
+                .map(|result| result.target.len())
+                .max()
+                .unwrap_or(0)
+                .max("target".len());
+            println!();
+            println!(
+                "{:<width$}  {:<6}  {:>7}  error",
+                "target",
+                "result",
+                "time",
+                width = width
+            );
+            for result in &results {
+                let line = format!(
+                    "{:<width$}  {:<6}  {:>6.1}s  {}",
+                    result.target,
+                    if result.ok { "ok" } else { "failed" },
+                    result.seconds,
+                    result.error.as_deref().unwrap_or_default(),
+                    width = width
+                );
+                println!("{}", line.trim_end());
+            }
+        }
+        OutputFormat::Json => println!(
+            "{}",
+            serde_json::to_string_pretty(&results).expect("serializing to string can't fail")
+        ),
+    }
+
+    if failed > 0 {
+        Err(format!("{} of {} targets failed", failed, results.len()))?;
+    }
+    Ok(())
+}
@@ -0,0 +1,254 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::io::{IsTerminal, Write};
+use std::time::Duration;
+
+use clap::ValueEnum;
+use mcumgr_smp::{
+    os_management::{self, TaskStats, TaskStatsResult},
+    smp::SmpFrame,
+};
+use serde::Serialize;
+use tracing::debug;
+
+use crate::context::Context;
+use crate::dump::timestamp;
+use crate::error::CliError;
+use crate::output::{self, OutputFormat};
+use crate::{out, outln, UsedTransport};
+
+/// Sort orders of the task table
+#[derive(ValueEnum, Copy, Clone, Debug, Default)]
+pub enum SortBy {
+    /// task name
+    #[default]
+    Name,
+    /// stack usage in percent, highest first
+    Stkuse,
+    /// priority, highest priority (lowest number) first
+    Prio,
+    /// runtime, highest first
+    Runtime,
+}
+
+/// Parameters of `os taskstat`
+pub struct TaskstatOptions {
+    pub sort: SortBy,
+    pub watch: Option<Duration>,
+    /// stack usage in percent above which a task is highlighted
+    pub threshold: f64,
+}
+
+/// Stack sizes are reported in 4 byte words
+const STACK_WORD: u64 = 4;
+
+fn stack_percent(task: &TaskStats) -> Option<f64> {
+    match (task.stkuse, task.stksiz) {
+        (Some(used), Some(size)) if size > 0 => Some(used as f64 * 100.0 / size as f64),
+        _ => None,
+    }
+}
+
+/// A task as printed with `--format json`, sizes in bytes
+#[derive(Serialize, Debug)]
+struct TaskJson<'a> {
+    name: &'a str,
+    #[serde(flatten)]
+    stats: &'a TaskStats,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stack_used_bytes: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stack_size_bytes: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stack_percent: Option<f64>,
+    above_threshold: bool,
+}
+
+pub async fn query(
+    ctx: &Context,
+    transport: &mut UsedTransport,
+) -> Result<BTreeMap<String, TaskStats>, Box<dyn Error>> {
+    let ret: SmpFrame<TaskStatsResult> = transport
+        .transceive_cbor(&os_management::task_stats(ctx.sequence.next()))
+        .await?;
+    debug!("{:?}", ret);
+
+    match ret.data {
+        TaskStatsResult::Ok { tasks } => Ok(tasks),
+        TaskStatsResult::Err { rc } => Err(CliError::device(rc).into()),
+    }
+}
+
+fn sort(tasks: &mut [(&String, &TaskStats)], by: SortBy) {
+    // tasks without a value go last in every order
+    match by {
+        SortBy::Name => {}
+        SortBy::Stkuse => tasks.sort_by(|a, b| {
+            let a = stack_percent(a.1).unwrap_or(-1.0);
+            let b = stack_percent(b.1).unwrap_or(-1.0);
+            b.total_cmp(&a)
+        }),
+        SortBy::Prio => tasks.sort_by_key(|(_, task)| (task.prio.is_none(), task.prio)),
+        SortBy::Runtime => tasks.sort_by_key(|(_, task)| std::cmp::Reverse(task.runtime)),
+    }
+}
+
+/// Format an optional field, `-` if the device doesn't report it
+fn field<T: ToString>(value: Option<T>) -> String {
+    value.map_or_else(|| "-".to_string(), |value| value.to_string())
+}
+
+/// Render the task table, highlighting tasks above the threshold
+pub fn render(
+    tasks: &BTreeMap<String, TaskStats>,
+    options: &TaskstatOptions,
+    color: bool,
+) -> String {
+    let mut sorted: Vec<_> = tasks.iter().collect();
+    sort(&mut sorted, options.sort);
+
+    let header = [
+        "name",
+        "prio",
+        "tid",
+        "state",
+        "stack (B)",
+        "stack %",
+        "cswcnt",
+        "runtime",
+    ];
+    let rows: Vec<[String; 8]> = sorted
+        .iter()
+        .map(|(name, task)| {
+            let stack = match (task.stkuse, task.stksiz) {
+                (None, None) => "-".to_string(),
+                (used, size) => format!(
+                    "{}/{}",
+                    field(used.map(|used| used * STACK_WORD)),
+                    field(size.map(|size| size * STACK_WORD))
+                ),
+            };
+            [
+                name.to_string(),
+                field(task.prio),
+                field(task.tid),
+                field(task.state),
+                stack,
+                field(stack_percent(task).map(|p| format!("{:.0}%", p))),
+                field(task.cswcnt),
+                field(task.runtime),
+            ]
+        })
+        .collect();
+
+    let mut widths = header.map(str::len);
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+
+    let line = |cells: &[String]| {
+        cells
+            .iter()
+            .zip(widths)
+            .enumerate()
+            .map(|(i, (cell, width))| match i {
+                0 => format!("{:<width$}", cell),
+                _ => format!("{:>width$}", cell),
+            })
+            .collect::<Vec<_>>()
+            .join("  ")
+    };
+
+    let mut out = line(&header.map(str::to_string)) + "\n";
+    for ((_, task), row) in sorted.iter().zip(&rows) {
+        let above = stack_percent(task).is_some_and(|p| p >= options.threshold);
+        match (above, color) {
+            (true, true) => out += &format!("\x1b[1;31m{}\x1b[0m\n", line(row)),
+            (true, false) => out += &format!("{}  !\n", line(row)),
+            (false, _) => out += &format!("{}\n", line(row)),
+        }
+    }
+    out
+}
+
+fn print_json(ctx: &Context, tasks: &BTreeMap<String, TaskStats>, options: &TaskstatOptions) {
+    let mut sorted: Vec<_> = tasks.iter().collect();
+    sort(&mut sorted, options.sort);
+
+    let tasks: Vec<_> = sorted
+        .into_iter()
+        .map(|(name, stats)| TaskJson {
+            name,
+            stats,
+            stack_used_bytes: stats.stkuse.map(|used| used * STACK_WORD),
+            stack_size_bytes: stats.stksiz.map(|size| size * STACK_WORD),
+            stack_percent: stack_percent(stats),
+            above_threshold: stack_percent(stats).is_some_and(|p| p >= options.threshold),
+        })
+        .collect();
+    outln!(
+        ctx,
+        "{}",
+        serde_json::to_string(&tasks).expect("serializing to string can't fail")
+    );
+}
+
+/// Print the task statistics as a table, or redraw it periodically until Ctrl-C is pressed.
+///
+/// With `--format json` every sample is printed as one line.
+pub async fn taskstat(
+    ctx: &Context,
+    transport: &mut UsedTransport,
+    options: &TaskstatOptions,
+    format: OutputFormat,
+) -> Result<(), Box<dyn Error>> {
+    let color = format == OutputFormat::Text && output::stdout_is_terminal(ctx);
+
+    let Some(interval) = options.watch else {
+        let tasks = query(ctx, transport).await?;
+        match format {
+            OutputFormat::Text => out!(ctx, "{}", render(&tasks, options, color)),
+            OutputFormat::Json => print_json(ctx, &tasks, options),
+        }
+        return Ok(());
+    };
+
+    let ctrl_c = tokio::signal::ctrl_c();
+    tokio::pin!(ctrl_c);
+
+    loop {
+        let tasks = tokio::select! {
+            biased;
+            _ = &mut ctrl_c => break,
+            ret = query(ctx, transport) => ret?,
+        };
+
+        match format {
+            OutputFormat::Text => {
+                // like watch(1): redraw from the top instead of scrolling
+                let mut stdout = std::io::stdout().lock();
+                if stdout.is_terminal() {
+                    write!(stdout, "\x1b[H\x1b[2J")?;
+                }
+                writeln!(
+                    stdout,
+                    "Every {:?}: os taskstat    {}\n",
+                    interval,
+                    timestamp()
+                )?;
+                write!(stdout, "{}", render(&tasks, options, color))?;
+                stdout.flush()?;
+            }
+            OutputFormat::Json => print_json(ctx, &tasks, options),
+        }
+
+        tokio::select! {
+            _ = &mut ctrl_c => break,
+            _ = tokio::time::sleep(interval) => {}
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,262 @@
+use std::error::Error;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use ciborium::Value;
+use mcumgr_smp::transport::observer::{Direction, TransportObserver};
+use mcumgr_smp::Group;
+use serde_json::json;
+
+use crate::context::Context;
+use crate::frame::{self, Header, HEADER_LEN};
+use crate::{cbor, datetime, error, image, Cli, Commands, SettingCmd, Transport};
+
+const REDACTED: &str = "<redacted>";
+
+/// Long help of `--log-file`
+pub const LOG_FILE_HELP: &str = "Append a transcript of the session to this file
+
+Every line is a JSON object with the time in UTC, the target and an `event`:
+  session   the resolved connection parameters
+  command   a command of the command line, a script or the interactive shell
+  frame     a frame sent or received: header fields, the rc of the response,
+            and the frame as hex with --log-frames
+  outcome   the result of a command, with exit code and rc if it failed
+Setting values are logged verbatim as part of the commands and frames, use
+--redact-values to replace them.";
+
+/// A JSON-lines file recording everything done to the device
+pub struct Transcript {
+    file: Mutex<File>,
+    target: String,
+    frames: bool,
+    redact: bool,
+    /// a failed write is only reported once
+    failed: AtomicBool,
+}
+
+impl Transcript {
+    fn write(&self, event: &str, mut entry: serde_json::Value) {
+        let mut line = json!({
+            "time": format!("{}Z", datetime::format_us(datetime::now_us())),
+            "target": self.target,
+            "event": event,
+        });
+        if let (Some(line), Some(entry)) = (line.as_object_mut(), entry.as_object_mut()) {
+            line.append(entry);
+        }
+
+        // one write per line, so concurrent processes appending to the file don't interleave
+        let mut out = line.to_string();
+        out.push('\n');
+        let ret = self
+            .file
+            .lock()
+            .expect("transcript lock is never poisoned")
+            .write_all(out.as_bytes());
+        if let Err(e) = ret {
+            if !self.failed.swap(true, Ordering::Relaxed) {
+                eprintln!("warning: writing the transcript failed: {}", e);
+            }
+        }
+    }
+}
+
+/// Open the transcript file for appending and record the resolved connection parameters
+pub fn open(cli: &Cli, path: &Path) -> Result<Arc<Transcript>, Box<dyn Error>> {
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(|e| format!("can't open {}: {}", path.display(), e))?;
+
+    let transcript = Transcript {
+        file: Mutex::new(file),
+        target: crate::describe_target(cli),
+        frames: cli.log_frames,
+        redact: cli.redact_values,
+        failed: AtomicBool::new(false),
+    };
+
+    let mut session = json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "transport": cli.transport,
+        "timeout_ms": cli.timeout_ms,
+        "slow_timeout_ms": cli.slow_timeout_ms,
+        "profile": cli.profile,
+    });
+    match cli.transport {
+        Some(Transport::Serial) => {
+            session["serial_device"] = cli.serial_device.as_deref().into();
+            session["serial_baud"] = cli.serial_baud.into();
+            session["serial_config"] = cli.serial_config.to_string().into();
+            session["serial_flow"] =
+                serde_json::to_value(cli.serial_flow).expect("serializing to a value can't fail");
+        }
+        Some(Transport::Udp) => {
+            session["dest_host"] = cli.dest_host.as_deref().into();
+            session["udp_port"] = cli.udp_port.into();
+        }
+        Some(Transport::Ble) => session["name"] = cli.name.as_deref().into(),
+        Some(Transport::Replay) => {
+            session["replay_file"] = cli
+                .replay_file
+                .as_deref()
+                .map(|path| path.display().to_string())
+                .into();
+        }
+        None => {}
+    }
+    transcript.write("session", session);
+
+    Ok(Arc::new(transcript))
+}
+
+/// Record a command. `source` is where it came from, e.g. `cli`, `script` or `shell`
+pub(crate) fn command(ctx: &Context, source: &str, words: &[String], parsed: Option<&Commands>) {
+    let Some(transcript) = &ctx.transcript else {
+        return;
+    };
+
+    let words = match parsed {
+        Some(parsed) if transcript.redact => redact(words, parsed),
+        _ => words.to_vec(),
+    };
+    transcript.write(
+        "command",
+        json!({
+            "source": source,
+            "command": words.join(" "),
+        }),
+    );
+}
+
+/// Record the result of a command, `None` if it succeeded
+pub fn outcome(ctx: &Context, err: Option<&(dyn Error + 'static)>) {
+    let Some(transcript) = &ctx.transcript else {
+        return;
+    };
+
+    let entry = match err {
+        None => json!({ "status": "ok" }),
+        Some(err) => {
+            let mut entry = error::to_json(err, error::exit_code(err));
+            entry["status"] = "failed".into();
+            entry
+        }
+    };
+    transcript.write("outcome", entry);
+}
+
+/// Record the response to a line of the interactive shell: the return value of the command,
+/// or the rc if the device didn't run it
+pub fn shell_outcome(ctx: &Context, response: Result<Option<i32>, i32>) {
+    let Some(transcript) = &ctx.transcript else {
+        return;
+    };
+
+    let entry = match response {
+        Ok(ret) => json!({ "status": "ok", "ret": ret }),
+        Err(rc) => json!({ "status": "failed", "rc": rc }),
+    };
+    transcript.write("outcome", entry);
+}
+
+/// Replace the value of setting write commands with a placeholder
+fn redact(words: &[String], parsed: &Commands) -> Vec<String> {
+    let is_value = |word: &str| match parsed {
+        Commands::Setting(SettingCmd::WriteString { val, .. })
+        | Commands::Setting(SettingCmd::WriteBytes { val, .. }) => word == val,
+        Commands::Setting(SettingCmd::WriteInt { val, .. }) => {
+            word.parse::<i128>().is_ok_and(|word| word == *val)
+        }
+        _ => false,
+    };
+
+    words
+        .iter()
+        .map(|word| {
+            if is_value(word) {
+                REDACTED.to_string()
+            } else {
+                word.clone()
+            }
+        })
+        .collect()
+}
+
+/// Records a summary of every frame in the transcript
+struct FrameLog(Arc<Transcript>);
+
+impl TransportObserver for FrameLog {
+    fn frame(&self, direction: Direction, bytes: &[u8]) {
+        let transcript = &self.0;
+
+        let direction = match direction {
+            Direction::Sent => "sent",
+            Direction::Received => "received",
+        };
+        let mut entry = json!({
+            "direction": direction,
+            "len": bytes.len(),
+        });
+
+        let header = Header::parse(bytes);
+        if let Some(header) = header {
+            entry["op"] = header.op.into();
+            entry["group"] = header.group.into();
+            entry["id"] = header.id.into();
+            entry["seq"] = header.sequence.into();
+            if let Some(name) = frame::command_name(header.group, header.id) {
+                entry["command"] = name.into();
+            }
+            if let Some(rc) = response_rc(&bytes[HEADER_LEN..]) {
+                entry["rc"] = rc.into();
+            }
+        }
+
+        if transcript.frames {
+            let settings = header.is_some_and(|header| {
+                matches!(Group::from(header.group), Group::SettingManagement)
+            });
+            entry["hex"] = if transcript.redact && settings {
+                REDACTED.into()
+            } else {
+                image::hex(bytes).into()
+            };
+        }
+
+        transcript.write("frame", entry);
+    }
+}
+
+/// The transport observer recording frames, if a transcript is written
+pub fn observer(ctx: &Context) -> Option<Arc<dyn TransportObserver>> {
+    ctx.transcript
+        .clone()
+        .map(|transcript| Arc::new(FrameLog(transcript)) as Arc<dyn TransportObserver>)
+}
+
+/// The `rc` of a payload, also inside the `err` map of SMP version 2 responses
+fn response_rc(payload: &[u8]) -> Option<i64> {
+    let (Value::Map(map), _) = cbor::decode(payload).ok()? else {
+        return None;
+    };
+
+    let field = |map: &[(Value, Value)], key: &str| {
+        map.iter()
+            .find(|(k, _)| k.as_text() == Some(key))
+            .map(|(_, v)| v.clone())
+    };
+    match (field(&map, "rc"), field(&map, "err")) {
+        (Some(Value::Integer(rc)), _) => i64::try_from(rc).ok(),
+        (_, Some(Value::Map(err))) => match field(&err, "rc") {
+            Some(Value::Integer(rc)) => i64::try_from(rc).ok(),
+            _ => None,
+        },
+        _ => None,
+    }
+}
@@ -0,0 +1,215 @@
+use std::error::Error;
+use std::time::Duration;
+
+use mcumgr_smp::transport::retry as policy;
+use tokio::time::Instant;
+use tracing::debug;
+
+use crate::context::Context;
+use crate::error::CliError;
+use crate::{autodetect, describe_target, open_transport, status, Cli, UsedTransport};
+
+/// Time between attempts to open the transport, doubled after every attempt up to
+/// [MAX_DELAY]
+const FIRST_DELAY: Duration = Duration::from_millis(250);
+const MAX_DELAY: Duration = Duration::from_secs(2);
+
+/// Time between the "waiting for device" messages
+const STATUS_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Print the status while waiting, at most every [STATUS_INTERVAL]
+struct Waiting {
+    start: Instant,
+    last_status: Option<Instant>,
+    delay: Duration,
+}
+
+impl Waiting {
+    fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            last_status: None,
+            delay: FIRST_DELAY,
+        }
+    }
+
+    /// Sleep until the next attempt, false if it would be after the deadline
+    async fn next_attempt(&mut self, ctx: &Context, target: &str, deadline: Instant) -> bool {
+        let now = Instant::now();
+        if now + self.delay > deadline {
+            return false;
+        }
+        if self
+            .last_status
+            .is_none_or(|last| now - last >= STATUS_INTERVAL)
+        {
+            status!(
+                ctx,
+                "waiting for device {}... ({}s left)",
+                target,
+                (deadline - now).as_secs()
+            );
+            self.last_status = Some(now);
+        }
+        tokio::time::sleep(self.delay).await;
+        self.delay = (self.delay * 2).min(MAX_DELAY);
+        true
+    }
+
+    fn done(&self, ctx: &Context, target: &str) {
+        if self.last_status.is_some() {
+            status!(
+                ctx,
+                "device {} is there after {:.1}s",
+                target,
+                self.start.elapsed().as_secs_f64()
+            );
+        }
+    }
+}
+
+/// Wait for a USB serial device to select for a command without `--transport`, with
+/// `--wait-for-device`. Returns right away without it or once a device is connected.
+pub async fn usb_device(ctx: &Context) {
+    let Some(deadline) = ctx.deadline else {
+        return;
+    };
+
+    let mut waiting = Waiting::new();
+    while !autodetect::any_candidate() {
+        if !waiting.next_attempt(ctx, "on USB", deadline).await {
+            // selecting the device reports that none was found
+            return;
+        }
+    }
+    waiting.done(ctx, "on USB");
+}
+
+/// Whether opening the transport may succeed later, see [policy::is_retryable_open]. The cause
+/// of a [CliError::Connect] isn't its source, so it is checked here
+fn is_retryable(err: &(dyn Error + 'static)) -> bool {
+    let mut current = Some(err);
+    while let Some(err) = current {
+        if let Some(CliError::Connect(cause)) = err.downcast_ref::<CliError>() {
+            return policy::is_retryable_open(cause.as_ref());
+        }
+        current = err.source();
+    }
+    policy::is_retryable_open(err)
+}
+
+/// Connect to the device, with `--wait-for-device` repeatedly until it succeeds or the
+/// deadline has passed.
+///
+/// Only failures of a device that isn't there yet are repeated, e.g. a serial port that
+/// doesn't exist yet or a device that doesn't answer the probe. Others, e.g. a port without
+/// permission, fail right away.
+pub async fn open(ctx: &Context, cli: &Cli) -> Result<UsedTransport, Box<dyn Error>> {
+    let Some(deadline) = ctx.deadline else {
+        return open_transport(ctx, cli).await;
+    };
+
+    let target = describe_target(cli);
+    let mut waiting = Waiting::new();
+    loop {
+        match open_transport(ctx, cli).await {
+            Ok(transport) => {
+                waiting.done(ctx, &target);
+                return Ok(transport);
+            }
+            Err(e) if !is_retryable(e.as_ref()) => return Err(e),
+            Err(e) => {
+                debug!("device not there yet: {}", e);
+                if !waiting.next_attempt(ctx, &target, deadline).await {
+                    let waited = waiting.start.elapsed().as_secs();
+                    Err(CliError::context(e, |e| {
+                        format!("device not available after waiting {}s: {}", waited, e)
+                    }))?;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use clap::Parser;
+    use mcumgr_smp::transport::timeouts::Timeouts;
+    use tokio::net::UdpSocket;
+
+    use super::*;
+    use crate::retry::Retry;
+
+    /// Answer every request like a device reporting its SMP buffers
+    async fn serve(socket: UdpSocket) {
+        // {"buf_size": 512, "buf_count": 4}
+        let payload = [
+            &[0xa2, 0x68][..],
+            b"buf_size",
+            &[0x19, 0x02, 0x00, 0x69],
+            b"buf_count",
+            &[0x04],
+        ]
+        .concat();
+
+        let mut buf = [0u8; 1024];
+        loop {
+            let (len, peer) = socket.recv_from(&mut buf).await.unwrap();
+            assert!(len >= 8, "short request");
+            // a read response to the request, with its version, group, sequence and command
+            let mut response = buf[..8].to_vec();
+            response[0] = (response[0] & !0x07) | 0x01;
+            response[2..4].copy_from_slice(&(payload.len() as u16).to_be_bytes());
+            response.extend_from_slice(&payload);
+            socket.send_to(&response, peer).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn waits_for_a_device_that_starts_late() {
+        let port = std::net::UdpSocket::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let cli = Cli::try_parse_from([
+            "smp-tool",
+            "--transport",
+            "udp",
+            "--dest-host",
+            "127.0.0.1",
+            "--udp-port",
+            &port.to_string(),
+            "--timeout-ms",
+            "200",
+            "os",
+            "echo",
+            "x",
+        ])
+        .unwrap();
+
+        let late = Duration::from_millis(800);
+        let server = tokio::spawn(async move {
+            tokio::time::sleep(late).await;
+            serve(UdpSocket::bind(("127.0.0.1", port)).await.unwrap()).await;
+        });
+
+        let ctx = Context {
+            retry: Arc::new(Retry::new(
+                policy::RetryPolicy::new(0, Duration::ZERO),
+                Timeouts::new(Duration::from_millis(cli.timeout_ms), Duration::ZERO),
+            )),
+            deadline: Some(Instant::now() + Duration::from_secs(10)),
+            ..Default::default()
+        };
+        let start = Instant::now();
+        let opened = open(&ctx, &cli).await;
+        server.abort();
+
+        assert!(opened.is_ok(), "{:?}", opened.err());
+        assert!(start.elapsed() >= late, "{:?}", start.elapsed());
+        assert!(start.elapsed() < Duration::from_secs(10));
+    }
+}
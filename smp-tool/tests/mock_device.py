@@ -0,0 +1,287 @@
+"""A device answering SMP requests over UDP, for tests without hardware.
+
+    with MockDevice() as device:
+        run("smp-tool", "-t", "udp", "-d", "127.0.0.1", "-p", str(device.port), ...)
+        assert device.image == firmware
+
+It answers echo, the MCUmgr parameters, reset, image state and upload and reading and
+writing settings like Zephyr's smp_svr sample. Other requests get rc 8, ENOTSUP.
+
+A completely uploaded image can be marked for test or confirmed. A reset swaps it with the
+running image like MCUboot, `running` is the image the device runs afterwards.
+
+`upload_faults` maps the offset of a chunk to the offset the device reports instead, once,
+as a device that lost data answers, e.g. 0 after a reboot.
+
+`drops` maps `(group, command)` to the number of requests of that command left unanswered,
+like lost responses.
+
+`swap_polls` is the number of image state requests after a reset that still report the old
+image, like a device answering while the bootloader swaps the slots.
+"""
+
+import hashlib
+import socket
+import struct
+import threading
+
+EINVAL = 3
+ENOENT = 5
+ENOTSUP = 8
+
+
+def encode(value):
+    """The value as CBOR, for the types SMP payloads use"""
+
+    def head(major, n):
+        if n < 24:
+            return bytes([major << 5 | n])
+        for info, fmt in ((24, ">B"), (25, ">H"), (26, ">I"), (27, ">Q")):
+            if n < 1 << (8 * struct.calcsize(fmt)):
+                return bytes([major << 5 | info]) + struct.pack(fmt, n)
+        raise ValueError(f"{n} doesn't fit into CBOR")
+
+    if value is None:
+        return b"\xf6"
+    if isinstance(value, bool):
+        return b"\xf5" if value else b"\xf4"
+    if isinstance(value, int):
+        return head(0, value) if value >= 0 else head(1, -1 - value)
+    if isinstance(value, bytes):
+        return head(2, len(value)) + value
+    if isinstance(value, str):
+        data = value.encode()
+        return head(3, len(data)) + data
+    if isinstance(value, list):
+        return head(4, len(value)) + b"".join(encode(item) for item in value)
+    if isinstance(value, dict):
+        return head(5, len(value)) + b"".join(encode(k) + encode(v) for k, v in value.items())
+    raise TypeError(f"can't encode {value!r}")
+
+
+def decode(data):
+    """The value of CBOR with definite lengths, as this crate sends it"""
+
+    def item(pos):
+        major, info = data[pos] >> 5, data[pos] & 0x1F
+        pos += 1
+        if major == 7:
+            simple = {20: False, 21: True, 22: None}
+            if info not in simple:
+                raise ValueError(f"unsupported simple value {info}")
+            return simple[info], pos
+        if info < 24:
+            n = info
+        else:
+            size = 1 << (info - 24)
+            n = int.from_bytes(data[pos : pos + size], "big")
+            pos += size
+        if major == 0:
+            return n, pos
+        if major == 1:
+            return -1 - n, pos
+        if major == 2:
+            return bytes(data[pos : pos + n]), pos + n
+        if major == 3:
+            return data[pos : pos + n].decode(), pos + n
+        if major == 4:
+            items = []
+            for _ in range(n):
+                value, pos = item(pos)
+                items.append(value)
+            return items, pos
+        if major == 5:
+            items = {}
+            for _ in range(n):
+                key, pos = item(pos)
+                items[key], pos = item(pos)
+            return items, pos
+        raise ValueError(f"unsupported major type {major}")
+
+    value, end = item(0)
+    if end != len(data):
+        raise ValueError("trailing bytes after the CBOR value")
+    return value
+
+
+class MockDevice:
+    """An SMP server on a UDP port of localhost, answering from a thread"""
+
+    RUNNING_HASH = bytes(32)
+
+    def __init__(self, buf_count=4, buf_size=2048):
+        self.buf_count = buf_count
+        self.buf_size = buf_size
+        self.settings = {}
+        # the upload in progress, or the last one
+        self.image = b""
+        self.image_len = None
+        self.image_sha = None
+        # the image marked for the next reset, confirmed if `permanent`
+        self.pending = False
+        self.permanent = False
+        self.running = {"hash": self.RUNNING_HASH, "version": "1.0.0", "confirmed": True}
+        # the image swapped out by the last reset
+        self.previous = None
+        self.upload_faults = {}
+        self.drops = {}
+        self.swap_polls = 0
+        # image state requests left until the swap of the last reset is done
+        self.swapping = 0
+        self.requests = []
+        self.resets = 0
+
+        self.socket = socket.socket(socket.AF_INET, socket.SOCK_DGRAM)
+        self.socket.bind(("127.0.0.1", 0))
+        self.socket.settimeout(0.05)
+        self.port = self.socket.getsockname()[1]
+        self._stop = threading.Event()
+        self._thread = threading.Thread(target=self._serve, daemon=True)
+
+    def __enter__(self):
+        self._thread.start()
+        return self
+
+    def __exit__(self, *exc):
+        self._stop.set()
+        self._thread.join()
+        self.socket.close()
+
+    @property
+    def upload_complete(self):
+        return self.image_len is not None and len(self.image) == self.image_len
+
+    def _serve(self):
+        while not self._stop.is_set():
+            try:
+                frame, peer = self.socket.recvfrom(65536)
+            except socket.timeout:
+                continue
+            response = self.handle(frame)
+            if response is not None:
+                self.socket.sendto(response, peer)
+
+    def handle(self, frame):
+        """The response to a request frame, `None` to not answer"""
+        op, flags, length, group, seq, command = struct.unpack(">BBHHBB", frame[:8])
+        payload = decode(frame[8 : 8 + length]) if length else {}
+        version, op = op & 0x18, op & 0x07
+        self.requests.append((op, group, command, payload))
+        if self.drops.get((group, command), 0) > 0:
+            self.drops[(group, command)] -= 1
+            return None
+
+        answer = self.answer(op, group, command, payload)
+        if answer is None:
+            return None
+        data = encode(answer)
+        return struct.pack(">BBHHBB", version | (op + 1), 0, len(data), group, seq, command) + data
+
+    def answer(self, op, group, command, payload):
+        """The payload of the response to a request"""
+        write = op == 2
+        if (group, command) == (0, 0) and write:
+            return {"r": payload["d"]}
+        if (group, command) == (0, 5) and write:
+            self.reset()
+            return {}
+        if (group, command) == (0, 6) and not write:
+            return {"buf_size": self.buf_size, "buf_count": self.buf_count}
+        if (group, command) == (1, 0) and not write:
+            images = self.image_states()
+            if self.swapping > 0:
+                self.swapping -= 1
+                if self.swapping == 0:
+                    self.swap()
+            return {"images": images}
+        if (group, command) == (1, 0) and write:
+            return self.set_state(payload)
+        if (group, command) == (1, 1) and write:
+            return self.write_chunk(payload)
+        if (group, command) == (3, 0) and not write:
+            if payload["name"] not in self.settings:
+                return {"rc": ENOENT}
+            return {"val": self.settings[payload["name"]]}
+        if (group, command) == (3, 0) and write:
+            self.settings[payload["name"]] = payload["val"]
+            return {}
+        return {"rc": ENOTSUP}
+
+    def write_chunk(self, chunk):
+        fault = self.upload_faults.pop(chunk["off"], None)
+        if fault is not None:
+            self.image = self.image[:fault]
+            return {"off": fault}
+        if chunk["off"] == 0:
+            self.image = b""
+            self.image_len = chunk["len"]
+            self.image_sha = chunk.get("sha")
+        if chunk["off"] == len(self.image):
+            self.image += chunk["data"]
+        answer = {"off": len(self.image)}
+        if self.upload_complete and self.image_sha is not None:
+            answer["match"] = hashlib.sha256(self.image).digest().startswith(self.image_sha)
+        return answer
+
+    def uploaded(self):
+        """The image in the secondary slot, if there is one"""
+        if self.upload_complete:
+            return {"hash": hashlib.sha256(self.image).digest(), "version": "1.0.1"}
+        return self.previous
+
+    def set_state(self, payload):
+        hash = payload.get("hash")
+        confirm = payload.get("confirm", False)
+        uploaded = self.uploaded()
+        if hash is None or hash == self.running["hash"]:
+            if not confirm:
+                return {"rc": EINVAL}
+            self.running["confirmed"] = True
+        elif uploaded is not None and hash == uploaded["hash"]:
+            self.pending = True
+            self.permanent = confirm
+        else:
+            return {"rc": ENOENT}
+        return {"images": self.image_states()}
+
+    def reset(self):
+        self.resets += 1
+        if not self.pending:
+            return
+        if self.swap_polls > 0:
+            self.swapping = self.swap_polls
+            return
+        self.swap()
+
+    def swap(self):
+        uploaded = self.uploaded()
+        self.previous = {"hash": self.running["hash"], "version": self.running["version"]}
+        self.running = {**uploaded, "confirmed": self.permanent}
+        self.image = b""
+        self.image_len = None
+        self.pending = self.permanent = False
+
+    def image_states(self):
+        images = [
+            {
+                "slot": 0,
+                "version": self.running["version"],
+                "hash": self.running["hash"],
+                "bootable": True,
+                "confirmed": self.running["confirmed"],
+                "active": True,
+            }
+        ]
+        uploaded = self.uploaded()
+        if uploaded is not None:
+            images.append(
+                {
+                    "slot": 1,
+                    "version": uploaded["version"],
+                    "hash": uploaded["hash"],
+                    "bootable": True,
+                    "pending": self.pending,
+                    "permanent": self.permanent,
+                }
+            )
+        return images
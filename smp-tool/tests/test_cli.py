@@ -0,0 +1,416 @@
+"""The smp-tool command line run against the mock device.
+
+    cargo build -p smp-tool && pytest smp-tool/tests
+
+SMP_TOOL selects another binary than target/debug/smp-tool.
+"""
+
+import hashlib
+import json
+import os
+import pathlib
+import struct
+import subprocess
+
+import pytest
+
+from mock_device import MockDevice
+
+SMP_TOOL = os.environ.get(
+    "SMP_TOOL", pathlib.Path(__file__).resolve().parents[2] / "target" / "debug" / "smp-tool"
+)
+
+
+@pytest.fixture
+def device():
+    with MockDevice() as device:
+        yield device
+
+
+@pytest.fixture
+def smp_tool(device):
+    """Run smp-tool with the connection to the mock device"""
+    if not pathlib.Path(SMP_TOOL).exists():
+        pytest.skip(f"{SMP_TOOL} isn't built")
+
+    def run(*args, timeout_ms=2000):
+        return subprocess.run(
+            [
+                str(SMP_TOOL),
+                "-t",
+                "udp",
+                "-d",
+                "127.0.0.1",
+                "-p",
+                str(device.port),
+                "--no-probe",
+                "--timeout-ms",
+                str(timeout_ms),
+                *args,
+            ],
+            capture_output=True,
+            text=True,
+            timeout=60,
+        )
+
+    return run
+
+
+@pytest.fixture
+def firmware(tmp_path):
+    data = bytes(range(256)) * 8
+    path = tmp_path / "firmware.bin"
+    path.write_bytes(data)
+    return path
+
+
+@pytest.fixture
+def mcuboot_image(tmp_path):
+    """An MCUboot image with a SHA-256 TLV, whose hash differs from the hash of the file"""
+    body = bytes(range(256)) * 4
+    header = struct.pack("<IIHHIIBBHI", 0x96F3B83D, 0, 32, 0, len(body), 0, 1, 0, 1, 0)
+    header += bytes(32 - len(header))
+    tlv_hash = hashlib.sha256(header + body).digest()
+    tlvs = struct.pack("<HH", 0x6907, 4 + 4 + 32) + struct.pack("<HH", 0x10, 32) + tlv_hash
+    path = tmp_path / "zephyr.signed.bin"
+    path.write_bytes(header + body + tlvs)
+    return path
+
+
+def test_flash_marks_image_by_file_hash(smp_tool, device, mcuboot_image):
+    # the mock reports the hash of the whole file, like devices without image TLV support
+    result = smp_tool("app", "flash", "--verify", "--test", str(mcuboot_image))
+
+    assert result.returncode == 0, result.stderr
+    assert "verified" in result.stderr
+    assert device.pending
+    set_state = [
+        payload
+        for _, group, command, payload in device.requests
+        if (group, command) == (1, 0) and payload
+    ]
+    assert set_state[-1]["hash"] == hashlib.sha256(mcuboot_image.read_bytes()).digest()
+
+
+def test_flash_after_device_restarted_upload(smp_tool, device, firmware):
+    device.upload_faults[1024] = 0
+
+    result = smp_tool("app", "flash", "-c", "512", str(firmware))
+
+    assert result.returncode == 0, result.stderr
+    assert "the device started the upload over" in result.stderr
+    assert device.image == firmware.read_bytes()
+    offsets = [payload["off"] for op, group, command, payload in device.requests if group == 1]
+    assert offsets == [0, 512, 1024, 0, 512, 1024, 1536]
+
+
+def test_flash_after_device_rewound(smp_tool, device, firmware):
+    device.upload_faults[1536] = 768
+
+    result = smp_tool("app", "flash", "-c", "512", str(firmware))
+
+    assert result.returncode == 0, result.stderr
+    assert "the device expects offset 768 again" in result.stderr
+    assert device.image == firmware.read_bytes()
+    offsets = [payload["off"] for op, group, command, payload in device.requests if group == 1]
+    assert offsets == [0, 512, 1024, 1536, 768, 1280, 1792]
+
+
+@pytest.mark.parametrize(
+    "value, args, printed",
+    [
+        (b"\x01\x02\xff", [], "0102ff"),
+        (b"\x2a\x00\x00\x00", ["--as", "int"], "42"),
+        (b"\xfe\xff", ["--as", "int"], "-2"),
+        (b"hello", ["--as", "string"], "hello"),
+        (b"hello", ["--as", "auto"], "hello"),
+        (b"\x00\xff", ["--as", "auto"], "hex:00ff"),
+        (b"\x01", ["--as", "bool"], "true"),
+    ],
+)
+def test_setting_read_prints_like_read_many(smp_tool, device, value, args, printed):
+    device.settings["app/value"] = value
+
+    read = smp_tool("setting", "read", "app/value", *args)
+    read_many = smp_tool("setting", "read-many", "app/value", *(args or ["--as", "hex"]))
+
+    assert read.returncode == 0, read.stderr
+    assert read.stdout == f"app/value={printed}\n"
+    assert read.stdout == read_many.stdout
+
+
+def test_setting_read_json(smp_tool, device):
+    device.settings["app/value"] = b"\x2a\x00"
+
+    result = smp_tool("--format", "json", "setting", "read", "app/value", "--as", "uint")
+
+    assert result.returncode == 0, result.stderr
+    assert json.loads(result.stdout) == {"app/value": {"value": 42}}
+
+
+def test_setting_read_invalid_width(smp_tool, device):
+    device.settings["app/value"] = b"\x01\x02\x03"
+
+    result = smp_tool("setting", "read", "app/value", "--as", "int")
+
+    assert result.returncode != 0
+    assert result.stdout == ""
+    assert "app/value" in result.stderr
+
+
+def test_exit_code_success(smp_tool, device):
+    result = smp_tool("os", "echo", "hello")
+
+    assert result.returncode == 0, result.stderr
+    assert "hello" in result.stdout
+
+
+def test_exit_code_device_rc(smp_tool, device):
+    text = smp_tool("setting", "read", "app/missing")
+    json_result = smp_tool("--format", "json", "setting", "read", "app/missing")
+
+    assert text.returncode == 5
+    assert text.stderr.startswith("error: ")
+    assert json_result.returncode == 5
+    error = json.loads(json_result.stderr)
+    assert error["exit_code"] == 5
+    assert error["rc"] == 5
+    assert error["rc_name"] == "MGMT_ERR_ENOENT"
+
+
+def test_exit_code_timeout(smp_tool, device):
+    device.drops[(0, 0)] = 1
+
+    result = smp_tool("os", "echo", "hello", timeout_ms=200)
+
+    assert result.returncode == 4, result.stderr
+    assert result.stdout == ""
+
+
+@pytest.mark.parametrize(
+    "args",
+    [
+        ["setting", "read"],
+        ["os", "echo", "hello", "--no-such-option"],
+        ["setting", "read", "app//value"],
+    ],
+)
+def test_exit_code_usage(smp_tool, device, args):
+    result = smp_tool(*args)
+
+    assert result.returncode == 2, result.stderr
+    # nothing reached the device
+    assert device.requests == []
+
+
+def update_steps(device):
+    """The requests of an update, repeated ones only once"""
+    names = {
+        (2, 1, 1): "upload",
+        (0, 1, 0): "state",
+        (2, 1, 0): "set-state",
+        (2, 0, 5): "reset",
+    }
+    steps = []
+    for op, group, command, payload in device.requests:
+        name = names.get((op, group, command))
+        if name == "set-state":
+            name = f"set-state confirm={payload['confirm']}"
+        if name is not None and (not steps or steps[-1] != name):
+            steps.append(name)
+    return steps
+
+
+def test_update(smp_tool, device, firmware):
+    result = smp_tool("app", "update", "-c", "512", "--confirm-timeout", "10", str(firmware))
+
+    assert result.returncode == 0, result.stderr
+    for step in ("[1/5]", "[2/5]", "[3/5]", "[4/5]", "[5/5]"):
+        assert step in result.stderr
+    hash = hashlib.sha256(firmware.read_bytes()).digest()
+    assert device.running == {"hash": hash, "version": "1.0.1", "confirmed": True}
+    assert device.resets == 1
+    assert update_steps(device) == [
+        "upload",
+        "state",
+        "set-state confirm=False",
+        "reset",
+        "state",
+        "set-state confirm=True",
+    ]
+    confirm = device.requests[-1][3]
+    assert confirm["hash"] == hash
+    # the confirmed image state is printed
+    assert hash.hex() in result.stdout
+
+
+def test_update_by_file_hash(smp_tool, device, mcuboot_image):
+    result = smp_tool("app", "update", "--confirm-timeout", "10", str(mcuboot_image))
+
+    assert result.returncode == 0, result.stderr
+    hash = hashlib.sha256(mcuboot_image.read_bytes()).digest()
+    assert device.running == {"hash": hash, "version": "1.0.1", "confirmed": True}
+
+
+def test_update_waits_for_the_swap(smp_tool, device, firmware):
+    # the device answers with the old image until the bootloader swapped the slots
+    device.swap_polls = 3
+    result = smp_tool("app", "update", "--confirm-timeout", "20", str(firmware))
+
+    assert result.returncode == 0, result.stderr
+    hash = hashlib.sha256(firmware.read_bytes()).digest()
+    assert device.running == {"hash": hash, "version": "1.0.1", "confirmed": True}
+    assert update_steps(device)[-2:] == ["state", "set-state confirm=True"]
+
+
+def test_update_chunk_size_from_buffer_size(smp_tool, device, firmware):
+    device.buf_size = 600
+    result = smp_tool("app", "update", "--no-confirm", str(firmware))
+
+    assert result.returncode == 0, result.stderr
+    chunks = [
+        payload["data"]
+        for _, group, command, payload in device.requests
+        if (group, command) == (1, 1)
+    ]
+    # the buffer size less the header and the encoding of the first chunk
+    assert [len(chunk) for chunk in chunks] == [496, 496, 496, 496, 64]
+
+
+def test_update_no_confirm(smp_tool, device, firmware):
+    result = smp_tool("app", "update", "--no-confirm", str(firmware))
+
+    assert result.returncode == 0, result.stderr
+    assert device.running["hash"] == hashlib.sha256(firmware.read_bytes()).digest()
+    assert not device.running["confirmed"]
+    assert update_steps(device)[-2:] == ["reset", "state"]
+
+
+PROGRESS_FIELDS = {"phase", "step", "bytes_done", "bytes_total", "rate", "eta_seconds", "warning"}
+
+
+def progress_events(stderr):
+    """Every JSON progress line of stderr, checked against the schema of `--progress json`"""
+    events = []
+    for line in stderr.splitlines():
+        if not line.startswith("{"):
+            continue
+        event = json.loads(line)
+        assert set(event) <= PROGRESS_FIELDS, line
+        assert {"phase", "bytes_done", "bytes_total", "rate", "eta_seconds"} <= set(event), line
+        assert isinstance(event["phase"], str)
+        assert isinstance(event.get("step", ""), str)
+        assert isinstance(event["bytes_done"], int) and event["bytes_done"] >= 0
+        assert event["bytes_total"] is None or isinstance(event["bytes_total"], int)
+        assert isinstance(event["rate"], (int, float)) and event["rate"] >= 0
+        assert event["eta_seconds"] is None or event["eta_seconds"] >= 0
+        events.append(event)
+    return events
+
+
+def test_flash_progress_json(smp_tool, device, firmware):
+    result = smp_tool("--progress", "json", "app", "flash", "-c", "512", str(firmware))
+
+    assert result.returncode == 0, result.stderr
+    events = progress_events(result.stderr)
+    assert events and all(event["phase"] == "upload" for event in events)
+    assert all(event["bytes_total"] == 2048 for event in events)
+    done = [event["bytes_done"] for event in events]
+    assert done == sorted(done)
+    assert done[-1] == 2048
+    assert "{" not in result.stdout
+
+
+def test_update_progress_json(smp_tool, device, firmware):
+    result = smp_tool("--progress", "json", "app", "update", "-c", "512", str(firmware))
+
+    assert result.returncode == 0, result.stderr
+    events = progress_events(result.stderr)
+    phases = [event["phase"] for event in events if event["phase"] != "upload"]
+    assert phases == ["mark", "reset", "wait", "confirm"]
+    assert [event["step"] for event in events][-4:] == ["2/5", "3/5", "4/5", "5/5"]
+    assert events[0]["step"] == "1/5"
+    assert max(event["bytes_done"] for event in events) == 2048
+
+
+def test_lost_reads_are_retried(smp_tool, device):
+    device.settings["app/value"] = b"\x01"
+    device.drops[(3, 0)] = 2
+
+    result = smp_tool(
+        "--retries", "2", "--retry-delay-ms", "10", "setting", "read", "app/value", timeout_ms=200
+    )
+
+    assert result.returncode == 0, result.stderr
+    assert result.stdout == "app/value=01\n"
+    assert "retrying 1/2" in result.stderr and "retrying 2/2" in result.stderr
+    assert len(device.requests) == 3
+    # the same request each time
+    assert len({repr(request) for request in device.requests}) == 1
+
+
+def test_retries_give_up(smp_tool, device):
+    device.drops[(0, 0)] = 3
+
+    result = smp_tool(
+        "--retries", "2", "--retry-delay-ms", "10", "os", "echo", "hello", timeout_ms=200
+    )
+
+    assert result.returncode == 4, result.stderr
+    assert len(device.requests) == 3
+
+
+def test_unsafe_requests_are_not_retried(smp_tool, device):
+    device.drops[(1, 0)] = 1
+
+    result = smp_tool("--retries", "2", "--retry-delay-ms", "10", "app", "confirm", timeout_ms=200)
+
+    assert result.returncode == 4, result.stderr
+    assert "retrying" not in result.stderr
+    assert [(op, group, command) for op, group, command, _ in device.requests] == [(2, 1, 0)]
+
+
+def test_lost_upload_chunk_is_retried(smp_tool, device, firmware):
+    device.drops[(1, 1)] = 1
+
+    result = smp_tool(
+        "--retries",
+        "1",
+        "--retry-delay-ms",
+        "10",
+        # the first chunk waits as long as an erase
+        "--slow-timeout-ms",
+        "200",
+        "app",
+        "flash",
+        "-c",
+        "512",
+        str(firmware),
+        timeout_ms=200,
+    )
+
+    assert result.returncode == 0, result.stderr
+    assert device.image == firmware.read_bytes()
+    offsets = [payload["off"] for op, group, command, payload in device.requests if group == 1]
+    assert offsets[:2] == [0, 0]
+
+
+@pytest.mark.parametrize("file", ["export.toml", "export.json"])
+def test_settings_export_import_round_trip(smp_tool, device, tmp_path, file):
+    values = {
+        "app/text": b"hello",
+        "app/nul": b"\x00",
+        "app/ones": b"\xff\xff\xff\xff",
+        "app/mixed": b"a\x00b\xffc",
+        "app/empty": b"",
+    }
+    device.settings.update(values)
+    path = tmp_path / file
+
+    export = smp_tool("setting", "export", str(path), *values)
+    assert export.returncode == 0, export.stderr
+
+    device.settings.clear()
+    imported = smp_tool("setting", "import", str(path))
+    assert imported.returncode == 0, imported.stderr
+    assert device.settings == values
@@ -0,0 +1,132 @@
+"""`smp-tool serve --stdio` driven by a JSON-RPC client against the mock device.
+
+    cargo build -p smp-tool && pytest smp-tool/tests
+
+SMP_TOOL selects another binary than target/debug/smp-tool.
+"""
+
+import itertools
+import json
+import os
+import pathlib
+import subprocess
+
+import pytest
+
+from mock_device import ENOENT, MockDevice
+
+SMP_TOOL = os.environ.get(
+    "SMP_TOOL", pathlib.Path(__file__).resolve().parents[2] / "target" / "debug" / "smp-tool"
+)
+
+
+class RpcError(Exception):
+    def __init__(self, error):
+        super().__init__(error["message"])
+        self.code = error["code"]
+        self.data = error.get("data", {})
+
+
+class SmpTool:
+    """A JSON-RPC client of `smp-tool serve --stdio`"""
+
+    def __init__(self, *global_args):
+        self.process = subprocess.Popen(
+            [str(SMP_TOOL), *global_args, "serve", "--stdio"],
+            stdin=subprocess.PIPE,
+            stdout=subprocess.PIPE,
+            text=True,
+        )
+        self.ids = itertools.count(1)
+
+    def call(self, method, params=None, on_progress=None):
+        request_id = next(self.ids)
+        request = {"jsonrpc": "2.0", "id": request_id, "method": method}
+        if params is not None:
+            request["params"] = params
+        self.process.stdin.write(json.dumps(request) + "\n")
+        self.process.stdin.flush()
+
+        for line in self.process.stdout:
+            message = json.loads(line)
+            if message.get("method") == "progress":
+                if on_progress:
+                    on_progress(message["params"])
+                continue
+            if message.get("id") != request_id:
+                continue
+            if "error" in message:
+                raise RpcError(message["error"])
+            return message["result"]
+        raise EOFError("smp-tool exited")
+
+    def close(self):
+        self.process.stdin.close()
+        return self.process.wait(timeout=10)
+
+
+@pytest.fixture
+def device():
+    with MockDevice() as device:
+        yield device
+
+
+@pytest.fixture
+def tool(device):
+    if not pathlib.Path(SMP_TOOL).exists():
+        pytest.skip(f"{SMP_TOOL} isn't built")
+    tool = SmpTool("-t", "udp", "-d", "127.0.0.1", "-p", str(device.port), "--no-probe")
+    yield tool
+    assert tool.close() == 0
+
+
+def test_echo(tool):
+    assert tool.call("os.echo", ["hello"]).strip() == "hello"
+
+
+def test_requests_share_the_connection(tool, device):
+    for n in range(3):
+        assert tool.call("os.echo", [f"hello {n}"]).strip() == f"hello {n}"
+    assert len(device.requests) == 3
+
+
+def test_flash_with_progress(tool, device, tmp_path):
+    firmware = bytes(range(256)) * 12
+    path = tmp_path / "firmware.bin"
+    path.write_bytes(firmware)
+
+    events = []
+    result = tool.call(
+        "app.flash", {"args": [str(path)], "chunk_size": 512}, on_progress=events.append
+    )
+
+    assert device.image == firmware
+    assert result is not None
+    assert events, "no progress notifications"
+    assert all(event["id"] == 1 for event in events)
+    assert events[-1]["bytes_done"] == len(firmware)
+
+
+def test_device_error(tool, device):
+    with pytest.raises(RpcError) as error:
+        tool.call("setting.read", ["no/such/setting"])
+    assert error.value.code == -32001
+    assert error.value.data["rc"] == ENOENT
+
+
+def test_setting_written_and_read(tool, device):
+    tool.call("setting.write-string", ["foo/bar", "baz"])
+    assert device.settings["foo/bar"] == b"baz"
+    assert "foo/bar" in tool.call("setting.read", ["foo/bar"])
+
+
+def test_unknown_method(tool):
+    with pytest.raises(RpcError) as error:
+        tool.call("os.no-such-command")
+    assert error.value.code == -32601
+
+
+def test_invalid_params(tool):
+    with pytest.raises(RpcError) as error:
+        tool.call("os.echo", {"no_such_option": True})
+    assert error.value.code == -32602